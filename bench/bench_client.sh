@@ -32,7 +32,7 @@ do
         /usr/bin/time "$TFTP_BIN" -v 127.0.0.1 69 -m octet -c get "$file" /tmp/testfile
         echo "Using: [0;34mtftp-rs[0m"
         sleep 1
-        /usr/bin/time ../target/release/examples/get "$file"
+        /usr/bin/time ../target/release/examples/get 127.0.0.1 "$file" /tmp/testfile
     else
         echo "Putting file: [0;32m$file[0m"
         echo "Using: [0;31mtftp-hpa[0m"
@@ -40,6 +40,6 @@ do
         /usr/bin/time "$TFTP_BIN" -v 127.0.0.1 69 -m octet -c put "fixtures/$file" testfile
         echo "Using: [0;34mtftp-rs[0m"
         sleep 1
-        /usr/bin/time ../target/release/examples/put "fixtures/$file"
+        /usr/bin/time ../target/release/examples/put 127.0.0.1 "fixtures/$file" testfile
     fi
 done
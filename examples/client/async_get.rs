@@ -0,0 +1,75 @@
+//! Reads several files from a server at the same time on a tokio-core reactor.
+//!
+//! Each transfer is a future, `join_all` runs them concurrently on a single
+//! thread. The files are saved under their base names in the current directory.
+//!
+//! ```text
+//! cargo run --example async-get --features tokio-client -- 127.0.0.1:6969 kernel initrd
+//! ```
+
+extern crate tftp;
+extern crate futures;
+extern crate tokio_core;
+
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process::exit;
+
+use futures::Future;
+use futures::future;
+use tokio_core::reactor::Core;
+
+use tftp::packet::Mode;
+use tftp::prelude::AsyncClient;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 {
+        eprintln!("Usage: {} SERVER_ADDR REMOTE_PATH...", args[0]);
+        exit(2)
+    }
+    let server_addr: SocketAddr = match args[1].parse() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!("Invalid server address {}: {}", args[1], e);
+            exit(2)
+        }
+    };
+    let mut core = Core::new().unwrap();
+    let client = AsyncClient::new(&core.handle(), server_addr);
+
+    // `get_to` copies a download into any `AsyncWrite`, here a buffer per
+    // file. `get` returns the blocks as a `Stream` instead.
+    let mut transfers = Vec::new();
+    for path in &args[2..] {
+        let path = path.clone();
+        let transfer = match client.get_to(&path, Mode::Octet, Cursor::new(Vec::new())) {
+            Ok(transfer) => transfer,
+            Err(e) => {
+                eprintln!("Can't start reading {}: {}", path, e);
+                exit(1)
+            }
+        };
+        transfers.push(transfer.map(move |(contents, size)| (path, contents.into_inner(), size)));
+    }
+
+    // The first failed transfer fails the others too.
+    let files = match core.run(future::join_all(transfers)) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Transfer failed: {}", e);
+            exit(1)
+        }
+    };
+    for (path, contents, size) in files {
+        let name = Path::new(&path).file_name().map(Path::new).unwrap_or_else(|| Path::new("download"));
+        if let Err(e) = fs::write(name, &contents) {
+            eprintln!("Can't write {}: {}", name.display(), e);
+            exit(1)
+        }
+        println!("Read {} to {} ({} bytes)", path, name.display(), size);
+    }
+}
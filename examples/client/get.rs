@@ -1,30 +1,46 @@
+//! Reads a file from a server into a local file.
+//!
+//! `SERVER` is an address (`192.168.0.1`, `[::1]:6969`) or a URL naming the
+//! directory of the file (`tftp://192.168.0.1/boot`), the port defaults to 69.
+//!
+//! ```text
+//! cargo run --example get -- 127.0.0.1:6969 hello.txt hello.txt
+//! ```
+
 extern crate tftp;
 
-use std::io::BufWriter;
-use std::fs::OpenOptions;
-use std::path::Path;
-use std::net::{SocketAddr, IpAddr, Ipv4Addr};
-use std::process::exit;
 use std::env;
-
-use tftp::client::get;
-use tftp::packet::Mode;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::process::exit;
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} PATH", args.get(0).unwrap());
-        return
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        eprintln!("Usage: {} SERVER REMOTE_PATH LOCAL_PATH", args[0]);
+        exit(2)
     }
-    let file_path = args[1].clone();
-    let mut file_options = OpenOptions::new();
-    file_options.truncate(true).create(true).write(true);
-    let file = match file_options.open(Path::new("result")) {
-        Ok(f) => f,
-        Err(_) => {
-            exit(1);
-        },
+    let mut file = match File::create(&args[3]) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => {
+            eprintln!("Can't create {}: {}", args[3], e);
+            exit(1)
+        }
+    };
+    // One-shot transfers use octet mode, 5 retries and a 5 second timeout,
+    // `ClientBuilder` configures anything else (see the `options` example).
+    let params = match tftp::get(&args[1], &args[2], &mut file) {
+        Ok(params) => params,
+        Err(e) => {
+            eprintln!("Transfer failed: {}", e);
+            drop(file);
+            let _ = fs::remove_file(&args[3]);
+            exit(1)
+        }
     };
-    let mut writer = BufWriter::new(file);
-    get(&Path::new(&file_path), Mode::Octet, &mut writer);
+    if let Err(e) = file.flush() {
+        eprintln!("Can't write {}: {}", args[3], e);
+        exit(1)
+    }
+    println!("Read {} to {} ({})", args[2], args[3], params);
 }
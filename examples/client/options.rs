@@ -0,0 +1,115 @@
+//! Negotiates transfer options (RFC 2347) with a server.
+//!
+//! Starts a server limited to 1468 byte blocks and reads a file from it with
+//! different requests: the block size the client asks for is lowered to the
+//! limit of the server, the timeout is adopted and the size is known before
+//! the transfer with `probe_size`. Options the server doesn't know are left
+//! out of its acknowledgment.
+//!
+//! ```text
+//! cargo run --example options
+//! ```
+
+extern crate tftp;
+extern crate futures;
+extern crate tokio_core;
+
+use std::io::{self, Cursor};
+use std::net::UdpSocket;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use futures::future::{self, FutureResult};
+use tokio_core::reactor::Handle;
+
+use tftp::handler::{Handler, Request};
+use tftp::packet::{TransferOptions, TSIZE_OPTION};
+use tftp::prelude::{BlockSize, ClientBuilder, Mode, ServerBuilder};
+
+/// Serves one file from memory and tells its size to clients asking for it.
+struct Image(Vec<u8>);
+
+impl Handler for Image {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = io::Sink;
+    type OpenRead = FutureResult<Cursor<Vec<u8>>, io::Error>;
+    type OpenWrite = FutureResult<io::Sink, io::Error>;
+
+    fn open_read(&self, _: &Request, _: &Handle) -> Self::OpenRead {
+        future::ok(Cursor::new(self.0.clone()))
+    }
+
+    fn open_write(&self, _: &Request, _: &Handle) -> Self::OpenWrite {
+        future::err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only"))
+    }
+
+    // The server negotiates blksize and timeout itself, other options are
+    // acknowledged by the handler. A read request asks for the size with a
+    // tsize of 0 (RFC 2349).
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static> {
+        let mut options = TransferOptions::new();
+        if request.options().and_then(|options| options.get(TSIZE_OPTION)) == Some("0") {
+            options.insert(TSIZE_OPTION, self.0.len().to_string());
+        }
+        options
+    }
+}
+
+fn main() {
+    let contents: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let image = Image(contents.clone());
+    thread::spawn(move || {
+        ServerBuilder::new(addr)
+            .handler(image)
+            .max_block_size(BlockSize::new(1468).unwrap())
+            .build()
+            .unwrap()
+            .run()
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(100));
+    let path = Path::new("image.bin");
+
+    // Without options the transfer uses 512 byte blocks and each side
+    // retransmits after its own timeout.
+    let plain = ClientBuilder::new(addr).build().unwrap();
+    let params = plain.get(path, Mode::Octet, &mut io::sink()).unwrap();
+    println!("Without options: {}", params);
+    println!("  {}", plain.stats().get());
+
+    // blksize asks for larger blocks, fewer packets for the same file. The
+    // server acknowledges the smaller of the requested size and its limit.
+    // timeout asks the server to retransmit after the client's timeout too.
+    let tuned = ClientBuilder::new(addr)
+        .block_size(BlockSize::new(8192).unwrap())
+        .timeout(Duration::from_secs(2))
+        .negotiate_timeout(true)
+        .build()
+        .unwrap();
+    let mut received = Vec::new();
+    let params = tuned.get(path, Mode::Octet, &mut received).unwrap();
+    assert_eq!(contents, received);
+    println!("Requesting 8192 byte blocks and a 2s timeout: {}", params);
+    println!("  {}", tuned.stats().get());
+
+    // The probe stops the transfer once the server acknowledged tsize.
+    match tuned.probe_size(path) {
+        Ok(size) => println!("Size of image.bin: {} bytes", size),
+        Err(e) => println!("Probing the size failed: {}", e),
+    }
+
+    // Other options are sent as they are, the handler acknowledges the ones
+    // it knows.
+    let mut options = TransferOptions::new();
+    options.insert(TSIZE_OPTION, "0");
+    options.insert("x-checksum", "sha256");
+    let (_, acknowledged) = tuned.get_with_options(path, Mode::Octet, &options, &mut io::sink()).unwrap();
+    for &(ref name, ref value) in acknowledged.iter() {
+        println!("Acknowledged {}={}", name, value);
+    }
+    if acknowledged.get("x-checksum").is_none() {
+        println!("x-checksum was not acknowledged");
+    }
+}
@@ -0,0 +1,38 @@
+//! Writes a local file to a server.
+//!
+//! The server must allow writes, e.g. `tftpd` without `--read-only`.
+//!
+//! ```text
+//! cargo run --example put -- 127.0.0.1:6969 hello.txt upload/hello.txt
+//! ```
+
+extern crate tftp;
+
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() != 4 {
+        eprintln!("Usage: {} SERVER LOCAL_PATH REMOTE_PATH", args[0]);
+        exit(2)
+    }
+    let mut file = match File::open(&args[2]) {
+        Ok(file) => BufReader::new(file),
+        Err(e) => {
+            eprintln!("Can't open {}: {}", args[2], e);
+            exit(1)
+        }
+    };
+    // The transfer completes once the server acknowledged the last block,
+    // a failed transfer may leave a partial file on the server.
+    match tftp::put(&args[1], &args[3], &mut file) {
+        Ok(params) => println!("Wrote {} to {} ({})", args[2], args[3], params),
+        Err(e) => {
+            eprintln!("Transfer failed: {}", e);
+            exit(1)
+        }
+    }
+}
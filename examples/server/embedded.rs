@@ -0,0 +1,98 @@
+//! Embeds a server with a custom handler in a program and reads from it.
+//!
+//! The handler generates a boot configuration for each client instead of
+//! reading files from a directory and refuses uploads. The server runs on a
+//! thread of its own, the main thread reads two files from it and drains the
+//! server before printing its counters.
+//!
+//! ```text
+//! cargo run --example embedded-server
+//! ```
+
+extern crate tftp;
+extern crate futures;
+extern crate tokio_core;
+
+use std::io::{self, Cursor};
+use std::net::UdpSocket;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use futures::future::{self, FutureResult};
+use tokio_core::reactor::Handle;
+
+use tftp::handler::{Handler, Request};
+use tftp::packet::Mode;
+use tftp::prelude::{Client, ServerBuilder};
+use tftp::stats::Stats;
+
+/// Serves `pxelinux.cfg/default` generated for the requesting client.
+struct BootConfig {
+    kernel: String,
+}
+
+impl Handler for BootConfig {
+    // Generated files are small, they are built in memory when the request
+    // arrives. Handlers fetching files from elsewhere return a future
+    // completing later, see the `object-store` example.
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = io::Sink;
+    type OpenRead = FutureResult<Cursor<Vec<u8>>, io::Error>;
+    type OpenWrite = FutureResult<io::Sink, io::Error>;
+
+    fn open_read(&self, request: &Request, _: &Handle) -> Self::OpenRead {
+        if request.filename() != "pxelinux.cfg/default" {
+            // Errors are sent to the client, `NotFound` as "File not found".
+            return future::err(io::Error::new(io::ErrorKind::NotFound, "no such file"))
+        }
+        let client = request.client_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+        let config = format!("DEFAULT linux\nLABEL linux\n  KERNEL {}\n  APPEND ip={}\n", self.kernel, client);
+        future::ok(Cursor::new(config.into_bytes()))
+    }
+
+    fn open_write(&self, _: &Request, _: &Handle) -> Self::OpenWrite {
+        future::err(io::Error::new(io::ErrorKind::PermissionDenied, "uploads are not accepted"))
+    }
+}
+
+fn main() {
+    // Any free port, a real deployment listens on port 69.
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let stats = Stats::new();
+    let server_stats = stats.clone();
+    // The server runs a tokio-core reactor and isn't `Send`, it's built on its
+    // thread. The drain handle can be sent back to stop it later.
+    let (drain_tx, drain_rx) = mpsc::channel();
+    let running = thread::spawn(move || {
+        let server = ServerBuilder::new(addr)
+            .handler(BootConfig { kernel: "vmlinuz".to_owned() })
+            .timeout(Duration::from_secs(1))
+            .stats(server_stats)
+            .build()
+            .unwrap();
+        drain_tx.send(server.drain_handle()).unwrap();
+        server.run()
+    });
+    let drain = drain_rx.recv().unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let client = Client::new(addr);
+    let mut config = Vec::new();
+    match client.get(Path::new("pxelinux.cfg/default"), Mode::Octet, &mut config) {
+        Ok(params) => print!("Read pxelinux.cfg/default ({}):\n{}", params, String::from_utf8_lossy(&config)),
+        Err(e) => println!("Reading pxelinux.cfg/default failed: {}", e),
+    }
+    match client.get(Path::new("pxelinux.cfg/01-aa-bb-cc-dd-ee-ff"), Mode::Octet, &mut io::sink()) {
+        Ok(_) => println!("Read pxelinux.cfg/01-aa-bb-cc-dd-ee-ff"),
+        Err(e) => println!("Reading pxelinux.cfg/01-aa-bb-cc-dd-ee-ff failed: {}", e),
+    }
+
+    // Waits for the running transfers, their counters are added when they end.
+    let report = drain.drain(Duration::from_secs(5));
+    running.join().unwrap().unwrap();
+    println!("Server drained, {} transfers aborted", report.aborted.len());
+    println!("Server: {}", stats.get());
+    println!("Client: {}", client.stats().get());
+}
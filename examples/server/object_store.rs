@@ -0,0 +1,121 @@
+//! Serves files from an in-memory object store with simulated latency.
+//!
+//! Opening a file waits for the store like a request to a remote backend
+//! (S3, HTTP, ...) would, other transfers keep running in the meantime.
+//! Uploads are buffered and stored once the transfer completes.
+//!
+//! ```text
+//! cargo run --example object-store -- 127.0.0.1:6969
+//! ```
+
+extern crate tftp;
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_io;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Cursor, Write};
+use std::mem;
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use futures::future;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::AsyncWrite;
+
+use tftp::handler::{Handler, Request};
+use tftp::server::ServerBuilder;
+
+type Objects = Rc<RefCell<HashMap<String, Vec<u8>>>>;
+
+struct ObjectStore {
+    objects: Objects,
+    latency: Duration,
+}
+
+impl ObjectStore {
+    /// Waits like a round trip to a remote store would.
+    fn round_trip(&self, handle: &Handle) -> Box<Future<Item = (), Error = io::Error>> {
+        match Timeout::new(self.latency, handle) {
+            Ok(timeout) => Box::new(timeout),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+}
+
+impl Handler for ObjectStore {
+    type Reader = Cursor<Vec<u8>>;
+    type Writer = Upload;
+    type OpenRead = Box<Future<Item = Cursor<Vec<u8>>, Error = io::Error>>;
+    type OpenWrite = Box<Future<Item = Upload, Error = io::Error>>;
+
+    fn open_read(&self, request: &Request, handle: &Handle) -> Self::OpenRead {
+        let objects = self.objects.clone();
+        let key = request.filename().to_owned();
+        Box::new(self.round_trip(handle).and_then(move |()| {
+            match objects.borrow().get(&key) {
+                Some(object) => Ok(Cursor::new(object.clone())),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "no such object")),
+            }
+        }))
+    }
+
+    fn open_write(&self, request: &Request, handle: &Handle) -> Self::OpenWrite {
+        let objects = self.objects.clone();
+        let key = request.filename().to_owned();
+        Box::new(self.round_trip(handle).and_then(move |()| {
+            if objects.borrow().contains_key(&key) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "object exists"))
+            }
+            Ok(Upload {
+                objects: objects,
+                key: key,
+                data: Vec::new(),
+            })
+        }))
+    }
+}
+
+/// Object being uploaded, stored when the transfer completes.
+struct Upload {
+    objects: Objects,
+    key: String,
+    data: Vec<u8>,
+}
+
+impl Write for Upload {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for Upload {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        let data = mem::replace(&mut self.data, Vec::new());
+        self.objects.borrow_mut().insert(self.key.clone(), data);
+        Ok(Async::Ready(()))
+    }
+}
+
+fn main() {
+    let addr = env::args().nth(1).unwrap_or_else(|| "127.0.0.1:6969".to_owned());
+    let mut objects = HashMap::new();
+    objects.insert("hello.txt".to_owned(), b"Hello from the object store!\n".to_vec());
+    let store = ObjectStore {
+        objects: Rc::new(RefCell::new(objects)),
+        latency: Duration::from_millis(50),
+    };
+    let server = ServerBuilder::new(addr.parse().expect("invalid address"))
+        .handler(store)
+        .build()
+        .unwrap();
+    server.run().unwrap();
+}
@@ -1,7 +0,0 @@
-extern crate tftp;
-
-use tftp::server::start;
-
-fn main() {
-    start();
-}
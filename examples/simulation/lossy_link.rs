@@ -0,0 +1,144 @@
+//! Simulates a lossy link between a client and a server in one process.
+//!
+//! `Client::get_over` runs a transfer over any `Transport`, here a UDP socket
+//! losing one in 5 datagrams in each direction. The transfer still completes,
+//! the counters of the client show the retransmissions it took. The same
+//! approach tests programs against delays, duplicates or reordering.
+//!
+//! The losses are drawn from a seeded `SharedRng`. The seed is printed, and
+//! `LOSSY_LINK_SEED` replays the same losses:
+//!
+//! ```text
+//! cargo run --example lossy-link
+//! LOSSY_LINK_SEED=42 cargo run --example lossy-link
+//! ```
+
+extern crate tftp;
+extern crate mio;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+
+use tftp::prelude::{ClientBuilder, Mode, Retries, ServerBuilder};
+use tftp::rng::SharedRng;
+use tftp::stats::Stats;
+use tftp::transport::{Transport, UdpTransport};
+
+/// One in `LOSS_RATIO` datagrams is lost.
+const LOSS_RATIO: u64 = 5;
+
+/// UDP socket losing datagrams.
+struct LossyLink {
+    inner: UdpTransport,
+    rng: SharedRng,
+}
+
+impl LossyLink {
+    fn lost(&self) -> bool {
+        self.rng.next_u64() % LOSS_RATIO == 0
+    }
+}
+
+impl Transport for LossyLink {
+    type Addr = SocketAddr;
+
+    fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        if self.lost() {
+            // Lost on the way, the sender can't tell.
+            return Ok(buf.len())
+        }
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (n, from) = try!(self.inner.recv_from(buf));
+            if !self.lost() {
+                return Ok((n, from))
+            }
+        }
+    }
+
+    fn connect(&mut self, addr: &SocketAddr) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+}
+
+// The client waits for the socket with mio, the link registers the inner one.
+impl Source for LossyLink {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
+
+fn main() {
+    let root = env::temp_dir().join(format!("tftp-lossy-link-example-{}", process::id()));
+    fs::create_dir_all(&root).unwrap();
+    let contents: Vec<u8> = (0..50000).map(|i| (i % 251) as u8).collect();
+    fs::write(root.join("firmware.bin"), &contents).unwrap();
+
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let server_root = root.clone();
+    let server_stats = Stats::new();
+    let server_counters = server_stats.clone();
+    thread::spawn(move || {
+        ServerBuilder::new(addr)
+            .root(server_root)
+            .timeout(Duration::from_millis(200))
+            .stats(server_counters)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap()
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Short timeouts keep the simulation quick, the retries cover several
+    // losses of the same packet.
+    let client = ClientBuilder::new(addr)
+        .timeout(Duration::from_millis(200))
+        .retries(Retries::new(10).unwrap())
+        .build()
+        .unwrap();
+    let rng = match env::var("LOSSY_LINK_SEED") {
+        Ok(seed) => SharedRng::seeded(seed.parse().expect("LOSSY_LINK_SEED must be a number")),
+        Err(_) => SharedRng::from_entropy(),
+    };
+    println!("Losses seeded with {} (LOSSY_LINK_SEED to replay)", rng.seed().unwrap());
+    let link = LossyLink {
+        inner: UdpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap(),
+        rng: rng,
+    };
+    let mut received = Vec::new();
+    match client.get_over(link, addr, Path::new("firmware.bin"), Mode::Octet, &mut received) {
+        Ok(params) => {
+            assert_eq!(contents, received);
+            println!("Read firmware.bin over the lossy link ({})", params);
+        }
+        Err(e) => println!("Transfer failed: {}", e),
+    }
+    println!("Client: {}", client.stats().get());
+    // The server may still wait for the last acknowledgment, its counters
+    // are added when the transfer ends.
+    thread::sleep(Duration::from_millis(500));
+    println!("Server: {}", server_stats.get());
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,180 @@
+//! Reads a file using only the protocol core of the crate.
+//!
+//! The transfer is driven by `ReadTransfer` over a minimal datagram interface.
+//! On `wasm32-wasi` the interface is imported from the host (module
+//! `tftp_host`), so the protocol logic can run inside a simulator that owns the
+//! network:
+//!
+//! ```text
+//! cargo build --example wasi-get --no-default-features --target wasm32-wasi
+//! ```
+//!
+//! On other targets the same code runs over a std UDP socket:
+//!
+//! ```text
+//! cargo run --example wasi-get --no-default-features -- 127.0.0.1:69 PATH
+//! ```
+//!
+//! The file is written to stdout.
+
+extern crate tftp;
+
+use std::env;
+use std::io::{self, Write};
+use std::process::exit;
+
+use tftp::packet::{Mode, RequestPacket, DataPacketOctet, ErrorPacket, AckPacket,
+    EncodePacket, DecodePacket};
+use tftp::transfer::{ReadTransfer, DataReceived, Timeout, DEFAULT_BLOCK_SIZE};
+
+/// Datagram interface provided by the environment.
+///
+/// Every datagram goes to and comes from the server, the host is responsible
+/// for addressing.
+trait Datagrams {
+    fn send(&mut self, buf: &[u8]) -> io::Result<()>;
+
+    /// Receives a datagram, returns `None` if none arrived within `timeout_ms`.
+    fn recv(&mut self, buf: &mut [u8], timeout_ms: u32) -> io::Result<Option<usize>>;
+}
+
+#[cfg(target_os = "wasi")]
+mod host {
+    use std::io;
+
+    use super::Datagrams;
+
+    #[link(wasm_import_module = "tftp_host")]
+    extern "C" {
+        /// Returns zero on success, a negative value on failure.
+        fn send(buf: *const u8, len: usize) -> i32;
+
+        /// Returns the datagram length, zero on timeout or a negative value on failure.
+        fn recv(buf: *mut u8, len: usize, timeout_ms: u32) -> i32;
+    }
+
+    pub struct HostDatagrams;
+
+    impl HostDatagrams {
+        pub fn new(_server: &str) -> io::Result<HostDatagrams> {
+            Ok(HostDatagrams)
+        }
+    }
+
+    impl Datagrams for HostDatagrams {
+        fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+            match unsafe { send(buf.as_ptr(), buf.len()) } {
+                n if n < 0 => Err(io::Error::new(io::ErrorKind::Other, "host send failed")),
+                _ => Ok(()),
+            }
+        }
+
+        fn recv(&mut self, buf: &mut [u8], timeout_ms: u32) -> io::Result<Option<usize>> {
+            match unsafe { recv(buf.as_mut_ptr(), buf.len(), timeout_ms) } {
+                n if n < 0 => Err(io::Error::new(io::ErrorKind::Other, "host receive failed")),
+                0 => Ok(None),
+                n => Ok(Some(n as usize)),
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+mod host {
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::time::Duration;
+
+    use super::Datagrams;
+
+    pub struct HostDatagrams {
+        socket: UdpSocket,
+        server: SocketAddr,
+        connected: bool,
+    }
+
+    impl HostDatagrams {
+        pub fn new(server: &str) -> io::Result<HostDatagrams> {
+            let server = try!(server.parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid server address")));
+            Ok(HostDatagrams {
+                socket: try!(UdpSocket::bind("0.0.0.0:0")),
+                server: server,
+                connected: false,
+            })
+        }
+    }
+
+    impl Datagrams for HostDatagrams {
+        fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.socket.send_to(buf, self.server).map(|_| ())
+        }
+
+        fn recv(&mut self, buf: &mut [u8], timeout_ms: u32) -> io::Result<Option<usize>> {
+            try!(self.socket.set_read_timeout(Some(Duration::from_millis(timeout_ms as u64))));
+            match self.socket.recv_from(buf) {
+                Ok((n, addr)) => {
+                    // The server answers from a new port, stick to it.
+                    if !self.connected {
+                        self.server = addr;
+                        self.connected = true;
+                    }
+                    Ok(Some(n))
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                    || e.kind() == io::ErrorKind::TimedOut => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+    }
+}
+
+fn get<D: Datagrams, W: Write>(net: &mut D, path: &str, writer: &mut W) -> io::Result<()> {
+    let mut transfer = ReadTransfer::new(DEFAULT_BLOCK_SIZE);
+    let mut last_sent = RequestPacket::read_request(path, Mode::Octet).encode().packet_buf().to_vec();
+    try!(net.send(&last_sent));
+
+    let mut buf = vec![0; DEFAULT_BLOCK_SIZE + 4];
+    while !transfer.is_done() {
+        let n = match try!(net.recv(&mut buf, 1000)) {
+            Some(n) => n,
+            None => match transfer.timeout() {
+                Timeout::Retransmit => {
+                    try!(net.send(&last_sent));
+                    continue
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::TimedOut, "transfer timed out")),
+            },
+        };
+        if let Some(data) = DataPacketOctet::decode(&buf[..n]) {
+            if let DataReceived::Accepted(ack) = transfer.receive_data(&data) {
+                try!(writer.write_all(data.data()));
+                last_sent = ack.encode().packet_buf().to_vec();
+                try!(net.send(&last_sent));
+            } else {
+                // Duplicate block, our acknowledgment was probably lost.
+                try!(net.send(&last_sent));
+            }
+        } else if let Some(error) = ErrorPacket::decode(&buf[..n]) {
+            return Err(io::Error::new(io::ErrorKind::Other, error.into_owned()))
+        } else if AckPacket::decode(&buf[..n]).is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected acknowledgment"))
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<_> = env::args().collect();
+    if args.len() != 3 {
+        println!("Usage: {} SERVER PATH", args[0]);
+        exit(2)
+    }
+    let stdout = io::stdout();
+    let result = host::HostDatagrams::new(&args[1])
+        .and_then(|mut net| get(&mut net, &args[2], &mut stdout.lock()));
+    if let Err(e) = result {
+        let _ = writeln!(io::stderr(), "{}", e);
+        exit(1)
+    }
+}
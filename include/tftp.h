@@ -0,0 +1,77 @@
+/*
+ * C interface to the tftp-rs client, exported by the library built with the
+ * `ffi` feature:
+ *
+ *     cargo rustc --lib --release --features ffi --crate-type cdylib
+ *
+ * and linked with -ltftp from target/release.
+ */
+
+#ifndef TFTP_H
+#define TFTP_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define TFTP_OK                     0
+#define TFTP_ERR_INVALID_ARGUMENT  -1   /* NULL or non UTF-8 argument */
+#define TFTP_ERR_RESOLVE           -2   /* server address can't be resolved */
+#define TFTP_ERR_INVALID_CONFIG    -3   /* invalid value in tftp_options */
+#define TFTP_ERR_IO                -4   /* socket or local file error */
+#define TFTP_ERR_TIMED_OUT         -5   /* server stopped responding */
+#define TFTP_ERR_PROTOCOL          -6   /* server violated the protocol */
+#define TFTP_ERR_CANCELLED         -7   /* progress callback returned non-zero */
+#define TFTP_ERR_INTERNAL          -8   /* unexpected internal failure */
+#define TFTP_ERR_PORT_UNREACHABLE  -9   /* nothing listens on the server port */
+
+/* Error packets from the server are reported as TFTP_ERR_SERVER_BASE minus
+ * the TFTP error code, e.g. -101 for "file not found". */
+#define TFTP_ERR_SERVER_BASE     -100
+#define TFTP_IS_SERVER_ERROR(code) ((code) <= TFTP_ERR_SERVER_BASE)
+#define TFTP_SERVER_ERROR_CODE(code) (TFTP_ERR_SERVER_BASE - (code))
+
+#define TFTP_MODE_OCTET     0
+#define TFTP_MODE_NETASCII  1
+
+/* Transfer options, a zero field selects the default value. */
+typedef struct tftp_options {
+    int mode;              /* TFTP_MODE_OCTET or TFTP_MODE_NETASCII */
+    uint32_t block_size;   /* block size requested from the server */
+    uint32_t timeout_ms;   /* time to wait before retransmitting */
+    uint32_t retries;      /* retransmissions before the transfer fails */
+} tftp_options;
+
+/* Called with the total number of transferred bytes, returning non-zero
+ * cancels the transfer. */
+typedef int (*tftp_progress_cb)(uint64_t bytes, void *user_data);
+
+/* Fetches remote_path from server ("HOST[:PORT]", port 69 by default) into
+ * local_path. options and progress may be NULL. A partially written file is
+ * removed when the transfer fails. */
+int tftp_client_get(const char *server,
+                    const char *remote_path,
+                    const char *local_path,
+                    const tftp_options *options,
+                    tftp_progress_cb progress,
+                    void *user_data);
+
+/* Sends local_path to server as remote_path. options and progress may be
+ * NULL. */
+int tftp_client_put(const char *server,
+                    const char *local_path,
+                    const char *remote_path,
+                    const tftp_options *options,
+                    tftp_progress_cb progress,
+                    void *user_data);
+
+/* Returns a static description of an error code. */
+const char *tftp_strerror(int code);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* TFTP_H */
@@ -0,0 +1,464 @@
+//! Command line TFTP client.
+//!
+//! ```text
+//! tftp get [OPTIONS] <URL | HOST PATH>
+//! tftp put [OPTIONS] <FILE> <URL | HOST [PATH]>
+//! ```
+
+extern crate tftp;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use tftp::client::{Client, ClientBuilder};
+use tftp::config::{BlockSize, Retries, ReplyPolicy, UnexpectedPacketPolicy, Quirk, Conformance};
+use tftp::filename::FilenameCodec;
+use tftp::packet::Mode;
+
+const USAGE: &'static str = "\
+Usage:
+    tftp get [OPTIONS] <URL | HOST PATH>
+    tftp put [OPTIONS] <FILE> <URL | HOST [PATH]>
+
+URL has the form tftp://HOST[:PORT]/PATH, the default port is 69.
+
+Options:
+    -m, --mode MODE        transfer mode, octet (default) or netascii
+    -b, --blksize BYTES    block size requested from the server
+    -t, --timeout SECONDS  time to wait for a response before retransmitting
+    --negotiate-timeout    ask the server to use the same timeout, sub-second
+                           timeouts need utimeout support of the server
+    -r, --retries COUNT    retransmissions before the transfer fails
+    --deadline SECONDS     time the whole transfer may take
+    -o, --output FILE      file the fetched data is written to, - for stdout
+                           (get only, defaults to the name of the remote file)
+    --max-size BYTES       fail if the fetched file is larger (get only)
+    --any-source           accept the first reply of the server from any address
+    --local-addr ADDR      local address and port the client socket is bound to
+    --happy-eyeballs       send the request to the IPv4 and the IPv6 address
+                           of the host and continue with the first to answer
+    --interface NAME       network interface transfers go through (Linux only)
+    --ignore-unexpected    drop packets the server must not send instead of failing
+    --accept-block-zero    accept servers numbering data blocks from 0 (get only)
+    --quirk NAME           work around a server bug in the option negotiation,
+                           oack-ack-one or repeated-oack, may be repeated
+    --strict               refuse deviations of the server from the RFCs,
+                           overriding the options tolerating them
+    --filename-encoding ENCODING
+                           encoding of the remote file name, utf8 (default),
+                           latin1 or percent
+    -q, --quiet            don't display progress
+    -h, --help             display this help";
+
+/// Time between the requests to the two address families of the server.
+const HAPPY_EYEBALLS_STAGGER: Duration = Duration::from_millis(250);
+
+/// Exit code of a failed transfer.
+const EXIT_FAILURE: i32 = 1;
+
+/// Exit code of invalid command line arguments.
+const EXIT_USAGE: i32 = 2;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Command {
+    Get,
+    Put,
+}
+
+struct Args {
+    command: Command,
+    server_addrs: Vec<SocketAddr>,
+    remote_path: String,
+    local_path: String,
+    mode: Mode,
+    block_size: BlockSize,
+    timeout: Duration,
+    negotiate_timeout: bool,
+    retries: Retries,
+    reply_policy: ReplyPolicy,
+    unexpected_packets: UnexpectedPacketPolicy,
+    max_size: Option<u64>,
+    accept_block_zero: bool,
+    quirks: Vec<Quirk>,
+    conformance: Conformance,
+    filename_codec: FilenameCodec,
+    deadline: Option<Duration>,
+    local_addr: Option<SocketAddr>,
+    interface: Option<String>,
+    happy_eyeballs: bool,
+    quiet: bool,
+}
+
+fn usage_error(msg: &str) -> ! {
+    let _ = writeln!(io::stderr(), "tftp: {}\n\n{}", msg, USAGE);
+    exit(EXIT_USAGE)
+}
+
+fn resolve(host: &str, port: u16) -> Vec<SocketAddr> {
+    let host = host.trim_left_matches('[').trim_right_matches(']');
+    match (host, port).to_socket_addrs().map(|addrs| addrs.collect::<Vec<_>>()) {
+        Ok(ref addrs) if addrs.is_empty() => usage_error(&format!("can't resolve host {}", host)),
+        Ok(addrs) => addrs,
+        Err(_) => usage_error(&format!("can't resolve host {}", host)),
+    }
+}
+
+/// Splits `HOST[:PORT]` into the host and the port.
+fn split_host_port(authority: &str) -> (&str, u16) {
+    let port_start = if authority.starts_with('[') {
+        authority.find(']').map(|end| end + 1)
+    } else {
+        authority.rfind(':')
+    };
+    match port_start {
+        Some(i) if authority[i..].starts_with(':') => {
+            match authority[i + 1..].parse() {
+                Ok(port) => (&authority[..i], port),
+                Err(_) => usage_error(&format!("invalid port in {}", authority)),
+            }
+        }
+        _ => (authority, 69),
+    }
+}
+
+/// Parses the remote file given either as a `tftp://` URL or as a host followed by a path.
+fn parse_remote(positional: &[String]) -> Option<(Vec<SocketAddr>, Option<String>)> {
+    match positional.first() {
+        Some(url) if url.starts_with("tftp://") => {
+            if positional.len() > 1 {
+                return None
+            }
+            let rest = &url["tftp://".len()..];
+            let (authority, path) = match rest.find('/') {
+                Some(i) => (&rest[..i], &rest[i + 1..]),
+                None => (rest, ""),
+            };
+            let (host, port) = split_host_port(authority);
+            let path = if path.is_empty() { None } else { Some(path.to_string()) };
+            Some((resolve(host, port), path))
+        }
+        Some(host) if positional.len() <= 2 => {
+            let (host, port) = split_host_port(host);
+            Some((resolve(host, port), positional.get(1).cloned()))
+        }
+        _ => None,
+    }
+}
+
+fn file_name(path: &str) -> Option<String> {
+    Path::new(path).file_name().and_then(|name| name.to_str()).map(|name| name.to_string())
+}
+
+fn option_value<I: Iterator<Item=String>, T: FromStr>(args: &mut I, name: &str) -> T {
+    match args.next().map(|value| value.parse()) {
+        Some(Ok(value)) => value,
+        Some(Err(_)) => usage_error(&format!("invalid value for {}", name)),
+        None => usage_error(&format!("missing value for {}", name)),
+    }
+}
+
+fn parse_args() -> Args {
+    let mut args = env::args().skip(1);
+    let command = match args.next().as_ref().map(|c| &c[..]) {
+        Some("get") => Command::Get,
+        Some("put") => Command::Put,
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            exit(0)
+        }
+        Some(other) => usage_error(&format!("unknown command {}", other)),
+        None => usage_error("missing command"),
+    };
+
+    let mut mode = Mode::Octet;
+    let mut block_size = BlockSize::default();
+    let mut timeout = None;
+    let mut negotiate_timeout = false;
+    let mut retries = Retries::default();
+    let mut output = None;
+    let mut max_size = None;
+    let mut accept_block_zero = false;
+    let mut quirks = Vec::new();
+    let mut conformance = Conformance::default();
+    let mut filename_codec = FilenameCodec::default();
+    let mut deadline = None;
+    let mut local_addr = None;
+    let mut interface = None;
+    let mut happy_eyeballs = false;
+    let mut quiet = false;
+    let mut reply_policy = ReplyPolicy::default();
+    let mut unexpected_packets = UnexpectedPacketPolicy::default();
+    let mut positional = Vec::new();
+    while let Some(arg) = args.next() {
+        match &arg[..] {
+            "-m" | "--mode" => mode = option_value(&mut args, &arg),
+            "-b" | "--blksize" => {
+                block_size = match BlockSize::new(option_value(&mut args, &arg)) {
+                    Ok(block_size) => block_size,
+                    Err(e) => usage_error(&e.to_string()),
+                }
+            }
+            "-t" | "--timeout" => {
+                let seconds: f64 = option_value(&mut args, &arg);
+                if !(seconds > 0.0) {
+                    usage_error("timeout must be longer than zero");
+                }
+                timeout = Some(Duration::from_millis((seconds * 1000.0) as u64));
+            }
+            "--negotiate-timeout" => negotiate_timeout = true,
+            "--deadline" => {
+                let seconds: f64 = option_value(&mut args, &arg);
+                if !(seconds > 0.0) {
+                    usage_error("deadline must be longer than zero");
+                }
+                deadline = Some(Duration::from_millis((seconds * 1000.0) as u64));
+            }
+            "-r" | "--retries" => {
+                retries = match Retries::new(option_value(&mut args, &arg)) {
+                    Ok(retries) => retries,
+                    Err(e) => usage_error(&e.to_string()),
+                }
+            }
+            "-o" | "--output" => output = Some(option_value::<_, String>(&mut args, &arg)),
+            "--max-size" => max_size = Some(option_value(&mut args, &arg)),
+            "-q" | "--quiet" => quiet = true,
+            "--any-source" => reply_policy = ReplyPolicy::AllowAddressChangeOnFirstReply,
+            "--local-addr" => local_addr = Some(option_value(&mut args, &arg)),
+            "--interface" => interface = Some(option_value::<_, String>(&mut args, &arg)),
+            "--happy-eyeballs" => happy_eyeballs = true,
+            "--ignore-unexpected" => unexpected_packets = UnexpectedPacketPolicy::Ignore,
+            "--accept-block-zero" => accept_block_zero = true,
+            "--quirk" => quirks.push(option_value(&mut args, &arg)),
+            "--strict" => conformance = Conformance::Strict,
+            "--filename-encoding" => filename_codec = option_value(&mut args, &arg),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0)
+            }
+            _ if arg.starts_with('-') && arg != "-" => usage_error(&format!("unknown option {}", arg)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let (server_addrs, remote_path, local_path) = match command {
+        Command::Get => {
+            let (server_addr, remote_path) = match parse_remote(&positional) {
+                Some((addr, Some(path))) => (addr, path),
+                _ => usage_error("expected a URL or a host and a path"),
+            };
+            let local_path = match output.or_else(|| file_name(&remote_path)) {
+                Some(local_path) => local_path,
+                None => usage_error("can't derive the output file name, use --output"),
+            };
+            (server_addr, remote_path, local_path)
+        }
+        Command::Put => {
+            if output.is_some() {
+                usage_error("--output can only be used with get");
+            }
+            if max_size.is_some() {
+                usage_error("--max-size can only be used with get");
+            }
+            if accept_block_zero {
+                usage_error("--accept-block-zero can only be used with get");
+            }
+            let local_path = match positional.first() {
+                Some(local_path) => local_path.clone(),
+                None => usage_error("expected a local file"),
+            };
+            let (server_addr, remote_path) = match parse_remote(&positional[1..]) {
+                Some((addr, path)) => (addr, path.or_else(|| file_name(&local_path))),
+                None => usage_error("expected a URL or a host and an optional path"),
+            };
+            match remote_path {
+                Some(remote_path) => (server_addr, remote_path, local_path),
+                None => usage_error("can't derive the remote file name, pass a path"),
+            }
+        }
+    };
+
+    Args {
+        command: command,
+        server_addrs: server_addrs,
+        remote_path: remote_path,
+        local_path: local_path,
+        mode: mode,
+        block_size: block_size,
+        timeout: timeout.unwrap_or(tftp::config::DEFAULT_TIMEOUT),
+        negotiate_timeout: negotiate_timeout,
+        retries: retries,
+        reply_policy: reply_policy,
+        unexpected_packets: unexpected_packets,
+        max_size: max_size,
+        accept_block_zero: accept_block_zero,
+        quirks: quirks,
+        conformance: conformance,
+        filename_codec: filename_codec,
+        deadline: deadline,
+        local_addr: local_addr,
+        interface: interface,
+        happy_eyeballs: happy_eyeballs,
+        quiet: quiet,
+    }
+}
+
+/// Reports the number of transferred bytes on stderr.
+struct Progress {
+    enabled: bool,
+    bytes: u64,
+    started: Instant,
+    reported: Instant,
+}
+
+impl Progress {
+    fn new(enabled: bool) -> Progress {
+        let now = Instant::now();
+        Progress {
+            enabled: enabled,
+            bytes: 0,
+            started: now,
+            reported: now,
+        }
+    }
+
+    fn add(&mut self, bytes: usize) {
+        let total = self.bytes + bytes as u64;
+        self.add_total(total);
+    }
+
+    fn add_total(&mut self, total: u64) {
+        self.bytes = total;
+        if self.enabled && self.reported.elapsed() >= Duration::from_millis(200) {
+            self.reported = Instant::now();
+            let _ = write!(io::stderr(), "\r{} bytes", self.bytes);
+        }
+    }
+
+    fn finish(&self) {
+        if self.enabled {
+            let elapsed = self.started.elapsed();
+            let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            let _ = writeln!(io::stderr(), "\r{} bytes in {:.2} s", self.bytes, seconds);
+        }
+    }
+}
+
+struct ProgressWriter<W> {
+    inner: W,
+    progress: Progress,
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.progress.add(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.progress.add(n);
+        Ok(n)
+    }
+}
+
+fn get(client: &Client, args: &Args) -> Result<(), tftp::Error> {
+    let remote_path = Path::new(&args.remote_path);
+    if args.local_path == "-" {
+        let stdout = io::stdout();
+        let mut writer = ProgressWriter { inner: stdout.lock(), progress: Progress::new(!args.quiet) };
+        try!(client.get(remote_path, args.mode, &mut writer));
+        try!(writer.flush());
+        writer.progress.finish();
+        return Ok(())
+    }
+    let mut progress = Progress::new(!args.quiet);
+    // The file is replaced once the transfer is complete, a failed transfer
+    // keeps the previous copy.
+    try!(client.get_to_file_with_progress(remote_path, args.mode, Path::new(&args.local_path), &mut |written| {
+        progress.add_total(written)
+    }));
+    progress.finish();
+    Ok(())
+}
+
+fn put(client: &Client, args: &Args) -> Result<(), tftp::Error> {
+    let file = try!(File::open(&args.local_path));
+    let mut reader = ProgressReader { inner: BufReader::new(file), progress: Progress::new(!args.quiet) };
+    try!(client.put(Path::new(&args.remote_path), args.mode, &mut reader));
+    reader.progress.finish();
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_interface(builder: ClientBuilder, interface: &str) -> ClientBuilder {
+    builder.device(interface)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_interface(_: ClientBuilder, _: &str) -> ClientBuilder {
+    usage_error("--interface is only supported on Linux")
+}
+
+fn main() {
+    let args = parse_args();
+    let builder = ClientBuilder::resolve(&args.server_addrs[..]).expect("resolved addresses aren't empty");
+    let mut builder = builder
+        .block_size(args.block_size)
+        .timeout(args.timeout)
+        .negotiate_timeout(args.negotiate_timeout)
+        .retries(args.retries)
+        .reply_policy(args.reply_policy)
+        .unexpected_packets(args.unexpected_packets)
+        .accept_block_zero(args.accept_block_zero)
+        .conformance(args.conformance)
+        .filename_codec(args.filename_codec);
+    for &quirk in &args.quirks {
+        builder = builder.quirk(quirk);
+    }
+    if let Some(max_size) = args.max_size {
+        builder = builder.max_size(max_size);
+    }
+    if let Some(deadline) = args.deadline {
+        builder = builder.deadline(deadline);
+    }
+    if let Some(local_addr) = args.local_addr {
+        builder = builder.local_addr(local_addr);
+    }
+    if let Some(ref interface) = args.interface {
+        builder = bind_to_interface(builder, interface);
+    }
+    if args.happy_eyeballs {
+        builder = builder.happy_eyeballs(HAPPY_EYEBALLS_STAGGER);
+    }
+    let client = builder.build()
+        .unwrap_or_else(|e| usage_error(&e.to_string()));
+    let result = match args.command {
+        Command::Get => get(&client, &args),
+        Command::Put => put(&client, &args),
+    };
+    if let Err(e) = result {
+        let _ = writeln!(io::stderr(), "tftp: {} failed: {}", match args.command {
+            Command::Get => "get",
+            Command::Put => "put",
+        }, e);
+        exit(EXIT_FAILURE)
+    }
+}
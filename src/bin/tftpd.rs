@@ -0,0 +1,299 @@
+//! TFTP server daemon.
+//!
+//! ```text
+//! tftpd [OPTIONS]
+//! ```
+
+extern crate tftp;
+#[macro_use] extern crate log;
+
+use std::cmp;
+use std::env;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::{Log, Level, LevelFilter, Metadata, Record};
+
+use tftp::config::{BlockSize, Conformance, Retries, WindowSize};
+use tftp::server_config::{ListenAddr, ServerConfig};
+
+const USAGE: &'static str = "\
+Usage:
+    tftpd [OPTIONS]
+
+Options:
+    -c, --config FILE       read the configuration from FILE, TOML or JSON
+                            if the name ends with .json, the other options
+                            override it
+    -d, --root DIR          directory files are served from (default: current directory)
+    -l, --listen ADDR       address to listen on (default: 0.0.0.0:69),
+                            unix:PATH for a Unix datagram socket
+        --read-only         reject write requests
+        --directories POLICY
+                            what requests of a directory get: not-found
+                            (default), listing or index:NAME to serve
+                            the file NAME in the directory
+        --symlinks POLICY   symbolic links followed: deny, within-root
+                            (default) or all
+        --max-blksize BYTES largest block size accepted during negotiation
+        --max-windowsize BLOCKS
+                            largest window size accepted during negotiation
+                            (default: 1, every block is acknowledged)
+        --segmentation-offload
+                            send the blocks of a window in one system call
+                            where the kernel supports it (Linux)
+    -t, --timeout SECONDS   time to wait for a response before retransmitting
+    -r, --retries COUNT     retransmissions before a transfer fails
+        --max-transfers COUNT
+                            transfers running at the same time, further
+                            requests wait (default: unlimited)
+        --max-bandwidth BYTES
+                            bytes per second the data packets of all
+                            transfers may take (default: unlimited)
+        --io-threads COUNT  read and write files on COUNT threads instead
+                            of the network thread, for slow disks
+        --share-files       open a file once for the downloads of it
+                            running at the same time, e.g. boot storms
+        --checksums         serve the SHA-256 digest of FILE as FILE.sha256
+                            when there is no such file
+        --fallback PATTERN=FILE
+                            serve FILE for missing files matching PATTERN,
+                            * and ? are wildcards, can be repeated
+        --session-file FILE record running downloads in FILE, a restarted
+                            server resumes them (experimental)
+        --filename-encoding ENCODING
+                            encoding of file names in requests, utf8
+                            (default), latin1 or percent
+        --strict            drop requests and errors of clients that
+                            deviate from the RFCs
+    -v, --verbose           log more, can be repeated
+    -q, --quiet             log only errors
+        --log-format FORMAT plain (default) or journal, which prefixes every
+                            line with a syslog priority for systemd
+    -h, --help              display this help";
+
+/// Exit code of a server failure.
+const EXIT_FAILURE: i32 = 1;
+
+/// Exit code of invalid command line arguments.
+const EXIT_USAGE: i32 = 2;
+
+/// Format of log lines written to stderr.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum LogFormat {
+    /// Level followed by the message, for running in the foreground.
+    Plain,
+    /// Message prefixed with its syslog priority, understood by systemd-journald.
+    Journal,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogFormat, ()> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "journal" => Ok(LogFormat::Journal),
+            _ => Err(()),
+        }
+    }
+}
+
+struct StderrLogger {
+    level: LevelFilter,
+    format: LogFormat,
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Debug output of the event loop drowns out the server's own messages.
+        let level = if metadata.target().starts_with("tftp") {
+            self.level
+        } else {
+            cmp::min(self.level, LevelFilter::Warn)
+        };
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return
+        }
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        let _ = match self.format {
+            LogFormat::Plain => writeln!(stderr, "{:<5} {}", record.level(), record.args()),
+            LogFormat::Journal => {
+                let priority = match record.level() {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                writeln!(stderr, "<{}>{}", priority, record.args())
+            }
+        };
+    }
+
+    fn flush(&self) {}
+}
+
+struct Args {
+    server: ServerConfig,
+    level: LevelFilter,
+    log_format: LogFormat,
+}
+
+fn usage_error(msg: &str) -> ! {
+    let _ = writeln!(io::stderr(), "tftpd: {}\n\n{}", msg, USAGE);
+    exit(EXIT_USAGE)
+}
+
+fn option_value<I: Iterator<Item=String>, T: FromStr>(args: &mut I, name: &str) -> T {
+    match args.next().map(|value| value.parse()) {
+        Some(Ok(value)) => value,
+        Some(Err(_)) => usage_error(&format!("invalid value for {}", name)),
+        None => usage_error(&format!("missing value for {}", name)),
+    }
+}
+
+/// Returns the configuration file given on the command line.
+fn config_file() -> Option<String> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-c" || arg == "--config" {
+            return Some(args.next().unwrap_or_else(|| usage_error(&format!("missing value for {}", arg))))
+        }
+    }
+    None
+}
+
+fn parse_args() -> Args {
+    let server = match config_file() {
+        Some(path) => ServerConfig::load(&path).unwrap_or_else(|e| usage_error(&format!("{}: {}", path, e))),
+        None => ServerConfig::default(),
+    };
+    let mut parsed = Args {
+        server: server,
+        level: LevelFilter::Info,
+        log_format: LogFormat::Plain,
+    };
+    let mut verbosity = 0;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let server = &mut parsed.server;
+        match &arg[..] {
+            "-c" | "--config" => {
+                args.next();
+            }
+            "-d" | "--root" => server.root = PathBuf::from(option_value::<_, String>(&mut args, &arg)),
+            "-l" | "--listen" => server.listen = option_value::<_, ListenAddr>(&mut args, &arg),
+            "--read-only" => server.read_only = true,
+            "--directories" => server.directories = option_value(&mut args, &arg),
+            "--symlinks" => server.symlinks = option_value(&mut args, &arg),
+            "--max-blksize" => {
+                server.max_block_size = match BlockSize::new(option_value(&mut args, &arg)) {
+                    Ok(block_size) => Some(block_size),
+                    Err(e) => usage_error(&e.to_string()),
+                }
+            }
+            "--max-windowsize" => {
+                server.max_window_size = match WindowSize::new(option_value(&mut args, &arg)) {
+                    Ok(window_size) => Some(window_size),
+                    Err(e) => usage_error(&e.to_string()),
+                }
+            }
+            "--segmentation-offload" => server.segmentation_offload = true,
+            "-t" | "--timeout" => {
+                let seconds: f64 = option_value(&mut args, &arg);
+                if !(seconds > 0.0) {
+                    usage_error("timeout must be longer than zero");
+                }
+                server.timeout = Duration::from_millis((seconds * 1000.0) as u64);
+            }
+            "-r" | "--retries" => {
+                server.retries = match Retries::new(option_value(&mut args, &arg)) {
+                    Ok(retries) => retries,
+                    Err(e) => usage_error(&e.to_string()),
+                }
+            }
+            "--max-transfers" => {
+                let max_transfers = option_value(&mut args, &arg);
+                if max_transfers == 0 {
+                    usage_error("max-transfers must be at least 1");
+                }
+                server.max_transfers = Some(max_transfers);
+            }
+            "--max-bandwidth" => {
+                let max_bandwidth = option_value(&mut args, &arg);
+                if max_bandwidth == 0 {
+                    usage_error("max-bandwidth must be at least 1");
+                }
+                server.max_bandwidth = Some(max_bandwidth);
+            }
+            "--io-threads" => {
+                let threads = option_value(&mut args, &arg);
+                if threads == 0 {
+                    usage_error("io-threads must be at least 1");
+                }
+                server.io_threads = Some(threads);
+            }
+            "--share-files" => server.share_files = true,
+            "--checksums" => server.checksums = true,
+            "--fallback" => {
+                let fallback: String = option_value(&mut args, &arg);
+                match fallback.find('=') {
+                    Some(i) if i > 0 && i + 1 < fallback.len() => {
+                        server.fallbacks.push((fallback[..i].to_owned(), fallback[i + 1..].to_owned()))
+                    }
+                    _ => usage_error("fallback must be PATTERN=FILE"),
+                }
+            }
+            "--session-file" => {
+                server.session_file = Some(PathBuf::from(option_value::<_, String>(&mut args, &arg)))
+            }
+            "--filename-encoding" => server.filename_codec = option_value(&mut args, &arg),
+            "--strict" => server.conformance = Conformance::Strict,
+            "-v" | "--verbose" => verbosity += 1,
+            "-q" | "--quiet" => verbosity = -1,
+            "--log-format" => parsed.log_format = option_value(&mut args, &arg),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0)
+            }
+            _ => usage_error(&format!("unknown argument {}", arg)),
+        }
+    }
+    parsed.level = match verbosity {
+        -1 => LevelFilter::Error,
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    if !parsed.server.root.is_dir() {
+        usage_error(&format!("{} is not a directory", parsed.server.root.display()));
+    }
+    parsed
+}
+
+fn main() {
+    let args = parse_args();
+
+    let logger = StderrLogger { level: args.level, format: args.log_format };
+    log::set_max_level(args.level);
+    log::set_logger(Box::leak(Box::new(logger))).expect("logger is set only once");
+
+    info!("Serving {}", args.server.root.display());
+    let builder = args.server.builder().unwrap_or_else(|e| {
+        error!("Failed to start I/O threads: {}", e);
+        exit(EXIT_FAILURE)
+    });
+    let server = builder.build().unwrap_or_else(|e| usage_error(&e.to_string()));
+    if let Err(e) = server.run() {
+        error!("Server failed: {}", e);
+        exit(EXIT_FAILURE)
+    }
+}
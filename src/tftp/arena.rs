@@ -0,0 +1,132 @@
+//! Bump arena of the packet buffers of a server transfer.
+//!
+//! During mass provisioning a server runs thousands of short transfers, each
+//! allocating its receive buffer and the option acknowledgment on its own. An
+//! `Arena` takes them from one chunk instead, in the order they are needed,
+//! and frees them all at once with the transfer. The option acknowledgment is
+//! kept encoded, its option strings are freed as soon as the transfer starts.
+//!
+//! Regions of the arena are offsets into the chunk, so the chunk may grow if
+//! its first size was too small. The bytes allocated at the end of the
+//! transfer are its high-water mark, collected by the `ArenaStats` of the
+//! server.
+
+use std::fmt;
+
+use packet::EncodePacket;
+
+/// Part of an `Arena`, valid until the arena is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    start: usize,
+    len: usize,
+}
+
+impl Region {
+    /// Returns the length of the region in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the region has no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the part of the region from `offset` of `len` bytes.
+    ///
+    /// Panics if the part isn't within the region.
+    pub fn slice(&self, offset: usize, len: usize) -> Region {
+        assert!(offset + len <= self.len, "slice of {}..{} out of a region of {} bytes", offset, offset + len,
+                self.len);
+        Region { start: self.start + offset, len: len }
+    }
+}
+
+/// Chunk of memory regions are bumped off, freed as a whole when dropped.
+pub struct Arena {
+    chunk: Vec<u8>,
+    used: usize,
+}
+
+impl Arena {
+    /// Creates an arena with a chunk of `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> Arena {
+        Arena {
+            chunk: vec![0; capacity],
+            used: 0,
+        }
+    }
+
+    /// Allocates a region of `len` zeroed bytes, growing the chunk if it's
+    /// full.
+    pub fn alloc(&mut self, len: usize) -> Region {
+        let start = self.used;
+        self.used += len;
+        if self.used > self.chunk.len() {
+            self.chunk.resize(self.used, 0);
+        }
+        Region { start: start, len: len }
+    }
+
+    /// Allocates a region holding a copy of `bytes`.
+    pub fn alloc_bytes(&mut self, bytes: &[u8]) -> Region {
+        let region = self.alloc(bytes.len());
+        self.get_mut(region).copy_from_slice(bytes);
+        region
+    }
+
+    /// Allocates a region holding the encoded `packet`.
+    pub fn alloc_packet<P: EncodePacket>(&mut self, packet: &P) -> Region {
+        self.alloc_bytes(packet.encode().packet_buf())
+    }
+
+    /// Returns the bytes of `region`.
+    pub fn get(&self, region: Region) -> &[u8] {
+        &self.chunk[region.start..region.start + region.len]
+    }
+
+    /// Returns the bytes of `region` for writing.
+    pub fn get_mut(&mut self, region: Region) -> &mut [u8] {
+        &mut self.chunk[region.start..region.start + region.len]
+    }
+
+    /// Returns the number of bytes allocated, the high-water mark of the
+    /// arena as regions are only freed with it.
+    pub fn high_water(&self) -> usize {
+        self.used
+    }
+
+    /// Returns the size of the chunk.
+    pub fn capacity(&self) -> usize {
+        self.chunk.len()
+    }
+}
+
+impl fmt::Debug for Arena {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Arena").field("used", &self.used).field("capacity", &self.chunk.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use packet::AckPacket;
+
+    use super::Arena;
+
+    #[test]
+    fn regions_survive_growth() {
+        let mut arena = Arena::with_capacity(8);
+        let first = arena.alloc_bytes(b"abcd");
+        let ack = arena.alloc_packet(&AckPacket::new(7));
+        assert_eq!(8, arena.capacity());
+        let grown = arena.alloc(16);
+        assert_eq!(24, arena.capacity());
+        assert_eq!(b"abcd", arena.get(first));
+        assert_eq!(&[0, 4, 0, 7], arena.get(ack));
+        assert_eq!(&[0; 16][..], arena.get(grown));
+        assert_eq!(b"bcd", arena.get(first.slice(1, 3)));
+        assert_eq!(24, arena.high_water());
+    }
+}
@@ -0,0 +1,652 @@
+//! Asynchronous client running on the tokio-core reactor.
+//!
+//! A download is a `Stream` of the received data blocks and an upload is a
+//! `Sink` accepting the file contents in chunks of any size, so transfers can
+//! be combined with other futures, e.g. `client.get(..)?.forward(file_sink)`.
+//! `get_to` and `put_from` connect a transfer directly to an `AsyncWrite` or
+//! an `AsyncRead`.
+
+use std::cmp;
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use tokio_core::net::UdpSocket;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use config::{BlockSize, Retries, ReplyPolicy, DEFAULT_TIMEOUT};
+use error::{Error, ErrorKind};
+use reason::{Reason, ReasonFormat};
+use packet::{self, Mode, Opcode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket,
+    OptionAckPacket, EncodePacket, DecodePacket, RawPacket};
+use transfer::{self, ReadTransfer, WriteTransfer, DataReceived, AckReceived, TransferParams, DEFAULT_BLOCK_SIZE,
+    request_options, negotiated_block_size};
+
+/// TFTP client running on a tokio-core reactor.
+#[derive(Debug, Clone)]
+pub struct AsyncClient {
+    handle: Handle,
+    server_addr: SocketAddr,
+    local_addr: SocketAddr,
+    block_size: BlockSize,
+    timeout: Duration,
+    retries: Retries,
+    reply_policy: ReplyPolicy,
+}
+
+impl AsyncClient {
+    /// Creates a client for the server at `server_addr` with default settings.
+    pub fn new(handle: &Handle, server_addr: SocketAddr) -> AsyncClient {
+        let local_addr = if server_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        AsyncClient {
+            handle: handle.clone(),
+            server_addr: server_addr,
+            local_addr: local_addr.parse().unwrap(),
+            block_size: BlockSize::default(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: Retries::default(),
+            reply_policy: ReplyPolicy::default(),
+        }
+    }
+
+    /// Sets the local address transfer sockets are bound to.
+    pub fn local_addr(mut self, addr: SocketAddr) -> AsyncClient {
+        self.local_addr = addr;
+        self
+    }
+
+    /// Sets the block size requested from the server.
+    pub fn block_size(mut self, block_size: BlockSize) -> AsyncClient {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the time to wait for a response before retransmitting.
+    pub fn timeout(mut self, timeout: Duration) -> AsyncClient {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of retransmissions before a transfer fails.
+    pub fn retries(mut self, retries: Retries) -> AsyncClient {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the addresses the first reply of the server is accepted from.
+    ///
+    /// By default it must come from the address the request was sent to.
+    pub fn reply_policy(mut self, policy: ReplyPolicy) -> AsyncClient {
+        self.reply_policy = policy;
+        self
+    }
+
+    /// Starts reading the file `path`, the returned stream yields its contents.
+    pub fn get(&self, path: &str, mode: Mode) -> io::Result<Download> {
+        let request = RequestPacket::read_request(path, mode)
+            .with_options(request_options(self.block_size.get()));
+        let connection = try!(Connection::new(self, request.encode()));
+        let mut transfer = ReadTransfer::new(DEFAULT_BLOCK_SIZE);
+        transfer.set_retries(self.retries);
+        Ok(Download {
+            connection: connection,
+            transfer: transfer,
+            block_size: self.block_size.get(),
+            retries: self.retries,
+            block: None,
+        })
+    }
+
+    /// Starts writing the file `path`, the contents are sent to the returned sink.
+    ///
+    /// The transfer completes when the sink is closed.
+    pub fn put(&self, path: &str, mode: Mode) -> io::Result<Upload> {
+        let request = RequestPacket::write_request(path, mode)
+            .with_options(request_options(self.block_size.get()));
+        let connection = try!(Connection::new(self, request.encode()));
+        let mut transfer = WriteTransfer::new(DEFAULT_BLOCK_SIZE);
+        transfer.set_retries(self.retries);
+        Ok(Upload {
+            connection: connection,
+            transfer: transfer,
+            block_size: self.block_size.get(),
+            retries: self.retries,
+            accepted: false,
+            pending: BytesMut::new(),
+            closing: false,
+        })
+    }
+
+    /// Reads the file `path` into `writer`.
+    ///
+    /// The returned future resolves to the flushed writer and the number of written bytes.
+    pub fn get_to<W: AsyncWrite>(&self, path: &str, mode: Mode, writer: W) -> io::Result<GetTo<W>> {
+        Ok(GetTo {
+            download: try!(self.get(path, mode)),
+            writer: Some(writer),
+            block: None,
+            position: 0,
+            bytes: 0,
+        })
+    }
+
+    /// Writes the contents of `reader` to the file `path`.
+    ///
+    /// The returned future resolves to the reader once the server acknowledged
+    /// the last block.
+    pub fn put_from<R: AsyncRead>(&self, path: &str, mode: Mode, reader: R) -> io::Result<PutFrom<R>> {
+        Ok(PutFrom {
+            upload: try!(self.put(path, mode)),
+            reader: Some(reader),
+            buffer: vec![0; self.block_size.get()],
+            chunk: None,
+            eof: false,
+        })
+    }
+}
+
+/// Packet received from the server.
+enum Received {
+    Data(u16, Bytes),
+    Ack(AckPacket),
+    OptionAck(OptionAckPacket<'static>),
+}
+
+/// Socket of a transfer together with the packet that is (re)sent to the server.
+struct Connection {
+    socket: UdpSocket,
+    server_addr: SocketAddr,
+    reply_policy: ReplyPolicy,
+    peer: Option<SocketAddr>,
+    last_sent: RawPacket,
+    send_pending: bool,
+    buffer: Vec<u8>,
+    timeout: Timeout,
+    timeout_duration: Duration,
+}
+
+impl Connection {
+    fn new(client: &AsyncClient, request: RawPacket) -> io::Result<Connection> {
+        let socket = try!(UdpSocket::bind(&client.local_addr, &client.handle));
+        let timeout = try!(Timeout::new(client.timeout, &client.handle));
+        Ok(Connection {
+            socket: socket,
+            server_addr: client.server_addr,
+            reply_policy: client.reply_policy,
+            peer: None,
+            last_sent: request,
+            send_pending: true,
+            buffer: vec![0; cmp::max(client.block_size.get(), DEFAULT_BLOCK_SIZE) + 4],
+            timeout: timeout,
+            timeout_duration: client.timeout,
+        })
+    }
+
+    /// Queues a packet replacing the one retransmitted on timeouts.
+    fn send<P: EncodePacket>(&mut self, packet: &P) {
+        let buffer = ::std::mem::replace(&mut self.last_sent, RawPacket::new(Vec::new(), 0)).into_buffer();
+        self.last_sent = packet.encode_using(buffer);
+        self.send_pending = true;
+    }
+
+    /// Sends the queued packet, returns `NotReady` until the socket accepts it.
+    fn flush(&mut self) -> Poll<(), Error> {
+        if self.send_pending {
+            let addr = self.peer.unwrap_or(self.server_addr);
+            try_nb!(self.socket.send_to(self.last_sent.packet_buf(), &addr));
+            self.send_pending = false;
+            self.timeout.reset(Instant::now() + self.timeout_duration);
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// Receives the next packet of the transfer.
+    ///
+    /// Returns `Ok(Async::Ready(None))` when the retransmission timeout expired.
+    fn receive(&mut self) -> Poll<Option<Received>, Error> {
+        loop {
+            let (n, from) = match self.socket.recv_from(&mut self.buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if try!(self.timeout.poll()).is_not_ready() {
+                        return Ok(Async::NotReady)
+                    }
+                    return Ok(Async::Ready(None))
+                }
+                Err(e) => return Err(From::from(e)),
+            };
+            match self.peer {
+                Some(peer) if peer != from => {
+                    let error = ReasonFormat::default().error_packet(packet::Error::UnknownTransferId,
+                                                                     Reason::UnknownTransferId, "unknown transfer ID");
+                    let _ = self.socket.send_to(error.encode().packet_buf(), &from);
+                    continue
+                }
+                None if self.reply_policy == ReplyPolicy::SameAddress && from.ip() != self.server_addr.ip() => continue,
+                _ => {}
+            }
+            if n < 2 {
+                continue
+            }
+            let packet = &self.buffer[..n];
+            let received = match Opcode::from_u16((packet[0] as u16) << 8 | packet[1] as u16) {
+                Some(Opcode::DATA) => DataPacketOctet::decode(packet)
+                    .map(|data| Received::Data(data.block_id(), Bytes::from(data.data()))),
+                Some(Opcode::ACK) => AckPacket::decode(packet).map(Received::Ack),
+                Some(Opcode::OACK) if self.peer.is_none() => OptionAckPacket::decode(packet)
+                    .map(|oack| Received::OptionAck(OptionAckPacket::new(oack.options().clone().into_owned()))),
+                Some(Opcode::ERROR) => match ErrorPacket::decode(packet) {
+                    Some(error) => {
+                        let error = error.into_owned();
+                        return Err(Error::new(ErrorKind::ServerError(error.error()), error))
+                    }
+                    None => None,
+                },
+                _ => None,
+            };
+            if let Some(received) = received {
+                self.peer = Some(from);
+                return Ok(Async::Ready(Some(received)))
+            }
+        }
+    }
+
+    /// Terminates the transfer because the server acknowledged options the client can't accept.
+    fn reject_options(&mut self, reason: &'static str) -> Error {
+        let error = ReasonFormat::default().error_packet(packet::Error::OptionNegotiation, Reason::OptionNegotiation,
+                                                         reason);
+        if let Some(peer) = self.peer {
+            let _ = self.socket.send_to(error.encode().packet_buf(), &peer);
+        }
+        Error::new(ErrorKind::Protocol, reason)
+    }
+}
+
+fn timed_out() -> Error {
+    From::from(io::Error::new(io::ErrorKind::TimedOut, "transfer timed out"))
+}
+
+/// Contents of a file read from the server.
+///
+/// Every received block is acknowledged before it is yielded, the stream ends
+/// after the last block.
+pub struct Download {
+    connection: Connection,
+    transfer: ReadTransfer,
+    block_size: usize,
+    retries: Retries,
+    block: Option<Bytes>,
+}
+
+impl Download {
+    /// Returns the parameters negotiated with the server, `None` until the
+    /// server responded.
+    pub fn params(&self) -> Option<TransferParams> {
+        self.connection.peer.map(|_| TransferParams::new(self.transfer.block_size(), self.connection.timeout_duration))
+    }
+}
+
+impl Stream for Download {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        loop {
+            try_ready!(self.connection.flush());
+            if let Some(block) = self.block.take() {
+                return Ok(Async::Ready(Some(block)))
+            }
+            if self.transfer.is_done() {
+                return Ok(Async::Ready(None))
+            }
+            match try_ready!(self.connection.receive()) {
+                Some(Received::Data(block_id, data)) => {
+                    let packet = DataPacketOctet::from_slice(block_id, &data);
+                    match self.transfer.receive_data(&packet) {
+                        DataReceived::Accepted(ack) => {
+                            self.connection.send(&ack);
+                            if !data.is_empty() {
+                                self.block = Some(data);
+                            }
+                        }
+                        DataReceived::Duplicate(ack) => self.connection.send(&ack),
+                        DataReceived::Ignored => {}
+                    }
+                }
+                Some(Received::OptionAck(oack)) => {
+                    let block_size = match negotiated_block_size(self.block_size, &oack) {
+                        Ok(block_size) => block_size,
+                        Err(reason) => return Err(self.connection.reject_options(reason)),
+                    };
+                    self.transfer = ReadTransfer::new(block_size);
+                    self.transfer.set_retries(self.retries);
+                    self.connection.send(&AckPacket::new(0));
+                }
+                Some(Received::Ack(_)) => {}
+                None => match self.transfer.timeout() {
+                    transfer::Timeout::Retransmit => self.connection.send_pending = true,
+                    _ => return Err(timed_out()),
+                },
+            }
+        }
+    }
+}
+
+/// Contents of a file written to the server.
+///
+/// Data shorter than a block is kept until more data arrives or the sink is
+/// closed, closing sends the last block and completes once the server
+/// acknowledged it.
+pub struct Upload {
+    connection: Connection,
+    transfer: WriteTransfer,
+    block_size: usize,
+    retries: Retries,
+    accepted: bool,
+    pending: BytesMut,
+    closing: bool,
+}
+
+impl Upload {
+    /// Returns the parameters negotiated with the server, `None` until the
+    /// server accepted the request.
+    pub fn params(&self) -> Option<TransferParams> {
+        if self.accepted {
+            Some(TransferParams::new(self.transfer.block_size(), self.connection.timeout_duration))
+        } else {
+            None
+        }
+    }
+
+    /// Advances the transfer, returns `Ready` when nothing can be sent until more data arrives.
+    fn drive(&mut self) -> Poll<(), Error> {
+        loop {
+            try_ready!(self.connection.flush());
+            if self.transfer.is_done() {
+                return Ok(Async::Ready(()))
+            }
+            if self.accepted && self.transfer.can_send() {
+                let block_size = self.transfer.block_size();
+                if self.pending.len() < block_size && !self.closing {
+                    return Ok(Async::Ready(()))
+                }
+                let len = cmp::min(block_size, self.pending.len());
+                let chunk = self.pending.split_to(len);
+                let packet = try!(self.transfer.next_block(&mut &chunk[..]));
+                self.connection.send(&packet);
+                continue
+            }
+            match try_ready!(self.connection.receive()) {
+                Some(Received::OptionAck(oack)) => {
+                    if self.accepted {
+                        continue
+                    }
+                    let block_size = match negotiated_block_size(self.block_size, &oack) {
+                        Ok(block_size) => block_size,
+                        Err(reason) => return Err(self.connection.reject_options(reason)),
+                    };
+                    self.transfer = WriteTransfer::new(block_size);
+                    self.transfer.set_retries(self.retries);
+                    self.accepted = true;
+                }
+                Some(Received::Ack(ack)) => match self.transfer.receive_ack(&ack) {
+                    AckReceived::Next => self.accepted = true,
+                    AckReceived::Done | AckReceived::Ignored => {}
+                    AckReceived::Rewind(_) => {
+                        return Err(Error::new(ErrorKind::Protocol, "unexpected acknowledgment"))
+                    }
+                },
+                Some(Received::Data(..)) => {}
+                None => match self.transfer.timeout() {
+                    transfer::Timeout::Retransmit => self.connection.send_pending = true,
+                    // Sent data is not kept to start over, uploads set no fallback.
+                    transfer::Timeout::Fallback(_) => unreachable!(),
+                    _ => return Err(timed_out()),
+                },
+            }
+        }
+    }
+}
+
+impl Sink for Upload {
+    type SinkItem = Bytes;
+    type SinkError = Error;
+
+    fn start_send(&mut self, item: Bytes) -> StartSend<Bytes, Error> {
+        if self.closing {
+            return Err(Error::new(ErrorKind::Cancelled, "upload is closed"))
+        }
+        if self.pending.len() >= self.transfer.block_size() {
+            try!(self.drive());
+            if self.pending.len() >= self.transfer.block_size() {
+                return Ok(AsyncSink::NotReady(item))
+            }
+        }
+        self.pending.extend_from_slice(&item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        self.drive()
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        // While closing the transfer is driven until the last block is acknowledged.
+        self.closing = true;
+        self.drive()
+    }
+}
+
+/// Future reading a file into an `AsyncWrite`, created by `AsyncClient::get_to`.
+pub struct GetTo<W> {
+    download: Download,
+    writer: Option<W>,
+    block: Option<Bytes>,
+    position: usize,
+    bytes: u64,
+}
+
+impl<W> GetTo<W> {
+    /// Returns the parameters negotiated with the server, see `Download::params`.
+    pub fn params(&self) -> Option<TransferParams> {
+        self.download.params()
+    }
+}
+
+impl<W: AsyncWrite> Future for GetTo<W> {
+    type Item = (W, u64);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(W, u64), Error> {
+        loop {
+            if let Some(ref block) = self.block {
+                let writer = self.writer.as_mut().expect("GetTo polled after completion");
+                while self.position < block.len() {
+                    let n = try_ready!(writer.poll_write(&block[self.position..]));
+                    if n == 0 {
+                        return Err(From::from(io::Error::new(io::ErrorKind::WriteZero, "writer accepted no data")))
+                    }
+                    self.position += n;
+                    self.bytes += n as u64;
+                }
+            }
+            self.block = None;
+            match try_ready!(self.download.poll()) {
+                Some(block) => {
+                    self.block = Some(block);
+                    self.position = 0;
+                }
+                None => {
+                    try_ready!(self.writer.as_mut().expect("GetTo polled after completion").poll_flush());
+                    return Ok(Async::Ready((self.writer.take().unwrap(), self.bytes)))
+                }
+            }
+        }
+    }
+}
+
+/// Future writing the contents of an `AsyncRead` to a file, created by `AsyncClient::put_from`.
+pub struct PutFrom<R> {
+    upload: Upload,
+    reader: Option<R>,
+    buffer: Vec<u8>,
+    chunk: Option<Bytes>,
+    eof: bool,
+}
+
+impl<R> PutFrom<R> {
+    /// Returns the parameters negotiated with the server, see `Upload::params`.
+    pub fn params(&self) -> Option<TransferParams> {
+        self.upload.params()
+    }
+}
+
+impl<R: AsyncRead> Future for PutFrom<R> {
+    type Item = R;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<R, Error> {
+        loop {
+            if let Some(chunk) = self.chunk.take() {
+                if let AsyncSink::NotReady(chunk) = try!(self.upload.start_send(chunk)) {
+                    // The upload is waiting for an acknowledgment and wakes the task.
+                    self.chunk = Some(chunk);
+                    return Ok(Async::NotReady)
+                }
+            }
+            if self.eof {
+                try_ready!(self.upload.close());
+                return Ok(Async::Ready(self.reader.take().expect("PutFrom polled after completion")))
+            }
+            let reader = self.reader.as_mut().expect("PutFrom polled after completion");
+            match try_ready!(reader.poll_read(&mut self.buffer)) {
+                0 => self.eof = true,
+                n => self.chunk = Some(Bytes::from(&self.buffer[..n])),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::net::UdpSocket;
+    use std::thread;
+
+    use futures::{Future, Sink, Stream};
+    use tokio_core::reactor::Core;
+
+    use config::{BlockSize, DEFAULT_TIMEOUT};
+    use packet::{Mode, RequestPacket, DataPacketOctet, AckPacket, OptionAckPacket, TransferOptions, EncodePacket,
+                 DecodePacket};
+    use super::AsyncClient;
+
+    /// Answers one request from a new socket and returns the datagrams received on it.
+    fn serve<F: FnOnce(&UdpSocket, &RequestPacket) + Send + 'static>(f: F) -> UdpSocket {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = listener.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buf = vec![0; 1024];
+            let (n, client) = server.recv_from(&mut buf).unwrap();
+            let request = RequestPacket::decode(&buf[..n]).unwrap();
+            let transfer = UdpSocket::bind("127.0.0.1:0").unwrap();
+            transfer.connect(client).unwrap();
+            f(&transfer, &request);
+        });
+        listener
+    }
+
+    fn receive_ack(socket: &UdpSocket) -> u16 {
+        let mut buf = [0; 4];
+        let n = socket.recv(&mut buf).unwrap();
+        AckPacket::decode(&buf[..n]).unwrap().block_id()
+    }
+
+    #[test]
+    fn download_yields_blocks() {
+        let listener = serve(|socket, request| {
+            assert_eq!(b"file", request.filename_raw());
+            let block = vec![1; 512];
+            socket.send(DataPacketOctet::from_slice(1, &block).encode().packet_buf()).unwrap();
+            assert_eq!(1, receive_ack(socket));
+            socket.send(DataPacketOctet::from_slice(2, &[2, 3]).encode().packet_buf()).unwrap();
+            assert_eq!(2, receive_ack(socket));
+        });
+        let mut core = Core::new().unwrap();
+        let client = AsyncClient::new(&core.handle(), listener.local_addr().unwrap());
+        let blocks = core.run(client.get("file", Mode::Octet).unwrap().collect()).unwrap();
+        assert_eq!(vec![512, 2], blocks.iter().map(|b| b.len()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn negotiated_parameters_are_exposed() {
+        let listener = serve(|socket, request| {
+            assert_eq!(Some("1024"), request.options().get("blksize"));
+            let mut options = TransferOptions::new();
+            options.insert("blksize", "800");
+            socket.send(OptionAckPacket::new(options).encode().packet_buf()).unwrap();
+            assert_eq!(0, receive_ack(socket));
+            socket.send(DataPacketOctet::from_slice(1, &[1; 10]).encode().packet_buf()).unwrap();
+            assert_eq!(1, receive_ack(socket));
+        });
+        let mut core = Core::new().unwrap();
+        let client = AsyncClient::new(&core.handle(), listener.local_addr().unwrap())
+            .block_size(BlockSize::new(1024).unwrap());
+        let mut download = client.get("file", Mode::Octet).unwrap();
+        assert_eq!(None, download.params());
+        core.run(download.by_ref().collect()).unwrap();
+        let params = download.params().unwrap();
+        assert_eq!((800, DEFAULT_TIMEOUT, 1, None),
+                   (params.block_size, params.timeout, params.window_size.get(), params.transfer_size));
+    }
+
+    #[test]
+    fn upload_sends_blocks_when_closed() {
+        let listener = serve(|socket, _| {
+            socket.send(AckPacket::new(0).encode().packet_buf()).unwrap();
+            let mut buf = vec![0; 1024];
+            for &(block_id, len) in &[(1, 512), (2, 88)] {
+                let n = socket.recv(&mut buf).unwrap();
+                let data = DataPacketOctet::decode(&buf[..n]).unwrap();
+                assert_eq!((block_id, len), (data.block_id(), data.data().len()));
+                socket.send(AckPacket::new(block_id).encode().packet_buf()).unwrap();
+            }
+        });
+        let mut core = Core::new().unwrap();
+        let client = AsyncClient::new(&core.handle(), listener.local_addr().unwrap());
+        let upload = client.put("file", Mode::Octet).unwrap();
+        let upload = core.run(upload.send(vec![0; 300].into()).and_then(|u| u.send(vec![0; 300].into()))).unwrap();
+        core.run(upload.flush().and_then(|mut u| ::futures::future::poll_fn(move || u.close()))).unwrap();
+    }
+
+    #[test]
+    fn download_is_written_to_async_writer() {
+        let listener = serve(|socket, _| {
+            socket.send(DataPacketOctet::from_slice(1, b"hello").encode().packet_buf()).unwrap();
+            assert_eq!(1, receive_ack(socket));
+        });
+        let mut core = Core::new().unwrap();
+        let client = AsyncClient::new(&core.handle(), listener.local_addr().unwrap());
+        let get = client.get_to("file", Mode::Octet, Cursor::new(Vec::new())).unwrap();
+        let (writer, bytes) = core.run(get).unwrap();
+        assert_eq!(5, bytes);
+        assert_eq!(b"hello", &writer.into_inner()[..]);
+    }
+
+    #[test]
+    fn upload_is_read_from_async_reader() {
+        let listener = serve(|socket, _| {
+            socket.send(AckPacket::new(0).encode().packet_buf()).unwrap();
+            let mut buf = vec![0; 1024];
+            let n = socket.recv(&mut buf).unwrap();
+            assert_eq!(b"hello", DataPacketOctet::decode(&buf[..n]).unwrap().data());
+            socket.send(AckPacket::new(1).encode().packet_buf()).unwrap();
+        });
+        let mut core = Core::new().unwrap();
+        let client = AsyncClient::new(&core.handle(), listener.local_addr().unwrap());
+        core.run(client.put_from("file", Mode::Octet, Cursor::new(b"hello")).unwrap()).unwrap();
+    }
+}
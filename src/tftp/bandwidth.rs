@@ -0,0 +1,330 @@
+//! Cap of the bandwidth the data packets of a server take.
+//!
+//! A boot server sharing an uplink with production traffic can saturate it
+//! during a boot storm. A `BandwidthCap` limits the bytes per second of the
+//! data packets of all transfers together: a transfer waits for its turn
+//! before sending a block, and a turn comes once the blocks sent before took
+//! their time at the capped rate. Waiting transfers of a higher `Priority`
+//! take their turns first, transfers of the same priority in the order they
+//! asked, so the busy transfers of a class share the rate block by block and
+//! transfers with equal block sizes get equal shares. Option acknowledgments,
+//! acknowledgments and errors are small and not capped.
+//!
+//! The cap is changed with `ServerHandle::set_max_bandwidth`, or with
+//! `BandwidthCap::set_rate` on a clone, while the server runs, e.g. from a
+//! thread following the load of the uplink. Waiting transfers take their
+//! turns at the new rate.
+
+use std::cmp::{self, Reverse};
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
+
+use handler::Priority;
+
+/// Egress budget of the data packets of one or more servers, clones share
+/// the budget.
+#[derive(Debug, Clone)]
+pub struct BandwidthCap(Arc<Mutex<Budget>>);
+
+#[derive(Debug)]
+struct Budget {
+    /// Bytes per second, `None` if unlimited.
+    rate: Option<u64>,
+    /// Time the blocks sent so far took at the rate.
+    next_free: Instant,
+    /// Bytes of the data packets charged to the budget.
+    bytes: u64,
+    /// Transfers waiting for their turn.
+    waiting: Vec<Waiter>,
+    /// Tickets handed out so far, they order the waiters of a priority.
+    tickets: u64,
+}
+
+#[derive(Debug)]
+struct Waiter {
+    ticket: u64,
+    priority: Priority,
+    task: Task,
+}
+
+/// Turn of a block of a transfer.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum Turn {
+    /// The block may be sent right away.
+    Now,
+    /// The transfer is the next to send, at the time unless the rate changes.
+    At(Instant),
+    /// Other transfers send first, the task is notified once it's next.
+    Behind,
+}
+
+impl Budget {
+    /// Returns the ticket of the waiter to send next.
+    fn next(&self) -> Option<u64> {
+        self.waiting.iter().max_by_key(|waiter| (waiter.priority, Reverse(waiter.ticket))).map(|waiter| waiter.ticket)
+    }
+
+    /// Removes the waiter of `ticket` and lets the next waiter wait for its
+    /// turn.
+    fn leave(&mut self, ticket: u64) {
+        self.waiting.retain(|waiter| waiter.ticket != ticket);
+        let next = self.next();
+        if let Some(waiter) = self.waiting.iter().find(|waiter| Some(waiter.ticket) == next) {
+            waiter.task.notify();
+        }
+    }
+}
+
+impl BandwidthCap {
+    /// Creates a cap of `bytes_per_sec` bytes per second.
+    pub fn new(bytes_per_sec: u64) -> BandwidthCap {
+        let cap = BandwidthCap::unlimited();
+        cap.set_rate(Some(bytes_per_sec));
+        cap
+    }
+
+    /// Creates a budget without a cap, only counting the bytes.
+    pub fn unlimited() -> BandwidthCap {
+        BandwidthCap(Arc::new(Mutex::new(Budget {
+            rate: None,
+            next_free: Instant::now(),
+            bytes: 0,
+            waiting: Vec::new(),
+            tickets: 0,
+        })))
+    }
+
+    /// Changes the cap to `bytes_per_sec` bytes per second, `None` removes it.
+    ///
+    /// A rate of 0 is taken as 1 byte per second.
+    pub fn set_rate(&self, bytes_per_sec: Option<u64>) {
+        let mut budget = self.lock();
+        budget.rate = bytes_per_sec.map(|rate| cmp::max(rate, 1));
+        budget.next_free = Instant::now();
+        for waiter in &budget.waiting {
+            waiter.task.notify();
+        }
+    }
+
+    /// Returns the cap in bytes per second, `None` if there is none.
+    pub fn rate(&self) -> Option<u64> {
+        self.lock().rate
+    }
+
+    /// Returns the bytes of the data packets sent under this budget,
+    /// retransmissions included.
+    ///
+    /// A packet is counted when its turn comes, before it is sent.
+    pub fn bytes(&self) -> u64 {
+        self.lock().bytes
+    }
+
+    fn lock(&self) -> MutexGuard<Budget> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Asks for the turn of a packet of `len` bytes of a transfer of
+    /// `priority`, `ticket` keeps the place of the transfer while it waits.
+    ///
+    /// Must be called from a task, it is notified when the transfer is next.
+    fn take_turn(&self, ticket: &mut Option<u64>, priority: Priority, len: usize, now: Instant) -> Turn {
+        let mut budget = self.lock();
+        let rate = match budget.rate {
+            Some(rate) => rate,
+            None => {
+                if let Some(ticket) = ticket.take() {
+                    budget.leave(ticket);
+                }
+                budget.bytes += len as u64;
+                return Turn::Now
+            }
+        };
+        let mine = match *ticket {
+            Some(mine) => mine,
+            None => {
+                budget.tickets += 1;
+                budget.tickets
+            }
+        };
+        *ticket = Some(mine);
+        match budget.waiting.iter_mut().position(|waiter| waiter.ticket == mine) {
+            Some(i) => budget.waiting[i].task = task::current(),
+            None => budget.waiting.push(Waiter {
+                ticket: mine,
+                priority: priority,
+                task: task::current(),
+            }),
+        }
+        if budget.next() != Some(mine) {
+            return Turn::Behind
+        }
+        if budget.next_free > now {
+            return Turn::At(budget.next_free)
+        }
+        *ticket = None;
+        let nanos = len as u64 * 1_000_000_000 / rate;
+        budget.next_free = now + Duration::from_nanos(nanos);
+        budget.bytes += len as u64;
+        budget.leave(mine);
+        Turn::Now
+    }
+
+    /// Gives up the place of a transfer that stopped waiting.
+    fn leave(&self, ticket: &mut Option<u64>) {
+        if let Some(ticket) = ticket.take() {
+            self.lock().leave(ticket);
+        }
+    }
+}
+
+impl Default for BandwidthCap {
+    /// Returns a budget without a cap.
+    fn default() -> BandwidthCap {
+        BandwidthCap::unlimited()
+    }
+}
+
+/// Waits for the turns of the blocks of one transfer.
+pub(crate) struct Pacer {
+    cap: BandwidthCap,
+    handle: Handle,
+    priority: Priority,
+    timer: Option<Timeout>,
+    /// Place of the transfer among the waiting ones.
+    ticket: Option<u64>,
+    /// The turn of the current packet came and it wasn't sent yet.
+    granted: bool,
+}
+
+impl Pacer {
+    pub fn new(cap: BandwidthCap, handle: &Handle) -> Pacer {
+        Pacer {
+            cap: cap,
+            handle: handle.clone(),
+            priority: Priority::default(),
+            timer: None,
+            ticket: None,
+            granted: false,
+        }
+    }
+
+    /// Changes the priority the turns of the next packets are taken with.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// Resolves once a packet of `len` bytes may be sent, the packet must be
+    /// reported with `sent` before the next one is paced.
+    pub fn poll_turn(&mut self, len: usize) -> Poll<(), io::Error> {
+        if self.granted {
+            return Ok(Async::Ready(()))
+        }
+        loop {
+            match self.cap.take_turn(&mut self.ticket, self.priority, len, Instant::now()) {
+                Turn::Now => {
+                    self.granted = true;
+                    return Ok(Async::Ready(()))
+                }
+                Turn::Behind => return Ok(Async::NotReady),
+                Turn::At(at) => {
+                    match self.timer {
+                        Some(ref mut timer) => timer.reset(at),
+                        None => self.timer = Some(try!(Timeout::new_at(at, &self.handle))),
+                    }
+                    let timer = self.timer.as_mut().expect("the turn is awaited with a timer");
+                    if try!(timer.poll()).is_not_ready() {
+                        return Ok(Async::NotReady)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Records that the packet whose turn came was sent.
+    pub fn sent(&mut self) {
+        self.granted = false;
+    }
+}
+
+impl Drop for Pacer {
+    fn drop(&mut self) {
+        self.cap.leave(&mut self.ticket);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use futures::{future, Future};
+
+    use handler::Priority;
+    use super::{BandwidthCap, Turn};
+
+    /// Runs `f` in a task, turns notify the task of a waiting transfer.
+    fn in_task<F: FnOnce()>(f: F) {
+        future::lazy(|| {
+            f();
+            Ok::<(), ()>(())
+        }).wait().unwrap();
+    }
+
+    #[test]
+    fn blocks_take_turns_at_the_capped_rate() {
+        let cap = BandwidthCap::new(1000);
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        in_task(|| {
+            // Two transfers sending 500 byte packets alternately.
+            let (mut a, mut b) = (None, None);
+            assert_eq!(Turn::Now, cap.take_turn(&mut a, Priority::Normal, 500, now));
+            assert_eq!(Turn::At(now + ms(500)), cap.take_turn(&mut b, Priority::Normal, 500, now));
+            assert_eq!(Turn::Behind, cap.take_turn(&mut a, Priority::Normal, 500, now + ms(100)));
+            assert_eq!(Turn::Now, cap.take_turn(&mut b, Priority::Normal, 500, now + ms(500)));
+            assert_eq!(Turn::At(now + ms(1000)), cap.take_turn(&mut a, Priority::Normal, 500, now + ms(500)));
+            assert_eq!(1000, cap.bytes());
+            // Idle time isn't saved up for bursts.
+            let later = now + Duration::from_secs(10);
+            assert_eq!(Turn::Now, cap.take_turn(&mut a, Priority::Normal, 500, later));
+            assert_eq!(Turn::At(later + ms(500)), cap.take_turn(&mut b, Priority::Normal, 500, later));
+
+            // A new rate applies to the waiting transfers right away.
+            let shared = cap.clone();
+            shared.set_rate(Some(2000));
+            let changed = Instant::now();
+            assert_eq!(Turn::Now, cap.take_turn(&mut b, Priority::Normal, 500, changed));
+            assert_eq!(Turn::At(changed + ms(250)), cap.take_turn(&mut a, Priority::Normal, 500, changed));
+            shared.set_rate(None);
+            assert_eq!(None, cap.rate());
+            assert_eq!(Turn::Now, cap.take_turn(&mut a, Priority::Normal, 500, changed));
+            assert_eq!(2500, cap.bytes());
+        });
+    }
+
+    #[test]
+    fn higher_classes_take_turns_first() {
+        let cap = BandwidthCap::new(1000);
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        in_task(|| {
+            let (mut bulk, mut other_bulk, mut boot) = (None, None, None);
+            assert_eq!(Turn::Now, cap.take_turn(&mut bulk, Priority::Bulk, 500, now));
+            assert_eq!(Turn::At(now + ms(500)), cap.take_turn(&mut other_bulk, Priority::Bulk, 500, now));
+            // A boot file asking later still sends first.
+            assert_eq!(Turn::At(now + ms(500)), cap.take_turn(&mut boot, Priority::Critical, 500, now + ms(100)));
+            assert_eq!(Turn::Behind, cap.take_turn(&mut other_bulk, Priority::Bulk, 500, now + ms(500)));
+            assert_eq!(Turn::Now, cap.take_turn(&mut boot, Priority::Critical, 500, now + ms(500)));
+            assert_eq!(Turn::At(now + ms(1000)), cap.take_turn(&mut other_bulk, Priority::Bulk, 500, now + ms(500)));
+
+            // A transfer that stops waiting gives up its place.
+            assert_eq!(Turn::At(now + ms(1000)), cap.take_turn(&mut boot, Priority::Critical, 500, now + ms(600)));
+            cap.leave(&mut boot);
+            assert_eq!(Turn::Now, cap.take_turn(&mut other_bulk, Priority::Bulk, 500, now + ms(1000)));
+        });
+    }
+}
@@ -0,0 +1,286 @@
+//! Batched datagram I/O.
+//!
+//! On Linux multiple datagrams are sent or received with a single `sendmmsg` or
+//! `recvmmsg` system call, which matters when a socket serves many concurrent
+//! transfers. On other platforms the functions fall back to sending and receiving
+//! one datagram at a time.
+//!
+//! `send_segments` sends datagrams of equal length to one peer, like the data
+//! packets of a window. On Linux they are passed to the kernel in one `sendmsg`
+//! with UDP segmentation offload (GSO), the kernel or the network card splits
+//! them into datagrams, which saves CPU per packet when serving many clients.
+//! Servers send the windows of downloads this way with
+//! `ServerBuilder::segmentation_offload`. Receive offload (GRO) isn't used,
+//! the receivers of windows read one datagram at a time.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+/// Receives up to `bufs.len()` datagrams, one into each buffer.
+///
+/// Blocks (unless the socket is non-blocking) until at least one datagram is
+/// available and returns the length and source of each received datagram, in
+/// the order of the filled buffers.
+pub fn recv_batch(socket: &UdpSocket, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+    if bufs.is_empty() {
+        return Ok(Vec::new())
+    }
+    imp::recv_batch(socket, bufs)
+}
+
+/// Sends each datagram to its destination address.
+///
+/// Returns the number of datagrams sent, which can be less than `datagrams.len()`.
+pub fn send_batch(socket: &UdpSocket, datagrams: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+    if datagrams.is_empty() {
+        return Ok(0)
+    }
+    imp::send_batch(socket, datagrams)
+}
+
+/// Sends each of `segments` as a datagram to `addr`.
+///
+/// All segments but the last must have the same length, the last can be
+/// shorter. Where segmentation offload isn't available, e.g. on kernels
+/// before Linux 4.18 or routes without checksum offload, the segments are
+/// sent with `send_batch` and offload isn't tried again.
+///
+/// Returns the number of segments sent, which can be less than `segments.len()`.
+pub fn send_segments(socket: &UdpSocket, segments: &[&[u8]], addr: SocketAddr) -> io::Result<usize> {
+    if segments.is_empty() {
+        return Ok(0)
+    }
+    try!(check_segments(segments));
+    imp::send_segments(socket, segments, addr)
+}
+
+/// Sends each of `segments` like `send_segments` over a socket of another
+/// runtime, e.g. the socket of a server transfer.
+#[cfg(target_os = "linux")]
+pub(crate) fn send_segments_over<S: AsRawFd>(socket: &S, segments: &[&[u8]], addr: SocketAddr)
+                                             -> io::Result<usize> {
+    if segments.is_empty() {
+        return Ok(0)
+    }
+    try!(check_segments(segments));
+    imp::send_segments(socket, segments, addr)
+}
+
+fn check_segments(segments: &[&[u8]]) -> io::Result<()> {
+    let len = segments[0].len();
+    let (last, rest) = segments.split_last().expect("segments are checked to be non-empty");
+    if last.len() > len || rest.iter().any(|segment| segment.len() != len) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "segments differ in length"))
+    }
+    Ok(())
+}
+
+fn datagrams<'a>(segments: &[&'a [u8]], addr: SocketAddr) -> Vec<(&'a [u8], SocketAddr)> {
+    segments.iter().map(|&segment| (segment, addr)).collect()
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    extern crate libc;
+
+    use std::cmp;
+    use std::io;
+    use std::mem;
+    use std::ptr;
+    use std::net::{SocketAddr, UdpSocket};
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use sys::{to_socket_addr, from_socket_addr};
+
+    /// Most segments the kernel accepts in one send (`UDP_MAX_SEGMENTS`).
+    const MAX_SEGMENTS: usize = 64;
+
+    /// Largest payload of a UDP datagram over IPv4, the limit of one send.
+    const MAX_OFFLOAD_LEN: usize = 65507;
+
+    /// Set once the kernel rejected segmentation offload.
+    static OFFLOAD_UNSUPPORTED: AtomicBool = AtomicBool::new(false);
+
+    pub fn recv_batch(socket: &UdpSocket, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        let n = bufs.len();
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; n];
+        let mut iovecs: Vec<libc::iovec> = bufs.iter_mut().map(|buf| {
+            libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            }
+        }).collect();
+        let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(n);
+        for i in 0..n {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = &mut iovecs[i];
+            msg.msg_hdr.msg_iovlen = 1;
+            msgs.push(msg);
+        }
+
+        let received = unsafe {
+            libc::recvmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), n as libc::c_uint,
+                           libc::MSG_WAITFORONE, ptr::null_mut())
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        let mut result = Vec::with_capacity(received as usize);
+        for i in 0..received as usize {
+            let addr = try!(to_socket_addr(&addrs[i]));
+            result.push((msgs[i].msg_len as usize, addr));
+        }
+        Ok(result)
+    }
+
+    pub fn send_batch<S: AsRawFd>(socket: &S, datagrams: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let n = datagrams.len();
+        let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> = datagrams.iter()
+            .map(|&(_, ref addr)| from_socket_addr(addr))
+            .collect();
+        let mut iovecs: Vec<libc::iovec> = datagrams.iter().map(|&(buf, _)| {
+            libc::iovec {
+                iov_base: buf.as_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            }
+        }).collect();
+        let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(n);
+        for i in 0..n {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = &mut addrs[i].0 as *mut _ as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = addrs[i].1;
+            msg.msg_hdr.msg_iov = &mut iovecs[i];
+            msg.msg_hdr.msg_iovlen = 1;
+            msgs.push(msg);
+        }
+
+        let sent = unsafe {
+            libc::sendmmsg(socket.as_raw_fd(), msgs.as_mut_ptr(), n as libc::c_uint, 0)
+        };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    }
+
+    pub fn send_segments<S: AsRawFd>(socket: &S, segments: &[&[u8]], addr: SocketAddr) -> io::Result<usize> {
+        let len = segments[0].len();
+        let n = cmp::min(segments.len(), cmp::min(MAX_SEGMENTS, MAX_OFFLOAD_LEN / cmp::max(len, 1)));
+        if n < 2 || len == 0 || OFFLOAD_UNSUPPORTED.load(Ordering::Relaxed) {
+            return send_batch(socket, &super::datagrams(segments, addr))
+        }
+        let (mut storage, storage_len) = from_socket_addr(&addr);
+        let mut iovecs: Vec<libc::iovec> = segments[..n].iter().map(|segment| {
+            libc::iovec {
+                iov_base: segment.as_ptr() as *mut libc::c_void,
+                iov_len: segment.len(),
+            }
+        }).collect();
+        // Aligned for the header of the control message.
+        let mut control = [0u64; 4];
+
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut storage as *mut _ as *mut libc::c_void;
+        msg.msg_namelen = storage_len;
+        msg.msg_iov = iovecs.as_mut_ptr();
+        msg.msg_iovlen = n as _;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(mem::size_of::<u16>() as libc::c_uint) } as _;
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as libc::c_uint) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, len as u16);
+        }
+
+        if unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) } >= 0 {
+            return Ok(n)
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINVAL) | Some(libc::ENOPROTOOPT) | Some(libc::EOPNOTSUPP) | Some(libc::EIO) => {
+                OFFLOAD_UNSUPPORTED.store(true, Ordering::Relaxed);
+                send_batch(socket, &super::datagrams(segments, addr))
+            }
+            _ => Err(e),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+    use std::net::{SocketAddr, UdpSocket};
+
+    pub fn recv_batch(socket: &UdpSocket, bufs: &mut [Vec<u8>]) -> io::Result<Vec<(usize, SocketAddr)>> {
+        socket.recv_from(&mut bufs[0]).map(|received| vec![received])
+    }
+
+    pub fn send_batch(socket: &UdpSocket, datagrams: &[(&[u8], SocketAddr)]) -> io::Result<usize> {
+        let mut sent = 0;
+        for &(buf, ref addr) in datagrams {
+            match socket.send_to(buf, addr) {
+                Ok(_) => sent += 1,
+                Err(ref e) if sent > 0 && e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sent)
+    }
+
+    pub fn send_segments(socket: &UdpSocket, segments: &[&[u8]], addr: SocketAddr) -> io::Result<usize> {
+        send_batch(socket, &super::datagrams(segments, addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+
+    use super::{recv_batch, send_batch, send_segments};
+
+    #[test]
+    fn batch_of_datagrams_is_sent_and_received() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        let datagrams = [(&b"foo"[..], addr), (&b"barbaz"[..], addr)];
+        assert_eq!(2, send_batch(&sender, &datagrams).unwrap());
+
+        let mut bufs = vec![vec![0; 16], vec![0; 16]];
+        let mut received = Vec::new();
+        while received.len() < 2 {
+            let batch = recv_batch(&receiver, bufs.split_at_mut(received.len()).1).unwrap();
+            received.extend(batch);
+        }
+        assert_eq!(&b"foo"[..], &bufs[0][..received[0].0]);
+        assert_eq!(&b"barbaz"[..], &bufs[1][..received[1].0]);
+        assert_eq!(sender.local_addr().unwrap(), received[1].1);
+    }
+
+    #[test]
+    fn segments_are_received_as_datagrams() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = receiver.local_addr().unwrap();
+
+        let segments = [&b"abcd"[..], &b"efgh"[..], &b"ij"[..]];
+        assert_eq!(3, send_segments(&sender, &segments, addr).unwrap());
+        let mut buf = [0; 16];
+        for segment in &segments {
+            let (n, _) = receiver.recv_from(&mut buf).unwrap();
+            assert_eq!(*segment, &buf[..n]);
+        }
+        let uneven = [&b"ab"[..], &b"cde"[..]];
+        assert!(send_segments(&sender, &uneven, addr).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+//! Probing of the protocol features a server supports.
+//!
+//! `probe` sends a series of crafted read requests to a server, one per
+//! feature, and reports how it answered each of them: the transfer options
+//! it acknowledges, the modes it accepts and whether it rejects invalid
+//! requests with an error. This is a diagnostic for interoperability problems
+//! with unknown, e.g. embedded, TFTP stacks, the report is meant to be
+//! attached to bug reports (`CapabilityReport::to_json`).
+//!
+//! Every probe is a read request from a new port, started transfers are
+//! terminated with an error packet as soon as the server answered. The probed
+//! file should exist and be readable, errors like "file not found" don't tell
+//! whether the server supports an option.
+
+use std::fmt::{self, Write};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use client::{Error, Result};
+use packet::{self, DecodePacket, EncodePacket, ErrorPacket, OptionAckPacket, Opcode, TransferOptions, BLKSIZE_OPTION,
+             TIMEOUT_OPTION, TSIZE_OPTION, UTIMEOUT_OPTION, WINDOWSIZE_OPTION};
+use reason::{Reason, ReasonFormat};
+
+/// Requests sent for each probe before the server is considered silent.
+const ATTEMPTS: u32 = 3;
+
+/// Largest response that is read, data packets are only recognized.
+const MAX_RESPONSE: usize = 1024;
+
+/// Names of the option probes, the options and the requested values.
+const OPTION_PROBES: &'static [(&'static str, &'static str, &'static str)] = &[
+    ("blksize", BLKSIZE_OPTION, "1024"),
+    ("tsize", TSIZE_OPTION, "0"),
+    ("timeout", TIMEOUT_OPTION, "3"),
+    ("windowsize", WINDOWSIZE_OPTION, "4"),
+    ("utimeout", UTIMEOUT_OPTION, "500000"),
+];
+
+/// Answer of the server to a probe request.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Response {
+    /// The server started the transfer with the first data block, ignoring
+    /// any options.
+    Data,
+
+    /// The server acknowledged these options (RFC 2347).
+    OptionAck(TransferOptions<'static>),
+
+    /// The server refused the request.
+    Error(packet::Error, String),
+
+    /// The server didn't answer any of the requests.
+    Silent,
+
+    /// The server answered with a packet that isn't a valid response to a
+    /// read request.
+    Invalid(Vec<u8>),
+}
+
+/// Conclusion drawn from a response.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Support {
+    /// The server supports the feature.
+    Yes,
+
+    /// The server doesn't support the feature.
+    No,
+
+    /// The response doesn't tell, e.g. the probed file doesn't exist.
+    Unknown,
+}
+
+impl Support {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Support::Yes => "yes",
+            Support::No => "no",
+            Support::Unknown => "unknown",
+        }
+    }
+}
+
+/// Outcome of one probe.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Probe {
+    /// Name of the probed feature, e.g. `blksize` or `mode-netascii`.
+    pub name: &'static str,
+
+    /// Answer of the server.
+    pub response: Response,
+
+    /// Whether the server supports the feature.
+    pub support: Support,
+}
+
+/// Features of a server found by `probe`.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct CapabilityReport {
+    /// Address the requests were sent to.
+    pub server: SocketAddr,
+
+    /// Outcomes in the order the probes were sent.
+    pub probes: Vec<Probe>,
+}
+
+impl CapabilityReport {
+    /// Returns whether the server supports the feature `name`, `Unknown` if
+    /// it wasn't probed.
+    ///
+    /// The probed features are:
+    ///
+    /// - `rfc1350` - the server answers a plain read request
+    /// - `options` - the server acknowledges options at all (RFC 2347)
+    /// - `blksize` - block size option (RFC 2348)
+    /// - `tsize`, `timeout` - transfer size and timeout options (RFC 2349)
+    /// - `windowsize` - window size option (RFC 7440)
+    /// - `utimeout` - timeout in microseconds, nonstandard
+    /// - `mode-netascii`, `mode-mail` - transfer modes of RFC 1350
+    /// - `mode-case-insensitive` - the mode `OcTeT` is accepted as octet
+    /// - `rejects-unknown-mode` - a request with an invalid mode is answered
+    ///   with an error
+    /// - `rejects-empty-filename` - a request for a file without a name is
+    ///   answered with an error
+    pub fn supports(&self, name: &str) -> Support {
+        if name == "options" {
+            return self.supports_options()
+        }
+        self.probes.iter().find(|probe| probe.name == name).map_or(Support::Unknown, |probe| probe.support)
+    }
+
+    /// Options are supported if any option was acknowledged.
+    fn supports_options(&self) -> Support {
+        let options = self.probes.iter().filter(|probe| OPTION_PROBES.iter().any(|&(name, _, _)| name == probe.name));
+        options.fold(Support::Unknown, |support, probe| match (support, probe.support) {
+            (Support::Yes, _) | (_, Support::Yes) => Support::Yes,
+            (_, Support::No) => Support::No,
+            (support, Support::Unknown) => support,
+        })
+    }
+
+    /// Returns the report as a JSON object, e.g.
+    ///
+    /// ```text
+    /// {"server":"192.0.2.1:69","options":"yes","probes":[
+    ///   {"name":"rfc1350","support":"yes","response":"data"},
+    ///   {"name":"blksize","support":"yes","response":"oack","options":{"blksize":"1024"}},
+    ///   {"name":"mode-mail","support":"no","response":"error","code":4,"message":"Illegal mode"}]}
+    /// ```
+    ///
+    /// without the line breaks. `response` is one of `data`, `oack`,
+    /// `error`, `silent` and `invalid`, invalid responses include the packet
+    /// in hex as `packet`.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        let _ = self.write_json(&mut json);
+        json
+    }
+
+    fn write_json(&self, json: &mut String) -> fmt::Result {
+        try!(write!(json, "{{\"server\":\"{}\",\"options\":\"{}\",\"probes\":[", self.server,
+                    self.supports_options().as_str()));
+        for (i, probe) in self.probes.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            try!(write!(json, "{{\"name\":\"{}\",\"support\":\"{}\",\"response\":", probe.name,
+                        probe.support.as_str()));
+            match probe.response {
+                Response::Data => json.push_str("\"data\""),
+                Response::OptionAck(ref options) => {
+                    json.push_str("\"oack\",\"options\":{");
+                    for (i, &(ref name, ref value)) in options.iter().enumerate() {
+                        if i > 0 {
+                            json.push(',');
+                        }
+                        push_json_string(json, name);
+                        json.push(':');
+                        push_json_string(json, value);
+                    }
+                    json.push('}');
+                }
+                Response::Error(error, ref message) => {
+                    try!(write!(json, "\"error\",\"code\":{},\"message\":", error as u16));
+                    push_json_string(json, message);
+                }
+                Response::Silent => json.push_str("\"silent\""),
+                Response::Invalid(ref packet) => {
+                    json.push_str("\"invalid\",\"packet\":\"");
+                    for byte in packet {
+                        try!(write!(json, "{:02x}", byte));
+                    }
+                    json.push('"');
+                }
+            }
+            json.push('}');
+        }
+        json.push_str("]}");
+        Ok(())
+    }
+}
+
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Probes the features of the server at `server_addr` by reading `filename`.
+///
+/// Every request is sent up to 3 times, `timeout` apart, before the server
+/// is considered silent. Fails only if the local socket fails, answers the
+/// server doesn't give are part of the report.
+pub fn probe(server_addr: SocketAddr, filename: &str, timeout: Duration) -> Result<CapabilityReport> {
+    let mut probes = Vec::new();
+    let base = try!(send_probe(server_addr, filename, "octet", &[], timeout));
+    let answered = base != Response::Silent;
+    let support = match base {
+        Response::Data | Response::Error(..) => Support::Yes,
+        Response::OptionAck(_) | Response::Invalid(_) => Support::No,
+        Response::Silent => Support::Unknown,
+    };
+    probes.push(Probe { name: "rfc1350", response: base, support: support });
+
+    for &(name, option, value) in OPTION_PROBES {
+        let response = try!(send_probe(server_addr, filename, "octet", &[(option, value)], timeout));
+        let support = match response {
+            Response::OptionAck(ref acknowledged) if acknowledged.get(option).is_some() => Support::Yes,
+            Response::OptionAck(_) | Response::Data => Support::No,
+            Response::Error(packet::Error::OptionNegotiation, _) => Support::No,
+            Response::Silent if answered => Support::No,
+            _ => Support::Unknown,
+        };
+        probes.push(Probe { name: name, response: response, support: support });
+    }
+
+    let modes = [("mode-netascii", "netascii"), ("mode-mail", "mail"), ("mode-case-insensitive", "OcTeT")];
+    for &(name, mode) in &modes {
+        let response = try!(send_probe(server_addr, filename, mode, &[], timeout));
+        let support = match response {
+            Response::Data | Response::OptionAck(_) => Support::Yes,
+            Response::Error(packet::Error::FileNotFound, _) | Response::Error(packet::Error::AccessViolation, _) => {
+                Support::Unknown
+            }
+            Response::Error(..) | Response::Invalid(_) => Support::No,
+            Response::Silent if answered => Support::No,
+            Response::Silent => Support::Unknown,
+        };
+        probes.push(Probe { name: name, response: response, support: support });
+    }
+
+    let invalid = [("rejects-unknown-mode", filename, "binary"), ("rejects-empty-filename", "", "octet")];
+    for &(name, filename, mode) in &invalid {
+        let response = try!(send_probe(server_addr, filename, mode, &[], timeout));
+        let support = match response {
+            Response::Error(..) => Support::Yes,
+            Response::Data | Response::OptionAck(_) | Response::Invalid(_) => Support::No,
+            Response::Silent if answered => Support::No,
+            Response::Silent => Support::Unknown,
+        };
+        probes.push(Probe { name: name, response: response, support: support });
+    }
+    Ok(CapabilityReport { server: server_addr, probes: probes })
+}
+
+/// Encodes a read request, unlike `RequestPacket` any mode can be sent.
+fn read_request(filename: &str, mode: &str, options: &[(&str, &str)]) -> Vec<u8> {
+    let mut request = vec![0, Opcode::RRQ as u8];
+    let mut fields = vec![filename, mode];
+    for &(name, value) in options {
+        fields.push(name);
+        fields.push(value);
+    }
+    for field in fields {
+        request.extend_from_slice(field.as_bytes());
+        request.push(0);
+    }
+    request
+}
+
+/// Sends the request from a new socket and returns the first answer.
+fn send_probe(server_addr: SocketAddr, filename: &str, mode: &str, options: &[(&str, &str)], timeout: Duration)
+              -> Result<Response> {
+    let local_addr = if server_addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = try!(UdpSocket::bind(local_addr));
+    try!(socket.set_read_timeout(Some(timeout)));
+    let request = read_request(filename, mode, options);
+    let mut buf = [0; MAX_RESPONSE];
+    for _ in 0..ATTEMPTS {
+        try!(socket.send_to(&request, server_addr));
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(Error::Io(e)),
+        };
+        let packet = &buf[..n];
+        let opcode = if n >= 2 { Opcode::from_u16((packet[0] as u16) << 8 | packet[1] as u16) } else { None };
+        let response = match opcode {
+            Some(Opcode::DATA) if n >= 4 => Response::Data,
+            Some(Opcode::OACK) => match OptionAckPacket::decode(packet) {
+                Some(oack) => Response::OptionAck(oack.options().clone().into_owned()),
+                None => Response::Invalid(packet.to_vec()),
+            },
+            Some(Opcode::ERROR) => match ErrorPacket::decode(packet) {
+                Some(error) => {
+                    let message = error.message().map(|message| message.into_owned()).unwrap_or_default();
+                    return Ok(Response::Error(error.error(), message))
+                }
+                None => Response::Invalid(packet.to_vec()),
+            },
+            _ => Response::Invalid(packet.to_vec()),
+        };
+        // The server started a transfer, it's terminated right away.
+        let error = ReasonFormat::default().error_packet(packet::Error::Undefined, Reason::Probe, "capability probe");
+        let _ = socket.send_to(error.encode().packet_buf(), from);
+        return Ok(response)
+    }
+    Ok(Response::Silent)
+}
+
+#[cfg(all(test, feature = "tokio-server"))]
+mod test {
+    use std::cmp;
+    use std::env;
+    use std::fs;
+    use std::net::UdpSocket;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    use config;
+    use server::ServerBuilder;
+    use super::{probe, Response, Support};
+
+    #[test]
+    fn features_of_the_server_are_reported() {
+        let root = env::temp_dir().join(format!("tftp-capabilities-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("file"), vec![7; 2000]).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let server_root = root.clone();
+        thread::spawn(move || ServerBuilder::new(addr).root(server_root).build().unwrap().run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let report = probe(addr, "file", Duration::from_millis(200)).unwrap();
+        assert_eq!(addr, report.server);
+        assert_eq!(Support::Yes, report.supports("rfc1350"));
+        assert_eq!(Support::Yes, report.supports("options"));
+        assert_eq!(Support::Yes, report.supports("blksize"));
+        assert_eq!(Support::Yes, report.supports("timeout"));
+        assert_eq!(Support::No, report.supports("windowsize"));
+        assert_eq!(Support::Yes, report.supports("mode-netascii"));
+        assert_eq!(Support::Yes, report.supports("rejects-empty-filename"));
+        assert_eq!(Support::Unknown, report.supports("nonexistent"));
+        let windowsize = report.probes.iter().find(|probe| probe.name == "windowsize").unwrap();
+        assert_eq!(Response::Data, windowsize.response);
+
+        let json = report.to_json();
+        assert!(json.starts_with(&format!("{{\"server\":\"{}\",\"options\":\"yes\",\"probes\":[{{\"name\":\"rfc1350\",\"support\":\"yes\",\
+                                           \"response\":\"data\"}}", addr)));
+        // Builds limited to smaller blocks acknowledge their largest one.
+        let block_size = cmp::min(1024, config::MAX_BLOCK_SIZE);
+        assert!(json.contains(&format!("{{\"name\":\"blksize\",\"support\":\"yes\",\"response\":\"oack\",\
+                                        \"options\":{{\"blksize\":\"{}\"}}}}", block_size)));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,23 +1,107 @@
 //! A Trivial File Transfer (TFTP) protocol client implementation.
 //!
 //! This module contains the ability to read data from or write data to a remote TFTP server.
+//!
+//! The writer of a read transfer or the reader of a write transfer can stop the
+//! transfer early by failing with an `Abort`, the server is then notified with
+//! an error packet. Other errors of the writer terminate the transfer on the
+//! server too, a full disk is reported with the disk full error code.
+//!
+//! A transfer failing after data was delivered returns `Error::Interrupted`
+//! with the `Progress` made, wrapping the error that stopped it.
+//!
+//! When the server's transfer port is closed, e.g. because the server was
+//! stopped, the ICMP port unreachable message the host sends back is reported
+//! on the connected socket and the transfer fails right away with
+//! `Error::PortUnreachable` instead of waiting for its timeouts.
+//!
+//! `Client::get_to_file` writes the file to a temporary file next to it and
+//! renames it into place once complete. The temporary names are random and
+//! taken only if no file has them, so concurrent fetches of one file don't
+//! write into each other's data, temporary files left behind by crashed
+//! fetches are removed once they are older than `ClientBuilder::stale_temp_age`.
+//! The names are drawn from `ClientBuilder::rng`, a seeded generator names
+//! them the same in every run.
+//!
+//! `get` and `put` borrow the writer or reader of the transfer. `get_into` and
+//! `put_owned` take ownership of it instead and return it with the result of
+//! the transfer, so a clone of the client can run the transfer on another
+//! thread and hand the data back, also what was written before a failure.
+//!
+//! Many legacy servers reject any option with an option negotiation error.
+//! The request is then sent once more without options, unless disabled with
+//! `ClientBuilder::option_fallback`, and the transfer uses the defaults of
+//! RFC 1350. `ProtocolStats::option_fallbacks` counts the fallbacks.
+//!
+//! Blocks larger than the path MTU are sent in fragments, and networks that
+//! drop fragments lose every such block. `ClientBuilder::block_size_fallback`
+//! restarts an upload whose blocks keep timing out with a smaller block size
+//! instead of failing it.
+//!
+//! `ClientBuilder::window_size` asks the server for windows of several blocks
+//! (RFC 7440), of uploads and downloads. After blocks get lost uploads pause
+//! between windows until they are acknowledged without loss again.
+//!
+//! With `ClientBuilder::journal` each transfer keeps a bounded `Journal` of
+//! its last events, a failed transfer returns it in `Error::Journaled` to
+//! diagnose failures that can't be reproduced.
+//!
+//! A writer that stalls stops the acknowledgments of a read transfer until
+//! the server gives up. `ClientBuilder::spool` buffers the data between the
+//! transfer and the writer, see `spool`.
+//!
+//! With the `compression` feature `ClientBuilder::decompress` writes gzip and
+//! zstd compressed downloads out decompressed.
+//!
+//! With the `experimental-resume` feature `ClientBuilder::resumable` transfers
+//! continue after the address of the client changed, see `resume`.
+//!
+//! A server whose host name resolves to IPv4 and IPv6 addresses can be reached
+//! with `ClientBuilder::happy_eyeballs` (RFC 8305): the request of a transfer
+//! is sent to the address of one family and shortly after to the other, the
+//! transfer continues with the family that answers first. The transfer the
+//! other family's server may have started fails once its packets hit the
+//! closed socket.
 
+use std::borrow::Cow;
+use std::cmp;
 use std::convert::From;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
 use std::io;
-use std::path::Path;
-use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
 use std::result;
-use std::str;
 use std::mem;
+use std::time::{Duration, Instant};
 
-use packet::{Mode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket,
-    EncodePacket, RawPacket, Opcode};
+use packet::{self, Mode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket, OptionAckPacket, TransferOptions,
+    EncodePacket, DecodePacket, RawPacket, Opcode, BLKSIZE_OPTION, TIMEOUT_OPTION, TSIZE_OPTION,
+    UTIMEOUT_OPTION, WINDOWSIZE_OPTION};
 use decodedpacket::DecodedPacket;
+use config::{self, BlockSize, Retries, ReplyPolicy, UnexpectedPacketPolicy, Quirk, Conformance, ConfigError,
+             WindowSize, DEFAULT_TIMEOUT};
+use transfer::{self, AckWindow, ReadTransfer, WriteTransfer, DataReceived, AckReceived, TransferParams,
+    BlockSizeFallback, DEFAULT_BLOCK_SIZE, request_options, request_timeout, request_window_size, negotiated_options,
+    negotiated_window_size};
+use transport::{self, PacketTooLarge, Tap, Tapped, Transport, UdpTransport, send_packet};
+use source::{BlockSource, ReadSource};
+use ratelimit::RateLimit;
+use reason::{Reason, ReasonFormat};
+use stats::{Stats, Recorder};
+#[cfg(feature = "compression")]
+use decompress::ContentDecoder;
+#[cfg(feature = "experimental-resume")]
+use resume;
+use filename::FilenameCodec;
+use journal::{Event, Journal};
+use replay::Direction;
+use rng::SharedRng;
+use spool::{self, Overflow, Spool};
 
-use mio::udp::UdpSocket;
-use mio::{Events, Poll, PollOpt, Event, Token, Ready};
-
-static MAX_DATA_SIZE: usize = 512;
+use mio::event::Source;
+use mio::{Events, Interest, Poll, Registry, Token};
 
 quick_error! {
     #[derive(Debug)]
@@ -34,192 +118,3621 @@ quick_error! {
             display("Server error: {}", err)
             cause(err)
         }
+        Protocol(reason: &'static str) {
+            description("protocol error")
+            display("Protocol error: {}", reason)
+        }
+        PortUnreachable(err: io::Error) {
+            description("port unreachable")
+            display("Server port is unreachable: {}", err)
+            cause(err)
+        }
+        Aborted(abort: Abort) {
+            description("transfer aborted")
+            display("Transfer aborted: {}", abort)
+        }
+        Write(err: io::Error, written: u64) {
+            description("write error")
+            display("Write error after {} bytes: {}", written, err)
+            cause(err)
+        }
+        TooLarge(max_size: u64) {
+            description("file too large")
+            display("File is larger than {} bytes", max_size)
+        }
+        PacketTooLarge(err: PacketTooLarge) {
+            from()
+            description("packet too large")
+            display("Protocol error: {}", err)
+        }
+        Interrupted(progress: Progress, err: Box<Error>) {
+            description("transfer interrupted")
+            display("Transfer interrupted after {}: {}", progress, err)
+            cause(&**err)
+        }
+        Journaled(journal: Journal, err: Box<Error>) {
+            description("transfer failed")
+            display("{}\n{}", err, journal)
+            cause(&**err)
+        }
+        SpoolOverflow(max_bytes: u64) {
+            description("spool overflow")
+            display("Writer fell more than the spool of {} bytes behind", max_bytes)
+        }
+    }
+}
+
+/// Data a transfer delivered before it failed.
+///
+/// Callers use it to decide whether to resume the transfer, start over or
+/// remove the incomplete copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes handed to the writer of a read transfer, or acknowledged by the
+    /// server in a write transfer.
+    pub bytes: u64,
+
+    /// Complete blocks written out or acknowledged by the server.
+    pub blocks: u64,
+
+    /// `true` if the writer of a read transfer was flushed after the failure,
+    /// always `false` in write transfers.
+    pub flushed: bool,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} bytes ({} blocks)", self.bytes, self.blocks)
+    }
+}
+
+/// Reason for stopping a transfer early, sent to the server in an error packet.
+///
+/// Writers and readers passed to the client abort the transfer by returning
+/// an `Abort` converted into an `io::Error`, e.g. when the local disk is full or
+/// the first block shows the file is already up to date. The transfer then
+/// fails with `Error::Aborted`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Abort {
+    error: packet::Error,
+    message: String,
+}
+
+impl Abort {
+    /// Creates an abort sending `error` with `message` to the server.
+    pub fn new<M: Into<String>>(error: packet::Error, message: M) -> Abort {
+        Abort {
+            error: error,
+            message: message.into(),
+        }
+    }
+
+    /// Returns the error code sent to the server.
+    pub fn error(&self) -> packet::Error {
+        self.error
+    }
+
+    /// Returns the message sent to the server.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Extracts an abort from an error returned by a writer or a reader.
+    fn from_io_error(err: io::Error) -> result::Result<Abort, io::Error> {
+        if err.get_ref().map_or(false, |inner| inner.is::<Abort>()) {
+            let inner = err.into_inner().unwrap();
+            Ok(*inner.downcast::<Abort>().unwrap())
+        } else {
+            Err(err)
+        }
+    }
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.error)
     }
 }
 
-type Result<T> = result::Result<T, Error>;
+impl error::Error for Abort {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
 
-trait PacketSender {
-    fn send_read_request(&self, path: &str, mode: Mode) -> Result<()>;
-    fn send_ack(&mut self, block_id: u16) -> Result<Option<()>>;
+impl From<Abort> for io::Error {
+    fn from(abort: Abort) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, abort)
+    }
 }
 
-trait PacketReceiver {
-    fn receive_data(&mut self) -> Result<Option<DecodedPacket<DataPacketOctet<'static>>>>;
+/// Result of a client operation.
+pub type Result<T> = result::Result<T, Error>;
+
+/// Packet received from the server.
+enum Received {
+    Data(DecodedPacket<DataPacketOctet<'static>>),
+    Ack(AckPacket),
+    OptionAck(DecodedPacket<OptionAckPacket<'static>>),
+    /// Request sent to the transfer identifier of the client.
+    Request,
 }
 
-struct InternalClient {
-    socket: UdpSocket,
-    remote_addr: SocketAddr,
-    buffer_data: Option<Vec<u8>>,
-    buffer_ack: Vec<u8>,
+struct InternalClient<T: Transport> {
+    socket: T,
+    remote_addr: T::Addr,
+    /// Address requests are sent to.
+    server_addr: T::Addr,
+    /// Transfer identifier of the server the transfer abandoned to restart.
+    abandoned: Option<T::Addr>,
+    reply_policy: ReplyPolicy,
+    unexpected_packets: UnexpectedPacketPolicy,
+    conformance: Conformance,
+    connected: bool,
+    buffer_receive: Option<Vec<u8>>,
+    buffer_send: Vec<u8>,
+    reasons: ReasonFormat,
+    wrong_tid_limit: RateLimit,
+    stats: Recorder,
+    /// Time the packet the server should respond to was sent, if it was
+    /// sent once.
+    sent_at: Option<Instant>,
+    journal: Option<Journal>,
+    /// Request continuing the transfer from another address of the client,
+    /// once the server acknowledged a session token.
+    #[cfg(feature = "experimental-resume")]
+    resume_request: Option<Vec<u8>>,
 }
 
-impl InternalClient {
-    fn new(socket: UdpSocket, remote_addr: SocketAddr) -> InternalClient {
+impl<T: Transport> InternalClient<T> {
+    fn new(socket: T, remote_addr: T::Addr, reply_policy: ReplyPolicy, unexpected_packets: UnexpectedPacketPolicy,
+           block_size: usize, reasons: ReasonFormat, stats: Stats) -> InternalClient<T> {
         InternalClient {
             socket: socket,
+            server_addr: remote_addr.clone(),
+            abandoned: None,
             remote_addr: remote_addr,
-            buffer_data: Some(vec![0; MAX_DATA_SIZE + 4]),
-            buffer_ack: vec![0; MAX_DATA_SIZE + 4],
+            reply_policy: reply_policy,
+            unexpected_packets: unexpected_packets,
+            conformance: Conformance::default(),
+            connected: false,
+            // Option acknowledgments and errors may be longer than small blocks.
+            buffer_receive: Some(transport::receive_buffer(cmp::max(block_size, DEFAULT_BLOCK_SIZE) + 4)),
+            buffer_send: vec![0; block_size + 4],
+            reasons: reasons,
+            wrong_tid_limit: RateLimit::default(),
+            stats: Recorder::new(stats),
+            sent_at: None,
+            journal: None,
+            #[cfg(feature = "experimental-resume")]
+            resume_request: None,
         }
     }
 
-    fn put_buffer_data(&mut self, buf: Vec<u8>) {
-        self.buffer_data = Some(buf);
+    /// Sets the conformance of the transfer.
+    fn set_conformance(&mut self, conformance: Conformance) {
+        self.conformance = conformance;
+        self.stats.conformance(conformance);
+    }
+
+    /// Records `event` in the journal, if the transfer keeps one.
+    fn record(&mut self, event: Event) {
+        if let Some(ref mut journal) = self.journal {
+            journal.record(event);
+        }
+    }
+
+    /// Returns the error of the failed transfer with its journal, if it kept one.
+    fn journaled(&mut self, err: Error) -> Error {
+        match self.journal.take() {
+            Some(journal) => Error::Journaled(journal, Box::new(err)),
+            None => err,
+        }
+    }
+
+    fn put_buffer_receive(&mut self, buf: Vec<u8>) {
+        self.buffer_receive = Some(buf);
+    }
+
+    /// Locks the transfer to the remote TID by connecting the transport to it.
+    ///
+    /// Once connected a UDP socket lets the kernel drop datagrams from other
+    /// sources, other transports may still return them and they are dropped here.
+    fn lock_tid(&mut self, addr: T::Addr) -> Result<()> {
+        try!(self.socket.connect(&addr));
+        self.remote_addr = addr;
+        self.connected = true;
+        Ok(())
     }
-}
 
-impl PacketSender for InternalClient {
-    fn send_read_request(&self, path: &str, mode: Mode) -> Result<()> {
-        let read_request = RequestPacket::read_request(path, mode);
-        let encoded = read_request.encode();
-        let buf = encoded.packet_buf();
-        self.socket.send_to(&buf, &self.remote_addr).map(|_| ()).map_err(From::from)
+    /// Abandons the transfer identifier of the server to restart the transfer
+    /// with a new request, see `ClientBuilder::block_size_fallback`.
+    ///
+    /// The transfer of the server is terminated with an error packet, its
+    /// datagrams arriving later are dropped.
+    fn abandon_tid(&mut self) -> Result<()> {
+        if !self.connected {
+            return Ok(())
+        }
+        let error = self.reasons.error_packet(packet::Error::Undefined, Reason::Restarted,
+                                              "restarting with a smaller block size");
+        let _ = self.send(&error);
+        try!(self.socket.disconnect());
+        self.abandoned = Some(mem::replace(&mut self.remote_addr, self.server_addr.clone()));
+        self.connected = false;
+        #[cfg(feature = "experimental-resume")]
+        {
+            self.resume_request = None;
+        }
+        Ok(())
+    }
+
+    /// Keeps the request continuing the transfer of `request` from another
+    /// address if the server acknowledged a session token, see `resume`.
+    #[cfg(feature = "experimental-resume")]
+    fn resumable(&mut self, request: &RawPacket, acknowledged: &TransferOptions) {
+        self.resume_request = resume::resume_request(request.packet_buf(), acknowledged);
     }
 
-    fn send_ack(&mut self, block_id: u16) -> Result<Option<()>> {
-        let buf = mem::replace(&mut self.buffer_ack, Vec::new());
-        let ack = AckPacket::new(block_id);
-        let encoded = ack.encode_using(buf);
-        let result = {
-            let buf = encoded.packet_buf();
-            self.socket.send_to(&buf, &self.remote_addr).map(|opt| opt.map(|_| ())).map_err(From::from)
+    #[cfg(not(feature = "experimental-resume"))]
+    fn resumable(&mut self, _: &RawPacket, _: &TransferOptions) {}
+
+    /// Sends the request continuing the transfer once the server rejected
+    /// the client as an unknown transfer identifier, e.g. because its address
+    /// changed. Returns `true` if the transfer continues.
+    #[cfg(feature = "experimental-resume")]
+    fn resume(&mut self, error: &ErrorPacket) -> bool {
+        if error.error() != packet::Error::UnknownTransferId {
+            return false
+        }
+        let request = match self.resume_request.take() {
+            Some(request) => request,
+            None => return false,
         };
-        self.buffer_ack = encoded.get_buffer();
+        let sent = self.send_datagram(&request);
+        self.resume_request = Some(request);
+        sent.is_ok()
+    }
+
+    #[cfg(not(feature = "experimental-resume"))]
+    fn resume(&mut self, _: &ErrorPacket) -> bool {
+        false
+    }
+
+    /// Sends a packet to the server, returns `None` if the socket would block.
+    fn send<P: EncodePacket>(&mut self, packet: &P) -> Result<Option<()>> {
+        let buf = mem::replace(&mut self.buffer_send, Vec::new());
+        let encoded = packet.encode_using(buf);
+        let result = self.send_datagram(encoded.packet_buf());
+        self.buffer_send = encoded.into_buffer();
         result
     }
+
+    /// Sends an encoded packet to the server, returns `None` if the socket would block.
+    fn send_datagram(&mut self, datagram: &[u8]) -> Result<Option<()>> {
+        let sent = self.socket.send_to(datagram, &self.remote_addr);
+        self.stats.sent(&sent);
+        if sent.is_ok() {
+            self.record(Event::datagram(Direction::Sent, datagram));
+        }
+        would_block(sent).map(|opt| opt.map(|_| ())).map_err(socket_error)
+    }
+
+    /// Starts timing the response to the packet that was just sent.
+    ///
+    /// Responses to retransmitted packets are not timed, they may answer any
+    /// of the copies.
+    fn time_response(&mut self, retransmitted: bool) {
+        self.sent_at = if retransmitted { None } else { Some(Instant::now()) };
+    }
+
+    /// Records the response time of the server once it answered the packet
+    /// that was sent last, returns it unless the packet was retransmitted.
+    fn responded(&mut self) -> Option<Duration> {
+        let response_time = self.sent_at.take().map(|sent_at| sent_at.elapsed());
+        if let Some(response_time) = response_time {
+            self.stats.response_time(response_time);
+        }
+        response_time
+    }
+
+    /// Receives the next packet from the server, returns `None` if the socket would block.
+    ///
+    /// Datagrams that can't be decoded are dropped. An error packet terminates
+    /// the transfer and is returned as `Error::Server`. Strict transfers drop
+    /// packets lacking their terminating zero byte, lenient ones add it.
+    fn receive(&mut self) -> Result<Option<Received>> {
+        loop {
+            let mut buf = self.buffer_receive.take()
+                .unwrap_or_else(|| transport::receive_buffer(DEFAULT_BLOCK_SIZE + 4));
+            let (mut n, from) = match would_block(self.socket.recv_from(&mut buf)) {
+                Ok(Some(received)) => received,
+                Ok(None) => {
+                    self.buffer_receive = Some(buf);
+                    return Ok(None)
+                }
+                Err(e) => {
+                    self.buffer_receive = Some(buf);
+                    return Err(socket_error(e))
+                }
+            };
+            self.stats.received();
+            self.record(Event::datagram(Direction::Received, &buf[..n]));
+            if !self.connected {
+                if self.abandoned.as_ref() == Some(&from) {
+                    self.stats.wrong_tid();
+                    self.buffer_receive = Some(buf);
+                    continue
+                }
+                if !T::same_host(&self.remote_addr, &from) {
+                    let strict = self.conformance.is_strict();
+                    let allowed = self.reply_policy == ReplyPolicy::AllowAddressChangeOnFirstReply;
+                    if strict || allowed {
+                        self.stats.deviation(self.conformance);
+                    }
+                    if strict || !allowed {
+                        self.stats.wrong_tid();
+                        self.buffer_receive = Some(buf);
+                        continue
+                    }
+                }
+                // An error ends the transfer, a request rejected for its options
+                // is sent again from the socket, which must not be locked then.
+                if n < 2 || buf[..2] != [0, Opcode::ERROR as u8] {
+                    try!(self.lock_tid(from));
+                }
+            } else if from != self.remote_addr {
+                // E.g. a second transfer the server started for a retransmitted request.
+                self.stats.wrong_tid();
+                self.buffer_receive = Some(buf);
+                if let Some(ip) = self.socket.host_ip(&from) {
+                    if !self.wrong_tid_limit.allow(ip) {
+                        self.stats.wrong_tid_suppressed();
+                        continue
+                    }
+                }
+                let error = self.reasons.error_packet(packet::Error::UnknownTransferId, Reason::UnknownTransferId,
+                                                      "unknown transfer ID").encode();
+                let sent = self.socket.send_to(error.packet_buf(), &from);
+                self.stats.sent(&sent);
+                if sent.is_ok() {
+                    self.record(Event::datagram(Direction::Sent, error.packet_buf()));
+                }
+                continue
+            }
+            if transport::is_truncated(n, &buf) {
+                let max_len = buf.len() - 1;
+                self.buffer_receive = Some(buf);
+                return Err(self.packet_too_large(max_len))
+            }
+            if packet::lacks_terminator(&buf[..n]) {
+                self.stats.deviation(self.conformance);
+                if self.conformance.is_strict() {
+                    self.stats.unexpected();
+                    self.buffer_receive = Some(buf);
+                    continue
+                }
+                // The datagram is shorter than the buffer.
+                buf[n] = 0;
+                n += 1;
+            }
+            let packet = RawPacket::new(buf, n);
+            let received = match packet.opcode() {
+                Some(Opcode::DATA) => DecodedPacket::decode(packet).map(Received::Data),
+                Some(Opcode::OACK) => DecodedPacket::decode(packet).map(Received::OptionAck),
+                Some(Opcode::ACK) => {
+                    let ack = AckPacket::decode(packet.packet_buf());
+                    self.buffer_receive = Some(packet.into_buffer());
+                    ack.map(Received::Ack)
+                }
+                Some(Opcode::ERROR) => {
+                    let error = packet.decode::<ErrorPacket>().map(ErrorPacket::into_owned);
+                    self.buffer_receive = Some(packet.into_buffer());
+                    match error {
+                        Some(ref error) if self.resume(error) => continue,
+                        Some(error) => return Err(Error::Server(error)),
+                        None => None,
+                    }
+                }
+                Some(Opcode::RRQ) | Some(Opcode::WRQ) => {
+                    self.buffer_receive = Some(packet.into_buffer());
+                    Some(Received::Request)
+                }
+                None => {
+                    self.buffer_receive = Some(packet.into_buffer());
+                    None
+                }
+            };
+            match received {
+                Some(received) => return Ok(Some(received)),
+                None => self.stats.unexpected(),
+            }
+        }
+    }
+
+    /// Terminates the transfer because the server acknowledged options the client can't accept.
+    fn reject_options(&mut self, reason: &'static str) -> Error {
+        let error = self.reasons.error_packet(packet::Error::OptionNegotiation, Reason::OptionNegotiation, reason);
+        let _ = self.send(&error);
+        Error::Protocol(reason)
+    }
+
+    /// Handles a packet the server must not send in the state of the transfer,
+    /// returns an error if the transfer has to stop.
+    fn unexpected_packet(&mut self) -> Result<()> {
+        self.stats.unexpected();
+        match self.unexpected_packets {
+            UnexpectedPacketPolicy::Ignore => return Ok(()),
+            UnexpectedPacketPolicy::Abort => {}
+            UnexpectedPacketPolicy::Reject => {
+                let error = self.reasons.error_packet(packet::Error::IllegalOperation, Reason::UnexpectedPacket,
+                                                      "unexpected packet");
+                let _ = self.send(&error);
+            }
+        }
+        Err(Error::Protocol("unexpected packet"))
+    }
+
+    /// Converts an error of the local writer or reader, an `Abort` terminates
+    /// the transfer on the server too.
+    fn local_error(&mut self, err: io::Error) -> Error {
+        match Abort::from_io_error(err) {
+            Ok(abort) => self.abort(abort),
+            Err(err) => Error::Io(err),
+        }
+    }
+
+    /// Terminates the transfer on the server with the error of `abort`.
+    fn abort(&mut self, abort: Abort) -> Error {
+        let _ = self.send(&ErrorPacket::new(abort.error(), abort.message()));
+        Error::Aborted(abort)
+    }
+
+    /// Terminates the transfer because the server sent a datagram longer
+    /// than `max_len` bytes.
+    fn packet_too_large(&mut self, max_len: usize) -> Error {
+        self.stats.unexpected();
+        let error = self.reasons.error_packet(packet::Error::IllegalOperation, Reason::OversizedPacket,
+                                              "packet too large");
+        let _ = self.send(&error);
+        Error::PacketTooLarge(PacketTooLarge { max_len: max_len })
+    }
+
+    /// Terminates a read transfer of a file larger than `max_size` bytes.
+    fn too_large(&mut self, max_size: u64) -> Error {
+        let error = self.reasons.error_packet(packet::Error::DiskFull, Reason::TooLarge, "file too large");
+        let _ = self.send(&error);
+        Error::TooLarge(max_size)
+    }
+
+    /// Converts an error of the writer of a read transfer after `written`
+    /// bytes, the server is told the transfer failed.
+    fn write_error(&mut self, err: io::Error, written: u64) -> Error {
+        if let Some(max_bytes) = Overflow::from_io_error(&err) {
+            let error = self.reasons.error_packet(packet::Error::DiskFull, Reason::LocalError, &err.to_string());
+            let _ = self.send(&error);
+            return Error::SpoolOverflow(max_bytes)
+        }
+        let err = match Abort::from_io_error(err) {
+            Ok(abort) => return self.abort(abort),
+            Err(err) => err,
+        };
+        let code = match err.kind() {
+            io::ErrorKind::StorageFull => packet::Error::DiskFull,
+            _ => packet::Error::Undefined,
+        };
+        let error = self.reasons.error_packet(code, Reason::LocalError, &err.to_string());
+        let _ = self.send(&error);
+        Error::Write(err, written)
+    }
+}
+
+/// Encodes a request using `codec` for the file name.
+fn encode_request(request: RequestPacket, codec: FilenameCodec) -> Result<RawPacket> {
+    request.encode_with(codec, Vec::new()).ok_or_else(|| {
+        Error::Io(io::Error::new(io::ErrorKind::InvalidInput, "file name can't be encoded"))
+    })
+}
+
+/// Returns the file name of a request for `path`.
+///
+/// On Unix these are the bytes of the path, names in legacy 8-bit encodings
+/// are sent unchanged.
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Cow<[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::from(path.as_os_str().as_bytes())
+}
+
+/// Returns the file name of a request for `path`, characters that are not
+/// Unicode are replaced.
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Cow<[u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(name) => Cow::from(name.as_bytes()),
+        Cow::Owned(name) => Cow::from(name.into_bytes()),
+    }
+}
+
+/// Converts an error of the transfer socket, an ICMP port unreachable
+/// reported as a refused connection means the server is gone.
+fn socket_error(err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => Error::PortUnreachable(err),
+        _ => Error::Io(err),
+    }
+}
+
+/// Converts a `WouldBlock` error into `None`.
+fn would_block<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn timed_out() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::TimedOut, "transfer timed out"))
+}
+
+/// Terminates a transfer that didn't complete before its deadline.
+fn deadline_exceeded<T: Transport>(client: &mut InternalClient<T>) -> Error {
+    let error = client.reasons.error_packet(packet::Error::Undefined, Reason::DeadlineExceeded,
+                                            "transfer deadline exceeded");
+    let _ = client.send(&error);
+    Error::Io(io::Error::new(io::ErrorKind::TimedOut, "transfer deadline exceeded"))
+}
+
+/// Outcome of advancing a transfer by one step.
+enum Step {
+    /// The transfer made progress and can be advanced again.
+    Continue,
+    /// A packet was sent and a response is expected before the timeout expires.
+    Sent,
+    /// The socket would block, the transfer waits for the next readiness event.
+    Blocked,
+    /// The transfer waits until the instant before it continues, no response
+    /// is expected meanwhile.
+    Wait(Instant),
+    /// The transfer is complete.
+    Done,
+}
+
+/// State machine of a transfer driven by `run`.
+trait ClientTransfer {
+    /// Advances the transfer by one step.
+    fn step<S: Transport>(&mut self, client: &mut InternalClient<S>) -> Result<Step>;
+
+    /// Handles an expired retransmission timeout.
+    fn timeout(&mut self) -> Result<()>;
+
+    /// Returns the name of the state of the transfer, for the journal.
+    fn state(&self) -> &'static str;
+
+    /// Returns the time to wait for a response to the packet that was just sent.
+    fn retransmission_timeout(&self, timeout: Duration) -> Duration {
+        timeout
+    }
 }
 
-impl PacketReceiver for InternalClient {
-    fn receive_data(&mut self) -> Result<Option<DecodedPacket<DataPacketOctet<'static>>>> {
-        let mut buf = mem::replace(&mut self.buffer_data, None).unwrap_or(vec![0; MAX_DATA_SIZE + 4]);
-        let result = try!(self.socket.recv_from(&mut buf));
-        let p = result.map(|(n, from)| {
-            self.remote_addr = from;
-            RawPacket::new(buf, n)
-        }).map(|packet| {
-            match packet.opcode() {
-                Some(Opcode::DATA) => {
-                    DecodedPacket::decode(packet).unwrap()
-                },
-                _ => unimplemented!(),
+const CLIENT: Token = Token(0);
+
+/// Drives a transfer using an event loop until it is complete or the
+/// `transfer_deadline` passed.
+fn run<S, T>(client: &mut InternalClient<S>, transfer: &mut T, timeout: Duration,
+             transfer_deadline: Option<Duration>) -> Result<()>
+    where S: Transport + Source,
+          T: ClientTransfer,
+{
+    let mut poll = try!(Poll::new());
+    let mut events = Events::with_capacity(16);
+
+    try!(poll.registry().register(&mut client.socket, CLIENT, Interest::READABLE | Interest::WRITABLE));
+
+    let started = Instant::now();
+    let transfer_deadline = transfer_deadline.map(|duration| started + duration);
+    let mut deadline = started + timeout;
+    let mut retransmitting = false;
+    let mut waiting = false;
+    let mut state = None;
+    loop {
+        // Readiness is edge-triggered, so the transfer is advanced until the
+        // socket would block before waiting for the next event.
+        loop {
+            if client.journal.is_some() && state != Some(transfer.state()) {
+                state = Some(transfer.state());
+                client.record(Event::State(transfer.state()));
             }
-        });
-        Ok(p)
+            match try!(transfer.step(client)) {
+                Step::Continue => {}
+                Step::Sent => {
+                    client.time_response(retransmitting);
+                    retransmitting = false;
+                    waiting = false;
+                    let retransmission_timeout = transfer.retransmission_timeout(timeout);
+                    client.record(Event::TimerArmed(retransmission_timeout));
+                    deadline = Instant::now() + retransmission_timeout;
+                }
+                Step::Blocked => break,
+                Step::Wait(until) => {
+                    deadline = until;
+                    waiting = true;
+                    break
+                }
+                Step::Done => return Ok(()),
+            }
+        }
+        let now = Instant::now();
+        if let Some(transfer_deadline) = transfer_deadline {
+            if now >= transfer_deadline {
+                return Err(deadline_exceeded(client))
+            }
+        }
+        if now >= deadline && waiting {
+            waiting = false;
+            continue
+        }
+        if now >= deadline {
+            client.record(Event::TimerFired);
+            try!(transfer.timeout());
+            client.stats.retransmission();
+            retransmitting = true;
+            deadline = now + timeout;
+            continue
+        }
+        let wake_up = transfer_deadline.map_or(deadline, |transfer_deadline| cmp::min(deadline, transfer_deadline));
+        try!(poll.poll(&mut events, Some(wake_up - now)));
+        for event in events.iter() {
+            match event.token() {
+                CLIENT => {}
+                _ => unreachable!(),
+            }
+        }
     }
 }
 
-enum ClientStates<'a> {
-    SendReadRequest(&'a Path, Mode),
-    ReceivingData(u16),
-    SendAck(DecodedPacket<DataPacketOctet<'static>>),
+enum GetStates {
+    SendRequest,
+    ReceivingData,
+    SendAck(Option<DecodedPacket<DataPacketOctet<'static>>>, AckPacket),
     Done,
 }
 
-impl<'a> ClientStates<'a> {
-    fn is_done(&self) -> bool {
-        match self {
-            &ClientStates::Done => true,
-            _ => false,
+/// Options a transfer requested, the option acknowledgment is checked against them.
+struct Requested {
+    block_size: usize,
+    timeout: Option<Duration>,
+    window_size: WindowSize,
+    extensions: TransferOptions<'static>,
+}
+
+impl Requested {
+    /// Returns what a request without options requests.
+    fn plain() -> Requested {
+        Requested {
+            block_size: DEFAULT_BLOCK_SIZE,
+            timeout: None,
+            window_size: WindowSize::default(),
+            extensions: TransferOptions::new(),
+        }
+    }
+
+    /// Returns what the request restarting the transfer with `block_size` requests.
+    fn with_block_size(&self, block_size: usize) -> Requested {
+        Requested {
+            block_size: block_size,
+            timeout: self.timeout,
+            window_size: self.window_size,
+            extensions: self.extensions.clone(),
+        }
+    }
+
+    /// Returns the options of the request.
+    fn options(&self) -> TransferOptions<'static> {
+        let mut options = request_options(self.block_size);
+        if let Some(timeout) = self.timeout {
+            request_timeout(&mut options, timeout);
         }
+        request_window_size(&mut options, self.window_size);
+        for &(ref name, ref value) in self.extensions.iter() {
+            options.insert(name.clone(), value.clone());
+        }
+        options
+    }
+
+    /// Checks an option acknowledgment, returns the negotiated block size and
+    /// window size and the acknowledged extensions.
+    fn negotiated(&self, oack: &OptionAckPacket) -> result::Result<Negotiated, &'static str> {
+        let mut requested = self.extensions.clone();
+        request_window_size(&mut requested, self.window_size);
+        let block_size = try!(negotiated_options(self.block_size, self.timeout, &requested, oack));
+        let window_size = try!(negotiated_window_size(self.window_size, oack));
+        let mut acknowledged = TransferOptions::new();
+        for &(ref name, ref value) in oack.options().iter() {
+            if self.extensions.get(name).is_some() {
+                acknowledged.insert(name.clone().into_owned(), value.clone().into_owned());
+            }
+        }
+        Ok((block_size, window_size, acknowledged))
     }
 }
 
-struct Client<'a> {
-    poll: Poll,
-    client: InternalClient,
-    writer: &'a mut io::Write,
+/// Block size, window size and extensions the server acknowledged.
+type Negotiated = (usize, WindowSize, TransferOptions<'static>);
+
+/// Handles a failure to receive the response to a request, returns the
+/// error unless the server rejected the options of the request before the
+/// transfer started and it's sent again without options.
+///
+/// `plain` is the request without options, the fallback is taken once.
+fn option_fallback<S: Transport>(client: &mut InternalClient<S>, err: Error, request: &mut RawPacket,
+                                 requested: &mut Requested, plain: &mut Option<RawPacket>) -> Result<()> {
+    match err {
+        Error::Server(ref error) if error.error() == packet::Error::OptionNegotiation && !client.connected &&
+                                    plain.is_some() => {}
+        err => return Err(err),
+    }
+    *request = plain.take().expect("fallback request is checked");
+    *requested = Requested::plain();
+    client.stats.option_fallback();
+    Ok(())
 }
 
-const CLIENT: Token = Token(0);
+struct GetTransfer<'a> {
+    request: RawPacket,
+    /// Request without options, if it's sent when the server rejects options.
+    plain_request: Option<RawPacket>,
+    requested: Requested,
+    acknowledged_options: TransferOptions<'static>,
+    retries: Retries,
+    transfer: ReadTransfer,
+    window_size: WindowSize,
+    /// Blocks of the window received without an acknowledgment.
+    window: AckWindow,
+    /// Acknowledgment of the last accepted block, sent again on timeouts.
+    last_ack: Option<AckPacket>,
+    state: GetStates,
+    writer: &'a mut io::Write,
+    written: u64,
+    blocks: u64,
+    max_size: Option<u64>,
+    accept_block_zero: bool,
+    quirks: Vec<Quirk>,
+}
 
-impl<'a> Client<'a> {
-    fn new(poll: Poll, client: InternalClient, writer: &'a mut io::Write) -> Client<'a> {
-        Client {
-            poll: poll,
-            client: client,
+impl<'a> GetTransfer<'a> {
+    fn new(request: RawPacket, requested: Requested, retries: Retries, max_size: Option<u64>,
+           accept_block_zero: bool, writer: &'a mut io::Write) -> GetTransfer<'a> {
+        let mut transfer = ReadTransfer::new(DEFAULT_BLOCK_SIZE);
+        transfer.set_retries(retries);
+        transfer.set_accept_block_zero(accept_block_zero);
+        GetTransfer {
+            request: request,
+            plain_request: None,
+            requested: requested,
+            acknowledged_options: TransferOptions::new(),
+            retries: retries,
+            transfer: transfer,
+            window_size: WindowSize::default(),
+            window: AckWindow::new(WindowSize::default()),
+            last_ack: None,
+            state: GetStates::SendRequest,
             writer: writer,
+            written: 0,
+            blocks: 0,
+            max_size: max_size,
+            accept_block_zero: accept_block_zero,
+            quirks: Vec::new(),
         }
     }
-}
 
-impl<'a> Client<'a> {
-    fn get(&mut self, path: &Path, mode: Mode) -> Result<()> {
-        let mut events = Events::with_capacity(1024);
-        let mut current_state = ClientStates::SendReadRequest(path, mode);
+    /// Wraps the error of a transfer that failed after data was written out,
+    /// the writer is flushed so the written data is not lost.
+    fn interrupted(&mut self, err: Error) -> Error {
+        if self.written == 0 {
+            return err
+        }
+        let progress = Progress {
+            bytes: self.written,
+            blocks: self.blocks,
+            flushed: self.writer.flush().is_ok(),
+        };
+        Error::Interrupted(progress, Box::new(err))
+    }
+
+    /// Writes out the data of a block, counting the written bytes.
+    fn write_data(&mut self, mut data: &[u8]) -> io::Result<()> {
+        while !data.is_empty() {
+            match self.writer.write(data) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "writer accepted no data")),
+                Ok(n) => {
+                    self.written += n as u64;
+                    data = &data[n..];
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
 
-        try!(self.poll.register(&self.client.socket, CLIENT, Ready::writable(), PollOpt::level()));
+    /// Writes out an accepted block, its receive buffer is reused.
+    fn write_block<S: Transport>(&mut self, client: &mut InternalClient<S>,
+                                 data_packet: DecodedPacket<DataPacketOctet<'static>>) -> Result<()> {
+        if let Err(e) = self.write_data(data_packet.data()) {
+            return Err(client.write_error(e, self.written))
+        }
+        self.blocks += 1;
+        client.put_buffer_receive(data_packet.into_inner());
+        Ok(())
+    }
+}
 
-        loop {
-            try!(self.poll.poll(&mut events, None));
-            for event in events.iter() {
-                match event.token() {
-                    CLIENT => {
-                        current_state = try!(self.handle_event(current_state, event));
-                        if current_state.is_done() {
-                            return Ok(())
+impl<'a> ClientTransfer for GetTransfer<'a> {
+    fn step<S: Transport>(&mut self, client: &mut InternalClient<S>) -> Result<Step> {
+        match mem::replace(&mut self.state, GetStates::Done) {
+            GetStates::SendRequest => {
+                if try!(client.send_datagram(self.request.packet_buf())).is_none() {
+                    self.state = GetStates::SendRequest;
+                    return Ok(Step::Blocked)
+                }
+                self.state = GetStates::ReceivingData;
+                Ok(Step::Sent)
+            }
+            GetStates::ReceivingData => {
+                self.state = GetStates::ReceivingData;
+                let received = match client.receive() {
+                    Ok(received) => received,
+                    Err(err) => {
+                        try!(option_fallback(client, err, &mut self.request, &mut self.requested,
+                                             &mut self.plain_request));
+                        self.state = GetStates::SendRequest;
+                        return Ok(Step::Continue)
+                    }
+                };
+                let data_packet = match received {
+                    Some(Received::Data(data_packet)) => data_packet,
+                    Some(Received::OptionAck(oack)) => {
+                        // Only the first response can acknowledge options.
+                        if self.last_ack.is_none() {
+                            client.responded();
+                            let block_size = match self.requested.negotiated(&oack) {
+                                Ok((block_size, window_size, acknowledged)) => {
+                                    self.acknowledged_options = acknowledged;
+                                    client.resumable(&self.request, &self.acknowledged_options);
+                                    self.window_size = window_size;
+                                    self.window = AckWindow::new(window_size);
+                                    block_size
+                                }
+                                Err(reason) => return Err(client.reject_options(reason)),
+                            };
+                            self.transfer = ReadTransfer::new(block_size);
+                            self.transfer.set_retries(self.retries);
+                            self.transfer.set_accept_block_zero(self.accept_block_zero);
+                            let block_id = if self.quirks.contains(&Quirk::OptionAckBlockOne) { 1 } else { 0 };
+                            self.state = GetStates::SendAck(None, AckPacket::new(block_id));
+                        } else if self.blocks > 0 {
+                            // Repeated after the transfer started.
+                            client.stats.deviation(client.conformance);
+                            if client.conformance.is_strict() {
+                                client.put_buffer_receive(oack.into_inner());
+                                try!(client.unexpected_packet());
+                                return Ok(Step::Continue)
+                            }
+                        } else if self.quirks.contains(&Quirk::RepeatedOptionAck) {
+                            client.stats.deviation(client.conformance);
+                            self.state = GetStates::SendAck(None, self.last_ack.expect("options were acknowledged"));
+                        }
+                        client.put_buffer_receive(oack.into_inner());
+                        return Ok(Step::Continue)
+                    }
+                    Some(Received::Ack(_)) | Some(Received::Request) => {
+                        try!(client.unexpected_packet());
+                        return Ok(Step::Continue)
+                    }
+                    None => return Ok(Step::Blocked),
+                };
+                // A longer payload is not a block of the transfer, whatever its number.
+                let block_size = self.transfer.block_size();
+                if data_packet.data().len() > block_size {
+                    client.put_buffer_receive(data_packet.into_inner());
+                    return Err(client.packet_too_large(block_size + 4))
+                }
+                let block_zero = data_packet.block_id() == 0 && self.blocks == 0;
+                match self.transfer.receive_data(&data_packet) {
+                    DataReceived::Accepted(ack) => {
+                        client.responded();
+                        if block_zero {
+                            client.stats.deviation(client.conformance);
+                        }
+                        // Blocks before this one are written by now.
+                        if let Some(max_size) = self.max_size {
+                            if self.written + data_packet.data().len() as u64 > max_size {
+                                client.put_buffer_receive(data_packet.into_inner());
+                                return Err(client.too_large(max_size))
+                            }
+                        }
+                        if self.window.accepted(self.transfer.is_done()) {
+                            self.state = GetStates::SendAck(Some(data_packet), ack);
+                        } else {
+                            // Blocks before the end of the window are not acknowledged.
+                            self.last_ack = Some(ack);
+                            try!(self.write_block(client, data_packet));
+                        }
+                    }
+                    DataReceived::Duplicate(ack) => {
+                        // Only the acknowledgment is repeated, the data was written already.
+                        client.stats.duplicate();
+                        self.state = GetStates::SendAck(None, ack);
+                        client.put_buffer_receive(data_packet.into_inner());
+                    }
+                    DataReceived::Ignored => {
+                        if block_zero && client.conformance.is_strict() {
+                            client.stats.deviation(client.conformance);
+                        }
+                        client.put_buffer_receive(data_packet.into_inner());
+                        // A block of the window was lost, the server continues after the last accepted one.
+                        match self.last_ack {
+                            Some(ack) if self.window.skipped() => self.state = GetStates::SendAck(None, ack),
+                            _ => {}
                         }
                     }
-                    _ => unreachable!(),
                 }
+                Ok(Step::Continue)
             }
+            GetStates::SendAck(data_packet, ack) => {
+                if try!(client.send(&ack)).is_none() {
+                    self.state = GetStates::SendAck(data_packet, ack);
+                    return Ok(Step::Blocked)
+                }
+                self.last_ack = Some(ack);
+                self.window.acknowledged();
+                if let Some(data_packet) = data_packet {
+                    try!(self.write_block(client, data_packet));
+                }
+                if self.transfer.is_done() {
+                    Ok(Step::Done)
+                } else {
+                    self.state = GetStates::ReceivingData;
+                    Ok(Step::Sent)
+                }
+            }
+            GetStates::Done => Ok(Step::Done),
         }
     }
 
-    fn handle_event<'b>(&mut self, current_state: ClientStates, event: Event) -> Result<ClientStates<'b>> {
-        match current_state {
-            ClientStates::SendReadRequest(path, mode) => {
-                try!(self.client.send_read_request(path.to_str().unwrap(), mode));
-                println!("Starting transfer ...");
-                try!(self.poll.reregister(&self.client.socket, CLIENT, Ready::readable(), PollOpt::level()));
-                Ok(ClientStates::ReceivingData(1))
+    fn timeout(&mut self) -> Result<()> {
+        match self.transfer.timeout() {
+            transfer::Timeout::Retransmit => {
+                if let GetStates::ReceivingData = self.state {
+                    self.state = match self.last_ack {
+                        Some(ack) => GetStates::SendAck(None, ack),
+                        None => GetStates::SendRequest,
+                    };
+                }
+                Ok(())
             }
-            ClientStates::ReceivingData(current_id) => {
-                let data_packet = match try!(self.client.receive_data()) {
-                    Some(data_packet) => data_packet,
-                    None => return Ok(ClientStates::ReceivingData(current_id)),
-                };
-                if current_id == data_packet.block_id() {
-                    self.handle_event(ClientStates::SendAck(data_packet), event)
-                } else {
-                    println!("Unexpected packet id: got={}, expected={}",
-                             data_packet.block_id(), current_id);
-                    Ok(ClientStates::ReceivingData(current_id))
+            _ => Err(timed_out()),
+        }
+    }
+
+    fn state(&self) -> &'static str {
+        match self.state {
+            GetStates::SendRequest => "sending request",
+            GetStates::ReceivingData => "receiving data",
+            GetStates::SendAck(..) => "sending ack",
+            GetStates::Done => "done",
+        }
+    }
+}
+
+enum ProbeStates {
+    SendRequest,
+    ReceivingOptionAck,
+}
+
+/// Asks the server for the size of a file with the transfer size option and
+/// terminates the transfer before any data is sent.
+struct ProbeTransfer {
+    request: RawPacket,
+    retries: Retries,
+    timeouts: u32,
+    state: ProbeStates,
+    size: Option<u64>,
+}
+
+impl ProbeTransfer {
+    fn new(request: RawPacket, retries: Retries) -> ProbeTransfer {
+        ProbeTransfer {
+            request: request,
+            retries: retries,
+            timeouts: 0,
+            state: ProbeStates::SendRequest,
+            size: None,
+        }
+    }
+}
+
+impl ClientTransfer for ProbeTransfer {
+    fn step<S: Transport>(&mut self, client: &mut InternalClient<S>) -> Result<Step> {
+        match self.state {
+            ProbeStates::SendRequest => {
+                if try!(client.send_datagram(self.request.packet_buf())).is_none() {
+                    return Ok(Step::Blocked)
                 }
+                self.state = ProbeStates::ReceivingOptionAck;
+                Ok(Step::Sent)
             }
-            ClientStates::SendAck(data_packet) => {
-                if try!(self.client.send_ack(data_packet.block_id())).is_none() {
-                    try!(self.poll.reregister(&self.client.socket, CLIENT, Ready::writable(), PollOpt::level()));
-                    println!("Could not send ack for packet id={}", data_packet.block_id());
-                    Ok(ClientStates::SendAck(data_packet))
-                } else {
-                    try!(self.writer.write_all(data_packet.data()));
-                    let data_len = data_packet.data().len();
-                    let next_id = data_packet.block_id() + 1;
-                    self.client.put_buffer_data(data_packet.into_inner());
-                    if data_len < MAX_DATA_SIZE {
-                        println!("Transfer complete");
-                        Ok(ClientStates::Done)
-                    } else {
-                        if event.kind().is_writable() {
-                            try!(self.poll.reregister(&self.client.socket, CLIENT, Ready::readable(), PollOpt::level()));
-                        }
-                        Ok(ClientStates::ReceivingData(next_id))
+            ProbeStates::ReceivingOptionAck => {
+                let size = match try!(client.receive()) {
+                    Some(Received::OptionAck(oack)) => {
+                        let size = oack.options().get(TSIZE_OPTION).and_then(|size| size.parse().ok());
+                        client.put_buffer_receive(oack.into_inner());
+                        size
+                    }
+                    Some(Received::Data(data_packet)) => {
+                        client.put_buffer_receive(data_packet.into_inner());
+                        None
+                    }
+                    Some(Received::Ack(_)) | Some(Received::Request) => {
+                        try!(client.unexpected_packet());
+                        return Ok(Step::Continue)
                     }
+                    None => return Ok(Step::Blocked),
+                };
+                // The transfer is not wanted either way, the server can stop right away.
+                let error = client.reasons.error_packet(packet::Error::OptionNegotiation, Reason::Probe, "size probe");
+                let _ = client.send(&error);
+                match size {
+                    Some(size) => {
+                        self.size = Some(size);
+                        Ok(Step::Done)
+                    }
+                    None => Err(Error::Protocol("server doesn't report the transfer size")),
                 }
             }
-            _ => unreachable!()
+        }
+    }
+
+    fn timeout(&mut self) -> Result<()> {
+        self.timeouts += 1;
+        if self.timeouts > self.retries.get() {
+            return Err(timed_out())
+        }
+        self.state = ProbeStates::SendRequest;
+        Ok(())
+    }
+
+    fn state(&self) -> &'static str {
+        match self.state {
+            ProbeStates::SendRequest => "sending request",
+            ProbeStates::ReceivingOptionAck => "receiving option ack",
         }
     }
 }
 
-pub fn get(path: &Path, mode: Mode, writer: &mut io::Write) {
-    println!("starting ...");
-    let remote_addr = "127.0.0.1:69".parse().unwrap();
-    let any = str::FromStr::from_str("0.0.0.0:0").unwrap();
-    let socket = UdpSocket::bind(&any).unwrap();
-    let poll =  Poll::new().unwrap();
-    let mut client = Client::new(poll, InternalClient::new(socket, remote_addr), writer);
-    client.get(path, mode).unwrap();
+enum PutStates {
+    SendRequest,
+    ReceivingAck,
+    /// Waits before the next window after a loss, see `WriteTransfer::pause`.
+    Pause(Instant),
+    ReadBlock,
+    SendData,
+    Restart,
+    Done,
+}
+
+/// Restart of an upload with a smaller block size, see `ClientBuilder::block_size_fallback`.
+struct Restart {
+    policy: BlockSizeFallback,
+    /// Write request asking for the smaller block size.
+    request: RawPacket,
+}
+
+struct PutTransfer<'a> {
+    request: RawPacket,
+    /// Request without options, if it's sent when the server rejects options.
+    plain_request: Option<RawPacket>,
+    requested: Requested,
+    acknowledged_options: TransferOptions<'static>,
+    transfer: WriteTransfer,
+    window_size: WindowSize,
+    state: PutStates,
+    source: &'a mut BlockSource,
+    /// Restart taken once the blocks of the negotiated size keep timing out.
+    restart: Option<Restart>,
+    requests_sent: u32,
+    acknowledged: u64,
+    blocks: u64,
+    quirks: Vec<Quirk>,
+}
+
+impl<'a> PutTransfer<'a> {
+    fn new(request: RawPacket, requested: Requested, retries: Retries, source: &'a mut BlockSource)
+           -> PutTransfer<'a> {
+        let mut transfer = WriteTransfer::new(DEFAULT_BLOCK_SIZE);
+        transfer.set_retries(retries);
+        PutTransfer {
+            request: request,
+            plain_request: None,
+            requested: requested,
+            acknowledged_options: TransferOptions::new(),
+            transfer: transfer,
+            window_size: WindowSize::default(),
+            state: PutStates::SendRequest,
+            source: source,
+            restart: None,
+            requests_sent: 0,
+            acknowledged: 0,
+            blocks: 0,
+            quirks: Vec::new(),
+        }
+    }
+
+    /// Wraps the error of a transfer that failed after the server acknowledged data.
+    fn interrupted(&self, err: Error) -> Error {
+        if self.blocks == 0 {
+            return err
+        }
+        let progress = Progress {
+            bytes: self.acknowledged,
+            blocks: self.blocks,
+            flushed: false,
+        };
+        Error::Interrupted(progress, Box::new(err))
+    }
+
+    /// Returns `true` before the server responded to the write request.
+    fn awaiting_response(&self) -> bool {
+        self.transfer.current_block().block_id() == 0
+    }
+
+    /// Sends windows of the size the server acknowledged.
+    fn set_window_size(&mut self, window_size: WindowSize) {
+        self.window_size = window_size;
+        self.transfer.set_window_size(window_size, true);
+    }
+
+    /// Adds the blocks acknowledged after `acked_blocks` were to the progress.
+    ///
+    /// Only the last block is shorter, it's acknowledged when the transfer is done.
+    fn count_acknowledged(&mut self, acked_blocks: u64) {
+        let blocks = self.transfer.acked_blocks() - acked_blocks;
+        self.acknowledged += blocks * self.transfer.block_size() as u64;
+        self.blocks += blocks;
+    }
+}
+
+impl<'a> ClientTransfer for PutTransfer<'a> {
+    fn step<S: Transport>(&mut self, client: &mut InternalClient<S>) -> Result<Step> {
+        match mem::replace(&mut self.state, PutStates::Done) {
+            PutStates::SendRequest => {
+                if try!(client.send_datagram(self.request.packet_buf())).is_none() {
+                    self.state = PutStates::SendRequest;
+                    return Ok(Step::Blocked)
+                }
+                self.requests_sent += 1;
+                self.state = PutStates::ReceivingAck;
+                Ok(Step::Sent)
+            }
+            PutStates::ReceivingAck => {
+                self.state = PutStates::ReceivingAck;
+                let received = match client.receive() {
+                    Ok(received) => received,
+                    Err(err) => {
+                        try!(option_fallback(client, err, &mut self.request, &mut self.requested,
+                                             &mut self.plain_request));
+                        self.requests_sent = 0;
+                        self.state = PutStates::SendRequest;
+                        return Ok(Step::Continue)
+                    }
+                };
+                let ack = match received {
+                    Some(Received::Ack(ack)) => ack,
+                    Some(Received::OptionAck(oack)) => {
+                        let awaiting_response = self.awaiting_response();
+                        // Option acknowledgment replaces the acknowledgment of block 0.
+                        if awaiting_response {
+                            client.responded();
+                            let (block_size, window_size) = match self.requested.negotiated(&oack) {
+                                Ok((block_size, window_size, acknowledged)) => {
+                                    self.acknowledged_options = acknowledged;
+                                    client.resumable(&self.request, &self.acknowledged_options);
+                                    (block_size, window_size)
+                                }
+                                Err(reason) => return Err(client.reject_options(reason)),
+                            };
+                            self.transfer.restart(block_size);
+                            self.set_window_size(window_size);
+                            // The block size checked against the policy is the negotiated one.
+                            self.transfer.set_block_size_fallback(self.restart.as_ref().map(|restart| restart.policy));
+                            self.state = PutStates::ReadBlock;
+                        }
+                        client.put_buffer_receive(oack.into_inner());
+                        if awaiting_response {
+                            return Ok(Step::Continue)
+                        }
+                        if self.transfer.current_block().block_id() != 1 {
+                            // Repeated after the server acknowledged data.
+                            client.stats.deviation(client.conformance);
+                            if client.conformance.is_strict() {
+                                try!(client.unexpected_packet());
+                            }
+                            return Ok(Step::Continue)
+                        }
+                        if !self.quirks.contains(&Quirk::RepeatedOptionAck) {
+                            return Ok(Step::Continue)
+                        }
+                        client.stats.deviation(client.conformance);
+                        AckPacket::new(1)
+                    }
+                    Some(Received::Data(data_packet)) => {
+                        client.put_buffer_receive(data_packet.into_inner());
+                        try!(client.unexpected_packet());
+                        return Ok(Step::Continue)
+                    }
+                    Some(Received::Request) => {
+                        try!(client.unexpected_packet());
+                        return Ok(Step::Continue)
+                    }
+                    None => return Ok(Step::Blocked),
+                };
+                let acked_blocks = self.transfer.acked_blocks();
+                match self.transfer.receive_ack(&ack) {
+                    // The blocks after the acknowledged ones of a rewind are
+                    // read at their offsets again, sending continues from there.
+                    AckReceived::Next | AckReceived::Rewind(_) => {
+                        let pause = client.responded().map_or(Duration::from_secs(0), |response_time| {
+                            self.transfer.pause(response_time)
+                        });
+                        self.count_acknowledged(acked_blocks);
+                        self.state = PutStates::Pause(Instant::now() + pause);
+                    }
+                    AckReceived::Done => {
+                        client.responded();
+                        return Ok(Step::Done)
+                    }
+                    AckReceived::Ignored => client.stats.duplicate(),
+                }
+                Ok(Step::Continue)
+            }
+            PutStates::Pause(until) => {
+                if Instant::now() < until {
+                    self.state = PutStates::Pause(until);
+                    return Ok(Step::Wait(until))
+                }
+                self.state = PutStates::ReadBlock;
+                Ok(Step::Continue)
+            }
+            PutStates::ReadBlock => {
+                if let Err(e) = self.transfer.next_block_from(self.source) {
+                    return Err(client.local_error(e))
+                }
+                self.state = PutStates::SendData;
+                Ok(Step::Continue)
+            }
+            PutStates::SendData => {
+                if try!(client.send(&self.transfer.current_block())).is_none() {
+                    self.state = PutStates::SendData;
+                    return Ok(Step::Blocked)
+                }
+                // The rest of the window is sent before waiting for an acknowledgment.
+                self.state = if self.transfer.can_send() { PutStates::ReadBlock } else { PutStates::ReceivingAck };
+                Ok(Step::Sent)
+            }
+            PutStates::Restart => {
+                let restart = self.restart.take().expect("fallback is only set with a restart");
+                try!(client.abandon_tid());
+                client.stats.block_size_fallback();
+                self.requested = self.requested.with_block_size(restart.policy.block_size);
+                self.request = restart.request;
+                self.acknowledged_options = TransferOptions::new();
+                // The data is sent again from its start once the server responded.
+                self.transfer.set_block_size_fallback(None);
+                self.transfer.restart(DEFAULT_BLOCK_SIZE);
+                self.set_window_size(WindowSize::default());
+                self.requests_sent = 0;
+                self.acknowledged = 0;
+                self.blocks = 0;
+                self.state = PutStates::SendRequest;
+                Ok(Step::Continue)
+            }
+            PutStates::Done => Ok(Step::Done),
+        }
+    }
+
+    fn timeout(&mut self) -> Result<()> {
+        match self.transfer.timeout() {
+            transfer::Timeout::Retransmit => {
+                if let PutStates::ReceivingAck = self.state {
+                    self.state = if self.awaiting_response() {
+                        PutStates::SendRequest
+                    } else {
+                        PutStates::SendData
+                    };
+                }
+                Ok(())
+            }
+            // Sending continues after the last acknowledged block.
+            transfer::Timeout::Rewind(_) => {
+                self.state = PutStates::ReadBlock;
+                Ok(())
+            }
+            transfer::Timeout::Fallback(_) => {
+                self.state = PutStates::Restart;
+                Ok(())
+            }
+            transfer::Timeout::Failed => Err(timed_out()),
+        }
+    }
+
+    fn state(&self) -> &'static str {
+        match self.state {
+            PutStates::SendRequest => "sending request",
+            PutStates::ReceivingAck => "receiving ack",
+            PutStates::Pause(_) => "pausing",
+            PutStates::ReadBlock => "reading data",
+            PutStates::SendData => "sending data",
+            PutStates::Restart => "restarting",
+            PutStates::Done => "done",
+        }
+    }
+
+    /// The write request is retransmitted with exponential backoff, a server
+    /// that has to create the file may take a while to respond.
+    fn retransmission_timeout(&self, timeout: Duration) -> Duration {
+        if self.awaiting_response() && self.requests_sent > 1 {
+            timeout * (1 << cmp::min(self.requests_sent - 1, MAX_REQUEST_BACKOFF))
+        } else {
+            timeout
+        }
+    }
+}
+
+/// Largest exponent of the backoff of write request retransmissions.
+const MAX_REQUEST_BACKOFF: u32 = 3;
+
+/// Builder for a `Client` with non-default configuration.
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    server_addr: SocketAddr,
+    alternate_addr: Option<SocketAddr>,
+    happy_eyeballs: Option<Duration>,
+    local_addr: SocketAddr,
+    block_size: BlockSize,
+    timeout: Duration,
+    negotiate_timeout: bool,
+    retries: Retries,
+    reply_policy: ReplyPolicy,
+    unexpected_packets: UnexpectedPacketPolicy,
+    max_size: Option<u64>,
+    deadline: Option<Duration>,
+    accept_block_zero: bool,
+    quirks: Vec<Quirk>,
+    conformance: Conformance,
+    filename_codec: FilenameCodec,
+    reasons: ReasonFormat,
+    wrong_tid_limit: RateLimit,
+    stats: Stats,
+    stale_temp_age: Duration,
+    tap: Option<Tap>,
+    journal: Option<usize>,
+    option_fallback: bool,
+    block_size_fallback: Option<BlockSizeFallback>,
+    window_size: WindowSize,
+    spool: Option<Spool>,
+    rng: SharedRng,
+    #[cfg(feature = "compression")]
+    decompress: bool,
+    #[cfg(feature = "experimental-resume")]
+    resumable: bool,
+    #[cfg(target_os = "linux")]
+    device: Option<String>,
+}
+
+impl ClientBuilder {
+    /// Creates a builder for a client of the server listening on `server_addr`.
+    pub fn new(server_addr: SocketAddr) -> ClientBuilder {
+        ClientBuilder {
+            server_addr: server_addr,
+            alternate_addr: None,
+            happy_eyeballs: None,
+            local_addr: "0.0.0.0:0".parse().unwrap(),
+            block_size: BlockSize::default(),
+            timeout: DEFAULT_TIMEOUT,
+            negotiate_timeout: false,
+            retries: Retries::default(),
+            reply_policy: ReplyPolicy::default(),
+            unexpected_packets: UnexpectedPacketPolicy::default(),
+            max_size: None,
+            deadline: None,
+            accept_block_zero: false,
+            quirks: Vec::new(),
+            conformance: Conformance::default(),
+            filename_codec: FilenameCodec::default(),
+            reasons: ReasonFormat::default(),
+            wrong_tid_limit: RateLimit::default(),
+            stats: Stats::new(),
+            stale_temp_age: DEFAULT_STALE_TEMP_AGE,
+            tap: None,
+            journal: None,
+            option_fallback: true,
+            block_size_fallback: None,
+            window_size: WindowSize::default(),
+            spool: None,
+            rng: SharedRng::from_entropy(),
+            #[cfg(feature = "compression")]
+            decompress: false,
+            #[cfg(feature = "experimental-resume")]
+            resumable: false,
+            #[cfg(target_os = "linux")]
+            device: None,
+        }
+    }
+
+    /// Creates a builder for a client of the server at `addr`, e.g.
+    /// `("boot.example.com", 69)`, resolving its host name.
+    ///
+    /// The first resolved address is the address of the server, the first
+    /// address of the other family is the alternate address.
+    pub fn resolve<A: ToSocketAddrs>(addr: A) -> io::Result<ClientBuilder> {
+        let mut addrs = try!(addr.to_socket_addrs());
+        let server_addr = match addrs.next() {
+            Some(addr) => addr,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "no address resolved")),
+        };
+        let alternate_addr = addrs.find(|addr| addr.is_ipv4() != server_addr.is_ipv4());
+        Ok(ClientBuilder {
+            alternate_addr: alternate_addr,
+            ..ClientBuilder::new(server_addr)
+        })
+    }
+
+    /// Sets another address of the server in the other address family, e.g.
+    /// the IPv4 address of a server whose IPv6 address the client was created
+    /// with. It's only used with `happy_eyeballs`.
+    pub fn alternate_addr(mut self, addr: SocketAddr) -> ClientBuilder {
+        self.alternate_addr = Some(addr);
+        self
+    }
+
+    /// Races the request of each transfer on both address families of the
+    /// server: it's sent to the address of the server, `stagger` later (e.g.
+    /// 250 milliseconds) to the alternate address too unless the server
+    /// answered already, and the transfer continues with the family that
+    /// answers first.
+    ///
+    /// A family blackholed by the network then delays transfers by the
+    /// stagger instead of failing them. Without an alternate address the
+    /// request is sent to the server address only.
+    pub fn happy_eyeballs(mut self, stagger: Duration) -> ClientBuilder {
+        self.happy_eyeballs = Some(stagger);
+        self
+    }
+
+    /// Sets the local address the client socket is bound to.
+    ///
+    /// By default the client binds to an ephemeral port on all interfaces.
+    pub fn local_addr(mut self, addr: SocketAddr) -> ClientBuilder {
+        self.local_addr = addr;
+        self
+    }
+
+    /// Restricts the client socket to the network interface `device`, e.g.
+    /// the management interface of a host with multiple interfaces.
+    ///
+    /// Uses `SO_BINDTODEVICE`, combine it with `local_addr` to pick the source
+    /// address too.
+    #[cfg(target_os = "linux")]
+    pub fn device<S: Into<String>>(mut self, device: S) -> ClientBuilder {
+        self.device = Some(device.into());
+        self
+    }
+
+    /// Sets the block size requested from the server (RFC 2348).
+    ///
+    /// The server may choose a smaller block size or ignore the option, in which
+    /// case the default block size of 512 bytes is used.
+    pub fn block_size(mut self, block_size: BlockSize) -> ClientBuilder {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the time to wait for a response before the last packet is sent again.
+    pub fn timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Asks the server to use the timeout of the client as its retransmission
+    /// interval too, e.g. for sub-second intervals on a LAN.
+    ///
+    /// Whole seconds are requested with the `timeout` option (RFC 2349), other
+    /// timeouts with the `utimeout` option in microseconds that only some
+    /// servers support, see `transfer::request_timeout`. Disabled by default.
+    pub fn negotiate_timeout(mut self, negotiate: bool) -> ClientBuilder {
+        self.negotiate_timeout = negotiate;
+        self
+    }
+
+    /// Sets the number of retransmissions of a packet before the transfer fails.
+    pub fn retries(mut self, retries: Retries) -> ClientBuilder {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the addresses the first reply of the server is accepted from.
+    ///
+    /// By default it must come from the address the request was sent to.
+    pub fn reply_policy(mut self, policy: ReplyPolicy) -> ClientBuilder {
+        self.reply_policy = policy;
+        self
+    }
+
+    /// Sets what the client does with packets the server must not send in the
+    /// state of the transfer.
+    ///
+    /// By default the server is sent an illegal operation error and the transfer fails.
+    pub fn unexpected_packets(mut self, policy: UnexpectedPacketPolicy) -> ClientBuilder {
+        self.unexpected_packets = policy;
+        self
+    }
+
+    /// Limits the size of files read from the server to `max_size` bytes.
+    ///
+    /// A read transfer of a larger file fails with `Error::TooLarge` as soon as
+    /// the limit is exceeded, the server is told with a disk full error. By
+    /// default the size is not limited.
+    pub fn max_size(mut self, max_size: u64) -> ClientBuilder {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Limits the time a whole transfer may take, independent of the timeout
+    /// of single packets.
+    ///
+    /// A transfer still running after `deadline` is terminated on the server
+    /// and fails with a `TimedOut` error. By default transfers take as long as
+    /// the server keeps responding.
+    pub fn deadline(mut self, deadline: Duration) -> ClientBuilder {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Accepts read transfers whose first data block is numbered 0 instead of
+    /// 1, a compatibility mode for broken servers that number blocks from 0,
+    /// also after an option acknowledgment.
+    ///
+    /// By default such blocks are ignored and the transfer times out.
+    pub fn accept_block_zero(mut self, accept: bool) -> ClientBuilder {
+        self.accept_block_zero = accept;
+        self
+    }
+
+    /// Works around a known bug of the server in the option negotiation, may
+    /// be called once per quirk.
+    ///
+    /// See `Client::with_quirks` to select the quirks per transfer.
+    pub fn quirk(mut self, quirk: Quirk) -> ClientBuilder {
+        if !self.quirks.contains(&quirk) {
+            self.quirks.push(quirk);
+        }
+        self
+    }
+
+    /// Sets how closely the server is held to the RFCs, lenient by default.
+    ///
+    /// Strict transfers ignore `accept_block_zero`, the reply policy allowing
+    /// another address and the quirks. The conformance and the deviations of
+    /// the server are counted in `ConformanceStats`.
+    pub fn conformance(mut self, conformance: Conformance) -> ClientBuilder {
+        self.conformance = conformance;
+        self
+    }
+
+    /// Sets whether a request the server rejects with an option negotiation
+    /// error is sent once more without options, enabled by default.
+    ///
+    /// Without options the transfer uses 512 byte blocks and the configured
+    /// timeout isn't negotiated, extensions of `get_with_options` and
+    /// `put_with_options` are not acknowledged.
+    pub fn option_fallback(mut self, fallback: bool) -> ClientBuilder {
+        self.option_fallback = fallback;
+        self
+    }
+
+    /// Restarts an upload with the block size of `fallback` once a block of
+    /// the larger negotiated size timed out `fallback.timeouts` times in a
+    /// row, e.g. because a hop drops the fragments of large datagrams.
+    ///
+    /// The transfer on the server is terminated with an error packet, the
+    /// write request is sent again asking for the smaller block size and the
+    /// data is sent from its start. An upload falls back once.
+    /// `ProtocolStats::block_size_fallbacks` counts the fallbacks. By default
+    /// uploads fail once their retries are exhausted.
+    pub fn block_size_fallback(mut self, fallback: BlockSizeFallback) -> ClientBuilder {
+        self.block_size_fallback = Some(fallback);
+        self
+    }
+
+    /// Sets the window size requested for transfers (RFC 7440), the number of
+    /// blocks sent before waiting for an acknowledgment.
+    ///
+    /// The server may lower it. Uploads pause between windows after a loss
+    /// until they are acknowledged without loss again, see
+    /// `transfer::CongestionWindow`. Downloads acknowledge the end of each
+    /// window, see `transfer::AckWindow`. By default the option is not
+    /// requested.
+    pub fn window_size(mut self, window_size: WindowSize) -> ClientBuilder {
+        self.window_size = window_size;
+        self
+    }
+
+    /// Sets the encoding of file names in requests, for servers that expect
+    /// names with non-ASCII characters in another encoding than UTF-8.
+    ///
+    /// Transfers of files whose name can't be encoded fail with an
+    /// `InvalidInput` error. By default names are sent as UTF-8.
+    pub fn filename_codec(mut self, codec: FilenameCodec) -> ClientBuilder {
+        self.filename_codec = codec;
+        self
+    }
+
+    /// Sets the prefixes of the messages of error packets the client sends,
+    /// by default they start with the standard reason code, see `reason`.
+    pub fn reason_format(mut self, format: ReasonFormat) -> ClientBuilder {
+        self.reasons = format;
+        self
+    }
+
+    /// Limits the unknown transfer ID errors answering datagrams from hosts
+    /// that aren't the server of a transfer. By default a host gets 5 errors
+    /// per second from all transfers of the client and its clones.
+    pub fn wrong_tid_limit(mut self, limit: RateLimit) -> ClientBuilder {
+        self.wrong_tid_limit = limit;
+        self
+    }
+
+    /// Collects the counters of the transfers of the client in `stats`, e.g.
+    /// to share one collector between clients.
+    ///
+    /// By default every client has its own collector, see `Client::stats`.
+    pub fn stats(mut self, stats: Stats) -> ClientBuilder {
+        self.stats = stats;
+        self
+    }
+
+    /// Sets the age after which temporary files of `Client::get_to_file` left
+    /// in the destination directory by other fetches are removed, one hour
+    /// by default.
+    ///
+    /// The age should be longer than the longest fetch, the temporary file of
+    /// a running fetch is removed otherwise.
+    pub fn stale_temp_age(mut self, age: Duration) -> ClientBuilder {
+        self.stale_temp_age = age;
+        self
+    }
+
+    /// Passes the datagrams of the sockets the client binds to `tap`.
+    ///
+    /// Transports passed to `get_over` and `put_over` are not tapped, they
+    /// can be wrapped in a `Tapped` instead.
+    pub fn tap(mut self, tap: Tap) -> ClientBuilder {
+        self.tap = Some(tap);
+        self
+    }
+
+    /// Keeps a journal of the last `capacity` events of each transfer, a
+    /// failed transfer returns it in `Error::Journaled`.
+    pub fn journal(mut self, capacity: usize) -> ClientBuilder {
+        self.journal = Some(capacity);
+        self
+    }
+
+    /// Buffers the data of read transfers in `spool` while the writer is
+    /// slow, the transfer keeps acknowledging blocks until the spool is full.
+    ///
+    /// Transfers then run on another thread, transports passed to `get_over`
+    /// are not spooled. By default a transfer waits for its writer.
+    pub fn spool(mut self, spool: Spool) -> ClientBuilder {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Draws the random numbers of the client from `rng`, seeded from entropy
+    /// by default.
+    ///
+    /// A generator of `SharedRng::seeded` makes runs of the client
+    /// reproducible, see `rng`.
+    pub fn rng(mut self, rng: SharedRng) -> ClientBuilder {
+        self.rng = rng;
+        self
+    }
+
+    /// Decompresses downloads that are gzip or zstd compressed before they
+    /// are written out, e.g. from servers hosting only compressed images.
+    ///
+    /// The format is detected from the magic number at the start of the file,
+    /// other files are written as they are. A download whose compressed data
+    /// is invalid or truncated fails. Limits like `max_size` apply to the
+    /// compressed file. By default files are written as they are received.
+    #[cfg(feature = "compression")]
+    pub fn decompress(mut self, detect: bool) -> ClientBuilder {
+        self.decompress = detect;
+        self
+    }
+
+    /// Asks the server for a session token, so transfers continue after the
+    /// address of the client changed, e.g. when DHCP renumbers it while it
+    /// boots. Only servers of this crate acknowledge the token, see `resume`.
+    /// Disabled by default.
+    #[cfg(feature = "experimental-resume")]
+    pub fn resumable(mut self, resumable: bool) -> ClientBuilder {
+        self.resumable = resumable;
+        self
+    }
+
+    /// Creates the configured client.
+    ///
+    /// Fails with all invalid settings at once, see `ConfigError::errors`:
+    /// zero durations, a local address of another address family than the
+    /// server, a spool smaller than a block and an invalid fallback block size.
+    pub fn build(self) -> result::Result<Client, ConfigError> {
+        let mut errors = Vec::new();
+        if let Err(err) = config::validate_timeout(self.timeout) {
+            errors.push(err);
+        }
+        if self.deadline == Some(Duration::new(0, 0)) {
+            errors.push(ConfigError::ZeroDuration("deadline"));
+        }
+        // An unspecified local address is bound in the family of the server.
+        if local_addr_for(self.local_addr, self.server_addr).is_ipv4() != self.server_addr.is_ipv4() {
+            errors.push(ConfigError::AddressFamily(self.local_addr, self.server_addr));
+        }
+        if let Some(ref spool) = self.spool {
+            if spool.max_bytes() < self.block_size.get() as u64 {
+                errors.push(ConfigError::SpoolTooSmall(spool.max_bytes(), self.block_size.get()));
+            }
+        }
+        if let Some(fallback) = self.block_size_fallback {
+            if let Err(err) = BlockSize::new(fallback.block_size) {
+                errors.push(err);
+            }
+        }
+        try!(config::all_valid(errors));
+        Ok(Client {
+            server_addr: self.server_addr,
+            alternate_addr: self.alternate_addr,
+            happy_eyeballs: self.happy_eyeballs,
+            local_addr: self.local_addr,
+            block_size: self.block_size,
+            timeout: self.timeout,
+            negotiate_timeout: self.negotiate_timeout,
+            retries: self.retries,
+            reply_policy: self.reply_policy,
+            unexpected_packets: self.unexpected_packets,
+            max_size: self.max_size,
+            deadline: self.deadline,
+            accept_block_zero: self.accept_block_zero,
+            quirks: self.quirks,
+            conformance: self.conformance,
+            filename_codec: self.filename_codec,
+            reasons: self.reasons.clone(),
+            wrong_tid_limit: self.wrong_tid_limit,
+            stats: self.stats,
+            stale_temp_age: self.stale_temp_age,
+            tap: self.tap,
+            journal: self.journal,
+            option_fallback: self.option_fallback,
+            block_size_fallback: self.block_size_fallback,
+            window_size: self.window_size,
+            spool: self.spool,
+            rng: self.rng,
+            #[cfg(feature = "compression")]
+            decompress: self.decompress,
+            #[cfg(feature = "experimental-resume")]
+            resumable: self.resumable,
+            #[cfg(target_os = "linux")]
+            device: self.device,
+        })
+    }
+}
+
+/// A TFTP client.
+///
+/// Transfers are driven by an event loop internally, the methods of the client
+/// block until the transfer is complete.
+#[derive(Debug, Clone)]
+pub struct Client {
+    server_addr: SocketAddr,
+    alternate_addr: Option<SocketAddr>,
+    happy_eyeballs: Option<Duration>,
+    local_addr: SocketAddr,
+    block_size: BlockSize,
+    timeout: Duration,
+    negotiate_timeout: bool,
+    retries: Retries,
+    reply_policy: ReplyPolicy,
+    unexpected_packets: UnexpectedPacketPolicy,
+    max_size: Option<u64>,
+    deadline: Option<Duration>,
+    accept_block_zero: bool,
+    quirks: Vec<Quirk>,
+    conformance: Conformance,
+    filename_codec: FilenameCodec,
+    reasons: ReasonFormat,
+    wrong_tid_limit: RateLimit,
+    stats: Stats,
+    stale_temp_age: Duration,
+    tap: Option<Tap>,
+    journal: Option<usize>,
+    option_fallback: bool,
+    block_size_fallback: Option<BlockSizeFallback>,
+    window_size: WindowSize,
+    spool: Option<Spool>,
+    rng: SharedRng,
+    #[cfg(feature = "compression")]
+    decompress: bool,
+    #[cfg(feature = "experimental-resume")]
+    resumable: bool,
+    #[cfg(target_os = "linux")]
+    device: Option<String>,
+}
+
+impl Client {
+    /// Creates a client of the server listening on `server_addr` using the default configuration.
+    pub fn new(server_addr: SocketAddr) -> Client {
+        ClientBuilder::new(server_addr).build().expect("default configuration is valid")
+    }
+
+    /// Reads a file from the server writing its contents to `writer`.
+    ///
+    /// Returns the parameters the transfer used after negotiation with the server.
+    pub fn get(&self, path: &Path, mode: Mode, writer: &mut io::Write) -> Result<TransferParams> {
+        self.get_with_options(path, mode, &TransferOptions::new(), writer).map(|(params, _)| params)
+    }
+
+    /// Reads a file from the server into `writer` like `get`, taking ownership
+    /// of the writer.
+    ///
+    /// Returns the result of the transfer with the writer, also if it failed.
+    pub fn get_into<W: io::Write>(&self, path: &Path, mode: Mode, mut writer: W) -> (Result<TransferParams>, W) {
+        let result = self.get(path, mode, &mut writer);
+        (result, writer)
+    }
+
+    /// Reads a file from the server into the local file `local_path`.
+    ///
+    /// The contents are written to a temporary file in the same directory
+    /// which replaces `local_path` once the transfer is complete, the
+    /// temporary file is removed if it fails. Stale temporary files of
+    /// earlier fetches of the same file are removed first.
+    pub fn get_to_file(&self, path: &Path, mode: Mode, local_path: &Path) -> Result<TransferParams> {
+        self.get_to_file_with_progress(path, mode, local_path, &mut |_| {})
+    }
+
+    /// Reads a file from the server into the local file `local_path` like
+    /// `get_to_file`, calling `progress` with the number of bytes written to
+    /// the temporary file so far.
+    pub fn get_to_file_with_progress(&self, path: &Path, mode: Mode, local_path: &Path, progress: &mut FnMut(u64))
+                                     -> Result<TransferParams> {
+        remove_stale_temp_files(local_path, self.stale_temp_age);
+        let (temp_path, file) = try!(create_temp_file(local_path, &self.rng));
+        let result = self.get_to_temp_file(path, mode, file, progress)
+            .and_then(|params| fs::rename(&temp_path, local_path).map(|_| params).map_err(Error::from));
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
+    }
+
+    fn get_to_temp_file(&self, path: &Path, mode: Mode, file: File, progress: &mut FnMut(u64))
+                        -> Result<TransferParams> {
+        let mut writer = ProgressWriter { inner: io::BufWriter::new(file), written: 0, progress: progress };
+        let params = try!(self.get(path, mode, &mut writer));
+        let file = try!(writer.inner.into_inner().map_err(|e| e.into_error()));
+        try!(file.sync_all());
+        Ok(params)
+    }
+
+    /// Writes a file to the server reading its contents from `reader`.
+    ///
+    /// Returns the parameters the transfer used after negotiation with the server.
+    pub fn put(&self, path: &Path, mode: Mode, reader: &mut io::Read) -> Result<TransferParams> {
+        self.put_from(path, mode, &mut ReadSource::with_window_size(reader, self.window_size))
+    }
+
+    /// Writes a file to the server reading its contents from `reader` like
+    /// `put`, taking ownership of the reader.
+    ///
+    /// Returns the result of the transfer with the reader, also if it failed.
+    pub fn put_owned<R: io::Read>(&self, path: &Path, mode: Mode, mut reader: R) -> (Result<TransferParams>, R) {
+        let result = self.put(path, mode, &mut reader);
+        (result, reader)
+    }
+
+    /// Writes a file to the server reading its contents from `source`.
+    ///
+    /// Blocks are read at their offsets, e.g. from a `File` or a slice, a
+    /// reader passed to `put` is read through a `ReadSource`.
+    pub fn put_from(&self, path: &Path, mode: Mode, source: &mut BlockSource) -> Result<TransferParams> {
+        let request = try!(self.write_request(path, mode, &self.requested(&TransferOptions::new())));
+        let (transport, server_addr) = try!(self.connect(&request));
+        self.put_over_with(transport, server_addr, path, mode, &TransferOptions::new(), source)
+            .map(|(params, _)| params)
+    }
+
+    /// Reads a file like `get`, appending the nonstandard `options` to the
+    /// request, e.g. to experiment with vendor extensions.
+    ///
+    /// Returns the options of them the server acknowledged too, the server
+    /// may change their values. Options the client negotiates itself
+    /// (`blksize`, `timeout`, `utimeout` and `windowsize`) are not sent.
+    pub fn get_with_options(&self, path: &Path, mode: Mode, options: &TransferOptions, writer: &mut io::Write)
+                            -> Result<(TransferParams, TransferOptions<'static>)> {
+        let request = try!(self.read_request(path, mode, &self.requested(options)));
+        let (transport, server_addr) = try!(self.connect(&request));
+        match self.spool {
+            Some(ref spool) => spool::run(spool, writer, |input| {
+                self.get_over_with(transport, server_addr, path, mode, options, input)
+            }),
+            None => self.get_over_with(transport, server_addr, path, mode, options, writer),
+        }
+    }
+
+    /// Writes a file like `put`, appending the nonstandard `options` to the
+    /// request, see `get_with_options`.
+    pub fn put_with_options(&self, path: &Path, mode: Mode, options: &TransferOptions, reader: &mut io::Read)
+                            -> Result<(TransferParams, TransferOptions<'static>)> {
+        let request = try!(self.write_request(path, mode, &self.requested(options)));
+        let (transport, server_addr) = try!(self.connect(&request));
+        self.put_over_with(transport, server_addr, path, mode, options,
+                           &mut ReadSource::with_window_size(reader, self.window_size))
+    }
+
+    /// Returns the size of a file on the server without transferring it.
+    ///
+    /// The file is requested with the transfer size option (RFC 2349) and the
+    /// transfer is terminated with an error packet once the server
+    /// acknowledged the option. Fails with `Error::Protocol` if the server
+    /// doesn't support the option and sends data instead.
+    pub fn probe_size(&self, path: &Path) -> Result<u64> {
+        let mut options = TransferOptions::new();
+        options.insert(TSIZE_OPTION, "0");
+        let request = try!(encode_request(RequestPacket::read_request_bytes(&path_bytes(path), Mode::Octet)
+            .with_options(options), self.filename_codec));
+        let (transport, server_addr) = try!(self.connect(&request));
+        let mut client = InternalClient::new(transport, server_addr, self.reply_policy, self.unexpected_packets,
+                                             DEFAULT_BLOCK_SIZE, self.reasons.clone(), self.stats.clone());
+        client.journal = self.journal.map(Journal::new);
+        client.wrong_tid_limit = self.wrong_tid_limit.clone();
+        client.set_conformance(self.conformance);
+        let mut transfer = ProbeTransfer::new(request, self.retries);
+        if let Err(err) = run(&mut client, &mut transfer, self.timeout, self.deadline) {
+            return Err(client.journaled(err))
+        }
+        Ok(transfer.size.expect("probe is done once the size is known"))
+    }
+
+    /// Returns a copy of the client working around `quirks` instead of the
+    /// configured ones, e.g. for the transfers from one buggy server.
+    ///
+    /// The copy shares the counters of the client.
+    pub fn with_quirks(&self, quirks: &[Quirk]) -> Client {
+        Client {
+            quirks: quirks.to_vec(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the collector of the counters of finished transfers, shared
+    /// with clones of the client.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Returns the generator of the random numbers of the client, its seed
+    /// reproduces a run.
+    pub fn rng(&self) -> &SharedRng {
+        &self.rng
+    }
+
+    /// Sends `error` to `addr` outside of a transfer, e.g. to reject a
+    /// transfer another tool started or to answer a stray packet.
+    ///
+    /// The packet is sent from a new socket bound like the sockets of transfers.
+    pub fn send_error(&self, addr: SocketAddr, error: &ErrorPacket) -> Result<()> {
+        let mut transport = try!(self.bind());
+        try!(send_packet(&mut transport, error, &addr, &mut Vec::new()));
+        Ok(())
+    }
+
+    /// Creates the socket of a transfer.
+    fn bind(&self) -> io::Result<Tapped<UdpTransport>> {
+        self.bind_to(local_addr_for(self.local_addr, self.server_addr))
+    }
+
+    /// Creates the socket of a transfer bound to `local_addr`.
+    fn bind_to(&self, local_addr: SocketAddr) -> io::Result<Tapped<UdpTransport>> {
+        let transport = try!(UdpTransport::bind(local_addr));
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(ref device) = self.device {
+                try!(transport.bind_to_device(device));
+            }
+        }
+        Ok(Tapped::optional(transport, self.tap.clone()))
+    }
+
+    /// Reads a file from the server at `server_addr` reachable through
+    /// `transport`, the configured addresses of the client are not used.
+    pub fn get_over<T>(&self, transport: T, server_addr: T::Addr, path: &Path, mode: Mode,
+                       writer: &mut io::Write) -> Result<TransferParams>
+        where T: Transport + Source,
+    {
+        self.get_over_with(transport, server_addr, path, mode, &TransferOptions::new(), writer)
+            .map(|(params, _)| params)
+    }
+
+    fn get_over_with<T>(&self, transport: T, server_addr: T::Addr, path: &Path, mode: Mode,
+                        extensions: &TransferOptions, writer: &mut io::Write)
+                        -> Result<(TransferParams, TransferOptions<'static>)>
+        where T: Transport + Source,
+    {
+        let requested = self.requested(extensions);
+        let request = try!(self.read_request(path, mode, &requested));
+        let mut client = InternalClient::new(transport, server_addr, self.reply_policy, self.unexpected_packets,
+                                             requested.block_size, self.reasons.clone(), self.stats.clone());
+        client.journal = self.journal.map(Journal::new);
+        client.wrong_tid_limit = self.wrong_tid_limit.clone();
+        client.set_conformance(self.conformance);
+        let plain_request = try!(self.plain_request(RequestPacket::read_request_bytes(&path_bytes(path), mode),
+                                                    &requested));
+        #[cfg(feature = "compression")]
+        let mut decoder = ContentDecoder::new(writer, self.decompress);
+        #[cfg(feature = "compression")]
+        let writer = &mut decoder;
+        let accept_block_zero = self.accept_block_zero && !self.conformance.is_strict();
+        let mut transfer = GetTransfer::new(request, requested, self.retries, self.max_size, accept_block_zero, writer);
+        transfer.plain_request = plain_request;
+        transfer.quirks = self.quirks();
+        if let Err(err) = run(&mut client, &mut transfer, self.timeout, self.deadline) {
+            return Err(client.journaled(transfer.interrupted(err)))
+        }
+        let mut params = TransferParams::new(transfer.transfer.block_size(), self.timeout);
+        params.window_size = transfer.window_size;
+        let options = mem::replace(&mut transfer.acknowledged_options, TransferOptions::new());
+        #[cfg(feature = "compression")]
+        {
+            // The server already completed the transfer, it isn't told.
+            let written = transfer.written;
+            try!(decoder.finish().map_err(|e| Error::Write(e, written)));
+        }
+        Ok((params, options))
+    }
+
+    /// Writes a file to the server at `server_addr` reachable through
+    /// `transport`, the configured addresses of the client are not used.
+    pub fn put_over<T>(&self, transport: T, server_addr: T::Addr, path: &Path, mode: Mode,
+                       reader: &mut io::Read) -> Result<TransferParams>
+        where T: Transport + Source,
+    {
+        self.put_over_with(transport, server_addr, path, mode, &TransferOptions::new(),
+                           &mut ReadSource::with_window_size(reader, self.window_size))
+            .map(|(params, _)| params)
+    }
+
+    fn put_over_with<T>(&self, transport: T, server_addr: T::Addr, path: &Path, mode: Mode,
+                        extensions: &TransferOptions, source: &mut BlockSource)
+                        -> Result<(TransferParams, TransferOptions<'static>)>
+        where T: Transport + Source,
+    {
+        let requested = self.requested(extensions);
+        let request = try!(self.write_request(path, mode, &requested));
+        let mut client = InternalClient::new(transport, server_addr, self.reply_policy, self.unexpected_packets,
+                                             requested.block_size, self.reasons.clone(), self.stats.clone());
+        client.journal = self.journal.map(Journal::new);
+        client.wrong_tid_limit = self.wrong_tid_limit.clone();
+        client.set_conformance(self.conformance);
+        let plain_request = try!(self.plain_request(RequestPacket::write_request_bytes(&path_bytes(path), mode),
+                                                    &requested));
+        let restart = match self.block_size_fallback {
+            Some(policy) if policy.block_size < requested.block_size => Some(Restart {
+                policy: policy,
+                request: try!(self.write_request(path, mode, &requested.with_block_size(policy.block_size))),
+            }),
+            _ => None,
+        };
+        let mut transfer = PutTransfer::new(request, requested, self.retries, source);
+        transfer.plain_request = plain_request;
+        transfer.restart = restart;
+        transfer.quirks = self.quirks();
+        let result = run(&mut client, &mut transfer, self.timeout, self.deadline);
+        client.stats.cache_hits(transfer.source.cache_hits());
+        if let Err(err) = result {
+            return Err(client.journaled(transfer.interrupted(err)))
+        }
+        let mut params = TransferParams::new(transfer.transfer.block_size(), self.timeout);
+        params.window_size = transfer.window_size;
+        Ok((params, mem::replace(&mut transfer.acknowledged_options, TransferOptions::new())))
+    }
+
+    /// Returns the encoded read request of a transfer requesting `requested`.
+    fn read_request(&self, path: &Path, mode: Mode, requested: &Requested) -> Result<RawPacket> {
+        encode_request(RequestPacket::read_request_bytes(&path_bytes(path), mode).with_options(requested.options()),
+                       self.filename_codec)
+    }
+
+    /// Returns the encoded write request of a transfer requesting `requested`.
+    fn write_request(&self, path: &Path, mode: Mode, requested: &Requested) -> Result<RawPacket> {
+        encode_request(RequestPacket::write_request_bytes(&path_bytes(path), mode).with_options(requested.options()),
+                       self.filename_codec)
+    }
+
+    /// Returns the transport of a transfer sending `request` and the address
+    /// of the server it's sent to, racing the address families of the server
+    /// with happy eyeballs.
+    fn connect(&self, request: &RawPacket) -> Result<(Raced, SocketAddr)> {
+        match (self.happy_eyeballs, self.alternate_addr) {
+            (Some(stagger), Some(alternate_addr)) => self.race(request.packet_buf(), alternate_addr, stagger),
+            _ => Ok((Raced::new(try!(self.bind())), self.server_addr)),
+        }
+    }
+
+    /// Sends `request` to the server address and `stagger` later to
+    /// `alternate_addr`, returns the transport of the family answering first
+    /// with its response.
+    ///
+    /// A family fails once its socket can't be bound or sending to it fails,
+    /// the other is tried right away then. Requests are retransmitted like in
+    /// transfers.
+    fn race(&self, request: &[u8], alternate_addr: SocketAddr, stagger: Duration) -> Result<(Raced, SocketAddr)> {
+        let addrs = [self.server_addr, alternate_addr];
+        let mut poll = try!(Poll::new());
+        let mut events = Events::with_capacity(4);
+        let mut sockets = Vec::new();
+        let mut failed: [Option<io::Error>; 2] = [None, None];
+        for (i, &addr) in addrs.iter().enumerate() {
+            let socket = self.bind_to(local_addr_for(self.local_addr, addr)).and_then(|mut socket| {
+                try!(poll.registry().register(&mut socket, Token(i), Interest::READABLE));
+                Ok(socket)
+            });
+            match socket {
+                Ok(socket) => sockets.push(Some(socket)),
+                Err(e) => {
+                    sockets.push(None);
+                    failed[i] = Some(e);
+                }
+            }
+        }
+        let mut buf = transport::receive_buffer(cmp::max(self.block_size.get(), DEFAULT_BLOCK_SIZE) + 4);
+        let started = Instant::now();
+        let mut sent = [false, false];
+        let mut deadline = started + self.timeout;
+        let mut retransmissions = 0;
+        loop {
+            let now = Instant::now();
+            for i in 0..2 {
+                let due = i == 0 || now >= started + stagger || failed[0].is_some();
+                if !sent[i] && failed[i].is_none() && due {
+                    let socket = sockets[i].as_mut().expect("socket of a family that didn't fail");
+                    match would_block(socket.send_to(request, &addrs[i])) {
+                        // A would-blocking request is sent again on the retransmission.
+                        Ok(_) => sent[i] = true,
+                        Err(e) => failed[i] = Some(e),
+                    }
+                }
+            }
+            for i in 0..2 {
+                while sent[i] && failed[i].is_none() {
+                    let received = {
+                        let socket = sockets[i].as_mut().expect("socket of a family that didn't fail");
+                        would_block(socket.recv_from(&mut buf))
+                    };
+                    match received {
+                        Ok(Some((n, from))) => {
+                            if UdpTransport::same_host(&addrs[i], &from) {
+                                let mut socket = sockets.swap_remove(i).expect("socket of a family that didn't fail");
+                                // The transfer registers it with its own event loop.
+                                try!(poll.registry().deregister(&mut socket));
+                                let transport = Raced {
+                                    inner: socket,
+                                    request: Some(request.to_vec()),
+                                    response: Some((buf[..n].to_vec(), from)),
+                                };
+                                return Ok((transport, addrs[i]))
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => failed[i] = Some(e),
+                    }
+                }
+            }
+            if failed[0].is_some() && failed[1].is_some() {
+                return Err(socket_error(failed[0].take().expect("failure is checked")))
+            }
+            if now >= deadline {
+                if retransmissions == self.retries.get() {
+                    return Err(timed_out())
+                }
+                retransmissions += 1;
+                for i in 0..2 {
+                    if let (true, Some(socket)) = (sent[i] && failed[i].is_none(), sockets[i].as_mut()) {
+                        let _ = socket.send_to(request, &addrs[i]);
+                    }
+                }
+                deadline = now + self.timeout;
+                continue
+            }
+            let mut wake_up = deadline;
+            if !sent[1] && failed[1].is_none() {
+                wake_up = cmp::min(wake_up, started + stagger);
+            }
+            try!(poll.poll(&mut events, Some(wake_up.saturating_duration_since(now))));
+        }
+    }
+
+    /// Returns the quirks a transfer works around, none in strict transfers.
+    fn quirks(&self) -> Vec<Quirk> {
+        if self.conformance.is_strict() { Vec::new() } else { self.quirks.clone() }
+    }
+
+    /// Returns `request` encoded without options if it's sent when the server
+    /// rejects the options of `requested`.
+    fn plain_request(&self, request: RequestPacket, requested: &Requested) -> Result<Option<RawPacket>> {
+        if !self.option_fallback || requested.options().is_empty() {
+            return Ok(None)
+        }
+        encode_request(request, self.filename_codec).map(Some)
+    }
+
+    /// Returns the options a transfer of the client requests.
+    ///
+    /// Extensions named like options the client negotiates itself are dropped.
+    fn requested(&self, extensions: &TransferOptions) -> Requested {
+        let mut requested = Requested {
+            block_size: self.block_size.get(),
+            timeout: if self.negotiate_timeout { Some(self.timeout) } else { None },
+            window_size: self.window_size,
+            extensions: TransferOptions::new(),
+        };
+        for &(ref name, ref value) in extensions.iter() {
+            let own = [BLKSIZE_OPTION, TIMEOUT_OPTION, UTIMEOUT_OPTION, WINDOWSIZE_OPTION].iter()
+                .any(|own| name.eq_ignore_ascii_case(own));
+            if !own {
+                requested.extensions.insert(name.clone().into_owned(), value.clone().into_owned());
+            }
+        }
+        #[cfg(feature = "experimental-resume")]
+        {
+            if self.resumable {
+                resume::request_token(&mut requested.extensions);
+            }
+        }
+        requested
+    }
+}
+
+/// Returns the local address of the socket of a transfer from `server_addr`,
+/// an unspecified local address of the other family is replaced by the
+/// unspecified address of the family of `server_addr`.
+fn local_addr_for(local_addr: SocketAddr, server_addr: SocketAddr) -> SocketAddr {
+    if local_addr.is_ipv4() == server_addr.is_ipv4() || !local_addr.ip().is_unspecified() {
+        return local_addr
+    }
+    let ip = if server_addr.is_ipv4() {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+    };
+    SocketAddr::new(ip, local_addr.port())
+}
+
+/// Transport of a transfer, after a race of the address families the socket
+/// of the family that answered first.
+///
+/// The request was sent during the race, so the transfer's first send of it
+/// is skipped and the response that won the race is received first. Without
+/// a race the socket is used as is.
+struct Raced {
+    inner: Tapped<UdpTransport>,
+    request: Option<Vec<u8>>,
+    response: Option<(Vec<u8>, SocketAddr)>,
+}
+
+impl Raced {
+    fn new(inner: Tapped<UdpTransport>) -> Raced {
+        Raced {
+            inner: inner,
+            request: None,
+            response: None,
+        }
+    }
+}
+
+impl Transport for Raced {
+    type Addr = SocketAddr;
+
+    fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        if self.request.take().map_or(false, |request| request == buf) {
+            return Ok(buf.len())
+        }
+        self.inner.send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        match self.response.take() {
+            Some((response, from)) => {
+                // Like the socket a longer response fills the buffer, it's detected as truncated.
+                let n = cmp::min(response.len(), buf.len());
+                buf[..n].copy_from_slice(&response[..n]);
+                Ok((n, from))
+            }
+            None => self.inner.recv_from(buf),
+        }
+    }
+
+    fn connect(&mut self, addr: &SocketAddr) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    fn disconnect(&mut self) -> io::Result<()> {
+        self.inner.disconnect()
+    }
+
+    fn same_host(a: &SocketAddr, b: &SocketAddr) -> bool {
+        UdpTransport::same_host(a, b)
+    }
+
+    fn send_data(&mut self, packet: &DataPacketOctet, addr: &SocketAddr, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        self.request = None;
+        self.inner.send_data(packet, addr, buffer)
+    }
+}
+
+impl Source for Raced {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.inner.register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+        self.inner.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.inner.deregister(registry)
+    }
+}
+
+/// Age of the temporary files of other fetches after which they are removed.
+const DEFAULT_STALE_TEMP_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Suffix of the temporary files of `Client::get_to_file`.
+const TEMP_SUFFIX: &'static str = ".tftp-part";
+
+/// Returns the prefix of the names of the temporary files of fetches of `path`.
+fn temp_prefix(path: &Path) -> String {
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    format!(".{}.", name)
+}
+
+/// Writer reporting the bytes written through it to `progress`.
+struct ProgressWriter<'a, W> {
+    inner: W,
+    written: u64,
+    progress: &'a mut FnMut(u64),
+}
+
+impl<'a, W: io::Write> io::Write for ProgressWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.written += n as u64;
+        (self.progress)(self.written);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Creates a new temporary file next to `path`, named after a random number
+/// of `rng` no other fetch uses.
+fn create_temp_file(path: &Path, rng: &SharedRng) -> io::Result<(PathBuf, File)> {
+    loop {
+        let temp = path.with_file_name(format!("{}{:016x}{}", temp_prefix(path), rng.next_u64(), TEMP_SUFFIX));
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((temp, file)),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Removes the temporary files of fetches of `path` not modified for `age`.
+///
+/// Failures are ignored, another process may be removing them too.
+fn remove_stale_temp_files(path: &Path, age: Duration) {
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let prefix = temp_prefix(path);
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(&prefix) || !name.ends_with(TEMP_SUFFIX) {
+            continue
+        }
+        let stale = entry.metadata().and_then(|metadata| metadata.modified()).ok()
+            .and_then(|modified| modified.elapsed().ok())
+            .map_or(false, |elapsed| elapsed >= age);
+        if stale {
+            let _ = fs::remove_file(entry.path());
+        }
+    }
+}
+
+/// Reads a file from the server listening on `server_addr` writing its contents to `writer`.
+///
+/// This is a shortcut for `Client::new(server_addr).get(path, mode, writer)`.
+pub fn get(server_addr: SocketAddr, path: &Path, mode: Mode, writer: &mut io::Write) -> Result<TransferParams> {
+    Client::new(server_addr).get(path, mode, writer)
+}
+
+/// Writes a file to the server listening on `server_addr` reading its contents from `reader`.
+///
+/// This is a shortcut for `Client::new(server_addr).put(path, mode, reader)`.
+pub fn put(server_addr: SocketAddr, path: &Path, mode: Mode, reader: &mut io::Read) -> Result<TransferParams> {
+    Client::new(server_addr).put(path, mode, reader)
+}
+
+/// Finds the servers reachable through `broadcast_addr`, e.g. `192.168.0.255:69`.
+///
+/// A read request for `probe` is broadcast and every host answering with data
+/// or an error within `wait` is a live server, so the probe file doesn't have
+/// to exist. Started transfers are terminated right away. Returns the server
+/// addresses using the port of `broadcast_addr`, in the order they answered.
+pub fn discover(broadcast_addr: SocketAddr, probe: &Path, wait: Duration) -> Result<Vec<SocketAddr>> {
+    let local_addr = match broadcast_addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    };
+    let mut socket = try!(::mio::net::UdpSocket::bind(local_addr.parse().unwrap()));
+    try!(socket.set_broadcast(true));
+    let mut poll = try!(Poll::new());
+    let mut events = Events::with_capacity(16);
+    try!(poll.registry().register(&mut socket, CLIENT, Interest::READABLE));
+
+    let request = RequestPacket::read_request_bytes(&path_bytes(probe), Mode::Octet).encode();
+    try!(socket.send_to(request.packet_buf(), broadcast_addr));
+    let deadline = Instant::now() + wait;
+    let mut servers = Vec::new();
+    let mut buf = vec![0; DEFAULT_BLOCK_SIZE + 4];
+    loop {
+        loop {
+            let (n, from) = match would_block(socket.recv_from(&mut buf)) {
+                Ok(Some(received)) => received,
+                Ok(None) => break,
+                Err(e) => return Err(Error::Io(e)),
+            };
+            if n < 2 {
+                continue
+            }
+            match Opcode::from_u16((buf[0] as u16) << 8 | buf[1] as u16) {
+                Some(Opcode::DATA) | Some(Opcode::OACK) => {
+                    let error = ReasonFormat::default().error_packet(packet::Error::Undefined, Reason::Probe,
+                                                                     "discovery probe").encode();
+                    let _ = socket.send_to(error.packet_buf(), from);
+                }
+                Some(Opcode::ERROR) => {}
+                _ => continue,
+            }
+            let server = SocketAddr::new(from.ip(), broadcast_addr.port());
+            if !servers.contains(&server) {
+                servers.push(server);
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(servers)
+        }
+        try!(poll.poll(&mut events, Some(deadline - now)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp;
+    use std::env;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::path::Path;
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use mio::event::Source;
+    use mio::{Interest, Registry, Token};
+
+    use packet::{self, Mode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket, OptionAckPacket, TransferOptions,
+                 EncodePacket, DecodePacket, Opcode, RawPacket, BLKSIZE_OPTION};
+    use config::{BlockSize, Quirk, Conformance, UnexpectedPacketPolicy, WindowSize, DEFAULT_TIMEOUT};
+    use transport::{Transport, UdpTransport};
+    use super::{Abort, Client, ClientBuilder, Error, Progress, discover};
+
+    /// Transport receiving every datagram twice.
+    struct Duplicating {
+        inner: UdpTransport,
+        repeat: Option<(Vec<u8>, SocketAddr)>,
+    }
+
+    impl Transport for Duplicating {
+        type Addr = SocketAddr;
+
+        fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+            self.inner.send_to(buf, addr)
+        }
+
+        fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            if let Some((datagram, from)) = self.repeat.take() {
+                buf[..datagram.len()].copy_from_slice(&datagram);
+                return Ok((datagram.len(), from))
+            }
+            let (n, from) = try!(self.inner.recv_from(buf));
+            self.repeat = Some((buf[..n].to_vec(), from));
+            Ok((n, from))
+        }
+
+        fn connect(&mut self, addr: &SocketAddr) -> io::Result<()> {
+            self.inner.connect(addr)
+        }
+    }
+
+    impl Source for Duplicating {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.inner.register(registry, token, interests)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.inner.reregister(registry, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            self.inner.deregister(registry)
+        }
+    }
+
+    /// Binds the port requests are sent to and serves them on a thread of its own.
+    fn fake_listener<T, F>(f: F) -> (SocketAddr, thread::JoinHandle<T>)
+        where T: Send + 'static,
+              F: FnOnce(UdpSocket) -> T + Send + 'static
+    {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        (server_addr, thread::spawn(move || f(listener)))
+    }
+
+    /// Receives a request, returns it with a socket of its own connected to the client.
+    fn accept(listener: &UdpSocket) -> (UdpSocket, Vec<u8>) {
+        let mut request = vec![0; 1024];
+        let (n, client) = listener.recv_from(&mut request).unwrap();
+        request.truncate(n);
+        let transfer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        transfer.connect(client).unwrap();
+        (transfer, request)
+    }
+
+    /// Serves the first request, from a socket connected to the client.
+    fn fake_server<T, F>(f: F) -> (SocketAddr, thread::JoinHandle<T>)
+        where T: Send + 'static,
+              F: FnOnce(UdpSocket, Vec<u8>) -> T + Send + 'static
+    {
+        fake_listener(move |listener| {
+            let (transfer, request) = accept(&listener);
+            f(transfer, request)
+        })
+    }
+
+    #[test]
+    fn duplicate_blocks_are_acknowledged_but_not_written() {
+        let contents: Vec<u8> = (0..1124).map(|i| i as u8).collect();
+        let served = contents.clone();
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            let blocks: Vec<&[u8]> = served.chunks(512).collect();
+            let mut acks = Vec::new();
+            let mut block_id = 1;
+            transfer.send(DataPacketOctet::from_slice(1, blocks[0]).encode().packet_buf()).unwrap();
+            // The client is done with the last block, its copy is never read.
+            while acks.last() != Some(&(blocks.len() as u16)) {
+                let n = transfer.recv(&mut buf).unwrap();
+                let ack = AckPacket::decode(&buf[..n]).unwrap().block_id();
+                acks.push(ack);
+                if ack == block_id && (block_id as usize) < blocks.len() {
+                    block_id += 1;
+                    let block = blocks[block_id as usize - 1];
+                    transfer.send(DataPacketOctet::from_slice(block_id, block).encode().packet_buf()).unwrap();
+                }
+            }
+            acks
+        });
+
+        let transport = Duplicating {
+            inner: UdpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap(),
+            repeat: None,
+        };
+        let mut received = Vec::new();
+        Client::new(server_addr).get_over(transport, server_addr, Path::new("file"), Mode::Octet, &mut received)
+            .unwrap();
+        assert_eq!(contents, received);
+        assert_eq!(vec![1, 1, 2, 2, 3], server.join().unwrap());
+    }
+
+    #[test]
+    fn get_to_file_replaces_file_and_removes_stale_temp_files() {
+        let dir = env::temp_dir().join(format!("tftp-get-to-file-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let stale = dir.join(".boot.img.1-0123456789abcdef.tftp-part");
+        let other = dir.join(".other.img.1-0123456789abcdef.tftp-part");
+        fs::write(&stale, b"stale").unwrap();
+        fs::write(&other, b"other").unwrap();
+        fs::write(dir.join("boot.img"), b"old").unwrap();
+
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            transfer.send(DataPacketOctet::from_slice(1, b"new").encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+        });
+
+        let client = ClientBuilder::new(server_addr).stale_temp_age(Duration::from_secs(0)).build().unwrap();
+        client.get_to_file(Path::new("boot.img"), Mode::Octet, &dir.join("boot.img")).unwrap();
+        server.join().unwrap();
+        assert_eq!(b"new", &fs::read(dir.join("boot.img")).unwrap()[..]);
+        assert!(!stale.exists());
+        // Only temporary files of the fetched file are removed.
+        assert!(other.exists());
+        assert_eq!(2, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn seeded_rng_names_temp_files_the_same() {
+        use rng::SharedRng;
+        use super::create_temp_file;
+
+        let dir = env::temp_dir().join(format!("tftp-seeded-temp-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("boot.img");
+        let (first, _) = create_temp_file(&path, &SharedRng::seeded(7)).unwrap();
+        // A taken name is skipped, the next one is the second number of the sequence.
+        let (second, _) = create_temp_file(&path, &SharedRng::seeded(7)).unwrap();
+        assert_ne!(first, second);
+        fs::remove_file(&first).unwrap();
+        fs::remove_file(&second).unwrap();
+        assert_eq!(first, create_temp_file(&path, &SharedRng::seeded(7)).unwrap().0);
+        assert!(first.to_string_lossy().ends_with(".boot.img.63cbe1e459320dd7.tftp-part"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn happy_eyeballs_continue_with_the_family_answering_first() {
+        let addrs: [SocketAddr; 3] = ["[::1]:69".parse().unwrap(), "[::2]:69".parse().unwrap(),
+                                      "127.0.0.1:69".parse().unwrap()];
+        let builder = ClientBuilder::resolve(&addrs[..]).unwrap();
+        assert_eq!((addrs[0], Some(addrs[2])), (builder.server_addr, builder.alternate_addr));
+
+        // The server address drops requests like a blackholed family.
+        let blackhole = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (alternate_addr, server) = fake_listener(move |listener| {
+            let mut buf = vec![0; 1024];
+            let (transfer, _) = accept(&listener);
+            transfer.send(DataPacketOctet::from_slice(1, b"abc").encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            // The request is sent once, the transfer doesn't repeat it.
+            listener.set_nonblocking(true).unwrap();
+            listener.recv_from(&mut buf).is_err()
+        });
+
+        let client = ClientBuilder::new(blackhole.local_addr().unwrap())
+            .alternate_addr(alternate_addr)
+            .happy_eyeballs(Duration::from_millis(50))
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        let mut received = Vec::new();
+        client.get(Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(b"abc", &received[..]);
+        assert!(server.join().unwrap());
+        let mut buf = vec![0; 1024];
+        let n = blackhole.recv(&mut buf).unwrap();
+        assert_eq!(Some(Opcode::RRQ), RawPacket::new(buf, n).opcode());
+    }
+
+    #[test]
+    fn owned_writers_are_returned_from_background_transfers() {
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            transfer.send(DataPacketOctet::from_slice(1, b"abc").encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+        });
+
+        let client = Client::new(server_addr);
+        let background = thread::spawn(move || client.get_into(Path::new("file"), Mode::Octet, Vec::new()));
+        let (result, received) = background.join().unwrap();
+        server.join().unwrap();
+        assert_eq!(b"abc", &received[..]);
+        assert_eq!(512, result.unwrap().block_size);
+    }
+
+    #[test]
+    fn owned_readers_and_writers_are_returned_from_failed_transfers() {
+        let (server_addr, server) = fake_listener(move |listener| {
+            let mut buf = vec![0; 1024];
+            let (transfer, _) = accept(&listener);
+            transfer.send(DataPacketOctet::from_slice(1, &[1; 512]).encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            transfer.send(ErrorPacket::new(packet::Error::AccessViolation, "gone").encode().packet_buf()).unwrap();
+
+            let (transfer, _) = accept(&listener);
+            let error = ErrorPacket::new(packet::Error::DiskFull, "no space left");
+            transfer.send(error.encode().packet_buf()).unwrap();
+        });
+
+        let client = Client::new(server_addr);
+        let (result, received) = client.get_into(Path::new("file"), Mode::Octet, Vec::new());
+        match result {
+            Err(Error::Interrupted(progress, err)) => {
+                assert_eq!(Progress { bytes: 512, blocks: 1, flushed: true }, progress);
+                match *err {
+                    Error::Server(ref error) => assert_eq!(packet::Error::AccessViolation, error.error()),
+                    ref other => panic!("unexpected cause: {:?}", other),
+                }
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        // The blocks received before the failure are kept.
+        assert_eq!(vec![1; 512], received);
+
+        let (result, reader) = client.put_owned(Path::new("file"), Mode::Octet, io::Cursor::new(b"abc".to_vec()));
+        match result {
+            Err(Error::Server(ref error)) => assert_eq!(packet::Error::DiskFull, error.error()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(b"abc".to_vec(), reader.into_inner());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn oversized_data_fails_transfer() {
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            transfer.send(DataPacketOctet::from_slice(1, &[0; 600]).encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            ErrorPacket::decode(&buf[..n]).map(|error| error.error())
+        });
+
+        let client = Client::new(server_addr);
+        let mut received = Vec::new();
+        match client.get(Path::new("file"), Mode::Octet, &mut received) {
+            Err(Error::PacketTooLarge(err)) => assert_eq!(516, err.max_len),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        // The truncated payload is not taken for the last block.
+        assert!(received.is_empty());
+        assert_eq!(Some(packet::Error::IllegalOperation), server.join().unwrap());
+    }
+
+    #[test]
+    fn rejected_options_are_dropped_once() {
+        let (server_addr, server) = fake_listener(move |listener| {
+            let mut buf = vec![0; 1024];
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (transfer, request) = accept(&listener);
+                let request = RequestPacket::decode(&request).unwrap();
+                requests.push(request.options().get(BLKSIZE_OPTION).map(str::to_owned));
+                if request.options().is_empty() {
+                    transfer.send(DataPacketOctet::from_slice(1, b"abc").encode().packet_buf()).unwrap();
+                    transfer.recv(&mut buf).unwrap();
+                } else {
+                    let error = ErrorPacket::new(packet::Error::OptionNegotiation, "options not supported");
+                    transfer.send(error.encode().packet_buf()).unwrap();
+                }
+            }
+            requests
+        });
+
+        let client = ClientBuilder::new(server_addr).block_size(BlockSize::new(256).unwrap()).build().unwrap();
+        let mut received = Vec::new();
+        let params = client.get(Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(b"abc".to_vec(), received);
+        assert_eq!(512, params.block_size);
+        assert_eq!(vec![Some("256".to_owned()), None], server.join().unwrap());
+        assert_eq!(1, client.stats().get().protocol.option_fallbacks);
+
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let error = ErrorPacket::new(packet::Error::OptionNegotiation, "options not supported");
+            transfer.send(error.encode().packet_buf()).unwrap();
+        });
+        let client = ClientBuilder::new(server_addr).block_size(BlockSize::new(256).unwrap()).option_fallback(false)
+            .build().unwrap();
+        match client.put(Path::new("file"), Mode::Octet, &mut &b"abc"[..]) {
+            Err(Error::Server(ref error)) => assert_eq!(packet::Error::OptionNegotiation, error.error()),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        server.join().unwrap();
+        assert_eq!(0, client.stats().get().protocol.option_fallbacks);
+    }
+
+    #[test]
+    #[cfg(any(feature = "max-blksize-1468", feature = "max-blksize-8192", feature = "max-blksize-65464"))]
+    fn uploads_fall_back_to_smaller_blocks() {
+        use config::Retries;
+        use transfer::BlockSizeFallback;
+
+        let contents: Vec<u8> = (0..1300).map(|i| i as u8).collect();
+        let (server_addr, server) = fake_listener(move |listener| {
+            let mut buf = vec![0; 2048];
+            let (transfer, request) = accept(&listener);
+            assert_eq!(Some("1024"), RequestPacket::decode(&request).unwrap().options().get(BLKSIZE_OPTION));
+            let mut options = TransferOptions::new();
+            options.insert(BLKSIZE_OPTION, "1024");
+            transfer.send(OptionAckPacket::new(options).encode().packet_buf()).unwrap();
+            // The path drops datagrams of more than 600 bytes, until the client gives up on them.
+            let abandoned = loop {
+                let n = transfer.recv(&mut buf).unwrap();
+                if let Some(error) = ErrorPacket::decode(&buf[..n]) {
+                    break error.error()
+                }
+                assert!(n > 600);
+            };
+
+            let (transfer, request) = accept(&listener);
+            assert_eq!(None, RequestPacket::decode(&request).unwrap().options().get(BLKSIZE_OPTION));
+            transfer.send(AckPacket::new(0).encode().packet_buf()).unwrap();
+            let mut received = Vec::new();
+            loop {
+                let n = transfer.recv(&mut buf).unwrap();
+                let data = DataPacketOctet::decode(&buf[..n]).unwrap();
+                received.extend_from_slice(data.data());
+                transfer.send(AckPacket::new(data.block_id()).encode().packet_buf()).unwrap();
+                if data.data().len() < 512 {
+                    break (abandoned, received)
+                }
+            }
+        });
+
+        let client = ClientBuilder::new(server_addr).block_size(BlockSize::new(1024).unwrap())
+            .timeout(Duration::from_millis(50))
+            .block_size_fallback(BlockSizeFallback::new(Retries::new(2).unwrap(), 512))
+            .build().unwrap();
+        let params = client.put(Path::new("file"), Mode::Octet, &mut &contents[..]).unwrap();
+        assert_eq!(512, params.block_size);
+        assert_eq!((packet::Error::Undefined, contents), server.join().unwrap());
+        assert_eq!(1, client.stats().get().protocol.block_size_fallbacks);
+    }
+
+    #[test]
+    fn uploads_send_window_again_after_lost_block() {
+        let contents: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let (server_addr, server) = fake_server(move |transfer, request| {
+            let mut buf = vec![0; 1024];
+            assert_eq!(Some("8"), RequestPacket::decode(&request).unwrap().options().get("windowsize"));
+            // Shorter than the timeout of the client, a window ending early leaves the server waiting.
+            transfer.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+            let mut options = TransferOptions::new();
+            options.insert("windowsize", "4");
+            transfer.send(OptionAckPacket::new(options).encode().packet_buf()).unwrap();
+            // Acknowledges like a receiver of 4 block windows (RFC 7440), the
+            // end of each window and the first block after a lost one.
+            let (mut received, mut in_window, mut gap, mut dropped) = (Vec::new(), 0, false, false);
+            let mut expected = 1;
+            loop {
+                let n = transfer.recv(&mut buf).expect("window ended early");
+                let data = DataPacketOctet::decode(&buf[..n]).unwrap();
+                if data.block_id() == 3 && !dropped {
+                    dropped = true;
+                    continue
+                }
+                if data.block_id() != expected {
+                    // The window starts again after the last received block.
+                    if !gap {
+                        transfer.send(AckPacket::new(expected - 1).encode().packet_buf()).unwrap();
+                        in_window = 0;
+                        gap = true;
+                    }
+                    continue
+                }
+                gap = false;
+                received.extend_from_slice(data.data());
+                in_window += 1;
+                expected += 1;
+                let last = data.data().len() < 512;
+                if in_window == 4 || last {
+                    transfer.send(AckPacket::new(data.block_id()).encode().packet_buf()).unwrap();
+                    in_window = 0;
+                }
+                if last {
+                    break received
+                }
+            }
+        });
+
+        let client = ClientBuilder::new(server_addr).window_size(WindowSize::new(8).unwrap()).build().unwrap();
+        let params = client.put(Path::new("file"), Mode::Octet, &mut &contents[..]).unwrap();
+        assert_eq!(4, params.window_size.get());
+        assert_eq!(contents, server.join().unwrap());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_downloads_are_decompressed() {
+        use std::io::Write;
+
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+
+        let contents: Vec<u8> = (0..4000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&contents).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert!(compressed.len() > 512 && compressed.len() % 512 != 0);
+        let data = compressed.clone();
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            for (i, block) in data.chunks(512).enumerate() {
+                transfer.send(DataPacketOctet::from_slice(i as u16 + 1, block).encode().packet_buf()).unwrap();
+                transfer.recv(&mut buf).unwrap();
+            }
+        });
+
+        let client = ClientBuilder::new(server_addr).decompress(true).build().unwrap();
+        let mut received = Vec::new();
+        client.get(Path::new("image"), Mode::Octet, &mut received).unwrap();
+        server.join().unwrap();
+        assert_eq!(contents, received);
+    }
+
+    #[test]
+    fn failed_transfer_returns_journal() {
+        use journal::Event;
+        use packet::Opcode;
+        use replay::Direction;
+
+        let (server_addr, server) = fake_listener(move |listener| {
+            // The first request is lost.
+            listener.recv_from(&mut [0; 1024]).unwrap();
+            let (transfer, _) = accept(&listener);
+            let error = ErrorPacket::new(packet::Error::FileNotFound, "no such file");
+            transfer.send(error.encode().packet_buf()).unwrap();
+        });
+
+        let client = ClientBuilder::new(server_addr).timeout(Duration::from_millis(50)).journal(16).build().unwrap();
+        let (journal, err) = match client.get(Path::new("file"), Mode::Octet, &mut Vec::new()) {
+            Err(Error::Journaled(journal, err)) => (journal, err),
+            result => panic!("unexpected result: {:?}", result),
+        };
+        server.join().unwrap();
+        match *err {
+            Error::Server(ref error) => assert_eq!(packet::Error::FileNotFound, error.error()),
+            ref err => panic!("unexpected error: {:?}", err),
+        }
+        let events: Vec<_> = journal.entries().map(|entry| entry.event.clone()).collect();
+        let request = |direction| Event::Datagram { direction: direction, opcode: Some(Opcode::RRQ), block: None,
+                                                    len: 13 };
+        assert_eq!(vec![Event::State("sending request"), request(Direction::Sent),
+                        Event::TimerArmed(Duration::from_millis(50)), Event::State("receiving data"),
+                        Event::TimerFired, Event::State("sending request"), request(Direction::Sent),
+                        Event::TimerArmed(Duration::from_millis(50)), Event::State("receiving data")],
+                   &events[..9]);
+        match events[9] {
+            Event::Datagram { direction: Direction::Received, opcode: Some(Opcode::ERROR), .. } => {}
+            ref event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(Error::Journaled(journal, err).to_string().contains("timer fired"));
+    }
+
+    #[test]
+    fn blocks_numbered_from_zero_are_accepted_when_enabled() {
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            let mut acks = Vec::new();
+            for (block_id, data) in vec![(0, &[1; 512][..]), (1, &b"abc"[..])] {
+                transfer.send(DataPacketOctet::from_slice(block_id, data).encode().packet_buf()).unwrap();
+                let n = transfer.recv(&mut buf).unwrap();
+                acks.push(AckPacket::decode(&buf[..n]).unwrap().block_id());
+            }
+            acks
+        });
+
+        let client = ClientBuilder::new(server_addr).accept_block_zero(true).build().unwrap();
+        let mut received = Vec::new();
+        client.get(Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(515, received.len());
+        assert_eq!(vec![0, 1], server.join().unwrap());
+        assert_eq!(1, client.stats().get().conformance.tolerated);
+    }
+
+    #[test]
+    fn strict_transfers_refuse_deviations() {
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            transfer.send(DataPacketOctet::from_slice(0, &[1; 512]).encode().packet_buf()).unwrap();
+            // An error without the zero byte terminating its message.
+            transfer.send(b"\x00\x05\x00\x01no such file").unwrap();
+            transfer.send(DataPacketOctet::from_slice(1, b"abc").encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            AckPacket::decode(&buf[..n]).unwrap().block_id()
+        });
+
+        let client = ClientBuilder::new(server_addr).accept_block_zero(true).conformance(Conformance::Strict)
+            .build().unwrap();
+        let mut received = Vec::new();
+        client.get(Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(b"abc", &received[..]);
+        assert_eq!(1, server.join().unwrap());
+        let conformance = client.stats().get().conformance;
+        assert_eq!((1, 0, 0, 2),
+                   (conformance.strict_transfers, conformance.lenient_transfers, conformance.tolerated,
+                    conformance.refused));
+    }
+
+    #[test]
+    fn lost_write_requests_are_retransmitted_with_backoff() {
+        let (server_addr, server) = fake_listener(move |listener| {
+            let mut buf = vec![0; 1024];
+            let mut received_at = Vec::new();
+            // The first two requests are lost.
+            for _ in 0..2 {
+                let (n, _) = listener.recv_from(&mut buf).unwrap();
+                assert!(RequestPacket::decode(&buf[..n]).is_some());
+                received_at.push(Instant::now());
+            }
+            let (transfer, request) = accept(&listener);
+            assert!(RequestPacket::decode(&request).is_some());
+            received_at.push(Instant::now());
+            transfer.send(AckPacket::new(0).encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            assert_eq!(Some(1), DataPacketOctet::decode(&buf[..n]).map(|data| data.block_id()));
+            // Late acknowledgment of the request must not trigger another data packet.
+            transfer.send(AckPacket::new(0).encode().packet_buf()).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            transfer.send(AckPacket::new(1).encode().packet_buf()).unwrap();
+            transfer.set_nonblocking(true).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            let mut repeated = 0;
+            while transfer.recv(&mut buf).is_ok() {
+                repeated += 1;
+            }
+            (received_at, repeated)
+        });
+
+        let client = ClientBuilder::new(server_addr).timeout(Duration::from_millis(100)).build().unwrap();
+        client.put(Path::new("file"), Mode::Octet, &mut &b"abc"[..]).unwrap();
+        let (received_at, repeated) = server.join().unwrap();
+        assert!(received_at[2] - received_at[1] >= Duration::from_millis(190));
+        assert_eq!(0, repeated);
+        let stats = client.stats().get();
+        assert_eq!(2, stats.protocol.retransmissions);
+        assert_eq!(1, stats.protocol.duplicates);
+        assert_eq!(4, stats.socket.datagrams_sent);
+        assert_eq!(3, stats.socket.datagrams_received);
+        // Only the response to the data packet is timed, the request was retransmitted.
+        assert_eq!(1, stats.response_times.count());
+        assert!(stats.response_times.min().unwrap() >= Duration::from_millis(50));
+    }
+
+    /// Transport that is never connected, datagrams from other sources reach the client.
+    struct Unconnected(UdpTransport);
+
+    impl Transport for Unconnected {
+        type Addr = SocketAddr;
+
+        fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+            self.0.send_to(buf, addr)
+        }
+
+        fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.0.recv_from(buf)
+        }
+    }
+
+    impl Source for Unconnected {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.0.register(registry, token, interests)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.0.reregister(registry, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            self.0.deregister(registry)
+        }
+    }
+
+    #[test]
+    fn packets_from_unknown_transfer_ids_are_counted() {
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            transfer.send(DataPacketOctet::from_slice(1, &[0; 512]).encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+            stranger.connect(transfer.peer_addr().unwrap()).unwrap();
+            stranger.send(DataPacketOctet::from_slice(2, b"x").encode().packet_buf()).unwrap();
+            let n = stranger.recv(&mut buf).unwrap();
+            let error = ErrorPacket::decode(&buf[..n]).map(|error| error.error());
+            transfer.send(DataPacketOctet::from_slice(2, b"abc").encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            error
+        });
+
+        let client = Client::new(server_addr);
+        let transport = Unconnected(UdpTransport::bind("127.0.0.1:0".parse().unwrap()).unwrap());
+        let mut received = Vec::new();
+        client.get_over(transport, server_addr, Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(515, received.len());
+        assert_eq!(Some(packet::Error::UnknownTransferId), server.join().unwrap());
+        let stats = client.stats().get();
+        assert_eq!(1, stats.socket.wrong_tid_discarded);
+        assert_eq!(4, stats.socket.datagrams_sent);
+        assert_eq!(3, stats.socket.datagrams_received);
+        assert_eq!(0, stats.protocol.unexpected_packets);
+    }
+
+    /// Refuses all data.
+    struct FullDisk;
+
+    impl Write for FullDisk {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(Abort::new(packet::Error::DiskFull, "no space left").into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Disk with space for `space` bytes.
+    struct SmallDisk {
+        space: usize,
+    }
+
+    impl Write for SmallDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.space == 0 {
+                return Err(io::Error::new(io::ErrorKind::StorageFull, "no space left on device"))
+            }
+            let n = cmp::min(self.space, buf.len());
+            self.space -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Serves blocks of 512 bytes to one client until it sends an error packet.
+    fn serve_until_error() -> (SocketAddr, thread::JoinHandle<ErrorPacket<'static>>) {
+        fake_server(move |transfer, request| {
+            let mut buf = vec![0; 1024];
+            assert!(RequestPacket::decode(&request).is_some());
+            for block_id in 1.. {
+                transfer.send(DataPacketOctet::from_slice(block_id, &[0; 512]).encode().packet_buf()).unwrap();
+                // The client may be gone already, the error packet is still queued.
+                let n = loop {
+                    match transfer.recv(&mut buf) {
+                        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                        result => break result.unwrap(),
+                    }
+                };
+                match AckPacket::decode(&buf[..n]) {
+                    Some(ack) => assert_eq!(block_id, ack.block_id()),
+                    None => return ErrorPacket::decode(&buf[..n]).unwrap().into_owned(),
+                }
+            }
+            unreachable!()
+        })
+    }
+
+    #[test]
+    fn full_disk_is_reported_to_server() {
+        let (server_addr, server) = serve_until_error();
+        match Client::new(server_addr).get(Path::new("file"), Mode::Octet, &mut SmallDisk { space: 700 }) {
+            Err(Error::Interrupted(progress, err)) => {
+                assert_eq!(Progress { bytes: 700, blocks: 1, flushed: true }, progress);
+                match *err {
+                    Error::Write(e, written) => {
+                        assert_eq!(io::ErrorKind::StorageFull, e.kind());
+                        assert_eq!(700, written);
+                    }
+                    other => panic!("unexpected cause: {:?}", other),
+                }
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(packet::Error::DiskFull, server.join().unwrap().error());
+    }
+
+    #[test]
+    fn interrupted_write_reports_acknowledged_data() {
+        let (server_addr, _) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            transfer.send(AckPacket::new(0).encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            transfer.send(AckPacket::new(1).encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            transfer.send(ErrorPacket::new(packet::Error::DiskFull, "full").encode().packet_buf()).unwrap();
+        });
+
+        let contents = vec![0; 1500];
+        match Client::new(server_addr).put(Path::new("file"), Mode::Octet, &mut &contents[..]) {
+            Err(Error::Interrupted(progress, err)) => {
+                assert_eq!(Progress { bytes: 512, blocks: 1, flushed: false }, progress);
+                match *err {
+                    Error::Server(ref error) => assert_eq!(packet::Error::DiskFull, error.error()),
+                    ref other => panic!("unexpected cause: {:?}", other),
+                }
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    /// Writer taking `delay` for each write.
+    struct SlowDisk {
+        data: Vec<u8>,
+        delay: Duration,
+    }
+
+    impl Write for SlowDisk {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            thread::sleep(self.delay);
+            self.data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn spool_keeps_acknowledging_while_writer_stalls() {
+        use spool::Spool;
+
+        for spool in vec![Spool::memory(4096), Spool::temp_file(4096)] {
+            // The server gives up on blocks not acknowledged within 200 ms.
+            let (server_addr, server) = fake_server(move |transfer, _| {
+                let mut buf = vec![0; 1024];
+                transfer.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+                for &(block_id, len) in &[(1, 512), (2, 512), (3, 100)] {
+                    let data = vec![block_id as u8; len];
+                    transfer.send(DataPacketOctet::from_slice(block_id, &data).encode().packet_buf()).unwrap();
+                    let n = transfer.recv(&mut buf).unwrap();
+                    assert_eq!(Some(block_id), AckPacket::decode(&buf[..n]).map(|ack| ack.block_id()));
+                }
+            });
+
+            let client = ClientBuilder::new(server_addr).spool(spool).build().unwrap();
+            let mut disk = SlowDisk { data: Vec::new(), delay: Duration::from_millis(500) };
+            client.get(Path::new("file"), Mode::Octet, &mut disk).unwrap();
+            server.join().unwrap();
+            assert_eq!(1124, disk.data.len());
+            assert_eq!(&[3; 100][..], &disk.data[1024..]);
+        }
+    }
+
+    #[test]
+    fn spool_overflow_is_reported_to_server() {
+        use spool::Spool;
+
+        let (server_addr, server) = serve_until_error();
+        let client = ClientBuilder::new(server_addr).spool(Spool::memory(2048)).build().unwrap();
+        let mut disk = SlowDisk { data: Vec::new(), delay: Duration::from_millis(50) };
+        match client.get(Path::new("file"), Mode::Octet, &mut disk) {
+            Err(Error::Interrupted(_, err)) => match *err {
+                Error::SpoolOverflow(2048) => {}
+                other => panic!("unexpected cause: {:?}", other),
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(packet::Error::DiskFull, server.join().unwrap().error());
+        // The spooled blocks are still written out.
+        assert!(disk.data.len() >= 2048);
+    }
+
+    #[test]
+    fn file_larger_than_max_size_is_refused() {
+        let (server_addr, server) = serve_until_error();
+        let client = ClientBuilder::new(server_addr).max_size(1024).build().unwrap();
+        let mut received = Vec::new();
+        match client.get(Path::new("file"), Mode::Octet, &mut received) {
+            Err(Error::Interrupted(progress, err)) => {
+                assert_eq!(Progress { bytes: 1024, blocks: 2, flushed: true }, progress);
+                match *err {
+                    Error::TooLarge(1024) => {}
+                    other => panic!("unexpected cause: {:?}", other),
+                }
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(1024, received.len());
+        assert_eq!(packet::Error::DiskFull, server.join().unwrap().error());
+    }
+
+    #[test]
+    fn transfer_deadline_is_enforced() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = ClientBuilder::new(server.local_addr().unwrap())
+            .deadline(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        let started = Instant::now();
+        match client.get(Path::new("file"), Mode::Octet, &mut Vec::new()) {
+            Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::TimedOut => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(started.elapsed() < DEFAULT_TIMEOUT);
+        let mut buf = vec![0; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        assert!(RequestPacket::decode(&buf[..n]).is_some());
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(packet::Error::Undefined, ErrorPacket::decode(&buf[..n]).unwrap().error());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn standalone_error_is_sent() {
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = Client::new(peer.local_addr().unwrap());
+        let error = ErrorPacket::new(packet::Error::IllegalOperation, "not a transfer");
+        client.send_error(peer.local_addr().unwrap(), &error).unwrap();
+        let mut buf = vec![0; 512];
+        let n = peer.recv(&mut buf).unwrap();
+        assert_eq!(Some(error), ErrorPacket::decode(&buf[..n]));
+    }
+
+    #[test]
+    fn invalid_settings_are_reported_together() {
+        use config::ConfigError;
+        use spool::Spool;
+
+        let server_addr = "[::1]:69".parse().unwrap();
+        let local_addr = "127.0.0.1:0".parse().unwrap();
+        let err = ClientBuilder::new(server_addr)
+            .timeout(Duration::from_secs(0))
+            .local_addr(local_addr)
+            .spool(Spool::memory(100))
+            .build()
+            .unwrap_err();
+        assert_eq!(&[ConfigError::ZeroTimeout,
+                     ConfigError::AddressFamily(local_addr, server_addr),
+                     ConfigError::SpoolTooSmall(100, 512)], err.errors());
+        // The default local address is bound in the family of the server.
+        assert!(ClientBuilder::new(server_addr).build().is_ok());
+    }
+
+    #[test]
+    fn unknown_device_is_rejected() {
+        let client = ClientBuilder::new("127.0.0.1:69".parse().unwrap()).device("tftp-none0").build().unwrap();
+        match client.get(Path::new("file"), Mode::Octet, &mut Vec::new()) {
+            Err(Error::Io(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn answering_servers_are_discovered() {
+        let (server_addr, server) = fake_server(move |transfer, request| {
+            let mut buf = vec![0; 1024];
+            assert!(RequestPacket::decode(&request).is_some());
+            transfer.send(DataPacketOctet::from_slice(1, b"probe").encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            ErrorPacket::decode(&buf[..n]).unwrap().into_owned()
+        });
+        let servers = discover(server_addr, Path::new("probe"), Duration::from_millis(200)).unwrap();
+        assert_eq!(vec![server_addr], servers);
+        assert_eq!(packet::Error::Undefined, server.join().unwrap().error());
+    }
+
+    #[test]
+    fn closed_server_port_fails_fast() {
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            // The server goes away right after the first block.
+            transfer.send(DataPacketOctet::from_slice(1, &[0; 512]).encode().packet_buf()).unwrap();
+        });
+        let started = Instant::now();
+        match Client::new(server_addr).get(Path::new("file"), Mode::Octet, &mut Vec::new()) {
+            Err(Error::Interrupted(_, ref err)) => match **err {
+                Error::PortUnreachable(_) => {}
+                ref other => panic!("unexpected error: {:?}", other),
+            },
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(started.elapsed() < DEFAULT_TIMEOUT);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn size_is_probed_without_transfer() {
+        let (server_addr, server) = fake_server(move |transfer, request| {
+            let mut buf = vec![0; 1024];
+            let tsize = RequestPacket::decode(&request).unwrap().options().get("tsize").map(|v| v.to_owned());
+            let mut options = TransferOptions::new();
+            options.insert("tsize", "1048576");
+            transfer.send(OptionAckPacket::new(options).encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            (tsize, ErrorPacket::decode(&buf[..n]).unwrap().into_owned())
+        });
+        assert_eq!(1048576, Client::new(server_addr).probe_size(Path::new("disk.img")).unwrap());
+        let (tsize, error) = server.join().unwrap();
+        assert_eq!(Some("0".to_owned()), tsize);
+        assert_eq!(packet::Error::OptionNegotiation, error.error());
+    }
+
+    #[test]
+    fn sub_second_timeout_is_negotiated() {
+        let (server_addr, server) = fake_server(move |transfer, request| {
+            let mut buf = vec![0; 1024];
+            let utimeout = RequestPacket::decode(&request).unwrap().options().get("utimeout").map(|v| v.to_owned());
+            let mut options = TransferOptions::new();
+            options.insert("utimeout", "200000");
+            transfer.send(OptionAckPacket::new(options).encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            assert_eq!(Some(AckPacket::new(0)), AckPacket::decode(&buf[..n]));
+            transfer.send(DataPacketOctet::from_slice(1, b"abc").encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            assert_eq!(Some(AckPacket::new(1)), AckPacket::decode(&buf[..n]));
+            utimeout
+        });
+        let client = ClientBuilder::new(server_addr)
+            .timeout(Duration::from_millis(200))
+            .negotiate_timeout(true)
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        client.get(Path::new("file"), Mode::Octet, &mut data).unwrap();
+        assert_eq!(b"abc", &data[..]);
+        assert_eq!(Some("200000".to_owned()), server.join().unwrap());
+    }
+
+    #[test]
+    fn option_ack_is_acknowledged_with_block_one() {
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            let mut options = TransferOptions::new();
+            options.insert("blksize", "256");
+            transfer.send(OptionAckPacket::new(options).encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            assert_eq!(Some(AckPacket::new(1)), AckPacket::decode(&buf[..n]));
+            transfer.send(DataPacketOctet::from_slice(1, b"abc").encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            assert_eq!(Some(AckPacket::new(1)), AckPacket::decode(&buf[..n]));
+        });
+        let client = ClientBuilder::new(server_addr)
+            .block_size(BlockSize::new(256).unwrap())
+            .quirk(Quirk::OptionAckBlockOne)
+            .build()
+            .unwrap();
+        let mut data = Vec::new();
+        client.get(Path::new("file"), Mode::Octet, &mut data).unwrap();
+        assert_eq!(b"abc", &data[..]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn repeated_option_ack_acknowledges_first_block() {
+        let (server_addr, server) = fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            let mut options = TransferOptions::new();
+            options.insert("blksize", "256");
+            let oack = OptionAckPacket::new(options).encode();
+            transfer.send(oack.packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            let data = DataPacketOctet::decode(&buf[..n]).map(|data| (data.block_id(), data.data().to_vec()));
+            // The server acknowledges the data with its options again.
+            transfer.send(oack.packet_buf()).unwrap();
+            data
+        });
+        let client = ClientBuilder::new(server_addr)
+            .block_size(BlockSize::new(256).unwrap())
+            .timeout(Duration::from_millis(200))
+            .build()
+            .unwrap()
+            .with_quirks(&[Quirk::RepeatedOptionAck]);
+        client.put(Path::new("file"), Mode::Octet, &mut &b"abc"[..]).unwrap();
+        assert_eq!(Some((1, b"abc".to_vec())), server.join().unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "experimental-resume")]
+    fn transfers_continue_once_the_server_rejects_a_new_address() {
+        use resume::SESSION_OPTION;
+
+        let (server_addr, server) = fake_server(move |transfer, request| {
+            let mut buf = vec![0; 1024];
+            assert_eq!(Some("1"), RequestPacket::decode(&request).unwrap().options().get(SESSION_OPTION));
+            let mut options = TransferOptions::new();
+            options.insert(SESSION_OPTION, "00000000000000ff");
+            transfer.send(OptionAckPacket::new(options).encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            transfer.send(DataPacketOctet::from_slice(1, &[1; 512]).encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            // The acknowledgment came from an address the server doesn't know.
+            let error = ErrorPacket::new(packet::Error::UnknownTransferId, "unknown transfer id");
+            transfer.send(error.encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            let resume = RequestPacket::decode(&buf[..n]).map(|request| {
+                (request.filename().unwrap().into_owned(), request.options().get(SESSION_OPTION).map(str::to_owned))
+            });
+            transfer.send(DataPacketOctet::from_slice(2, b"abc").encode().packet_buf()).unwrap();
+            transfer.recv(&mut buf).unwrap();
+            resume
+        });
+
+        let client = ClientBuilder::new(server_addr).resumable(true).build().unwrap();
+        let mut received = Vec::new();
+        client.get(Path::new("file"), Mode::Octet, &mut received).unwrap();
+        let mut expected = vec![1; 512];
+        expected.extend_from_slice(b"abc");
+        assert_eq!(expected, received);
+        assert_eq!(Some(("file".to_owned(), Some("00000000000000ff".to_owned()))), server.join().unwrap());
+    }
+
+    #[test]
+    fn aborted_transfer_is_reported_to_server() {
+        let (server_addr, server) = serve_until_error();
+        match Client::new(server_addr).get(Path::new("file"), Mode::Octet, &mut FullDisk) {
+            Err(Error::Aborted(abort)) => assert_eq!(packet::Error::DiskFull, abort.error()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let error = server.join().unwrap();
+        assert_eq!(packet::Error::DiskFull, error.error());
+        assert_eq!(Some("no space left"), error.message().as_ref().map(|m| &m[..]));
+    }
+
+    /// Answers a read request with an acknowledgment followed by a file of
+    /// three bytes, returns the first reply of the client.
+    fn serve_with_stray_ack() -> (SocketAddr, thread::JoinHandle<Vec<u8>>) {
+        fake_server(move |transfer, _| {
+            let mut buf = vec![0; 1024];
+            transfer.send(AckPacket::new(1).encode().packet_buf()).unwrap();
+            transfer.send(DataPacketOctet::from_slice(1, b"abc").encode().packet_buf()).unwrap();
+            let n = transfer.recv(&mut buf).unwrap();
+            buf[..n].to_vec()
+        })
+    }
+
+    #[test]
+    fn unexpected_packet_is_rejected() {
+        let (server_addr, server) = serve_with_stray_ack();
+        match Client::new(server_addr).get(Path::new("file"), Mode::Octet, &mut Vec::new()) {
+            Err(Error::Protocol(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let reply = server.join().unwrap();
+        assert_eq!(packet::Error::IllegalOperation, ErrorPacket::decode(&reply).unwrap().error());
+    }
+
+    #[test]
+    fn unexpected_packet_is_ignored_by_policy() {
+        let (server_addr, server) = serve_with_stray_ack();
+        let client = ClientBuilder::new(server_addr)
+            .unexpected_packets(UnexpectedPacketPolicy::Ignore)
+            .build()
+            .unwrap();
+        let mut received = Vec::new();
+        client.get(Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(b"abc".to_vec(), received);
+        assert_eq!(Some(1), AckPacket::decode(&server.join().unwrap()).map(|ack| ack.block_id()));
+    }
 }
@@ -0,0 +1,495 @@
+//! Strongly typed configuration values.
+//!
+//! Values are validated when they are created, so a configuration built from them
+//! can't contain zero retries or a block size the protocol doesn't allow.
+//!
+//! Settings that are only invalid together, or because of the host, like a
+//! root directory that isn't writable, are checked when the client or server
+//! is built. Building reports all of them at once in `ConfigError::Invalid`,
+//! so a configuration is fixed in one go.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Default time to wait for a packet before retransmitting.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+quick_error! {
+    #[derive(Debug, Eq, PartialEq, Clone)]
+    pub enum ConfigError {
+        ZeroRetries {
+            description("zero retries")
+            display("Number of retries must be at least 1")
+        }
+        InvalidBlockSize(size: usize) {
+            description("invalid block size")
+            display("Block size {} is not between {} and {}", size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+        }
+        ZeroWindowSize {
+            description("zero window size")
+            display("Window size must be at least 1")
+        }
+        ZeroTimeout {
+            description("zero timeout")
+            display("Timeout must be longer than zero")
+        }
+        InvalidSubnet(subnet: String) {
+            description("invalid subnet")
+            display("Subnet {} is not an address and a prefix length, like 10.0.0.0/8", subnet)
+        }
+        UnknownQuirk(quirk: String) {
+            description("unknown quirk")
+            display("Quirk {} is not known, expected oack-ack-one or repeated-oack", quirk)
+        }
+        UnknownConformance(conformance: String) {
+            description("unknown conformance")
+            display("Conformance {} is not known, expected strict or lenient", conformance)
+        }
+        ZeroDuration(setting: &'static str) {
+            description("zero duration")
+            display("The {} must be longer than zero", setting)
+        }
+        AddressFamily(local: SocketAddr, server: SocketAddr) {
+            description("mismatched address families")
+            display("Local address {} can't reach server {} of the other address family, pick a local address of \
+                     the server's family", local, server)
+        }
+        SpoolTooSmall(max_bytes: u64, block_size: usize) {
+            description("spool too small")
+            display("Spool of {} bytes can't hold a block of {} bytes, make it at least as large as the block size",
+                    max_bytes, block_size)
+        }
+        InvalidRoot(root: PathBuf, problem: String) {
+            description("invalid root directory")
+            display("Root directory {} {}", root.display(), problem)
+        }
+        DuplicateSubnet(subnet: Subnet) {
+            description("duplicate subnet")
+            display("Subnet {} is configured more than once, only the first configuration is used", subnet)
+        }
+        ShadowedRoute(prefix: String, by: String) {
+            description("shadowed route")
+            display("Route {:?} is never used, the earlier route {:?} matches all of its file names", prefix, by)
+        }
+        In(context: String, err: Box<ConfigError>) {
+            description("invalid setting")
+            display("{}: {}", context, err)
+        }
+        Invalid(errors: Vec<ConfigError>) {
+            description("invalid configuration")
+            display("{} invalid settings:{}", errors.len(),
+                    errors.iter().map(|err| format!("\n  - {}", err)).collect::<String>())
+        }
+    }
+}
+
+impl ConfigError {
+    /// Returns the problems of the configuration, one for most errors and
+    /// all of them for `Invalid`.
+    pub fn errors(&self) -> &[ConfigError] {
+        match *self {
+            ConfigError::Invalid(ref errors) => errors,
+            ref err => ::std::slice::from_ref(err),
+        }
+    }
+
+    /// Places the error in `context`, e.g. the subnet whose setting it is.
+    pub fn within<C: fmt::Display>(self, context: C) -> ConfigError {
+        match self {
+            ConfigError::Invalid(errors) => {
+                let context = context.to_string();
+                ConfigError::Invalid(errors.into_iter().map(|err| err.within(&context)).collect())
+            }
+            err => ConfigError::In(context.to_string(), Box::new(err)),
+        }
+    }
+}
+
+/// Returns the problems found in a configuration as one error, `Invalid` if
+/// there are several.
+pub fn all_valid(errors: Vec<ConfigError>) -> Result<(), ConfigError> {
+    let mut errors: Vec<ConfigError> = errors.into_iter().flat_map(|err| match err {
+        ConfigError::Invalid(errors) => errors,
+        err => vec![err],
+    }).collect();
+    match errors.len() {
+        0 => Ok(()),
+        1 => Err(errors.remove(0)),
+        _ => Err(ConfigError::Invalid(errors)),
+    }
+}
+
+/// Number of times a packet is retransmitted before a transfer fails.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct Retries(u32);
+
+impl Retries {
+    /// Creates a retry count, zero retries are not allowed.
+    pub fn new(retries: u32) -> Result<Retries, ConfigError> {
+        if retries == 0 {
+            Err(ConfigError::ZeroRetries)
+        } else {
+            Ok(Retries(retries))
+        }
+    }
+
+    /// Returns the number of retries.
+    pub fn get(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for Retries {
+    fn default() -> Retries {
+        Retries(5)
+    }
+}
+
+/// Addresses a client accepts the first reply of the server from.
+///
+/// Servers answer a request from a new port, their transfer identifier, and the
+/// transfer is locked to the address of the first reply.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ReplyPolicy {
+    /// The first reply must come from the host the request was sent to.
+    SameAddress,
+
+    /// The first reply may come from any address. Some multi-homed servers
+    /// answer from a different address than the one the request was sent to.
+    AllowAddressChangeOnFirstReply,
+}
+
+impl Default for ReplyPolicy {
+    fn default() -> ReplyPolicy {
+        ReplyPolicy::SameAddress
+    }
+}
+
+/// What a transfer does with a packet the peer must not send in its state,
+/// e.g. a data packet sent to a client that writes a file.
+///
+/// Packets that can't be decoded at all are always dropped.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum UnexpectedPacketPolicy {
+    /// The packet is dropped and the transfer continues. Tolerates peers that
+    /// send stray packets, e.g. a late request retransmission.
+    Ignore,
+
+    /// The transfer fails without telling the peer.
+    Abort,
+
+    /// The peer is sent an illegal operation error (code 4) and the transfer
+    /// fails, as RFC 1350 describes.
+    Reject,
+}
+
+impl Default for UnexpectedPacketPolicy {
+    fn default() -> UnexpectedPacketPolicy {
+        UnexpectedPacketPolicy::Reject
+    }
+}
+
+/// Known bugs of servers in the option negotiation the client can work around.
+///
+/// Quirks are off by default, they break the handshake with servers that don't
+/// have the bug.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Quirk {
+    /// The server of a read expects its option acknowledgment to be
+    /// acknowledged with block number 1 instead of 0.
+    OptionAckBlockOne,
+
+    /// The server sends its option acknowledgment again instead of the next
+    /// packet of the handshake. In a read the client acknowledges the options
+    /// again, in a write the repeated option acknowledgment is taken as the
+    /// acknowledgment of the first data block.
+    RepeatedOptionAck,
+}
+
+impl FromStr for Quirk {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Quirk, ConfigError> {
+        match s {
+            "oack-ack-one" => Ok(Quirk::OptionAckBlockOne),
+            "repeated-oack" => Ok(Quirk::RepeatedOptionAck),
+            _ => Err(ConfigError::UnknownQuirk(s.to_owned())),
+        }
+    }
+}
+
+/// How closely the client and the server hold peers to the RFCs.
+///
+/// Lenient transfers tolerate the deviations of common implementations the
+/// configuration allows: a first reply from another address (`ReplyPolicy`),
+/// read transfers starting at block 0 (`accept_block_zero`), option
+/// acknowledgments repeated after the transfer started and the `Quirk`s of
+/// the client, and requests, errors and option acknowledgments whose last
+/// string lacks its terminating zero byte. Strict transfers refuse all of
+/// them whatever the configuration, e.g. to test implementations in a lab.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Conformance {
+    /// Deviations from the RFCs are refused.
+    Strict,
+
+    /// Deviations of common implementations are tolerated.
+    Lenient,
+}
+
+impl Conformance {
+    /// Returns `true` for strict conformance.
+    pub fn is_strict(&self) -> bool {
+        *self == Conformance::Strict
+    }
+}
+
+impl Default for Conformance {
+    fn default() -> Conformance {
+        Conformance::Lenient
+    }
+}
+
+impl FromStr for Conformance {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Conformance, ConfigError> {
+        match s {
+            "strict" => Ok(Conformance::Strict),
+            "lenient" => Ok(Conformance::Lenient),
+            _ => Err(ConfigError::UnknownConformance(s.to_owned())),
+        }
+    }
+}
+
+/// Smallest block size allowed by RFC 2348.
+pub const MIN_BLOCK_SIZE: usize = 8;
+
+/// Largest block size supported by this build.
+///
+/// RFC 2348 allows up to 65464 bytes. The `max-blksize-*` features select the
+/// limit, the largest enabled one is used and without any of them blocks are
+/// limited to the 512 bytes of RFC 1350. Larger block sizes can't be created
+/// and negotiations of them are lowered or rejected.
+#[cfg(feature = "max-blksize-65464")]
+pub const MAX_BLOCK_SIZE: usize = 65464;
+#[cfg(all(feature = "max-blksize-8192", not(feature = "max-blksize-65464")))]
+pub const MAX_BLOCK_SIZE: usize = 8192;
+#[cfg(all(feature = "max-blksize-1468", not(any(feature = "max-blksize-8192", feature = "max-blksize-65464"))))]
+pub const MAX_BLOCK_SIZE: usize = 1468;
+#[cfg(not(any(feature = "max-blksize-1468", feature = "max-blksize-8192", feature = "max-blksize-65464")))]
+pub const MAX_BLOCK_SIZE: usize = 512;
+
+/// Size of the data block of a transfer.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct BlockSize(usize);
+
+impl BlockSize {
+    /// Creates a block size in range allowed by RFC 2348.
+    pub fn new(size: usize) -> Result<BlockSize, ConfigError> {
+        if size < MIN_BLOCK_SIZE || size > MAX_BLOCK_SIZE {
+            Err(ConfigError::InvalidBlockSize(size))
+        } else {
+            Ok(BlockSize(size))
+        }
+    }
+
+    /// Returns the block size in bytes.
+    pub fn get(&self) -> usize {
+        self.0
+    }
+}
+
+impl Default for BlockSize {
+    fn default() -> BlockSize {
+        BlockSize(512)
+    }
+}
+
+/// Number of blocks sent before waiting for an acknowledgment (RFC 7440).
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
+pub struct WindowSize(u16);
+
+impl WindowSize {
+    /// Creates a window size, zero is not allowed.
+    pub fn new(size: u16) -> Result<WindowSize, ConfigError> {
+        if size == 0 {
+            Err(ConfigError::ZeroWindowSize)
+        } else {
+            Ok(WindowSize(size))
+        }
+    }
+
+    /// Returns the number of blocks in a window.
+    pub fn get(&self) -> u16 {
+        self.0
+    }
+}
+
+impl Default for WindowSize {
+    fn default() -> WindowSize {
+        WindowSize(1)
+    }
+}
+
+/// Network of addresses in CIDR notation, like `10.1.0.0/16` or `fd00::/8`.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+pub struct Subnet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    /// Creates the subnet of the first `prefix_len` bits of `addr`, the other
+    /// bits are cleared.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Subnet, ConfigError> {
+        let bits = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > bits {
+            return Err(ConfigError::InvalidSubnet(format!("{}/{}", addr, prefix_len)))
+        }
+        Ok(Subnet {
+            addr: mask(addr, prefix_len),
+            prefix_len: prefix_len,
+        })
+    }
+
+    /// Returns the network address.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Returns the number of leading bits addresses of the subnet share.
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// Returns `true` if `addr` is in the subnet. IPv4 addresses mapped to
+    /// IPv6 (`::ffff:10.0.0.1`) are in the IPv4 subnets.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => match v6.octets() {
+                [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => IpAddr::V4(Ipv4Addr::new(a, b, c, d)),
+                _ => addr,
+            },
+            _ => addr,
+        };
+        addr.is_ipv4() == self.addr.is_ipv4() && mask(addr, self.prefix_len) == self.addr
+    }
+}
+
+fn mask(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v4) => {
+            let bits = u32::from(v4) & !(u32::max_value().checked_shr(prefix_len as u32).unwrap_or(0));
+            IpAddr::V4(Ipv4Addr::from(bits))
+        }
+        IpAddr::V6(v6) => {
+            let bits = u128::from(v6) & !(u128::max_value().checked_shr(prefix_len as u32).unwrap_or(0));
+            IpAddr::V6(Ipv6Addr::from(bits))
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Subnet, ConfigError> {
+        let invalid = || ConfigError::InvalidSubnet(s.to_owned());
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = try!(parts.next().unwrap_or("").parse().map_err(|_| invalid()));
+        let prefix_len = match parts.next() {
+            Some(prefix_len) => try!(prefix_len.parse().map_err(|_| invalid())),
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Subnet::new(addr, prefix_len).map_err(|_| invalid())
+    }
+}
+
+/// Validates a timeout duration, zero timeouts are not allowed.
+pub fn validate_timeout(timeout: Duration) -> Result<Duration, ConfigError> {
+    if timeout == Duration::new(0, 0) {
+        Err(ConfigError::ZeroTimeout)
+    } else {
+        Ok(timeout)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Retries, BlockSize, WindowSize, Subnet, ConfigError, all_valid, validate_timeout, MAX_BLOCK_SIZE};
+
+    #[test]
+    fn zero_retries_are_rejected() {
+        assert_eq!(Err(ConfigError::ZeroRetries), Retries::new(0));
+        assert_eq!(3, Retries::new(3).unwrap().get());
+    }
+
+    #[test]
+    fn block_size_out_of_range_is_rejected() {
+        assert_eq!(Err(ConfigError::InvalidBlockSize(7)), BlockSize::new(7));
+        assert_eq!(Err(ConfigError::InvalidBlockSize(65465)), BlockSize::new(65465));
+        assert_eq!(Err(ConfigError::InvalidBlockSize(MAX_BLOCK_SIZE + 1)), BlockSize::new(MAX_BLOCK_SIZE + 1));
+        assert_eq!(512, BlockSize::new(512).unwrap().get());
+        assert_eq!(MAX_BLOCK_SIZE, BlockSize::new(MAX_BLOCK_SIZE).unwrap().get());
+        if MAX_BLOCK_SIZE >= 1468 {
+            assert_eq!(1468, BlockSize::new(1468).unwrap().get());
+        }
+    }
+
+    #[test]
+    fn zero_window_size_is_rejected() {
+        assert_eq!(Err(ConfigError::ZeroWindowSize), WindowSize::new(0));
+    }
+
+    #[test]
+    fn zero_timeout_is_rejected() {
+        assert_eq!(Err(ConfigError::ZeroTimeout), validate_timeout(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn problems_are_reported_together() {
+        assert_eq!(Ok(()), all_valid(Vec::new()));
+        assert_eq!(Err(ConfigError::ZeroTimeout), all_valid(vec![ConfigError::ZeroTimeout]));
+
+        let lab: Subnet = "10.1.0.0/16".parse().unwrap();
+        let nested = ConfigError::Invalid(vec![ConfigError::ZeroTimeout, ConfigError::ZeroDuration("deadline")]);
+        let err = all_valid(vec![ConfigError::ZeroRetries, nested.within(lab)]).unwrap_err();
+        assert_eq!(3, err.errors().len());
+        assert_eq!("3 invalid settings:\n  \
+                    - Number of retries must be at least 1\n  \
+                    - 10.1.0.0/16: Timeout must be longer than zero\n  \
+                    - 10.1.0.0/16: The deadline must be longer than zero", err.to_string());
+    }
+
+    #[test]
+    fn subnets_contain_addresses_with_their_prefix() {
+        let lab: Subnet = "10.1.2.3/16".parse().unwrap();
+        assert_eq!("10.1.0.0/16", lab.to_string());
+        assert!(lab.contains("10.1.200.7".parse().unwrap()));
+        assert!(lab.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!lab.contains("10.2.0.1".parse().unwrap()));
+        assert!(!lab.contains("::a01:1".parse().unwrap()));
+
+        let host: Subnet = "fd00::1".parse().unwrap();
+        assert_eq!(128, host.prefix_len());
+        assert!(host.contains("fd00::1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Subnet>().unwrap().contains("192.0.2.1".parse().unwrap()));
+
+        assert_eq!(Err(ConfigError::InvalidSubnet("10.0.0.0/33".to_owned())), "10.0.0.0/33".parse::<Subnet>());
+        assert!("lab/8".parse::<Subnet>().is_err());
+    }
+}
@@ -10,18 +10,18 @@ pub struct DecodedPacket<P: Sized> {
 
 impl<P: DecodePacket<'static>> DecodedPacket<P> {
     pub fn decode(raw: RawPacket) -> Option<DecodedPacket<P>> {
-        let mut p = DecodedPacket {
-            raw: raw,
-            packet: unsafe { mem::uninitialized() },
-        };
-        P::decode(unsafe { extend_buf_lifetime(&p.raw.packet_buf()) }).map(|packet| {
-            p.packet = packet;
-            p
+        // The packet borrows the heap buffer of `raw`, which stays in place when `raw` is moved.
+        let packet = P::decode(unsafe { extend_buf_lifetime(raw.packet_buf()) });
+        packet.map(|packet| {
+            DecodedPacket {
+                raw: raw,
+                packet: packet,
+            }
         })
     }
 
     pub fn into_inner(self) -> Vec<u8> {
-        self.raw.get_buffer()
+        self.raw.into_buffer()
     }
 }
 
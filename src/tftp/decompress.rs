@@ -0,0 +1,603 @@
+//! Streaming decompression of gzip and zstd files.
+//!
+//! A `Decoder` decompresses the data of a reader as it is read. The compressed
+//! data is buffered by the decoder itself, a read of the wrapped reader that
+//! would block returns the `WouldBlock` error without losing data, so the
+//! decoder also wraps the non-blocking readers of handlers. Concatenated gzip
+//! members and zstd frames are decompressed one after the other, like the
+//! `gzip` and `zstd` tools do.
+//!
+//! A `ContentDecoder` decompresses the data written to it instead, if it
+//! starts with the magic number of a compressed format, other data is passed
+//! through. `ClientBuilder::decompress` wraps the writer of downloads in one.
+
+use std::fmt;
+use std::mem;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use flate2::{Crc, Decompress, FlushDecompress, Status};
+use ruzstd::decoding::FrameDecoder;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Largest compressed size of a gzip file whose size field is its exact
+/// decompressed size, deflate expands data at most 1032 times and the field
+/// holds the size modulo 4 GiB.
+const MAX_EXACT_GZIP_LEN: u64 = (1 << 32) / 1032;
+
+/// Bytes of compressed data read from the wrapped reader at once.
+const READ_LEN: usize = 16 * 1024;
+
+/// Compression format of a file.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the format of the data starting with `data`, by its magic
+    /// number.
+    pub fn detect(data: &[u8]) -> Option<Compression> {
+        if data.starts_with(&GZIP_MAGIC) {
+            Some(Compression::Gzip)
+        } else if data.starts_with(&ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the extension of the names of files in the format.
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    /// Returns the decompressed size of `file` if it can be read without
+    /// decompressing it.
+    ///
+    /// The size is recorded in the header of zstd frames and in the trailer
+    /// of gzip files, though only modulo 4 GiB and for gzip files of a few
+    /// megabytes it's known to be exact. Files of several gzip members or zstd
+    /// frames, which the tools don't create, aren't recognized and get a
+    /// wrong size.
+    pub fn decompressed_size<F: Read + Seek>(&self, file: &mut F) -> io::Result<Option<u64>> {
+        match *self {
+            Compression::Gzip => {
+                let len = try!(file.seek(SeekFrom::End(0)));
+                if len < 18 || len > MAX_EXACT_GZIP_LEN {
+                    return Ok(None)
+                }
+                let mut size = [0; 4];
+                try!(file.seek(SeekFrom::End(-4)));
+                try!(file.read_exact(&mut size));
+                Ok(Some(le(&size)))
+            }
+            Compression::Zstd => {
+                let mut header = Vec::with_capacity(MAX_ZSTD_HEADER_LEN);
+                try!(file.seek(SeekFrom::Start(0)));
+                try!(file.take(MAX_ZSTD_HEADER_LEN as u64).read_to_end(&mut header));
+                Ok(zstd_header(&header).and_then(|header| header.content_size))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// Returns the little-endian number in `bytes`.
+fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |n, &byte| n << 8 | byte as u64)
+}
+
+fn invalid_data<E: fmt::Display>(compression: Compression, error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {} data: {}", compression, error))
+}
+
+/// Returns the length of the gzip member header at the start of `data`,
+/// `None` if `data` ends before it.
+fn gzip_header_len(data: &[u8]) -> io::Result<Option<usize>> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    if data.len() < 10 {
+        return Ok(None)
+    }
+    if !data.starts_with(&GZIP_MAGIC) || data[2] != 8 {
+        return Err(invalid_data(Compression::Gzip, "not a deflate member"))
+    }
+    let flags = data[3];
+    let mut len = 10;
+    if flags & FEXTRA != 0 {
+        match data.get(len..len + 2) {
+            Some(extra) => len += 2 + le(extra) as usize,
+            None => return Ok(None),
+        }
+    }
+    for &field in &[FNAME, FCOMMENT] {
+        if flags & field != 0 {
+            match data.get(len..).and_then(|rest| rest.iter().position(|&byte| byte == 0)) {
+                Some(end) => len += end + 1,
+                None => return Ok(None),
+            }
+        }
+    }
+    if flags & FHCRC != 0 {
+        len += 2;
+    }
+    Ok(if data.len() >= len { Some(len) } else { None })
+}
+
+/// Largest zstd frame header, including the magic number.
+const MAX_ZSTD_HEADER_LEN: usize = 18;
+
+/// Header of a zstd frame.
+struct ZstdHeader {
+    len: usize,
+    content_size: Option<u64>,
+}
+
+/// Parses the zstd frame header at the start of `data`, `None` if `data`
+/// ends before it.
+fn zstd_header(data: &[u8]) -> Option<ZstdHeader> {
+    let descriptor = match data.get(4) {
+        Some(&descriptor) if data.starts_with(&ZSTD_MAGIC) => descriptor,
+        _ => return None,
+    };
+    let single_segment = descriptor & 0x20 != 0;
+    let dict_id_len = [0, 1, 2, 4][(descriptor & 0x03) as usize];
+    let content_size_len = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => 0,
+        flag => 1 << flag,
+    };
+    let start = 5 + if single_segment { 0 } else { 1 } + dict_id_len;
+    let len = start + content_size_len;
+    let content_size = match data.get(start..len) {
+        Some(field) if field.len() == 2 => Some(le(field) + 256),
+        Some(field) if !field.is_empty() => Some(le(field)),
+        Some(_) => None,
+        None => return None,
+    };
+    Some(ZstdHeader {
+        len: len,
+        content_size: content_size,
+    })
+}
+
+/// Position of a decompressor in the compressed data.
+enum Stage {
+    /// Before a gzip member or a zstd frame.
+    Start,
+    Deflate,
+    GzipTrailer,
+    Zstd(Box<FrameDecoder>),
+}
+
+/// Decompressor of data passed to it in pieces.
+pub struct Inflater {
+    compression: Compression,
+    stage: Stage,
+    inflate: Decompress,
+    crc: Crc,
+}
+
+impl Inflater {
+    /// Creates a decompressor of data in the `compression` format.
+    pub fn new(compression: Compression) -> Inflater {
+        Inflater {
+            compression: compression,
+            stage: Stage::Start,
+            inflate: Decompress::new(false),
+            crc: Crc::new(),
+        }
+    }
+
+    /// Returns the format of the decompressed data.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Returns `true` if the data passed so far ended with a complete gzip
+    /// member or zstd frame.
+    pub fn is_complete(&self) -> bool {
+        match self.stage {
+            Stage::Start => true,
+            _ => false,
+        }
+    }
+
+    /// Decompresses the start of `input` into `output`, returns the bytes of
+    /// `input` consumed and of `output` produced.
+    ///
+    /// Consuming and producing nothing means the decompressor needs more
+    /// input than `input` to make progress, the unconsumed input has to be
+    /// passed again with the data following it.
+    pub fn decompress(&mut self, input: &[u8], output: &mut [u8]) -> io::Result<(usize, usize)> {
+        let compression = self.compression;
+        match self.stage {
+            Stage::Start if input.is_empty() => Ok((0, 0)),
+            Stage::Start if compression == Compression::Gzip => {
+                match try!(gzip_header_len(input)) {
+                    Some(len) => {
+                        self.stage = Stage::Deflate;
+                        Ok((len, 0))
+                    }
+                    None => Ok((0, 0)),
+                }
+            }
+            Stage::Start => {
+                if input.len() >= 4 && !input.starts_with(&ZSTD_MAGIC) {
+                    return Err(invalid_data(compression, "not a zstd frame"))
+                }
+                // The decoder can't be given a partial frame header.
+                if zstd_header(input).map_or(true, |header| input.len() < header.len) {
+                    return Ok((0, 0))
+                }
+                self.stage = Stage::Zstd(Box::new(FrameDecoder::new()));
+                self.decompress(input, output)
+            }
+            Stage::Deflate => {
+                let (total_in, total_out) = (self.inflate.total_in(), self.inflate.total_out());
+                let status = try!(self.inflate.decompress(input, output, FlushDecompress::None)
+                    .map_err(|e| invalid_data(compression, e)));
+                let consumed = (self.inflate.total_in() - total_in) as usize;
+                let produced = (self.inflate.total_out() - total_out) as usize;
+                self.crc.update(&output[..produced]);
+                if status == Status::StreamEnd {
+                    self.stage = Stage::GzipTrailer;
+                }
+                Ok((consumed, produced))
+            }
+            Stage::GzipTrailer => {
+                if input.len() < 8 {
+                    return Ok((0, 0))
+                }
+                if le(&input[..4]) != self.crc.sum() as u64 || le(&input[4..8]) != self.crc.amount() as u64 {
+                    return Err(invalid_data(compression, "checksum mismatch"))
+                }
+                self.inflate.reset(false);
+                self.crc.reset();
+                self.stage = Stage::Start;
+                Ok((8, 0))
+            }
+            Stage::Zstd(ref mut decoder) => {
+                let (mut consumed, produced) = try!(decoder.decode_from_to(input, output)
+                    .map_err(|e| invalid_data(compression, e)));
+                // The decoder claims the checksum at the end of a frame as
+                // consumed even if it's not in the input yet.
+                if consumed > input.len() {
+                    consumed = 0;
+                }
+                if decoder.is_finished() && decoder.can_collect() == 0 {
+                    self.stage = Stage::Start;
+                }
+                Ok((consumed, produced))
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Inflater {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Inflater").field("compression", &self.compression).finish()
+    }
+}
+
+/// Reader decompressing the data of a wrapped reader.
+#[derive(Debug)]
+pub struct Decoder<R> {
+    reader: R,
+    inflater: Inflater,
+    /// Compressed data read but not decompressed yet, from `start`.
+    input: Vec<u8>,
+    start: usize,
+    /// The wrapped reader returned the end of the data.
+    end: bool,
+}
+
+impl<R: Read> Decoder<R> {
+    /// Creates a reader of the decompressed data of `reader`, which is in
+    /// the `compression` format.
+    pub fn new(reader: R, compression: Compression) -> Decoder<R> {
+        Decoder {
+            reader: reader,
+            inflater: Inflater::new(compression),
+            input: Vec::new(),
+            start: 0,
+            end: false,
+        }
+    }
+
+    /// Returns the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads more compressed data into the input buffer.
+    fn fill(&mut self) -> io::Result<()> {
+        self.input.drain(..self.start);
+        self.start = 0;
+        let filled = self.input.len();
+        self.input.resize(filled + READ_LEN, 0);
+        loop {
+            match self.reader.read(&mut self.input[filled..]) {
+                Ok(n) => {
+                    self.input.truncate(filled + n);
+                    self.end = n == 0;
+                    return Ok(())
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.input.truncate(filled);
+                    return Err(e)
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            let (consumed, produced) = try!(self.inflater.decompress(&self.input[self.start..], buf));
+            self.start += consumed;
+            if produced > 0 {
+                return Ok(produced)
+            }
+            if consumed > 0 {
+                continue
+            }
+            if self.end {
+                if self.start == self.input.len() && self.inflater.is_complete() {
+                    return Ok(0)
+                }
+                let message = format!("{} data is truncated", self.inflater.compression());
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message))
+            }
+            try!(self.fill());
+        }
+    }
+}
+
+/// Length of the longest magic number of the detected formats.
+const MAGIC_LEN: usize = 4;
+
+/// Writer decompressing the data written to it if it's compressed.
+///
+/// The format is detected from the first bytes of the data, data of no
+/// known format is written to the wrapped writer as it is. `finish` has to be
+/// called after the last write, it fails if the compressed data is truncated.
+#[derive(Debug)]
+pub struct ContentDecoder<W> {
+    writer: W,
+    detect: bool,
+    /// The format was detected, or not, from the start of the data.
+    detected: bool,
+    inflater: Option<Inflater>,
+    /// Start of the data while the format isn't detected yet, then the
+    /// compressed data not decompressed yet.
+    input: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl<W: Write> ContentDecoder<W> {
+    /// Creates a writer decompressing compressed data into `writer`, with
+    /// `detect` false all data is passed through.
+    pub fn new(writer: W, detect: bool) -> ContentDecoder<W> {
+        ContentDecoder {
+            writer: writer,
+            detect: detect,
+            detected: false,
+            inflater: None,
+            input: Vec::new(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Returns the format of the data written so far, `None` if it isn't
+    /// compressed or not known yet.
+    pub fn compression(&self) -> Option<Compression> {
+        self.inflater.as_ref().map(Inflater::compression)
+    }
+
+    /// Ends the data, writes out the rest and flushes the wrapped writer.
+    ///
+    /// Fails with `UnexpectedEof` if the compressed data is truncated.
+    pub fn finish(&mut self) -> io::Result<()> {
+        try!(self.detect_format());
+        if let Some(ref inflater) = self.inflater {
+            if !self.input.is_empty() || !inflater.is_complete() {
+                let message = format!("{} data is truncated", inflater.compression());
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, message))
+            }
+        }
+        self.writer.flush()
+    }
+
+    /// Returns the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Detects the format from the data written so far and writes it out.
+    fn detect_format(&mut self) -> io::Result<()> {
+        if self.detected {
+            return Ok(())
+        }
+        self.detected = true;
+        self.inflater = Compression::detect(&self.input).map(Inflater::new);
+        if self.inflater.is_some() {
+            self.output.resize(READ_LEN, 0);
+            self.decompress()
+        } else {
+            let start = mem::replace(&mut self.input, Vec::new());
+            self.writer.write_all(&start)
+        }
+    }
+
+    /// Decompresses the buffered compressed data into the wrapped writer.
+    fn decompress(&mut self) -> io::Result<()> {
+        let inflater = match self.inflater {
+            Some(ref mut inflater) => inflater,
+            None => return Ok(()),
+        };
+        let mut start = 0;
+        loop {
+            let (consumed, produced) = try!(inflater.decompress(&self.input[start..], &mut self.output));
+            try!(self.writer.write_all(&self.output[..produced]));
+            start += consumed;
+            if consumed == 0 && produced == 0 {
+                break
+            }
+        }
+        self.input.drain(..start);
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for ContentDecoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.detect || (self.detected && self.inflater.is_none()) {
+            return self.writer.write(buf)
+        }
+        self.input.extend_from_slice(buf);
+        if !self.detected {
+            if self.input.len() >= MAGIC_LEN {
+                try!(self.detect_format());
+            }
+        } else {
+            try!(self.decompress());
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Read, Write};
+
+    use flate2::Compression as Level;
+    use flate2::write::GzEncoder;
+    use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+    use super::{Compression, ContentDecoder, Decoder};
+
+    /// Reader returning at most 7 bytes per read and blocking every other read.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        block: bool,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.block = !self.block;
+            if self.block {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            let len = buf.len().min(7).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn read_until_end<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut buf = [0; 100];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(data),
+                Ok(n) => data.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn compressed_data_is_decompressed_across_blocked_reads() {
+        let data: Vec<u8> = (0..20000u32).map(|i| (i * i % 251) as u8).collect();
+        let mut gzipped = gzip(&data[..10000]);
+        gzipped.extend(gzip(&data[10000..]));
+        let zstd = compress_to_vec(&data[..], CompressionLevel::Fastest);
+        for &(compression, ref compressed) in &[(Compression::Gzip, &gzipped), (Compression::Zstd, &zstd)] {
+            assert_eq!(Some(compression), Compression::detect(compressed));
+            let mut decoder = Decoder::new(Trickle { data: compressed, block: false }, compression);
+            assert_eq!(data, read_until_end(&mut decoder).unwrap());
+
+            let truncated = &compressed[..compressed.len() - 3];
+            let error = read_until_end(&mut Decoder::new(truncated, compression)).unwrap_err();
+            assert_eq!(io::ErrorKind::UnexpectedEof, error.kind());
+        }
+        assert_eq!(None, Compression::detect(b"plain"));
+        assert_eq!(io::ErrorKind::InvalidData, read_until_end(&mut Decoder::new(&b"plain text"[..], Compression::Gzip))
+            .unwrap_err().kind());
+
+        // The encoder doesn't record the size, the zstd tool does.
+        assert_eq!(None, Compression::Zstd.decompressed_size(&mut Cursor::new(&zstd)).unwrap());
+        // Frame of one raw block with the size recorded.
+        let mut sized = vec![0x28, 0xb5, 0x2f, 0xfd, 0x60, 0x20, 0x4d, 0x01, 0x71, 0x02];
+        sized.extend_from_slice(&data[..20000]);
+        assert_eq!(Some(20000), Compression::Zstd.decompressed_size(&mut Cursor::new(&sized)).unwrap());
+        assert_eq!(data, read_until_end(&mut Decoder::new(&sized[..], Compression::Zstd)).unwrap());
+        let single = gzip(&data);
+        assert_eq!(Some(20000), Compression::Gzip.decompressed_size(&mut Cursor::new(&single)).unwrap());
+    }
+
+    #[test]
+    fn written_data_is_decompressed_if_compressed() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i % 13) as u8).collect();
+        let zstd = compress_to_vec(&data[..], CompressionLevel::Fastest);
+        for compressed in &[gzip(&data), zstd] {
+            let mut decoder = ContentDecoder::new(Vec::new(), true);
+            for block in compressed.chunks(3) {
+                decoder.write_all(block).unwrap();
+            }
+            decoder.finish().unwrap();
+            assert_eq!(Compression::detect(compressed), decoder.compression());
+            assert_eq!(data, decoder.into_inner());
+
+            let mut truncated = ContentDecoder::new(Vec::new(), true);
+            truncated.write_all(&compressed[..compressed.len() / 2]).unwrap();
+            assert_eq!(io::ErrorKind::UnexpectedEof, truncated.finish().unwrap_err().kind());
+
+            let mut disabled = ContentDecoder::new(Vec::new(), false);
+            disabled.write_all(compressed).unwrap();
+            disabled.finish().unwrap();
+            assert_eq!(&compressed[..], &disabled.into_inner()[..]);
+        }
+        for plain in &[&b"plain text"[..], &b"ab"[..]] {
+            let mut decoder = ContentDecoder::new(Vec::new(), true);
+            decoder.write_all(plain).unwrap();
+            decoder.finish().unwrap();
+            assert_eq!(None, decoder.compression());
+            assert_eq!(plain, &&decoder.into_inner()[..]);
+        }
+    }
+}
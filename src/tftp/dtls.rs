@@ -0,0 +1,403 @@
+//! DTLS protected transfers (experimental).
+//!
+//! The request is sent in the clear, everything after it runs inside a DTLS
+//! association between the transfer identifiers of the client and the server.
+//! The server opens the association from its transfer socket, so the server is
+//! the DTLS client and the TFTP client the DTLS server. Both ends have to agree
+//! on using DTLS and on the credentials out of band, for example by configuring
+//! the same pre-shared key in their `SslContext`s.
+//!
+//! Error packets in the clear are accepted before the association exists, so a
+//! client learns why a server rejected its request. Once the association is
+//! established only protected packets from the peer are accepted and packets
+//! to other hosts are dropped.
+//!
+//! This isn't a standardized protocol, both ends have to use this crate.
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::IpAddr;
+
+use openssl::ssl::{self, ErrorCode, Ssl, SslContext, SslStream};
+
+use packet::Opcode;
+use transport::Transport;
+
+/// Largest block size of a protected transfer, a data packet has to fit into
+/// a single DTLS record.
+pub const MAX_BLOCK_SIZE: usize = 16380;
+
+/// Largest datagram sent, records are never split by the DTLS layer.
+const MAX_DATAGRAM_SIZE: u32 = 65507;
+
+/// Datagrams of a transport exchanged with a single peer.
+struct Channel<T: Transport> {
+    transport: T,
+    peer: T::Addr,
+    /// Datagram received before the association was started.
+    first: Option<Vec<u8>>,
+}
+
+impl<T: Transport> Read for Channel<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(datagram) = self.first.take() {
+            let n = datagram.len().min(buf.len());
+            buf[..n].copy_from_slice(&datagram[..n]);
+            return Ok(n)
+        }
+        loop {
+            let (n, from) = try!(self.transport.recv_from(buf));
+            if from == self.peer {
+                return Ok(n)
+            }
+        }
+    }
+}
+
+impl<T: Transport> Write for Channel<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transport.send_to(buf, &self.peer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Accept,
+    Connect,
+}
+
+enum State<T: Transport> {
+    Plain(T),
+    Handshake(SslStream<Channel<T>>),
+    Established(SslStream<Channel<T>>),
+    Failed,
+}
+
+/// Transport protecting the packets of a transfer with DTLS.
+pub struct DtlsTransport<T: Transport> {
+    context: SslContext,
+    role: Role,
+    state: State<T>,
+    /// Packet sent by the server before the association was established.
+    queued: Option<Vec<u8>>,
+}
+
+impl<T: Transport> DtlsTransport<T> {
+    /// Creates the transport of a client.
+    ///
+    /// The request is sent in the clear, the association is accepted when the
+    /// server opens it.
+    pub fn accept(transport: T, context: &SslContext) -> DtlsTransport<T> {
+        DtlsTransport::new(transport, context, Role::Accept)
+    }
+
+    /// Creates the transport of a server transfer.
+    ///
+    /// The association with the client is opened by the first packet sent,
+    /// which is delivered once the association is established.
+    pub fn connect(transport: T, context: &SslContext) -> DtlsTransport<T> {
+        DtlsTransport::new(transport, context, Role::Connect)
+    }
+
+    fn new(transport: T, context: &SslContext, role: Role) -> DtlsTransport<T> {
+        DtlsTransport {
+            context: context.clone(),
+            role: role,
+            state: State::Plain(transport),
+            queued: None,
+        }
+    }
+
+    /// Returns `true` once the association is established.
+    pub fn is_established(&self) -> bool {
+        match self.state {
+            State::Established(_) => true,
+            _ => false,
+        }
+    }
+
+    fn transport_mut(&mut self) -> io::Result<&mut T> {
+        match self.state {
+            State::Plain(ref mut transport) => Ok(transport),
+            State::Handshake(ref mut stream) | State::Established(ref mut stream) => Ok(&mut stream.get_mut().transport),
+            State::Failed => Err(failed()),
+        }
+    }
+
+    /// Starts the association with `peer`.
+    fn start(&mut self, peer: T::Addr, first: Option<Vec<u8>>) -> io::Result<()> {
+        let transport = match mem::replace(&mut self.state, State::Failed) {
+            State::Plain(transport) => transport,
+            _ => unreachable!(),
+        };
+        let mut ssl = try!(Ssl::new(&self.context).map_err(other));
+        try!(ssl.set_mtu(MAX_DATAGRAM_SIZE).map_err(other));
+        let channel = Channel {
+            transport: transport,
+            peer: peer,
+            first: first,
+        };
+        self.state = State::Handshake(try!(SslStream::new(ssl, channel).map_err(other)));
+        self.handshake()
+    }
+
+    /// Continues the handshake, fails with `WouldBlock` until it completes.
+    fn handshake(&mut self) -> io::Result<()> {
+        let result = match self.state {
+            State::Handshake(ref mut stream) => match self.role {
+                Role::Accept => stream.accept(),
+                Role::Connect => stream.connect(),
+            },
+            _ => return Ok(()),
+        };
+        if let Err(e) = result {
+            let e = io_error(e);
+            if e.kind() != io::ErrorKind::WouldBlock {
+                self.state = State::Failed;
+            }
+            return Err(e)
+        }
+        self.state = match mem::replace(&mut self.state, State::Failed) {
+            State::Handshake(stream) => State::Established(stream),
+            _ => unreachable!(),
+        };
+        if let Some(packet) = self.queued.take() {
+            if let State::Established(ref mut stream) = self.state {
+                try!(stream.ssl_write(&packet).map_err(io_error));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for DtlsTransport<T> {
+    type Addr = T::Addr;
+
+    fn send_to(&mut self, buf: &[u8], addr: &T::Addr) -> io::Result<usize> {
+        if let State::Plain(ref mut transport) = self.state {
+            if self.role == Role::Accept {
+                // The request.
+                return transport.send_to(buf, addr)
+            }
+        }
+        match self.state {
+            State::Plain(_) => {
+                self.queued = Some(buf.to_vec());
+                try!(ignore_would_block(self.start(addr.clone(), None)));
+            }
+            State::Handshake(_) => {
+                // A retransmitted request is dropped, the server already answered it.
+                if self.role == Role::Connect {
+                    self.queued = Some(buf.to_vec());
+                }
+                try!(ignore_would_block(self.handshake()));
+            }
+            State::Established(ref mut stream) => {
+                if *addr == stream.get_ref().peer {
+                    return stream.ssl_write(buf).map_err(io_error)
+                }
+            }
+            State::Failed => return Err(failed()),
+        }
+        Ok(buf.len())
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, T::Addr)> {
+        loop {
+            match self.state {
+                State::Plain(ref mut transport) => {
+                    let (n, from) = try!(transport.recv_from(buf));
+                    if is_record(&buf[..n]) {
+                        if self.role == Role::Accept {
+                            let first = buf[..n].to_vec();
+                            try!(ignore_would_block(self.start(from, Some(first))));
+                        }
+                        continue
+                    }
+                    if n >= 2 && buf[0] == 0 && buf[1] == Opcode::ERROR as u8 {
+                        return Ok((n, from))
+                    }
+                }
+                State::Handshake(_) => try!(self.handshake()),
+                State::Established(ref mut stream) => {
+                    let n = try!(stream.ssl_read(buf).map_err(io_error));
+                    return Ok((n, stream.get_ref().peer.clone()))
+                }
+                State::Failed => return Err(failed()),
+            }
+        }
+    }
+
+    fn connect(&mut self, addr: &T::Addr) -> io::Result<()> {
+        try!(self.transport_mut()).connect(addr)
+    }
+
+    fn same_host(a: &T::Addr, b: &T::Addr) -> bool {
+        T::same_host(a, b)
+    }
+
+    fn host_ip(&self, addr: &T::Addr) -> Option<IpAddr> {
+        match self.state {
+            State::Plain(ref transport) => transport.host_ip(addr),
+            State::Handshake(ref stream) | State::Established(ref stream) => stream.get_ref().transport.host_ip(addr),
+            State::Failed => None,
+        }
+    }
+}
+
+#[cfg(feature = "mio-client")]
+impl<T: Transport + ::mio::event::Source> ::mio::event::Source for DtlsTransport<T> {
+    fn register(&mut self, registry: &::mio::Registry, token: ::mio::Token,
+                interests: ::mio::Interest) -> io::Result<()> {
+        try!(self.transport_mut()).register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &::mio::Registry, token: ::mio::Token,
+                  interests: ::mio::Interest) -> io::Result<()> {
+        try!(self.transport_mut()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &::mio::Registry) -> io::Result<()> {
+        try!(self.transport_mut()).deregister(registry)
+    }
+}
+
+/// Returns `true` if the datagram is a DTLS record, TFTP packets start with a zero byte.
+fn is_record(datagram: &[u8]) -> bool {
+    match datagram.first() {
+        Some(&content_type) => content_type >= 20 && content_type <= 25,
+        None => false,
+    }
+}
+
+fn io_error(e: ssl::Error) -> io::Error {
+    let code = e.code();
+    match e.into_io_error() {
+        Ok(e) => e,
+        Err(_) if code == ErrorCode::WANT_READ || code == ErrorCode::WANT_WRITE => io::ErrorKind::WouldBlock.into(),
+        Err(_) if code == ErrorCode::ZERO_RETURN => {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "DTLS association closed by the peer")
+        }
+        Err(e) => other(e),
+    }
+}
+
+fn other<E: Into<Box<::std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e)
+}
+
+fn failed() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "DTLS association failed")
+}
+
+fn ignore_would_block(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use openssl::ssl::{SslContext, SslMethod};
+
+    use packet::{self, AckPacket, ErrorPacket, EncodePacket, DecodePacket};
+    use transport::Transport;
+
+    use super::DtlsTransport;
+
+    const KEY: &'static [u8] = b"0123456789abcdef";
+
+    fn context(server_side: bool) -> SslContext {
+        let mut builder = SslContext::builder(SslMethod::dtls()).unwrap();
+        builder.set_cipher_list("PSK-AES128-GCM-SHA256").unwrap();
+        if server_side {
+            builder.set_psk_client_callback(|_, _, identity, key| {
+                identity[..5].copy_from_slice(b"tftp\0");
+                key[..KEY.len()].copy_from_slice(KEY);
+                Ok(KEY.len())
+            });
+        } else {
+            builder.set_psk_server_callback(|_, _, key| {
+                key[..KEY.len()].copy_from_slice(KEY);
+                Ok(KEY.len())
+            });
+        }
+        builder.build()
+    }
+
+    fn socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket
+    }
+
+    /// Receives a datagram, driving the transport until one arrives.
+    fn receive<T: Transport>(transport: &mut T, buf: &mut [u8]) -> (usize, T::Addr) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match transport.recv_from(buf) {
+                Ok(received) => return received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(e) => panic!("receive failed: {}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn packets_are_exchanged_after_the_request() {
+        let client_socket = socket();
+        let client_addr = client_socket.local_addr().unwrap();
+        let mut listener = socket();
+        let listener_addr = listener.local_addr().unwrap();
+        let mut client = DtlsTransport::accept(client_socket, &context(false));
+
+        client.send_to(b"\0\x01request", &listener_addr).unwrap();
+        let mut buf = [0; 64];
+        let (n, _) = receive(&mut listener, &mut buf);
+        assert_eq!(b"\0\x01request", &buf[..n]);
+
+        let server = thread::spawn(move || {
+            let mut server = DtlsTransport::connect(socket(), &context(true));
+            server.send_to(&AckPacket::new(0).encode().packet_buf(), &client_addr).unwrap();
+            let mut buf = [0; 64];
+            let (n, from) = receive(&mut server, &mut buf);
+            assert_eq!(client_addr, from);
+            assert!(server.is_established());
+            AckPacket::decode(&buf[..n]).unwrap().block_id()
+        });
+
+        let (n, server_addr) = receive(&mut client, &mut buf);
+        assert!(client.is_established());
+        assert_eq!(Some(0), AckPacket::decode(&buf[..n]).map(|ack| ack.block_id()));
+        client.send_to(&AckPacket::new(7).encode().packet_buf(), &server_addr).unwrap();
+        assert_eq!(7, server.join().unwrap());
+    }
+
+    #[test]
+    fn plain_errors_are_accepted_before_the_association() {
+        let client_socket = socket();
+        let client_addr = client_socket.local_addr().unwrap();
+        let mut client = DtlsTransport::accept(client_socket, &context(false));
+        let server = socket();
+        let error = ErrorPacket::new(packet::Error::FileNotFound, "not found").encode();
+        server.send_to(b"\0\x03\0\x01data", &client_addr).unwrap();
+        server.send_to(error.packet_buf(), &client_addr).unwrap();
+
+        let mut buf = [0; 64];
+        let (n, _) = receive(&mut client, &mut buf);
+        assert_eq!(error.packet_buf(), &buf[..n]);
+        assert!(!client.is_established());
+    }
+}
@@ -0,0 +1,232 @@
+//! Crate-level error type.
+//!
+//! Modules report failures using their own error types, all of which can be
+//! converted into `Error` so applications can handle failures uniformly by
+//! inspecting `Error::kind`.
+
+use std::error;
+use std::fmt;
+use std::io;
+
+#[cfg(feature = "mio-client")]
+use client;
+use config::ConfigError;
+use packet;
+use replay::ReplayError;
+use session;
+#[cfg(feature = "tokio-server")]
+use server_config::ConfigFileError;
+
+/// Category of an error.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ErrorKind {
+    /// I/O error on the socket or the local data source or sink.
+    Io,
+
+    /// Remote side violated the protocol.
+    Protocol,
+
+    /// Remote side terminated the transfer with an error packet.
+    ServerError(packet::Error),
+
+    /// Remote side stopped responding.
+    TimedOut,
+
+    /// Nothing listens on the port of the remote side any more, reported by
+    /// an ICMP port unreachable message.
+    ///
+    /// Only clients see it, their sockets are connected to the server. The
+    /// transfer sockets of the server stay unconnected to answer datagrams of
+    /// unknown transfer IDs, the system doesn't pass them ICMP errors.
+    PortUnreachable,
+
+    /// Transfer was cancelled locally.
+    Cancelled,
+
+    /// Configuration is invalid.
+    InvalidConfig,
+}
+
+/// Error type used by the whole crate.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    inner: Box<error::Error + Send + Sync>,
+}
+
+impl Error {
+    /// Creates an error of a given kind wrapping the underlying error.
+    pub fn new<E>(kind: ErrorKind, error: E) -> Error
+        where E: Into<Box<error::Error + Send + Sync>>
+    {
+        Error {
+            kind: kind,
+            inner: error.into(),
+        }
+    }
+
+    /// Returns the category of this error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns a reference to the underlying error.
+    pub fn get_ref(&self) -> &(error::Error + Send + Sync + 'static) {
+        &*self.inner
+    }
+
+    /// Consumes the error, returning the underlying error.
+    pub fn into_inner(self) -> Box<error::Error + Send + Sync> {
+        self.inner
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        #[allow(deprecated)]
+        self.inner.description()
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        Some(&*self.inner)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::new(io_error_kind(&err), err)
+    }
+}
+
+fn io_error_kind(err: &io::Error) -> ErrorKind {
+    match err.kind() {
+        io::ErrorKind::TimedOut => ErrorKind::TimedOut,
+        io::ErrorKind::ConnectionRefused => ErrorKind::PortUnreachable,
+        _ => ErrorKind::Io,
+    }
+}
+
+impl From<ConfigError> for Error {
+    fn from(err: ConfigError) -> Error {
+        Error::new(ErrorKind::InvalidConfig, err)
+    }
+}
+
+#[cfg(feature = "tokio-server")]
+impl From<ConfigFileError> for Error {
+    fn from(err: ConfigFileError) -> Error {
+        match err {
+            ConfigFileError::Io(err) => From::from(err),
+            err => Error::new(ErrorKind::InvalidConfig, err),
+        }
+    }
+}
+
+#[cfg(feature = "mio-client")]
+impl From<client::Error> for Error {
+    fn from(err: client::Error) -> Error {
+        let kind = client_error_kind(&err);
+        match err {
+            client::Error::Io(err) => From::from(err),
+            client::Error::Server(packet) => Error::new(kind, packet),
+            client::Error::Aborted(abort) => Error::new(kind, abort),
+            err => Error::new(kind, err),
+        }
+    }
+}
+
+/// Returns the category of a client error, an interrupted transfer has the
+/// category of the error that interrupted it.
+#[cfg(feature = "mio-client")]
+fn client_error_kind(err: &client::Error) -> ErrorKind {
+    match *err {
+        client::Error::Io(ref err) => io_error_kind(err),
+        client::Error::Server(ref packet) => ErrorKind::ServerError(packet.error()),
+        client::Error::Protocol(_) | client::Error::PacketTooLarge(_) => ErrorKind::Protocol,
+        client::Error::PortUnreachable(_) => ErrorKind::PortUnreachable,
+        client::Error::Aborted(_) | client::Error::TooLarge(_) | client::Error::SpoolOverflow(_) => {
+            ErrorKind::Cancelled
+        }
+        client::Error::Write(..) => ErrorKind::Io,
+        client::Error::Interrupted(_, ref err) | client::Error::Journaled(_, ref err) => client_error_kind(err),
+    }
+}
+
+impl From<session::Error> for Error {
+    fn from(err: session::Error) -> Error {
+        match err {
+            session::Error::Io(err, _) => From::from(err),
+            session::Error::Server(packet) => Error::new(ErrorKind::ServerError(packet.error()), packet),
+            err @ session::Error::Protocol(..) => Error::new(ErrorKind::Protocol, err),
+            err @ session::Error::TimedOut => Error::new(ErrorKind::TimedOut, err),
+        }
+    }
+}
+
+impl From<ReplayError> for Error {
+    fn from(err: ReplayError) -> Error {
+        match err {
+            ReplayError::Io(err) => From::from(err),
+            err @ ReplayError::TimedOut(..) => Error::new(ErrorKind::TimedOut, err),
+            err => Error::new(ErrorKind::Protocol, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use config::ConfigError;
+
+    use super::{Error, ErrorKind};
+
+    #[test]
+    fn io_timeout_is_timed_out_kind() {
+        let err: Error = io::Error::new(io::ErrorKind::TimedOut, "timeout").into();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+    }
+
+    #[test]
+    #[cfg(feature = "mio-client")]
+    fn server_error_keeps_error_code() {
+        use client;
+        use packet::{self, ErrorPacket};
+
+        let packet = ErrorPacket::new(packet::Error::FileNotFound, "missing");
+        let err: Error = client::Error::Server(packet).into();
+        assert_eq!(ErrorKind::ServerError(packet::Error::FileNotFound), err.kind());
+        assert_eq!("file not found: missing", err.to_string());
+    }
+
+    #[test]
+    #[cfg(feature = "mio-client")]
+    fn interrupted_transfer_has_kind_of_cause() {
+        use client::{self, Progress};
+        use packet::{self, ErrorPacket};
+
+        let progress = Progress { bytes: 1024, blocks: 2, flushed: true };
+        let cause = client::Error::Server(ErrorPacket::new(packet::Error::DiskFull, "full"));
+        let err: Error = client::Error::Interrupted(progress, Box::new(cause)).into();
+        assert_eq!(ErrorKind::ServerError(packet::Error::DiskFull), err.kind());
+        assert_eq!("Transfer interrupted after 1024 bytes (2 blocks): Server error: disk full: full", err.to_string());
+    }
+
+    #[test]
+    fn refused_connection_is_port_unreachable_kind() {
+        let err: Error = io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into();
+        assert_eq!(ErrorKind::PortUnreachable, err.kind());
+    }
+
+    #[test]
+    fn config_error_is_invalid_config_kind() {
+        let err: Error = ConfigError::ZeroRetries.into();
+        assert_eq!(ErrorKind::InvalidConfig, err.kind());
+    }
+}
@@ -0,0 +1,324 @@
+//! C interface to the client.
+//!
+//! The functions in this module are exported from the shared library built
+//! with the `ffi` feature, `cargo rustc --lib --release --features ffi
+//! --crate-type cdylib`, and declared in `include/tftp.h`. Every function returns
+//! `TFTP_OK` on success or one of the negative `TFTP_ERR_*` codes. An error
+//! packet sent by the server is reported as `TFTP_ERR_SERVER_BASE` minus the
+//! TFTP error code of the packet.
+
+#![allow(non_camel_case_types)]
+
+use std::ffi::CStr;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic;
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+use client::{Abort, Client, ClientBuilder};
+use config::{BlockSize, Retries};
+use error::{Error, ErrorKind};
+use packet::{self, Mode};
+
+/// Transfer completed successfully.
+pub const TFTP_OK: c_int = 0;
+/// A required argument is NULL or not valid UTF-8.
+pub const TFTP_ERR_INVALID_ARGUMENT: c_int = -1;
+/// Server address can't be resolved.
+pub const TFTP_ERR_RESOLVE: c_int = -2;
+/// Options contain an invalid value.
+pub const TFTP_ERR_INVALID_CONFIG: c_int = -3;
+/// I/O error on the socket or the local file.
+pub const TFTP_ERR_IO: c_int = -4;
+/// Server stopped responding.
+pub const TFTP_ERR_TIMED_OUT: c_int = -5;
+/// Server violated the protocol.
+pub const TFTP_ERR_PROTOCOL: c_int = -6;
+/// Progress callback requested cancellation.
+pub const TFTP_ERR_CANCELLED: c_int = -7;
+/// Unexpected internal failure.
+pub const TFTP_ERR_INTERNAL: c_int = -8;
+/// Nothing listens on the port of the server.
+pub const TFTP_ERR_PORT_UNREACHABLE: c_int = -9;
+/// Base of the codes of server errors, the TFTP error code is subtracted from it.
+pub const TFTP_ERR_SERVER_BASE: c_int = -100;
+
+/// Transfer mode `octet`.
+pub const TFTP_MODE_OCTET: c_int = 0;
+/// Transfer mode `netascii`.
+pub const TFTP_MODE_NETASCII: c_int = 1;
+
+/// Called with the total number of transferred bytes and the `user_data` pointer.
+///
+/// Returning a non-zero value cancels the transfer.
+pub type tftp_progress_cb = Option<extern "C" fn(bytes: u64, user_data: *mut c_void) -> c_int>;
+
+/// Transfer options, a zero field selects the default value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct tftp_options {
+    /// `TFTP_MODE_OCTET` or `TFTP_MODE_NETASCII`.
+    pub mode: c_int,
+    /// Block size requested from the server.
+    pub block_size: u32,
+    /// Time to wait for a response before retransmitting, in milliseconds.
+    pub timeout_ms: u32,
+    /// Retransmissions before the transfer fails.
+    pub retries: u32,
+}
+
+/// Reports progress to the C callback and remembers whether it cancelled the transfer.
+struct Progress {
+    callback: tftp_progress_cb,
+    user_data: *mut c_void,
+    bytes: u64,
+    cancelled: bool,
+}
+
+impl Progress {
+    fn add(&mut self, bytes: usize) -> io::Result<()> {
+        self.bytes += bytes as u64;
+        if let Some(callback) = self.callback {
+            if callback(self.bytes, self.user_data) != 0 {
+                self.cancelled = true;
+                return Err(Abort::new(packet::Error::Undefined, "transfer cancelled").into())
+            }
+        }
+        Ok(())
+    }
+}
+
+struct ProgressWriter<W> {
+    inner: W,
+    progress: Progress,
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        try!(self.progress.add(n));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct ProgressReader<R> {
+    inner: R,
+    progress: Progress,
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        try!(self.progress.add(n));
+        Ok(n)
+    }
+}
+
+/// Converts a crate error into a C error code.
+fn error_code(err: &Error) -> c_int {
+    match err.kind() {
+        ErrorKind::Io => TFTP_ERR_IO,
+        ErrorKind::Protocol => TFTP_ERR_PROTOCOL,
+        ErrorKind::ServerError(code) => TFTP_ERR_SERVER_BASE - code as c_int,
+        ErrorKind::TimedOut => TFTP_ERR_TIMED_OUT,
+        ErrorKind::PortUnreachable => TFTP_ERR_PORT_UNREACHABLE,
+        ErrorKind::Cancelled => TFTP_ERR_CANCELLED,
+        ErrorKind::InvalidConfig => TFTP_ERR_INVALID_CONFIG,
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, c_int> {
+    if s.is_null() {
+        return Err(TFTP_ERR_INVALID_ARGUMENT)
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| TFTP_ERR_INVALID_ARGUMENT)
+}
+
+/// Resolves `HOST[:PORT]`, the port defaults to 69.
+fn resolve(server: &str) -> Result<SocketAddr, c_int> {
+    if let Ok(addr) = server.parse() {
+        return Ok(addr)
+    }
+    let resolved = match server.rfind(':') {
+        Some(i) if !server[..i].contains(':') => {
+            let port = try!(server[i + 1..].parse().map_err(|_| TFTP_ERR_RESOLVE));
+            (&server[..i], port).to_socket_addrs()
+        }
+        _ => (server.trim_left_matches('[').trim_right_matches(']'), 69).to_socket_addrs(),
+    };
+    resolved.ok().and_then(|mut addrs| addrs.next()).ok_or(TFTP_ERR_RESOLVE)
+}
+
+fn build_client(server_addr: SocketAddr, options: &tftp_options) -> Result<(Client, Mode), c_int> {
+    let mode = match options.mode {
+        TFTP_MODE_OCTET => Mode::Octet,
+        TFTP_MODE_NETASCII => Mode::NetAscii,
+        _ => return Err(TFTP_ERR_INVALID_CONFIG),
+    };
+    let mut builder = ClientBuilder::new(server_addr);
+    if options.block_size != 0 {
+        builder = builder.block_size(try!(BlockSize::new(options.block_size as usize)
+            .map_err(|_| TFTP_ERR_INVALID_CONFIG)));
+    }
+    if options.timeout_ms != 0 {
+        builder = builder.timeout(Duration::from_millis(options.timeout_ms as u64));
+    }
+    if options.retries != 0 {
+        builder = builder.retries(try!(Retries::new(options.retries)
+            .map_err(|_| TFTP_ERR_INVALID_CONFIG)));
+    }
+    let client = try!(builder.build().map_err(|_| TFTP_ERR_INVALID_CONFIG));
+    Ok((client, mode))
+}
+
+fn transfer_result(result: Result<(), Error>, progress: &Progress) -> c_int {
+    match result {
+        Ok(()) => TFTP_OK,
+        Err(_) if progress.cancelled => TFTP_ERR_CANCELLED,
+        Err(e) => error_code(&e),
+    }
+}
+
+/// Runs `f` and converts a panic into an error code, unwinding into C is undefined behavior.
+fn catch_panic<F: FnOnce() -> Result<c_int, c_int>>(f: F) -> c_int {
+    match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(Ok(code)) | Ok(Err(code)) => code,
+        Err(_) => TFTP_ERR_INTERNAL,
+    }
+}
+
+unsafe fn options_arg(options: *const tftp_options) -> tftp_options {
+    if options.is_null() {
+        tftp_options { mode: TFTP_MODE_OCTET, block_size: 0, timeout_ms: 0, retries: 0 }
+    } else {
+        ptr::read(options)
+    }
+}
+
+/// Fetches `remote_path` from `server` (`HOST[:PORT]`) into the file `local_path`.
+///
+/// `options` and `progress` may be NULL. A partially written file is removed
+/// when the transfer fails.
+#[no_mangle]
+pub unsafe extern "C" fn tftp_client_get(server: *const c_char,
+                                         remote_path: *const c_char,
+                                         local_path: *const c_char,
+                                         options: *const tftp_options,
+                                         progress: tftp_progress_cb,
+                                         user_data: *mut c_void) -> c_int {
+    catch_panic(|| {
+        let server_addr = try!(resolve(try!(str_arg(server))));
+        let remote_path = try!(str_arg(remote_path));
+        let local_path = try!(str_arg(local_path));
+        let (client, mode) = try!(build_client(server_addr, &options_arg(options)));
+
+        let file = try!(File::create(local_path).map_err(|_| TFTP_ERR_IO));
+        let mut writer = ProgressWriter {
+            inner: BufWriter::new(file),
+            progress: Progress { callback: progress, user_data: user_data, bytes: 0, cancelled: false },
+        };
+        let result = client.get(Path::new(remote_path), mode, &mut writer).map_err(Error::from)
+            .and_then(|_| writer.flush().map_err(Error::from));
+        let code = transfer_result(result, &writer.progress);
+        if code != TFTP_OK {
+            let _ = fs::remove_file(local_path);
+        }
+        Ok(code)
+    })
+}
+
+/// Sends the file `local_path` to `server` (`HOST[:PORT]`) as `remote_path`.
+///
+/// `options` and `progress` may be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn tftp_client_put(server: *const c_char,
+                                         local_path: *const c_char,
+                                         remote_path: *const c_char,
+                                         options: *const tftp_options,
+                                         progress: tftp_progress_cb,
+                                         user_data: *mut c_void) -> c_int {
+    catch_panic(|| {
+        let server_addr = try!(resolve(try!(str_arg(server))));
+        let local_path = try!(str_arg(local_path));
+        let remote_path = try!(str_arg(remote_path));
+        let (client, mode) = try!(build_client(server_addr, &options_arg(options)));
+
+        let file = try!(File::open(local_path).map_err(|_| TFTP_ERR_IO));
+        let mut reader = ProgressReader {
+            inner: BufReader::new(file),
+            progress: Progress { callback: progress, user_data: user_data, bytes: 0, cancelled: false },
+        };
+        let result = client.put(Path::new(remote_path), mode, &mut reader).map(|_| ()).map_err(Error::from);
+        Ok(transfer_result(result, &reader.progress))
+    })
+}
+
+/// Returns a static description of an error code.
+#[no_mangle]
+pub extern "C" fn tftp_strerror(code: c_int) -> *const c_char {
+    let description: &'static [u8] = match code {
+        TFTP_OK => b"success\0",
+        TFTP_ERR_INVALID_ARGUMENT => b"invalid argument\0",
+        TFTP_ERR_RESOLVE => b"can't resolve server address\0",
+        TFTP_ERR_INVALID_CONFIG => b"invalid option\0",
+        TFTP_ERR_IO => b"I/O error\0",
+        TFTP_ERR_TIMED_OUT => b"transfer timed out\0",
+        TFTP_ERR_PROTOCOL => b"protocol error\0",
+        TFTP_ERR_CANCELLED => b"transfer cancelled\0",
+        TFTP_ERR_INTERNAL => b"internal error\0",
+        TFTP_ERR_PORT_UNREACHABLE => b"port unreachable\0",
+        c if c <= TFTP_ERR_SERVER_BASE => b"server error\0",
+        _ => b"unknown error\0",
+    };
+    description.as_ptr() as *const c_char
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::CStr;
+    use std::ptr;
+
+    use packet;
+    use error::{Error, ErrorKind};
+    use super::*;
+
+    #[test]
+    fn server_errors_have_distinct_codes() {
+        let err = Error::new(ErrorKind::ServerError(packet::Error::FileNotFound), "not found");
+        assert_eq!(TFTP_ERR_SERVER_BASE - 1, error_code(&err));
+        let err = Error::new(ErrorKind::ServerError(packet::Error::Undefined), "undefined");
+        assert_eq!(TFTP_ERR_SERVER_BASE, error_code(&err));
+    }
+
+    #[test]
+    fn null_arguments_are_rejected() {
+        let code = unsafe {
+            tftp_client_get(ptr::null(), ptr::null(), ptr::null(), ptr::null(), None, ptr::null_mut())
+        };
+        assert_eq!(TFTP_ERR_INVALID_ARGUMENT, code);
+    }
+
+    #[test]
+    fn server_address_defaults_to_port_69() {
+        assert_eq!(Ok("127.0.0.1:69".parse().unwrap()), resolve("127.0.0.1"));
+        assert_eq!(Ok("127.0.0.1:6969".parse().unwrap()), resolve("127.0.0.1:6969"));
+        assert_eq!(Ok("[::1]:69".parse().unwrap()), resolve("[::1]"));
+        assert_eq!(Err(TFTP_ERR_RESOLVE), resolve("127.0.0.1:port"));
+    }
+
+    #[test]
+    fn strerror_returns_c_strings() {
+        let description = unsafe { CStr::from_ptr(tftp_strerror(TFTP_ERR_SERVER_BASE - 2)) };
+        assert_eq!("server error", description.to_str().unwrap());
+        let description = unsafe { CStr::from_ptr(tftp_strerror(TFTP_ERR_PORT_UNREACHABLE)) };
+        assert_eq!("port unreachable", description.to_str().unwrap());
+    }
+}
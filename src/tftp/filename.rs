@@ -0,0 +1,136 @@
+//! Encodings of file names in requests.
+//!
+//! RFC 1350 only allows netascii file names, how other characters are sent is
+//! up to the implementations. This crate sends names as UTF-8 by default, which
+//! many legacy servers mangle. A `FilenameCodec` selects another encoding of
+//! the file name of read and write requests, see `RequestPacket::encode_with`
+//! and `RequestPacket::decode_with`.
+
+use std::error;
+use std::fmt;
+use std::str::{self, FromStr};
+
+/// Encoding of the file name of a request on the wire.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum FilenameCodec {
+    /// Names are sent as UTF-8 unchanged.
+    Utf8,
+
+    /// Names are sent as ISO 8859-1, used by many Windows and embedded
+    /// servers. Names with characters outside of it can't be encoded.
+    Latin1,
+
+    /// Bytes of the UTF-8 name outside of printable ASCII, and `%`, are sent
+    /// percent-encoded (`%C3%A9`), leaving a pure ASCII name.
+    Percent,
+}
+
+impl Default for FilenameCodec {
+    fn default() -> FilenameCodec {
+        FilenameCodec::Utf8
+    }
+}
+
+impl FilenameCodec {
+    /// Encodes a file name, returns `None` if the name can't be represented.
+    pub fn encode(&self, filename: &str) -> Option<Vec<u8>> {
+        match *self {
+            FilenameCodec::Utf8 => Some(filename.as_bytes().to_vec()),
+            FilenameCodec::Latin1 => filename.chars()
+                .map(|c| if (c as u32) < 0x100 { Some(c as u8) } else { None })
+                .collect(),
+            FilenameCodec::Percent => {
+                let mut encoded = Vec::with_capacity(filename.len());
+                for &b in filename.as_bytes() {
+                    if b >= 0x20 && b < 0x7f && b != b'%' {
+                        encoded.push(b);
+                    } else {
+                        encoded.extend_from_slice(format!("%{:02X}", b).as_bytes());
+                    }
+                }
+                Some(encoded)
+            }
+        }
+    }
+
+    /// Decodes a file name received in a request, returns `None` if it's not
+    /// valid in the encoding.
+    pub fn decode(&self, raw: &[u8]) -> Option<String> {
+        match *self {
+            FilenameCodec::Utf8 => str::from_utf8(raw).ok().map(|name| name.to_owned()),
+            FilenameCodec::Latin1 => Some(raw.iter().map(|&b| b as char).collect()),
+            FilenameCodec::Percent => {
+                let mut decoded = Vec::with_capacity(raw.len());
+                let mut i = 0;
+                while i < raw.len() {
+                    if raw[i] == b'%' {
+                        let byte = raw.get(i + 1..i + 3)
+                            .and_then(|hex| str::from_utf8(hex).ok())
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                        match byte {
+                            Some(byte) => decoded.push(byte),
+                            None => return None,
+                        }
+                        i += 3;
+                    } else {
+                        decoded.push(raw[i]);
+                        i += 1;
+                    }
+                }
+                String::from_utf8(decoded).ok()
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseFilenameCodecError;
+
+impl fmt::Display for ParseFilenameCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "provided string was not `utf8`, `latin1` or `percent`".fmt(f)
+    }
+}
+
+impl error::Error for ParseFilenameCodecError {
+    fn description(&self) -> &str { "failed to parse FilenameCodec" }
+}
+
+impl FromStr for FilenameCodec {
+    type Err = ParseFilenameCodecError;
+
+    fn from_str(s: &str) -> Result<FilenameCodec, ParseFilenameCodecError> {
+        match s {
+            "utf8" => Ok(FilenameCodec::Utf8),
+            "latin1" => Ok(FilenameCodec::Latin1),
+            "percent" => Ok(FilenameCodec::Percent),
+            _ => Err(ParseFilenameCodecError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FilenameCodec;
+
+    #[test]
+    fn latin1_encodes_only_its_characters() {
+        assert_eq!(Some(b"caf\xe9".to_vec()), FilenameCodec::Latin1.encode("café"));
+        assert_eq!(None, FilenameCodec::Latin1.encode("файл"));
+        assert_eq!(Some("café".to_owned()), FilenameCodec::Latin1.decode(b"caf\xe9"));
+    }
+
+    #[test]
+    fn percent_encoding_leaves_ascii_name() {
+        assert_eq!(Some(b"caf%C3%A9 100%25".to_vec()), FilenameCodec::Percent.encode("café 100%"));
+        assert_eq!(Some("café 100%".to_owned()), FilenameCodec::Percent.decode(b"caf%C3%A9 100%25"));
+        assert_eq!(None, FilenameCodec::Percent.decode(b"bad%4"));
+        assert_eq!(None, FilenameCodec::Percent.decode(b"%FF"));
+    }
+
+    #[test]
+    fn utf8_passes_names_through() {
+        assert_eq!(Some("café".as_bytes().to_vec()), FilenameCodec::Utf8.encode("café"));
+        assert_eq!(None, FilenameCodec::Utf8.decode(b"caf\xe9"));
+    }
+}
@@ -0,0 +1,2047 @@
+//! Sources and destinations of the files transferred by the server.
+//!
+//! The server asks a `Handler` to open the file of every accepted request.
+//! Opening returns a future, so a handler can fetch content from a remote
+//! backend (object storage, HTTP, ...) without blocking the reactor, and the
+//! returned reader or writer is polled as the transfer progresses.
+//!
+//! `FsHandler` serves files from a directory and is used by default, its file
+//! operations can run on an `IoPool` off the reactor. With `SharedFiles` the
+//! concurrent downloads of a file, e.g. during a boot storm, read one open
+//! file. A `Router` dispatches requests to different handlers by file name
+//! prefix.
+//!
+//! `FsHandler::checksum_sidecars` serves the SHA-256 digest of every file as
+//! `<file>.sha256`, so clients can verify downloads with a plain request.
+//! `FsHandler::fallback` serves a fallback file for missing files matching a
+//! pattern, e.g. a recovery image, so misconfigured devices still boot into a
+//! state that can be diagnosed. With the `compression` feature
+//! `FsHandler::compressed` serves `<file>.gz` or `<file>.zst` decompressed
+//! when `<file>` is missing, so image repositories can be stored compressed.
+//!
+//! Handlers also assign transfers a `Priority`. When the server limits the
+//! number of concurrent transfers, waiting requests of higher priority start
+//! first, e.g. a route of boot files wrapped in `Prioritized` isn't starved by
+//! bulk image downloads. Under a bandwidth cap their data packets are sent
+//! first as well.
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use futures::{Async, Poll};
+use futures::future::{self, Either, Future, FutureResult};
+use tokio_core::reactor::Handle;
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use config::{ConfigError, DEFAULT_TIMEOUT};
+#[cfg(feature = "compression")]
+use decompress::{Compression, Decoder};
+use packet::{Mode, TransferOptions};
+#[cfg(feature = "compression")]
+use packet::TSIZE_OPTION;
+use pool::{self, Blocking, IoPool, Pooled};
+use sha256::{self, Sha256};
+#[cfg(target_os = "linux")]
+use sys;
+use transfer::{TransferParams, DEFAULT_BLOCK_SIZE};
+
+/// Request a handler opens a file for.
+#[derive(Debug, Clone, Copy)]
+pub struct Request<'a> {
+    filename: &'a str,
+    mode: Mode,
+    client_addr: Option<SocketAddr>,
+    params: TransferParams,
+    options: Option<&'a TransferOptions<'a>>,
+}
+
+impl<'a> Request<'a> {
+    /// Creates a request of a client at `client_addr`.
+    pub fn new(filename: &'a str, mode: Mode, client_addr: Option<SocketAddr>) -> Request<'a> {
+        Request {
+            filename: filename,
+            mode: mode,
+            client_addr: client_addr,
+            params: TransferParams::new(DEFAULT_BLOCK_SIZE, DEFAULT_TIMEOUT),
+            options: None,
+        }
+    }
+
+    /// Sets the parameters negotiated for the transfer.
+    pub fn with_params(mut self, params: TransferParams) -> Request<'a> {
+        self.params = params;
+        self
+    }
+
+    /// Returns the requested file name.
+    pub fn filename(&self) -> &'a str {
+        self.filename
+    }
+
+    /// Returns the transfer mode.
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Returns the address of the client, `None` for clients connected over a
+    /// Unix socket.
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client_addr
+    }
+
+    /// Returns the parameters the transfer runs with after option negotiation.
+    pub fn params(&self) -> TransferParams {
+        self.params
+    }
+
+    /// Sets the options the client appended to the request.
+    pub fn with_options(mut self, options: &'a TransferOptions<'a>) -> Request<'a> {
+        self.options = Some(options);
+        self
+    }
+
+    /// Returns the options the client appended to the request, including the
+    /// ones the server doesn't know, `None` if they are not known.
+    pub fn options(&self) -> Option<&'a TransferOptions<'a>> {
+        self.options
+    }
+}
+
+/// Priority class of a transfer, from lowest to highest.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Copy)]
+pub enum Priority {
+    /// Background transfers, like large images, that can wait.
+    Bulk,
+
+    /// Regular transfers.
+    Normal,
+
+    /// Transfers something waits for, like the boot files of PXE clients.
+    Critical,
+}
+
+impl Default for Priority {
+    fn default() -> Priority {
+        Priority::Normal
+    }
+}
+
+/// Opens files for the server.
+///
+/// Errors returned while opening are sent to the client, their kind selects
+/// the TFTP error code (`NotFound`, `PermissionDenied` and `AlreadyExists`
+/// have their own codes).
+///
+/// A write transfer calls `AsyncWrite::shutdown` on the writer before the
+/// last block is acknowledged. A writer dropped without being shut down
+/// belongs to a failed transfer and should discard the received data.
+pub trait Handler: 'static {
+    /// Source of a file read by a client.
+    type Reader: AsyncRead + 'static;
+
+    /// Destination of a file written by a client.
+    type Writer: AsyncWrite + 'static;
+
+    /// Future opening a file for reading.
+    type OpenRead: Future<Item = Self::Reader, Error = io::Error> + 'static;
+
+    /// Future opening a file for writing.
+    type OpenWrite: Future<Item = Self::Writer, Error = io::Error> + 'static;
+
+    /// Opens a file requested by a read request.
+    fn open_read(&self, request: &Request, handle: &Handle) -> Self::OpenRead;
+
+    /// Opens a file requested by a write request.
+    fn open_write(&self, request: &Request, handle: &Handle) -> Self::OpenWrite;
+
+    /// Returns the priority of the transfer of `request`, by default
+    /// `Priority::Normal`.
+    ///
+    /// The priority orders requests waiting for a free transfer slot when the
+    /// server limits the number of concurrent transfers, and the data packets
+    /// of read transfers waiting for their turn under a bandwidth cap.
+    fn priority(&self, request: &Request) -> Priority {
+        let _ = request;
+        Priority::Normal
+    }
+
+    /// Returns the values the server acknowledges for options of `request`
+    /// it doesn't support itself, e.g. vendor extensions. By default none.
+    ///
+    /// Options the client didn't request and the ones the server negotiates
+    /// itself are not acknowledged.
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static> {
+        let _ = request;
+        TransferOptions::new()
+    }
+
+    /// Returns the file the client of the read `request` fetches next, e.g.
+    /// the initrd after the kernel. By default none.
+    ///
+    /// The server opens the follow-up while the transfer of `request` runs
+    /// and keeps it open for the next read request of the client for up to
+    /// five minutes, so sequenced fetches don't wait for the file to be opened.
+    fn follow_up(&self, request: &Request) -> Option<String> {
+        let _ = request;
+        None
+    }
+
+    /// Returns the problems of the handler's configuration, checked when the
+    /// server is built. `read_only` tells if the server accepts writes through
+    /// the handler. By default none.
+    fn validate(&self, read_only: bool) -> Vec<ConfigError> {
+        let _ = read_only;
+        Vec::new()
+    }
+}
+
+/// Serves files from a directory of the local file system.
+///
+/// Requested names are resolved inside the root directory, names that would
+/// escape it are rejected, and by default so are symbolic links leading out
+/// of it, see `SymlinkPolicy`. Existing files are never overwritten. Uploads are
+/// written to a hidden file next to the destination and moved into place once
+/// complete, so readers never see a partial file.
+///
+/// By default files are opened, read and written on the reactor thread, which
+/// is fast enough for files in the page cache. With an `IoPool` slow disks
+/// don't delay other transfers.
+#[derive(Clone)]
+pub struct FsHandler {
+    root: PathBuf,
+    pool: Option<IoPool>,
+    transform: Option<Transform>,
+    check_upload: Option<UploadCheck>,
+    directories: DirectoryPolicy,
+    symlinks: SymlinkPolicy,
+    shared: Option<SharedFiles>,
+    checksums: Option<Checksums>,
+    /// Patterns of missing files and the files served instead.
+    fallbacks: Vec<(String, String)>,
+    /// Missing files are served decompressed from compressed files.
+    compressed: bool,
+}
+
+/// What an `FsHandler` serves when a read request names a directory.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum DirectoryPolicy {
+    /// The request fails with "file not found".
+    NotFound,
+
+    /// The file with this name in the directory is served, e.g. `default`.
+    /// The request fails if the directory doesn't have one.
+    Index(String),
+
+    /// A listing of the directory is served, the names of its entries sorted
+    /// one per line, names of directories end with `/`. With a transform the
+    /// listing is passed to it like the contents of a file, so it can be
+    /// turned into e.g. an iPXE menu.
+    Listing,
+}
+
+/// Symbolic links an `FsHandler` follows in the paths of requested files.
+///
+/// Links are checked when a file is opened, then the checked path is opened.
+/// On Linux it's opened one component at a time without following links, so
+/// a link created in between by someone with write access to the root fails
+/// the request. On other systems, and for the directory of an upload, such a
+/// link is not caught.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum SymlinkPolicy {
+    /// Requests of paths with a symbolic link in them fail.
+    Deny,
+
+    /// Links are followed as long as the file they lead to is inside the
+    /// root. Paths are compared after resolving all links, links that don't
+    /// lead anywhere are rejected.
+    WithinRoot,
+
+    /// All links are followed, even out of the root.
+    All,
+}
+
+impl Default for SymlinkPolicy {
+    fn default() -> SymlinkPolicy {
+        SymlinkPolicy::WithinRoot
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseSymlinkPolicyError;
+
+impl fmt::Display for ParseSymlinkPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "provided string was not `deny`, `within-root` or `all`".fmt(f)
+    }
+}
+
+impl error::Error for ParseSymlinkPolicyError {
+    fn description(&self) -> &str { "failed to parse SymlinkPolicy" }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = ParseSymlinkPolicyError;
+
+    fn from_str(s: &str) -> Result<SymlinkPolicy, ParseSymlinkPolicyError> {
+        match s {
+            "deny" => Ok(SymlinkPolicy::Deny),
+            "within-root" => Ok(SymlinkPolicy::WithinRoot),
+            "all" => Ok(SymlinkPolicy::All),
+            _ => Err(ParseSymlinkPolicyError),
+        }
+    }
+}
+
+impl Default for DirectoryPolicy {
+    fn default() -> DirectoryPolicy {
+        DirectoryPolicy::NotFound
+    }
+}
+
+#[derive(Debug)]
+pub struct ParseDirectoryPolicyError;
+
+impl fmt::Display for ParseDirectoryPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        "provided string was not `not-found`, `listing` or `index:NAME`".fmt(f)
+    }
+}
+
+impl error::Error for ParseDirectoryPolicyError {
+    fn description(&self) -> &str { "failed to parse DirectoryPolicy" }
+}
+
+impl FromStr for DirectoryPolicy {
+    type Err = ParseDirectoryPolicyError;
+
+    fn from_str(s: &str) -> Result<DirectoryPolicy, ParseDirectoryPolicyError> {
+        match s {
+            "not-found" => Ok(DirectoryPolicy::NotFound),
+            "listing" => Ok(DirectoryPolicy::Listing),
+            _ if s.starts_with("index:") && s.len() > "index:".len() => {
+                Ok(DirectoryPolicy::Index(s["index:".len()..].to_owned()))
+            }
+            _ => Err(ParseDirectoryPolicyError),
+        }
+    }
+}
+
+/// Rewrites the contents of a file for a request, see `FsHandler::transform`.
+pub type Transform = Arc<Fn(&Request, Vec<u8>) -> io::Result<Vec<u8>> + Send + Sync>;
+
+/// Accepts or rejects an uploaded file, see `FsHandler::check_upload`.
+pub type UploadCheck = Arc<Fn(&Request, &Path) -> io::Result<()> + Send + Sync>;
+
+impl FsHandler {
+    /// Creates a handler serving files from `root`.
+    pub fn new<P: Into<PathBuf>>(root: P) -> FsHandler {
+        FsHandler {
+            root: root.into(),
+            pool: None,
+            transform: None,
+            check_upload: None,
+            directories: DirectoryPolicy::default(),
+            symlinks: SymlinkPolicy::default(),
+            shared: None,
+            checksums: None,
+            fallbacks: Vec::new(),
+            compressed: false,
+        }
+    }
+
+    /// Opens, reads and writes files on the threads of `pool`.
+    pub fn io_pool(mut self, pool: IoPool) -> FsHandler {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Rewrites the contents of files before they are sent, e.g. to substitute
+    /// the address of the client into an iPXE script template.
+    ///
+    /// Files are read into memory whole and passed to `transform` with the
+    /// request, the client gets the returned contents. An error fails the
+    /// request like an error opening the file. Add the handler as a `Router`
+    /// route to transform only the files under a prefix.
+    pub fn transform<F>(mut self, transform: F) -> FsHandler
+        where F: Fn(&Request, Vec<u8>) -> io::Result<Vec<u8>> + Send + Sync + 'static,
+    {
+        self.transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// Checks uploads with `check` before they are moved into place, e.g. to
+    /// scan configurations uploaded by field devices.
+    ///
+    /// `check` gets the request and the path of the complete upload, which is
+    /// still a hidden file. An error rejects the upload, the file is removed
+    /// and the client gets the error. The check runs on the I/O pool if the
+    /// handler has one, on the reactor thread otherwise.
+    pub fn check_upload<F>(mut self, check: F) -> FsHandler
+        where F: Fn(&Request, &Path) -> io::Result<()> + Send + Sync + 'static,
+    {
+        self.check_upload = Some(Arc::new(check));
+        self
+    }
+
+    /// Sets what is served when a read request names a directory, by default
+    /// the request fails with "file not found".
+    pub fn directories(mut self, policy: DirectoryPolicy) -> FsHandler {
+        self.directories = policy;
+        self
+    }
+
+    /// Sets the symbolic links followed in the paths of requested files, by
+    /// default links are followed within the root only.
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> FsHandler {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Shares the open files between the concurrent downloads of a file
+    /// through `files`, instead of opening the file for every download.
+    ///
+    /// Downloads of a file that changed since it was opened open it again.
+    /// `files` can be shared with other handlers and counts the opened and
+    /// shared files, see `SharedFiles::stats`.
+    pub fn share_files(mut self, files: SharedFiles) -> FsHandler {
+        self.shared = Some(files);
+        self
+    }
+
+    /// Serves the SHA-256 digest of a file when `<file>.sha256` is requested
+    /// and doesn't exist, in the format of `sha256sum`.
+    ///
+    /// Digests are cached until the file changes. They are digests of the
+    /// contents clients get, a file served decompressed (`compressed`) is
+    /// digested decompressed. The contents of a `transform` depend on the
+    /// request, handlers with a transform serve no sidecars. Without an I/O
+    /// pool digests are computed on a thread of their own.
+    pub fn checksum_sidecars(mut self, enabled: bool) -> FsHandler {
+        self.checksums = if enabled { Some(Checksums::default()) } else { None };
+        self
+    }
+
+    /// Serves the file `file` of the root when a read request names a missing
+    /// file matching `pattern`, instead of failing with "file not found".
+    ///
+    /// In the pattern `*` matches any number of characters, also `/`, and `?`
+    /// matches one character. The first matching fallback is served, the
+    /// transform of the handler gets the original request, e.g. to render an
+    /// iPXE script reporting the missing file. Uploads are not affected.
+    pub fn fallback<S: Into<String>, F: Into<String>>(mut self, pattern: S, file: F) -> FsHandler {
+        self.fallbacks.push((pattern.into(), file.into()));
+        self
+    }
+
+    /// Serves a missing file decompressed from `<file>.gz` or `<file>.zst`
+    /// if one of them exists, before a fallback.
+    ///
+    /// Clients requesting the transfer size get the size of the decompressed
+    /// file when the compressed file records it, see
+    /// `Compression::decompressed_size`. The transform of the handler isn't
+    /// applied to decompressed files.
+    #[cfg(feature = "compression")]
+    pub fn compressed(mut self, enabled: bool) -> FsHandler {
+        self.compressed = enabled;
+        self
+    }
+
+    /// Returns the directory files are served from.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn resolve(&self, request: &Request) -> io::Result<PathBuf> {
+        resolve_path(&self.root, request.filename())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "invalid file name"))
+    }
+
+    /// Returns the path of the fallback of the file of `request`, if one
+    /// matches its name.
+    fn fallback_path(&self, request: &Request) -> Option<PathBuf> {
+        let filename: Vec<char> = request.filename().chars().collect();
+        self.fallbacks.iter()
+            .find(|&&(ref pattern, _)| glob_matches(&pattern.chars().collect::<Vec<_>>(), &filename))
+            .and_then(|&(_, ref file)| resolve_path(&self.root, file))
+    }
+}
+
+/// Returns `true` if `name` matches the glob `pattern`, see `FsHandler::fallback`.
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&'*', rest)) => (0..name.len() + 1).any(|skipped| glob_matches(rest, &name[skipped..])),
+        Some((&'?', rest)) => !name.is_empty() && glob_matches(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && glob_matches(rest, &name[1..]),
+    }
+}
+
+impl Handler for FsHandler {
+    type Reader = FsReader;
+    type Writer = FsWriter;
+    type OpenRead = FsOpen<FsReader>;
+    type OpenWrite = FsOpen<FsWriter>;
+
+    fn open_read(&self, request: &Request, _: &Handle) -> FsOpen<FsReader> {
+        let path = match self.resolve(request) {
+            Ok(path) => path,
+            Err(e) => return Either::A(future::err(e)),
+        };
+        let template = self.transform.clone().map(|transform| Template::new(transform, request));
+        let directories = self.directories.clone();
+        let (root, symlinks, shared) = (self.root.clone(), self.symlinks, self.shared.clone());
+        let checksums = if template.is_none() { self.checksums.clone() } else { None };
+        let fallback = self.fallback_path(request);
+        let compressed = self.compressed;
+        // A missing sidecar is generated by reading the whole file.
+        let digests = checksums.is_some() && sidecar_file(&path).is_some() && fs::symlink_metadata(&path).is_err();
+        let open = move |pool: Option<IoPool>| {
+            let checked = |path: &Path| open_checked(&root, path, symlinks);
+            let open_path = |path: PathBuf, template: Option<Template>| {
+                try!(check_symlinks(&root, &path, symlinks));
+                if let (Some(checksums), Some(file)) = (checksums.as_ref(), sidecar_file(&path)) {
+                    if fs::symlink_metadata(&path).is_err() {
+                        try!(check_symlinks(&root, &file, symlinks));
+                        let sidecar = match checksums.sidecar(&file, &file, &checked, |file| file) {
+                            Err(ref e) if e.kind() == io::ErrorKind::NotFound && compressed => {
+                                try!(compressed_sidecar(checksums, &file, &checked))
+                            }
+                            sidecar => try!(sidecar),
+                        };
+                        return Ok(FsReader { contents: FsContents::Rendered(io::Cursor::new(sidecar)) })
+                    }
+                }
+                open_file(path, &directories, template, pool.clone(), shared.as_ref(), &checked)
+            };
+            let opened = match open_path(path.clone(), template.clone()) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && compressed => {
+                    open_compressed(&path, |path| open_path(path, None))
+                }
+                opened => opened,
+            };
+            match (opened, fallback) {
+                (Err(ref e), Some(fallback)) if e.kind() == io::ErrorKind::NotFound => {
+                    debug!("Serving fallback {} instead of a missing file", fallback.display());
+                    open_path(fallback, template)
+                }
+                (opened, _) => opened,
+            }
+        };
+        match self.pool {
+            Some(ref pool) => {
+                let reader_pool = pool.clone();
+                Either::B(pool.spawn(move || open(Some(reader_pool))))
+            }
+            None if digests => Either::B(pool::spawn_thread(move || open(None))),
+            None => Either::A(future::result(open(None))),
+        }
+    }
+
+    fn open_write(&self, request: &Request, _: &Handle) -> FsOpen<FsWriter> {
+        let path = match self.resolve(request) {
+            Ok(path) => path,
+            Err(e) => return Either::A(future::err(e)),
+        };
+        let check = self.check_upload.clone().map(|check| (check, OwnedRequest::new(request)));
+        let (root, symlinks) = (self.root.clone(), self.symlinks);
+        let create = move |path: PathBuf, pool: Option<IoPool>| {
+            try!(check_symlinks(&root, &path, symlinks));
+            if fs::symlink_metadata(&path).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "file already exists"))
+            }
+            let partial = partial_path(&path);
+            let file = io::BufWriter::new(try!(OpenOptions::new().write(true).create_new(true).open(&partial)));
+            Ok(FsWriter {
+                file: match pool {
+                    Some(ref pool) => FsFile::Pooled(Pooled::new(file, pool.clone())),
+                    None => FsFile::Local(file),
+                },
+                finish: Some(Finish {
+                    partial: partial.clone(),
+                    path: path,
+                    check: check,
+                }),
+                finishing: None,
+                pool: pool,
+                partial: partial,
+                complete: false,
+            })
+        };
+        match self.pool {
+            Some(ref pool) => {
+                let writer_pool = pool.clone();
+                Either::B(pool.spawn(move || create(path, Some(writer_pool))))
+            }
+            None => Either::A(future::result(create(path, None))),
+        }
+    }
+
+    /// The root must be a directory, writable unless the server is
+    /// read-only. Writing is checked by creating a hidden file, like uploads.
+    fn validate(&self, read_only: bool) -> Vec<ConfigError> {
+        let invalid = |problem: String| vec![ConfigError::InvalidRoot(self.root.clone(), problem)];
+        match fs::metadata(&self.root) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return invalid("doesn't exist".to_owned()),
+            Err(e) => return invalid(format!("can't be read ({})", e)),
+            Ok(ref metadata) if !metadata.is_dir() => return invalid("is not a directory".to_owned()),
+            Ok(_) => {}
+        }
+        if !read_only {
+            let probe = partial_path(&self.root.join("write-check"));
+            match OpenOptions::new().write(true).create_new(true).open(&probe) {
+                Ok(_) => {
+                    let _ = fs::remove_file(&probe);
+                }
+                Err(e) => return invalid(format!("is not writable ({}), fix its permissions or serve it read-only", e)),
+            }
+        }
+        Vec::new()
+    }
+
+    /// Acknowledges the decompressed size to clients reading a compressed
+    /// file in octet mode with the transfer size option.
+    #[cfg(feature = "compression")]
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static> {
+        let mut options = TransferOptions::new();
+        // Read requests ask for the size with a size of 0.
+        let asked = request.options().and_then(|options| options.get(TSIZE_OPTION)) == Some("0");
+        if !self.compressed || !asked || request.mode() != Mode::Octet {
+            return options
+        }
+        let path = match self.resolve(request) {
+            Ok(ref path) if fs::symlink_metadata(path).is_err() => path.clone(),
+            _ => return options,
+        };
+        let size = compressed_variant(&path).and_then(|(compressed, compression)| {
+            open_checked(&self.root, &compressed, self.symlinks)
+                .and_then(|mut file| compression.decompressed_size(&mut file)).ok()
+        });
+        if let Some(Some(size)) = size {
+            options.insert(TSIZE_OPTION, size.to_string());
+        }
+        options
+    }
+}
+
+/// Opens the compressed variant of the missing file at `path` with `open`,
+/// the reader decompresses it.
+#[cfg(feature = "compression")]
+fn open_compressed<F>(path: &Path, open: F) -> io::Result<FsReader>
+    where F: FnOnce(PathBuf) -> io::Result<FsReader>,
+{
+    match compressed_variant(path) {
+        Some((compressed, compression)) => {
+            debug!("Serving {} decompressed", compressed.display());
+            let reader = try!(open(compressed));
+            Ok(FsReader { contents: FsContents::Decompressed(Box::new(Decoder::new(reader, compression))) })
+        }
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn open_compressed<F>(_: &Path, _: F) -> io::Result<FsReader>
+    where F: FnOnce(PathBuf) -> io::Result<FsReader>,
+{
+    Err(io::Error::new(io::ErrorKind::NotFound, "file not found"))
+}
+
+/// Returns the sidecar of the missing file at `path` digesting its compressed
+/// variant decompressed, like it's served.
+#[cfg(feature = "compression")]
+fn compressed_sidecar(checksums: &Checksums, path: &Path, open: &Fn(&Path) -> io::Result<File>)
+                      -> io::Result<Vec<u8>> {
+    match compressed_variant(path) {
+        Some((compressed, compression)) => {
+            checksums.sidecar(path, &compressed, open, |file| Decoder::new(file, compression))
+        }
+        None => Err(io::Error::new(io::ErrorKind::NotFound, "file not found")),
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+fn compressed_sidecar(_: &Checksums, _: &Path, _: &Fn(&Path) -> io::Result<File>) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::NotFound, "file not found"))
+}
+
+/// Returns the path and the format of the existing compressed variant of the
+/// file at `path`.
+#[cfg(feature = "compression")]
+fn compressed_variant(path: &Path) -> Option<(PathBuf, Compression)> {
+    [Compression::Gzip, Compression::Zstd].iter().filter_map(|&compression| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".");
+        name.push(compression.extension());
+        let variant = PathBuf::from(name);
+        if fs::metadata(&variant).map(|metadata| metadata.is_file()).unwrap_or(false) {
+            Some((variant, compression))
+        } else {
+            None
+        }
+    }).next()
+}
+
+impl fmt::Debug for FsHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("FsHandler")
+            .field("root", &self.root)
+            .field("pool", &self.pool)
+            .field("transform", &self.transform.is_some())
+            .field("check_upload", &self.check_upload.is_some())
+            .field("directories", &self.directories)
+            .field("symlinks", &self.symlinks)
+            .field("shared", &self.shared)
+            .field("checksum_sidecars", &self.checksums.is_some())
+            .field("fallbacks", &self.fallbacks)
+            .field("compressed", &self.compressed)
+            .finish()
+    }
+}
+
+/// Opens the file at `path` for reading with `open`, or what `directories`
+/// selects if it's a directory.
+fn open_file(path: PathBuf, directories: &DirectoryPolicy, template: Option<Template>, pool: Option<IoPool>,
+             shared: Option<&SharedFiles>, open: &Fn(&Path) -> io::Result<File>) -> io::Result<FsReader> {
+    let path = if try!(fs::metadata(&path)).is_dir() {
+        match *directories {
+            DirectoryPolicy::NotFound => return Err(io::Error::new(io::ErrorKind::NotFound, "is a directory")),
+            DirectoryPolicy::Index(ref name) => {
+                let index = path.join(name);
+                if try!(fs::metadata(&index)).is_dir() {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, "index is a directory"))
+                }
+                index
+            }
+            DirectoryPolicy::Listing => {
+                // Only the names are read by path, once the directory passed the check.
+                try!(open(&path));
+                let listing = try!(list_directory(&path));
+                return match template {
+                    Some(template) => template.apply(listing),
+                    None => Ok(FsReader { contents: FsContents::Rendered(io::Cursor::new(listing)) }),
+                }
+            }
+        }
+    } else {
+        path
+    };
+    if let Some(template) = template {
+        return template.render(try!(open(&path)))
+    }
+    if let Some(shared) = shared {
+        let reader = SharedReader {
+            file: try!(shared.open(&path, open)),
+            position: 0,
+        };
+        return Ok(FsReader {
+            contents: FsContents::Shared(match pool {
+                Some(pool) => FsFile::Pooled(Pooled::new(reader, pool)),
+                None => FsFile::Local(reader),
+            }),
+        })
+    }
+    let file = try!(open(&path));
+    Ok(FsReader {
+        contents: FsContents::File(match pool {
+            Some(pool) => FsFile::Pooled(Pooled::new(file, pool)),
+            None => FsFile::Local(file),
+        }),
+    })
+}
+
+/// Returns the sorted names of the entries of the directory at `path`, one
+/// per line, names of directories end with `/`.
+fn list_directory(path: &Path) -> io::Result<Vec<u8>> {
+    let mut names = Vec::new();
+    for entry in try!(fs::read_dir(path)) {
+        let entry = try!(entry);
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        if try!(entry.file_type()).is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+    let mut listing = Vec::new();
+    for name in names {
+        listing.extend_from_slice(name.as_bytes());
+        listing.push(b'\n');
+    }
+    Ok(listing)
+}
+
+/// Files opened by `FsHandler`s, shared by the downloads of a file running at
+/// the same time, see `FsHandler::share_files`.
+///
+/// A file is closed once its last download ended. Clones share the files and
+/// the counters.
+#[derive(Debug, Clone, Default)]
+pub struct SharedFiles(Arc<Mutex<SharedFilesState>>);
+
+#[derive(Debug, Default)]
+struct SharedFilesState {
+    files: HashMap<PathBuf, Weak<SharedFile>>,
+    stats: SharedFileStats,
+}
+
+/// Counters of `SharedFiles`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy, Default)]
+pub struct SharedFileStats {
+    /// Files opened for a download.
+    pub opened: u64,
+
+    /// Downloads that read a file another download opened.
+    pub shared: u64,
+
+    /// Files open at the moment.
+    pub open: usize,
+}
+
+impl SharedFiles {
+    /// Creates an empty set of shared files.
+    pub fn new() -> SharedFiles {
+        SharedFiles::default()
+    }
+
+    /// Returns the counters of the files.
+    pub fn stats(&self) -> SharedFileStats {
+        let mut state = self.0.lock().unwrap();
+        state.files.retain(|_, file| file.upgrade().is_some());
+        state.stats.open = state.files.len();
+        state.stats
+    }
+
+    /// Returns the open file at `path` if it didn't change since it was
+    /// opened, opens it with `open` otherwise.
+    fn open(&self, path: &Path, open: &Fn(&Path) -> io::Result<File>) -> io::Result<Arc<SharedFile>> {
+        let id = FileId::new(&try!(fs::metadata(path)));
+        let mut state = self.0.lock().unwrap();
+        if let Some(file) = state.files.get(path).and_then(|file| file.upgrade()) {
+            if file.id == id {
+                state.stats.shared += 1;
+                return Ok(file)
+            }
+        }
+        let file = try!(open(path));
+        // The file may have been replaced between reading the metadata and opening it.
+        let file = Arc::new(SharedFile {
+            id: FileId::new(&try!(file.metadata())),
+            file: file,
+        });
+        state.files.retain(|_, file| file.upgrade().is_some());
+        state.files.insert(path.to_path_buf(), Arc::downgrade(&file));
+        state.stats.opened += 1;
+        Ok(file)
+    }
+}
+
+/// Extension of the checksum sidecars, see `FsHandler::checksum_sidecars`.
+const SIDECAR_EXTENSION: &'static str = "sha256";
+
+/// Returns the file the sidecar at `path` is the checksum of, `None` if
+/// `path` is not the path of a sidecar.
+fn sidecar_file(path: &Path) -> Option<PathBuf> {
+    match path.extension() {
+        Some(extension) if extension == SIDECAR_EXTENSION => Some(path.with_extension("")),
+        _ => None,
+    }
+}
+
+/// Checksum sidecars of the files of an `FsHandler` by the file they are
+/// read from, cached until that file changes.
+#[derive(Debug, Clone, Default)]
+struct Checksums(Arc<Mutex<HashMap<PathBuf, Digest>>>);
+
+#[derive(Debug)]
+struct Digest {
+    source: PathBuf,
+    id: FileId,
+    sidecar: Vec<u8>,
+}
+
+impl Checksums {
+    /// Returns the sidecar of the file at `path`, whose contents are read
+    /// from the file at `source`, opened with `open`, by the reader `read`
+    /// returns.
+    fn sidecar<F, R>(&self, path: &Path, source: &Path, open: &Fn(&Path) -> io::Result<File>, read: F)
+                     -> io::Result<Vec<u8>>
+        where F: FnOnce(File) -> R,
+              R: Read,
+    {
+        let metadata = try!(fs::metadata(source));
+        if metadata.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "is a directory"))
+        }
+        let id = FileId::new(&metadata);
+        if let Some(digest) = self.0.lock().unwrap().get(path) {
+            if digest.source == source && digest.id == id {
+                return Ok(digest.sidecar.clone())
+            }
+        }
+        // The digest is computed without holding the lock, concurrent
+        // requests of a changed file may compute it more than once.
+        let file = try!(open(source));
+        let id = FileId::new(&try!(file.metadata()));
+        let mut reader = read(file);
+        let mut sha = Sha256::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => sha.update(&buf[..n]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let sidecar = format!("{}  {}\n", sha256::to_hex(&sha.finish()), name).into_bytes();
+        let mut cache = self.0.lock().unwrap();
+        cache.retain(|_, digest| digest.source.exists());
+        cache.insert(path.to_path_buf(), Digest {
+            source: source.to_path_buf(),
+            id: id,
+            sidecar: sidecar.clone(),
+        });
+        Ok(sidecar)
+    }
+}
+
+/// Identity of the contents of a file, changed when the file is replaced or
+/// modified.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+struct FileId {
+    len: u64,
+    modified: Option<SystemTime>,
+    #[cfg(unix)]
+    inode: (u64, u64),
+}
+
+impl FileId {
+    fn new(metadata: &Metadata) -> FileId {
+        FileId {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+            #[cfg(unix)]
+            inode: {
+                use std::os::unix::fs::MetadataExt;
+                (metadata.dev(), metadata.ino())
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct SharedFile {
+    id: FileId,
+    file: File,
+}
+
+impl SharedFile {
+    /// Reads at `offset` without moving the position of the file, which the
+    /// other readers share.
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        self.file.read_at(buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::os::windows::fs::FileExt;
+        self.file.seek_read(buf, offset)
+    }
+}
+
+/// Download reading a shared file.
+#[derive(Debug)]
+struct SharedReader {
+    file: Arc<SharedFile>,
+    position: u64,
+}
+
+impl Read for SharedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.file.read_at(buf, self.position));
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Copy of a request that can be moved to the I/O pool.
+#[derive(Debug, Clone)]
+struct OwnedRequest {
+    filename: String,
+    mode: Mode,
+    client_addr: Option<SocketAddr>,
+    params: TransferParams,
+}
+
+impl OwnedRequest {
+    fn new(request: &Request) -> OwnedRequest {
+        OwnedRequest {
+            filename: request.filename().to_owned(),
+            mode: request.mode(),
+            client_addr: request.client_addr(),
+            params: request.params(),
+        }
+    }
+
+    fn request(&self) -> Request {
+        Request::new(&self.filename, self.mode, self.client_addr).with_params(self.params)
+    }
+}
+
+/// Transform of a file with the request it's applied for.
+#[derive(Clone)]
+struct Template {
+    transform: Transform,
+    request: OwnedRequest,
+}
+
+impl Template {
+    fn new(transform: Transform, request: &Request) -> Template {
+        Template {
+            transform: transform,
+            request: OwnedRequest::new(request),
+        }
+    }
+
+    fn render(self, mut file: File) -> io::Result<FsReader> {
+        let mut contents = Vec::new();
+        try!(file.read_to_end(&mut contents));
+        self.apply(contents)
+    }
+
+    fn apply(self, contents: Vec<u8>) -> io::Result<FsReader> {
+        let rendered = try!((self.transform)(&self.request.request(), contents));
+        Ok(FsReader { contents: FsContents::Rendered(io::Cursor::new(rendered)) })
+    }
+}
+
+/// Returns the hidden path an upload to `path` is written to until it's
+/// complete.
+fn partial_path(path: &Path) -> PathBuf {
+    static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+    let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let upload = UPLOADS.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{}.{}-{}.part", name, process::id(), upload))
+}
+
+/// Moves a complete upload into place once the check of the handler accepted
+/// it.
+struct Finish {
+    partial: PathBuf,
+    path: PathBuf,
+    check: Option<(UploadCheck, OwnedRequest)>,
+}
+
+impl Finish {
+    fn run(self) -> io::Result<()> {
+        if let Some((check, request)) = self.check {
+            try!(check(&request.request(), &self.partial));
+        }
+        // Unlike renaming, linking fails if the file was created during the upload.
+        match fs::hard_link(&self.partial, &self.path) {
+            Ok(()) => fs::remove_file(&self.partial),
+            Err(ref e) if e.kind() != io::ErrorKind::AlreadyExists => fs::rename(&self.partial, &self.path),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl fmt::Debug for Finish {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Finish").field("partial", &self.partial).field("path", &self.path).finish()
+    }
+}
+
+/// Future opening a file of an `FsHandler`, on the reactor thread, on the I/O
+/// pool or, generating a checksum sidecar, on a thread of its own.
+pub type FsOpen<T> = Either<FutureResult<T, io::Error>, Blocking<T>>;
+
+/// File accessed on the reactor thread or on the I/O pool.
+#[derive(Debug)]
+enum FsFile<F> {
+    Local(F),
+    Pooled(Pooled<F>),
+}
+
+/// Rejects writes, reads are passed to the wrapped handler.
+#[derive(Debug, Clone)]
+pub struct ReadOnly<H> {
+    handler: H,
+}
+
+impl<H: Handler> ReadOnly<H> {
+    /// Wraps `handler`, write requests fail with `PermissionDenied`.
+    pub fn new(handler: H) -> ReadOnly<H> {
+        ReadOnly {
+            handler: handler,
+        }
+    }
+}
+
+impl<H: Handler> Handler for ReadOnly<H> {
+    type Reader = H::Reader;
+    type Writer = H::Writer;
+    type OpenRead = H::OpenRead;
+    type OpenWrite = FutureResult<H::Writer, io::Error>;
+
+    fn open_read(&self, request: &Request, handle: &Handle) -> H::OpenRead {
+        self.handler.open_read(request, handle)
+    }
+
+    fn open_write(&self, _: &Request, _: &Handle) -> FutureResult<H::Writer, io::Error> {
+        future::err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only"))
+    }
+
+    fn priority(&self, request: &Request) -> Priority {
+        self.handler.priority(request)
+    }
+
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static> {
+        self.handler.acknowledge_options(request)
+    }
+
+    fn follow_up(&self, request: &Request) -> Option<String> {
+        self.handler.follow_up(request)
+    }
+
+    fn validate(&self, _: bool) -> Vec<ConfigError> {
+        self.handler.validate(true)
+    }
+}
+
+/// Assigns all transfers of the wrapped handler one priority.
+#[derive(Debug, Clone)]
+pub struct Prioritized<H> {
+    handler: H,
+    priority: Priority,
+}
+
+impl<H: Handler> Prioritized<H> {
+    /// Wraps `handler`, its transfers get `priority`.
+    pub fn new(handler: H, priority: Priority) -> Prioritized<H> {
+        Prioritized {
+            handler: handler,
+            priority: priority,
+        }
+    }
+}
+
+impl<H: Handler> Handler for Prioritized<H> {
+    type Reader = H::Reader;
+    type Writer = H::Writer;
+    type OpenRead = H::OpenRead;
+    type OpenWrite = H::OpenWrite;
+
+    fn open_read(&self, request: &Request, handle: &Handle) -> H::OpenRead {
+        self.handler.open_read(request, handle)
+    }
+
+    fn open_write(&self, request: &Request, handle: &Handle) -> H::OpenWrite {
+        self.handler.open_write(request, handle)
+    }
+
+    fn priority(&self, _: &Request) -> Priority {
+        self.priority
+    }
+
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static> {
+        self.handler.acknowledge_options(request)
+    }
+
+    fn follow_up(&self, request: &Request) -> Option<String> {
+        self.handler.follow_up(request)
+    }
+
+    fn validate(&self, read_only: bool) -> Vec<ConfigError> {
+        self.handler.validate(read_only)
+    }
+}
+
+/// Announces the file read after another one, e.g. the initrd after the
+/// kernel, see `Handler::follow_up`. Other requests are passed to the
+/// wrapped handler.
+#[derive(Debug, Clone)]
+pub struct FollowUps<H> {
+    handler: H,
+    sequence: Vec<(String, String)>,
+}
+
+impl<H: Handler> FollowUps<H> {
+    /// Wraps `handler` without follow-ups.
+    pub fn new(handler: H) -> FollowUps<H> {
+        FollowUps {
+            handler: handler,
+            sequence: Vec::new(),
+        }
+    }
+
+    /// Announces `next` as the follow-up of `filename`, names are compared
+    /// without leading slashes.
+    pub fn then<S: Into<String>, T: Into<String>>(mut self, filename: S, next: T) -> FollowUps<H> {
+        let filename = filename.into().trim_left_matches('/').to_owned();
+        self.sequence.push((filename, next.into()));
+        self
+    }
+}
+
+impl<H: Handler> Handler for FollowUps<H> {
+    type Reader = H::Reader;
+    type Writer = H::Writer;
+    type OpenRead = H::OpenRead;
+    type OpenWrite = H::OpenWrite;
+
+    fn open_read(&self, request: &Request, handle: &Handle) -> H::OpenRead {
+        self.handler.open_read(request, handle)
+    }
+
+    fn open_write(&self, request: &Request, handle: &Handle) -> H::OpenWrite {
+        self.handler.open_write(request, handle)
+    }
+
+    fn priority(&self, request: &Request) -> Priority {
+        self.handler.priority(request)
+    }
+
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static> {
+        self.handler.acknowledge_options(request)
+    }
+
+    fn follow_up(&self, request: &Request) -> Option<String> {
+        let filename = request.filename().trim_left_matches('/');
+        match self.sequence.iter().find(|&&(ref name, _)| name == filename) {
+            Some(&(_, ref next)) => Some(next.clone()),
+            None => self.handler.follow_up(request),
+        }
+    }
+
+    fn validate(&self, read_only: bool) -> Vec<ConfigError> {
+        self.handler.validate(read_only)
+    }
+}
+
+/// Future opening a file through a route.
+pub type OpenRoute<T> = Box<Future<Item = T, Error = io::Error>>;
+
+/// Handler of a route with the file types erased.
+trait RouteHandler {
+    fn open_read(&self, request: &Request, handle: &Handle) -> OpenRoute<Box<AsyncRead>>;
+
+    fn open_write(&self, request: &Request, handle: &Handle) -> OpenRoute<Box<AsyncWrite>>;
+
+    fn priority(&self, request: &Request) -> Priority;
+
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static>;
+
+    fn follow_up(&self, request: &Request) -> Option<String>;
+
+    fn validate(&self, read_only: bool) -> Vec<ConfigError>;
+}
+
+impl<H: Handler> RouteHandler for H {
+    fn open_read(&self, request: &Request, handle: &Handle) -> OpenRoute<Box<AsyncRead>> {
+        Box::new(Handler::open_read(self, request, handle).map(|reader| Box::new(reader) as Box<AsyncRead>))
+    }
+
+    fn open_write(&self, request: &Request, handle: &Handle) -> OpenRoute<Box<AsyncWrite>> {
+        Box::new(Handler::open_write(self, request, handle).map(|writer| Box::new(writer) as Box<AsyncWrite>))
+    }
+
+    fn priority(&self, request: &Request) -> Priority {
+        Handler::priority(self, request)
+    }
+
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static> {
+        Handler::acknowledge_options(self, request)
+    }
+
+    fn follow_up(&self, request: &Request) -> Option<String> {
+        Handler::follow_up(self, request)
+    }
+
+    fn validate(&self, read_only: bool) -> Vec<ConfigError> {
+        Handler::validate(self, read_only)
+    }
+}
+
+/// Dispatches requests to handlers by file name prefix.
+///
+/// Routes are tried in the order they were added, the first one whose prefix
+/// the requested file name starts with opens the file. The handler sees the
+/// file name with the prefix removed. Prefixes are compared as strings, leading
+/// slashes are ignored, so `images/` matches `/images/boot.img` but not
+/// `images.txt`. An empty prefix matches every file name. Requests no route
+/// matches fail with `NotFound`.
+pub struct Router {
+    routes: Vec<(String, Box<RouteHandler>)>,
+}
+
+impl Router {
+    /// Creates a router without routes.
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+        }
+    }
+
+    /// Adds a route serving file names starting with `prefix` from `handler`.
+    pub fn route<P: Into<String>, H: Handler>(mut self, prefix: P, handler: H) -> Router {
+        let prefix = prefix.into();
+        let prefix = prefix.trim_left_matches('/').to_owned();
+        self.routes.push((prefix, Box::new(handler)));
+        self
+    }
+
+    /// Returns the prefixes of the routes in the order they are tried.
+    pub fn prefixes(&self) -> Vec<&str> {
+        self.routes.iter().map(|&(ref prefix, _)| &prefix[..]).collect()
+    }
+
+    fn find<'a>(&self, request: &Request<'a>) -> io::Result<(&RouteHandler, Request<'a>)> {
+        let filename = request.filename().trim_left_matches('/');
+        for &(ref prefix, ref handler) in &self.routes {
+            if filename.starts_with(&prefix[..]) {
+                let routed = Request { filename: &filename[prefix.len()..], ..*request };
+                return Ok((&**handler, routed))
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, "no route for file name"))
+    }
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Router").field("prefixes", &self.prefixes()).finish()
+    }
+}
+
+impl Handler for Router {
+    type Reader = Box<AsyncRead>;
+    type Writer = Box<AsyncWrite>;
+    type OpenRead = Either<OpenRoute<Box<AsyncRead>>, FutureResult<Box<AsyncRead>, io::Error>>;
+    type OpenWrite = Either<OpenRoute<Box<AsyncWrite>>, FutureResult<Box<AsyncWrite>, io::Error>>;
+
+    fn open_read(&self, request: &Request, handle: &Handle) -> Self::OpenRead {
+        match self.find(request) {
+            Ok((handler, routed)) => Either::A(handler.open_read(&routed, handle)),
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    fn open_write(&self, request: &Request, handle: &Handle) -> Self::OpenWrite {
+        match self.find(request) {
+            Ok((handler, routed)) => Either::A(handler.open_write(&routed, handle)),
+            Err(e) => Either::B(future::err(e)),
+        }
+    }
+
+    fn priority(&self, request: &Request) -> Priority {
+        self.find(request).map(|(handler, routed)| handler.priority(&routed)).unwrap_or_default()
+    }
+
+    fn acknowledge_options(&self, request: &Request) -> TransferOptions<'static> {
+        self.find(request).map(|(handler, routed)| handler.acknowledge_options(&routed)).unwrap_or_default()
+    }
+
+    /// The follow-up of the routed handler is read through the same route.
+    fn follow_up(&self, request: &Request) -> Option<String> {
+        let filename = request.filename().trim_left_matches('/');
+        self.find(request).ok().and_then(|(handler, routed)| {
+            let prefix = &filename[..filename.len() - routed.filename().len()];
+            handler.follow_up(&routed).map(|next| format!("{}{}", prefix, next))
+        })
+    }
+
+    /// Routes whose file names all match an earlier route are reported too.
+    fn validate(&self, read_only: bool) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        for (i, &(ref prefix, ref handler)) in self.routes.iter().enumerate() {
+            let route = format!("route {:?}", prefix);
+            errors.extend(handler.validate(read_only).into_iter().map(|err| err.within(&route)));
+            if let Some(&(ref earlier, _)) = self.routes[..i].iter().find(|&&(ref earlier, _)| {
+                prefix.starts_with(&earlier[..])
+            }) {
+                errors.push(ConfigError::ShadowedRoute(prefix.clone(), earlier.clone()));
+            }
+        }
+        errors
+    }
+}
+
+/// File read by a client.
+///
+/// Without an I/O pool files are read with blocking I/O, which is fast enough
+/// for files that are in the page cache.
+#[derive(Debug)]
+pub struct FsReader {
+    contents: FsContents,
+}
+
+/// Contents of a file read by a client.
+#[derive(Debug)]
+enum FsContents {
+    File(FsFile<File>),
+    Shared(FsFile<SharedReader>),
+    /// Contents returned by the transform of the handler.
+    Rendered(io::Cursor<Vec<u8>>),
+    /// Contents of a compressed file, decompressed as they're read.
+    #[cfg(feature = "compression")]
+    Decompressed(Box<Decoder<FsReader>>),
+}
+
+impl Read for FsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.contents {
+            FsContents::File(FsFile::Local(ref mut file)) => file.read(buf),
+            FsContents::File(FsFile::Pooled(ref mut file)) => file.read(buf),
+            FsContents::Shared(FsFile::Local(ref mut file)) => file.read(buf),
+            FsContents::Shared(FsFile::Pooled(ref mut file)) => file.read(buf),
+            FsContents::Rendered(ref mut rendered) => rendered.read(buf),
+            #[cfg(feature = "compression")]
+            FsContents::Decompressed(ref mut decoder) => decoder.read(buf),
+        }
+    }
+}
+
+impl AsyncRead for FsReader {}
+
+/// File written by a client, removed unless the transfer completes.
+#[derive(Debug)]
+pub struct FsWriter {
+    file: FsFile<io::BufWriter<File>>,
+    /// Moves the upload into place, taken once the upload is flushed.
+    finish: Option<Finish>,
+    finishing: Option<FsOpen<()>>,
+    pool: Option<IoPool>,
+    partial: PathBuf,
+    complete: bool,
+}
+
+impl Write for FsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.file {
+            FsFile::Local(ref mut file) => file.write(buf),
+            FsFile::Pooled(ref mut file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.file {
+            FsFile::Local(ref mut file) => file.flush(),
+            FsFile::Pooled(ref mut file) => file.flush(),
+        }
+    }
+}
+
+impl AsyncWrite for FsWriter {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if self.finishing.is_none() {
+            try_nb!(self.flush());
+            let finish = match self.finish.take() {
+                Some(finish) => finish,
+                None => return Ok(Async::Ready(())),
+            };
+            self.finishing = Some(match self.pool {
+                Some(ref pool) => Either::B(pool.spawn(move || finish.run())),
+                None => Either::A(future::result(finish.run())),
+            });
+        }
+        if let Some(ref mut finishing) = self.finishing {
+            try_ready!(finishing.poll());
+        }
+        self.complete = true;
+        Ok(Async::Ready(()))
+    }
+}
+
+impl Drop for FsWriter {
+    fn drop(&mut self) {
+        if !self.complete {
+            // Don't leave a partially written or rejected file behind.
+            let _ = fs::remove_file(&self.partial);
+        }
+    }
+}
+
+/// Opens the file at `path`, a path inside `root`, for reading if `policy`
+/// allows the symbolic links in it.
+///
+/// Once the links are checked the checked path is opened: the path itself
+/// if links are denied, the path the links resolved to if they are followed
+/// within the root. On Linux it's opened from the root without following
+/// links, a link swapped in after the check fails the open.
+fn open_checked(root: &Path, path: &Path, policy: SymlinkPolicy) -> io::Result<File> {
+    try!(check_symlinks(root, path, policy));
+    match policy {
+        SymlinkPolicy::All => File::open(path),
+        SymlinkPolicy::Deny => open_beneath(root, path),
+        SymlinkPolicy::WithinRoot => {
+            let root = try!(fs::canonicalize(root));
+            let resolved = try!(fs::canonicalize(path));
+            if !resolved.starts_with(&root) {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "symbolic link leads out of the root"))
+            }
+            open_beneath(&root, &resolved)
+        }
+    }
+}
+
+/// Opens `path`, a path inside `root` without symbolic links.
+#[cfg(target_os = "linux")]
+fn open_beneath(root: &Path, path: &Path) -> io::Result<File> {
+    match path.strip_prefix(root) {
+        Ok(relative) => sys::open_beneath(root, relative),
+        Err(_) => Err(io::Error::new(io::ErrorKind::PermissionDenied, "path leads out of the root")),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_beneath(_: &Path, path: &Path) -> io::Result<File> {
+    File::open(path)
+}
+
+/// Checks the symbolic links in `path`, a path inside `root`, are allowed by
+/// `policy`.
+///
+/// Parts of the path that don't exist yet, like the file of an upload, are
+/// not checked.
+fn check_symlinks(root: &Path, path: &Path, policy: SymlinkPolicy) -> io::Result<()> {
+    match policy {
+        SymlinkPolicy::All => Ok(()),
+        SymlinkPolicy::Deny => {
+            let mut current = root.to_path_buf();
+            for component in path.strip_prefix(root).unwrap_or(path).components() {
+                current.push(component);
+                match fs::symlink_metadata(&current) {
+                    Ok(ref metadata) if metadata.file_type().is_symlink() => {
+                        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "symbolic links are not allowed"))
+                    }
+                    Ok(_) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        }
+        SymlinkPolicy::WithinRoot => {
+            let root = try!(fs::canonicalize(root));
+            let mut existing = path;
+            let resolved = loop {
+                match fs::canonicalize(existing) {
+                    Ok(resolved) => break resolved,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        // A link to a missing file may lead anywhere once the file is created.
+                        if fs::symlink_metadata(existing).is_ok() {
+                            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "dangling symbolic link"))
+                        }
+                        match existing.parent() {
+                            Some(parent) => existing = parent,
+                            None => return Ok(()),
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+            };
+            if resolved.starts_with(&root) {
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "symbolic link leads out of the root"))
+            }
+        }
+    }
+}
+
+/// Resolves a requested file name to a path inside `root`.
+///
+/// Leading slashes are ignored, names that would escape the root are rejected.
+fn resolve_path(root: &Path, filename: &str) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    let mut empty = true;
+    for component in Path::new(filename.trim_left_matches('/')).components() {
+        match component {
+            Component::Normal(name) => {
+                path.push(name);
+                empty = false;
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    if empty { None } else { Some(path) }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use packet::Mode;
+    use super::{resolve_path, FollowUps, FsHandler, Handler, Prioritized, Priority, Request, Router};
+
+    #[test]
+    fn paths_are_resolved_inside_root() {
+        let root = Path::new("/srv/tftp");
+        assert_eq!(Some(PathBuf::from("/srv/tftp/boot/pxelinux.0")), resolve_path(root, "/boot/pxelinux.0"));
+        assert_eq!(Some(PathBuf::from("/srv/tftp/a")), resolve_path(root, "./a"));
+        assert_eq!(None, resolve_path(root, "../etc/passwd"));
+        assert_eq!(None, resolve_path(root, "a/../../b"));
+        assert_eq!(None, resolve_path(root, "/"));
+    }
+
+    #[test]
+    fn first_matching_route_is_used() {
+        let router = Router::new()
+            .route("/images/boot", FsHandler::new("/srv/boot"))
+            .route("images/", FsHandler::new("/srv/images"))
+            .route("", FsHandler::new("/srv/tftp"));
+        assert_eq!(vec!["images/boot", "images/", ""], router.prefixes());
+
+        let routed = |filename| {
+            let request = Request::new(filename, Mode::Octet, None);
+            router.find(&request).map(|(_, routed)| routed.filename())
+        };
+        assert_eq!("/pxelinux.0", routed("/images/boot/pxelinux.0").unwrap());
+        assert_eq!("disk.img", routed("images/disk.img").unwrap());
+        assert_eq!("images.txt", routed("images.txt").unwrap());
+    }
+
+    #[test]
+    fn follow_ups_are_read_through_routes() {
+        let router = Router::new()
+            .route("boot/", FollowUps::new(FsHandler::new("/srv/boot")).then("/vmlinuz", "initrd.img"))
+            .route("", FsHandler::new("/srv/tftp"));
+        let follow_up = |filename| router.follow_up(&Request::new(filename, Mode::Octet, None));
+        assert_eq!(Some("boot/initrd.img".to_owned()), follow_up("/boot/vmlinuz"));
+        assert_eq!(None, follow_up("boot/initrd.img"));
+        assert_eq!(None, follow_up("vmlinuz"));
+    }
+
+    #[test]
+    fn routes_assign_priorities() {
+        let router = Router::new()
+            .route("boot/", Prioritized::new(FsHandler::new("/srv/boot"), Priority::Critical))
+            .route("images/", Prioritized::new(FsHandler::new("/srv/images"), Priority::Bulk))
+            .route("configs/", FsHandler::new("/srv/configs"));
+        let priority = |filename| router.priority(&Request::new(filename, Mode::Octet, None));
+        assert_eq!(Priority::Critical, priority("boot/pxelinux.0"));
+        assert_eq!(Priority::Bulk, priority("images/disk.img"));
+        assert_eq!(Priority::Normal, priority("configs/a.cfg"));
+        assert_eq!(Priority::Normal, priority("unrouted"));
+    }
+
+    #[test]
+    fn transform_rewrites_read_files() {
+        use std::env;
+        use std::fs;
+        use std::io::Read;
+        use std::process;
+
+        use futures::Future;
+        use tokio_core::reactor::Core;
+
+        let root = env::temp_dir().join(format!("tftp-transform-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("boot.ipxe"), "chain http://boot/${client}\n").unwrap();
+        let handler = FsHandler::new(&root).transform(|request, contents| {
+            let client = request.client_addr().map(|addr| addr.ip().to_string()).unwrap_or_default();
+            let script = String::from_utf8_lossy(&contents).replace("${client}", &client);
+            Ok(script.into_bytes())
+        });
+
+        let core = Core::new().unwrap();
+        let request = Request::new("boot.ipxe", Mode::Octet, Some("192.0.2.7:2000".parse().unwrap()));
+        let mut reader = handler.open_read(&request, &core.handle()).wait().unwrap();
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!("chain http://boot/192.0.2.7\n", contents);
+
+        let missing = Request::new("missing", Mode::Octet, None);
+        assert_eq!(io::ErrorKind::NotFound, handler.open_read(&missing, &core.handle()).wait().err().unwrap().kind());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn concurrent_downloads_share_open_file() {
+        use std::env;
+        use std::fs;
+        use std::io::Read;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use futures::Future;
+        use tokio_core::reactor::Core;
+
+        use super::{SharedFiles, SharedFileStats};
+
+        let root = env::temp_dir().join(format!("tftp-shared-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("kernel"), b"0123456789").unwrap();
+        let files = SharedFiles::new();
+        let handler = FsHandler::new(&root).share_files(files.clone());
+
+        let core = Core::new().unwrap();
+        let request = Request::new("kernel", Mode::Octet, None);
+        let mut first = handler.open_read(&request, &core.handle()).wait().unwrap();
+        let mut second = handler.open_read(&request, &core.handle()).wait().unwrap();
+        let mut buf = [0; 4];
+        first.read_exact(&mut buf).unwrap();
+        assert_eq!(b"0123", &buf);
+        // Readers keep their own positions.
+        let mut contents = Vec::new();
+        second.read_to_end(&mut contents).unwrap();
+        assert_eq!(b"0123456789", &contents[..]);
+        assert_eq!(SharedFileStats { opened: 1, shared: 1, open: 1 }, files.stats());
+
+        // A changed file is opened again.
+        thread::sleep(Duration::from_millis(10));
+        fs::write(root.join("kernel"), b"new kernel").unwrap();
+        let mut third = handler.open_read(&request, &core.handle()).wait().unwrap();
+        contents.clear();
+        third.read_to_end(&mut contents).unwrap();
+        assert_eq!(b"new kernel", &contents[..]);
+        drop((first, second, third));
+        assert_eq!(SharedFileStats { opened: 2, shared: 1, open: 0 }, files.stats());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn checksum_sidecars_are_generated() {
+        use std::env;
+        use std::fs;
+        use std::io::Read;
+        use std::process;
+
+        use futures::Future;
+        use tokio_core::reactor::Core;
+
+        let root = env::temp_dir().join(format!("tftp-sidecars-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("boot.img"), b"abc").unwrap();
+        fs::write(root.join("initrd"), b"initrd").unwrap();
+        fs::write(root.join("initrd.sha256"), b"stored\n").unwrap();
+        let handler = FsHandler::new(&root).checksum_sidecars(true);
+
+        let core = Core::new().unwrap();
+        let read = |filename| {
+            let request = Request::new(filename, Mode::Octet, None);
+            handler.open_read(&request, &core.handle()).wait().map(|mut reader| {
+                let mut contents = String::new();
+                reader.read_to_string(&mut contents).unwrap();
+                contents
+            })
+        };
+        let sidecar = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  boot.img\n";
+        assert_eq!(sidecar, read("boot.img.sha256").unwrap());
+        // Cached digest.
+        assert_eq!(sidecar, read("boot.img.sha256").unwrap());
+        // Sidecars that exist are served as they are.
+        assert_eq!("stored\n", read("initrd.sha256").unwrap());
+        assert_eq!(io::ErrorKind::NotFound, read("missing.sha256").unwrap_err().kind());
+
+        // Transformed contents depend on the request, they have no sidecars.
+        let transformed = FsHandler::new(&root).checksum_sidecars(true).transform(|_, contents| Ok(contents));
+        let request = Request::new("boot.img.sha256", Mode::Octet, None);
+        assert_eq!(io::ErrorKind::NotFound, transformed.open_read(&request, &core.handle()).wait().unwrap_err().kind());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn fallback_is_served_for_missing_files() {
+        use std::env;
+        use std::fs;
+        use std::io::Read;
+        use std::process;
+
+        use futures::Future;
+        use tokio_core::reactor::Core;
+
+        let root = env::temp_dir().join(format!("tftp-fallback-{}", process::id()));
+        fs::create_dir_all(root.join("boot")).unwrap();
+        fs::write(root.join("boot/kernel"), b"kernel").unwrap();
+        fs::write(root.join("recovery.img"), b"recovery").unwrap();
+        let handler = FsHandler::new(&root)
+            .fallback("boot/*.cfg", "missing.img")
+            .fallback("boot/*", "recovery.img");
+
+        let core = Core::new().unwrap();
+        let read = |filename| {
+            let request = Request::new(filename, Mode::Octet, None);
+            handler.open_read(&request, &core.handle()).wait().map(|mut reader| {
+                let mut contents = String::new();
+                reader.read_to_string(&mut contents).unwrap();
+                contents
+            })
+        };
+        assert_eq!("kernel", read("boot/kernel").unwrap());
+        assert_eq!("recovery", read("boot/x86/initrd").unwrap());
+        // The first matching fallback is used, even if it's missing as well.
+        assert_eq!(io::ErrorKind::NotFound, read("boot/host.cfg").unwrap_err().kind());
+        assert_eq!(io::ErrorKind::NotFound, read("pxelinux.0").unwrap_err().kind());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_files_are_served_decompressed() {
+        use std::env;
+        use std::fs;
+        use std::io::{Read, Write};
+        use std::process;
+
+        use flate2::Compression as Level;
+        use flate2::write::GzEncoder;
+        use futures::Future;
+        use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+        use tokio_core::reactor::Core;
+
+        use packet::TransferOptions;
+        use sha256::{self, Sha256};
+
+        let root = env::temp_dir().join(format!("tftp-compressed-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let image: Vec<u8> = (0..5000u32).map(|i| (i % 7) as u8).collect();
+        let mut gzip = GzEncoder::new(Vec::new(), Level::default());
+        gzip.write_all(&image).unwrap();
+        fs::write(root.join("kernel.gz"), gzip.finish().unwrap()).unwrap();
+        fs::write(root.join("initrd.zst"), compress_to_vec(&image[..], CompressionLevel::Fastest)).unwrap();
+        fs::write(root.join("plain"), b"plain").unwrap();
+        fs::write(root.join("plain.gz"), b"not gzip").unwrap();
+        let handler = FsHandler::new(&root).compressed(true);
+
+        let core = Core::new().unwrap();
+        let read = |filename| {
+            let request = Request::new(filename, Mode::Octet, None);
+            handler.open_read(&request, &core.handle()).wait().map(|mut reader| {
+                let mut contents = Vec::new();
+                reader.read_to_end(&mut contents).unwrap();
+                contents
+            })
+        };
+        assert_eq!(image, read("kernel").unwrap());
+        assert_eq!(image, read("initrd").unwrap());
+        assert_eq!(b"plain".to_vec(), read("plain").unwrap());
+        assert_eq!(io::ErrorKind::NotFound, read("missing").unwrap_err().kind());
+        assert_eq!(io::ErrorKind::NotFound, FsHandler::new(&root).open_read(&Request::new("kernel", Mode::Octet, None),
+                                                                             &core.handle()).wait().unwrap_err().kind());
+
+        let mut options = TransferOptions::new();
+        options.insert("tsize", "0");
+        let request = Request::new("kernel", Mode::Octet, None).with_options(&options);
+        assert_eq!(Some("5000"), handler.acknowledge_options(&request).get("tsize"));
+        for &filename in &["initrd", "plain"] {
+            let request = Request::new(filename, Mode::Octet, None).with_options(&options);
+            assert!(handler.acknowledge_options(&request).is_empty());
+        }
+
+        // Sidecars digest the contents as they are served.
+        let handler = FsHandler::new(&root).compressed(true).checksum_sidecars(true);
+        let sidecar = |filename| {
+            let request = Request::new(filename, Mode::Octet, None);
+            handler.open_read(&request, &core.handle()).wait().map(|mut reader| {
+                let mut contents = String::new();
+                reader.read_to_string(&mut contents).unwrap();
+                contents
+            })
+        };
+        let digest = |contents: &[u8]| {
+            let mut sha = Sha256::new();
+            sha.update(contents);
+            sha256::to_hex(&sha.finish())
+        };
+        assert_eq!(format!("{}  kernel\n", digest(&image)), sidecar("kernel.sha256").unwrap());
+        let gzip = fs::read(root.join("kernel.gz")).unwrap();
+        assert_eq!(format!("{}  kernel.gz\n", digest(&gzip)), sidecar("kernel.gz.sha256").unwrap());
+        assert_eq!(format!("{}  kernel\n", digest(&image)), sidecar("kernel.sha256").unwrap());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn glob_patterns_match_names() {
+        use super::glob_matches;
+
+        let matches = |pattern: &str, name: &str| {
+            glob_matches(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+        };
+        assert!(matches("boot/*", "boot/a/b"));
+        assert!(matches("*.cfg", "pxelinux.cfg/01-aa.cfg"));
+        assert!(matches("ipxe-??.efi", "ipxe-64.efi"));
+        assert!(matches("*", ""));
+        assert!(!matches("boot/*", "images/boot/a"));
+        assert!(!matches("ipxe-??.efi", "ipxe-6.efi"));
+    }
+
+    #[test]
+    fn directories_are_served_by_policy() {
+        use std::env;
+        use std::fs;
+        use std::io::Read;
+        use std::process;
+
+        use futures::Future;
+        use tokio_core::reactor::Core;
+
+        use super::DirectoryPolicy;
+
+        let root = env::temp_dir().join(format!("tftp-directories-{}", process::id()));
+        fs::create_dir_all(root.join("pxelinux.cfg/old")).unwrap();
+        fs::write(root.join("pxelinux.cfg/default"), "menu\n").unwrap();
+        fs::write(root.join("pxelinux.cfg/01-52-54-00-12-34-56"), "host\n").unwrap();
+        let core = Core::new().unwrap();
+        let read = |handler: FsHandler| {
+            let request = Request::new("pxelinux.cfg", Mode::Octet, None);
+            handler.open_read(&request, &core.handle()).wait().map(|mut reader| {
+                let mut contents = String::new();
+                reader.read_to_string(&mut contents).unwrap();
+                contents
+            })
+        };
+
+        assert_eq!(io::ErrorKind::NotFound, read(FsHandler::new(&root)).unwrap_err().kind());
+        let index = FsHandler::new(&root).directories(DirectoryPolicy::Index("default".to_owned()));
+        assert_eq!("menu\n", read(index).unwrap());
+        let listing = FsHandler::new(&root).directories(DirectoryPolicy::Listing);
+        assert_eq!("01-52-54-00-12-34-56\ndefault\nold/\n", read(listing.clone()).unwrap());
+        let menu = listing.transform(|_, contents| {
+            let entries = contents.iter().filter(|&&b| b == b'\n').count();
+            Ok(format!("{} entries", entries).into_bytes())
+        });
+        assert_eq!("3 entries", read(menu).unwrap());
+        assert_eq!(Ok(DirectoryPolicy::Index("default".to_owned())), "index:default".parse().map_err(|_| ()));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_are_followed_by_policy() {
+        use std::env;
+        use std::fs;
+        use std::os::unix::fs::symlink;
+        use std::process;
+
+        use futures::Future;
+        use tokio_core::reactor::Core;
+
+        use super::SymlinkPolicy;
+
+        let dir = env::temp_dir().join(format!("tftp-symlinks-{}", process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("images")).unwrap();
+        fs::write(root.join("images/disk.img"), "disk").unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        symlink("images/disk.img", root.join("current.img")).unwrap();
+        symlink("../secret", root.join("escape")).unwrap();
+        symlink("..", root.join("up")).unwrap();
+        symlink("missing", root.join("dangling")).unwrap();
+        let core = Core::new().unwrap();
+        let opened = |policy, filename| {
+            let handler = FsHandler::new(&root).symlinks(policy);
+            let request = Request::new(filename, Mode::Octet, None);
+            handler.open_read(&request, &core.handle()).wait().map(|_| ()).map_err(|e| e.kind())
+        };
+        let denied = Err(io::ErrorKind::PermissionDenied);
+
+        assert_eq!(Ok(()), opened(SymlinkPolicy::WithinRoot, "current.img"));
+        assert_eq!(denied, opened(SymlinkPolicy::WithinRoot, "escape"));
+        assert_eq!(denied, opened(SymlinkPolicy::WithinRoot, "up/secret"));
+        assert_eq!(denied, opened(SymlinkPolicy::WithinRoot, "dangling"));
+        assert_eq!(denied, opened(SymlinkPolicy::Deny, "current.img"));
+        assert_eq!(Ok(()), opened(SymlinkPolicy::Deny, "images/disk.img"));
+        assert_eq!(Ok(()), opened(SymlinkPolicy::All, "up/secret"));
+
+        // A link swapped in after the check fails the open.
+        #[cfg(target_os = "linux")]
+        {
+            use super::open_beneath;
+
+            assert_eq!(denied, open_beneath(&root, &root.join("current.img")).map(|_| ()).map_err(|e| e.kind()));
+            assert_eq!(denied, open_beneath(&root, &root.join("up/secret")).map(|_| ()).map_err(|e| e.kind()));
+            assert!(open_beneath(&root, &root.join("images/disk.img")).is_ok());
+        }
+
+        let handler = FsHandler::new(&root);
+        let upload = Request::new("up/planted", Mode::Octet, None);
+        assert_eq!(io::ErrorKind::PermissionDenied,
+                   handler.open_write(&upload, &core.handle()).wait().err().unwrap().kind());
+        assert!(!dir.join("planted").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejected_uploads_are_removed() {
+        use std::env;
+        use std::fs;
+        use std::io::Write;
+        use std::process;
+
+        use futures::Future;
+        use futures::future;
+        use tokio_core::reactor::Core;
+        use tokio_io::AsyncWrite;
+
+        let root = env::temp_dir().join(format!("tftp-check-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let handler = FsHandler::new(&root).check_upload(|_, path| {
+            if try!(fs::read(path)).starts_with(b"MZ") {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "executables are not accepted"))
+            } else {
+                Ok(())
+            }
+        });
+        let core = Core::new().unwrap();
+        let upload = |filename, contents: &[u8]| {
+            let request = Request::new(filename, Mode::Octet, None);
+            let mut writer = handler.open_write(&request, &core.handle()).wait().unwrap();
+            writer.write_all(contents).unwrap();
+            let shutdown = future::poll_fn(|| writer.shutdown()).wait();
+            drop(writer);
+            shutdown
+        };
+
+        upload("switch.cfg", b"hostname switch").unwrap();
+        assert_eq!(b"hostname switch".to_vec(), fs::read(root.join("switch.cfg")).unwrap());
+        let err = upload("virus.cfg", b"MZ\x90").unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+        assert!(!root.join("virus.cfg").exists());
+        // Nothing but the accepted upload is left, no partial files.
+        assert_eq!(1, fs::read_dir(&root).unwrap().count());
+
+        let request = Request::new("switch.cfg", Mode::Octet, None);
+        assert_eq!(io::ErrorKind::AlreadyExists,
+                   handler.open_write(&request, &core.handle()).wait().err().unwrap().kind());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unrouted_requests_are_not_found() {
+        let router = Router::new().route("images/", FsHandler::new("/srv/images"));
+        let request = Request::new("configs/a.cfg", Mode::Octet, None);
+        assert_eq!(io::ErrorKind::NotFound, router.find(&request).err().unwrap().kind());
+    }
+}
@@ -0,0 +1,187 @@
+//! Bounded journal of the events of a transfer, for post-mortem debugging.
+//!
+//! A `Journal` keeps the last events of a transfer with the time since it
+//! started: datagrams sent and received, retransmission timers armed and
+//! fired and the states the transfer went through. Older events are dropped,
+//! so the memory used is bounded however long the transfer runs.
+//!
+//! A client configured with `ClientBuilder::journal` returns the journal of a
+//! failed transfer with the error in `Error::Journaled`. It's displayed as a
+//! log blob of one event per line, e.g. to attach to a bug report of a
+//! failure that happens only sporadically in the field.
+
+use std::collections::vec_deque::{self, VecDeque};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use packet::Opcode;
+use replay::Direction;
+
+/// Event of a transfer.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Event {
+    /// A datagram was sent or received, data and acknowledgment packets
+    /// have a block number.
+    Datagram {
+        direction: Direction,
+        opcode: Option<Opcode>,
+        block: Option<u16>,
+        len: usize,
+    },
+
+    /// The retransmission timer was armed to fire after the duration.
+    TimerArmed(Duration),
+
+    /// The retransmission timer fired.
+    TimerFired,
+
+    /// The transfer entered the named state.
+    State(&'static str),
+}
+
+impl Event {
+    /// Returns the event of `datagram` sent or received.
+    pub fn datagram(direction: Direction, datagram: &[u8]) -> Event {
+        let field = |i: usize| datagram.get(i..i + 2).map(|bytes| (bytes[0] as u16) << 8 | bytes[1] as u16);
+        let opcode = field(0).and_then(Opcode::from_u16);
+        let block = match opcode {
+            Some(Opcode::DATA) | Some(Opcode::ACK) => field(2),
+            _ => None,
+        };
+        Event::Datagram {
+            direction: direction,
+            opcode: opcode,
+            block: block,
+            len: datagram.len(),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Datagram { direction, opcode, block, len } => {
+                let direction = match direction {
+                    Direction::Sent => "sent",
+                    Direction::Received => "received",
+                };
+                match opcode {
+                    Some(opcode) => try!(write!(f, "{} {:?}", direction, opcode)),
+                    None => try!(write!(f, "{} invalid datagram", direction)),
+                }
+                if let Some(block) = block {
+                    try!(write!(f, " block {}", block));
+                }
+                write!(f, " ({} bytes)", len)
+            }
+            Event::TimerArmed(timeout) => write!(f, "timer armed for {}", Seconds(timeout)),
+            Event::TimerFired => f.write_str("timer fired"),
+            Event::State(state) => write!(f, "state {}", state),
+        }
+    }
+}
+
+/// Event recorded in a journal.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Entry {
+    /// Time since the journal was started.
+    pub at: Duration,
+    pub event: Event,
+}
+
+/// Ring of the last events of a transfer.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    started: Instant,
+    capacity: usize,
+    entries: VecDeque<Entry>,
+    dropped: u64,
+}
+
+impl Journal {
+    /// Starts a journal keeping the last `capacity` events.
+    pub fn new(capacity: usize) -> Journal {
+        Journal {
+            started: Instant::now(),
+            capacity: capacity,
+            entries: VecDeque::with_capacity(capacity),
+            dropped: 0,
+        }
+    }
+
+    /// Records `event`, dropping the oldest event if the journal is full.
+    pub fn record(&mut self, event: Event) {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(Entry {
+            at: self.started.elapsed(),
+            event: event,
+        });
+    }
+
+    /// Returns the recorded events, oldest first.
+    pub fn entries(&self) -> vec_deque::Iter<Entry> {
+        self.entries.iter()
+    }
+
+    /// Returns the number of events dropped to make room for newer ones.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+impl fmt::Display for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "journal of the last {} events", self.entries.len()));
+        if self.dropped > 0 {
+            try!(write!(f, ", {} earlier dropped", self.dropped));
+        }
+        for entry in &self.entries {
+            try!(write!(f, "\n{:>12} {}", Seconds(entry.at).to_string(), entry.event));
+        }
+        Ok(())
+    }
+}
+
+/// Duration displayed in seconds with microsecond precision.
+struct Seconds(Duration);
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:06}s", self.0.as_secs(), self.0.subsec_micros())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use packet::{AckPacket, EncodePacket, Opcode};
+    use replay::Direction;
+    use super::{Event, Journal};
+
+    #[test]
+    fn oldest_events_are_dropped() {
+        let mut journal = Journal::new(2);
+        journal.record(Event::State("sending request"));
+        journal.record(Event::TimerArmed(Duration::from_millis(1500)));
+        journal.record(Event::datagram(Direction::Received, AckPacket::new(3).encode().packet_buf()));
+        let events: Vec<_> = journal.entries().map(|entry| entry.event.clone()).collect();
+        let ack = Event::Datagram { direction: Direction::Received, opcode: Some(Opcode::ACK), block: Some(3), len: 4 };
+        assert_eq!(vec![Event::TimerArmed(Duration::from_millis(1500)), ack], events);
+        assert_eq!(1, journal.dropped());
+
+        let dump = journal.to_string();
+        let lines: Vec<_> = dump.lines().collect();
+        assert_eq!("journal of the last 2 events, 1 earlier dropped", lines[0]);
+        assert!(lines[1].ends_with("s timer armed for 1.500000s"));
+        assert!(lines[2].ends_with("s received ACK block 3 (4 bytes)"));
+        assert_eq!("sent invalid datagram (1 bytes)", Event::datagram(Direction::Sent, &[9]).to_string());
+    }
+}
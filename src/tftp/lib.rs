@@ -12,18 +12,119 @@
 //! RFCs implemented:
 //!
 //! - RFC 1350 - TFTP Protocol (revision 2) (http://tools.ietf.org/html/rfc1350)
+//! - RFC 2347 - TFTP Option Extension (http://tools.ietf.org/html/rfc2347)
+//! - RFC 2348 - TFTP Blocksize Option (http://tools.ietf.org/html/rfc2348)
+//!
+//! The packet, netascii and transfer state machine modules don't depend on any
+//! networking runtime, neither do `session` transfers driven by the caller's
+//! own event loop, building with `--no-default-features` leaves only them,
+//! which also compiles for `wasm32` targets (see the `wasi-get` example).
+//! Front-ends are selected with Cargo features:
+//!
+//! - `mio-client` - blocking client driven by a mio event loop (default)
+//! - `tokio-server` - server running on the tokio-core reactor (default), files
+//!   are opened by a `Handler` which may fetch them asynchronously
+//! - `tokio-client` - asynchronous client on the tokio-core reactor, downloads
+//!   are `Stream`s and uploads `Sink`s of `Bytes`, or `AsyncWrite`/`AsyncRead` pipes
+//! - `cli` - `tftp` command line client binary
+//! - `tftpd` - `tftpd` server binary
+//! - `embedded` - client running on an `embedded-nal` UDP stack, on targets
+//!   with the standard library
+//! - `ffi` - C interface to the client, see `include/tftp.h`
+//! - `experimental-dtls` - transfers protected by DTLS, using OpenSSL
+//! - `experimental-resume` - transfers continuing after the address of the
+//!   client changed, see `resume`
+//! - `compression` - decompression of gzip and zstd files, e.g. served by
+//!   `FsHandler::compressed`
+//! - `toml-config`, `json-config` - server configuration files, see
+//!   `server_config::ServerConfig`
+//! - `nightly-bench` - benchmarks, requires a nightly compiler
+//!
+//! Simple scripts read and write files with the one-shot `tftp::get` and
+//! `tftp::put`, `tftp::prelude` imports the types most programs need.
 
 #![crate_name = "tftp"]
-#![cfg_attr(test, feature(test))]
+#![cfg_attr(all(test, feature = "nightly-bench"), feature(test))]
 
-extern crate mio;
-#[macro_use(try_nb)] extern crate tokio_core;
-extern crate futures;
+#[cfg(feature = "mio-client")] extern crate mio;
+#[cfg(any(feature = "tokio-server", feature = "tokio-client"))] #[macro_use(try_nb)] extern crate tokio_core;
+#[cfg(any(feature = "tokio-server", feature = "tokio-client"))] #[macro_use(try_ready)] extern crate futures;
+#[cfg(any(feature = "tokio-server", feature = "tokio-client"))] extern crate tokio_io;
+#[cfg(feature = "tokio-client")] extern crate bytes;
+#[cfg(all(unix, feature = "tokio-server"))] extern crate mio_uds;
+#[cfg(feature = "embedded")] extern crate embedded_nal;
+#[cfg(feature = "embedded")] extern crate nb;
+#[cfg(feature = "experimental-dtls")] extern crate openssl;
+#[cfg(feature = "compression")] extern crate flate2;
+#[cfg(feature = "compression")] extern crate ruzstd;
+#[cfg(feature = "toml-config")] extern crate toml;
+#[cfg(feature = "json-config")] extern crate serde_json;
 #[macro_use(quick_error)] extern crate quick_error;
+#[cfg(feature = "tokio-server")] #[macro_use] extern crate log;
 
 pub mod packet;
 pub mod netascii;
+pub mod filename;
 mod decodedpacket;
+pub mod config;
+pub mod error;
+pub mod transfer;
+pub mod source;
+pub mod session;
+pub mod transport;
+pub mod stats;
+pub mod retry;
+pub mod ratelimit;
+pub mod reason;
+pub mod replay;
+pub mod journal;
+pub mod batch;
+pub mod rng;
+#[cfg(feature = "compression")]
+pub mod decompress;
+#[cfg(target_os = "linux")]
+pub mod vectored;
+#[cfg(target_os = "linux")]
+mod sys;
 
+#[cfg(feature = "mio-client")]
 pub mod client;
+#[cfg(feature = "mio-client")]
+mod oneshot;
+#[cfg(feature = "mio-client")]
+pub mod capabilities;
+#[cfg(feature = "mio-client")]
+pub mod spool;
+#[cfg(feature = "tokio-client")]
+pub mod async_client;
+#[cfg(feature = "tokio-server")]
 pub mod server;
+#[cfg(feature = "tokio-server")]
+pub mod handler;
+#[cfg(feature = "tokio-server")]
+pub mod server_config;
+#[cfg(feature = "tokio-server")]
+pub mod pool;
+#[cfg(feature = "tokio-server")]
+pub mod arena;
+#[cfg(feature = "tokio-server")]
+pub mod snapshot;
+#[cfg(feature = "tokio-server")]
+mod sha256;
+#[cfg(feature = "tokio-server")]
+pub mod wheel;
+#[cfg(feature = "tokio-server")]
+pub mod bandwidth;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "embedded")]
+pub mod nal;
+#[cfg(feature = "experimental-dtls")]
+pub mod dtls;
+#[cfg(feature = "experimental-resume")]
+pub mod resume;
+pub mod prelude;
+
+pub use error::{Error, ErrorKind};
+#[cfg(feature = "mio-client")]
+pub use oneshot::{get, put};
@@ -0,0 +1,404 @@
+//! Client running on an `embedded-nal` UDP stack.
+//!
+//! Firmware talking to the network through a driver that implements
+//! `UdpFullStack` (a Wi-Fi module, smoltcp, ...) can fetch files with the same
+//! transfer state machine the std client uses. The stack is polled until the
+//! transfer completes and time is read from a caller provided `Clock`.
+//!
+//! The module doesn't use any operating system facilities, but the crate
+//! needs the standard library: packet encoding and the transfer state
+//! machines use `std::io`. The client runs on targets that have one, like
+//! ESP-IDF or embedded Linux, bare-metal `no_std` firmware isn't supported.
+
+use std::cmp;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use embedded_nal::UdpFullStack;
+use nb;
+
+use config::{BlockSize, Retries, ReplyPolicy, DEFAULT_TIMEOUT};
+use reason::{Reason, ReasonFormat};
+use packet::{self, Mode, Opcode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket,
+    OptionAckPacket, EncodePacket, DecodePacket, RawPacket};
+use transfer::{ReadTransfer, DataReceived, Timeout, DEFAULT_BLOCK_SIZE, request_options,
+    negotiated_block_size};
+
+/// Monotonic millisecond clock.
+pub trait Clock {
+    /// Returns the current time in milliseconds.
+    fn now_ms(&mut self) -> u64;
+}
+
+impl<F: FnMut() -> u64> Clock for F {
+    fn now_ms(&mut self) -> u64 {
+        self()
+    }
+}
+
+/// Error of a transfer over an `embedded-nal` stack.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The network stack failed.
+    Network(E),
+    /// The server terminated the transfer with an error packet.
+    Server(ErrorPacket<'static>),
+    /// The server violated the protocol.
+    Protocol(&'static str),
+    /// The server stopped responding.
+    TimedOut,
+    /// The sink refused the received data.
+    Write,
+}
+
+impl<E: fmt::Debug> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Network(ref err) => write!(f, "Network error: {:?}", err),
+            Error::Server(ref err) => write!(f, "Server error: {}", err),
+            Error::Protocol(reason) => write!(f, "Protocol error: {}", reason),
+            Error::TimedOut => write!(f, "Transfer timed out"),
+            Error::Write => write!(f, "Received data could not be written"),
+        }
+    }
+}
+
+/// TFTP client using an `embedded-nal` stack.
+pub struct Client<C> {
+    server_addr: SocketAddr,
+    local_port: u16,
+    block_size: BlockSize,
+    timeout: Duration,
+    retries: Retries,
+    reply_policy: ReplyPolicy,
+    clock: C,
+}
+
+impl<C: Clock> Client<C> {
+    /// Creates a client for the server at `server_addr` using `local_port` as its transfer ID.
+    pub fn new(server_addr: SocketAddr, local_port: u16, clock: C) -> Client<C> {
+        Client {
+            server_addr: server_addr,
+            local_port: local_port,
+            block_size: BlockSize::default(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: Retries::default(),
+            reply_policy: ReplyPolicy::default(),
+            clock: clock,
+        }
+    }
+
+    /// Sets the block size requested from the server.
+    ///
+    /// Every received block is kept in memory, small targets may want to stay at
+    /// the default of 512 bytes.
+    pub fn block_size(mut self, block_size: BlockSize) -> Client<C> {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the time to wait for a response before retransmitting.
+    pub fn timeout(mut self, timeout: Duration) -> Client<C> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of retransmissions before a transfer fails.
+    pub fn retries(mut self, retries: Retries) -> Client<C> {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the addresses the first reply of the server is accepted from.
+    ///
+    /// By default it must come from the address the request was sent to.
+    pub fn reply_policy(mut self, policy: ReplyPolicy) -> Client<C> {
+        self.reply_policy = policy;
+        self
+    }
+
+    /// Reads the file `path` from the server, passing the received data to `write` in order.
+    pub fn get<S, W>(&mut self, stack: &mut S, path: &str, mode: Mode, write: W) -> Result<(), Error<S::Error>>
+        where S: UdpFullStack, W: FnMut(&[u8]) -> Result<(), ()>
+    {
+        let mut socket = try!(stack.socket().map_err(Error::Network));
+        let result = stack.bind(&mut socket, self.local_port).map_err(Error::Network)
+            .and_then(|_| self.run_get(stack, &mut socket, path, mode, write));
+        let _ = stack.close(socket);
+        result
+    }
+
+    fn run_get<S, W>(&mut self, stack: &mut S, socket: &mut S::UdpSocket, path: &str, mode: Mode,
+                     mut write: W) -> Result<(), Error<S::Error>>
+        where S: UdpFullStack, W: FnMut(&[u8]) -> Result<(), ()>
+    {
+        let timeout_ms = cmp::max(1, self.timeout.as_secs() * 1000 + self.timeout.subsec_millis() as u64);
+        let request = RequestPacket::read_request(path, mode)
+            .with_options(request_options(self.block_size.get()));
+        let mut last_sent = request.encode();
+        let mut transfer = ReadTransfer::new(DEFAULT_BLOCK_SIZE);
+        transfer.set_retries(self.retries);
+        // Transfer ID of the server, known after the first response.
+        let mut peer = None;
+        let mut buf = vec![0; cmp::max(self.block_size.get(), DEFAULT_BLOCK_SIZE) + 4];
+
+        try!(send_to(stack, socket, self.server_addr, &last_sent));
+        let mut deadline = self.clock.now_ms() + timeout_ms;
+        loop {
+            let (n, from) = match stack.receive(socket, &mut buf) {
+                Ok(received) => received,
+                Err(nb::Error::WouldBlock) => {
+                    if self.clock.now_ms() < deadline {
+                        continue
+                    }
+                    match transfer.timeout() {
+                        Timeout::Retransmit => {
+                            try!(send_to(stack, socket, peer.unwrap_or(self.server_addr), &last_sent));
+                            deadline = self.clock.now_ms() + timeout_ms;
+                            continue
+                        }
+                        _ => return Err(Error::TimedOut),
+                    }
+                }
+                Err(nb::Error::Other(e)) => return Err(Error::Network(e)),
+            };
+            match peer {
+                Some(peer) if peer != from => {
+                    let error = ReasonFormat::default().error_packet(packet::Error::UnknownTransferId,
+                                                                     Reason::UnknownTransferId, "unknown transfer ID");
+                    try!(send_to(stack, socket, from, &error.encode()));
+                    continue
+                }
+                None if self.reply_policy == ReplyPolicy::SameAddress && from.ip() != self.server_addr.ip() => continue,
+                _ => {}
+            }
+            if n < 2 {
+                continue
+            }
+            let packet = &buf[..n];
+            match Opcode::from_u16((packet[0] as u16) << 8 | packet[1] as u16) {
+                Some(Opcode::DATA) => {
+                    let data = match DataPacketOctet::decode(packet) {
+                        Some(data) => data,
+                        None => continue,
+                    };
+                    peer = Some(from);
+                    match transfer.receive_data(&data) {
+                        DataReceived::Accepted(ack) => {
+                            try!(write(data.data()).map_err(|_| Error::Write));
+                            last_sent = ack.encode();
+                            try!(send_to(stack, socket, from, &last_sent));
+                            if transfer.is_done() {
+                                return Ok(())
+                            }
+                            deadline = self.clock.now_ms() + timeout_ms;
+                        }
+                        DataReceived::Duplicate(ack) => {
+                            try!(send_to(stack, socket, from, &ack.encode()));
+                        }
+                        DataReceived::Ignored => {}
+                    }
+                }
+                Some(Opcode::OACK) if peer.is_none() => {
+                    let oack = match OptionAckPacket::decode(packet) {
+                        Some(oack) => oack,
+                        None => continue,
+                    };
+                    peer = Some(from);
+                    let block_size = match negotiated_block_size(self.block_size.get(), &oack) {
+                        Ok(block_size) => block_size,
+                        Err(reason) => {
+                            let error = ReasonFormat::default().error_packet(packet::Error::OptionNegotiation,
+                                                                             Reason::OptionNegotiation, reason);
+                            let _ = send_to(stack, socket, from, &error.encode());
+                            return Err(Error::Protocol(reason))
+                        }
+                    };
+                    transfer = ReadTransfer::new(block_size);
+                    transfer.set_retries(self.retries);
+                    last_sent = AckPacket::new(0).encode();
+                    try!(send_to(stack, socket, from, &last_sent));
+                    deadline = self.clock.now_ms() + timeout_ms;
+                }
+                Some(Opcode::ERROR) => {
+                    if let Some(error) = ErrorPacket::decode(packet) {
+                        return Err(Error::Server(error.into_owned()))
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn send_to<S: UdpFullStack>(stack: &mut S, socket: &mut S::UdpSocket, addr: SocketAddr,
+                            packet: &RawPacket) -> Result<(), Error<S::Error>> {
+    loop {
+        match stack.send_to(socket, addr, packet.packet_buf()) {
+            Ok(()) => return Ok(()),
+            Err(nb::Error::WouldBlock) => continue,
+            Err(nb::Error::Other(e)) => return Err(Error::Network(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+
+    use embedded_nal::{UdpClientStack, UdpFullStack};
+    use nb;
+
+    use config::{BlockSize, ReplyPolicy};
+    use packet::{self, Mode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket, OptionAckPacket,
+        TransferOptions, EncodePacket, DecodePacket, BLKSIZE_OPTION};
+    use super::{Client, Error};
+
+    /// Stack with a scripted server answering every datagram the client sends.
+    struct ScriptedStack<F> {
+        server: F,
+        incoming: VecDeque<(SocketAddr, Vec<u8>)>,
+    }
+
+    impl<F: FnMut(&[u8]) -> Vec<(SocketAddr, Vec<u8>)>> UdpClientStack for ScriptedStack<F> {
+        type UdpSocket = ();
+        type Error = ();
+
+        fn socket(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn connect(&mut self, _: &mut (), _: SocketAddr) -> Result<(), ()> {
+            Err(())
+        }
+
+        fn send(&mut self, _: &mut (), _: &[u8]) -> nb::Result<(), ()> {
+            Err(nb::Error::Other(()))
+        }
+
+        fn receive(&mut self, _: &mut (), buf: &mut [u8]) -> nb::Result<(usize, SocketAddr), ()> {
+            match self.incoming.pop_front() {
+                Some((from, datagram)) => {
+                    buf[..datagram.len()].copy_from_slice(&datagram);
+                    Ok((datagram.len(), from))
+                }
+                None => Err(nb::Error::WouldBlock),
+            }
+        }
+
+        fn close(&mut self, _: ()) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    impl<F: FnMut(&[u8]) -> Vec<(SocketAddr, Vec<u8>)>> UdpFullStack for ScriptedStack<F> {
+        fn bind(&mut self, _: &mut (), _: u16) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn send_to(&mut self, _: &mut (), _: SocketAddr, buf: &[u8]) -> nb::Result<(), ()> {
+            let responses = (self.server)(buf);
+            self.incoming.extend(responses);
+            Ok(())
+        }
+    }
+
+    fn server_addr() -> SocketAddr {
+        "10.0.0.1:69".parse().unwrap()
+    }
+
+    fn transfer_addr() -> SocketAddr {
+        "10.0.0.1:3000".parse().unwrap()
+    }
+
+    #[test]
+    fn file_is_read_with_negotiated_block_size() {
+        let contents: Vec<u8> = (0..1500).map(|i| i as u8).collect();
+        let served = contents.clone();
+        let mut stack = ScriptedStack {
+            server: move |datagram: &[u8]| {
+                if let Some(request) = RequestPacket::decode(datagram) {
+                    assert_eq!(Some("1024"), request.options().get(BLKSIZE_OPTION));
+                    let mut options = TransferOptions::new();
+                    options.insert(BLKSIZE_OPTION, "1024");
+                    return vec![(transfer_addr(), OptionAckPacket::new(options).encode().packet_buf().to_vec())]
+                }
+                let block = AckPacket::decode(datagram).unwrap().block_id() as usize;
+                let start = block * 1024;
+                if start > served.len() {
+                    return vec![]
+                }
+                let end = ::std::cmp::min(start + 1024, served.len());
+                let data = DataPacketOctet::from_slice(block as u16 + 1, &served[start..end]);
+                vec![(transfer_addr(), data.encode().packet_buf().to_vec())]
+            },
+            incoming: VecDeque::new(),
+        };
+        let mut now = 0;
+        let mut client = Client::new(server_addr(), 4000, move || { now += 1; now })
+            .block_size(BlockSize::new(1024).unwrap());
+        let mut received = Vec::new();
+        client.get(&mut stack, "image.bin", Mode::Octet, |data| {
+            received.extend_from_slice(data);
+            Ok(())
+        }).unwrap();
+        assert_eq!(contents, received);
+    }
+
+    #[test]
+    fn server_error_terminates_transfer() {
+        let mut stack = ScriptedStack {
+            server: |_: &[u8]| {
+                let error = ErrorPacket::new(packet::Error::FileNotFound, "no such file");
+                vec![(transfer_addr(), error.encode().packet_buf().to_vec())]
+            },
+            incoming: VecDeque::new(),
+        };
+        let mut client = Client::new(server_addr(), 4000, || 0);
+        match client.get(&mut stack, "missing", Mode::Octet, |_| Ok(())) {
+            Err(Error::Server(error)) => assert_eq!(packet::Error::FileNotFound, error.error()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reply_from_other_address_is_accepted_by_policy() {
+        let server = |datagram: &[u8]| {
+            if RequestPacket::decode(datagram).is_some() {
+                let data = DataPacketOctet::from_slice(1, b"hello");
+                return vec![("10.0.0.2:3000".parse().unwrap(), data.encode().packet_buf().to_vec())]
+            }
+            vec![]
+        };
+        let mut stack = ScriptedStack { server: server, incoming: VecDeque::new() };
+        let mut now = 0;
+        let mut client = Client::new(server_addr(), 4000, move || { now += 100; now });
+        match client.get(&mut stack, "file", Mode::Octet, |_| Ok(())) {
+            Err(Error::TimedOut) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut stack = ScriptedStack { server: server, incoming: VecDeque::new() };
+        let mut client = Client::new(server_addr(), 4000, || 0)
+            .reply_policy(ReplyPolicy::AllowAddressChangeOnFirstReply);
+        let mut received = Vec::new();
+        client.get(&mut stack, "file", Mode::Octet, |data| {
+            received.extend_from_slice(data);
+            Ok(())
+        }).unwrap();
+        assert_eq!(b"hello", &received[..]);
+    }
+
+    #[test]
+    fn transfer_times_out_without_responses() {
+        let mut stack = ScriptedStack { server: |_: &[u8]| vec![], incoming: VecDeque::new() };
+        let mut now = 0;
+        let mut client = Client::new(server_addr(), 4000, move || { now += 100; now });
+        match client.get(&mut stack, "file", Mode::Octet, |_| Ok(())) {
+            Err(Error::TimedOut) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -62,6 +62,23 @@ pub fn to_netascii<'a>(s: &'a str) -> NetasciiString<'a> {
     return Cow::from(encoded)
 }
 
+/// Converts a file name in any encoding into netascii, escaping '\r' and '\n'
+/// like `to_netascii`. Other bytes are left unchanged.
+pub fn to_netascii_bytes<'a>(s: &'a [u8]) -> Cow<'a, [u8]> {
+    if !s.iter().any(|&b| b == b'\r' || b == b'\n') {
+        return Cow::from(s)
+    }
+    let mut encoded = Vec::with_capacity(s.len() + 1);
+    for &b in s {
+        match b {
+            b'\n' => encoded.extend_from_slice(b"\r\n"),
+            b'\r' => encoded.extend_from_slice(b"\r\0"),
+            _ => encoded.push(b),
+        }
+    }
+    Cow::from(encoded)
+}
+
 #[cfg(test)]
 mod test {
     use std::borrow::Cow;
@@ -123,7 +140,7 @@ mod test {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "nightly-bench"))]
 mod bench {
     extern crate test;
 
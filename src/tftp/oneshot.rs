@@ -0,0 +1,117 @@
+//! One-shot transfers for simple scripts, re-exported as `tftp::get` and
+//! `tftp::put`.
+//!
+//! The server is given as `HOST[:PORT]` or as a `tftp://HOST[:PORT][/PATH]`
+//! URL, the default port is 69. Transfers use octet mode, 5 retries and a
+//! 5 second timeout, other settings need a `ClientBuilder`.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use client::{Client, ClientBuilder};
+use config::Retries;
+use error::{Error, ErrorKind};
+use packet::Mode;
+use transfer::TransferParams;
+
+/// Timeout of one-shot transfers.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Retries of one-shot transfers.
+const RETRIES: u32 = 5;
+
+/// Reads the file `path` from `server` writing its contents to `dest`.
+///
+/// A URL naming a file reads that file if `path` is empty, otherwise `path`
+/// is relative to it, e.g. `get("tftp://10.0.0.1/pxe", "boot.img", ...)`
+/// reads `pxe/boot.img`.
+pub fn get(server: &str, path: &str, dest: &mut io::Write) -> Result<TransferParams, Error> {
+    let (addr, path) = try!(parse_remote(server, path));
+    try!(client(addr)).get(&path, Mode::Octet, dest).map_err(Error::from)
+}
+
+/// Writes the file `path` to `server` reading its contents from `src`.
+///
+/// `server` and `path` name the file like for `get`.
+pub fn put(server: &str, path: &str, src: &mut io::Read) -> Result<TransferParams, Error> {
+    let (addr, path) = try!(parse_remote(server, path));
+    try!(client(addr)).put(&path, Mode::Octet, src).map_err(Error::from)
+}
+
+fn client(addr: SocketAddr) -> Result<Client, Error> {
+    ClientBuilder::new(addr)
+        .timeout(TIMEOUT)
+        .retries(Retries::new(RETRIES).unwrap())
+        .build()
+        .map_err(Error::from)
+}
+
+/// Returns the address of `server` and the path of the file on it.
+fn parse_remote(server: &str, path: &str) -> Result<(SocketAddr, PathBuf), Error> {
+    let (authority, base) = if server.starts_with("tftp://") {
+        let rest = &server["tftp://".len()..];
+        match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        }
+    } else {
+        (server, "")
+    };
+    let file = match (base.is_empty(), path.is_empty()) {
+        (_, true) => Path::new(base).to_path_buf(),
+        (true, false) => Path::new(path).to_path_buf(),
+        (false, false) => Path::new(base).join(path),
+    };
+    if file.as_os_str().is_empty() {
+        return Err(Error::new(ErrorKind::InvalidConfig, format!("no file to transfer from {}", server)))
+    }
+    let (host, port) = try!(split_host_port(authority));
+    let addr = try!((host, port).to_socket_addrs()).next();
+    match addr {
+        Some(addr) => Ok((addr, file)),
+        None => Err(Error::new(ErrorKind::Io, format!("can't resolve host {}", host))),
+    }
+}
+
+/// Splits `HOST[:PORT]` into the host and the port, IPv6 hosts are enclosed
+/// in brackets.
+fn split_host_port(authority: &str) -> Result<(&str, u16), Error> {
+    let port_start = if authority.starts_with('[') {
+        authority.find(']').map(|end| end + 1)
+    } else {
+        authority.rfind(':')
+    };
+    let (host, port) = match port_start {
+        Some(i) if authority[i..].starts_with(':') => {
+            match authority[i + 1..].parse() {
+                Ok(port) => (&authority[..i], port),
+                Err(_) => return Err(Error::new(ErrorKind::InvalidConfig, format!("invalid port in {}", authority))),
+            }
+        }
+        _ => (authority, 69),
+    };
+    Ok((host.trim_left_matches('[').trim_right_matches(']'), port))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use error::ErrorKind;
+    use super::parse_remote;
+
+    #[test]
+    fn remotes_are_parsed_from_addresses_and_urls() {
+        let (addr, path) = parse_remote("127.0.0.1", "boot.img").unwrap();
+        assert_eq!(("127.0.0.1:69".parse().unwrap(), Path::new("boot.img")), (addr, path.as_path()));
+        let (addr, path) = parse_remote("tftp://[::1]:6969/pxe/boot.img", "").unwrap();
+        assert_eq!(("[::1]:6969".parse().unwrap(), Path::new("pxe/boot.img")), (addr, path.as_path()));
+        let (_, path) = parse_remote("tftp://127.0.0.1/pxe", "boot.img").unwrap();
+        assert_eq!(Path::new("pxe/boot.img"), path.as_path());
+
+        assert_eq!(ErrorKind::InvalidConfig, parse_remote("tftp://127.0.0.1", "").unwrap_err().kind());
+        assert_eq!(ErrorKind::InvalidConfig, parse_remote("127.0.0.1:tftp", "file").unwrap_err().kind());
+    }
+}
@@ -7,9 +7,11 @@ use std::borrow::Cow;
 use std::convert::From;
 use std::error;
 use std::fmt;
+use std::slice;
 use std::str::{self, FromStr};
 
-use netascii::{NetasciiString, to_netascii, from_netascii};
+use netascii::{NetasciiString, to_netascii, to_netascii_bytes, from_netascii};
+use filename::FilenameCodec;
 
 use self::byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
 
@@ -31,6 +33,9 @@ pub enum Opcode {
 
     /// Error
     ERROR = 5,
+
+    /// Option acknowledgment (RFC 2347)
+    OACK  = 6,
 }
 
 impl Opcode {
@@ -44,11 +49,28 @@ impl Opcode {
             3 => Some(Opcode::DATA),
             4 => Some(Opcode::ACK),
             5 => Some(Opcode::ERROR),
+            6 => Some(Opcode::OACK),
             _ => None
         }
     }
 }
 
+/// Returns `true` if `datagram` is a request, an error or an option
+/// acknowledgment whose last string lacks its terminating zero byte.
+///
+/// Some embedded implementations leave it out, the decoders accept the mode of
+/// requests and the message of errors without it.
+pub fn lacks_terminator(datagram: &[u8]) -> bool {
+    let opcode = (&datagram[..]).read_u16::<BigEndian>().ok().and_then(Opcode::from_u16);
+    match opcode {
+        Some(Opcode::RRQ) | Some(Opcode::WRQ) => datagram.last() != Some(&0),
+        Some(Opcode::ERROR) => datagram.len() <= 4 || datagram.last() != Some(&0),
+        // An acknowledgment of no options has no strings.
+        Some(Opcode::OACK) => datagram.len() > 2 && datagram.last() != Some(&0),
+        _ => false,
+    }
+}
+
 /// Mode of data transfer
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum Mode {
@@ -125,6 +147,9 @@ pub enum Error {
 
     /// No such user
     NoSuchUser                = 7,
+
+    /// Transfer terminated during option negotiation (RFC 2347).
+    OptionNegotiation         = 8,
 }
 
 impl Error {
@@ -141,6 +166,7 @@ impl Error {
             5 => Some(Error::UnknownTransferId),
             6 => Some(Error::FileAlreadyExists),
             7 => Some(Error::NoSuchUser),
+            8 => Some(Error::OptionNegotiation),
             _ => None
         }
     }
@@ -157,6 +183,7 @@ impl<'a> fmt::Display for Error {
             Error::UnknownTransferId => "unknown transfer id",
             Error::FileAlreadyExists => "file already exists",
             Error::NoSuchUser => "no such user",
+            Error::OptionNegotiation => "option negotiation failed",
         }.fmt(f)
     }
 }
@@ -198,14 +225,119 @@ pub trait EncodePacket : Packet {
     fn encode_using(&self, buf: Vec<u8>) -> RawPacket;
 }
 
+/// Block size option (RFC 2348).
+pub const BLKSIZE_OPTION: &'static str = "blksize";
+
+/// Timeout interval option (RFC 2349).
+pub const TIMEOUT_OPTION: &'static str = "timeout";
+
+/// Timeout interval option in microseconds, an extension of tftp-hpa and
+/// other servers for retransmission intervals below a second.
+pub const UTIMEOUT_OPTION: &'static str = "utimeout";
+
+/// Transfer size option (RFC 2349).
+pub const TSIZE_OPTION: &'static str = "tsize";
+
+/// Window size option (RFC 7440).
+pub const WINDOWSIZE_OPTION: &'static str = "windowsize";
+
+/// Options appended to a request or acknowledged by an option acknowledgment (RFC 2347).
+///
+/// Option names are case insensitive, values are kept as they were received.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct TransferOptions<'a> {
+    options: Vec<(Cow<'a, str>, Cow<'a, str>)>,
+}
+
+impl<'a> TransferOptions<'a> {
+    /// Creates an empty set of options.
+    pub fn new() -> TransferOptions<'a> {
+        TransferOptions {
+            options: Vec::new(),
+        }
+    }
+
+    /// Sets the value of an option, replacing the previous value of the option with the same name.
+    pub fn insert<N, V>(&mut self, name: N, value: V)
+        where N: Into<Cow<'a, str>>, V: Into<Cow<'a, str>>
+    {
+        let name = name.into();
+        let value = value.into();
+        match self.options.iter().position(|&(ref n, _)| n.eq_ignore_ascii_case(&name)) {
+            Some(i) => self.options[i].1 = value,
+            None => self.options.push((name, value)),
+        }
+    }
+
+    /// Returns the value of an option.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    /// Returns an iterator over the option names and values in the order they were added.
+    pub fn iter(&self) -> slice::Iter<(Cow<'a, str>, Cow<'a, str>)> {
+        self.options.iter()
+    }
+
+    /// Returns `true` if there are no options.
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty()
+    }
+
+    /// Converts the options into options owning their names and values.
+    pub fn into_owned(self) -> TransferOptions<'static> {
+        TransferOptions {
+            options: self.options.into_iter()
+                .map(|(n, v)| (Cow::from(n.into_owned()), Cow::from(v.into_owned())))
+                .collect(),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        self.options.iter().map(|&(ref n, ref v)| n.len() + 1 + v.len() + 1).sum()
+    }
+
+    fn decode<I: Iterator<Item=&'a str>>(parts: I) -> Option<TransferOptions<'a>> {
+        let mut options = TransferOptions::new();
+        let mut parts = parts.peekable();
+        loop {
+            match parts.next() {
+                // The last option is terminated by a zero byte, leaving an empty trailing part.
+                None => break,
+                Some("") if parts.peek().is_none() => break,
+                Some(name) => match parts.next() {
+                    // Value must be terminated by a zero byte as well.
+                    Some(value) if parts.peek().is_some() => options.insert(name, value),
+                    _ => return None,
+                },
+            }
+        }
+        Some(options)
+    }
+
+    fn write_to(&self, b: &mut Cursor<Vec<u8>>) {
+        for &(ref name, ref value) in self.options.iter() {
+            b.write(name.as_bytes()).unwrap();
+            b.write_u8(0).unwrap();
+            b.write(value.as_bytes()).unwrap();
+            b.write_u8(0).unwrap();
+        }
+    }
+}
+
 /// Request packet
+///
+/// The file name is kept as the netascii encoded bytes, names in legacy 8-bit
+/// encodings, e.g. Latin-1 names sent by old firmware, are not rejected.
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum RequestPacket<'a> {
     /// Read request packet
-    ReadRequest(NetasciiString<'a>, Mode),
+    ReadRequest(Cow<'a, [u8]>, Mode, TransferOptions<'a>),
 
     /// Write request packet
-    WriteRequest(NetasciiString<'a>, Mode),
+    WriteRequest(Cow<'a, [u8]>, Mode, TransferOptions<'a>),
 }
 
 impl<'a> RequestPacket<'a> {
@@ -213,36 +345,140 @@ impl<'a> RequestPacket<'a> {
     ///
     /// Filename is converted to netascii if required.
     pub fn read_request<'b>(filename: &'b str, mode: Mode) -> RequestPacket<'b> {
-        RequestPacket::ReadRequest(to_netascii(filename), mode)
+        RequestPacket::read_request_bytes(filename.as_bytes(), mode)
     }
 
     /// Create a new write request.
     ///
     /// Filename is converted to netascii if required.
     pub fn write_request<'b>(filename: &'b str, mode: Mode) -> RequestPacket<'b> {
-        RequestPacket::WriteRequest(to_netascii(filename), mode)
+        RequestPacket::write_request_bytes(filename.as_bytes(), mode)
+    }
+
+    /// Creates a new read request for a file name in any encoding.
+    ///
+    /// Filename is converted to netascii if required, other bytes are sent unchanged.
+    pub fn read_request_bytes<'b>(filename: &'b [u8], mode: Mode) -> RequestPacket<'b> {
+        RequestPacket::ReadRequest(to_netascii_bytes(filename), mode, TransferOptions::new())
+    }
+
+    /// Creates a new write request for a file name in any encoding, see `read_request_bytes`.
+    pub fn write_request_bytes<'b>(filename: &'b [u8], mode: Mode) -> RequestPacket<'b> {
+        RequestPacket::WriteRequest(to_netascii_bytes(filename), mode, TransferOptions::new())
+    }
+
+    /// Replaces the options of the request.
+    pub fn with_options(self, options: TransferOptions<'a>) -> RequestPacket<'a> {
+        match self {
+            RequestPacket::ReadRequest(filename, mode, _) => RequestPacket::ReadRequest(filename, mode, options),
+            RequestPacket::WriteRequest(filename, mode, _) => RequestPacket::WriteRequest(filename, mode, options),
+        }
     }
 
     /// Returns a file name that the request is for.
     ///
-    /// If netascii encoding is invalid `None` is returned.
+    /// If the name is not UTF-8 or netascii encoding is invalid `None` is returned.
     pub fn filename<'b>(&'b self) -> Option<Cow<'b, str>> {
-        from_netascii(self.filename_raw())
+        str::from_utf8(self.filename_raw()).ok().and_then(from_netascii)
+    }
+
+    /// Returns a file name that the request is for, bytes that are not UTF-8
+    /// are replaced with U+FFFD and invalid netascii is kept escaped.
+    ///
+    /// The name is meant for logs and error messages, it may not match the
+    /// name of any file.
+    pub fn filename_lossy(&self) -> Cow<str> {
+        match String::from_utf8_lossy(self.filename_raw()) {
+            Cow::Borrowed(name) => from_netascii(name).unwrap_or(Cow::from(name)),
+            Cow::Owned(name) => Cow::from(from_netascii(&name).map_or_else(|| name.clone(), Cow::into_owned)),
+        }
     }
 
     /// Returns a raw file name netascii encoded.
-    pub fn filename_raw(&self) -> &str {
+    pub fn filename_raw(&self) -> &[u8] {
         match *self {
-            RequestPacket::ReadRequest(ref filename, _) => &filename[..],
-            RequestPacket::WriteRequest(ref filename, _) => &filename[..],
+            RequestPacket::ReadRequest(ref filename, _, _) => &filename[..],
+            RequestPacket::WriteRequest(ref filename, _, _) => &filename[..],
         }
     }
 
     /// Returns a transfer mode.
     pub fn mode(&self) -> Mode {
         match *self {
-            RequestPacket::ReadRequest(_, mode) => mode,
-            RequestPacket::WriteRequest(_, mode) => mode
+            RequestPacket::ReadRequest(_, mode, _) => mode,
+            RequestPacket::WriteRequest(_, mode, _) => mode
+        }
+    }
+
+    /// Returns the options requested by the client.
+    pub fn options(&self) -> &TransferOptions<'a> {
+        match *self {
+            RequestPacket::ReadRequest(_, _, ref options) => options,
+            RequestPacket::WriteRequest(_, _, ref options) => options,
+        }
+    }
+
+    /// Decodes a request whose file name is encoded with `codec`.
+    ///
+    /// With `FilenameCodec::Utf8` the name is kept unchanged, also when it's
+    /// not valid UTF-8, see `filename_lossy`.
+    pub fn decode_with(data: &[u8], codec: FilenameCodec) -> Option<RequestPacket<'static>> {
+        let opcode = (&data[..]).read_u16::<BigEndian>().ok().and_then(Opcode::from_u16);
+        if opcode != Some(Opcode::RRQ) && opcode != Some(Opcode::WRQ) {
+            return None
+        }
+        let rest = &data[2..];
+        let end = match rest.iter().position(|&b| b == 0) {
+            Some(end) => end,
+            None => return None,
+        };
+        let filename = match codec {
+            FilenameCodec::Utf8 => Cow::from(rest[..end].to_vec()),
+            _ => match codec.decode(&rest[..end]) {
+                Some(filename) => Cow::from(filename.into_bytes()),
+                None => return None,
+            },
+        };
+        str::from_utf8(&rest[end + 1..]).ok().map(|s| s.split('\0')).and_then(|mut parts| {
+            let mode = parts.next().and_then(|m| FromStr::from_str(m).ok());
+            let options = TransferOptions::decode(parts).map(TransferOptions::into_owned);
+            match (mode, options) {
+                (Some(mode), Some(options)) => {
+                    if opcode.unwrap() == Opcode::RRQ {
+                        Some(RequestPacket::ReadRequest(filename, mode, options))
+                    } else {
+                        Some(RequestPacket::WriteRequest(filename, mode, options))
+                    }
+                }
+                _ => None
+            }
+        })
+    }
+
+    /// Encodes the request with the file name encoded by `codec`, `buf` is
+    /// used to hold the packet.
+    ///
+    /// Returns `None` if the file name can't be represented by `codec`. Names
+    /// that are not UTF-8 are already encoded and are sent unchanged.
+    pub fn encode_with(&self, codec: FilenameCodec, buf: Vec<u8>) -> Option<RawPacket> {
+        match str::from_utf8(self.filename_raw()) {
+            Ok(filename) => codec.encode(filename).map(|filename| self.encode_filename(&filename, buf)),
+            Err(_) => Some(self.encode_filename(self.filename_raw(), buf)),
+        }
+    }
+
+    fn encode_filename(&self, filename: &[u8], buf: Vec<u8>) -> RawPacket {
+        let mut b = Cursor::new(buf);
+        b.write_u16::<BigEndian>(self.opcode() as u16).unwrap();
+        b.write(filename).unwrap();
+        b.write_u8(0).unwrap();
+        b.write(self.mode().as_str().as_bytes()).unwrap();
+        b.write_u8(0).unwrap();
+        self.options().write_to(&mut b);
+
+        RawPacket {
+            buf: b.into_inner(),
+            len: 2 + filename.len() + 1 + self.mode().as_str().len() + 1 + self.options().encoded_len()
         }
     }
 }
@@ -250,13 +486,13 @@ impl<'a> RequestPacket<'a> {
 impl<'a> Packet for RequestPacket<'a> {
     fn opcode(&self) -> Opcode {
         match *self {
-            RequestPacket::ReadRequest(_, _) => Opcode::RRQ,
-            RequestPacket::WriteRequest(_, _) => Opcode::WRQ
+            RequestPacket::ReadRequest(..) => Opcode::RRQ,
+            RequestPacket::WriteRequest(..) => Opcode::WRQ
         }
     }
 
     fn len(&self) -> usize {
-        2 + self.filename_raw().len() + 1 + self.mode().as_str().len() + 1
+        2 + self.filename_raw().len() + 1 + self.mode().as_str().len() + 1 + self.options().encoded_len()
     }
 }
 
@@ -268,16 +504,21 @@ impl<'a> DecodePacket<'a> for RequestPacket<'a> {
         if opcode != Some(Opcode::RRQ) && opcode != Some(Opcode::WRQ) {
             return None
         }
-        // FIXME
-        str::from_utf8(&data[2..]).ok().map(|s| s.split('\0')).and_then(|mut parts| {
-            let filename = parts.next().map(|s| Cow::from(s));
+        let rest = &data[2..];
+        let end = match rest.iter().position(|&b| b == 0) {
+            Some(end) => end,
+            None => return None,
+        };
+        let filename = Cow::from(&rest[..end]);
+        str::from_utf8(&rest[end + 1..]).ok().map(|s| s.split('\0')).and_then(|mut parts| {
             let mode = parts.next().and_then(|m| FromStr::from_str(m).ok());
-            match (filename, mode) {
-                (Some(filename), Some(mode)) => {
+            let options = TransferOptions::decode(parts);
+            match (mode, options) {
+                (Some(mode), Some(options)) => {
                     if opcode.unwrap() == Opcode::RRQ {
-                        Some(RequestPacket::ReadRequest(filename, mode))
+                        Some(RequestPacket::ReadRequest(filename, mode, options))
                     } else {
-                        Some(RequestPacket::WriteRequest(filename, mode))
+                        Some(RequestPacket::WriteRequest(filename, mode, options))
                     }
                 }
                 _ => None
@@ -287,13 +528,59 @@ impl<'a> DecodePacket<'a> for RequestPacket<'a> {
 }
 
 impl<'a> EncodePacket for RequestPacket<'a> {
+    fn encode_using(&self, buf: Vec<u8>) -> RawPacket {
+        self.encode_filename(self.filename_raw(), buf)
+    }
+}
+
+/// Option acknowledgment packet (RFC 2347)
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct OptionAckPacket<'a> {
+    options: TransferOptions<'a>,
+}
+
+impl<'a> OptionAckPacket<'a> {
+    /// Creates an acknowledgment of the options accepted by the server.
+    pub fn new(options: TransferOptions<'a>) -> OptionAckPacket<'a> {
+        OptionAckPacket {
+            options: options,
+        }
+    }
+
+    /// Returns the acknowledged options.
+    pub fn options(&self) -> &TransferOptions<'a> {
+        &self.options
+    }
+}
+
+impl<'a> Packet for OptionAckPacket<'a> {
+    fn opcode(&self) -> Opcode {
+        Opcode::OACK
+    }
+
+    fn len(&self) -> usize {
+        2 + self.options.encoded_len()
+    }
+}
+
+impl<'a> DecodePacket<'a> for OptionAckPacket<'a> {
+    fn decode(data: &'a [u8]) -> Option<OptionAckPacket<'a>> {
+        let mut cur = Cursor::new(data);
+        let opcode = cur.read_u16::<BigEndian>().ok().and_then(Opcode::from_u16);
+        if opcode != Some(Opcode::OACK) {
+            return None
+        }
+        str::from_utf8(&data[2..]).ok()
+            .and_then(|s| TransferOptions::decode(s.split('\0')))
+            .map(OptionAckPacket::new)
+    }
+}
+
+impl<'a> EncodePacket for OptionAckPacket<'a> {
     fn encode_using(&self, buf: Vec<u8>) -> RawPacket {
         let mut b = Cursor::new(buf);
-        b.write_u16::<BigEndian>(self.opcode() as u16).unwrap();
-        b.write(self.filename_raw().as_bytes()).unwrap();
-        b.write_u8(0).unwrap();
-        b.write(self.mode().as_str().as_bytes()).unwrap();
-        b.write_u8(0).unwrap();
+        b.write_u16::<BigEndian>(Opcode::OACK as u16).unwrap();
+        self.options.write_to(&mut b);
 
         RawPacket {
             buf: b.into_inner(),
@@ -391,6 +678,14 @@ impl<'a> DataPacketOctet<'a> {
         &self.data[..self.len]
     }
 
+    /// Returns the encoded packet header containing the opcode and block number.
+    ///
+    /// Together with `data` it can be used to send the packet without encoding
+    /// it into a single buffer.
+    pub fn header(&self) -> [u8; 4] {
+        [0, Opcode::DATA as u8, (self.block_id >> 8) as u8, self.block_id as u8]
+    }
+
     /// Tries to move the buffer out of this object and returns it, consuming the `RawPacket`.
     ///
     /// Returns `None` if contained buffer is a slice.
@@ -412,16 +707,14 @@ impl<'a> Packet for DataPacketOctet<'a> {
     }
 }
 
-impl<'a> DecodePacket<'a> for DataPacketOctet<'static> {
-    fn decode(data: &'a [u8]) -> Option<DataPacketOctet<'static>> {
+impl<'a> DecodePacket<'a> for DataPacketOctet<'a> {
+    fn decode(data: &'a [u8]) -> Option<DataPacketOctet<'a>> {
         let mut cur = Cursor::new(data);
         let opcode = cur.read_u16::<BigEndian>().ok().and_then(Opcode::from_u16);
         match opcode {
             Some(Opcode::DATA) => {
                 cur.read_u16::<BigEndian>().ok().map(|block_id| {
-                    let payload = data[4..].to_vec();
-                    let len = payload.len();
-                    DataPacketOctet::from_vec(block_id, payload, len)
+                    DataPacketOctet::from_slice(block_id, &data[4..])
                 })
             }
             _ => None
@@ -479,6 +772,14 @@ impl<'a> ErrorPacket<'a> {
     pub fn message(&'a self) -> Option<Cow<'a, str>> {
         from_netascii(&self.message[..])
     }
+
+    /// Converts the packet into a packet owning its message.
+    pub fn into_owned(self) -> ErrorPacket<'static> {
+        ErrorPacket {
+            error: self.error,
+            message: Cow::from(self.message.into_owned()),
+        }
+    }
 }
 
 impl<'a> Packet for ErrorPacket<'a> {
@@ -499,8 +800,8 @@ impl<'a> DecodePacket<'a> for ErrorPacket<'a> {
             Some(Opcode::ERROR) => {
                 let error = cur.read_u16::<BigEndian>().ok().and_then(Error::from_u16);
                 // FIXME
-                let msg = str::from_utf8(&data[4..]).ok().map(|s| s.split('\0'))
-                                                            .and_then(|mut i| i.next());
+                let msg = data.get(4..).and_then(|msg| str::from_utf8(msg).ok()).map(|s| s.split('\0'))
+                                                                                 .and_then(|mut i| i.next());
                 match (error, msg) {
                     (Some(error), Some(msg)) => Some(ErrorPacket::new(error, msg)),
                     _ => None
@@ -526,6 +827,79 @@ impl<'a> EncodePacket for ErrorPacket<'a> {
     }
 }
 
+/// A packet of any type, decoded according to its opcode.
+///
+/// Lets custom flows handle whatever the remote side sends, e.g. a probe of
+/// the options a server supports receiving either an option acknowledgment,
+/// the first data block or an error.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum AnyPacket<'a> {
+    /// Read or write request.
+    Request(RequestPacket<'a>),
+
+    /// Data block.
+    Data(DataPacketOctet<'a>),
+
+    /// Acknowledgment of a data block.
+    Ack(AckPacket),
+
+    /// Error terminating the transfer.
+    Error(ErrorPacket<'a>),
+
+    /// Option acknowledgment (RFC 2347).
+    OptionAck(OptionAckPacket<'a>),
+}
+
+impl<'a> Packet for AnyPacket<'a> {
+    fn opcode(&self) -> Opcode {
+        match *self {
+            AnyPacket::Request(ref packet) => packet.opcode(),
+            AnyPacket::Data(ref packet) => packet.opcode(),
+            AnyPacket::Ack(ref packet) => packet.opcode(),
+            AnyPacket::Error(ref packet) => packet.opcode(),
+            AnyPacket::OptionAck(ref packet) => packet.opcode(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match *self {
+            AnyPacket::Request(ref packet) => packet.len(),
+            AnyPacket::Data(ref packet) => packet.len(),
+            AnyPacket::Ack(ref packet) => packet.len(),
+            AnyPacket::Error(ref packet) => packet.len(),
+            AnyPacket::OptionAck(ref packet) => packet.len(),
+        }
+    }
+}
+
+impl<'a> DecodePacket<'a> for AnyPacket<'a> {
+    fn decode(data: &'a [u8]) -> Option<AnyPacket<'a>> {
+        let opcode = match (&data[..]).read_u16::<BigEndian>().ok().and_then(Opcode::from_u16) {
+            Some(opcode) => opcode,
+            None => return None,
+        };
+        match opcode {
+            Opcode::RRQ | Opcode::WRQ => RequestPacket::decode(data).map(AnyPacket::Request),
+            Opcode::DATA => DataPacketOctet::decode(data).map(AnyPacket::Data),
+            Opcode::ACK => AckPacket::decode(data).map(AnyPacket::Ack),
+            Opcode::ERROR => ErrorPacket::decode(data).map(AnyPacket::Error),
+            Opcode::OACK => OptionAckPacket::decode(data).map(AnyPacket::OptionAck),
+        }
+    }
+}
+
+impl<'a> EncodePacket for AnyPacket<'a> {
+    fn encode_using(&self, buf: Vec<u8>) -> RawPacket {
+        match *self {
+            AnyPacket::Request(ref packet) => packet.encode_using(buf),
+            AnyPacket::Data(ref packet) => packet.encode_using(buf),
+            AnyPacket::Ack(ref packet) => packet.encode_using(buf),
+            AnyPacket::Error(ref packet) => packet.encode_using(buf),
+            AnyPacket::OptionAck(ref packet) => packet.encode_using(buf),
+        }
+    }
+}
+
 /// A Trivial File Transfer Protocol encoded packet.
 #[derive(Clone)]
 pub struct RawPacket {
@@ -572,12 +946,19 @@ impl RawPacket {
     /// This method should be used for maximal buffer reuse. Memory is zeroed before returning.
     pub fn get_buffer(self) -> Vec<u8> {
         let mut buffer = self.buf;
-        // TODO: optimize
-        for x in buffer.iter_mut() {
-            *x = 0 ;
+        for b in buffer.iter_mut() {
+            *b = 0;
         }
         buffer
     }
+
+    /// Moves the buffer out of this object without zeroing it, consuming the `RawPacket`.
+    ///
+    /// Can be used instead of `get_buffer` when the next use of the buffer overwrites
+    /// all the bytes it reads, e.g. receiving a datagram or encoding a packet of the same length.
+    pub fn into_buffer(self) -> Vec<u8> {
+        self.buf
+    }
 }
 
 #[cfg(test)]
@@ -587,24 +968,47 @@ mod test {
 
     use std::borrow::Cow;
     use std::convert::From;
+    use std::fs;
+    use std::path::Path;
 
     use self::rand::Rng;
     use self::quickcheck::{quickcheck, Arbitrary, Gen};
 
+    use filename::FilenameCodec;
     use super::{Mode, Error, EncodePacket, DecodePacket};
     use super::{RequestPacket, AckPacket, DataPacketOctet,
-                ErrorPacket};
+                ErrorPacket, OptionAckPacket, TransferOptions, AnyPacket};
 
     impl Arbitrary for RequestPacket<'static> {
         fn arbitrary<G: Gen>(g: &mut G) -> RequestPacket<'static> {
             let transfer_type = if g.gen() { Mode::Octet } else { Mode::NetAscii };
             let str_len = g.gen_range(0usize, 50);
             let filename: String = g.gen_ascii_chars().take(str_len).collect();
+            let options = TransferOptions::arbitrary(g);
             if g.gen() {
-                RequestPacket::ReadRequest(Cow::from(filename), transfer_type)
+                RequestPacket::ReadRequest(Cow::from(filename.into_bytes()), transfer_type, options)
             } else {
-                RequestPacket::WriteRequest(Cow::from(filename), transfer_type)
+                RequestPacket::WriteRequest(Cow::from(filename.into_bytes()), transfer_type, options)
+            }
+        }
+    }
+
+    impl Arbitrary for TransferOptions<'static> {
+        fn arbitrary<G: Gen>(g: &mut G) -> TransferOptions<'static> {
+            let mut options = TransferOptions::new();
+            for _ in 0..g.gen_range(0usize, 4) {
+                let name_len = g.gen_range(1usize, 10);
+                let name: String = g.gen_ascii_chars().take(name_len).collect();
+                let value = g.gen::<u16>().to_string();
+                options.insert(name, value);
             }
+            options
+        }
+    }
+
+    impl Arbitrary for OptionAckPacket<'static> {
+        fn arbitrary<G: Gen>(g: &mut G) -> OptionAckPacket<'static> {
+            OptionAckPacket::new(TransferOptions::arbitrary(g))
         }
     }
 
@@ -625,7 +1029,7 @@ mod test {
 
     impl Arbitrary for ErrorPacket<'static> {
         fn arbitrary<G: Gen>(g: &mut G) -> ErrorPacket<'static> {
-            let error = Error::from_u16(g.gen_range(0, 8)).unwrap();
+            let error = Error::from_u16(g.gen_range(0, 9)).unwrap();
             let msg_len = g.gen_range(0usize, 50);
             let message: String = g.gen_ascii_chars().take(msg_len).collect();
             ErrorPacket{
@@ -675,6 +1079,64 @@ mod test {
         assert_eq!(expected, raw_packet.packet_buf());
     }
 
+    #[test]
+    fn request_packet_with_options_is_encoded() {
+        let mut options = TransferOptions::new();
+        options.insert("blksize", "1428");
+        let packet = RequestPacket::read_request("foo", Mode::Octet).with_options(options);
+        let raw_packet = packet.encode();
+        let expected = b"\x00\x01foo\0octet\0blksize\01428\0";
+        assert_eq!(expected, raw_packet.packet_buf());
+    }
+
+    #[test]
+    fn request_packet_with_truncated_option_is_not_decoded() {
+        let decoded: Option<RequestPacket> = DecodePacket::decode(b"\x00\x01foo\0octet\0blksize\0");
+        assert_eq!(None, decoded);
+    }
+
+    #[test]
+    fn request_packet_file_name_is_encoded_with_codec() {
+        let mut options = TransferOptions::new();
+        options.insert("blksize", "1428");
+        let packet = RequestPacket::write_request("café", Mode::Octet).with_options(options);
+        let raw_packet = packet.encode_with(FilenameCodec::Latin1, Vec::new()).unwrap();
+        assert_eq!(b"\x00\x02caf\xe9\0octet\0blksize\01428\0", raw_packet.packet_buf());
+        assert_eq!(Some(packet.clone()), RequestPacket::decode_with(raw_packet.packet_buf(), FilenameCodec::Latin1));
+        let undecoded = RequestPacket::decode_with(raw_packet.packet_buf(), FilenameCodec::Utf8).unwrap();
+        assert_eq!((None, Cow::from("caf\u{FFFD}")), (undecoded.filename(), undecoded.filename_lossy()));
+        assert_eq!(b"caf\xe9", undecoded.filename_raw());
+        assert!(RequestPacket::read_request("файл", Mode::Octet).encode_with(FilenameCodec::Latin1, Vec::new()).is_none());
+    }
+
+    #[test]
+    fn request_packet_with_8bit_file_name_is_decoded() {
+        let decoded: RequestPacket = DecodePacket::decode(b"\x00\x01caf\xe9\r\nx\0octet\0").unwrap();
+        assert_eq!(None, decoded.filename());
+        assert_eq!("caf\u{FFFD}\nx", decoded.filename_lossy());
+        let packet = RequestPacket::read_request_bytes(b"caf\xe9\nx", Mode::Octet);
+        assert_eq!(decoded, packet);
+        let raw_packet = packet.encode_with(FilenameCodec::Percent, Vec::new()).unwrap();
+        assert_eq!(b"\x00\x01caf\xe9\r\nx\0octet\0", raw_packet.packet_buf());
+    }
+
+    #[test]
+    fn option_names_are_case_insensitive() {
+        let mut options = TransferOptions::new();
+        options.insert("BlkSize", "1024");
+        options.insert("blksize", "1428");
+        assert_eq!(Some("1428"), options.get("BLKSIZE"));
+        assert_eq!(1, options.iter().count());
+    }
+
+    #[test]
+    fn encoding_and_decoding_option_ack_packet_is_identity() {
+        fn prop(packet: OptionAckPacket<'static>)  -> bool {
+            Some(packet.clone()) == packet.encode().decode()
+        }
+        quickcheck(prop as fn(OptionAckPacket<'static>) -> bool)
+    }
+
     #[test]
     fn encoding_and_decoding_request_packet_is_identity() {
         fn prop(packet: RequestPacket<'static>)  -> bool {
@@ -707,6 +1169,12 @@ mod test {
         assert_eq!(&expected[..], raw_packet.packet_buf());
     }
 
+    #[test]
+    fn packet_data_octet_header_is_encoded() {
+        let packet = DataPacketOctet::from_slice(258, &[1u8, 2]);
+        assert_eq!([0, 3, 1, 2], packet.header());
+    }
+
     #[test]
     fn encoding_and_decoding_packet_data_octet_is_identity() {
         fn prop(packet: DataPacketOctet<'static>) -> bool {
@@ -739,6 +1207,63 @@ mod test {
         quickcheck(prop as fn(ErrorPacket<'static>) -> bool)
     }
 
+    #[test]
+    fn any_packet_is_decoded_by_opcode() {
+        let ack = AckPacket::new(7).encode();
+        assert_eq!(Some(AnyPacket::Ack(AckPacket::new(7))), AnyPacket::decode(ack.packet_buf()));
+        let error = ErrorPacket::new(Error::FileNotFound, "missing");
+        assert_eq!(Some(AnyPacket::Error(error.clone())), AnyPacket::decode(error.encode().packet_buf()));
+        assert_eq!(None, AnyPacket::decode(&[0, 9, 0, 1]));
+        assert_eq!(None, AnyPacket::decode(&[0]));
+    }
+
+    /// Runs a datagram through every decoder, returns the kind `AnyPacket`
+    /// decodes it as or `invalid`.
+    fn classify(data: &[u8]) -> &'static str {
+        let _ = RequestPacket::decode(data);
+        let _ = OptionAckPacket::decode(data);
+        let _ = AckPacket::decode(data);
+        let _ = DataPacketOctet::decode(data);
+        let _ = ErrorPacket::decode(data);
+        for &codec in &[FilenameCodec::Utf8, FilenameCodec::Latin1, FilenameCodec::Percent] {
+            let _ = RequestPacket::decode_with(data, codec);
+        }
+        match AnyPacket::decode(data) {
+            Some(AnyPacket::Request(RequestPacket::ReadRequest(..))) => "rrq",
+            Some(AnyPacket::Request(RequestPacket::WriteRequest(..))) => "wrq",
+            Some(AnyPacket::Data(_)) => "data",
+            Some(AnyPacket::Ack(_)) => "ack",
+            Some(AnyPacket::Error(_)) => "error",
+            Some(AnyPacket::OptionAck(_)) => "oack",
+            None => "invalid",
+        }
+    }
+
+    #[test]
+    fn malformed_datagrams_of_corpus_are_classified() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("data/corpus/decode");
+        let expected = fs::read_to_string(corpus.join("expected.txt")).unwrap();
+        let mut checked = 0;
+        for line in expected.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let mut fields = line.split_whitespace();
+            let (name, kind) = (fields.next().unwrap(), fields.next().unwrap());
+            let data = fs::read(corpus.join(format!("{}.bin", name))).unwrap();
+            assert_eq!((name, kind), (name, classify(&data)));
+            checked += 1;
+        }
+        let files = fs::read_dir(&corpus).unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension().map_or(false, |ext| ext == "bin"))
+            .count();
+        assert_eq!(files, checked, "every corpus file needs an expected kind");
+    }
+
+    #[test]
+    fn packet_buffer_is_not_zeroed_when_moved_out() {
+        let packet = AckPacket::new(1);
+        let raw_packet = packet.encode();
+        assert_eq!(vec![0, 4, 0, 1], raw_packet.into_buffer());
+    }
+
     #[test]
     fn packet_buffer_is_zeroes_before_reuse() {
         let packet = AckPacket::new(1);
@@ -748,14 +1273,16 @@ mod test {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "nightly-bench"))]
 mod bench {
     extern crate test;
 
     use self::test::{Bencher, black_box};
 
+    use std::mem;
+
     use super::{Mode, EncodePacket, Error};
-    use super::{RequestPacket, AckPacket, DataPacketOctet, ErrorPacket};
+    use super::{RequestPacket, AckPacket, DataPacketOctet, ErrorPacket, RawPacket};
 
     #[bench]
     fn decode_read_request(b: &mut Bencher) {
@@ -857,4 +1384,26 @@ mod bench {
         });
         b.bytes = raw_packet.len() as u64;
     }
+
+    #[bench]
+    fn raw_packet_get_buffer(b: &mut Bencher) {
+        let mut buf = vec![1u8; 516];
+        b.iter(|| {
+            let raw_packet = RawPacket::new(mem::replace(&mut buf, Vec::new()), 516);
+            buf = raw_packet.get_buffer();
+            black_box(&buf);
+        });
+        b.bytes = 516;
+    }
+
+    #[bench]
+    fn raw_packet_into_buffer(b: &mut Bencher) {
+        let mut buf = vec![1u8; 516];
+        b.iter(|| {
+            let raw_packet = RawPacket::new(mem::replace(&mut buf, Vec::new()), 516);
+            buf = raw_packet.into_buffer();
+            black_box(&buf);
+        });
+        b.bytes = 516;
+    }
 }
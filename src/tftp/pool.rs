@@ -0,0 +1,424 @@
+//! Thread pool running blocking file I/O off the reactor.
+//!
+//! All transfers of the server share one reactor thread. A read or write of a
+//! local file that waits for a slow disk stalls every transfer, including
+//! their retransmission timers. An `IoPool` runs such operations on worker
+//! threads instead, the transfer waits for the result like for any other
+//! future. `FsHandler::io_pool` moves the file operations of a handler there.
+//!
+//! The queue of operations waiting for a free thread is bounded. Operations
+//! started with `IoPool::spawn`, like opening the file of a new transfer, that
+//! don't fit fail right away with a "server busy" error instead of piling up
+//! behind a stuck disk. Reads and writes of a `Pooled` file of a running
+//! transfer wait for room in the queue instead, failing them would fail the
+//! transfer.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+use futures::{Async, Future, Poll};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+type Job = Box<FnOnce() + Send>;
+
+/// Worker threads running blocking operations, clones share the threads.
+///
+/// The threads exit once the pool and all its clones are dropped.
+#[derive(Clone)]
+pub struct IoPool {
+    queue: SyncSender<Job>,
+    /// Tasks waiting for room in the queue, woken up when a thread takes an
+    /// operation off it.
+    waiting: Arc<Mutex<Vec<Task>>>,
+    threads: usize,
+}
+
+impl IoPool {
+    /// Starts `threads` worker threads, at most `queue` operations wait for a
+    /// free thread.
+    pub fn new(threads: usize, queue: usize) -> io::Result<IoPool> {
+        if threads == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "I/O pool needs at least one thread"))
+        }
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let waiting = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..threads {
+            let (receiver, waiting) = (receiver.clone(), waiting.clone());
+            try!(thread::Builder::new().name(format!("tftp-io-{}", i)).spawn(move || work(&receiver, &waiting)));
+        }
+        Ok(IoPool {
+            queue: sender,
+            waiting: waiting,
+            threads: threads,
+        })
+    }
+
+    /// Returns the number of worker threads.
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Runs `op` on a worker thread, the returned future resolves to its
+    /// result.
+    ///
+    /// Fails without running `op` if the queue is full.
+    pub fn spawn<F, T>(&self, op: F) -> Blocking<T>
+        where F: FnOnce() -> io::Result<T> + Send + 'static,
+              T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = sender.send(op());
+        });
+        match self.queue.try_send(job) {
+            Ok(()) => Blocking(Ok(receiver)),
+            Err(TrySendError::Full(_)) => {
+                Blocking(Err(Some(io::Error::new(io::ErrorKind::Other, "server busy, I/O queue is full"))))
+            }
+            Err(TrySendError::Disconnected(_)) => Blocking(Err(Some(stopped()))),
+        }
+    }
+
+    /// Queues `job`, returns `false` if the queue is full and the current
+    /// task is woken up once a thread takes an operation off it.
+    fn queue_or_wait(&self, job: Job) -> io::Result<bool> {
+        let job = match self.queue.try_send(job) {
+            Ok(()) => return Ok(true),
+            Err(TrySendError::Full(job)) => job,
+            Err(TrySendError::Disconnected(_)) => return Err(stopped()),
+        };
+        // Registered before trying again, so room made in between isn't missed.
+        self.waiting.lock().unwrap().push(task::current());
+        match self.queue.try_send(job) {
+            Ok(()) => Ok(true),
+            Err(TrySendError::Full(_)) => Ok(false),
+            Err(TrySendError::Disconnected(_)) => Err(stopped()),
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "I/O threads stopped")
+}
+
+impl fmt::Debug for IoPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IoPool").field("threads", &self.threads).finish()
+    }
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>, waiting: &Mutex<Vec<Task>>) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        if let Ok(mut waiting) = waiting.lock() {
+            for task in waiting.drain(..) {
+                task.notify();
+            }
+        }
+        match job {
+            // A panicking operation fails its future, the thread keeps serving.
+            Ok(job) => { let _ = panic::catch_unwind(AssertUnwindSafe(job)); }
+            Err(_) => return,
+        }
+    }
+}
+
+/// Runs `op` on a thread of its own, the returned future resolves to its
+/// result.
+///
+/// For the rare long operations of handlers without an `IoPool`, e.g.
+/// digesting a whole file, that would stall the reactor.
+pub fn spawn_thread<F, T>(op: F) -> Blocking<T>
+    where F: FnOnce() -> io::Result<T> + Send + 'static,
+          T: Send + 'static,
+{
+    let (sender, receiver) = oneshot::channel();
+    let spawned = thread::Builder::new().name("tftp-blocking".to_string()).spawn(move || {
+        let _ = sender.send(op());
+    });
+    match spawned {
+        Ok(_) => Blocking(Ok(receiver)),
+        Err(e) => Blocking(Err(Some(e))),
+    }
+}
+
+/// Result of an operation running on an `IoPool` or a thread of its own.
+#[derive(Debug)]
+pub struct Blocking<T>(Result<oneshot::Receiver<io::Result<T>>, Option<io::Error>>);
+
+impl<T> Future for Blocking<T> {
+    type Item = T;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<T, io::Error> {
+        match self.0 {
+            Ok(ref mut receiver) => match receiver.poll() {
+                Ok(Async::Ready(result)) => result.map(Async::Ready),
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "I/O operation panicked")),
+            },
+            Err(ref mut e) => {
+                Err(e.take().unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "polled after failure")))
+            }
+        }
+    }
+}
+
+enum State<F> {
+    Idle(F),
+    Busy(Blocking<(F, Vec<u8>, io::Result<usize>)>),
+    /// The file was lost with an operation the pool failed to run.
+    Lost,
+}
+
+/// File whose reads and writes run on an `IoPool`.
+///
+/// Reads fill a buffer of the size of the caller's buffer on the pool and fail
+/// with `WouldBlock` until it's filled. Writes are copied and written behind,
+/// a write error is returned by the next write, flush or shutdown. Shutting
+/// down flushes the file. While the queue of the pool is full operations fail
+/// with `WouldBlock` too, the file is kept until there is room.
+pub struct Pooled<F> {
+    pool: IoPool,
+    state: State<F>,
+    buffer: Vec<u8>,
+    position: usize,
+    flushed: bool,
+}
+
+impl<F: Send + 'static> Pooled<F> {
+    /// Moves the operations on `file` to `pool`.
+    pub fn new(file: F, pool: IoPool) -> Pooled<F> {
+        Pooled {
+            pool: pool,
+            state: State::Idle(file),
+            buffer: Vec::new(),
+            position: 0,
+            flushed: true,
+        }
+    }
+
+    /// Starts `op` on the file and the buffer, fails with `WouldBlock` if the
+    /// queue of the pool is full.
+    fn start<O>(&mut self, op: O) -> io::Result<()>
+        where O: FnOnce(&mut F, &mut Vec<u8>) -> io::Result<usize> + Send + 'static,
+    {
+        let file = match mem::replace(&mut self.state, State::Lost) {
+            State::Idle(file) => file,
+            _ => return Err(lost()),
+        };
+        // The job takes the file once it runs, until then it can be taken
+        // back if the job isn't queued.
+        let handed = Arc::new(Mutex::new(Some((file, mem::replace(&mut self.buffer, Vec::new())))));
+        let taken = handed.clone();
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let taken = taken.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+            if let Some((mut file, mut buffer)) = taken {
+                let result = op(&mut file, &mut buffer);
+                let _ = sender.send(Ok((file, buffer, result)));
+            }
+        });
+        let queued = self.pool.queue_or_wait(job);
+        if let Ok(true) = queued {
+            self.state = State::Busy(Blocking(Ok(receiver)));
+            return Ok(())
+        }
+        let taken = handed.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        if let Some((file, buffer)) = taken {
+            self.state = State::Idle(file);
+            self.buffer = buffer;
+        }
+        match queued {
+            Ok(_) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Waits for the running operation, returns its result or `None` if no
+    /// operation ran.
+    fn finish(&mut self) -> io::Result<Option<usize>> {
+        let done = match self.state {
+            State::Idle(_) => return Ok(None),
+            State::Busy(ref mut op) => match op.poll() {
+                Ok(Async::Ready(done)) => Ok(done),
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+                Err(e) => Err(e),
+            },
+            State::Lost => return Err(lost()),
+        };
+        match done {
+            Ok((file, buffer, result)) => {
+                self.state = State::Idle(file);
+                self.buffer = buffer;
+                result.map(Some)
+            }
+            Err(e) => {
+                self.state = State::Lost;
+                Err(e)
+            }
+        }
+    }
+}
+
+fn lost() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "file lost by a failed I/O operation")
+}
+
+impl<F: Read + Send + 'static> Read for Pooled<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.buffer.len() {
+                let n = try!((&self.buffer[self.position..]).read(buf));
+                self.position += n;
+                return Ok(n)
+            }
+            if let Some(n) = try!(self.finish()) {
+                self.position = 0;
+                if n == 0 {
+                    return Ok(0)
+                }
+                continue
+            }
+            let length = buf.len();
+            try!(self.start(move |file, buffer| {
+                buffer.clear();
+                buffer.resize(length, 0);
+                let result = file.read(buffer);
+                buffer.truncate(*result.as_ref().unwrap_or(&0));
+                result
+            }));
+        }
+    }
+}
+
+impl<F: Read + Send + 'static> AsyncRead for Pooled<F> {}
+
+impl<F: Write + Send + 'static> Write for Pooled<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        try!(self.finish());
+        self.buffer.clear();
+        self.buffer.extend_from_slice(buf);
+        self.flushed = false;
+        try!(self.start(|file, buffer| file.write_all(buffer).map(|_| buffer.len())));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        loop {
+            try!(self.finish());
+            if self.flushed {
+                return Ok(())
+            }
+            try!(self.start(|file, _| file.flush().map(|_| 0)));
+            self.flushed = true;
+        }
+    }
+}
+
+impl<F: Write + Send + 'static> AsyncWrite for Pooled<F> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.flush());
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<F> fmt::Debug for Pooled<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pooled").field("pool", &self.pool).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Read, Write};
+    use std::mem;
+    use std::thread;
+    use std::time::Duration;
+
+    use futures::{Async, Future, Poll};
+    use futures::future;
+    use tokio_io::AsyncWrite;
+
+    use super::{IoPool, Pooled};
+
+    /// Drives `op` until it stops failing with `WouldBlock`.
+    fn wait<T, O: FnMut() -> io::Result<T>>(mut op: O) -> io::Result<T> {
+        future::poll_fn(|| { let result = try_nb!(op()); Ok(result.into()) }).wait()
+    }
+
+    #[test]
+    fn reads_and_writes_run_on_pool() {
+        let pool = IoPool::new(2, 4).unwrap();
+        let mut reader = Pooled::new(Cursor::new((0..100).collect::<Vec<u8>>()), pool.clone());
+        let mut read = Vec::new();
+        let mut buf = [0; 32];
+        loop {
+            let n = wait(|| reader.read(&mut buf)).unwrap();
+            if n == 0 {
+                break
+            }
+            read.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!((0..100).collect::<Vec<u8>>(), read);
+
+        let mut writer = Pooled::new(Vec::new(), pool);
+        wait(|| writer.write(b"hello ")).unwrap();
+        wait(|| writer.write(b"world")).unwrap();
+        future::poll_fn(|| writer.shutdown()).wait().unwrap();
+        match writer.state {
+            super::State::Idle(ref written) => assert_eq!(b"hello world", &written[..]),
+            _ => panic!("writer is not idle"),
+        }
+    }
+
+    #[test]
+    fn full_queue_rejects_operations() {
+        let pool = IoPool::new(1, 1).unwrap();
+        let running = pool.spawn(|| { thread::sleep(Duration::from_millis(200)); Ok(()) });
+        // Give the worker time to take the first operation off the queue.
+        thread::sleep(Duration::from_millis(50));
+        let queued = pool.spawn(|| Ok(()));
+        let rejected = pool.spawn(|| Ok(()));
+        assert!(rejected.wait().unwrap_err().to_string().contains("busy"));
+        running.wait().unwrap();
+        queued.wait().unwrap();
+    }
+
+    #[test]
+    fn full_queue_delays_pooled_operations() {
+        let pool = IoPool::new(1, 1).unwrap();
+        let running = pool.spawn(|| { thread::sleep(Duration::from_millis(100)); Ok(()) });
+        thread::sleep(Duration::from_millis(50));
+        let queued = pool.spawn(|| Ok(()));
+        // Both transfers find the queue full, they wait for room instead of
+        // losing their files.
+        let transfers: Vec<_> = (0..2u8).map(|i| {
+            let mut reader = Pooled::new(Cursor::new(vec![i; 100]), pool.clone());
+            let mut read = Vec::new();
+            future::poll_fn(move || -> Poll<Vec<u8>, io::Error> {
+                let mut buf = [0; 32];
+                loop {
+                    match try_nb!(reader.read(&mut buf)) {
+                        0 => return Ok(Async::Ready(mem::replace(&mut read, Vec::new()))),
+                        n => read.extend_from_slice(&buf[..n]),
+                    }
+                }
+            })
+        }).collect();
+        assert_eq!(vec![vec![0; 100], vec![1; 100]], future::join_all(transfers).wait().unwrap());
+        running.wait().unwrap();
+        queued.wait().unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+//! Types needed by most users of the crate, to be glob imported with
+//! `use tftp::prelude::*`.
+
+pub use config::{BlockSize, Retries, WindowSize};
+pub use error::{Error, ErrorKind};
+pub use packet::Mode;
+pub use transfer::TransferParams;
+
+#[cfg(feature = "mio-client")]
+pub use client::{Client, ClientBuilder};
+#[cfg(feature = "mio-client")]
+pub use oneshot::{get, put};
+#[cfg(feature = "tokio-client")]
+pub use async_client::AsyncClient;
+#[cfg(feature = "tokio-server")]
+pub use server::{Server, ServerBuilder};
+#[cfg(feature = "tokio-server")]
+pub use handler::{FsHandler, Handler};
@@ -0,0 +1,112 @@
+//! Rate limit of the error packets answering datagrams from unknown transfer
+//! IDs.
+//!
+//! A datagram arriving at the socket of a transfer from another address than
+//! the peer is answered with an unknown transfer ID error (RFC 1350). Source
+//! addresses of UDP datagrams are easily spoofed, without a limit a flood of
+//! spoofed datagrams makes the host send errors to the victim. A `RateLimit`
+//! allows a number of these errors per host and interval and suppresses the
+//! rest, `SocketStats::wrong_tid_suppressed` counts the suppressed errors.
+//!
+//! Hosts are told apart by their IP address, peers of transports without one,
+//! like Unix sockets, are not limited.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Errors a host gets per interval by default.
+pub const DEFAULT_LIMIT: u32 = 5;
+
+/// Interval of the default limit.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Hosts whose errors are counted, errors to further hosts are suppressed
+/// until the interval of a counted host ended.
+const MAX_HOSTS: usize = 1024;
+
+/// Limit of the errors sent to each host, clones share the counts.
+#[derive(Debug, Clone)]
+pub struct RateLimit(Arc<Mutex<Limiter>>);
+
+#[derive(Debug)]
+struct Limiter {
+    limit: u32,
+    interval: Duration,
+    /// Start of the current interval of each host and the errors sent to it.
+    hosts: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimit {
+    /// Creates a limit of `limit` errors per host and `interval`.
+    pub fn new(limit: u32, interval: Duration) -> RateLimit {
+        RateLimit(Arc::new(Mutex::new(Limiter {
+            limit: limit,
+            interval: interval,
+            hosts: HashMap::new(),
+        })))
+    }
+
+    /// Returns `true` and counts the error if an error may be sent to `host`.
+    pub fn allow(&self, host: IpAddr) -> bool {
+        self.allow_at(host, Instant::now())
+    }
+
+    fn allow_at(&self, host: IpAddr, now: Instant) -> bool {
+        let mut limiter = self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let interval = limiter.interval;
+        if limiter.hosts.len() >= MAX_HOSTS && !limiter.hosts.contains_key(&host) {
+            limiter.hosts.retain(|_, &mut (start, _)| now.duration_since(start) < interval);
+            if limiter.hosts.len() >= MAX_HOSTS {
+                return false
+            }
+        }
+        let limit = limiter.limit;
+        let entry = limiter.hosts.entry(host).or_insert((now, 0));
+        if now.duration_since(entry.0) >= interval {
+            *entry = (now, 0);
+        }
+        if entry.1 >= limit {
+            return false
+        }
+        entry.1 += 1;
+        true
+    }
+}
+
+impl Default for RateLimit {
+    /// Returns a limit of 5 errors per host and second.
+    fn default() -> RateLimit {
+        RateLimit::new(DEFAULT_LIMIT, DEFAULT_INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimit, MAX_HOSTS};
+
+    #[test]
+    fn errors_are_limited_per_host_and_interval() {
+        let limit = RateLimit::new(2, Duration::from_secs(1));
+        let start = Instant::now();
+        let victim: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(limit.allow_at(victim, start));
+        assert!(limit.clone().allow_at(victim, start));
+        assert!(!limit.allow_at(victim, start + Duration::from_millis(500)));
+        assert!(limit.allow_at(other, start + Duration::from_millis(500)));
+        assert!(limit.allow_at(victim, start + Duration::from_secs(1)));
+
+        // A flood from many hosts doesn't grow the counts without bound.
+        let flood = start + Duration::from_secs(5);
+        for i in 0..MAX_HOSTS as u32 {
+            assert!(limit.allow_at(IpAddr::from([10, (i >> 16) as u8, (i >> 8) as u8, i as u8]), flood));
+        }
+        assert!(!limit.allow_at(victim, flood));
+        assert!(limit.allow_at(victim, flood + Duration::from_secs(1)));
+    }
+}
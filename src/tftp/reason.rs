@@ -0,0 +1,264 @@
+//! Reason codes of the error packets the crate sends itself.
+//!
+//! The message of an error packet the client or the server generates starts
+//! with a prefix naming the cause, e.g. `[unknown-tid] unknown transfer id`,
+//! so the causes can be counted from the logs of the peers. `ReasonFormat`
+//! replaces the prefixes or leaves them out. Messages of the handlers and of
+//! aborted transfers are sent as they are.
+//!
+//! The English messages themselves can be replaced by a `MessageProvider`,
+//! e.g. with terse codes for machine peers or with localized text for tools
+//! showing them to operators. The prefix is added to the provided message.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use packet::{self, ErrorPacket};
+
+/// Cause of an error packet sent by the crate.
+#[derive(Debug, Eq, PartialEq, Hash, Clone, Copy)]
+pub enum Reason {
+    /// A host or port that is not the peer sent a packet to a transfer.
+    UnknownTransferId,
+
+    /// The peer requested or acknowledged options that can't be accepted.
+    OptionNegotiation,
+
+    /// The peer sent a packet that is not valid in the state of the transfer.
+    UnexpectedPacket,
+
+    /// The transfer didn't complete before its deadline.
+    DeadlineExceeded,
+
+    /// The requested file name is not valid.
+    InvalidFileName,
+
+    /// The server doesn't accept writes.
+    ReadOnly,
+
+    /// The file is larger than the configured limit.
+    TooLarge,
+
+    /// The file couldn't be opened.
+    OpenFailed,
+
+    /// Reading or writing the local file failed during the transfer.
+    LocalError,
+
+    /// A probe ended the transfer once it learned what it asked for.
+    Probe,
+
+    /// The peer sent a datagram longer than the transfer allows.
+    OversizedPacket,
+
+    /// The transfer is abandoned to start it again with a smaller block size.
+    Restarted,
+}
+
+impl Reason {
+    /// Returns the code naming the reason in the standard prefix.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            Reason::UnknownTransferId => "unknown-tid",
+            Reason::OptionNegotiation => "options",
+            Reason::UnexpectedPacket => "unexpected-packet",
+            Reason::DeadlineExceeded => "deadline",
+            Reason::InvalidFileName => "invalid-filename",
+            Reason::ReadOnly => "read-only",
+            Reason::TooLarge => "too-large",
+            Reason::OpenFailed => "open-failed",
+            Reason::LocalError => "local-error",
+            Reason::Probe => "probe",
+            Reason::OversizedPacket => "oversized-packet",
+            Reason::Restarted => "restarted",
+        }
+    }
+
+    /// Splits a message with a standard prefix into the reason and the rest
+    /// of the message, e.g. to aggregate the errors received from a peer.
+    pub fn parse_message(message: &str) -> Option<(Reason, &str)> {
+        if !message.starts_with('[') {
+            return None
+        }
+        let end = match message.find("] ") {
+            Some(end) => end,
+            None => return None,
+        };
+        message[1..end].parse().ok().map(|reason| (reason, &message[end + 2..]))
+    }
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.code())
+    }
+}
+
+const REASONS: [Reason; 12] = [Reason::UnknownTransferId, Reason::OptionNegotiation, Reason::UnexpectedPacket,
+                               Reason::DeadlineExceeded, Reason::InvalidFileName, Reason::ReadOnly, Reason::TooLarge,
+                               Reason::OpenFailed, Reason::LocalError, Reason::Probe, Reason::OversizedPacket,
+                               Reason::Restarted];
+
+/// Error returned when parsing an unknown reason code.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct ParseReasonError(String);
+
+impl fmt::Display for ParseReasonError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown reason code: {}", self.0)
+    }
+}
+
+impl FromStr for Reason {
+    type Err = ParseReasonError;
+
+    fn from_str(s: &str) -> Result<Reason, ParseReasonError> {
+        REASONS.iter().cloned().find(|reason| reason.code() == s).ok_or_else(|| ParseReasonError(s.to_owned()))
+    }
+}
+
+/// Source of the messages of the error packets sent by the crate.
+pub trait MessageProvider: Send + Sync {
+    /// Returns the message sent for `reason`.
+    ///
+    /// `default` is the English message the crate sends otherwise, it can
+    /// include details like the error of the local file.
+    fn message(&self, reason: Reason, default: &str) -> String;
+}
+
+impl<F: Fn(Reason, &str) -> String + Send + Sync> MessageProvider for F {
+    fn message(&self, reason: Reason, default: &str) -> String {
+        self(reason, default)
+    }
+}
+
+/// Prefixes and messages of the error packets sent by the crate.
+///
+/// By default every message starts with `[code] `, see `Reason::code`.
+#[derive(Clone)]
+pub struct ReasonFormat {
+    enabled: bool,
+    custom: Vec<(Reason, String)>,
+    messages: Option<Arc<MessageProvider>>,
+}
+
+impl Default for ReasonFormat {
+    fn default() -> ReasonFormat {
+        ReasonFormat {
+            enabled: true,
+            custom: Vec::new(),
+            messages: None,
+        }
+    }
+}
+
+impl fmt::Debug for ReasonFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReasonFormat")
+            .field("enabled", &self.enabled)
+            .field("custom", &self.custom)
+            .field("messages", &self.messages.is_some())
+            .finish()
+    }
+}
+
+/// Formats are equal if they use the same prefixes and the same provider.
+impl PartialEq for ReasonFormat {
+    fn eq(&self, other: &ReasonFormat) -> bool {
+        let same_messages = match (&self.messages, &other.messages) {
+            (&Some(ref a), &Some(ref b)) => Arc::ptr_eq(a, b),
+            (&None, &None) => true,
+            _ => false,
+        };
+        self.enabled == other.enabled && self.custom == other.custom && same_messages
+    }
+}
+
+impl Eq for ReasonFormat {}
+
+impl ReasonFormat {
+    /// Creates a format using the standard prefixes.
+    pub fn new() -> ReasonFormat {
+        ReasonFormat::default()
+    }
+
+    /// Creates a format sending the messages without prefixes.
+    pub fn plain() -> ReasonFormat {
+        ReasonFormat {
+            enabled: false,
+            custom: Vec::new(),
+            messages: None,
+        }
+    }
+
+    /// Uses `prefix` for the messages of `reason` instead of the standard
+    /// prefix, also when the other prefixes are left out.
+    pub fn prefix<S: Into<String>>(mut self, reason: Reason, prefix: S) -> ReasonFormat {
+        self.custom.retain(|&(custom, _)| custom != reason);
+        self.custom.push((reason, prefix.into()));
+        self
+    }
+
+    /// Sends the messages of `provider` instead of the English ones.
+    pub fn messages<M: MessageProvider + 'static>(mut self, provider: M) -> ReasonFormat {
+        self.messages = Some(Arc::new(provider));
+        self
+    }
+
+    /// Returns `message`, or the message of the provider, with the prefix of `reason`.
+    pub fn message(&self, reason: Reason, message: &str) -> String {
+        let provided;
+        let message = match self.messages {
+            Some(ref provider) => {
+                provided = provider.message(reason, message);
+                &provided[..]
+            }
+            None => message,
+        };
+        match self.custom.iter().find(|&&(custom, _)| custom == reason) {
+            Some(&(_, ref prefix)) => format!("{}{}", prefix, message),
+            None if self.enabled => format!("[{}] {}", reason.code(), message),
+            None => message.to_owned(),
+        }
+    }
+
+    /// Returns an error packet for `reason` with a prefixed message.
+    pub fn error_packet(&self, error: packet::Error, reason: Reason, message: &str) -> ErrorPacket<'static> {
+        ErrorPacket::new(error, &self.message(reason, message)).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Reason, ReasonFormat};
+
+    #[test]
+    fn messages_are_prefixed_with_reason() {
+        let message = ReasonFormat::new().message(Reason::UnknownTransferId, "unknown transfer id");
+        assert_eq!("[unknown-tid] unknown transfer id", message);
+        assert_eq!(Some((Reason::UnknownTransferId, "unknown transfer id")), Reason::parse_message(&message));
+        assert_eq!(None, Reason::parse_message("[bogus] message"));
+        assert_eq!(None, Reason::parse_message("File not found"));
+
+        let format = ReasonFormat::plain().prefix(Reason::ReadOnly, "E403 ");
+        assert_eq!("E403 server is read-only", format.message(Reason::ReadOnly, "server is read-only"));
+        assert_eq!("file too large", format.message(Reason::TooLarge, "file too large"));
+    }
+
+    #[test]
+    fn messages_are_replaced_by_provider() {
+        let terse = ReasonFormat::plain().messages(|reason: Reason, _: &str| reason.code().to_uppercase());
+        assert_eq!("UNKNOWN-TID", terse.message(Reason::UnknownTransferId, "unknown transfer id"));
+
+        let localized = ReasonFormat::new().messages(|reason, default: &str| match reason {
+            Reason::ReadOnly => "Server nimmt keine Dateien an".to_owned(),
+            _ => default.to_owned(),
+        });
+        assert_eq!("[read-only] Server nimmt keine Dateien an",
+                   localized.message(Reason::ReadOnly, "server is read-only"));
+        assert_eq!("[too-large] file too large", localized.message(Reason::TooLarge, "file too large"));
+        assert_eq!(localized.clone(), localized);
+        assert!(localized != ReasonFormat::new());
+    }
+}
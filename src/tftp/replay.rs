@@ -0,0 +1,452 @@
+//! Deterministic replay of captured sessions.
+//!
+//! A `Capture` is a sequence of datagrams recorded on one side of a transfer
+//! together with their direction and time offset from the start of the session.
+//! Replaying a capture feeds the received datagrams to the transfer state machines
+//! and checks that the datagrams they produce match the recorded sent ones, which
+//! turns a capture from a bug report into a regression test.
+//!
+//! The retransmission timer of the transfer runs on the clock of the capture:
+//! the timeout expires once a record is later than the timeout after the last
+//! datagram the state machine produced, and the retransmitted datagram must
+//! be recorded then. Gaps in the capture, and retransmissions recorded too
+//! early or missing, are reproduced like the datagrams.
+//!
+//! Capture format is a sequence of records, each encoded as:
+//!
+//! ```text
+//! direction (u8) | timestamp in microseconds (u64) | length (u16) | datagram
+//! ```
+//!
+//! All integers are big endian, direction is `0` for sent and `1` for received datagrams.
+
+extern crate byteorder;
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use packet::{AckPacket, DataPacketOctet, DecodePacket, EncodePacket, Opcode};
+use transfer::{ReadTransfer, WriteTransfer, DataReceived, AckReceived, Timeout, TransferParams};
+
+use self::byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+/// Direction of a captured datagram, relative to the capturing side.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Direction {
+    /// Datagram was sent to the remote side.
+    Sent,
+
+    /// Datagram was received from the remote side.
+    Received,
+}
+
+/// A single captured datagram.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Record {
+    /// Time elapsed since the start of the session.
+    pub timestamp: Duration,
+
+    /// Direction of the datagram.
+    pub direction: Direction,
+
+    /// Raw datagram bytes.
+    pub datagram: Vec<u8>,
+}
+
+impl Record {
+    /// Creates a new record.
+    pub fn new(timestamp: Duration, direction: Direction, datagram: Vec<u8>) -> Record {
+        Record {
+            timestamp: timestamp,
+            direction: direction,
+            datagram: datagram,
+        }
+    }
+}
+
+/// Datagrams captured during one session, ordered by time.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct Capture {
+    records: Vec<Record>,
+}
+
+impl Capture {
+    /// Creates an empty capture.
+    pub fn new() -> Capture {
+        Capture {
+            records: Vec::new(),
+        }
+    }
+
+    /// Appends a record to the capture.
+    pub fn push(&mut self, record: Record) {
+        self.records.push(record);
+    }
+
+    /// Returns all records in the capture.
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    /// Reads a capture stored in the crate's capture format.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Capture> {
+        let mut capture = Capture::new();
+        loop {
+            let direction = match reader.read_u8() {
+                Ok(0) => Direction::Sent,
+                Ok(1) => Direction::Received,
+                Ok(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid direction")),
+                Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(capture),
+                Err(e) => return Err(e),
+            };
+            let micros = try!(reader.read_u64::<BigEndian>());
+            let len = try!(reader.read_u16::<BigEndian>());
+            let mut datagram = vec![0; len as usize];
+            try!(reader.read_exact(&mut datagram));
+            let timestamp = Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1000);
+            capture.push(Record::new(timestamp, direction, datagram));
+        }
+    }
+
+    /// Writes the capture using the crate's capture format.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for record in &self.records {
+            let direction = match record.direction {
+                Direction::Sent => 0,
+                Direction::Received => 1,
+            };
+            let micros = record.timestamp.as_secs() * 1_000_000 +
+                         (record.timestamp.subsec_nanos() / 1000) as u64;
+            try!(writer.write_u8(direction));
+            try!(writer.write_u64::<BigEndian>(micros));
+            try!(writer.write_u16::<BigEndian>(record.datagram.len() as u16));
+            try!(writer.write_all(&record.datagram));
+        }
+        Ok(())
+    }
+}
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ReplayError {
+        Io(err: io::Error) {
+            from()
+            description("io error")
+            display("I/O error: {}", err)
+            cause(err)
+        }
+        OutOfOrder(index: usize) {
+            description("record out of order")
+            display("Record {} has a timestamp earlier than the previous record", index)
+        }
+        UnexpectedPacket(index: usize) {
+            description("unexpected packet")
+            display("Record {} is not a packet the transfer can handle", index)
+        }
+        Mismatch(index: usize, expected: Vec<u8>, actual: Option<Vec<u8>>) {
+            description("sent datagram mismatch")
+            display("Record {}: expected datagram {:?}, state machine produced {:?}", index, expected, actual)
+        }
+        Incomplete {
+            description("incomplete transfer")
+            display("Capture ended before the transfer was complete")
+        }
+        TimedOut(index: usize) {
+            description("transfer timed out")
+            display("Record {} came after the transfer gave up retransmitting", index)
+        }
+    }
+}
+
+/// Result of replaying a read transfer.
+pub type ReplayResult<T> = Result<T, ReplayError>;
+
+struct Expected {
+    produced: VecDeque<Vec<u8>>,
+    last_timestamp: Duration,
+    /// Datagram produced last, sent again when the timeout expires.
+    last_produced: Vec<u8>,
+    timeout: Duration,
+    /// Time the retransmission timeout expires, `None` if no datagram awaits
+    /// a response.
+    deadline: Option<Duration>,
+}
+
+impl Expected {
+    fn new(timeout: Duration) -> Expected {
+        Expected {
+            produced: VecDeque::new(),
+            last_timestamp: Duration::new(0, 0),
+            last_produced: Vec::new(),
+            timeout: timeout,
+            deadline: None,
+        }
+    }
+
+    fn check_order(&mut self, index: usize, record: &Record) -> ReplayResult<()> {
+        if record.timestamp < self.last_timestamp {
+            return Err(ReplayError::OutOfOrder(index))
+        }
+        self.last_timestamp = record.timestamp;
+        Ok(())
+    }
+
+    /// Records a datagram the state machine produced at `now`, the timeout
+    /// starts again.
+    fn produce(&mut self, datagram: Vec<u8>, now: Duration) {
+        self.produced.push_back(datagram.clone());
+        self.last_produced = datagram;
+        self.deadline = Some(now + self.timeout);
+    }
+
+    /// Expires the timeouts up to the time of the record `index`, the last
+    /// datagram is sent again on every expired timeout.
+    fn expire<F: FnMut() -> Timeout>(&mut self, index: usize, now: Duration, mut timeout: F) -> ReplayResult<()> {
+        while let Some(deadline) = self.deadline {
+            if deadline > now {
+                break
+            }
+            match timeout() {
+                Timeout::Retransmit => {
+                    self.produced.push_back(self.last_produced.clone());
+                    self.deadline = Some(deadline + self.timeout);
+                }
+                Timeout::Failed => return Err(ReplayError::TimedOut(index)),
+                // Replayed transfers send one block at a time without a fallback.
+                Timeout::Rewind(_) | Timeout::Fallback(_) => unreachable!(),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_sent(&mut self, index: usize, record: &Record) -> ReplayResult<()> {
+        if is_request(&record.datagram) {
+            // Requests are sent by the front-ends, not by the state machines.
+            return Ok(())
+        }
+        match self.produced.pop_front() {
+            Some(ref actual) if *actual == record.datagram => Ok(()),
+            actual => Err(ReplayError::Mismatch(index, record.datagram.clone(), actual)),
+        }
+    }
+}
+
+fn is_request(datagram: &[u8]) -> bool {
+    datagram.len() >= 2 && (datagram[1] == Opcode::RRQ as u8 || datagram[1] == Opcode::WRQ as u8)
+        && datagram[0] == 0
+}
+
+/// Replays a capture taken on the receiving side of a transfer running with
+/// the block size and the timeout of `params`.
+///
+/// Returns the data that would be written out by the receiver.
+pub fn replay_read(capture: &Capture, params: TransferParams) -> ReplayResult<Vec<u8>> {
+    let mut transfer = ReadTransfer::new(params.block_size);
+    let mut expected = Expected::new(params.timeout);
+    let mut output = Vec::new();
+    for (index, record) in capture.records().iter().enumerate() {
+        try!(expected.check_order(index, record));
+        try!(expected.expire(index, record.timestamp, || transfer.timeout()));
+        match record.direction {
+            Direction::Received => {
+                let packet = match DataPacketOctet::decode(&record.datagram) {
+                    Some(packet) => packet,
+                    None => return Err(ReplayError::UnexpectedPacket(index)),
+                };
+                match transfer.receive_data(&packet) {
+                    DataReceived::Accepted(ack) => {
+                        output.extend_from_slice(packet.data());
+                        expected.produce(ack.encode().packet_buf().to_vec(), record.timestamp);
+                    }
+                    DataReceived::Duplicate(ack) => {
+                        expected.produce(ack.encode().packet_buf().to_vec(), record.timestamp)
+                    }
+                    DataReceived::Ignored => {}
+                }
+                if transfer.is_done() {
+                    expected.deadline = None;
+                }
+            }
+            Direction::Sent => try!(expected.check_sent(index, record)),
+        }
+    }
+    if transfer.is_done() && expected.produced.is_empty() {
+        Ok(output)
+    } else {
+        Err(ReplayError::Incomplete)
+    }
+}
+
+/// Replays a capture taken on the sending side of a transfer running with
+/// the block size and the timeout of `params`.
+///
+/// Data blocks are read from `data`. When `write_request` is `true` the sender
+/// waits for the acknowledgment of block 0 before sending the first block,
+/// otherwise it's sent at the start of the capture.
+pub fn replay_write<R: Read>(capture: &Capture, params: TransferParams, write_request: bool,
+                             data: &mut R) -> ReplayResult<()> {
+    let mut transfer = WriteTransfer::new(params.block_size);
+    let mut expected = Expected::new(params.timeout);
+    if !write_request {
+        let packet = try!(transfer.next_block(data)).encode();
+        expected.produce(packet.packet_buf().to_vec(), Duration::new(0, 0));
+    }
+    for (index, record) in capture.records().iter().enumerate() {
+        try!(expected.check_order(index, record));
+        try!(expected.expire(index, record.timestamp, || transfer.timeout()));
+        match record.direction {
+            Direction::Received => {
+                let packet = match AckPacket::decode(&record.datagram) {
+                    Some(packet) => packet,
+                    None => return Err(ReplayError::UnexpectedPacket(index)),
+                };
+                match transfer.receive_ack(&packet) {
+                    AckReceived::Next => {
+                        let packet = try!(transfer.next_block(data)).encode();
+                        expected.produce(packet.packet_buf().to_vec(), record.timestamp);
+                    }
+                    AckReceived::Done => expected.deadline = None,
+                    AckReceived::Ignored | AckReceived::Rewind(_) => {}
+                }
+            }
+            Direction::Sent => try!(expected.check_sent(index, record)),
+        }
+    }
+    if transfer.is_done() && expected.produced.is_empty() {
+        Ok(())
+    } else {
+        Err(ReplayError::Incomplete)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use packet::{AckPacket, DataPacketOctet, EncodePacket, Mode, RequestPacket};
+    use transfer::TransferParams;
+
+    use super::{Capture, Record, Direction, ReplayError, replay_read, replay_write};
+
+    fn params() -> TransferParams {
+        TransferParams::new(4, Duration::from_secs(1))
+    }
+
+    fn record(millis: u64, direction: Direction, packet: &EncodePacket) -> Record {
+        Record::new(Duration::from_millis(millis), direction, packet.encode().packet_buf().to_vec())
+    }
+
+    fn read_session() -> Capture {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Sent, &RequestPacket::read_request("foo", Mode::Octet)));
+        capture.push(record(1, Direction::Received, &DataPacketOctet::from_slice(1, b"abcd")));
+        capture.push(record(2, Direction::Sent, &AckPacket::new(1)));
+        capture.push(record(3, Direction::Received, &DataPacketOctet::from_slice(2, b"ef")));
+        capture.push(record(4, Direction::Sent, &AckPacket::new(2)));
+        capture
+    }
+
+    #[test]
+    fn capture_format_roundtrip() {
+        let capture = read_session();
+        let mut buf = Vec::new();
+        capture.write_to(&mut buf).unwrap();
+        assert_eq!(capture, Capture::read_from(&mut Cursor::new(buf)).unwrap());
+    }
+
+    #[test]
+    fn read_session_is_replayed() {
+        assert_eq!(b"abcdef".to_vec(), replay_read(&read_session(), params()).unwrap());
+    }
+
+    #[test]
+    fn read_session_with_wrong_ack_is_detected() {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Received, &DataPacketOctet::from_slice(1, b"ab")));
+        capture.push(record(1, Direction::Sent, &AckPacket::new(2)));
+        match replay_read(&capture, params()) {
+            Err(ReplayError::Mismatch(1, _, Some(_))) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn incomplete_read_session_is_detected() {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Received, &DataPacketOctet::from_slice(1, b"abcd")));
+        match replay_read(&capture, params()) {
+            Err(ReplayError::Incomplete) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn write_session_is_replayed() {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Sent, &DataPacketOctet::from_slice(1, b"abcd")));
+        capture.push(record(1, Direction::Received, &AckPacket::new(1)));
+        capture.push(record(2, Direction::Sent, &DataPacketOctet::from_slice(2, b"")));
+        capture.push(record(3, Direction::Received, &AckPacket::new(2)));
+        replay_write(&capture, params(), false, &mut Cursor::new(b"abcd".to_vec())).unwrap();
+    }
+
+    #[test]
+    fn read_session_with_retransmission_is_replayed() {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Received, &DataPacketOctet::from_slice(1, b"abcd")));
+        capture.push(record(2, Direction::Sent, &AckPacket::new(1)));
+        capture.push(record(1002, Direction::Sent, &AckPacket::new(1)));
+        capture.push(record(1500, Direction::Received, &DataPacketOctet::from_slice(2, b"ef")));
+        capture.push(record(1501, Direction::Sent, &AckPacket::new(2)));
+        assert_eq!(b"abcdef".to_vec(), replay_read(&capture, params()).unwrap());
+    }
+
+    #[test]
+    fn early_retransmission_is_detected() {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Received, &DataPacketOctet::from_slice(1, b"abcd")));
+        capture.push(record(2, Direction::Sent, &AckPacket::new(1)));
+        capture.push(record(500, Direction::Sent, &AckPacket::new(1)));
+        match replay_read(&capture, params()) {
+            Err(ReplayError::Mismatch(2, _, None)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_retransmission_is_detected() {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Received, &DataPacketOctet::from_slice(1, b"abcd")));
+        capture.push(record(2, Direction::Sent, &AckPacket::new(1)));
+        capture.push(record(1500, Direction::Received, &DataPacketOctet::from_slice(2, b"ef")));
+        capture.push(record(1501, Direction::Sent, &AckPacket::new(2)));
+        match replay_read(&capture, params()) {
+            Err(ReplayError::Mismatch(3, _, Some(_))) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn write_session_with_retransmission_is_replayed() {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Sent, &DataPacketOctet::from_slice(1, b"abcd")));
+        capture.push(record(1000, Direction::Sent, &DataPacketOctet::from_slice(1, b"abcd")));
+        capture.push(record(1100, Direction::Received, &AckPacket::new(1)));
+        capture.push(record(1101, Direction::Sent, &DataPacketOctet::from_slice(2, b"")));
+        capture.push(record(1102, Direction::Received, &AckPacket::new(2)));
+        replay_write(&capture, params(), false, &mut Cursor::new(b"abcd".to_vec())).unwrap();
+    }
+
+    #[test]
+    fn gap_past_the_retries_times_out() {
+        let mut capture = Capture::new();
+        capture.push(record(0, Direction::Sent, &DataPacketOctet::from_slice(1, b"abcd")));
+        capture.push(record(60000, Direction::Received, &AckPacket::new(1)));
+        match replay_write(&capture, params(), false, &mut Cursor::new(b"abcd".to_vec())) {
+            Err(ReplayError::TimedOut(1)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Transfers continuing after the address of the client changed (experimental).
+//!
+//! The server knows the client of a transfer by its address. A client that is
+//! renumbered during a transfer, e.g. by DHCP while it boots, loses the
+//! transfer: its packets arrive at the transfer socket of the server from an
+//! unknown transfer identifier and are rejected with an error.
+//!
+//! A client asks for a session token with the `x-session` option of its
+//! request, the server acknowledges the option with an opaque token. Once the
+//! server rejects a packet of the client as coming from an unknown transfer
+//! identifier, the client sends its request again to the transfer socket, with
+//! the token as its only option. The server continues the transfer with the
+//! new address of the client and sends its last packet again.
+//!
+//! Tokens keep hosts that don't see the traffic of a transfer from taking it
+//! over, they are no protection against hosts that do. Snapshots of the server
+//! record the address a transfer started from.
+//!
+//! This isn't a standardized protocol, both ends have to use this crate.
+
+use std::fmt;
+use std::str::FromStr;
+
+use packet::{DecodePacket, EncodePacket, OptionAckPacket, RequestPacket, TransferOptions};
+use rng::SharedRng;
+
+/// Option asking for a session token, acknowledged with the token.
+pub const SESSION_OPTION: &'static str = "x-session";
+
+/// Value of the option in the request of a new transfer.
+const REQUESTED: &'static str = "1";
+
+/// Opaque token of a transfer that may continue from another address.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct Token(u64);
+
+impl Token {
+    /// Draws a new token from `rng`.
+    pub fn generate(rng: &SharedRng) -> Token {
+        Token(rng.next_u64())
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for Token {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Token, ()> {
+        if s.len() != 16 {
+            return Err(())
+        }
+        u64::from_str_radix(s, 16).map(Token).map_err(|_| ())
+    }
+}
+
+/// Adds the option asking for a session token to the options of a request.
+pub fn request_token(options: &mut TransferOptions) {
+    options.insert(SESSION_OPTION, REQUESTED);
+}
+
+/// Acknowledges the session token `requested` asks for with a new one drawn
+/// from `rng`, returns the option acknowledgment and the token.
+pub fn acknowledge_token(oack: Option<OptionAckPacket<'static>>, requested: &TransferOptions, rng: &SharedRng)
+                         -> (Option<OptionAckPacket<'static>>, Option<Token>) {
+    if requested.get(SESSION_OPTION) != Some(REQUESTED) {
+        return (oack, None)
+    }
+    let token = Token::generate(rng);
+    let mut acknowledged = oack.map(|oack| oack.options().clone()).unwrap_or_default();
+    acknowledged.insert(SESSION_OPTION, token.to_string());
+    (Some(OptionAckPacket::new(acknowledged)), Some(token))
+}
+
+/// Returns the request continuing the transfer of `request` from another
+/// address, if the server acknowledged a session token.
+pub fn resume_request(request: &[u8], acknowledged: &TransferOptions) -> Option<Vec<u8>> {
+    let token = acknowledged.get(SESSION_OPTION).and_then(|value| value.parse::<Token>().ok());
+    token.and_then(|token| RequestPacket::decode(request).map(|request| {
+        let mut options = TransferOptions::new();
+        options.insert(SESSION_OPTION, token.to_string());
+        request.with_options(options).encode().packet_buf().to_vec()
+    }))
+}
+
+/// Returns `true` if `datagram` is a request continuing the transfer of `token`.
+pub fn resumes(datagram: &[u8], token: Token) -> bool {
+    RequestPacket::decode(datagram).and_then(|request| {
+        request.options().get(SESSION_OPTION).and_then(|value| value.parse::<Token>().ok())
+    }) == Some(token)
+}
+
+#[cfg(test)]
+mod test {
+    use packet::{EncodePacket, Mode, OptionAckPacket, RequestPacket, TransferOptions};
+    use rng::SharedRng;
+    use super::{Token, SESSION_OPTION, acknowledge_token, request_token, resume_request, resumes};
+
+    #[test]
+    fn acknowledged_tokens_continue_their_transfer_only() {
+        let mut requested = TransferOptions::new();
+        request_token(&mut requested);
+        let mut blksize = TransferOptions::new();
+        blksize.insert("blksize", "1024");
+        let rng = SharedRng::seeded(7);
+        let (oack, token) = acknowledge_token(Some(OptionAckPacket::new(blksize)), &requested, &rng);
+        let (oack, token) = (oack.unwrap(), token.unwrap());
+        assert_eq!(Some("1024"), oack.options().get("blksize"));
+        assert_eq!(Ok(token), oack.options().get(SESSION_OPTION).unwrap().parse());
+        assert_eq!((None, None), acknowledge_token(None, &TransferOptions::new(), &rng));
+
+        let request = RequestPacket::read_request("boot.img", Mode::Octet).with_options(requested).encode();
+        let resume = resume_request(request.packet_buf(), oack.options()).unwrap();
+        assert!(resumes(&resume, token));
+        assert!(!resumes(&resume, Token::generate(&rng)));
+        assert!(!resumes(request.packet_buf(), token));
+        assert_eq!(None, resume_request(request.packet_buf(), &TransferOptions::new()));
+    }
+}
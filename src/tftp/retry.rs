@@ -0,0 +1,159 @@
+//! Classification of failed transfers into retryable and fatal ones.
+//!
+//! Layers retrying a transfer, or failing over to another server, consult an
+//! `ErrorClassifier` to decide whether repeating the request can succeed. Error
+//! packets are classified by a table of rules matching the error code and,
+//! optionally, the message, since many servers report transient conditions like
+//! being busy with the undefined error code (0) and a message only.
+
+use std::time::Duration;
+
+use error::{Error, ErrorKind};
+use packet::{self, ErrorPacket};
+
+/// Delay before retrying a request a server refused because it's busy.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Outcome of classifying a failed transfer.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ErrorClass {
+    /// The request may succeed when repeated after the delay, on the same or
+    /// on another server.
+    Retryable(Duration),
+
+    /// Repeating the request fails the same way.
+    Fatal,
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    code: packet::Error,
+    /// Lowercase text the message has to contain, any message matches if `None`.
+    message: Option<String>,
+    class: ErrorClass,
+}
+
+/// Table of rules classifying error packets of servers.
+///
+/// Rules are tried in the order they were added and the first matching rule
+/// decides, errors no rule matches get the default class. The default table
+/// retries busy servers after `DEFAULT_RETRY_DELAY` and stray packets (unknown
+/// transfer ID) right away, all other errors are fatal.
+#[derive(Debug, Clone)]
+pub struct ErrorClassifier {
+    rules: Vec<Rule>,
+    default: ErrorClass,
+}
+
+impl ErrorClassifier {
+    /// Creates a table without rules classifying all errors as `default`.
+    pub fn new(default: ErrorClass) -> ErrorClassifier {
+        ErrorClassifier {
+            rules: Vec::new(),
+            default: default,
+        }
+    }
+
+    /// Adds a rule classifying all errors with `code` as `class`.
+    pub fn rule(mut self, code: packet::Error, class: ErrorClass) -> ErrorClassifier {
+        self.rules.push(Rule {
+            code: code,
+            message: None,
+            class: class,
+        });
+        self
+    }
+
+    /// Adds a rule classifying errors with `code` whose message contains
+    /// `text`, ignoring case, as `class`.
+    pub fn message_rule(mut self, code: packet::Error, text: &str, class: ErrorClass) -> ErrorClassifier {
+        self.rules.push(Rule {
+            code: code,
+            message: Some(text.to_lowercase()),
+            class: class,
+        });
+        self
+    }
+
+    /// Classifies an error packet sent by a server.
+    pub fn classify(&self, error: &ErrorPacket) -> ErrorClass {
+        let message = error.message().map(|message| message.to_lowercase()).unwrap_or_default();
+        self.rules.iter()
+            .find(|rule| {
+                rule.code == error.error() && rule.message.as_ref().map_or(true, |text| message.contains(&text[..]))
+            })
+            .map_or(self.default, |rule| rule.class)
+    }
+
+    /// Classifies a failed transfer.
+    ///
+    /// Server errors are classified by the table, a server that stopped
+    /// responding may be retried right away and one whose port is closed after
+    /// `DEFAULT_RETRY_DELAY`, e.g. while it restarts. Other failures are fatal.
+    pub fn classify_error(&self, error: &Error) -> ErrorClass {
+        match error.kind() {
+            ErrorKind::ServerError(code) => match error.get_ref().downcast_ref::<ErrorPacket<'static>>() {
+                Some(packet) => self.classify(packet),
+                None => self.classify(&ErrorPacket::new(code, "")),
+            },
+            ErrorKind::TimedOut => ErrorClass::Retryable(Duration::from_secs(0)),
+            ErrorKind::PortUnreachable => ErrorClass::Retryable(DEFAULT_RETRY_DELAY),
+            _ => ErrorClass::Fatal,
+        }
+    }
+}
+
+impl Default for ErrorClassifier {
+    fn default() -> ErrorClassifier {
+        let busy = ErrorClass::Retryable(DEFAULT_RETRY_DELAY);
+        ErrorClassifier::new(ErrorClass::Fatal)
+            .message_rule(packet::Error::Undefined, "busy", busy)
+            .message_rule(packet::Error::Undefined, "try again", busy)
+            .message_rule(packet::Error::Undefined, "too many", busy)
+            .rule(packet::Error::UnknownTransferId, ErrorClass::Retryable(Duration::from_secs(0)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use error::{Error, ErrorKind};
+    use packet::{self, ErrorPacket};
+
+    use super::{ErrorClass, ErrorClassifier, DEFAULT_RETRY_DELAY};
+
+    #[test]
+    fn busy_server_is_retried_and_missing_file_is_fatal() {
+        let classifier = ErrorClassifier::default();
+        let busy = ErrorPacket::new(packet::Error::Undefined, "Server Busy, try later");
+        assert_eq!(ErrorClass::Retryable(DEFAULT_RETRY_DELAY), classifier.classify(&busy));
+        let undefined = ErrorPacket::new(packet::Error::Undefined, "internal error");
+        assert_eq!(ErrorClass::Fatal, classifier.classify(&undefined));
+        let missing = ErrorPacket::new(packet::Error::FileNotFound, "busy");
+        assert_eq!(ErrorClass::Fatal, classifier.classify(&missing));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let retry = ErrorClass::Retryable(Duration::from_secs(5));
+        let classifier = ErrorClassifier::new(ErrorClass::Fatal)
+            .message_rule(packet::Error::FileNotFound, "not yet", retry)
+            .rule(packet::Error::FileNotFound, ErrorClass::Fatal);
+        assert_eq!(retry, classifier.classify(&ErrorPacket::new(packet::Error::FileNotFound, "Not yet built")));
+        assert_eq!(ErrorClass::Fatal, classifier.classify(&ErrorPacket::new(packet::Error::FileNotFound, "gone")));
+    }
+
+    #[test]
+    fn crate_errors_are_classified() {
+        let classifier = ErrorClassifier::default();
+        let busy = Error::new(ErrorKind::ServerError(packet::Error::Undefined),
+                              ErrorPacket::new(packet::Error::Undefined, "busy").into_owned());
+        assert_eq!(ErrorClass::Retryable(DEFAULT_RETRY_DELAY), classifier.classify_error(&busy));
+        let timed_out: Error = io::Error::new(io::ErrorKind::TimedOut, "timeout").into();
+        assert_eq!(ErrorClass::Retryable(Duration::from_secs(0)), classifier.classify_error(&timed_out));
+        let io: Error = io::Error::new(io::ErrorKind::Other, "broken").into();
+        assert_eq!(ErrorClass::Fatal, classifier.classify_error(&io));
+    }
+}
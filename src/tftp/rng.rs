@@ -0,0 +1,134 @@
+//! Randomness of the crate, injectable so runs can be reproduced.
+//!
+//! The client draws the names of the temporary files of `Client::get_to_file`
+//! from a `SharedRng`, by default seeded from the entropy of the process. A
+//! generator created with `SharedRng::seeded` draws the same numbers in every
+//! run, so a failure from a bug report or a CI run can be replayed exactly;
+//! `SharedRng::seed` returns the seed of a run to record it. Simulations, like
+//! the lossy link of the `lossy-link` example, take their losses from a seeded
+//! generator for the same reason.
+//!
+//! The crate draws no other random numbers, apart from the session tokens of
+//! the `experimental-resume` feature: the ephemeral port of a transfer is
+//! chosen by the operating system and retransmission timeouts have no jitter.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// Source of random numbers.
+pub trait Rng: Send {
+    /// Returns the next random number.
+    fn next_u64(&mut self) -> u64;
+}
+
+/// SplitMix64 generator, the same seed gives the same numbers on every
+/// platform.
+#[derive(Debug, Clone)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    /// Creates a generator starting from `seed`.
+    pub fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+}
+
+impl Rng for SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Generator shared by the clones of a client, clones draw from the same
+/// sequence.
+#[derive(Clone)]
+pub struct SharedRng {
+    rng: Arc<Mutex<Box<Rng>>>,
+    seed: Option<u64>,
+}
+
+impl SharedRng {
+    /// Creates a `SplitMix64` generator starting from `seed`.
+    pub fn seeded(seed: u64) -> SharedRng {
+        SharedRng {
+            rng: Arc::new(Mutex::new(Box::new(SplitMix64::new(seed)))),
+            seed: Some(seed),
+        }
+    }
+
+    /// Creates a `SplitMix64` generator with a seed from the entropy of the
+    /// process, different in every run.
+    pub fn from_entropy() -> SharedRng {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let mut hasher = RandomState::new().build_hasher();
+        CREATED.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        SharedRng::seeded(hasher.finish())
+    }
+
+    /// Shares `rng`, e.g. a generator of a simulation framework.
+    pub fn new<R: Rng + 'static>(rng: R) -> SharedRng {
+        SharedRng {
+            rng: Arc::new(Mutex::new(Box::new(rng))),
+            seed: None,
+        }
+    }
+
+    /// Returns the seed of a `SplitMix64` generator, `None` for other
+    /// generators.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Returns the next random number of the sequence.
+    pub fn next_u64(&self) -> u64 {
+        self.rng.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).next_u64()
+    }
+}
+
+impl Default for SharedRng {
+    /// Returns a generator seeded from entropy.
+    fn default() -> SharedRng {
+        SharedRng::from_entropy()
+    }
+}
+
+impl fmt::Debug for SharedRng {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SharedRng").field("seed", &self.seed).finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rng, SharedRng, SplitMix64};
+
+    #[test]
+    fn seeded_generators_repeat_their_sequence() {
+        let mut reference = SplitMix64::new(0);
+        assert_eq!(0xe220_a839_7b1d_cdaf, reference.next_u64());
+        assert_eq!(0x6e78_9e6a_a1b9_65f4, reference.next_u64());
+
+        let (first, second) = (SharedRng::seeded(42), SharedRng::seeded(42));
+        let drawn: Vec<u64> = (0..4).map(|_| first.next_u64()).collect();
+        assert_eq!(drawn, (0..4).map(|_| second.next_u64()).collect::<Vec<_>>());
+        // Clones share the sequence.
+        let clone = first.clone();
+        assert_ne!(clone.next_u64(), first.next_u64());
+
+        let entropy = SharedRng::from_entropy();
+        let seed = entropy.seed().unwrap();
+        assert_eq!(SharedRng::seeded(seed).next_u64(), entropy.next_u64());
+        assert_eq!(None, SharedRng::new(SplitMix64::new(1)).seed());
+    }
+}
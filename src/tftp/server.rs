@@ -1,25 +1,82 @@
-use std::io::{self, Cursor, Read};
-use std::convert::Into;
-use std::net::SocketAddr;
-use std::thread;
+//! A Trivial File Transfer (TFTP) protocol server implementation.
+//!
+//! Files are opened by a `Handler`, by default `FsHandler` serving a root
+//! directory. Every transfer runs on its own socket as a task on the tokio-core
+//! reactor. The server listens on a UDP socket or, on Unix, on a Unix datagram
+//! socket for local clients. The retransmission timers of the transfers can
+//! share a `TimerWheel` instead of using a reactor timeout each.
+//!
+//! A running server is stopped through a `DrainHandle`: it closes the listening
+//! socket and lets the running transfers finish until a deadline, the ones
+//! still running then are aborted and reported. A `ServerHandle` also changes
+//! the bandwidth cap of the running server.
+//!
+//! Embedders wanting full control over each request use `Server::incoming`
+//! instead of `Server::run`. The stream yields an `IncomingRequest` for every
+//! request received, the embedder serves a file, a reader or a writer, rejects
+//! the request or takes over the transfer socket and runs the protocol itself.
+//! The answer may wait for an asynchronous decision, e.g. a database lookup:
+//! the client's retransmissions of a request are absorbed while it's pending
+//! and requests not answered within the retransmission budget of the client
+//! are rejected.
+
+use std::cell::{Cell, RefCell};
+use std::cmp::{self, Reverse};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::net::{self, IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::result;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use tokio_core::net::UdpSocket;
-use tokio_core::reactor::Core;
-use tokio_core::channel::{Receiver, channel};
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
 use futures::{Poll, Async};
+use futures::task::{self, Task};
 use futures::stream::Stream;
 use futures::Future;
+use futures::future::{self, Either};
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::AllowStdIo;
 
-use decodedpacket::DecodedPacket;
-use packet::{RequestPacket, RawPacket, DataPacketOctet, EncodePacket, AckPacket};
+use packet::{self, RequestPacket, DataPacketOctet, EncodePacket, DecodePacket, AckPacket,
+    ErrorPacket, OptionAckPacket, TransferOptions, Packet, Opcode, Mode, BLKSIZE_OPTION,
+    TIMEOUT_OPTION, UTIMEOUT_OPTION, WINDOWSIZE_OPTION};
+use config::{self, BlockSize, Retries, Subnet, UnexpectedPacketPolicy, Conformance, ConfigError, WindowSize,
+             MIN_BLOCK_SIZE, DEFAULT_TIMEOUT};
+use transfer::{self, AckWindow, ReadTransfer, WriteTransfer, DataReceived, AckReceived, TransferParams,
+               DEFAULT_BLOCK_SIZE};
+use handler::{Handler, FsHandler, Priority, Request, Router};
+use transport::{self, PacketTooLarge, Tap, Tapped, Transport, send_packet};
+use source::ReadSource;
+use replay::Direction;
+use ratelimit::RateLimit;
+use arena::{Arena, Region};
+use bandwidth::{BandwidthCap, Pacer};
+use reason::{Reason, ReasonFormat};
+use stats::{Stats, Recorder};
+use snapshot::{self, Entry, Registry, Tracked};
+use filename::FilenameCodec;
+use wheel::{self, TimerWheel};
+#[cfg(feature = "experimental-dtls")]
+use dtls::{self, DtlsTransport};
+#[cfg(feature = "experimental-dtls")]
+use openssl::ssl::SslContext;
+#[cfg(feature = "experimental-resume")]
+use resume::{self, Token};
+#[cfg(feature = "experimental-resume")]
+use rng::SharedRng;
 
-struct ClientRequest {
-    addr: SocketAddr,
-    request: DecodedPacket<RequestPacket<'static>>,
+struct ClientRequest<A> {
+    addr: A,
+    request: RequestPacket<'static>,
 }
 
-impl ClientRequest {
-    fn new(addr: SocketAddr, request: DecodedPacket<RequestPacket<'static>>) -> ClientRequest {
+impl<A> ClientRequest<A> {
+    fn new(addr: A, request: RequestPacket<'static>) -> ClientRequest<A> {
         ClientRequest {
             addr: addr,
             request: request,
@@ -27,114 +84,4146 @@ impl ClientRequest {
     }
 }
 
-struct RequestAcceptor {
-    socket: UdpSocket,
+struct RequestAcceptor<S> {
+    socket: S,
+    stats: Stats,
+    filename_codec: FilenameCodec,
+    conformance: Conformance,
 }
 
-impl RequestAcceptor {
-    fn new(socket: UdpSocket) -> RequestAcceptor {
+impl<S: Transport> RequestAcceptor<S> {
+    fn new(socket: S, stats: Stats, filename_codec: FilenameCodec, conformance: Conformance) -> RequestAcceptor<S> {
         RequestAcceptor {
             socket: socket,
+            stats: stats,
+            filename_codec: filename_codec,
+            conformance: conformance,
         }
     }
 }
 
-impl Stream for RequestAcceptor {
-    type Item = ClientRequest;
+impl<S: Transport> Stream for RequestAcceptor<S> {
+    type Item = ClientRequest<S::Addr>;
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut buf = vec![0; 512];
-        let (n, addr) = try_nb!(self.socket.recv_from(&mut buf));
+        loop {
+            // Requests with their options are at most 512 bytes long.
+            let mut buf = transport::receive_buffer(512);
+            let (mut n, addr) = try_nb!(self.socket.recv_from(&mut buf));
+            let mut stats = Recorder::new(self.stats.clone());
+            stats.received();
+            if transport::is_truncated(n, &buf) {
+                warn!("Ignoring oversized request from {:?}", addr);
+                stats.unexpected();
+                continue
+            }
+            if refuse_unterminated(&buf[..n], &addr, self.conformance, &mut stats) {
+                continue
+            }
+            if packet::lacks_terminator(&buf[..n]) {
+                // The datagram is shorter than the buffer.
+                buf[n] = 0;
+                n += 1;
+            }
+
+            match RequestPacket::decode_with(&buf[..n], self.filename_codec) {
+                Some(packet) => return Ok(Some(ClientRequest::new(addr, packet)).into()),
+                None => {
+                    warn!("Ignoring invalid request from {:?}", addr);
+                    stats.unexpected();
+                }
+            }
+        }
+    }
+}
+
+/// Requests waiting for a free transfer slot, started by priority and then in
+/// the order they arrived.
+struct WaitQueue<A> {
+    queue: Vec<Queued<A>>,
+    arrivals: u64,
+    /// Requests the client didn't repeat for this long are dropped, the client
+    /// gave up on them.
+    expiry: Duration,
+}
+
+struct Queued<A> {
+    request: ClientRequest<A>,
+    priority: Priority,
+    arrival: u64,
+    received: Instant,
+}
+
+impl<A: PartialEq + fmt::Debug> WaitQueue<A> {
+    fn new(expiry: Duration) -> WaitQueue<A> {
+        WaitQueue {
+            queue: Vec::new(),
+            arrivals: 0,
+            expiry: expiry,
+        }
+    }
+
+    /// Adds a request, a repeated request of a waiting client keeps its place.
+    fn push(&mut self, request: ClientRequest<A>, priority: Priority, now: Instant) {
+        if let Some(queued) = self.queue.iter_mut().find(|queued| queued.request.addr == request.addr) {
+            queued.request = request;
+            queued.priority = priority;
+            queued.received = now;
+            return
+        }
+        self.arrivals += 1;
+        self.queue.push(Queued {
+            request: request,
+            priority: priority,
+            arrival: self.arrivals,
+            received: now,
+        });
+    }
+
+    /// Removes the request to start next.
+    fn pop(&mut self, now: Instant) -> Option<ClientRequest<A>> {
+        let expiry = self.expiry;
+        self.queue.retain(|queued| {
+            let waiting = now.duration_since(queued.received) < expiry;
+            if !waiting {
+                debug!("Dropping expired request of {:?}", queued.request.addr);
+            }
+            waiting
+        });
+        let next = self.queue.iter().enumerate()
+            .max_by_key(|&(_, queued)| (queued.priority, Reverse(queued.arrival)))
+            .map(|(i, _)| i);
+        next.map(|i| self.queue.remove(i).request)
+    }
+}
+
+/// Number of running transfers, shared with their slots.
+struct Slots {
+    max: usize,
+    running: Cell<usize>,
+    /// Scheduler or drain waiting for a slot to become free.
+    waiting: RefCell<Option<Task>>,
+    next_id: Cell<u64>,
+    /// Transfers of the slots that started, reported if a drain aborts them.
+    transfers: RefCell<Vec<(u64, AbortedTransfer)>>,
+}
+
+/// Slot of a running transfer, freed when dropped.
+struct Slot {
+    slots: Rc<Slots>,
+    id: u64,
+}
+
+impl Slot {
+    fn new(slots: &Rc<Slots>) -> Slot {
+        slots.running.set(slots.running.get() + 1);
+        slots.next_id.set(slots.next_id.get() + 1);
+        Slot {
+            slots: slots.clone(),
+            id: slots.next_id.get(),
+        }
+    }
+
+    /// Records the transfer using the slot.
+    fn describe(&self, transfer: AbortedTransfer) {
+        self.slots.transfers.borrow_mut().push((self.id, transfer));
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let id = self.id;
+        self.slots.transfers.borrow_mut().retain(|&(transfer, _)| transfer != id);
+        self.slots.running.set(self.slots.running.get() - 1);
+        if let Some(task) = self.slots.waiting.borrow_mut().take() {
+            task.notify();
+        }
+    }
+}
+
+/// Hands out accepted requests while fewer transfers than the limit run, the
+/// other requests wait in the queue.
+struct Scheduler<S: Transport, P> {
+    acceptor: RequestAcceptor<S>,
+    priority: P,
+    queue: WaitQueue<S::Addr>,
+    slots: Rc<Slots>,
+}
+
+impl<S: Transport, P> Scheduler<S, P> {
+    fn new(acceptor: RequestAcceptor<S>, priority: P, max_transfers: Option<usize>, expiry: Duration)
+           -> Scheduler<S, P> {
+        Scheduler {
+            acceptor: acceptor,
+            priority: priority,
+            queue: WaitQueue::new(expiry),
+            slots: Rc::new(Slots {
+                max: max_transfers.unwrap_or(usize::max_value()),
+                running: Cell::new(0),
+                waiting: RefCell::new(None),
+                next_id: Cell::new(0),
+                transfers: RefCell::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Takes a slot for a transfer that didn't wait in the queue, even if
+    /// the limit is reached.
+    fn claim(&self) -> Slot {
+        Slot::new(&self.slots)
+    }
+}
+
+impl<S: Transport, P: FnMut(&ClientRequest<S::Addr>) -> Priority> Stream for Scheduler<S, P> {
+    type Item = (ClientRequest<S::Addr>, Slot);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while let Async::Ready(request) = try!(self.acceptor.poll()) {
+            let request = match request {
+                Some(request) => request,
+                None => return Ok(Async::Ready(None)),
+            };
+            let priority = (self.priority)(&request);
+            if self.slots.running.get() >= self.slots.max {
+                info!("{} transfers running, {:?} waits ({:?})", self.slots.running.get(), request.addr, priority);
+            }
+            self.queue.push(request, priority, Instant::now());
+        }
+        if self.slots.running.get() >= self.slots.max {
+            *self.slots.waiting.borrow_mut() = Some(task::current());
+            return Ok(Async::NotReady)
+        }
+        match self.queue.pop(Instant::now()) {
+            Some(request) => Ok(Async::Ready(Some((request, Slot::new(&self.slots))))),
+            None => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// Socket a server listens on, transfers run on sockets of the same kind.
+trait Endpoint: Transport + Sized + 'static {
+    /// Source of the sockets of new transfers.
+    type Local;
+
+    /// Socket of a transfer that is not registered with the reactor yet.
+    type Unregistered;
+
+    fn local(&self) -> io::Result<Self::Local>;
+
+    /// Binds the socket of a new transfer.
+    fn bind_transfer(local: &Self::Local) -> io::Result<Self::Unregistered>;
+
+    /// Sends a datagram from a socket that is not registered yet.
+    ///
+    /// The datagram is sent right away instead of waiting for write readiness.
+    fn send_unregistered(socket: &Self::Unregistered, buf: &[u8], addr: &Self::Addr) -> io::Result<usize>;
+
+    fn register(socket: Self::Unregistered, handle: &Handle) -> io::Result<Self>;
+
+    /// Returns the network address of a peer, `None` for local peers.
+    fn network_addr(addr: &Self::Addr) -> Option<SocketAddr>;
+
+    /// Returns the network address a transfer socket is bound to, `None` for
+    /// local sockets.
+    fn transfer_addr(socket: &Self::Unregistered) -> Option<SocketAddr>;
+
+    /// Binds the socket of a resumed transfer to the address it was bound to
+    /// before and returns it with the address of the peer, `None` if the
+    /// transfer can't be resumed on this kind of socket.
+    fn rebind_transfer(entry: &Entry) -> Option<io::Result<(Self::Unregistered, Self::Addr)>>;
+
+    /// Returns the tap of the sockets of this kind, `None` if they are not
+    /// tapped.
+    fn tap(tap: &Option<Tap>) -> Option<Tap<Self::Addr>>;
+}
+
+impl Endpoint for UdpSocket {
+    type Local = SocketAddr;
+    type Unregistered = net::UdpSocket;
+
+    fn local(&self) -> io::Result<SocketAddr> {
+        let mut addr = try!(self.local_addr());
+        addr.set_port(0);
+        Ok(addr)
+    }
+
+    fn bind_transfer(local: &SocketAddr) -> io::Result<net::UdpSocket> {
+        net::UdpSocket::bind(local)
+    }
+
+    fn send_unregistered(socket: &net::UdpSocket, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        socket.send_to(buf, addr)
+    }
+
+    fn register(socket: net::UdpSocket, handle: &Handle) -> io::Result<UdpSocket> {
+        UdpSocket::from_socket(socket, handle)
+    }
+
+    fn network_addr(addr: &SocketAddr) -> Option<SocketAddr> {
+        Some(*addr)
+    }
+
+    fn transfer_addr(socket: &net::UdpSocket) -> Option<SocketAddr> {
+        socket.local_addr().ok()
+    }
+
+    fn rebind_transfer(entry: &Entry) -> Option<io::Result<(net::UdpSocket, SocketAddr)>> {
+        Some(net::UdpSocket::bind(entry.local).map(|socket| (socket, entry.client)))
+    }
+
+    fn tap(tap: &Option<Tap>) -> Option<Tap> {
+        tap.clone()
+    }
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::cell::Cell;
+    use std::fs;
+    use std::io;
+    use std::net::SocketAddr;
+    use std::os::unix::net;
+    use std::path::{Path, PathBuf};
+
+    use mio_uds;
+    use tokio_core::reactor::{Handle, PollEvented};
+    use futures::Async;
+
+    use snapshot::Entry;
+    use transport::{Tap, Transport, unix_path};
+    use super::Endpoint;
+
+    /// Path of a socket, the socket file is removed when the socket is closed.
+    #[derive(Debug)]
+    pub struct SocketPath(PathBuf);
+
+    impl Drop for SocketPath {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Unix datagram socket registered with the reactor.
+    pub struct UnixSocket {
+        io: PollEvented<mio_uds::UnixDatagram>,
+        path: SocketPath,
+    }
+
+    impl UnixSocket {
+        pub fn bind(path: &Path, handle: &Handle) -> io::Result<UnixSocket> {
+            let socket = try!(net::UnixDatagram::bind(path));
+            UnixSocket::register((socket, SocketPath(path.to_path_buf())), handle)
+        }
+    }
+
+    impl Transport for UnixSocket {
+        type Addr = PathBuf;
+
+        fn send_to(&mut self, buf: &[u8], addr: &PathBuf) -> io::Result<usize> {
+            if let Async::NotReady = self.io.poll_write() {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            let result = self.io.get_ref().send_to(buf, addr);
+            if let Err(ref e) = result {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.io.need_write();
+                }
+            }
+            result
+        }
+
+        fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, PathBuf)> {
+            if let Async::NotReady = self.io.poll_read() {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            match self.io.get_ref().recv_from(buf) {
+                Ok((n, addr)) => Ok((n, unix_path(&addr))),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        self.io.need_read();
+                    }
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    /// Paths of transfer sockets, the path of the listening socket with a
+    /// counter appended.
+    pub struct TransferPaths {
+        base: PathBuf,
+        next: Cell<u64>,
+    }
+
+    impl Endpoint for UnixSocket {
+        type Local = TransferPaths;
+        type Unregistered = (net::UnixDatagram, SocketPath);
+
+        fn local(&self) -> io::Result<TransferPaths> {
+            Ok(TransferPaths {
+                base: self.path.0.clone(),
+                next: Cell::new(0),
+            })
+        }
+
+        fn bind_transfer(local: &TransferPaths) -> io::Result<(net::UnixDatagram, SocketPath)> {
+            let n = local.next.get();
+            local.next.set(n + 1);
+            let mut path = local.base.clone().into_os_string();
+            path.push(format!(".{}", n));
+            let path = PathBuf::from(path);
+            // Left behind by a previous server that didn't exit cleanly.
+            let _ = fs::remove_file(&path);
+            let socket = try!(net::UnixDatagram::bind(&path));
+            Ok((socket, SocketPath(path)))
+        }
+
+        fn send_unregistered(socket: &(net::UnixDatagram, SocketPath), buf: &[u8], addr: &PathBuf) -> io::Result<usize> {
+            socket.0.send_to(buf, addr)
+        }
+
+        fn register(socket: (net::UnixDatagram, SocketPath), handle: &Handle) -> io::Result<UnixSocket> {
+            let (socket, path) = socket;
+            let socket = try!(mio_uds::UnixDatagram::from_datagram(socket));
+            Ok(UnixSocket {
+                io: try!(PollEvented::new(socket, handle)),
+                path: path,
+            })
+        }
+
+        fn network_addr(_: &PathBuf) -> Option<SocketAddr> {
+            None
+        }
 
-        let packet: DecodedPacket<RequestPacket> = DecodedPacket::decode(RawPacket::new(buf, n)).unwrap();
-        Ok(Some(ClientRequest::new(addr, packet)).into())
+        fn transfer_addr(_: &(net::UnixDatagram, SocketPath)) -> Option<SocketAddr> {
+            None
+        }
+
+        fn rebind_transfer(_: &Entry) -> Option<io::Result<((net::UnixDatagram, SocketPath), PathBuf)>> {
+            None
+        }
+
+        /// Peers of Unix sockets have no network address, their datagrams
+        /// are not tapped.
+        fn tap(_: &Option<Tap>) -> Option<Tap<PathBuf>> {
+            None
+        }
+    }
+}
+
+/// Configuration shared by all transfers of a server.
+#[derive(Debug, Clone)]
+struct ServerConfig {
+    read_only: bool,
+    max_block_size: BlockSize,
+    max_window_size: WindowSize,
+    segmentation_offload: bool,
+    timeout: Duration,
+    retries: Retries,
+    unexpected_packets: UnexpectedPacketPolicy,
+    conformance: Conformance,
+    deadline: Option<Duration>,
+    keepalive: Option<Duration>,
+    max_transfers: Option<usize>,
+    session_file: Option<PathBuf>,
+    filename_codec: FilenameCodec,
+    reasons: ReasonFormat,
+    wrong_tid_limit: RateLimit,
+    bandwidth: BandwidthCap,
+    tap: Option<Tap>,
+    stats: Stats,
+    timer_wheel: Option<TimerWheel>,
+    #[cfg(feature = "experimental-dtls")]
+    dtls: Option<SslContext>,
+    /// Draws the session tokens of transfers.
+    #[cfg(feature = "experimental-resume")]
+    rng: SharedRng,
+}
+
+/// Socket of a transfer.
+#[cfg(not(feature = "experimental-dtls"))]
+type TransferSocket<E> = Tapped<E>;
+
+/// Socket of a transfer, wrapped in DTLS if configured.
+#[cfg(feature = "experimental-dtls")]
+type TransferSocket<E> = Box<Transport<Addr = <E as Transport>::Addr>>;
+
+#[cfg(not(feature = "experimental-dtls"))]
+fn transfer_socket<E: Endpoint>(config: &ServerConfig, socket: E) -> TransferSocket<E> {
+    Tapped::optional(socket, E::tap(&config.tap))
+}
+
+#[cfg(feature = "experimental-dtls")]
+fn transfer_socket<E: Endpoint>(config: &ServerConfig, socket: E) -> TransferSocket<E> {
+    let socket = Tapped::optional(socket, E::tap(&config.tap));
+    match config.dtls {
+        Some(ref context) => Box::new(DtlsTransport::connect(socket, context)),
+        None => Box::new(socket),
     }
 }
 
-struct RequestHandler {
-    socket: UdpSocket,
-    client_request: ClientRequest,
-    data: Cursor<Vec<u8>>,
-    block_id: u16,
+/// Session token of a transfer, there are none without the
+/// `experimental-resume` feature.
+#[cfg(not(feature = "experimental-resume"))]
+#[derive(Debug, Clone, Copy)]
+enum Token {}
+
+/// Acknowledges the session token the client asked for, see `resume`.
+#[cfg(feature = "experimental-resume")]
+fn session_token(oack: Option<OptionAckPacket<'static>>, requested: &TransferOptions, config: &ServerConfig)
+                 -> (Option<OptionAckPacket<'static>>, Option<Token>) {
+    resume::acknowledge_token(oack, requested, &config.rng)
+}
+
+#[cfg(not(feature = "experimental-resume"))]
+fn session_token(oack: Option<OptionAckPacket<'static>>, _: &TransferOptions, _: &ServerConfig)
+                 -> (Option<OptionAckPacket<'static>>, Option<Token>) {
+    (oack, None)
+}
+
+/// Returns `true` if a datagram from an unknown transfer identifier asks to
+/// continue the transfer of `token` from there.
+#[cfg(feature = "experimental-resume")]
+fn resumes(datagram: &[u8], token: Option<Token>) -> bool {
+    token.map_or(false, |token| resume::resumes(datagram, token))
+}
+
+#[cfg(not(feature = "experimental-resume"))]
+fn resumes(_: &[u8], _: Option<Token>) -> bool {
+    false
+}
+
+/// Sends a file to the client in response to a read request.
+struct ReadRequestHandler<R, S: Transport> {
+    socket: S,
+    addr: S::Addr,
+    /// File of the handler, the blocks of the window are kept for retransmissions.
+    data: ReadSource<R>,
+    transfer: WriteTransfer,
+    /// Buffers of the transfer, freed with it.
+    arena: Arena,
+    /// Option acknowledgment, encoded in the arena.
+    oack: Option<Region>,
     send_data: bool,
-    last_id: Option<u16>,
+    /// Encodes the data packets the socket can't send from the block, empty
+    /// until the first one.
+    send_buffer: Vec<u8>,
+    ack_buffer: Region,
+    timeout: RetransmitTimer,
+    timeout_duration: Duration,
+    unexpected_packets: UnexpectedPacketPolicy,
+    conformance: Conformance,
+    reasons: ReasonFormat,
+    wrong_tid_limit: RateLimit,
+    deadline: Option<Timeout>,
+    /// Timer of keepalives and their interval, if enabled.
+    keepalive: Option<(Timeout, Duration)>,
+    /// The handler is producing the next block and the keepalive timer runs.
+    stalled: bool,
+    /// Record of the transfer in the snapshot, if enabled.
+    session: Option<Tracked>,
+    /// Turns of the data packets under the bandwidth cap of the server.
+    pacer: Pacer,
+    /// Blocks of the window read so far, if they are sent together.
+    window: Option<Window>,
+    /// When the last data packet was sent.
+    sent_at: Instant,
+    /// Timer of the pause before the next window after a loss, with windows.
+    pause: Option<Timeout>,
+    /// The next window waits for the pause timer.
+    paused: bool,
+    /// Session token the client may continue the transfer from another address with.
+    token: Option<Token>,
+    stats: Recorder,
+}
+
+/// Data packets of a window encoded back to back, sent with segmentation offload.
+#[derive(Default)]
+struct Window {
+    buffer: Vec<u8>,
+    /// End of each packet in the buffer.
+    ends: Vec<usize>,
+    /// Number of packets sent.
+    sent: usize,
+}
+
+impl Window {
+    fn push(&mut self, packet: &DataPacketOctet) {
+        self.buffer.extend_from_slice(&packet.header());
+        self.buffer.extend_from_slice(packet.data());
+        self.ends.push(self.buffer.len());
+    }
+
+    fn is_empty(&self) -> bool {
+        self.ends.is_empty()
+    }
+
+    /// Returns the packets that weren't sent yet.
+    fn unsent(&self) -> Vec<&[u8]> {
+        let mut start = if self.sent == 0 { 0 } else { self.ends[self.sent - 1] };
+        self.ends[self.sent..].iter().map(|&end| {
+            let packet = &self.buffer[start..end];
+            start = end;
+            packet
+        }).collect()
+    }
+
+    /// Empties the window once all packets were sent, the buffers are reused.
+    fn clear(&mut self) {
+        self.buffer.clear();
+        self.ends.clear();
+        self.sent = 0;
+    }
 }
 
-impl RequestHandler {
-    fn new(socket: UdpSocket, client_request: ClientRequest) -> RequestHandler {
-        RequestHandler {
+impl<R: AsyncRead, S: Transport> ReadRequestHandler<R, S> {
+    fn new(handle: &Handle, socket: S, addr: S::Addr, data: R, params: TransferParams,
+           oack: Option<OptionAckPacket<'static>>, config: &ServerConfig) -> io::Result<ReadRequestHandler<R, S>> {
+        let block_size = params.block_size;
+        let mut transfer = WriteTransfer::new(block_size);
+        transfer.set_retries(config.retries);
+        transfer.set_window_size(params.window_size, true);
+        let timeout = try!(RetransmitTimer::new(params.timeout, config, handle));
+        let keepalive = match config.keepalive {
+            Some(interval) => Some((try!(Timeout::new(interval, handle)), interval)),
+            None => None,
+        };
+        // Errors of the client may be longer than small blocks, the spare
+        // byte tells truncated datagrams like `transport::receive_buffer`.
+        let receive_len = cmp::max(block_size, DEFAULT_BLOCK_SIZE) + 4 + 1;
+        let mut arena = Arena::with_capacity(receive_len + oack.as_ref().map_or(0, |oack| oack.len()));
+        let ack_buffer = arena.alloc(receive_len);
+        let oack = oack.map(|oack| arena.alloc_packet(&oack));
+        Ok(ReadRequestHandler {
             socket: socket,
-            client_request: client_request,
-            data: Cursor::new(vec![1; 1025]),
-            block_id: 1,
-            send_data: true,
-            last_id: None,
+            addr: addr,
+            data: ReadSource::with_window_size(data, params.window_size),
+            transfer: transfer,
+            // Without options the first block is sent as soon as it's read,
+            // otherwise after the client acknowledged the options.
+            send_data: oack.is_some(),
+            arena: arena,
+            oack: oack,
+            send_buffer: Vec::new(),
+            ack_buffer: ack_buffer,
+            timeout: timeout,
+            timeout_duration: params.timeout,
+            unexpected_packets: config.unexpected_packets,
+            conformance: config.conformance,
+            reasons: config.reasons.clone(),
+            wrong_tid_limit: config.wrong_tid_limit.clone(),
+            deadline: try!(transfer_deadline(config, handle)),
+            keepalive: keepalive,
+            stalled: false,
+            session: None,
+            pacer: Pacer::new(config.bandwidth.clone(), handle),
+            window: if config.segmentation_offload && params.window_size.get() > 1 {
+                Some(Window::default())
+            } else {
+                None
+            },
+            sent_at: Instant::now(),
+            pause: if params.window_size.get() > 1 {
+                Some(try!(Timeout::new(Duration::from_secs(0), handle)))
+            } else {
+                None
+            },
+            paused: false,
+            token: None,
+            stats: transfer_stats(config),
+        })
+    }
+
+    /// Records the progress of the transfer in the snapshot.
+    fn tracked(mut self, session: Option<Tracked>) -> ReadRequestHandler<R, S> {
+        self.session = session;
+        self
+    }
+
+    /// Takes the turns of the data packets under the bandwidth cap with the
+    /// priority the handler assigned the transfer.
+    fn prioritized(mut self, priority: Priority) -> ReadRequestHandler<R, S> {
+        self.pacer.set_priority(priority);
+        self
+    }
+
+    /// Lets the client continue the transfer from another address with `token`.
+    fn resumable(mut self, token: Option<Token>) -> ReadRequestHandler<R, S> {
+        self.token = token;
+        self
+    }
+
+    /// Continues a transfer of which the client acknowledged `acked_blocks`
+    /// blocks before the server restarted.
+    fn resume(mut self, acked_blocks: u64) -> ReadRequestHandler<R, S> {
+        self.transfer.resume(acked_blocks);
+        self.oack = None;
+        self.send_data = false;
+        self
+    }
+
+    /// Sends the last acknowledged block again whenever the handler took
+    /// another keepalive interval to produce the next one.
+    ///
+    /// The client acknowledges the duplicate and restarts its timeout instead
+    /// of giving up on the transfer. Nothing is sent before the first block.
+    fn keep_alive(&mut self) -> io::Result<()> {
+        let interval = match self.keepalive {
+            Some((_, interval)) => interval,
+            None => return Ok(()),
+        };
+        if self.transfer.current_block().block_id() == 0 {
+            return Ok(())
+        }
+        if !self.stalled {
+            self.stalled = true;
+            self.reset_keepalive(interval);
+        }
+        while try!(self.poll_keepalive()).is_ready() {
+            let data_packet = self.transfer.current_block();
+            warn!("Handler stalled after block {} for {:?}, sending it again", data_packet.block_id(), self.addr);
+            self.stats.keepalive();
+            let sent = self.socket.send_data(&data_packet, &self.addr, &mut self.send_buffer);
+            self.stats.sent(&sent);
+            match sent {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+            self.reset_keepalive(interval);
+        }
+        Ok(())
+    }
+
+    fn reset_keepalive(&mut self, interval: Duration) {
+        if let Some((ref mut timer, _)) = self.keepalive {
+            timer.reset(Instant::now() + interval);
+        }
+    }
+
+    fn poll_keepalive(&mut self) -> Poll<(), io::Error> {
+        match self.keepalive {
+            Some((ref mut timer, _)) => timer.poll(),
+            None => Ok(Async::NotReady),
+        }
+    }
+
+    /// Reads the next block of the file into the transfer.
+    ///
+    /// The block is collected across polls by the source, the transfer only
+    /// sees complete blocks or the end of the file. A resumed transfer skips
+    /// the blocks the client acknowledged before.
+    fn read_block(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.transfer.next_block_from(&mut self.data));
+        Ok(Async::Ready(()))
+    }
+
+    /// Sends the blocks of the window read so far, as many at once as the
+    /// socket takes.
+    fn send_window(&mut self) -> Poll<(), io::Error> {
+        let window = self.window.as_mut().expect("only windows sent together are collected");
+        while window.sent < window.ends.len() {
+            let sent = {
+                let packets = window.unsent();
+                try_ready!(self.pacer.poll_turn(packets.iter().map(|packet| packet.len()).sum()));
+                trace!("Sending {} data packets together", packets.len());
+                self.socket.send_segments(&packets, &self.addr)
+            };
+            self.stats.sent_segments(&sent);
+            window.sent += try_nb!(sent);
+            self.pacer.sent();
+        }
+        window.clear();
+        Ok(Async::Ready(()))
+    }
+
+    /// Pauses before the next window while fewer blocks than the window are
+    /// sent per round trip after a loss, see `WriteTransfer::pause`.
+    fn pause_window(&mut self) {
+        let pause = self.transfer.pause(self.sent_at.elapsed());
+        if let Some(ref mut timer) = self.pause {
+            if pause > Duration::from_secs(0) {
+                trace!("Pausing {:?} before the next window to {:?}", pause, self.addr);
+                timer.reset(Instant::now() + pause);
+                self.paused = true;
+            }
+        }
+    }
+
+    fn poll_pause(&mut self) -> Poll<(), io::Error> {
+        match self.pause {
+            Some(ref mut timer) => timer.poll(),
+            None => Ok(Async::Ready(())),
         }
     }
 }
 
-impl Future for RequestHandler {
+impl<R: AsyncRead, S: Transport> Future for ReadRequestHandler<R, S> {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        try!(check_deadline(&mut self.deadline, &mut self.socket, &self.addr, &self.reasons, &mut self.stats));
         loop {
             if self.send_data {
-                match self.last_id {
-                    Some(last_id) if self.block_id > last_id => break,
-                    _ => {}
+                match self.oack {
+                    Some(oack) => {
+                        debug!("Sending option acknowledgment to {:?}", self.addr);
+                        let sent = self.socket.send_to(self.arena.get(oack), &self.addr);
+                        self.stats.sent(&sent);
+                        try_nb!(sent);
+                    }
+                    None => {
+                        let data_packet = self.transfer.current_block();
+                        let len = data_packet.data().len() + 4;
+                        try_ready!(self.pacer.poll_turn(len));
+                        trace!("Sending data packet id = {} length = {}", data_packet.block_id(), data_packet.data().len());
+                        let sent = self.socket.send_data(&data_packet, &self.addr, &mut self.send_buffer);
+                        self.stats.sent(&sent);
+                        try_nb!(sent);
+                        self.pacer.sent();
+                    }
                 }
+                self.send_data = false;
+                self.sent_at = Instant::now();
+                self.timeout.reset(self.sent_at + self.timeout_duration);
+            }
 
-                let mut buf = vec![0; 512];
-                let n = self.data.read(&mut buf).unwrap();
-
-                if n < 512 {
-                    self.last_id = Some(self.block_id);
+            if self.paused {
+                if try!(self.poll_pause()).is_not_ready() {
+                    return Ok(Async::NotReady)
                 }
-
-                let data_packet = DataPacketOctet::from_vec(self.block_id, buf, n);
-                let encoded_packet = data_packet.encode();
-
-                println!("Sending data packet id = {} length = {}", self.block_id, n);
-                println!("{}", encoded_packet.packet_buf().len());
-                try_nb!(self.socket.send_to(encoded_packet.packet_buf(), &self.client_request.addr));
-                self.send_data = false;
+                self.paused = false;
+            }
+            if self.oack.is_none() && self.transfer.can_send() {
+                if try!(self.read_block()).is_not_ready() {
+                    if self.window.as_ref().map_or(true, Window::is_empty) {
+                        try!(self.keep_alive());
+                        return Ok(Async::NotReady)
+                    }
+                    // The blocks read so far are sent while the handler produces the next one.
+                } else {
+                    self.stalled = false;
+                    match self.window {
+                        Some(ref mut window) => window.push(&self.transfer.current_block()),
+                        None => {
+                            self.send_data = true;
+                            continue
+                        }
+                    }
+                    if self.transfer.can_send() {
+                        continue
+                    }
+                }
+            }
+            // Collected blocks are sent before acknowledgments are received,
+            // a rewind finds the window empty.
+            if self.window.as_ref().map_or(false, |window| !window.is_empty()) {
+                try_ready!(self.send_window());
+                self.sent_at = Instant::now();
+                self.timeout.reset(self.sent_at + self.timeout_duration);
+                continue
             }
 
-            let mut buf = vec![0; 512];
-            let (n, _) = try_nb!(self.socket.recv_from(&mut buf));
-            let ack_packet: DecodedPacket<AckPacket> = DecodedPacket::decode(RawPacket::new(buf, n)).unwrap();
-            println!("Received ack packet id = {}", ack_packet.block_id());
-            self.block_id += 1;
-            self.send_data = true;
+            let (n, from) = match self.socket.recv_from(self.arena.get_mut(self.ack_buffer)) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if try!(self.timeout.poll()).is_not_ready() {
+                        return Ok(Async::NotReady)
+                    }
+                    match self.transfer.timeout() {
+                        transfer::Timeout::Retransmit => {
+                            debug!("Retransmitting to {:?}", self.addr);
+                            self.stats.retransmission();
+                            self.send_data = true;
+                            continue
+                        }
+                        transfer::Timeout::Rewind(_) => {
+                            debug!("Sending window to {:?} again", self.addr);
+                            self.stats.retransmission();
+                            continue
+                        }
+                        // The block size was acknowledged to the client, the server sets no fallback.
+                        transfer::Timeout::Fallback(_) => unreachable!(),
+                        transfer::Timeout::Failed => {
+                            return Err(io::Error::new(io::ErrorKind::TimedOut, "transfer timed out"))
+                        }
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            self.stats.received();
+            if from != self.addr {
+                if resumes(&self.arena.get(self.ack_buffer)[..n], self.token) {
+                    info!("{:?} continues reading from {:?}", self.addr, from);
+                    self.addr = from;
+                    self.send_data = true;
+                    continue
+                }
+                reject_unknown_tid(&mut self.socket, &from, &self.reasons, &self.wrong_tid_limit, &mut self.stats);
+                continue
+            }
+            let datagram = self.arena.get(self.ack_buffer);
+            if transport::is_truncated(n, datagram) {
+                return Err(packet_too_large(&mut self.socket, &self.addr, datagram.len() - 1, &self.reasons,
+                                            &mut self.stats))
+            }
+            if refuse_unterminated(&datagram[..n], &self.addr, self.conformance, &mut self.stats) {
+                continue
+            }
+            if let Some(error) = client_error(&datagram[..n]) {
+                return Err(error)
+            }
+            let ack_packet = match AckPacket::decode(&datagram[..n]) {
+                Some(ack_packet) => ack_packet,
+                None => {
+                    try!(unexpected_packet(&mut self.socket, &self.addr, &datagram[..n], Opcode::ACK,
+                                           self.unexpected_packets, &self.reasons, &mut self.stats));
+                    continue
+                }
+            };
+            trace!("Received ack packet id = {}", ack_packet.block_id());
+            match self.transfer.receive_ack(&ack_packet) {
+                // The blocks after the acknowledged ones are read at their
+                // offsets again, sending continues from there.
+                AckReceived::Next | AckReceived::Rewind(_) => {
+                    self.oack = None;
+                    if let Some(ref mut session) = self.session {
+                        session.update(self.transfer.acked_blocks());
+                    }
+                    self.pause_window();
+                }
+                AckReceived::Done => break,
+                AckReceived::Ignored => self.stats.duplicate(),
+            }
         }
         Ok(().into())
     }
 }
 
-pub fn start() {
-    let mut l = Core::new().unwrap();
-    let handle = l.handle();
+impl<R, S: Transport> Drop for ReadRequestHandler<R, S> {
+    fn drop(&mut self) {
+        self.stats.arena(self.arena.high_water());
+        self.stats.cache_hits(self.data.cache_hits());
+    }
+}
 
-    let addr = "127.0.0.1:9999".to_string().parse::<SocketAddr>().unwrap();
-    let socket = UdpSocket::bind(&addr, &handle).unwrap();
+/// Receives a file from the client in response to a write request.
+struct WriteRequestHandler<W, S: Transport> {
+    socket: S,
+    addr: S::Addr,
+    data: W,
+    transfer: ReadTransfer,
+    /// Blocks of the window received without an acknowledgment.
+    window: AckWindow,
+    /// Buffers of the transfer, freed with it.
+    arena: Arena,
+    /// Option acknowledgment, encoded in the arena.
+    oack: Option<Region>,
+    /// Acknowledgment of the last accepted block.
+    ack: AckPacket,
+    send_ack: bool,
+    /// Data of the received block in the receive buffer, no datagram is
+    /// received until it's written.
+    block: Region,
+    block_written: Option<usize>,
+    /// Encodes acknowledgments and errors.
+    send_buffer: Vec<u8>,
+    data_buffer: Region,
+    timeout: RetransmitTimer,
+    timeout_duration: Duration,
+    unexpected_packets: UnexpectedPacketPolicy,
+    conformance: Conformance,
+    reasons: ReasonFormat,
+    wrong_tid_limit: RateLimit,
+    deadline: Option<Timeout>,
+    /// Session token the client may continue the transfer from another address with.
+    token: Option<Token>,
+    stats: Recorder,
+}
 
-    println!("Listening on {}", addr);
+impl<W: AsyncWrite, S: Transport> WriteRequestHandler<W, S> {
+    fn new(handle: &Handle, socket: S, addr: S::Addr, data: W, params: TransferParams,
+           oack: Option<OptionAckPacket<'static>>, config: &ServerConfig) -> io::Result<WriteRequestHandler<W, S>> {
+        let block_size = params.block_size;
+        let mut transfer = ReadTransfer::new(block_size);
+        transfer.set_retries(config.retries);
+        let timeout = try!(RetransmitTimer::new(params.timeout, config, handle));
+        // Errors of the client may be longer than small blocks, the spare
+        // byte tells truncated datagrams like `transport::receive_buffer`.
+        let receive_len = cmp::max(block_size, DEFAULT_BLOCK_SIZE) + 4 + 1;
+        let mut arena = Arena::with_capacity(receive_len + oack.as_ref().map_or(0, |oack| oack.len()));
+        let data_buffer = arena.alloc(receive_len);
+        let oack = oack.map(|oack| arena.alloc_packet(&oack));
+        Ok(WriteRequestHandler {
+            socket: socket,
+            addr: addr,
+            data: data,
+            transfer: transfer,
+            window: AckWindow::new(params.window_size),
+            arena: arena,
+            oack: oack,
+            ack: AckPacket::new(0),
+            send_ack: true,
+            block: data_buffer.slice(0, 0),
+            block_written: None,
+            send_buffer: Vec::with_capacity(AckPacket::new(0).len()),
+            data_buffer: data_buffer,
+            timeout: timeout,
+            timeout_duration: params.timeout,
+            unexpected_packets: config.unexpected_packets,
+            conformance: config.conformance,
+            reasons: config.reasons.clone(),
+            wrong_tid_limit: config.wrong_tid_limit.clone(),
+            deadline: try!(transfer_deadline(config, handle)),
+            token: None,
+            stats: transfer_stats(config),
+        })
+    }
 
-    let acceptor = RequestAcceptor::new(socket);
-    let server = acceptor.for_each(|client_request| {
-        println!("mode = {:?}, filename = {:?}", client_request.request.mode(), client_request.request.filename());
+    /// Lets the client continue the transfer from another address with `token`.
+    fn resumable(mut self, token: Option<Token>) -> WriteRequestHandler<W, S> {
+        self.token = token;
+        self
+    }
 
-        handle.spawn({
-            let mut addr = addr.clone();
-            addr.set_port(0);
-            let socket = UdpSocket::bind(&addr, &handle).unwrap();
-            RequestHandler::new(socket, client_request).map_err(|_| ())
-        });
+    /// Writes the received block, the block is acknowledged once it's
+    /// written if it ends a window.
+    fn write_block(&mut self) -> Poll<(), io::Error> {
+        if let Some(mut written) = self.block_written {
+            while written < self.block.len() {
+                written += try_ready!(self.data.poll_write(&self.arena.get(self.block)[written..]));
+                self.block_written = Some(written);
+            }
+            // The last acknowledgment tells the client the file is stored.
+            if self.transfer.is_done() {
+                try_ready!(self.data.shutdown());
+            }
+            self.block_written = None;
+            self.send_ack = self.window.accepted(self.transfer.is_done());
+        }
+        Ok(Async::Ready(()))
+    }
+}
 
-        Ok(())
-    });
+impl<W: AsyncWrite, S: Transport> Future for WriteRequestHandler<W, S> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        try!(check_deadline(&mut self.deadline, &mut self.socket, &self.addr, &self.reasons, &mut self.stats));
+        loop {
+            match self.write_block() {
+                Ok(Async::Ready(())) => {}
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => {
+                    // The client learns the upload failed, e.g. because the handler rejected it.
+                    let packet = self.reasons.error_packet(io_error_code(&e), Reason::LocalError, &e.to_string());
+                    let sent = send_packet(&mut self.socket, &packet, &self.addr, &mut self.send_buffer);
+                    self.stats.sent(&sent);
+                    return Err(e)
+                }
+            }
+            if self.send_ack {
+                // Option acknowledgment replaces the acknowledgment of the write request.
+                let sent = match self.oack {
+                    Some(oack) => self.socket.send_to(self.arena.get(oack), &self.addr),
+                    None => send_packet(&mut self.socket, &self.ack, &self.addr, &mut self.send_buffer),
+                };
+                self.stats.sent(&sent);
+                try_nb!(sent);
+                self.send_ack = false;
+                self.window.acknowledged();
+                if self.transfer.is_done() {
+                    return Ok(().into())
+                }
+                self.timeout.reset(Instant::now() + self.timeout_duration);
+            }
+
+            let (n, from) = match self.socket.recv_from(self.arena.get_mut(self.data_buffer)) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if try!(self.timeout.poll()).is_not_ready() {
+                        return Ok(Async::NotReady)
+                    }
+                    match self.transfer.timeout() {
+                        transfer::Timeout::Retransmit => {
+                            debug!("Retransmitting to {:?}", self.addr);
+                            self.stats.retransmission();
+                            self.send_ack = true;
+                            continue
+                        }
+                        _ => return Err(io::Error::new(io::ErrorKind::TimedOut, "transfer timed out")),
+                    }
+                }
+                Err(e) => return Err(e),
+            };
+            self.stats.received();
+            if from != self.addr {
+                if resumes(&self.arena.get(self.data_buffer)[..n], self.token) {
+                    info!("{:?} continues writing from {:?}", self.addr, from);
+                    self.addr = from;
+                    self.send_ack = true;
+                    continue
+                }
+                reject_unknown_tid(&mut self.socket, &from, &self.reasons, &self.wrong_tid_limit, &mut self.stats);
+                continue
+            }
+            let datagram = self.arena.get(self.data_buffer);
+            if transport::is_truncated(n, datagram) {
+                return Err(packet_too_large(&mut self.socket, &self.addr, datagram.len() - 1, &self.reasons,
+                                            &mut self.stats))
+            }
+            if refuse_unterminated(&datagram[..n], &self.addr, self.conformance, &mut self.stats) {
+                continue
+            }
+            if let Some(error) = client_error(&datagram[..n]) {
+                return Err(error)
+            }
+            let data_packet = match DataPacketOctet::decode(&datagram[..n]) {
+                Some(data_packet) => data_packet,
+                None => {
+                    try!(unexpected_packet(&mut self.socket, &self.addr, &datagram[..n], Opcode::DATA,
+                                           self.unexpected_packets, &self.reasons, &mut self.stats));
+                    continue
+                }
+            };
+            trace!("Received data packet id = {} length = {}", data_packet.block_id(), data_packet.data().len());
+            if data_packet.data().len() > self.transfer.block_size() {
+                return Err(packet_too_large(&mut self.socket, &self.addr, self.transfer.block_size() + 4,
+                                            &self.reasons, &mut self.stats))
+            }
+            let len = data_packet.data().len();
+            match self.transfer.receive_data(&data_packet) {
+                DataReceived::Accepted(ack) => {
+                    // The data follows the opcode and the block number.
+                    self.block = self.data_buffer.slice(4, len);
+                    self.block_written = Some(0);
+                    self.oack = None;
+                    self.ack = ack;
+                }
+                DataReceived::Duplicate(_) => {
+                    // Previous acknowledgment was lost, the client sent the block again.
+                    self.stats.duplicate();
+                    self.send_ack = true;
+                }
+                // A block of the window was lost, the client continues after the last accepted one.
+                DataReceived::Ignored => self.send_ack = self.window.skipped(),
+            }
+        }
+    }
+}
+
+impl<W, S: Transport> Drop for WriteRequestHandler<W, S> {
+    fn drop(&mut self) {
+        self.stats.arena(self.arena.high_water());
+    }
+}
 
-    l.run(server).unwrap();
+/// Retransmission timer of a transfer, on the reactor or on a timer wheel.
+enum RetransmitTimer {
+    Reactor(Timeout),
+    Wheel(wheel::Timer),
+}
+
+impl RetransmitTimer {
+    fn new(timeout: Duration, config: &ServerConfig, handle: &Handle) -> io::Result<RetransmitTimer> {
+        match config.timer_wheel {
+            Some(ref wheel) => Ok(RetransmitTimer::Wheel(wheel.timer(Instant::now() + timeout, handle))),
+            None => Timeout::new(timeout, handle).map(RetransmitTimer::Reactor),
+        }
+    }
+
+    fn reset(&mut self, at: Instant) {
+        match *self {
+            RetransmitTimer::Reactor(ref mut timeout) => timeout.reset(at),
+            RetransmitTimer::Wheel(ref mut timer) => timer.reset(at),
+        }
+    }
+}
+
+impl Future for RetransmitTimer {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            RetransmitTimer::Reactor(ref mut timeout) => timeout.poll(),
+            RetransmitTimer::Wheel(ref mut timer) => timer.poll(),
+        }
+    }
+}
+
+/// Starts the timer of the deadline of a transfer if one is configured.
+fn transfer_deadline(config: &ServerConfig, handle: &Handle) -> io::Result<Option<Timeout>> {
+    match config.deadline {
+        Some(deadline) => Timeout::new(deadline, handle).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Fails a transfer once its deadline passed, the client is told with an error
+/// packet.
+fn check_deadline<S: Transport>(deadline: &mut Option<Timeout>, socket: &mut S, addr: &S::Addr, reasons: &ReasonFormat,
+                                stats: &mut Recorder) -> io::Result<()> {
+    if let Some(ref mut deadline) = *deadline {
+        if try!(deadline.poll()).is_ready() {
+            let packet = reasons.error_packet(packet::Error::Undefined, Reason::DeadlineExceeded,
+                                              "transfer deadline exceeded").encode();
+            let sent = socket.send_to(packet.packet_buf(), addr);
+            stats.sent(&sent);
+            if let Err(e) = sent {
+                warn!("Could not send error to {:?}: {}", addr, e);
+            }
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "transfer deadline exceeded"))
+        }
+    }
+    Ok(())
+}
+
+/// Returns the recorder of the counters of a transfer.
+fn transfer_stats(config: &ServerConfig) -> Recorder {
+    let mut stats = Recorder::new(config.stats.clone());
+    stats.conformance(config.conformance);
+    stats
+}
+
+/// Counts a datagram of the client lacking its terminating zero byte, returns
+/// `true` if it's dropped because the transfer is strict.
+fn refuse_unterminated<A: fmt::Debug>(datagram: &[u8], addr: &A, conformance: Conformance, stats: &mut Recorder)
+                                      -> bool {
+    if !packet::lacks_terminator(datagram) {
+        return false
+    }
+    stats.deviation(conformance);
+    if !conformance.is_strict() {
+        return false
+    }
+    warn!("Ignoring unterminated packet from {:?}", addr);
+    stats.unexpected();
+    true
+}
+
+/// Returns an error if the datagram is an error packet sent by the client.
+fn client_error(datagram: &[u8]) -> Option<io::Error> {
+    ErrorPacket::decode(datagram).map(|error| {
+        io::Error::new(io::ErrorKind::Other, format!("client terminated the transfer: {}", error))
+    })
+}
+
+/// Handles a datagram that is not the `expected` packet of the transfer,
+/// returns an error if the transfer has to stop.
+///
+/// Malformed packets of the expected type and datagrams without a valid opcode
+/// are dropped regardless of the policy.
+fn unexpected_packet<S: Transport>(socket: &mut S, addr: &S::Addr, datagram: &[u8], expected: Opcode,
+                                   policy: UnexpectedPacketPolicy, reasons: &ReasonFormat, stats: &mut Recorder)
+                                   -> io::Result<()> {
+    stats.unexpected();
+    if datagram.len() < 2 {
+        return Ok(())
+    }
+    match Opcode::from_u16((datagram[0] as u16) << 8 | datagram[1] as u16) {
+        Some(opcode) if opcode != expected => warn!("Unexpected {:?} packet from {:?}", opcode, addr),
+        _ => return Ok(()),
+    }
+    match policy {
+        UnexpectedPacketPolicy::Ignore => return Ok(()),
+        UnexpectedPacketPolicy::Abort => {}
+        UnexpectedPacketPolicy::Reject => {
+            let packet = reasons.error_packet(packet::Error::IllegalOperation, Reason::UnexpectedPacket,
+                                              "unexpected packet").encode();
+            let sent = socket.send_to(packet.packet_buf(), addr);
+            stats.sent(&sent);
+            if let Err(e) = sent {
+                warn!("Could not send error to {:?}: {}", addr, e);
+            }
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected packet from client"))
+}
+
+/// Fails a transfer because the client sent a datagram longer than `max_len`
+/// bytes, the client is told with an error packet.
+fn packet_too_large<S: Transport>(socket: &mut S, addr: &S::Addr, max_len: usize, reasons: &ReasonFormat,
+                                  stats: &mut Recorder) -> io::Error {
+    warn!("Oversized packet from {:?}", addr);
+    stats.unexpected();
+    let packet = reasons.error_packet(packet::Error::IllegalOperation, Reason::OversizedPacket,
+                                      "packet too large").encode();
+    let sent = socket.send_to(packet.packet_buf(), addr);
+    stats.sent(&sent);
+    if let Err(e) = sent {
+        warn!("Could not send error to {:?}: {}", addr, e);
+    }
+    PacketTooLarge { max_len: max_len }.into()
+}
+
+/// Tells a host that sent a packet to a transfer socket that it's not part of
+/// the transfer, unless it exceeded `limit`.
+fn reject_unknown_tid<S: Transport>(socket: &mut S, addr: &S::Addr, reasons: &ReasonFormat, limit: &RateLimit,
+                                    stats: &mut Recorder) {
+    warn!("Packet from unknown transfer id {:?}", addr);
+    stats.wrong_tid();
+    if let Some(ip) = socket.host_ip(addr) {
+        if !limit.allow(ip) {
+            stats.wrong_tid_suppressed();
+            return
+        }
+    }
+    let packet = reasons.error_packet(packet::Error::UnknownTransferId, Reason::UnknownTransferId,
+                                      "unknown transfer id").encode();
+    let sent = socket.send_to(packet.packet_buf(), addr);
+    stats.sent(&sent);
+    if let Err(e) = sent {
+        warn!("Could not send error to {:?}: {}", addr, e);
+    }
+}
+
+/// Rejects a request with an error sent from the socket of the transfer.
+fn reject_request<E: Endpoint>(socket: &E::Unregistered, addr: &E::Addr, error: packet::Error, reason: Reason,
+                               message: &str, config: &ServerConfig) {
+    let packet = config.reasons.error_packet(error, reason, message).encode();
+    let sent = E::send_unregistered(socket, packet.packet_buf(), addr);
+    Recorder::new(config.stats.clone()).sent(&sent);
+    if let (Ok(n), Some(tap)) = (sent.as_ref(), E::tap(&config.tap)) {
+        tap.observe(Direction::Sent, addr, &packet.packet_buf()[..*n]);
+    }
+    if let Err(e) = sent {
+        warn!("Could not send error to {:?}: {}", addr, e);
+    }
+}
+
+/// Negotiates the options requested by the client.
+///
+/// Returns the parameters of the transfer and the options to acknowledge. Unknown
+/// options and invalid values are ignored as allowed by RFC 2347, transfers
+/// without a requested timeout use `timeout`.
+fn negotiate(options: &TransferOptions, max_block_size: BlockSize, max_window_size: WindowSize, timeout: Duration)
+             -> (TransferParams, Option<OptionAckPacket<'static>>) {
+    let mut params = TransferParams::new(DEFAULT_BLOCK_SIZE, timeout);
+    let mut acknowledged = TransferOptions::new();
+    match options.get(BLKSIZE_OPTION).and_then(|value| value.parse::<usize>().ok()) {
+        Some(requested) if requested >= MIN_BLOCK_SIZE => {
+            params.block_size = cmp::min(requested, max_block_size.get());
+            acknowledged.insert(BLKSIZE_OPTION, params.block_size.to_string());
+        }
+        _ => {}
+    }
+    if let Some((name, requested)) = transfer::requested_timeout(options) {
+        params.timeout = requested;
+        acknowledged.insert(name, options.get(name).unwrap_or_default().to_owned());
+    }
+    // Without windows the option is left unacknowledged, even a window of one block.
+    if max_window_size != WindowSize::default() {
+        if let Some(window_size) = transfer::requested_window_size(options, max_window_size) {
+            params.window_size = window_size;
+            acknowledged.insert(WINDOWSIZE_OPTION, window_size.get().to_string());
+        }
+    }
+    if acknowledged.is_empty() {
+        (params, None)
+    } else {
+        (params, Some(OptionAckPacket::new(acknowledged)))
+    }
+}
+
+/// Adds the options the handler acknowledges to the ones the server negotiated.
+///
+/// Options the client didn't request and the ones the server negotiates
+/// itself are left out.
+fn acknowledge_extensions(oack: Option<OptionAckPacket<'static>>, requested: &TransferOptions,
+                          extensions: &TransferOptions<'static>) -> Option<OptionAckPacket<'static>> {
+    let mut acknowledged = oack.map(|oack| oack.options().clone()).unwrap_or_default();
+    for &(ref name, ref value) in extensions.iter() {
+        let own = [BLKSIZE_OPTION, TIMEOUT_OPTION, UTIMEOUT_OPTION, WINDOWSIZE_OPTION].iter()
+            .any(|own| name.eq_ignore_ascii_case(own));
+        if requested.get(name).is_some() && !own {
+            acknowledged.insert(name.clone(), value.clone());
+        }
+    }
+    if acknowledged.is_empty() {
+        None
+    } else {
+        Some(OptionAckPacket::new(acknowledged))
+    }
+}
+
+fn io_error_code(err: &io::Error) -> packet::Error {
+    match err.kind() {
+        io::ErrorKind::NotFound => packet::Error::FileNotFound,
+        io::ErrorKind::PermissionDenied => packet::Error::AccessViolation,
+        io::ErrorKind::AlreadyExists => packet::Error::FileAlreadyExists,
+        _ => packet::Error::Undefined,
+    }
+}
+
+/// Returns the priority the handler assigns the transfer of a request.
+fn request_priority<E: Endpoint, H: Handler>(handler: &H, config: &ServerConfig, client_request: &ClientRequest<E::Addr>)
+                                             -> Priority {
+    let request = &client_request.request;
+    match request.filename() {
+        Some(filename) => {
+            let (params, _) = negotiate(request.options(), config.max_block_size, config.max_window_size,
+                                        config.timeout);
+            let client_addr = E::network_addr(&client_request.addr);
+            handler.priority(&Request::new(&filename, request.mode(), client_addr).with_params(params)
+                .with_options(request.options()))
+        }
+        None => Priority::default(),
+    }
+}
+
+fn handle_request<E: Endpoint, H: Handler>(handle: &Handle, config: &Rc<ServerConfig>, handler: &H, local: &E::Local,
+                                           client_request: ClientRequest<E::Addr>, slot: Slot,
+                                           sessions: Option<&Rc<Registry>>, prefetch: &Rc<Prefetch<H::Reader>>)
+                                           -> io::Result<()> {
+    let socket = try!(E::bind_transfer(local));
+    let client_addr = client_request.addr;
+    let request = &client_request.request;
+
+    let filename = match request.filename() {
+        Some(filename) => filename.into_owned(),
+        None => {
+            warn!("Rejecting request for {:?} from {:?}", request.filename_lossy(), client_addr);
+            reject_request::<E>(&socket, &client_addr, packet::Error::AccessViolation, Reason::InvalidFileName,
+                                "invalid file name", config);
+            return Ok(())
+        }
+    };
+    let (params, oack) = negotiate(request.options(), config.max_block_size, config.max_window_size,
+                                   config.timeout);
+    let handler_request = Request::new(&filename, request.mode(), E::network_addr(&client_addr)).with_params(params)
+        .with_options(request.options());
+    let oack = acknowledge_extensions(oack, request.options(), &handler.acknowledge_options(&handler_request));
+    let (oack, token) = session_token(oack, request.options(), config);
+    let reactor = handle.clone();
+    let config = config.clone();
+
+    match request.opcode() {
+        Opcode::RRQ => {
+            info!("{:?} reads {} ({})", client_addr, filename, params);
+            let session = match (sessions, E::transfer_addr(&socket), E::network_addr(&client_addr)) {
+                (Some(sessions), Some(local), Some(client)) => Some(Registry::track(sessions, Entry {
+                    client: client,
+                    local: local,
+                    filename: filename.clone(),
+                    mode: request.mode(),
+                    block_size: params.block_size,
+                    acked_blocks: 0,
+                })),
+                _ => None,
+            };
+            let open = match prefetch.take(&handler_request) {
+                Some(reader) => {
+                    debug!("{} was opened ahead of the request", filename);
+                    Either::A(future::ok(reader))
+                }
+                None => Either::B(handler.open_read(&handler_request, handle)),
+            };
+            Prefetch::start(prefetch, handler, &handler_request, handle);
+            let priority = handler.priority(&handler_request);
+            let addr = client_addr.clone();
+            spawn_transfer::<E, _, _, _>(handle, socket, client_addr, config.clone(), slot, "reading", filename.clone(),
+                                         open,
+                                         move |socket, data| {
+                let socket = transfer_socket(&config, socket);
+                ReadRequestHandler::new(&reactor, socket, addr, data, params, oack, &config)
+                    .map(|transfer| transfer.tracked(session).prioritized(priority).resumable(token))
+            });
+        }
+        _ => {
+            if config.read_only {
+                warn!("Rejecting write of {} from {:?}, server is read-only", filename, client_addr);
+                reject_request::<E>(&socket, &client_addr, packet::Error::AccessViolation, Reason::ReadOnly,
+                                    "server is read-only", &config);
+                return Ok(())
+            }
+            info!("{:?} writes {} ({})", client_addr, filename, params);
+            let open = handler.open_write(&handler_request, handle);
+            let addr = client_addr.clone();
+            spawn_transfer::<E, _, _, _>(handle, socket, client_addr, config.clone(), slot, "writing", filename.clone(),
+                                         open,
+                                         move |socket, data| {
+                let socket = transfer_socket(&config, socket);
+                WriteRequestHandler::new(&reactor, socket, addr, data, params, oack, &config)
+                    .map(|transfer| transfer.resumable(token))
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Continues a read transfer recorded in the snapshot of a previous server.
+///
+/// The transfer socket is bound to its previous address again, the file is
+/// opened and the next block is sent once the acknowledged ones are skipped.
+fn resume_transfer<E: Endpoint, H: Handler>(handle: &Handle, config: &Rc<ServerConfig>, handler: &H, entry: Entry,
+                                            slot: Slot, sessions: &Rc<Registry>) -> io::Result<()> {
+    let (socket, client_addr) = match E::rebind_transfer(&entry) {
+        Some(bound) => try!(bound),
+        None => return Ok(()),
+    };
+    info!("{:?} resumes reading {} after block {}", client_addr, entry.filename, entry.acked_blocks);
+    let params = TransferParams::new(entry.block_size, config.timeout);
+    let handler_request = Request::new(&entry.filename, entry.mode, Some(entry.client)).with_params(params);
+    let open = handler.open_read(&handler_request, handle);
+    let priority = handler.priority(&handler_request);
+    let reactor = handle.clone();
+    let config = config.clone();
+    let addr = client_addr.clone();
+    let filename = entry.filename.clone();
+    let acked_blocks = entry.acked_blocks;
+    let session = Registry::track(sessions, entry);
+    spawn_transfer::<E, _, _, _>(handle, socket, client_addr, config.clone(), slot, "reading", filename, open,
+                                 move |socket, data| {
+        let socket = transfer_socket(&config, socket);
+        ReadRequestHandler::new(&reactor, socket, addr, data, params, None, &config)
+            .map(|transfer| transfer.tracked(Some(session)).prioritized(priority).resume(acked_blocks))
+    });
+    Ok(())
+}
+
+/// Runs a transfer once the handler opened the file, the request is rejected
+/// if the file can't be opened. The slot is freed once the transfer ended.
+fn spawn_transfer<E, O, F, T>(handle: &Handle, socket: E::Unregistered, client_addr: E::Addr,
+                              config: Rc<ServerConfig>, slot: Slot, action: &'static str, filename: String, open: O,
+                              start: F)
+    where E: Endpoint,
+          O: Future<Error = io::Error> + 'static,
+          F: FnOnce(E, O::Item) -> io::Result<T> + 'static,
+          T: Future<Item = (), Error = io::Error> + 'static,
+{
+    slot.describe(AbortedTransfer {
+        client: E::network_addr(&client_addr),
+        filename: filename.clone(),
+        upload: action == "writing",
+    });
+    let reactor = handle.clone();
+    handle.spawn(open.then(move |opened| {
+        let data = match opened {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Can't open {} for {:?}: {}", filename, client_addr, e);
+                reject_request::<E>(&socket, &client_addr, io_error_code(&e), Reason::OpenFailed, &e.to_string(),
+                                    &config);
+                return Either::A(future::ok(()))
+            }
+        };
+        let transfer = E::register(socket, &reactor).and_then(|socket| start(socket, data));
+        Either::B(future::result(transfer).flatten().then(move |result| {
+            match result {
+                Ok(()) => info!("{:?} finished {} {}", client_addr, action, filename),
+                Err(e) => warn!("{:?} failed {} {}: {}", client_addr, action, filename, e),
+            }
+            drop(slot);
+            Ok(())
+        }))
+    }));
+}
+
+/// Time a file opened ahead of a request is kept open, see `Handler::follow_up`.
+const FOLLOW_UP_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Files opened ahead of the read requests of clients, see `Handler::follow_up`.
+///
+/// One file is kept per client, files not requested before they expire are
+/// closed.
+struct Prefetch<R> {
+    files: RefCell<Vec<Prefetched<R>>>,
+}
+
+/// File opened for the next read request of a client, `None` while the
+/// handler is opening it.
+struct Prefetched<R> {
+    client: IpAddr,
+    filename: String,
+    mode: Mode,
+    started: Instant,
+    reader: Option<R>,
+}
+
+impl<R> Prefetched<R> {
+    fn matches(&self, client: IpAddr, filename: &str, mode: Mode) -> bool {
+        self.client == client && self.mode == mode &&
+            self.filename.trim_left_matches('/') == filename.trim_left_matches('/')
+    }
+}
+
+impl<R: 'static> Prefetch<R> {
+    fn new() -> Prefetch<R> {
+        Prefetch {
+            files: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Opens the follow-up of the read `request` in the background, if the
+    /// handler announces one.
+    fn start<H: Handler<Reader = R>>(prefetch: &Rc<Prefetch<R>>, handler: &H, request: &Request, handle: &Handle) {
+        let (client, filename) = match (request.client_addr(), handler.follow_up(request)) {
+            (Some(addr), Some(filename)) => (addr.ip(), filename),
+            _ => return,
+        };
+        let mode = request.mode();
+        {
+            let mut files = prefetch.files.borrow_mut();
+            if files.iter().any(|file| file.matches(client, &filename, mode)) {
+                return
+            }
+            files.retain(|file| file.client != client);
+            files.push(Prefetched {
+                client: client,
+                filename: filename.clone(),
+                mode: mode,
+                started: Instant::now(),
+                reader: None,
+            });
+        }
+        debug!("Opening {} ahead of the request of {}", filename, client);
+        let open = handler.open_read(&Request::new(&filename, mode, request.client_addr()), handle);
+        let prefetch = prefetch.clone();
+        handle.spawn(open.then(move |opened| {
+            let mut files = prefetch.files.borrow_mut();
+            // The client may have requested the file while it was opened.
+            let pending = files.iter().position(|file| file.reader.is_none() && file.matches(client, &filename, mode));
+            match (opened, pending) {
+                (Ok(reader), Some(i)) => files[i].reader = Some(reader),
+                (Err(e), Some(i)) => {
+                    debug!("Can't open {} ahead of the request of {}: {}", filename, client, e);
+                    files.remove(i);
+                }
+                _ => {}
+            }
+            Ok(())
+        }));
+    }
+
+    /// Returns the file opened ahead of a read request, if it's ready.
+    fn take(&self, request: &Request) -> Option<R> {
+        let client = match request.client_addr() {
+            Some(addr) => addr.ip(),
+            None => return None,
+        };
+        let mut files = self.files.borrow_mut();
+        let now = Instant::now();
+        files.retain(|file| now.duration_since(file.started) < FOLLOW_UP_EXPIRY);
+        files.iter().position(|file| file.matches(client, request.filename(), request.mode()))
+            .and_then(|i| files.remove(i).reader)
+    }
+}
+
+/// Socket the server listens on.
+#[derive(Debug, Clone)]
+enum Listen {
+    Udp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Settings overriding the configuration of a server for the clients in a
+/// subnet, see `ServerBuilder::subnet`.
+///
+/// Settings that are not set keep the value of the server.
+#[derive(Debug, Clone, Default)]
+pub struct SubnetConfig {
+    root: Option<PathBuf>,
+    read_only: Option<bool>,
+    max_block_size: Option<BlockSize>,
+    timeout: Option<Duration>,
+    retries: Option<Retries>,
+}
+
+impl SubnetConfig {
+    /// Creates overrides that change nothing.
+    pub fn new() -> SubnetConfig {
+        SubnetConfig::default()
+    }
+
+    /// Serves the files of the directory `root` to the subnet, instead of
+    /// asking the handler of the server.
+    pub fn root<P: Into<PathBuf>>(mut self, root: P) -> SubnetConfig {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Rejects write requests of the subnet when `read_only` is `true`.
+    pub fn read_only(mut self, read_only: bool) -> SubnetConfig {
+        self.read_only = Some(read_only);
+        self
+    }
+
+    /// Sets the largest block size accepted from the subnet.
+    pub fn max_block_size(mut self, block_size: BlockSize) -> SubnetConfig {
+        self.max_block_size = Some(block_size);
+        self
+    }
+
+    /// Sets the retransmission timeout of transfers with the subnet.
+    pub fn timeout(mut self, timeout: Duration) -> SubnetConfig {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the number of retransmissions of transfers with the subnet.
+    pub fn retries(mut self, retries: Retries) -> SubnetConfig {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Returns the configuration of the server with the overrides applied.
+    fn apply(&self, config: &ServerConfig) -> ServerConfig {
+        let mut config = config.clone();
+        config.read_only = self.read_only.unwrap_or(config.read_only);
+        config.max_block_size = self.max_block_size.unwrap_or(config.max_block_size);
+        config.timeout = self.timeout.unwrap_or(config.timeout);
+        config.retries = self.retries.unwrap_or(config.retries);
+        config
+    }
+
+    /// Returns the problems of the overrides, those of the settings the
+    /// subnet inherits are reported for the server.
+    fn validate(&self, config: &ServerConfig) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        if let Some(Err(err)) = self.timeout.map(config::validate_timeout) {
+            errors.push(err);
+        }
+        if let Some(ref root) = self.root {
+            errors.extend(FsHandler::new(root.clone()).validate(config.read_only));
+        }
+        errors
+    }
+}
+
+/// Configuration of the clients in a subnet, with the handler of its root
+/// directory if it has its own.
+#[derive(Debug, Clone)]
+struct Overlay {
+    subnet: Subnet,
+    config: Rc<ServerConfig>,
+    handler: Option<FsHandler>,
+}
+
+/// Returns the overlay of the client at `addr`, overlays are sorted from the
+/// most specific subnet.
+fn find_overlay(overlays: &[Overlay], addr: Option<SocketAddr>) -> Option<&Overlay> {
+    addr.and_then(|addr| overlays.iter().find(|overlay| overlay.subnet.contains(addr.ip())))
+}
+
+/// Builder for a `Server` with non-default configuration.
+#[derive(Debug, Clone)]
+pub struct ServerBuilder<H = FsHandler> {
+    listen: Listen,
+    config: ServerConfig,
+    subnets: Vec<(Subnet, SubnetConfig)>,
+    handler: H,
+}
+
+impl ServerBuilder {
+    /// Creates a builder for a server listening on `addr`.
+    pub fn new(addr: SocketAddr) -> ServerBuilder {
+        ServerBuilder::listen(Listen::Udp(addr))
+    }
+
+    /// Creates a builder for a server listening on the Unix datagram socket
+    /// at `path`.
+    ///
+    /// The sockets of transfers are created next to it, named by appending a
+    /// counter to `path`. Clients have to bind their sockets to a path too so
+    /// the server can answer them.
+    #[cfg(unix)]
+    pub fn unix<P: Into<PathBuf>>(path: P) -> ServerBuilder {
+        ServerBuilder::listen(Listen::Unix(path.into()))
+    }
+
+    fn listen(listen: Listen) -> ServerBuilder {
+        ServerBuilder {
+            listen: listen,
+            config: ServerConfig {
+                read_only: false,
+                max_block_size: BlockSize::new(config::MAX_BLOCK_SIZE).unwrap(),
+                max_window_size: WindowSize::default(),
+                segmentation_offload: false,
+                timeout: DEFAULT_TIMEOUT,
+                retries: Retries::default(),
+                unexpected_packets: UnexpectedPacketPolicy::default(),
+                conformance: Conformance::default(),
+                deadline: None,
+                keepalive: None,
+                max_transfers: None,
+                session_file: None,
+                filename_codec: FilenameCodec::default(),
+                reasons: ReasonFormat::default(),
+                wrong_tid_limit: RateLimit::default(),
+                bandwidth: BandwidthCap::unlimited(),
+                tap: None,
+                stats: Stats::new(),
+                timer_wheel: None,
+                #[cfg(feature = "experimental-dtls")]
+                dtls: None,
+                #[cfg(feature = "experimental-resume")]
+                rng: SharedRng::from_entropy(),
+            },
+            subnets: Vec::new(),
+            handler: FsHandler::new("."),
+        }
+    }
+
+    /// Sets the directory files are served from and written to.
+    ///
+    /// By default the current directory is used.
+    pub fn root<P: Into<PathBuf>>(mut self, root: P) -> ServerBuilder {
+        self.handler = FsHandler::new(root);
+        self
+    }
+}
+
+impl<H: Handler> ServerBuilder<H> {
+    /// Sets the handler opening the transferred files, replacing the file
+    /// system.
+    pub fn handler<T: Handler>(self, handler: T) -> ServerBuilder<T> {
+        ServerBuilder {
+            listen: self.listen,
+            config: self.config,
+            subnets: self.subnets,
+            handler: handler,
+        }
+    }
+
+    /// Serves files through an ordered table of routes added with `route`,
+    /// replacing the handler. Wrap a route's handler in `ReadOnly` to reject
+    /// writes to it only.
+    pub fn routes(self) -> ServerBuilder<Router> {
+        self.handler(Router::new())
+    }
+
+    /// Overrides the configuration for clients in `subnet`, e.g. to serve a
+    /// lab network from another directory than the production network.
+    ///
+    /// The overrides are resolved for every request. The most specific subnet
+    /// containing the address of the client applies, or the first one added if
+    /// several are equally specific. Clients outside of all subnets, and local
+    /// clients on Unix sockets, get the configuration of the server.
+    pub fn subnet(mut self, subnet: Subnet, config: SubnetConfig) -> ServerBuilder<H> {
+        self.subnets.push((subnet, config));
+        self
+    }
+
+    /// Rejects all write requests when `read_only` is `true`.
+    pub fn read_only(mut self, read_only: bool) -> ServerBuilder<H> {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Sets the largest block size the server accepts during negotiation (RFC 2348).
+    pub fn max_block_size(mut self, block_size: BlockSize) -> ServerBuilder<H> {
+        self.config.max_block_size = block_size;
+        self
+    }
+
+    /// Sets the largest window size the server accepts during negotiation (RFC 7440).
+    ///
+    /// Files read by clients are sent in windows of up to `window_size`
+    /// blocks, after a loss the server pauses between windows until they are
+    /// acknowledged without loss again, see `transfer::CongestionWindow`.
+    /// Files written by clients are acknowledged at the end of each window,
+    /// see `transfer::AckWindow`. By default the option is not acknowledged
+    /// and every block is acknowledged on its own.
+    pub fn max_window_size(mut self, window_size: WindowSize) -> ServerBuilder<H> {
+        self.config.max_window_size = window_size;
+        self
+    }
+
+    /// Sends the data packets of a window together when `offload` is `true`.
+    ///
+    /// On Linux they are passed to the kernel in one system call with UDP
+    /// segmentation offload, see `batch::send_segments`, which saves CPU per
+    /// packet when many clients download with large windows. Where offload
+    /// isn't supported the packets are sent one by one. The bandwidth cap
+    /// paces the window as a whole. By default each packet is sent on its own.
+    pub fn segmentation_offload(mut self, offload: bool) -> ServerBuilder<H> {
+        self.config.segmentation_offload = offload;
+        self
+    }
+
+    /// Sets the time to wait for a response before the last packet is sent again.
+    pub fn timeout(mut self, timeout: Duration) -> ServerBuilder<H> {
+        self.config.timeout = timeout;
+        self
+    }
+
+    /// Sets what transfers do with packets the client must not send in their
+    /// state.
+    ///
+    /// By default the client is sent an illegal operation error and the transfer fails.
+    pub fn unexpected_packets(mut self, policy: UnexpectedPacketPolicy) -> ServerBuilder<H> {
+        self.config.unexpected_packets = policy;
+        self
+    }
+
+    /// Sets how closely clients are held to the RFCs, lenient by default.
+    ///
+    /// Strict servers drop requests and errors lacking their terminating zero
+    /// byte. The conformance and the deviations of the clients are counted in
+    /// `ConformanceStats`.
+    pub fn conformance(mut self, conformance: Conformance) -> ServerBuilder<H> {
+        self.config.conformance = conformance;
+        self
+    }
+
+    /// Limits the time a whole transfer may take, independent of the timeout
+    /// of single packets.
+    ///
+    /// Transfers still running after `deadline` are terminated with an error
+    /// sent to the client. By default transfers take as long as the client
+    /// keeps responding.
+    pub fn deadline(mut self, deadline: Duration) -> ServerBuilder<H> {
+        self.config.deadline = Some(deadline);
+        self
+    }
+
+    /// Runs the retransmission timers of transfers on a timer wheel.
+    ///
+    /// Transfers reset their timer for every packet they send. With many
+    /// concurrent transfers a wheel ticking every few milliseconds is cheaper
+    /// than a reactor timeout per transfer, timers fire up to a tick late. The
+    /// drift is collected in the `TimerStats` of `wheel`. By default every
+    /// transfer has its own reactor timeout.
+    pub fn timer_wheel(mut self, wheel: TimerWheel) -> ServerBuilder<H> {
+        self.config.timer_wheel = Some(wheel);
+        self
+    }
+
+    /// Keeps clients waiting while the handler is slow to produce the next
+    /// block of a read transfer.
+    ///
+    /// Whenever producing a block takes another `interval`, the previous block
+    /// is sent again, the client acknowledges the duplicate and restarts its
+    /// timeout. The interval should be shorter than the timeout of clients.
+    /// Stalls are logged and counted, see `ProtocolStats::keepalives`. By
+    /// default nothing is sent until the block is ready.
+    pub fn keepalive(mut self, interval: Duration) -> ServerBuilder<H> {
+        self.config.keepalive = Some(interval);
+        self
+    }
+
+    /// Limits the number of transfers running at the same time.
+    ///
+    /// Requests over the limit wait until a transfer ends, those the handler
+    /// gives a higher `Priority` start first. A waiting request is dropped once
+    /// the client stopped repeating it for as long as the server waits for a
+    /// client before a transfer fails. A limit of zero is treated as one. By
+    /// default the number of transfers is not limited.
+    pub fn max_transfers(mut self, max_transfers: usize) -> ServerBuilder<H> {
+        self.config.max_transfers = Some(cmp::max(max_transfers, 1));
+        self
+    }
+
+    /// Records running read transfers in the snapshot file at `path` so a
+    /// restarted server can resume them (experimental).
+    ///
+    /// The snapshot is saved every `snapshot::SAVE_INTERVAL`. A server started with
+    /// a snapshot saved less than the timeout times the retries ago binds the
+    /// recorded transfer sockets again and continues the transfers, see the
+    /// `snapshot` module. Only transfers over UDP without DTLS are recorded.
+    pub fn session_file<P: Into<PathBuf>>(mut self, path: P) -> ServerBuilder<H> {
+        self.config.session_file = Some(path.into());
+        self
+    }
+
+    /// Sets the number of retransmissions of a packet before a transfer fails.
+    pub fn retries(mut self, retries: Retries) -> ServerBuilder<H> {
+        self.config.retries = retries;
+        self
+    }
+
+    /// Sets the encoding of file names in requests, e.g. for legacy clients
+    /// sending names with non-ASCII characters in Latin-1.
+    ///
+    /// Requests with names that are not valid in the encoding are ignored. By
+    /// default names are expected in UTF-8, other names are rejected with an
+    /// error.
+    pub fn filename_codec(mut self, codec: FilenameCodec) -> ServerBuilder<H> {
+        self.config.filename_codec = codec;
+        self
+    }
+
+    /// Sets the prefixes of the messages of error packets the server sends,
+    /// by default they start with the standard reason code, see `reason`.
+    pub fn reason_format(mut self, format: ReasonFormat) -> ServerBuilder<H> {
+        self.config.reasons = format;
+        self
+    }
+
+    /// Limits the unknown transfer ID errors answering datagrams from hosts
+    /// that aren't the peer of a transfer, so spoofed datagrams can't make
+    /// the server flood a victim with errors. By default a host gets 5 errors
+    /// per second from all transfers together.
+    pub fn wrong_tid_limit(mut self, limit: RateLimit) -> ServerBuilder<H> {
+        self.config.wrong_tid_limit = limit;
+        self
+    }
+
+    /// Caps the bytes per second of the data packets of all transfers, see
+    /// the `bandwidth` module. Servers built with clones of `cap` share it,
+    /// e.g. the IPv4 and the IPv6 server of a host. By default there is no
+    /// cap.
+    pub fn bandwidth_cap(mut self, cap: BandwidthCap) -> ServerBuilder<H> {
+        self.config.bandwidth = cap;
+        self
+    }
+
+    /// Passes the datagrams of the UDP sockets of the server to `tap`, the
+    /// requests received on the listening socket and the datagrams of the
+    /// transfers. Datagrams of transfers protected by DTLS are passed
+    /// encrypted.
+    pub fn tap(mut self, tap: Tap) -> ServerBuilder<H> {
+        self.config.tap = Some(tap);
+        self
+    }
+
+    /// Collects the counters of the transfers of the server in `stats`.
+    ///
+    /// By default the server has its own collector, see `Server::stats`.
+    /// Passing one in lets another thread read the counters while the server
+    /// runs.
+    pub fn stats(mut self, stats: Stats) -> ServerBuilder<H> {
+        self.config.stats = stats;
+        self
+    }
+
+    /// Protects transfers with DTLS using `context` (experimental).
+    ///
+    /// Requests are still received in the clear, see the `dtls` module. The
+    /// block size is limited to `dtls::MAX_BLOCK_SIZE`.
+    #[cfg(feature = "experimental-dtls")]
+    pub fn dtls(mut self, context: SslContext) -> ServerBuilder<H> {
+        self.config.dtls = Some(context);
+        self
+    }
+
+    /// Creates the configured server.
+    ///
+    /// Fails with all invalid settings at once, see `ConfigError::errors`:
+    /// zero durations, a root directory that doesn't exist or isn't writable
+    /// although the server accepts writes, other problems the handler finds
+    /// with `Handler::validate` and subnets configured twice.
+    #[cfg_attr(not(feature = "experimental-dtls"), allow(unused_mut))]
+    pub fn build(mut self) -> result::Result<Server<H>, ConfigError> {
+        let mut errors = Vec::new();
+        if let Err(err) = config::validate_timeout(self.config.timeout) {
+            errors.push(err);
+        }
+        if self.config.deadline == Some(Duration::new(0, 0)) {
+            errors.push(ConfigError::ZeroDuration("deadline"));
+        }
+        if self.config.keepalive == Some(Duration::new(0, 0)) {
+            errors.push(ConfigError::ZeroDuration("keepalive interval"));
+        }
+        errors.extend(self.handler.validate(self.config.read_only));
+        #[cfg(feature = "experimental-dtls")]
+        {
+            if self.config.dtls.is_some() && self.config.max_block_size.get() > dtls::MAX_BLOCK_SIZE {
+                self.config.max_block_size = BlockSize::new(dtls::MAX_BLOCK_SIZE).unwrap();
+            }
+            // The DTLS sessions of the clients end with the server.
+            if self.config.dtls.is_some() && self.config.session_file.take().is_some() {
+                warn!("Transfers over DTLS can't be resumed, not recording them");
+            }
+        }
+        let mut overlays: Vec<Overlay> = Vec::with_capacity(self.subnets.len());
+        for (subnet, overrides) in self.subnets {
+            let config = overrides.apply(&self.config);
+            let context = format!("subnet {}", subnet);
+            errors.extend(overrides.validate(&config).into_iter().map(|err| err.within(&context)));
+            if overlays.iter().any(|overlay| overlay.subnet == subnet) {
+                errors.push(ConfigError::DuplicateSubnet(subnet));
+            }
+            overlays.push(Overlay {
+                subnet: subnet,
+                config: Rc::new(config),
+                handler: overrides.root.map(FsHandler::new),
+            });
+        }
+        try!(config::all_valid(errors));
+        // The sort is stable, equally specific subnets stay in the order they were added.
+        overlays.sort_by(|a, b| b.subnet.prefix_len().cmp(&a.subnet.prefix_len()));
+        Ok(Server {
+            listen: self.listen,
+            config: self.config,
+            overlays: overlays,
+            handler: Rc::new(self.handler),
+            drain: Arc::new(DrainState::default()),
+        })
+    }
+}
+
+impl ServerBuilder<Router> {
+    /// Adds a route serving file names starting with `prefix` from `handler`.
+    ///
+    /// Routes are tried in the order they are added, see `Router`.
+    pub fn route<P: Into<String>, T: Handler>(mut self, prefix: P, handler: T) -> ServerBuilder<Router> {
+        self.handler = self.handler.route(prefix, handler);
+        self
+    }
+}
+
+/// A TFTP server.
+///
+/// Clones of a server share its `DrainHandle`.
+#[derive(Debug, Clone)]
+pub struct Server<H = FsHandler> {
+    listen: Listen,
+    config: ServerConfig,
+    overlays: Vec<Overlay>,
+    handler: Rc<H>,
+    drain: Arc<DrainState>,
+}
+
+/// Transfer a drain aborted because it didn't finish before the deadline.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct AbortedTransfer {
+    /// Network address of the client, `None` for clients on Unix sockets.
+    pub client: Option<SocketAddr>,
+
+    /// Name of the transferred file.
+    pub filename: String,
+
+    /// The client was writing the file, the upload is incomplete.
+    pub upload: bool,
+}
+
+/// Outcome of draining a server.
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
+pub struct DrainReport {
+    /// Number of transfers that finished before the deadline.
+    pub finished: usize,
+
+    /// Transfers still running at the deadline.
+    pub aborted: Vec<AbortedTransfer>,
+}
+
+#[derive(Debug, Default)]
+struct DrainState {
+    state: Mutex<Draining>,
+    drained: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Draining {
+    /// Time the running transfers are given once a drain was requested.
+    deadline: Option<Duration>,
+    /// Server waiting for a drain request.
+    task: Option<Task>,
+    report: Option<DrainReport>,
+}
+
+/// Handle stopping a server running on another thread, see `Server::drain_handle`.
+#[derive(Debug, Clone)]
+pub struct DrainHandle(Arc<DrainState>);
+
+impl DrainHandle {
+    /// Stops accepting new requests and lets the running transfers finish
+    /// for at most `deadline`, e.g. before restarting the server.
+    ///
+    /// The listening socket is closed right away, requests waiting for a
+    /// transfer slot are dropped. Transfers still running at the deadline are
+    /// aborted without notifying their clients, their next packets are
+    /// answered with ICMP port unreachable messages. `Server::run` returns
+    /// once the server is drained.
+    ///
+    /// Blocks until the server is drained. A server that isn't running yet
+    /// drains as soon as it is started.
+    pub fn drain(&self, deadline: Duration) -> DrainReport {
+        let mut state = self.0.state.lock().unwrap();
+        if state.deadline.is_none() {
+            state.deadline = Some(deadline);
+        }
+        if let Some(task) = state.task.take() {
+            task.notify();
+        }
+        while state.report.is_none() {
+            state = self.0.drained.wait(state).unwrap();
+        }
+        state.report.clone().unwrap()
+    }
+}
+
+/// Handle controlling a server running on another thread, see
+/// `Server::handle`.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    drain: DrainHandle,
+    bandwidth: BandwidthCap,
+}
+
+impl ServerHandle {
+    /// Stops the server, see `DrainHandle::drain`.
+    pub fn drain(&self, deadline: Duration) -> DrainReport {
+        self.drain.drain(deadline)
+    }
+
+    /// Caps the bandwidth of the data packets of the server to
+    /// `bytes_per_sec` bytes per second, `None` removes the cap.
+    ///
+    /// The running transfers send their next blocks at the new rate, see the
+    /// `bandwidth` module.
+    pub fn set_max_bandwidth(&self, bytes_per_sec: Option<u64>) {
+        self.bandwidth.set_rate(bytes_per_sec);
+    }
+
+    /// Returns the bandwidth cap of the server in bytes per second, `None` if
+    /// there is none.
+    pub fn max_bandwidth(&self) -> Option<u64> {
+        self.bandwidth.rate()
+    }
+}
+
+/// Completes with the deadline of the drain once one was requested.
+struct DrainRequest(Arc<DrainState>);
+
+impl Future for DrainRequest {
+    type Item = Duration;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Duration, io::Error> {
+        let mut state = self.0.state.lock().unwrap();
+        match state.deadline {
+            Some(deadline) => Ok(Async::Ready(deadline)),
+            None => {
+                state.task = Some(task::current());
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+/// Completes once all transfers finished or the deadline passed.
+struct Drained {
+    slots: Rc<Slots>,
+    deadline: Timeout,
+}
+
+impl Future for Drained {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        if self.slots.running.get() == 0 {
+            return Ok(Async::Ready(()))
+        }
+        *self.slots.waiting.borrow_mut() = Some(task::current());
+        self.deadline.poll()
+    }
+}
+
+/// Requests received by a server running in the low-level mode, see
+/// `Server::incoming`.
+pub struct Incoming {
+    acceptor: RequestAcceptor<Tapped<UdpSocket>>,
+    local: SocketAddr,
+    handle: Handle,
+    config: Rc<ServerConfig>,
+    pending: Rc<Pending>,
+}
+
+/// Requests handed to the embedder that weren't answered yet, or were
+/// answered so recently that retransmissions may still arrive.
+#[derive(Default)]
+struct Pending {
+    next_id: Cell<u64>,
+    requests: RefCell<Vec<PendingRequest>>,
+}
+
+struct PendingRequest {
+    id: u64,
+    addr: SocketAddr,
+    request: RequestPacket<'static>,
+    /// Retransmission timeout of the client, repeats are absorbed for this
+    /// long after the answer.
+    timeout: Duration,
+    answered: Option<Instant>,
+}
+
+impl Pending {
+    /// Returns `true` if the request repeats one that is pending or was
+    /// answered within the retransmission timeout.
+    fn contains(&self, addr: SocketAddr, request: &RequestPacket, now: Instant) -> bool {
+        self.requests.borrow_mut().retain(|pending| match pending.answered {
+            Some(answered) => now.duration_since(answered) < pending.timeout,
+            None => true,
+        });
+        self.requests.borrow().iter().any(|pending| pending.addr == addr && pending.request == *request)
+    }
+
+    fn add(&self, addr: SocketAddr, request: RequestPacket<'static>, timeout: Duration) -> u64 {
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.requests.borrow_mut().push(PendingRequest {
+            id: id,
+            addr: addr,
+            request: request,
+            timeout: timeout,
+            answered: None,
+        });
+        id
+    }
+
+    /// Marks a request as answered, returns `false` if it was answered before.
+    fn answer(&self, id: u64) -> bool {
+        let mut requests = self.requests.borrow_mut();
+        match requests.iter_mut().find(|pending| pending.id == id && pending.answered.is_none()) {
+            Some(pending) => {
+                pending.answered = Some(Instant::now());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets a request that wasn't answered, its repeats are new requests.
+    fn forget(&self, id: u64) {
+        self.requests.borrow_mut().retain(|pending| pending.id != id || pending.answered.is_some());
+    }
+}
+
+impl Incoming {
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.acceptor.socket.get_ref().local_addr()
+    }
+
+    /// Rejects the request `id` of the client at `addr` if it's still pending
+    /// after `budget`, by then the client gave up retransmitting it.
+    fn expire(&self, id: u64, addr: SocketAddr, budget: Duration) -> io::Result<()> {
+        let timeout = try!(Timeout::new(budget, &self.handle));
+        let (pending, local, config) = (self.pending.clone(), self.local, self.config.clone());
+        self.handle.spawn(timeout.then(move |_| {
+            if pending.answer(id) {
+                warn!("Rejecting request of {:?}, it wasn't answered in time", addr);
+                match UdpSocket::bind_transfer(&local) {
+                    Ok(socket) => {
+                        reject_request::<UdpSocket>(&socket, &addr, packet::Error::Undefined,
+                                                    Reason::DeadlineExceeded, "request wasn't answered in time",
+                                                    &config)
+                    }
+                    Err(e) => warn!("Could not reject request of {:?}: {}", addr, e),
+                }
+            }
+            Ok(())
+        }));
+        Ok(())
+    }
+}
+
+impl Stream for Incoming {
+    type Item = IncomingRequest;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<IncomingRequest>, io::Error> {
+        loop {
+            let client_request = match try_ready!(self.acceptor.poll()) {
+                Some(client_request) => client_request,
+                None => return Ok(Async::Ready(None)),
+            };
+            if self.pending.contains(client_request.addr, &client_request.request, Instant::now()) {
+                debug!("Ignoring repeated request of {:?} waiting for an answer", client_request.addr);
+                Recorder::new(self.config.stats.clone()).duplicate();
+                continue
+            }
+            debug!("mode = {:?}, filename = {:?} from {:?}", client_request.request.mode(),
+                   client_request.request.filename(), client_request.addr);
+            let (params, _) = negotiate(client_request.request.options(), self.config.max_block_size,
+                                        self.config.max_window_size, self.config.timeout);
+            let budget = params.timeout * self.config.retries.get();
+            let id = self.pending.add(client_request.addr, client_request.request.clone(), params.timeout);
+            try!(self.expire(id, client_request.addr, budget));
+            return Ok(Async::Ready(Some(IncomingRequest {
+                addr: client_request.addr,
+                request: client_request.request,
+                deadline: Instant::now() + budget,
+                local: self.local,
+                handle: self.handle.clone(),
+                config: self.config.clone(),
+                pending: self.pending.clone(),
+                id: id,
+            })))
+        }
+    }
+}
+
+impl fmt::Debug for Incoming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Incoming").field("local", &self.local).finish()
+    }
+}
+
+/// Request of a client, answered by the embedder.
+///
+/// Transfers are started on a new socket with the options negotiated like
+/// `Server::run` does, the configuration of the server applies to them. The
+/// handler of the server, its subnets, the read-only setting and the limit of
+/// the running transfers don't, the embedder decides about each request.
+/// A request that is dropped without answering it is ignored, the client
+/// repeats it until it gives up.
+///
+/// The request can be kept while the embedder waits for a decision about it.
+/// Until it's answered or dropped, the client's retransmissions of the request
+/// are absorbed instead of showing up as new requests. The client waits for
+/// the answer as long as it retransmits the request, a request that wasn't
+/// answered by `deadline` is rejected and answering it fails with `TimedOut`.
+/// Repeats arriving within the retransmission timeout after the answer are
+/// absorbed too, they crossed the answer on the way.
+pub struct IncomingRequest {
+    addr: SocketAddr,
+    request: RequestPacket<'static>,
+    deadline: Instant,
+    local: SocketAddr,
+    handle: Handle,
+    config: Rc<ServerConfig>,
+    pending: Rc<Pending>,
+    id: u64,
+}
+
+impl IncomingRequest {
+    /// Returns the address of the client.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Returns the request packet, with the file name, mode and options.
+    pub fn request(&self) -> &RequestPacket<'static> {
+        &self.request
+    }
+
+    /// Returns `true` if the client writes a file.
+    pub fn is_write(&self) -> bool {
+        self.request.opcode() == Opcode::WRQ
+    }
+
+    /// Returns the time the request has to be answered by, the negotiated
+    /// timeout times the configured retries after it arrived.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Returns the parameters the transfer uses once the request is served.
+    pub fn params(&self) -> TransferParams {
+        negotiate(self.request.options(), self.config.max_block_size, self.config.max_window_size,
+                  self.config.timeout).0
+    }
+
+    /// Serves the file at `path`: a read request reads the file, a write
+    /// request creates it and fails if it exists.
+    ///
+    /// The request is rejected if the file can't be opened.
+    pub fn serve_file<P: AsRef<Path>>(self, path: P) -> Serving {
+        if let Err(e) = self.answer() {
+            return Serving(Box::new(future::err(e)))
+        }
+        let path = path.as_ref();
+        let opened = if self.is_write() {
+            OpenOptions::new().write(true).create_new(true).open(path)
+        } else {
+            File::open(path)
+        };
+        match opened {
+            Ok(file) if self.is_write() => self.serve_writer(AllowStdIo::new(file)),
+            Ok(file) => self.serve_reader(AllowStdIo::new(file)),
+            Err(e) => {
+                warn!("Can't open {} for {:?}: {}", path.display(), self.addr, e);
+                let rejected = self.reject_with(io_error_code(&e), Reason::OpenFailed, &e.to_string());
+                Serving(Box::new(future::result(rejected.and(Err(e)))))
+            }
+        }
+    }
+
+    /// Serves a read request with the data of `reader`.
+    ///
+    /// A write request is rejected, the transfer fails with `InvalidInput`.
+    pub fn serve_reader<R: AsyncRead + 'static>(self, reader: R) -> Serving {
+        if let Err(e) = self.answer() {
+            return Serving(Box::new(future::err(e)))
+        }
+        if self.is_write() {
+            return self.serve_unexpected("write")
+        }
+        info!("{:?} reads {} ({})", self.addr, self.request.filename_lossy(), self.params());
+        let transfer = self.start(|config, handle, socket, addr, params, oack| {
+            ReadRequestHandler::new(handle, socket, addr, reader, params, oack, config)
+        });
+        Serving(Box::new(future::result(transfer).flatten()))
+    }
+
+    /// Serves a write request, storing the data in `writer`.
+    ///
+    /// The writer is shut down once the last block was written, before the
+    /// client is told the file was stored. A read request is rejected, the
+    /// transfer fails with `InvalidInput`.
+    pub fn serve_writer<W: AsyncWrite + 'static>(self, writer: W) -> Serving {
+        if let Err(e) = self.answer() {
+            return Serving(Box::new(future::err(e)))
+        }
+        if !self.is_write() {
+            return self.serve_unexpected("read")
+        }
+        info!("{:?} writes {} ({})", self.addr, self.request.filename_lossy(), self.params());
+        let transfer = self.start(|config, handle, socket, addr, params, oack| {
+            WriteRequestHandler::new(handle, socket, addr, writer, params, oack, config)
+        });
+        Serving(Box::new(future::result(transfer).flatten()))
+    }
+
+    /// Rejects the request, the client gets an error packet with `error` and
+    /// `message`.
+    pub fn reject(self, error: packet::Error, message: &str) -> io::Result<()> {
+        try!(self.answer());
+        let socket = try!(UdpSocket::bind_transfer(&self.local));
+        let packet = ErrorPacket::new(error, message).encode();
+        let sent = UdpSocket::send_unregistered(&socket, packet.packet_buf(), &self.addr);
+        Recorder::new(self.config.stats.clone()).sent(&sent);
+        if let (Ok(n), Some(tap)) = (sent.as_ref(), UdpSocket::tap(&self.config.tap)) {
+            tap.observe(Direction::Sent, &self.addr, &packet.packet_buf()[..*n]);
+        }
+        sent.map(|_| ())
+    }
+
+    /// Takes over the request: returns a new socket connected to the client
+    /// and the request, the embedder runs the transfer itself, e.g. with the
+    /// state machines of the `transfer` module.
+    ///
+    /// Nothing was sent to the client yet, options are not negotiated.
+    pub fn take_over(self) -> io::Result<(net::UdpSocket, RequestPacket<'static>)> {
+        try!(self.answer());
+        let socket = try!(UdpSocket::bind_transfer(&self.local));
+        try!(socket.connect(self.addr));
+        Ok((socket, self.request.clone()))
+    }
+
+    /// Marks the request as answered, fails if it was rejected because its
+    /// deadline passed.
+    fn answer(&self) -> io::Result<()> {
+        if self.pending.answer(self.id) {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "request wasn't answered in time"))
+        }
+    }
+
+    /// Rejects the request with the message of `reason`.
+    fn reject_with(&self, error: packet::Error, reason: Reason, message: &str) -> io::Result<()> {
+        let socket = try!(UdpSocket::bind_transfer(&self.local));
+        reject_request::<UdpSocket>(&socket, &self.addr, error, reason, message, &self.config);
+        Ok(())
+    }
+
+    /// Rejects a request of the opposite direction than the one served.
+    fn serve_unexpected(self, direction: &str) -> Serving {
+        let message = format!("unexpected {} request", direction);
+        let rejected = self.reject_with(packet::Error::IllegalOperation, Reason::UnexpectedPacket, &message);
+        let err = io::Error::new(io::ErrorKind::InvalidInput, message);
+        Serving(Box::new(future::result(rejected.and(Err(err)))))
+    }
+
+    /// Binds the socket of the transfer and starts the transfer with the
+    /// negotiated options.
+    fn start<F, T>(self, start: F) -> io::Result<T>
+        where F: FnOnce(&ServerConfig, &Handle, TransferSocket<UdpSocket>, SocketAddr, TransferParams,
+                        Option<OptionAckPacket<'static>>) -> io::Result<T>,
+    {
+        let (params, oack) = negotiate(self.request.options(), self.config.max_block_size,
+                                       self.config.max_window_size, self.config.timeout);
+        let socket = try!(UdpSocket::bind_transfer(&self.local).and_then(|socket| {
+            UdpSocket::register(socket, &self.handle)
+        }));
+        let socket = transfer_socket(&self.config, socket);
+        start(&self.config, &self.handle, socket, self.addr, params, oack)
+    }
+}
+
+impl Drop for IncomingRequest {
+    fn drop(&mut self) {
+        self.pending.forget(self.id);
+    }
+}
+
+impl fmt::Debug for IncomingRequest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IncomingRequest")
+            .field("addr", &self.addr)
+            .field("request", &self.request)
+            .finish()
+    }
+}
+
+/// Transfer of an `IncomingRequest`, completes once the transfer ended.
+///
+/// The transfer runs while the future is polled, e.g. spawned on the reactor
+/// of the `Incoming` stream.
+pub struct Serving(Box<Future<Item = (), Error = io::Error>>);
+
+impl Future for Serving {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.0.poll()
+    }
+}
+
+impl fmt::Debug for Serving {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Serving")
+    }
+}
+
+impl<H: Handler> Server<H> {
+    /// Sends `error` to `addr` outside of a transfer, e.g. to answer a stray
+    /// packet or reject a probe.
+    ///
+    /// Like the errors rejecting requests, the packet is sent from a new socket
+    /// on the address the server listens on. Servers listening on a Unix socket
+    /// can't reach network addresses and fail with `InvalidInput`.
+    pub fn send_error(&self, addr: SocketAddr, error: &ErrorPacket) -> io::Result<()> {
+        let local = match self.listen {
+            Listen::Udp(mut local) => {
+                local.set_port(0);
+                local
+            }
+            #[cfg(unix)]
+            Listen::Unix(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "server listens on a unix socket"))
+            }
+        };
+        let mut socket = try!(net::UdpSocket::bind(local));
+        send_packet(&mut socket, error, &addr, &mut Vec::new()).map(|_| ())
+    }
+
+    /// Returns the collector of the counters of finished transfers.
+    ///
+    /// Datagrams received on the socket the server listens on are counted as
+    /// they arrive.
+    pub fn stats(&self) -> &Stats {
+        &self.config.stats
+    }
+
+    /// Returns the bandwidth cap of the server, its rate can be changed while
+    /// the server runs (`BandwidthCap::set_rate`).
+    pub fn bandwidth_cap(&self) -> &BandwidthCap {
+        &self.config.bandwidth
+    }
+
+    /// Listens for requests on the reactor of `handle` and returns them as a
+    /// stream instead of serving them with the handler, see `IncomingRequest`.
+    ///
+    /// Only servers listening on UDP are supported, others fail with
+    /// `InvalidInput`. The stream ends only if the socket fails.
+    pub fn incoming(&self, handle: &Handle) -> io::Result<Incoming> {
+        let addr = match self.listen {
+            Listen::Udp(addr) => addr,
+            #[cfg(unix)]
+            Listen::Unix(_) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "server listens on a unix socket"))
+            }
+        };
+        let socket = try!(UdpSocket::bind(&addr, handle));
+        info!("Listening on {}", try!(socket.local_addr()));
+        let local = try!(socket.local());
+        let config = Rc::new(self.config.clone());
+        let socket = Tapped::optional(socket, config.tap.clone());
+        Ok(Incoming {
+            acceptor: RequestAcceptor::new(socket, config.stats.clone(), config.filename_codec, config.conformance),
+            local: local,
+            handle: handle.clone(),
+            config: config,
+            pending: Rc::new(Pending::default()),
+        })
+    }
+
+    /// Returns a handle draining the server from another thread.
+    pub fn drain_handle(&self) -> DrainHandle {
+        DrainHandle(self.drain.clone())
+    }
+
+    /// Returns a handle draining the server or changing its bandwidth cap
+    /// from another thread.
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            drain: self.drain_handle(),
+            bandwidth: self.config.bandwidth.clone(),
+        }
+    }
+
+    /// Runs the server, returns only if the server socket fails or the server
+    /// was drained, see `DrainHandle::drain`.
+    pub fn run(&self) -> io::Result<()> {
+        let core = try!(Core::new());
+        match self.listen {
+            Listen::Udp(addr) => {
+                let socket = try!(UdpSocket::bind(&addr, &core.handle()));
+                info!("Listening on {}", try!(socket.local_addr()));
+                self.serve(core, socket)
+            }
+            #[cfg(unix)]
+            Listen::Unix(ref path) => {
+                let socket = try!(unix::UnixSocket::bind(path, &core.handle()));
+                info!("Listening on {}", path.display());
+                self.serve(core, socket)
+            }
+        }
+    }
+
+    fn serve<E: Endpoint>(&self, mut core: Core, socket: E) -> io::Result<()> {
+        let handle = core.handle();
+        let local = try!(socket.local());
+        let config = Rc::new(self.config.clone());
+
+        let socket = Tapped::optional(socket, E::tap(&config.tap));
+        let acceptor = RequestAcceptor::new(socket, config.stats.clone(), config.filename_codec, config.conformance);
+        let handler = &*self.handler;
+        let overlays = &self.overlays[..];
+        let priority = |client_request: &ClientRequest<E::Addr>| {
+            match find_overlay(overlays, E::network_addr(&client_request.addr)) {
+                Some(&Overlay { handler: Some(ref fs), ref config, .. }) => {
+                    request_priority::<E, FsHandler>(fs, config, client_request)
+                }
+                Some(overlay) => request_priority::<E, H>(handler, &overlay.config, client_request),
+                None => request_priority::<E, H>(handler, &config, client_request),
+            }
+        };
+        let expiry = config.timeout * config.retries.get();
+        let scheduler = Scheduler::new(acceptor, priority, config.max_transfers, expiry);
+        let slots = scheduler.slots.clone();
+        let sessions = match config.session_file {
+            Some(ref path) => Some(try!(self.resume_sessions(&handle, &config, &scheduler, path.clone()))),
+            None => None,
+        };
+        let prefetch = Rc::new(Prefetch::new());
+        let fs_prefetch = Rc::new(Prefetch::new());
+        let server = scheduler.for_each(|(client_request, slot)| {
+            debug!("mode = {:?}, filename = {:?} from {:?}", client_request.request.mode(),
+                   client_request.request.filename(), client_request.addr);
+            let started = match find_overlay(overlays, E::network_addr(&client_request.addr)) {
+                Some(&Overlay { handler: Some(ref fs), config: ref subnet_config, subnet }) => {
+                    debug!("{:?} is in {}", client_request.addr, subnet);
+                    handle_request::<E, FsHandler>(&handle, subnet_config, fs, &local, client_request, slot,
+                                                   sessions.as_ref(), &fs_prefetch)
+                }
+                Some(overlay) => {
+                    debug!("{:?} is in {}", client_request.addr, overlay.subnet);
+                    handle_request::<E, H>(&handle, &overlay.config, handler, &local, client_request, slot,
+                                           sessions.as_ref(), &prefetch)
+                }
+                None => handle_request::<E, H>(&handle, &config, handler, &local, client_request, slot,
+                                               sessions.as_ref(), &prefetch),
+            };
+            if let Err(e) = started {
+                warn!("Could not start transfer: {}", e);
+            }
+            Ok(())
+        });
+
+        // Dropping the server closes the listening socket.
+        let deadline = match core.run(server.select2(DrainRequest(self.drain.clone()))) {
+            Ok(Either::A(_)) => return Ok(()),
+            Ok(Either::B((deadline, _))) => deadline,
+            Err(Either::A((e, _))) | Err(Either::B((e, _))) => return Err(e),
+        };
+        let running = slots.running.get();
+        info!("Draining {} transfers for {:?}", running, deadline);
+        let drained = Drained {
+            slots: slots.clone(),
+            deadline: try!(Timeout::new(deadline, &handle)),
+        };
+        try!(core.run(drained));
+        let aborted: Vec<_> = slots.transfers.borrow().iter().map(|&(_, ref transfer)| transfer.clone()).collect();
+        for transfer in &aborted {
+            warn!("Aborting transfer of {} for {:?}", transfer.filename, transfer.client);
+        }
+        // The transfers are dropped with the reactor.
+        drop(core);
+        let mut state = self.drain.state.lock().unwrap();
+        state.report = Some(DrainReport {
+            finished: running - aborted.len(),
+            aborted: aborted,
+        });
+        self.drain.drained.notify_all();
+        Ok(())
+    }
+
+    /// Resumes the transfers recorded in the snapshot file at `path` and
+    /// starts saving the snapshot periodically.
+    fn resume_sessions<E: Endpoint, P>(&self, handle: &Handle, config: &Rc<ServerConfig>, scheduler: &Scheduler<Tapped<E>, P>,
+                                       path: PathBuf) -> io::Result<Rc<Registry>> {
+        let entries = match snapshot::load(&path, config.timeout * config.retries.get()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Can't load transfer snapshot {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        let sessions = Rc::new(Registry::new(path));
+        for entry in entries {
+            let resumed = match find_overlay(&self.overlays, Some(entry.client)) {
+                Some(&Overlay { handler: Some(ref fs), config: ref subnet_config, .. }) => {
+                    resume_transfer::<E, FsHandler>(handle, subnet_config, fs, entry, scheduler.claim(), &sessions)
+                }
+                Some(overlay) => {
+                    resume_transfer::<E, H>(handle, &overlay.config, &*self.handler, entry, scheduler.claim(),
+                                            &sessions)
+                }
+                None => resume_transfer::<E, H>(handle, config, &*self.handler, entry, scheduler.claim(), &sessions),
+            };
+            if let Err(e) = resumed {
+                warn!("Could not resume transfer: {}", e);
+            }
+        }
+        let saving = sessions.clone();
+        handle.spawn(try!(Interval::new(snapshot::SAVE_INTERVAL, handle)).for_each(move |_| {
+            if let Err(e) = saving.save() {
+                warn!("Can't save transfer snapshot {}: {}", saving.path().display(), e);
+            }
+            Ok(())
+        }).map_err(|e| warn!("Transfer snapshot timer failed: {}", e)));
+        Ok(sessions)
+    }
+}
+
+/// Runs a server on `127.0.0.1:9999` serving the current directory.
+pub fn start() {
+    let addr = "127.0.0.1:9999".parse().unwrap();
+    ServerBuilder::new(addr).build().unwrap().run().unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use config::{BlockSize, WindowSize, DEFAULT_TIMEOUT};
+    use packet::TransferOptions;
+
+    use super::negotiate;
+
+    #[test]
+    fn block_size_is_limited() {
+        let mut options = TransferOptions::new();
+        options.insert("blksize", "8192");
+        let (params, oack) = negotiate(&options, BlockSize::new(256).unwrap(), WindowSize::default(),
+                                       DEFAULT_TIMEOUT);
+        assert_eq!(256, params.block_size);
+        assert_eq!(Some("256"), oack.as_ref().and_then(|oack| oack.options().get("blksize")));
+    }
+
+    #[test]
+    fn requested_timeout_is_acknowledged() {
+        let mut options = TransferOptions::new();
+        options.insert("utimeout", "200000");
+        let (params, oack) = negotiate(&options, BlockSize::default(), WindowSize::default(), DEFAULT_TIMEOUT);
+        assert_eq!(Duration::from_millis(200), params.timeout);
+        assert_eq!(Some("200000"), oack.as_ref().and_then(|oack| oack.options().get("utimeout")));
+    }
+
+    #[test]
+    fn window_size_is_negotiated_up_to_limit() {
+        let mut options = TransferOptions::new();
+        options.insert("windowsize", "16");
+        let (params, oack) = negotiate(&options, BlockSize::default(), WindowSize::new(8).unwrap(),
+                                       DEFAULT_TIMEOUT);
+        assert_eq!(8, params.window_size.get());
+        assert_eq!(Some("8"), oack.as_ref().and_then(|oack| oack.options().get("windowsize")));
+
+        let (params, oack) = negotiate(&options, BlockSize::default(), WindowSize::default(), DEFAULT_TIMEOUT);
+        assert_eq!(1, params.window_size.get());
+        assert_eq!(None, oack);
+    }
+
+    #[test]
+    fn standalone_error_is_sent() {
+        use std::net::UdpSocket;
+
+        use packet::{self, ErrorPacket, DecodePacket};
+        use super::ServerBuilder;
+
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap()).build().unwrap();
+        let error = ErrorPacket::new(packet::Error::UnknownTransferId, "no such transfer");
+        server.send_error(peer.local_addr().unwrap(), &error).unwrap();
+        let mut buf = vec![0; 512];
+        let n = peer.recv(&mut buf).unwrap();
+        assert_eq!(Some(error), ErrorPacket::decode(&buf[..n]));
+    }
+
+    #[test]
+    fn waiting_requests_start_by_priority() {
+        use std::time::{Duration, Instant};
+
+        use handler::Priority;
+        use packet::{Mode, RequestPacket};
+        use super::{ClientRequest, WaitQueue};
+
+        let request = |addr: u8, filename: &'static str| {
+            ClientRequest::new(addr, RequestPacket::read_request(filename, Mode::Octet))
+        };
+        let filename = |request: Option<ClientRequest<u8>>| request.unwrap().request.filename().unwrap().into_owned();
+        let now = Instant::now();
+        let mut queue = WaitQueue::new(Duration::from_secs(5));
+        queue.push(request(1, "image"), Priority::Bulk, now);
+        queue.push(request(2, "config"), Priority::Normal, now);
+        queue.push(request(3, "pxelinux.0"), Priority::Critical, now);
+        queue.push(request(4, "other-image"), Priority::Bulk, now);
+        // The repeated request keeps its place ahead of the later bulk request.
+        queue.push(request(1, "image"), Priority::Bulk, now + Duration::from_secs(1));
+
+        assert_eq!("pxelinux.0", filename(queue.pop(now)));
+        assert_eq!("config", filename(queue.pop(now)));
+        assert_eq!("image", filename(queue.pop(now)));
+        // The client of the last request gave up.
+        assert!(queue.pop(now + Duration::from_secs(5)).is_none());
+    }
+
+    #[test]
+    fn most_specific_subnet_applies() {
+        use std::env;
+
+        use config::{self, BlockSize, Subnet};
+        use super::{find_overlay, ServerBuilder, SubnetConfig};
+
+        let server = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .subnet("10.0.0.0/8".parse::<Subnet>().unwrap(), SubnetConfig::new().read_only(true))
+            .subnet("10.1.0.0/16".parse::<Subnet>().unwrap(),
+                    SubnetConfig::new().root(env::temp_dir()).max_block_size(BlockSize::new(256).unwrap()))
+            .build()
+            .unwrap();
+        let overlay = |addr: &str| find_overlay(&server.overlays, Some(addr.parse().unwrap()));
+
+        let lab = overlay("10.1.2.3:2000").unwrap();
+        assert_eq!("10.1.0.0/16", lab.subnet.to_string());
+        assert!(lab.handler.is_some());
+        assert!(!lab.config.read_only);
+        assert_eq!(256, lab.config.max_block_size.get());
+
+        let production = overlay("10.2.0.1:2000").unwrap();
+        assert!(production.config.read_only);
+        assert!(production.handler.is_none());
+        assert_eq!(config::MAX_BLOCK_SIZE, production.config.max_block_size.get());
+
+        assert!(overlay("192.0.2.1:2000").is_none());
+        assert!(find_overlay(&server.overlays, None).is_none());
+    }
+
+    #[test]
+    fn invalid_settings_are_reported_together() {
+        use std::env;
+        use std::fs;
+        use std::process;
+
+        use config::{ConfigError, Subnet};
+        use handler::{FsHandler, ReadOnly};
+        use super::{ServerBuilder, SubnetConfig};
+
+        let root = env::temp_dir().join(format!("tftp-invalid-settings-{}", process::id()));
+        let file = env::temp_dir().join(format!("tftp-invalid-settings-{}.img", process::id()));
+        fs::write(&file, b"image").unwrap();
+        let lab: Subnet = "10.1.0.0/16".parse().unwrap();
+        let err = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .root(&root)
+            .timeout(Duration::from_secs(0))
+            .keepalive(Duration::from_secs(0))
+            .subnet(lab, SubnetConfig::new().root(&file).timeout(Duration::from_secs(0)))
+            .subnet(lab, SubnetConfig::new().read_only(true))
+            .build()
+            .unwrap_err();
+        assert_eq!(&[ConfigError::ZeroTimeout,
+                     ConfigError::ZeroDuration("keepalive interval"),
+                     ConfigError::InvalidRoot(root.clone(), "doesn't exist".to_owned()),
+                     ConfigError::ZeroTimeout.within("subnet 10.1.0.0/16"),
+                     ConfigError::InvalidRoot(file.clone(), "is not a directory".to_owned())
+                        .within("subnet 10.1.0.0/16"),
+                     ConfigError::DuplicateSubnet(lab)], err.errors());
+
+        // Routes are checked through the router, read-only routes needn't be writable.
+        let err = ServerBuilder::new("127.0.0.1:0".parse().unwrap())
+            .routes()
+            .route("", ReadOnly::new(FsHandler::new(env::temp_dir())))
+            .route("images/", FsHandler::new(&root))
+            .build()
+            .unwrap_err();
+        assert_eq!(&[ConfigError::InvalidRoot(root.clone(), "doesn't exist".to_owned()).within("route \"images/\""),
+                     ConfigError::ShadowedRoute("images/".to_owned(), "".to_owned())], err.errors());
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn invalid_options_are_ignored() {
+        let mut options = TransferOptions::new();
+        options.insert("blksize", "4");
+        options.insert("unknown", "1");
+        options.insert("timeout", "0");
+        let (params, oack) = negotiate(&options, BlockSize::default(), WindowSize::default(), DEFAULT_TIMEOUT);
+        assert_eq!((512, DEFAULT_TIMEOUT), (params.block_size, params.timeout));
+        assert_eq!(None, oack);
+    }
+
+    #[cfg(feature = "mio-client")]
+    #[test]
+    fn handler_acknowledges_extension_options() {
+        use std::io;
+        use std::net::UdpSocket;
+        use std::path::Path;
+        use std::thread;
+        use std::time::Duration;
+
+        use futures::future::{self, FutureResult};
+        use tokio_core::reactor::Handle;
+
+        use client::Client;
+        use handler::{Handler, Request};
+        use packet::Mode;
+        use super::ServerBuilder;
+
+        struct VendorHandler;
+
+        impl Handler for VendorHandler {
+            type Reader = io::Cursor<Vec<u8>>;
+            type Writer = io::Sink;
+            type OpenRead = FutureResult<io::Cursor<Vec<u8>>, io::Error>;
+            type OpenWrite = FutureResult<io::Sink, io::Error>;
+
+            fn open_read(&self, request: &Request, _: &Handle) -> Self::OpenRead {
+                let vendor = request.options().and_then(|options| options.get("x-vendor")).unwrap_or("none");
+                future::ok(io::Cursor::new(vendor.as_bytes().to_vec()))
+            }
+
+            fn open_write(&self, _: &Request, _: &Handle) -> Self::OpenWrite {
+                future::ok(io::sink())
+            }
+
+            fn acknowledge_options(&self, _: &Request) -> TransferOptions<'static> {
+                let mut options = TransferOptions::new();
+                options.insert("x-vendor", "2");
+                options.insert("x-unrequested", "1");
+                options.insert("blksize", "8");
+                options
+            }
+        }
+
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        thread::spawn(move || ServerBuilder::new(addr).handler(VendorHandler).build().unwrap().run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let mut options = TransferOptions::new();
+        options.insert("x-vendor", "1");
+        let mut received = Vec::new();
+        let (params, acknowledged) = Client::new(addr)
+            .get_with_options(Path::new("file"), Mode::Octet, &options, &mut received)
+            .unwrap();
+        assert_eq!(b"1", &received[..]);
+        assert_eq!(512, params.block_size);
+        assert_eq!(Some("2"), acknowledged.get("x-vendor"));
+        assert_eq!(None, acknowledged.get("x-unrequested"));
+    }
+
+    #[cfg(feature = "mio-client")]
+    #[test]
+    fn follow_up_is_opened_ahead_of_request() {
+        use std::io;
+        use std::net::UdpSocket;
+        use std::path::Path;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        use futures::future::{self, FutureResult};
+        use tokio_core::reactor::Handle;
+
+        use client::Client;
+        use handler::{Handler, Request};
+        use packet::Mode;
+        use super::ServerBuilder;
+
+        struct BootHandler(Arc<Mutex<Vec<String>>>);
+
+        impl Handler for BootHandler {
+            type Reader = io::Cursor<Vec<u8>>;
+            type Writer = io::Sink;
+            type OpenRead = FutureResult<io::Cursor<Vec<u8>>, io::Error>;
+            type OpenWrite = FutureResult<io::Sink, io::Error>;
+
+            fn open_read(&self, request: &Request, _: &Handle) -> Self::OpenRead {
+                self.0.lock().unwrap().push(request.filename().to_owned());
+                future::ok(io::Cursor::new(request.filename().as_bytes().to_vec()))
+            }
+
+            fn open_write(&self, _: &Request, _: &Handle) -> Self::OpenWrite {
+                future::ok(io::sink())
+            }
+
+            fn follow_up(&self, request: &Request) -> Option<String> {
+                if request.filename() == "kernel" { Some("initrd".to_owned()) } else { None }
+            }
+        }
+
+        let opened = Arc::new(Mutex::new(Vec::new()));
+        let handler = BootHandler(opened.clone());
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        thread::spawn(move || ServerBuilder::new(addr).handler(handler).build().unwrap().run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let client = Client::new(addr);
+        for &filename in &["kernel", "initrd", "initrd"] {
+            let mut received = Vec::new();
+            client.get(Path::new(filename), Mode::Octet, &mut received).unwrap();
+            assert_eq!(filename.as_bytes(), &received[..]);
+        }
+        // The first initrd was opened while the kernel was sent, the second is opened again.
+        assert_eq!(vec!["kernel", "initrd", "initrd"], *opened.lock().unwrap());
+    }
+
+    #[test]
+    fn drain_aborts_transfers_running_at_deadline() {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        use packet::{DataPacketOctet, DecodePacket, EncodePacket, Mode, RequestPacket};
+        use super::{AbortedTransfer, ServerBuilder};
+
+        let dir = env::temp_dir().join(format!("tftp-drain-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), vec![1; 1000]).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let root = dir.clone();
+        let server = thread::spawn(move || {
+            let server = ServerBuilder::new(addr).root(root).build().unwrap();
+            sender.send(server.drain_handle()).unwrap();
+            server.run()
+        });
+        let drain = receiver.recv().unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // The client never acknowledges the first block.
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(RequestPacket::read_request("file", Mode::Octet).encode().packet_buf(), addr).unwrap();
+        let mut buf = vec![0; 1024];
+        let n = client.recv(&mut buf).unwrap();
+        assert_eq!(Some(1), DataPacketOctet::decode(&buf[..n]).map(|data| data.block_id()));
+
+        let report = drain.drain(Duration::from_millis(200));
+        assert_eq!(0, report.finished);
+        assert_eq!(vec![AbortedTransfer {
+            client: Some(client.local_addr().unwrap()),
+            filename: "file".to_owned(),
+            upload: false,
+        }], report.aborted);
+        server.join().unwrap().unwrap();
+        // The listening socket is closed, a new server can take over.
+        UdpSocket::bind(addr).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "mio-client")]
+    #[test]
+    fn datagrams_are_tapped_on_both_sides() {
+        use std::env;
+        use std::fs;
+        use std::net::{SocketAddr, UdpSocket};
+        use std::path::Path;
+        use std::process;
+        use std::sync::{Arc, Mutex};
+        use std::thread;
+        use std::time::Duration;
+
+        use client::ClientBuilder;
+        use packet::Mode;
+        use replay::Direction;
+        use transport::Tap;
+        use super::ServerBuilder;
+
+        type Datagrams = Arc<Mutex<Vec<(Direction, SocketAddr, u8)>>>;
+
+        fn recording_tap(datagrams: &Datagrams) -> Tap {
+            let datagrams = datagrams.clone();
+            Tap::new(move |direction, addr: &SocketAddr, datagram: &[u8]| {
+                datagrams.lock().unwrap().push((direction, *addr, datagram[1]));
+            })
+        }
+
+        let dir = env::temp_dir().join(format!("tftp-tap-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), b"abc").unwrap();
+        let server_datagrams = Datagrams::default();
+        let client_datagrams = Datagrams::default();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (root, tap) = (dir.clone(), recording_tap(&server_datagrams));
+        thread::spawn(move || ServerBuilder::new(addr).root(root).tap(tap).build().unwrap().run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let client = ClientBuilder::new(addr).tap(recording_tap(&client_datagrams)).build().unwrap();
+        let mut received = Vec::new();
+        client.get(Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(b"abc", &received[..]);
+        let client_datagrams = client_datagrams.lock().unwrap().clone();
+        let directions: Vec<_> = client_datagrams.iter().map(|&(direction, _, opcode)| (direction, opcode)).collect();
+        assert_eq!(vec![(Direction::Sent, 1), (Direction::Received, 3), (Direction::Sent, 4)], directions);
+        assert_eq!(addr, client_datagrams[0].1);
+
+        // The server may still be receiving the last acknowledgement.
+        for _ in 0..100 {
+            if server_datagrams.lock().unwrap().len() == 3 {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let server_datagrams = server_datagrams.lock().unwrap().clone();
+        let directions: Vec<_> = server_datagrams.iter().map(|&(direction, _, opcode)| (direction, opcode)).collect();
+        assert_eq!(vec![(Direction::Received, 1), (Direction::Sent, 3), (Direction::Received, 4)], directions);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "mio-client")]
+    #[test]
+    fn incoming_requests_are_answered_by_embedder() {
+        use std::net::UdpSocket;
+        use std::path::Path;
+        use std::sync::mpsc;
+        use std::thread;
+
+        use futures::{Future, Stream};
+        use tokio_core::reactor::Core;
+
+        use client::{Client, Error};
+        use packet::{self, Mode};
+        use super::ServerBuilder;
+
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            let server = ServerBuilder::new(addr).build().unwrap();
+            let incoming = server.incoming(&handle).unwrap();
+            tx.send(()).unwrap();
+            core.run(incoming.for_each(|request| {
+                if request.request().filename_lossy() == "greeting" {
+                    handle.spawn(request.serve_reader(&b"hello"[..]).map_err(|e| panic!("{}", e)));
+                } else {
+                    request.reject(packet::Error::FileNotFound, "no such file").unwrap();
+                }
+                Ok(())
+            })).unwrap();
+        });
+        rx.recv().unwrap();
+
+        let client = Client::new(addr);
+        let mut received = Vec::new();
+        client.get(Path::new("greeting"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(b"hello", &received[..]);
+        match client.get(Path::new("other"), Mode::Octet, &mut Vec::new()) {
+            Err(Error::Server(error)) => {
+                assert_eq!(packet::Error::FileNotFound, error.error());
+                assert_eq!("no such file", error.message().unwrap());
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[cfg(feature = "mio-client")]
+    #[test]
+    fn deferred_requests_absorb_retransmissions_until_deadline() {
+        use std::net::UdpSocket;
+        use std::path::Path;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        use futures::{Future, Stream};
+        use tokio_core::reactor::{Core, Timeout};
+
+        use client::{ClientBuilder, Error};
+        use config::Retries;
+        use packet::Mode;
+        use reason::Reason;
+        use super::ServerBuilder;
+
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (tx, rx) = mpsc::channel();
+        let seen = requests.clone();
+        thread::spawn(move || {
+            let mut core = Core::new().unwrap();
+            let handle = core.handle();
+            // Requests have to be answered within 300ms.
+            let server = ServerBuilder::new(addr).timeout(Duration::from_millis(100)).retries(Retries::new(3).unwrap())
+                .build().unwrap();
+            let incoming = server.incoming(&handle).unwrap();
+            tx.send(()).unwrap();
+            core.run(incoming.for_each(|request| {
+                seen.fetch_add(1, Ordering::SeqCst);
+                // E.g. an address management lookup before answering.
+                let lookup = if request.request().filename_lossy() == "slow" { 150 } else { 1000 };
+                let decided = Timeout::new(Duration::from_millis(lookup), &handle).unwrap();
+                handle.spawn(decided.map_err(|e| panic!("{}", e))
+                    .and_then(move |_| request.serve_reader(&b"hello"[..]).then(|_| Ok(()))));
+                Ok(())
+            })).unwrap();
+        });
+        rx.recv().unwrap();
+
+        let client = ClientBuilder::new(addr).timeout(Duration::from_millis(50)).retries(Retries::new(10).unwrap())
+            .build().unwrap();
+        let mut received = Vec::new();
+        client.get(Path::new("slow"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(b"hello", &received[..]);
+        assert_eq!(1, requests.load(Ordering::SeqCst));
+        match client.get(Path::new("late"), Mode::Octet, &mut Vec::new()) {
+            Err(Error::Server(error)) => {
+                let message = error.message().unwrap();
+                assert_eq!(Some(Reason::DeadlineExceeded), Reason::parse_message(&message).map(|(reason, _)| reason));
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(2, requests.load(Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "mio-client")]
+    #[test]
+    fn transfers_share_the_bandwidth_cap() {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::path::Path;
+        use std::process;
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        use bandwidth::BandwidthCap;
+        use client::Client;
+        use packet::Mode;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-bandwidth-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..25000).map(|i| i as u8).collect();
+        fs::write(dir.join("file"), &contents).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let cap = BandwidthCap::new(50000);
+        let (root, server_cap) = (dir.clone(), cap.clone());
+        let (handle_tx, handle_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let server = ServerBuilder::new(addr).root(root).bandwidth_cap(server_cap).build().unwrap();
+            handle_tx.send(server.handle()).unwrap();
+            server.run().unwrap();
+        });
+        let server = handle_rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        // Two downloads of 49 blocks each take about a second at 50000 bytes
+        // per second.
+        let start = Instant::now();
+        let downloads: Vec<_> = (0..2).map(|_| thread::spawn(move || {
+            let mut received = Vec::new();
+            Client::new(addr).get(Path::new("file"), Mode::Octet, &mut received).unwrap();
+            (received, start.elapsed())
+        })).collect();
+        let mut times = Vec::new();
+        for download in downloads {
+            let (received, elapsed) = download.join().unwrap();
+            assert_eq!(contents, received);
+            times.push(elapsed);
+        }
+        assert!(times.iter().all(|&elapsed| elapsed >= Duration::from_millis(800)), "{:?}", times);
+        // Packets are counted before they're sent.
+        assert_eq!(2 * (25000 + 49 * 4), cap.bytes());
+
+        // Lifting the cap applies to the running server.
+        assert_eq!(Some(50000), server.max_bandwidth());
+        server.set_max_bandwidth(None);
+        let start = Instant::now();
+        Client::new(addr).get(Path::new("file"), Mode::Octet, &mut Vec::new()).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn errors_to_unknown_transfer_ids_are_rate_limited() {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use packet::{self, AckPacket, DataPacketOctet, DecodePacket, EncodePacket, ErrorPacket, Mode, RequestPacket};
+        use ratelimit::RateLimit;
+        use stats::Stats;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-wrong-tid-limit-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (root, stats) = (dir.clone(), Stats::new());
+        let server_stats = stats.clone();
+        thread::spawn(move || {
+            ServerBuilder::new(addr).root(root).wrong_tid_limit(RateLimit::new(2, Duration::from_secs(60)))
+                .stats(server_stats).build().unwrap().run().unwrap()
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(RequestPacket::write_request("file", Mode::Octet).encode().packet_buf(), addr).unwrap();
+        let mut buf = vec![0; 1024];
+        let (n, transfer) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(Some(0), AckPacket::decode(&buf[..n]).map(|ack| ack.block_id()));
+
+        // A flood of datagrams from another port of the host gets two errors.
+        let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        stranger.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+        for _ in 0..5 {
+            stranger.send_to(AckPacket::new(0).encode().packet_buf(), transfer).unwrap();
+        }
+        let mut errors = 0;
+        while let Ok(n) = stranger.recv(&mut buf) {
+            assert_eq!(packet::Error::UnknownTransferId, ErrorPacket::decode(&buf[..n]).unwrap().error());
+            errors += 1;
+        }
+        assert_eq!(2, errors);
+
+        client.send_to(DataPacketOctet::from_slice(1, b"data").encode().packet_buf(), transfer).unwrap();
+        let (n, _) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(Some(1), AckPacket::decode(&buf[..n]).map(|ack| ack.block_id()));
+        for _ in 0..100 {
+            if stats.get().socket.wrong_tid_discarded == 5 {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = stats.get();
+        assert_eq!(5, stats.socket.wrong_tid_discarded);
+        assert_eq!(3, stats.socket.wrong_tid_suppressed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn oversized_data_fails_upload() {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use packet::{self, AckPacket, DataPacketOctet, DecodePacket, EncodePacket, ErrorPacket, Mode, RequestPacket};
+        use reason::Reason;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-oversized-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let root = dir.clone();
+        thread::spawn(move || ServerBuilder::new(addr).root(root).read_only(false).build().unwrap().run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(RequestPacket::write_request("file", Mode::Octet).encode().packet_buf(), addr).unwrap();
+        let mut buf = vec![0; 1024];
+        let (n, transfer) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(Some(0), AckPacket::decode(&buf[..n]).map(|ack| ack.block_id()));
+        // A truncated datagram would look like a short last block.
+        client.send_to(DataPacketOctet::from_slice(1, &[0; 600]).encode().packet_buf(), transfer).unwrap();
+        let (n, _) = client.recv_from(&mut buf).unwrap();
+        let error = ErrorPacket::decode(&buf[..n]).unwrap();
+        assert_eq!(packet::Error::IllegalOperation, error.error());
+        let message = error.message().unwrap();
+        assert_eq!(Some(Reason::OversizedPacket), Reason::parse_message(&message).map(|(reason, _)| reason));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "mio-client"))]
+    #[test]
+    fn files_are_served_over_unix_sockets() {
+        use std::env;
+        use std::fs;
+        use std::path::Path;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use mio::net::UnixDatagram;
+
+        use client::Client;
+        use packet::Mode;
+        use stats::Stats;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-unix-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        fs::write(dir.join("file"), &contents).unwrap();
+
+        let server_path = dir.join("server.sock");
+        let (path, root) = (server_path.clone(), dir.clone());
+        let stats = Stats::new();
+        let server_stats = stats.clone();
+        thread::spawn(move || ServerBuilder::unix(path).root(root).stats(server_stats).build().unwrap().run().unwrap());
+        while !server_path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let client_path = dir.join("client.sock");
+        let socket = UnixDatagram::bind(&client_path).unwrap();
+        let client = Client::new("127.0.0.1:69".parse().unwrap());
+        let mut received = Vec::new();
+        let params = client.get_over(socket, server_path.clone(), Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(contents, received);
+        assert_eq!(512, params.block_size);
+
+        // Counters of the transfer are collected once the server got the last acknowledgment.
+        for _ in 0..100 {
+            if stats.get().socket.datagrams_received == 5 {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stats = stats.get();
+        assert_eq!(5, stats.socket.datagrams_received);
+        assert_eq!(4, stats.socket.datagrams_sent);
+        assert_eq!(0, stats.socket.wrong_tid_discarded);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "mio-client"))]
+    #[test]
+    fn files_are_transferred_on_io_pool() {
+        use std::env;
+        use std::fs;
+        use std::path::Path;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use mio::net::UnixDatagram;
+
+        use client::Client;
+        use handler::FsHandler;
+        use packet::Mode;
+        use pool::IoPool;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-pool-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let server_path = dir.join("server.sock");
+        let (path, root) = (server_path.clone(), dir.clone());
+        thread::spawn(move || {
+            let handler = FsHandler::new(root).io_pool(IoPool::new(2, 8).unwrap());
+            ServerBuilder::unix(path).handler(handler).build().unwrap().run().unwrap()
+        });
+        while !server_path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let client = Client::new("127.0.0.1:69".parse().unwrap());
+        let contents: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let socket = UnixDatagram::bind(dir.join("put.sock")).unwrap();
+        client.put_over(socket, server_path.clone(), Path::new("file"), Mode::Octet, &mut &contents[..]).unwrap();
+        assert_eq!(contents, fs::read(dir.join("file")).unwrap());
+
+        let socket = UnixDatagram::bind(dir.join("get.sock")).unwrap();
+        let mut received = Vec::new();
+        client.get_over(socket, server_path.clone(), Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(contents, received);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "mio-client"))]
+    #[test]
+    fn rejected_upload_is_reported_to_client() {
+        use std::env;
+        use std::fs;
+        use std::io;
+        use std::path::Path;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use mio::net::UnixDatagram;
+
+        use client::Client;
+        use error::{Error, ErrorKind};
+        use handler::FsHandler;
+        use packet::{self, Mode};
+        use pool::IoPool;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-rejected-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let server_path = dir.join("server.sock");
+        let (path, root) = (server_path.clone(), dir.clone());
+        thread::spawn(move || {
+            let handler = FsHandler::new(root).io_pool(IoPool::new(1, 4).unwrap())
+                .check_upload(|_, _| Err(io::Error::new(io::ErrorKind::PermissionDenied, "content rejected")));
+            ServerBuilder::unix(path).handler(handler).build().unwrap().run().unwrap()
+        });
+        while !server_path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let client = Client::new("127.0.0.1:69".parse().unwrap());
+        let socket = UnixDatagram::bind(dir.join("put.sock")).unwrap();
+        let err = client.put_over(socket, server_path.clone(), Path::new("file"), Mode::Octet, &mut &b"data"[..])
+            .unwrap_err();
+        assert_eq!(ErrorKind::ServerError(packet::Error::AccessViolation), Error::from(err).kind());
+        assert!(!dir.join("file").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "mio-client"))]
+    #[test]
+    fn stalled_handler_keeps_client_waiting() {
+        use std::env;
+        use std::fs;
+        use std::io::{self, Read};
+        use std::path::Path;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use futures::{Async, Future};
+        use futures::future::{self, FutureResult};
+        use mio::net::UnixDatagram;
+        use tokio_core::reactor::{Handle, Timeout};
+        use tokio_io::AsyncRead;
+
+        use client::ClientBuilder;
+        use config::Retries;
+        use handler::{FsHandler, FsOpen, FsWriter, Handler, Request};
+        use packet::Mode;
+        use stats::Stats;
+        use super::ServerBuilder;
+
+        /// Produces the first block right away and the rest after a delay.
+        struct SlowReader {
+            data: Vec<u8>,
+            position: usize,
+            stall: Timeout,
+        }
+
+        impl Read for SlowReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.position >= 512 {
+                    if let Async::NotReady = try!(self.stall.poll()) {
+                        return Err(io::ErrorKind::WouldBlock.into())
+                    }
+                }
+                let n = try!((&self.data[self.position..]).read(buf));
+                self.position += n;
+                Ok(n)
+            }
+        }
+
+        impl AsyncRead for SlowReader {}
+
+        struct SlowHandler;
+
+        impl Handler for SlowHandler {
+            type Reader = SlowReader;
+            type Writer = FsWriter;
+            type OpenRead = FutureResult<SlowReader, io::Error>;
+            type OpenWrite = FsOpen<FsWriter>;
+
+            fn open_read(&self, _: &Request, handle: &Handle) -> FutureResult<SlowReader, io::Error> {
+                future::result(Timeout::new(Duration::from_millis(1500), handle).map(|stall| SlowReader {
+                    data: vec![7; 1000],
+                    position: 0,
+                    stall: stall,
+                }))
+            }
+
+            fn open_write(&self, request: &Request, handle: &Handle) -> FsOpen<FsWriter> {
+                FsHandler::new(".").open_write(request, handle)
+            }
+        }
+
+        let dir = env::temp_dir().join(format!("tftp-keepalive-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let server_path = dir.join("server.sock");
+        let path = server_path.clone();
+        let stats = Stats::new();
+        let server_stats = stats.clone();
+        thread::spawn(move || {
+            ServerBuilder::unix(path).handler(SlowHandler).keepalive(Duration::from_millis(300)).stats(server_stats)
+                .build().unwrap().run().unwrap()
+        });
+        while !server_path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // Without keepalives the client gives up before the second block is ready.
+        let client = ClientBuilder::new("127.0.0.1:69".parse().unwrap())
+            .timeout(Duration::from_millis(500))
+            .retries(Retries::new(1).unwrap())
+            .build()
+            .unwrap();
+        let socket = UnixDatagram::bind(dir.join("client.sock")).unwrap();
+        let mut received = Vec::new();
+        client.get_over(socket, server_path.clone(), Path::new("file"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(vec![7; 1000], received);
+
+        for _ in 0..100 {
+            if stats.get().protocol.keepalives > 0 {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(stats.get().protocol.keepalives >= 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "mio-client"))]
+    #[test]
+    fn transfers_in_snapshot_are_resumed() {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use packet::{AckPacket, DataPacketOctet, DecodePacket, EncodePacket, Mode};
+        use snapshot::{self, Entry};
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-resume-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..1100).map(|i| i as u8).collect();
+        fs::write(dir.join("kernel"), &contents).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let local = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let session_file = dir.join("sessions");
+        snapshot::save(&session_file, &[Entry {
+            client: client.local_addr().unwrap(),
+            local: local,
+            filename: "kernel".to_owned(),
+            mode: Mode::Octet,
+            block_size: 512,
+            acked_blocks: 1,
+        }]).unwrap();
+
+        let (root, path) = (dir.clone(), session_file.clone());
+        thread::spawn(move || {
+            ServerBuilder::new("127.0.0.1:0".parse().unwrap()).root(root).session_file(path).build().unwrap()
+                .run().unwrap()
+        });
+
+        let mut buf = vec![0; 1024];
+        for &(block_id, start, end) in &[(2, 512, 1024), (3, 1024, 1100)] {
+            let (n, from) = client.recv_from(&mut buf).unwrap();
+            assert_eq!(local, from);
+            let data = DataPacketOctet::decode(&buf[..n]).unwrap();
+            assert_eq!(block_id, data.block_id());
+            assert_eq!(&contents[start..end], data.data());
+            client.send_to(AckPacket::new(block_id).encode().packet_buf(), from).unwrap();
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unacknowledged_blocks_are_retransmitted_by_timer_wheel() {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use packet::{DataPacketOctet, DecodePacket, EncodePacket, Mode, RequestPacket};
+        use wheel::{TimerStats, TimerWheel};
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-wheel-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), vec![3; 100]).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stats = TimerStats::new();
+        let (root, wheel) = (dir.clone(), TimerWheel::new(Duration::from_millis(5)).stats(stats.clone()));
+        thread::spawn(move || {
+            ServerBuilder::new(addr).root(root).timeout(Duration::from_millis(100)).timer_wheel(wheel).build().unwrap()
+                .run().unwrap()
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client.send_to(RequestPacket::read_request("file", Mode::Octet).encode().packet_buf(), addr).unwrap();
+        let mut buf = vec![0; 1024];
+        // The block isn't acknowledged, it's sent again once the timer fired.
+        for _ in 0..2 {
+            let (n, _) = client.recv_from(&mut buf).unwrap();
+            let data = DataPacketOctet::decode(&buf[..n]).unwrap();
+            assert_eq!((1, &[3; 100][..]), (data.block_id(), data.data()));
+        }
+        let drift = stats.get();
+        assert!(drift.fired >= 1);
+        assert!(drift.max().unwrap() < Duration::from_millis(100));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn arena_high_water_is_collected() {
+        use std::cmp;
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use packet::{AckPacket, DataPacketOctet, DecodePacket, EncodePacket, Mode, OptionAckPacket, RequestPacket,
+                     TransferOptions};
+        use config;
+        use stats::Stats;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-arena-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), b"abc").unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (root, stats) = (dir.clone(), Stats::new());
+        let collector = stats.clone();
+        thread::spawn(move || ServerBuilder::new(addr).root(root).stats(collector).build().unwrap().run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut options = TransferOptions::new();
+        let block_size = cmp::min(1024, config::MAX_BLOCK_SIZE);
+        options.insert("blksize", block_size.to_string());
+        let request = RequestPacket::read_request("file", Mode::Octet).with_options(options);
+        client.send_to(request.encode().packet_buf(), addr).unwrap();
+        let mut buf = vec![0; 2048];
+        let (n, transfer) = client.recv_from(&mut buf).unwrap();
+        let oack_len = OptionAckPacket::decode(&buf[..n]).map(|_| n).unwrap();
+        client.send_to(AckPacket::new(0).encode().packet_buf(), transfer).unwrap();
+        let (n, _) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(Some(&b"abc"[..]), DataPacketOctet::decode(&buf[..n]).as_ref().map(|data| data.data()));
+        client.send_to(AckPacket::new(1).encode().packet_buf(), transfer).unwrap();
+        for _ in 0..100 {
+            if stats.get().arena.transfers == 1 {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The receive buffer of the negotiated block size and the option acknowledgment.
+        let arena = stats.get().arena;
+        assert_eq!((1, block_size as u64 + 5 + oack_len as u64), (arena.transfers, arena.max_high_water));
+        assert_eq!(Some(arena.max_high_water), arena.mean_high_water());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn strict_server_drops_unterminated_requests() {
+        use std::env;
+        use std::fs;
+        use std::io::ErrorKind;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use config::Conformance;
+        use packet::{AckPacket, DataPacketOctet, DecodePacket, EncodePacket, Mode, RequestPacket};
+        use stats::Stats;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-strict-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), b"abc").unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (root, stats) = (dir.clone(), Stats::new());
+        let collector = stats.clone();
+        thread::spawn(move || {
+            ServerBuilder::new(addr).root(root).conformance(Conformance::Strict).stats(collector).build().unwrap()
+                .run().unwrap()
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        client.send_to(b"\x00\x01file\x00octet", addr).unwrap();
+        let mut buf = vec![0; 1024];
+        let err = client.recv_from(&mut buf).unwrap_err();
+        assert!(err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut);
+
+        client.send_to(RequestPacket::read_request("file", Mode::Octet).encode().packet_buf(), addr).unwrap();
+        let (n, transfer) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(Some(&b"abc"[..]), DataPacketOctet::decode(&buf[..n]).as_ref().map(|data| data.data()));
+        client.send_to(AckPacket::new(1).encode().packet_buf(), transfer).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let conformance = stats.get().conformance;
+        assert_eq!((1, 1), (conformance.strict_transfers, conformance.refused));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(all(unix, feature = "mio-client"))]
+    #[test]
+    fn empty_and_block_multiple_files_are_transferred() {
+        use std::env;
+        use std::fs;
+        use std::path::Path;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use mio::net::UnixDatagram;
+
+        use client::Client;
+        use packet::Mode;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-sizes-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let server_path = dir.join("server.sock");
+        let (path, root) = (server_path.clone(), dir.clone());
+        thread::spawn(move || ServerBuilder::unix(path).root(root).build().unwrap().run().unwrap());
+        while !server_path.exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let client = Client::new("127.0.0.1:69".parse().unwrap());
+        for &size in &[0, 512, 1024] {
+            let contents: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let name = format!("file-{}", size);
+            let socket = UnixDatagram::bind(dir.join(format!("put-{}.sock", size))).unwrap();
+            client.put_over(socket, server_path.clone(), Path::new(&name), Mode::Octet, &mut &contents[..]).unwrap();
+            assert_eq!(contents, fs::read(dir.join(&name)).unwrap());
+
+            let socket = UnixDatagram::bind(dir.join(format!("get-{}.sock", size))).unwrap();
+            let mut received = Vec::new();
+            client.get_over(socket, server_path.clone(), Path::new(&name), Mode::Octet, &mut received).unwrap();
+            assert_eq!(contents, received);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn window_is_sent_again_after_lost_block() {
+        download_window_losing_block(false);
+    }
+
+    #[test]
+    fn windows_are_sent_with_segmentation_offload() {
+        download_window_losing_block(true);
+    }
+
+    /// Downloads a file in windows of 4 blocks, the first transmission of block 3 is lost.
+    fn download_window_losing_block(offload: bool) {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use config::WindowSize;
+        use packet::{AckPacket, DataPacketOctet, DecodePacket, EncodePacket, Mode, OptionAckPacket, RequestPacket};
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-window-{}-{}", offload, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        fs::write(dir.join("file"), &contents).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let root = dir.clone();
+        thread::spawn(move || {
+            ServerBuilder::new(addr).root(root).max_window_size(WindowSize::new(8).unwrap())
+                .segmentation_offload(offload).build().unwrap().run().unwrap()
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut options = TransferOptions::new();
+        options.insert("windowsize", "4");
+        let request = RequestPacket::read_request("file", Mode::Octet).with_options(options);
+        client.send_to(request.encode().packet_buf(), addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = vec![0; 1024];
+        let (n, server) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(Some("4"), OptionAckPacket::decode(&buf[..n]).unwrap().options().get("windowsize"));
+        client.send_to(AckPacket::new(0).encode().packet_buf(), server).unwrap();
+
+        // Acknowledges like a receiver of 4 block windows (RFC 7440), the end
+        // of each window and the first block after a lost one. The timeout is
+        // shorter than the one of the server, a window ending early leaves the
+        // client waiting.
+        client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+        let (mut received, mut in_window, mut gap, mut dropped) = (Vec::new(), 0, false, false);
+        let mut expected = 1;
+        loop {
+            let (n, _) = client.recv_from(&mut buf).expect("window ended early");
+            let data = DataPacketOctet::decode(&buf[..n]).unwrap();
+            if data.block_id() == 3 && !dropped {
+                dropped = true;
+                continue
+            }
+            if data.block_id() != expected {
+                // The window starts again after the last received block.
+                if !gap {
+                    client.send_to(AckPacket::new(expected - 1).encode().packet_buf(), server).unwrap();
+                    in_window = 0;
+                    gap = true;
+                }
+                continue
+            }
+            gap = false;
+            received.extend_from_slice(data.data());
+            in_window += 1;
+            expected += 1;
+            let last = data.data().len() < 512;
+            if in_window == 4 || last {
+                client.send_to(AckPacket::new(data.block_id()).encode().packet_buf(), server).unwrap();
+                in_window = 0;
+            }
+            if last {
+                break
+            }
+        }
+        assert!(dropped);
+        assert_eq!(contents, received);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "mio-client")]
+    #[test]
+    fn client_uploads_and_downloads_windows() {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::path::Path;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use client::ClientBuilder;
+        use config::WindowSize;
+        use packet::Mode;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-windows-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        fs::write(dir.join("download"), &contents).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let root = dir.clone();
+        thread::spawn(move || {
+            ServerBuilder::new(addr).root(root).max_window_size(WindowSize::new(4).unwrap()).build().unwrap()
+                .run().unwrap()
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        let client = ClientBuilder::new(addr).window_size(WindowSize::new(8).unwrap()).build().unwrap();
+        let params = client.put(Path::new("upload"), Mode::Octet, &mut &contents[..]).unwrap();
+        assert_eq!(4, params.window_size.get());
+        assert_eq!(contents, fs::read(dir.join("upload")).unwrap());
+        let mut received = Vec::new();
+        let params = client.get(Path::new("download"), Mode::Octet, &mut received).unwrap();
+        assert_eq!(4, params.window_size.get());
+        assert_eq!(contents, received);
+        // Neither side waited for the rest of a window.
+        assert_eq!(0, client.stats().get().protocol.retransmissions);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "experimental-resume")]
+    fn transfers_continue_from_another_address_with_their_token() {
+        use std::env;
+        use std::fs;
+        use std::net::UdpSocket;
+        use std::process;
+        use std::thread;
+        use std::time::Duration;
+
+        use packet::{self, AckPacket, DataPacketOctet, DecodePacket, EncodePacket, ErrorPacket, Mode,
+                     OptionAckPacket, RequestPacket, TransferOptions};
+        use resume::{self, Token, SESSION_OPTION};
+        use rng::SharedRng;
+        use super::ServerBuilder;
+
+        let dir = env::temp_dir().join(format!("tftp-resume-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let contents: Vec<u8> = (0..1300).map(|i| i as u8).collect();
+        fs::write(dir.join("file"), &contents).unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let root = dir.clone();
+        thread::spawn(move || ServerBuilder::new(addr).root(root).build().unwrap().run().unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut options = TransferOptions::new();
+        resume::request_token(&mut options);
+        let request = RequestPacket::read_request("file", Mode::Octet).with_options(options).encode();
+        client.send_to(request.packet_buf(), addr).unwrap();
+        let mut buf = vec![0; 1024];
+        let (n, transfer) = client.recv_from(&mut buf).unwrap();
+        let acknowledged = OptionAckPacket::decode(&buf[..n]).unwrap().options().clone().into_owned();
+        assert!(acknowledged.get(SESSION_OPTION).unwrap().parse::<Token>().is_ok());
+        client.send_to(AckPacket::new(0).encode().packet_buf(), transfer).unwrap();
+        let n = client.recv(&mut buf).unwrap();
+        assert_eq!(&contents[..512], DataPacketOctet::decode(&buf[..n]).unwrap().data());
+
+        // The client is renumbered, the server doesn't know its new address.
+        let renumbered = UdpSocket::bind("127.0.0.1:0").unwrap();
+        renumbered.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        renumbered.send_to(AckPacket::new(1).encode().packet_buf(), transfer).unwrap();
+        let n = renumbered.recv(&mut buf).unwrap();
+        assert_eq!(packet::Error::UnknownTransferId, ErrorPacket::decode(&buf[..n]).unwrap().error());
+        // Another token doesn't take the transfer over.
+        let mut other = TransferOptions::new();
+        other.insert(SESSION_OPTION, Token::generate(&SharedRng::seeded(1)).to_string());
+        renumbered.send_to(&resume::resume_request(request.packet_buf(), &other).unwrap(), transfer).unwrap();
+        let n = renumbered.recv(&mut buf).unwrap();
+        assert_eq!(packet::Error::UnknownTransferId, ErrorPacket::decode(&buf[..n]).unwrap().error());
+
+        // The token continues the transfer, the last block is sent again.
+        renumbered.send_to(&resume::resume_request(request.packet_buf(), &acknowledged).unwrap(), transfer).unwrap();
+        let n = renumbered.recv(&mut buf).unwrap();
+        assert_eq!(Some(1), DataPacketOctet::decode(&buf[..n]).map(|data| data.block_id()));
+        client.send_to(AckPacket::new(1).encode().packet_buf(), transfer).unwrap();
+        let n = client.recv(&mut buf).unwrap();
+        assert_eq!(packet::Error::UnknownTransferId, ErrorPacket::decode(&buf[..n]).unwrap().error());
+        let mut received = contents[..512].to_vec();
+        for block_id in 1..3 {
+            renumbered.send_to(AckPacket::new(block_id).encode().packet_buf(), transfer).unwrap();
+            let n = renumbered.recv(&mut buf).unwrap();
+            received.extend_from_slice(DataPacketOctet::decode(&buf[..n]).unwrap().data());
+        }
+        renumbered.send_to(AckPacket::new(3).encode().packet_buf(), transfer).unwrap();
+        assert_eq!(contents, received);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,727 @@
+//! Declarative configuration of a server.
+//!
+//! A `ServerConfig` describes a server as data: the address it listens on,
+//! the root and the settings of its `FsHandler`, the limits of the option
+//! negotiation and of transfers, and the subnets whose clients get other
+//! settings. `ServerConfig::builder` turns it into a `ServerBuilder`, which
+//! embedders can configure further before building the server.
+//!
+//! With the `toml-config` and `json-config` features the configuration is
+//! read from TOML or JSON documents, the `tftpd` binary reads the same files
+//! with `--config`:
+//!
+//! ```toml
+//! listen = "0.0.0.0:69"
+//! root = "/srv/tftp"
+//! read_only = true
+//!
+//! [handler]
+//! symlinks = "deny"
+//! share_files = true
+//! fallbacks = [{ pattern = "pxelinux.cfg/*", file = "pxelinux.cfg/default" }]
+//!
+//! [options]
+//! max_blksize = 1468
+//! max_windowsize = 8
+//! timeout = 1.5
+//!
+//! [limits]
+//! max_transfers = 200
+//!
+//! [[subnets]]
+//! subnet = "10.1.0.0/16"
+//! read_only = false
+//! ```
+//!
+//! Every key is optional, see the fields of `ServerConfig` for their meaning
+//! and defaults. Unknown keys and invalid values are rejected with an error
+//! naming the key, e.g. `options.max_blksize`.
+
+use std::io;
+use std::net::{AddrParseError, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+use std::cmp;
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+use std::fmt;
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+use std::fs;
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+use std::path::Path;
+
+#[cfg(feature = "json-config")]
+use serde_json;
+#[cfg(feature = "toml-config")]
+use toml;
+
+use bandwidth::BandwidthCap;
+use config::{BlockSize, Conformance, Retries, Subnet, WindowSize, DEFAULT_TIMEOUT};
+use filename::FilenameCodec;
+use handler::{DirectoryPolicy, FsHandler, SharedFiles, SymlinkPolicy};
+use pool::IoPool;
+use server::{ServerBuilder, SubnetConfig};
+
+/// File operations waiting for a free I/O thread, per thread.
+const IO_QUEUE_PER_THREAD: usize = 16;
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum ConfigFileError {
+        Io(err: io::Error) {
+            from()
+            description("reading the configuration failed")
+            display("Reading the configuration failed: {}", err)
+            cause(err)
+        }
+        Syntax(message: String) {
+            description("invalid syntax")
+            display("Invalid configuration: {}", message)
+        }
+        UnknownKey(key: String) {
+            description("unknown key")
+            display("Unknown key {}", key)
+        }
+        InvalidValue(key: String, message: String) {
+            description("invalid value")
+            display("Invalid value of {}: {}", key, message)
+        }
+    }
+}
+
+/// Address a server listens on.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum ListenAddr {
+    /// UDP address and port, like `0.0.0.0:69`.
+    Udp(SocketAddr),
+
+    /// Path of a Unix datagram socket, written `unix:PATH`.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<ListenAddr, AddrParseError> {
+        #[cfg(unix)]
+        {
+            if s.starts_with("unix:") {
+                return Ok(ListenAddr::Unix(PathBuf::from(&s["unix:".len()..])))
+            }
+        }
+        s.parse().map(ListenAddr::Udp)
+    }
+}
+
+/// Configuration of a server, by default like `ServerBuilder::new` listening
+/// on `0.0.0.0:69` and serving the current directory.
+///
+/// The key of each setting in configuration files is given in brackets,
+/// durations are given in seconds.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Address the server listens on (`listen`).
+    pub listen: ListenAddr,
+
+    /// Directory files are served from and written to (`root`).
+    pub root: PathBuf,
+
+    /// Write requests are rejected (`read_only`).
+    pub read_only: bool,
+
+    /// What requests of a directory get (`handler.directories`), like
+    /// `not-found`, `listing` or `index:NAME`.
+    pub directories: DirectoryPolicy,
+
+    /// Symbolic links followed (`handler.symlinks`), `deny`, `within-root` or `all`.
+    pub symlinks: SymlinkPolicy,
+
+    /// SHA-256 digests are served as `FILE.sha256` (`handler.checksums`).
+    pub checksums: bool,
+
+    /// Files are opened once for concurrent downloads (`handler.share_files`).
+    pub share_files: bool,
+
+    /// Files are read and written on this many threads (`handler.io_threads`).
+    pub io_threads: Option<usize>,
+
+    /// Patterns of missing files and the files served instead
+    /// (`handler.fallbacks`, tables with a `pattern` and a `file`).
+    pub fallbacks: Vec<(String, String)>,
+
+    /// Missing files are served from their compressed variants
+    /// (`handler.compressed`).
+    #[cfg(feature = "compression")]
+    pub compressed: bool,
+
+    /// Largest block size accepted during the negotiation (`options.max_blksize`).
+    pub max_block_size: Option<BlockSize>,
+
+    /// Largest window size accepted during the negotiation
+    /// (`options.max_windowsize`), by default every block is acknowledged.
+    pub max_window_size: Option<WindowSize>,
+
+    /// Windows are sent with UDP segmentation offload
+    /// (`options.segmentation_offload`).
+    pub segmentation_offload: bool,
+
+    /// Time to wait for a response before retransmitting (`options.timeout`).
+    pub timeout: Duration,
+
+    /// Retransmissions before a transfer fails (`options.retries`).
+    pub retries: Retries,
+
+    /// Encoding of file names in requests (`options.filename_encoding`),
+    /// `utf8`, `latin1` or `percent`.
+    pub filename_codec: FilenameCodec,
+
+    /// How closely clients are held to the RFCs (`options.conformance`),
+    /// `strict` or `lenient`.
+    pub conformance: Conformance,
+
+    /// Transfers running at the same time (`limits.max_transfers`).
+    pub max_transfers: Option<usize>,
+
+    /// Bytes per second of the data packets of all transfers
+    /// (`limits.max_bandwidth`), see `bandwidth::BandwidthCap`.
+    pub max_bandwidth: Option<u64>,
+
+    /// Time a whole transfer may take (`limits.deadline`).
+    pub deadline: Option<Duration>,
+
+    /// Interval of keepalives while the handler is slow (`limits.keepalive`).
+    pub keepalive: Option<Duration>,
+
+    /// File recording running downloads (`session_file`).
+    pub session_file: Option<PathBuf>,
+
+    /// Subnets whose clients get other settings (`subnets`, tables with a
+    /// `subnet` and any of `root`, `read_only`, `max_blksize`, `timeout` and
+    /// `retries`).
+    pub subnets: Vec<(Subnet, SubnetConfig)>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            listen: ListenAddr::Udp("0.0.0.0:69".parse().unwrap()),
+            root: PathBuf::from("."),
+            read_only: false,
+            directories: DirectoryPolicy::default(),
+            symlinks: SymlinkPolicy::default(),
+            checksums: false,
+            share_files: false,
+            io_threads: None,
+            fallbacks: Vec::new(),
+            #[cfg(feature = "compression")]
+            compressed: false,
+            max_block_size: None,
+            max_window_size: None,
+            segmentation_offload: false,
+            timeout: DEFAULT_TIMEOUT,
+            retries: Retries::default(),
+            filename_codec: FilenameCodec::default(),
+            conformance: Conformance::default(),
+            max_transfers: None,
+            max_bandwidth: None,
+            deadline: None,
+            keepalive: None,
+            session_file: None,
+            subnets: Vec::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Creates the default configuration.
+    pub fn new() -> ServerConfig {
+        ServerConfig::default()
+    }
+
+    /// Reads the configuration from a TOML document.
+    #[cfg(feature = "toml-config")]
+    pub fn from_toml(document: &str) -> Result<ServerConfig, ConfigFileError> {
+        match toml::from_str(document) {
+            Ok(value) => ServerConfig::from_value(from_toml_value(value)),
+            Err(e) => Err(ConfigFileError::Syntax(e.to_string())),
+        }
+    }
+
+    /// Reads the configuration from a JSON document.
+    #[cfg(feature = "json-config")]
+    pub fn from_json(document: &str) -> Result<ServerConfig, ConfigFileError> {
+        match serde_json::from_str(document) {
+            Ok(value) => ServerConfig::from_value(from_json_value(value).unwrap_or(Value::Table(Vec::new()))),
+            Err(e) => Err(ConfigFileError::Syntax(e.to_string())),
+        }
+    }
+
+    /// Reads the configuration from the file `path`, a JSON document if its
+    /// name ends with `.json` and a TOML document otherwise.
+    #[cfg(any(feature = "toml-config", feature = "json-config"))]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ServerConfig, ConfigFileError> {
+        let path = path.as_ref();
+        let document = try!(fs::read_to_string(path));
+        match path.extension().and_then(|extension| extension.to_str()) {
+            #[cfg(feature = "json-config")]
+            Some("json") => ServerConfig::from_json(&document),
+            #[cfg(feature = "toml-config")]
+            _ => ServerConfig::from_toml(&document),
+            #[cfg(not(feature = "toml-config"))]
+            _ => Err(ConfigFileError::Syntax(format!("{} is not a JSON file", path.display()))),
+        }
+    }
+
+    /// Returns a builder of the configured server.
+    ///
+    /// Fails if the I/O threads can't be started.
+    pub fn builder(&self) -> io::Result<ServerBuilder> {
+        let mut handler = FsHandler::new(self.root.clone())
+            .directories(self.directories.clone())
+            .symlinks(self.symlinks)
+            .checksum_sidecars(self.checksums);
+        #[cfg(feature = "compression")]
+        {
+            handler = handler.compressed(self.compressed);
+        }
+        if let Some(threads) = self.io_threads {
+            handler = handler.io_pool(try!(IoPool::new(threads, threads * IO_QUEUE_PER_THREAD)));
+        }
+        for &(ref pattern, ref file) in &self.fallbacks {
+            handler = handler.fallback(pattern.clone(), file.clone());
+        }
+        if self.share_files {
+            handler = handler.share_files(SharedFiles::new());
+        }
+        let builder = match self.listen {
+            ListenAddr::Udp(addr) => ServerBuilder::new(addr),
+            #[cfg(unix)]
+            ListenAddr::Unix(ref path) => ServerBuilder::unix(path.clone()),
+        };
+        let mut builder = builder.handler(handler)
+            .read_only(self.read_only)
+            .segmentation_offload(self.segmentation_offload)
+            .timeout(self.timeout)
+            .retries(self.retries)
+            .filename_codec(self.filename_codec)
+            .conformance(self.conformance);
+        if let Some(max_block_size) = self.max_block_size {
+            builder = builder.max_block_size(max_block_size);
+        }
+        if let Some(max_window_size) = self.max_window_size {
+            builder = builder.max_window_size(max_window_size);
+        }
+        if let Some(max_transfers) = self.max_transfers {
+            builder = builder.max_transfers(max_transfers);
+        }
+        if let Some(max_bandwidth) = self.max_bandwidth {
+            builder = builder.bandwidth_cap(BandwidthCap::new(max_bandwidth));
+        }
+        if let Some(deadline) = self.deadline {
+            builder = builder.deadline(deadline);
+        }
+        if let Some(keepalive) = self.keepalive {
+            builder = builder.keepalive(keepalive);
+        }
+        if let Some(ref session_file) = self.session_file {
+            builder = builder.session_file(session_file.clone());
+        }
+        for &(subnet, ref config) in &self.subnets {
+            builder = builder.subnet(subnet, config.clone());
+        }
+        Ok(builder)
+    }
+
+    #[cfg(any(feature = "toml-config", feature = "json-config"))]
+    fn from_value(document: Value) -> Result<ServerConfig, ConfigFileError> {
+        let entries = match document {
+            Value::Table(entries) => entries,
+            _ => return Err(ConfigFileError::Syntax("expected a table of settings".to_owned())),
+        };
+        let mut config = ServerConfig::default();
+        let mut document = Table::new(String::new(), &entries);
+        if let Some(listen) = try!(document.parse("listen")) {
+            config.listen = listen;
+        }
+        if let Some(root) = try!(document.string("root")) {
+            config.root = PathBuf::from(root);
+        }
+        if let Some(read_only) = try!(document.boolean("read_only")) {
+            config.read_only = read_only;
+        }
+        if let Some(session_file) = try!(document.string("session_file")) {
+            config.session_file = Some(PathBuf::from(session_file));
+        }
+        if let Some(mut handler) = try!(document.table("handler")) {
+            try!(config.read_handler(&mut handler));
+            try!(handler.finish());
+        }
+        if let Some(mut options) = try!(document.table("options")) {
+            if let Some(block_size) = try!(options.integer("max_blksize")) {
+                config.max_block_size = Some(try!(options.check("max_blksize", BlockSize::new(block_size as usize))));
+            }
+            if let Some(window_size) = try!(options.integer("max_windowsize")) {
+                let window_size = cmp::min(window_size, u16::max_value() as u64) as u16;
+                config.max_window_size = Some(try!(options.check("max_windowsize", WindowSize::new(window_size))));
+            }
+            if let Some(offload) = try!(options.boolean("segmentation_offload")) {
+                config.segmentation_offload = offload;
+            }
+            if let Some(timeout) = try!(options.seconds("timeout")) {
+                config.timeout = timeout;
+            }
+            if let Some(retries) = try!(options.integer("retries")) {
+                let retries = if retries > u32::max_value() as u64 { u32::max_value() } else { retries as u32 };
+                config.retries = try!(options.check("retries", Retries::new(retries)));
+            }
+            if let Some(codec) = try!(options.parse("filename_encoding")) {
+                config.filename_codec = codec;
+            }
+            if let Some(conformance) = try!(options.parse("conformance")) {
+                config.conformance = conformance;
+            }
+            try!(options.finish());
+        }
+        if let Some(mut limits) = try!(document.table("limits")) {
+            config.max_transfers = try!(limits.count("max_transfers"));
+            config.max_bandwidth = try!(limits.count("max_bandwidth")).map(|bytes| bytes as u64);
+            config.deadline = try!(limits.seconds("deadline"));
+            config.keepalive = try!(limits.seconds("keepalive"));
+            try!(limits.finish());
+        }
+        if let Some(subnets) = try!(document.array("subnets")) {
+            for (i, value) in subnets.iter().enumerate() {
+                let mut subnet = try!(Table::element(document.key(&format!("subnets[{}]", i)), value));
+                config.subnets.push(try!(read_subnet(&mut subnet)));
+                try!(subnet.finish());
+            }
+        }
+        try!(document.finish());
+        Ok(config)
+    }
+
+    #[cfg(any(feature = "toml-config", feature = "json-config"))]
+    fn read_handler(&mut self, handler: &mut Table) -> Result<(), ConfigFileError> {
+        if let Some(directories) = try!(handler.parse("directories")) {
+            self.directories = directories;
+        }
+        if let Some(symlinks) = try!(handler.parse("symlinks")) {
+            self.symlinks = symlinks;
+        }
+        if let Some(checksums) = try!(handler.boolean("checksums")) {
+            self.checksums = checksums;
+        }
+        if let Some(share_files) = try!(handler.boolean("share_files")) {
+            self.share_files = share_files;
+        }
+        self.io_threads = try!(handler.count("io_threads"));
+        #[cfg(feature = "compression")]
+        {
+            if let Some(compressed) = try!(handler.boolean("compressed")) {
+                self.compressed = compressed;
+            }
+        }
+        if let Some(fallbacks) = try!(handler.array("fallbacks")) {
+            for (i, value) in fallbacks.iter().enumerate() {
+                let mut fallback = try!(Table::element(handler.key(&format!("fallbacks[{}]", i)), value));
+                let pattern = try!(fallback.required_string("pattern"));
+                let file = try!(fallback.required_string("file"));
+                try!(fallback.finish());
+                self.fallbacks.push((pattern.to_owned(), file.to_owned()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+fn read_subnet(table: &mut Table) -> Result<(Subnet, SubnetConfig), ConfigFileError> {
+    let subnet = match try!(table.parse("subnet")) {
+        Some(subnet) => subnet,
+        None => return Err(ConfigFileError::InvalidValue(table.key("subnet"), "missing".to_owned())),
+    };
+    let mut config = SubnetConfig::new();
+    if let Some(root) = try!(table.string("root")) {
+        config = config.root(root);
+    }
+    if let Some(read_only) = try!(table.boolean("read_only")) {
+        config = config.read_only(read_only);
+    }
+    if let Some(block_size) = try!(table.integer("max_blksize")) {
+        config = config.max_block_size(try!(table.check("max_blksize", BlockSize::new(block_size as usize))));
+    }
+    if let Some(timeout) = try!(table.seconds("timeout")) {
+        config = config.timeout(timeout);
+    }
+    if let Some(retries) = try!(table.integer("retries")) {
+        let retries = if retries > u32::max_value() as u64 { u32::max_value() } else { retries as u32 };
+        config = config.retries(try!(table.check("retries", Retries::new(retries))));
+    }
+    Ok((subnet, config))
+}
+
+/// Value of a configuration document, TOML and JSON documents are converted
+/// to it.
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+#[derive(Debug, Clone)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Vec<(String, Value)>),
+}
+
+#[cfg(feature = "toml-config")]
+fn from_toml_value(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::Integer(i),
+        toml::Value::Float(f) => Value::Float(f),
+        toml::Value::Boolean(b) => Value::Boolean(b),
+        toml::Value::Datetime(datetime) => Value::String(datetime.to_string()),
+        toml::Value::Array(values) => Value::Array(values.into_iter().map(from_toml_value).collect()),
+        toml::Value::Table(entries) => {
+            Value::Table(entries.into_iter().map(|(key, value)| (key, from_toml_value(value))).collect())
+        }
+    }
+}
+
+/// Converts a JSON value, `null` is left out like a missing key.
+#[cfg(feature = "json-config")]
+fn from_json_value(value: serde_json::Value) -> Option<Value> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(b) => Some(Value::Boolean(b)),
+        serde_json::Value::Number(n) => Some(match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Float(n.as_f64().unwrap_or(0.0)),
+        }),
+        serde_json::Value::String(s) => Some(Value::String(s)),
+        serde_json::Value::Array(values) => Some(Value::Array(values.into_iter().filter_map(from_json_value).collect())),
+        serde_json::Value::Object(entries) => {
+            Some(Value::Table(entries.into_iter()
+                .filter_map(|(key, value)| from_json_value(value).map(|value| (key, value)))
+                .collect()))
+        }
+    }
+}
+
+/// Table of a configuration document, keeps track of the keys that were read
+/// to reject unknown ones.
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+struct Table<'a> {
+    /// Key of the table, empty for the document itself.
+    path: String,
+    entries: &'a [(String, Value)],
+    read: Vec<bool>,
+}
+
+#[cfg(any(feature = "toml-config", feature = "json-config"))]
+impl<'a> Table<'a> {
+    fn new(path: String, entries: &'a [(String, Value)]) -> Table<'a> {
+        Table {
+            path: path,
+            entries: entries,
+            read: vec![false; entries.len()],
+        }
+    }
+
+    /// Returns the table that is the element `path` of an array.
+    fn element(path: String, value: &'a Value) -> Result<Table<'a>, ConfigFileError> {
+        match *value {
+            Value::Table(ref entries) => Ok(Table::new(path, entries)),
+            _ => Err(ConfigFileError::InvalidValue(path, "expected a table".to_owned())),
+        }
+    }
+
+    /// Returns the full name of `key`.
+    fn key(&self, key: &str) -> String {
+        if self.path.is_empty() { key.to_owned() } else { format!("{}.{}", self.path, key) }
+    }
+
+    fn invalid<T>(&self, key: &str, message: &str) -> Result<T, ConfigFileError> {
+        Err(ConfigFileError::InvalidValue(self.key(key), message.to_owned()))
+    }
+
+    /// Converts the error of a value that failed validation.
+    fn check<T, E: fmt::Display>(&self, key: &str, result: Result<T, E>) -> Result<T, ConfigFileError> {
+        result.or_else(|e| self.invalid(key, &e.to_string()))
+    }
+
+    fn get(&mut self, key: &str) -> Option<&'a Value> {
+        let entries = self.entries;
+        entries.iter().position(|&(ref name, _)| name == key).map(|i| {
+            self.read[i] = true;
+            &entries[i].1
+        })
+    }
+
+    fn string(&mut self, key: &str) -> Result<Option<&'a str>, ConfigFileError> {
+        match self.get(key) {
+            Some(&Value::String(ref s)) => Ok(Some(s)),
+            Some(_) => self.invalid(key, "expected a string"),
+            None => Ok(None),
+        }
+    }
+
+    fn required_string(&mut self, key: &str) -> Result<&'a str, ConfigFileError> {
+        match try!(self.string(key)) {
+            Some(s) => Ok(s),
+            None => self.invalid(key, "missing"),
+        }
+    }
+
+    /// Returns a value given as a string, like an address.
+    fn parse<T>(&mut self, key: &str) -> Result<Option<T>, ConfigFileError>
+        where T: FromStr, T::Err: fmt::Display
+    {
+        match try!(self.string(key)) {
+            Some(s) => self.check(key, s.parse()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn integer(&mut self, key: &str) -> Result<Option<u64>, ConfigFileError> {
+        match self.get(key) {
+            Some(&Value::Integer(i)) if i >= 0 => Ok(Some(i as u64)),
+            Some(_) => self.invalid(key, "expected a non-negative integer"),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns a number of things that must be at least 1.
+    fn count(&mut self, key: &str) -> Result<Option<usize>, ConfigFileError> {
+        match try!(self.integer(key)) {
+            Some(0) => self.invalid(key, "must be at least 1"),
+            count => Ok(count.map(|count| count as usize)),
+        }
+    }
+
+    fn boolean(&mut self, key: &str) -> Result<Option<bool>, ConfigFileError> {
+        match self.get(key) {
+            Some(&Value::Boolean(b)) => Ok(Some(b)),
+            Some(_) => self.invalid(key, "expected true or false"),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns a duration given in seconds, which must be longer than zero.
+    fn seconds(&mut self, key: &str) -> Result<Option<Duration>, ConfigFileError> {
+        let seconds = match self.get(key) {
+            Some(&Value::Integer(i)) => i as f64,
+            Some(&Value::Float(f)) => f,
+            Some(_) => return self.invalid(key, "expected a number of seconds"),
+            None => return Ok(None),
+        };
+        if !(seconds > 0.0 && seconds < u32::max_value() as f64) {
+            return self.invalid(key, "must be longer than zero")
+        }
+        Ok(Some(Duration::from_millis((seconds * 1000.0) as u64)))
+    }
+
+    fn table(&mut self, key: &str) -> Result<Option<Table<'a>>, ConfigFileError> {
+        match self.get(key) {
+            Some(&Value::Table(ref entries)) => Ok(Some(Table::new(self.key(key), entries))),
+            Some(_) => self.invalid(key, "expected a table"),
+            None => Ok(None),
+        }
+    }
+
+    fn array(&mut self, key: &str) -> Result<Option<&'a [Value]>, ConfigFileError> {
+        match self.get(key) {
+            Some(&Value::Array(ref values)) => Ok(Some(values)),
+            Some(_) => self.invalid(key, "expected an array"),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails if the table has a key that wasn't read.
+    fn finish(self) -> Result<(), ConfigFileError> {
+        match self.read.iter().position(|&read| !read) {
+            Some(i) => Err(ConfigFileError::UnknownKey(self.key(&self.entries[i].0))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "toml-config"))]
+mod test {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use config::Conformance;
+    use handler::SymlinkPolicy;
+    use super::{ConfigFileError, ListenAddr, ServerConfig};
+
+    #[test]
+    fn configuration_is_read_from_toml() {
+        let config = ServerConfig::from_toml(r#"
+            listen = "127.0.0.1:6969"
+            root = "/srv/tftp"
+
+            [handler]
+            symlinks = "deny"
+            fallbacks = [{ pattern = "pxelinux.cfg/*", file = "pxelinux.cfg/default" }]
+
+            [options]
+            max_blksize = 1468
+            max_windowsize = 8
+            segmentation_offload = true
+            timeout = 1.5
+            conformance = "strict"
+
+            [limits]
+            max_transfers = 200
+            max_bandwidth = 12500000
+
+            [[subnets]]
+            subnet = "10.1.0.0/16"
+            read_only = true
+        "#).unwrap();
+        assert_eq!(ListenAddr::Udp("127.0.0.1:6969".parse().unwrap()), config.listen);
+        assert_eq!(Path::new("/srv/tftp"), config.root);
+        assert_eq!(SymlinkPolicy::Deny, config.symlinks);
+        assert_eq!(vec![("pxelinux.cfg/*".to_owned(), "pxelinux.cfg/default".to_owned())], config.fallbacks);
+        assert_eq!(Some(1468), config.max_block_size.map(|size| size.get()));
+        assert_eq!(Some(8), config.max_window_size.map(|size| size.get()));
+        assert!(config.segmentation_offload);
+        assert_eq!(Duration::from_millis(1500), config.timeout);
+        assert_eq!(Conformance::Strict, config.conformance);
+        assert_eq!(Some(200), config.max_transfers);
+        assert_eq!(Some(12500000), config.max_bandwidth);
+        assert_eq!("10.1.0.0/16", config.subnets[0].0.to_string());
+        config.builder().unwrap();
+    }
+
+    #[test]
+    fn errors_name_the_offending_key() {
+        let key = |document: &str| match ServerConfig::from_toml(document) {
+            Err(ConfigFileError::InvalidValue(key, _)) | Err(ConfigFileError::UnknownKey(key)) => key,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert_eq!("options.max_blksize", key("[options]\nmax_blksize = 4"));
+        assert_eq!("listen", key("listen = \"localhost\""));
+        assert_eq!("limits.max_transfer", key("[limits]\nmax_transfer = 10"));
+        assert_eq!("subnets[1].subnet", key("[[subnets]]\nsubnet = \"10.0.0.0/8\"\n[[subnets]]\nread_only = true"));
+        assert_eq!("handler.fallbacks[0].file", key("[handler]\nfallbacks = [{ pattern = \"*\" }]"));
+        match ServerConfig::from_toml("root = ") {
+            Err(ConfigFileError::Syntax(_)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[cfg(feature = "json-config")]
+    #[test]
+    fn configuration_is_read_from_json() {
+        let config = ServerConfig::from_json(r#"{"read_only": true, "options": {"retries": 8}, "session_file": null}"#)
+            .unwrap();
+        assert!(config.read_only);
+        assert_eq!(8, config.retries.get());
+        assert_eq!(None, config.session_file);
+    }
+}
@@ -0,0 +1,406 @@
+//! Client transfers driven by the caller's own event loop.
+//!
+//! A `Transfer` doesn't own a socket or read the clock. The caller sends the
+//! datagrams it returns, passes in the datagrams received from the server and
+//! the current time, and calls `poll_timeout` once `next_timeout` passed. This
+//! fits applications that already run a loop of their own, like GUI toolkits,
+//! tick based engines or runtimes no bundled front-end supports.
+//!
+//! The request is sent to the well-known port of the server, everything after
+//! that to the source of the first reply, the transfer identifier of the
+//! server. Datagrams from other sources must not be passed to the transfer.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use config::{BlockSize, Retries, DEFAULT_TIMEOUT};
+use reason::{Reason, ReasonFormat};
+use packet::{self, Mode, Opcode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket, OptionAckPacket,
+    EncodePacket, DecodePacket};
+use transfer::{self, ReadTransfer, WriteTransfer, DataReceived, AckReceived, TransferParams, DEFAULT_BLOCK_SIZE,
+    request_options, negotiated_block_size};
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum Error {
+        Io(err: io::Error, reply: Vec<u8>) {
+            description("io error")
+            display("I/O error: {}", err)
+            cause(err)
+        }
+        Server(err: ErrorPacket<'static>) {
+            description("server error")
+            display("Server error: {}", err)
+            cause(err)
+        }
+        Protocol(reason: &'static str, reply: Vec<u8>) {
+            description("protocol error")
+            display("Protocol error: {}", reason)
+        }
+        TimedOut {
+            description("timed out")
+            display("Transfer timed out")
+        }
+    }
+}
+
+impl Error {
+    /// Returns the error packet that tells the server the transfer failed.
+    ///
+    /// The caller should send it, otherwise the server only notices when its
+    /// retransmissions time out.
+    pub fn reply(&self) -> Option<&[u8]> {
+        match *self {
+            Error::Io(_, ref reply) | Error::Protocol(_, ref reply) => Some(reply),
+            Error::Server(_) | Error::TimedOut => None,
+        }
+    }
+}
+
+/// Result of driving a transfer.
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Local end of the transfer.
+enum Side<'a> {
+    Read(ReadTransfer, &'a mut io::Write),
+    Write(WriteTransfer, &'a mut io::Read),
+}
+
+/// Client transfer of a single file.
+pub struct Transfer<'a> {
+    side: Side<'a>,
+    path: String,
+    mode: Mode,
+    block_size: BlockSize,
+    timeout: Duration,
+    retries: Retries,
+    replied: bool,
+    last_sent: Vec<u8>,
+    next_timeout: Option<Instant>,
+}
+
+impl<'a> Transfer<'a> {
+    /// Creates a transfer reading the file `path` into `writer`.
+    pub fn get(path: &str, mode: Mode, writer: &'a mut io::Write) -> Transfer<'a> {
+        Transfer::new(Side::Read(ReadTransfer::new(DEFAULT_BLOCK_SIZE), writer), path, mode)
+    }
+
+    /// Creates a transfer writing the contents of `reader` to the file `path`.
+    pub fn put(path: &str, mode: Mode, reader: &'a mut io::Read) -> Transfer<'a> {
+        Transfer::new(Side::Write(WriteTransfer::new(DEFAULT_BLOCK_SIZE), reader), path, mode)
+    }
+
+    fn new(side: Side<'a>, path: &str, mode: Mode) -> Transfer<'a> {
+        Transfer {
+            side: side,
+            path: path.to_owned(),
+            mode: mode,
+            block_size: BlockSize::default(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: Retries::default(),
+            replied: false,
+            last_sent: Vec::new(),
+            next_timeout: None,
+        }
+    }
+
+    /// Sets the block size requested from the server (RFC 2348).
+    pub fn block_size(mut self, block_size: BlockSize) -> Transfer<'a> {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the time to wait for a response before the last datagram is sent again.
+    pub fn timeout(mut self, timeout: Duration) -> Transfer<'a> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of retransmissions before the transfer fails.
+    pub fn retries(mut self, retries: Retries) -> Transfer<'a> {
+        self.retries = retries;
+        self
+    }
+
+    /// Starts the transfer, returns the request to send to the server.
+    pub fn start(&mut self, now: Instant) -> Vec<u8> {
+        let retries = self.retries;
+        match self.side {
+            Side::Read(ref mut transfer, _) => transfer.set_retries(retries),
+            Side::Write(ref mut transfer, _) => transfer.set_retries(retries),
+        }
+        let request = match self.side {
+            Side::Read(..) => RequestPacket::read_request(&self.path, self.mode),
+            Side::Write(..) => RequestPacket::write_request(&self.path, self.mode),
+        };
+        let request = request.with_options(request_options(self.block_size.get())).encode();
+        self.sent_datagram(request.packet_buf().to_vec(), now)
+    }
+
+    /// Handles a datagram received from the server, returns the datagram to
+    /// send in response.
+    ///
+    /// Datagrams that can't be decoded or don't belong to the state of the
+    /// transfer are ignored.
+    pub fn handle_datagram(&mut self, datagram: &[u8], now: Instant) -> Result<Option<Vec<u8>>> {
+        if datagram.len() < 2 || self.is_done() && !self.is_reading() {
+            return Ok(None)
+        }
+        match Opcode::from_u16((datagram[0] as u16) << 8 | datagram[1] as u16) {
+            Some(Opcode::ERROR) => match ErrorPacket::decode(datagram) {
+                Some(error) => Err(Error::Server(error.into_owned())),
+                None => Ok(None),
+            },
+            Some(Opcode::OACK) if !self.replied => match OptionAckPacket::decode(datagram) {
+                Some(oack) => self.receive_oack(&oack, now),
+                None => Ok(None),
+            },
+            Some(Opcode::DATA) if self.is_reading() => match DataPacketOctet::decode(datagram) {
+                Some(data) => self.receive_data(&data, now),
+                None => Ok(None),
+            },
+            Some(Opcode::ACK) if !self.is_reading() => match AckPacket::decode(datagram) {
+                Some(ack) => self.receive_ack(&ack, now),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Handles the passing of time, returns a datagram to send again if the
+    /// retransmission timeout expired.
+    pub fn poll_timeout(&mut self, now: Instant) -> Result<Option<Vec<u8>>> {
+        match self.next_timeout {
+            Some(deadline) if now >= deadline => {}
+            _ => return Ok(None),
+        }
+        let timeout = match self.side {
+            Side::Read(ref mut transfer, _) => transfer.timeout(),
+            Side::Write(ref mut transfer, _) => transfer.timeout(),
+        };
+        match timeout {
+            transfer::Timeout::Retransmit => {
+                self.next_timeout = Some(now + self.timeout);
+                Ok(Some(self.last_sent.clone()))
+            }
+            // The reader of a put can't be read again from its start, no fallback is set.
+            transfer::Timeout::Fallback(_) => unreachable!(),
+            _ => {
+                self.next_timeout = None;
+                Err(Error::TimedOut)
+            }
+        }
+    }
+
+    /// Returns the time `poll_timeout` has to be called at, `None` when the
+    /// transfer is not waiting for the server.
+    pub fn next_timeout(&self) -> Option<Instant> {
+        self.next_timeout
+    }
+
+    /// Returns `true` when the transfer is complete.
+    ///
+    /// A completed read transfer still answers repeated last blocks, the server
+    /// sends them until it receives the final acknowledgment.
+    pub fn is_done(&self) -> bool {
+        match self.side {
+            Side::Read(ref transfer, _) => transfer.is_done(),
+            Side::Write(ref transfer, _) => transfer.is_done(),
+        }
+    }
+
+    /// Returns the parameters negotiated with the server, `None` until the
+    /// server responded.
+    pub fn params(&self) -> Option<TransferParams> {
+        if !self.replied {
+            return None
+        }
+        let block_size = match self.side {
+            Side::Read(ref transfer, _) => transfer.block_size(),
+            Side::Write(ref transfer, _) => transfer.block_size(),
+        };
+        Some(TransferParams::new(block_size, self.timeout))
+    }
+
+    fn is_reading(&self) -> bool {
+        match self.side {
+            Side::Read(..) => true,
+            Side::Write(..) => false,
+        }
+    }
+
+    /// Remembers a packet for retransmissions and returns it encoded.
+    fn sent<P: EncodePacket>(&mut self, packet: &P, now: Instant) -> Vec<u8> {
+        self.sent_datagram(packet.encode().packet_buf().to_vec(), now)
+    }
+
+    fn sent_datagram(&mut self, datagram: Vec<u8>, now: Instant) -> Vec<u8> {
+        self.last_sent = datagram.clone();
+        self.next_timeout = if self.is_done() { None } else { Some(now + self.timeout) };
+        datagram
+    }
+
+    fn receive_oack(&mut self, oack: &OptionAckPacket, now: Instant) -> Result<Option<Vec<u8>>> {
+        self.replied = true;
+        let block_size = match negotiated_block_size(self.block_size.get(), oack) {
+            Ok(block_size) => block_size,
+            Err(reason) => {
+                self.next_timeout = None;
+                let reply = ReasonFormat::default().error_packet(packet::Error::OptionNegotiation,
+                                                                 Reason::OptionNegotiation, reason).encode();
+                return Err(Error::Protocol(reason, reply.packet_buf().to_vec()))
+            }
+        };
+        let retries = self.retries;
+        let result = match self.side {
+            Side::Read(ref mut transfer, _) => {
+                *transfer = ReadTransfer::new(block_size);
+                transfer.set_retries(retries);
+                Ok(None)
+            }
+            // Option acknowledgment replaces the acknowledgment of block 0.
+            Side::Write(ref mut transfer, ref mut reader) => {
+                transfer.restart(block_size);
+                transfer.next_block(reader).map(|data| Some(data.encode().packet_buf().to_vec()))
+            }
+        };
+        match result {
+            Ok(Some(data)) => Ok(Some(self.sent_datagram(data, now))),
+            Ok(None) => Ok(Some(self.sent(&AckPacket::new(0), now))),
+            Err(e) => Err(self.local_error(e)),
+        }
+    }
+
+    fn receive_data(&mut self, data: &DataPacketOctet, now: Instant) -> Result<Option<Vec<u8>>> {
+        self.replied = true;
+        let written = match self.side {
+            Side::Read(ref mut transfer, ref mut writer) => match transfer.receive_data(data) {
+                DataReceived::Accepted(ack) => writer.write_all(data.data()).map(|()| Some(ack)),
+                // The data was written already, only the acknowledgment got lost.
+                DataReceived::Duplicate(ack) => return Ok(Some(ack.encode().packet_buf().to_vec())),
+                DataReceived::Ignored => return Ok(None),
+            },
+            Side::Write(..) => return Ok(None),
+        };
+        match written {
+            Ok(Some(ack)) => Ok(Some(self.sent(&ack, now))),
+            Ok(None) => Ok(None),
+            Err(e) => Err(self.local_error(e)),
+        }
+    }
+
+    fn receive_ack(&mut self, ack: &AckPacket, now: Instant) -> Result<Option<Vec<u8>>> {
+        self.replied = true;
+        let result = match self.side {
+            Side::Write(ref mut transfer, ref mut reader) => match transfer.receive_ack(ack) {
+                AckReceived::Next => transfer.next_block(reader).map(|data| Some(data.encode().packet_buf().to_vec())),
+                AckReceived::Done => Ok(None),
+                AckReceived::Ignored => return Ok(None),
+                // No window is requested, each event returns at most one datagram
+                // to send. A single block in flight is acknowledged as a whole.
+                AckReceived::Rewind(_) => unreachable!(),
+            },
+            Side::Read(..) => return Ok(None),
+        };
+        match result {
+            Ok(Some(data)) => Ok(Some(self.sent_datagram(data, now))),
+            Ok(None) => {
+                self.next_timeout = None;
+                Ok(None)
+            }
+            Err(e) => Err(self.local_error(e)),
+        }
+    }
+
+    /// Fails the transfer because the local writer or reader failed.
+    fn local_error(&mut self, err: io::Error) -> Error {
+        self.next_timeout = None;
+        let reply = ReasonFormat::default().error_packet(packet::Error::Undefined, Reason::LocalError,
+                                                         &err.to_string()).encode();
+        Error::Io(err, reply.packet_buf().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::time::{Duration, Instant};
+
+    use config::{BlockSize, Retries};
+    use packet::{self, Mode, RequestPacket, DataPacketOctet, AckPacket, ErrorPacket, OptionAckPacket,
+        TransferOptions, EncodePacket, DecodePacket, BLKSIZE_OPTION};
+    use super::{Error, Transfer};
+
+    fn encode<P: EncodePacket>(packet: &P) -> Vec<u8> {
+        packet.encode().packet_buf().to_vec()
+    }
+
+    #[test]
+    fn get_acknowledges_every_block() {
+        let now = Instant::now();
+        let mut received = Vec::new();
+        {
+            let mut transfer = Transfer::get("file", Mode::Octet, &mut received);
+            let request = transfer.start(now);
+            assert_eq!(Some("file"), RequestPacket::decode(&request).unwrap().filename().as_ref().map(|f| &f[..]));
+            let first = encode(&DataPacketOctet::from_slice(1, &[1; 512]));
+            assert_eq!(Some(encode(&AckPacket::new(1))), transfer.handle_datagram(&first, now).unwrap());
+            // Repeated block is acknowledged again but not written.
+            assert_eq!(Some(encode(&AckPacket::new(1))), transfer.handle_datagram(&first, now).unwrap());
+            let last = encode(&DataPacketOctet::from_slice(2, b"end"));
+            assert_eq!(Some(encode(&AckPacket::new(2))), transfer.handle_datagram(&last, now).unwrap());
+            assert!(transfer.is_done());
+            assert_eq!(None, transfer.next_timeout());
+            assert_eq!(512, transfer.params().unwrap().block_size);
+        }
+        assert_eq!(515, received.len());
+    }
+
+    #[test]
+    fn last_datagram_is_retransmitted_on_timeout() {
+        let now = Instant::now();
+        let mut received = Vec::new();
+        let mut transfer = Transfer::get("file", Mode::Octet, &mut received)
+            .timeout(Duration::from_secs(1))
+            .retries(Retries::new(1).unwrap());
+        let request = transfer.start(now);
+        assert_eq!(Some(now + Duration::from_secs(1)), transfer.next_timeout());
+        assert_eq!(None, transfer.poll_timeout(now).unwrap());
+        let later = now + Duration::from_secs(1);
+        assert_eq!(Some(request), transfer.poll_timeout(later).unwrap());
+        match transfer.poll_timeout(later + Duration::from_secs(1)) {
+            Err(Error::TimedOut) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn put_sends_blocks_of_negotiated_size() {
+        let now = Instant::now();
+        let mut reader = Cursor::new(b"0123456789".to_vec());
+        let mut transfer = Transfer::put("file", Mode::Octet, &mut reader).block_size(BlockSize::new(8).unwrap());
+        transfer.start(now);
+        let mut options = TransferOptions::new();
+        options.insert(BLKSIZE_OPTION, "8");
+        let oack = encode(&OptionAckPacket::new(options));
+        let first = transfer.handle_datagram(&oack, now).unwrap().unwrap();
+        assert_eq!(b"01234567", DataPacketOctet::decode(&first).unwrap().data());
+        let second = transfer.handle_datagram(&encode(&AckPacket::new(1)), now).unwrap().unwrap();
+        assert_eq!(b"89", DataPacketOctet::decode(&second).unwrap().data());
+        assert_eq!(None, transfer.handle_datagram(&encode(&AckPacket::new(2)), now).unwrap());
+        assert!(transfer.is_done());
+    }
+
+    #[test]
+    fn server_error_fails_transfer() {
+        let now = Instant::now();
+        let mut received = Vec::new();
+        let mut transfer = Transfer::get("file", Mode::Octet, &mut received);
+        transfer.start(now);
+        let error = encode(&ErrorPacket::new(packet::Error::FileNotFound, "missing"));
+        match transfer.handle_datagram(&error, now) {
+            Err(ref e @ Error::Server(_)) => assert_eq!(None, e.reply()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
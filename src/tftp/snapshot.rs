@@ -0,0 +1,281 @@
+//! Snapshots of running read transfers of the server (experimental).
+//!
+//! A server restarted for an upgrade or after a crash loses all transfers, and
+//! clients that were booting from it wait for their timeouts and start over,
+//! if they retry at all. With a snapshot file configured, see
+//! `ServerBuilder::session_file`, the server periodically records the state of
+//! its read transfers: the client, the address of the transfer socket, the
+//! file, the negotiated block size and how many blocks the client
+//! acknowledged. A server started shortly after binds the same transfer
+//! sockets again and continues with the next block, the clients don't notice
+//! more than a lost packet.
+//!
+//! The file is a line per transfer after a header with the time it was saved:
+//!
+//! ```text
+//! tftp-snapshot 1 1760612400
+//! 10.0.0.7:2044 10.0.0.1:50112 octet 1428 312 pxelinux.0
+//! ```
+//!
+//! File names are percent-encoded, see `FilenameCodec::Percent`. Transfers
+//! are only resumed if the file didn't change in between, the server can't
+//! check that.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use filename::FilenameCodec;
+use packet::Mode;
+
+/// Interval the server saves the snapshot in while transfers change.
+pub const SAVE_INTERVAL: Duration = Duration::from_millis(500);
+
+const HEADER: &'static str = "tftp-snapshot 1";
+
+/// State of a read transfer recorded in a snapshot.
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Entry {
+    /// Address of the client.
+    pub client: SocketAddr,
+
+    /// Address of the transfer socket of the server, the transfer identifier
+    /// the client knows the server by.
+    pub local: SocketAddr,
+
+    /// Requested file.
+    pub filename: String,
+
+    /// Requested transfer mode.
+    pub mode: Mode,
+
+    /// Negotiated block size.
+    pub block_size: usize,
+
+    /// Number of blocks the client acknowledged.
+    pub acked_blocks: u64,
+}
+
+impl Entry {
+    fn encode(&self) -> String {
+        let filename = FilenameCodec::Percent.encode(&self.filename).unwrap_or_default();
+        format!("{} {} {} {} {} {}", self.client, self.local, self.mode.as_str(), self.block_size, self.acked_blocks,
+                String::from_utf8_lossy(&filename))
+    }
+
+    fn decode(line: &str) -> Option<Entry> {
+        let fields: Vec<&str> = line.splitn(6, ' ').collect();
+        if fields.len() != 6 {
+            return None
+        }
+        match (fields[0].parse(), fields[1].parse(), fields[2].parse(), fields[3].parse(), fields[4].parse(),
+               FilenameCodec::Percent.decode(fields[5].as_bytes())) {
+            (Ok(client), Ok(local), Ok(mode), Ok(block_size), Ok(acked_blocks), Some(filename)) => Some(Entry {
+                client: client,
+                local: local,
+                filename: filename,
+                mode: mode,
+                block_size: block_size,
+                acked_blocks: acked_blocks,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Writes `entries` to the snapshot file at `path`.
+///
+/// The snapshot is written next to it first and then renamed, a server
+/// stopped in the middle leaves the previous snapshot intact.
+pub fn save<'a, I: IntoIterator<Item = &'a Entry>>(path: &Path, entries: I) -> io::Result<()> {
+    let saved = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let mut contents = format!("{} {}\n", HEADER, saved.as_secs());
+    for entry in entries {
+        contents.push_str(&entry.encode());
+        contents.push('\n');
+    }
+    let temporary = temporary_path(path);
+    {
+        let mut file = try!(File::create(&temporary));
+        try!(file.write_all(contents.as_bytes()));
+        try!(file.sync_all());
+    }
+    fs::rename(&temporary, path)
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    PathBuf::from(temporary)
+}
+
+/// Reads the snapshot file at `path`.
+///
+/// Snapshots saved more than `max_age` ago are ignored, the clients gave up on
+/// their transfers by now. A missing file is an empty snapshot, lines that
+/// can't be parsed are skipped.
+pub fn load(path: &Path, max_age: Duration) -> io::Result<Vec<Entry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let header = match lines.next() {
+        Some(header) => try!(header),
+        None => return Ok(Vec::new()),
+    };
+    let saved = if header.starts_with(HEADER) {
+        header[HEADER.len()..].trim().parse().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+    } else {
+        None
+    };
+    let saved = match saved {
+        Some(saved) => saved,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "not a transfer snapshot")),
+    };
+    match SystemTime::now().duration_since(saved) {
+        Ok(age) if age <= max_age => {}
+        // Saved in the future, the clock was changed.
+        Err(_) => {}
+        Ok(_) => return Ok(Vec::new()),
+    }
+    let mut entries = Vec::new();
+    for line in lines {
+        if let Some(entry) = Entry::decode(&try!(line)) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Running transfers recorded in the snapshot file of a server.
+#[derive(Debug)]
+pub(crate) struct Registry {
+    path: PathBuf,
+    entries: RefCell<HashMap<u64, Entry>>,
+    next_id: Cell<u64>,
+    changed: Cell<bool>,
+}
+
+impl Registry {
+    pub(crate) fn new(path: PathBuf) -> Registry {
+        Registry {
+            path: path,
+            entries: RefCell::new(HashMap::new()),
+            next_id: Cell::new(0),
+            changed: Cell::new(true),
+        }
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Starts recording the transfer of `entry`, the transfer is removed once
+    /// the returned handle is dropped.
+    pub(crate) fn track(registry: &Rc<Registry>, entry: Entry) -> Tracked {
+        let id = registry.next_id.get();
+        registry.next_id.set(id + 1);
+        let tracked = Tracked {
+            registry: registry.clone(),
+            id: id,
+            entry: entry,
+        };
+        tracked.record();
+        tracked
+    }
+
+    /// Saves the snapshot if transfers changed since it was saved last.
+    pub(crate) fn save(&self) -> io::Result<()> {
+        if !self.changed.get() {
+            return Ok(())
+        }
+        try!(save(&self.path, self.entries.borrow().values()));
+        self.changed.set(false);
+        Ok(())
+    }
+}
+
+/// Transfer recorded in a `Registry`.
+#[derive(Debug)]
+pub(crate) struct Tracked {
+    registry: Rc<Registry>,
+    id: u64,
+    entry: Entry,
+}
+
+impl Tracked {
+    /// Records that the client acknowledged `acked_blocks` blocks.
+    pub(crate) fn update(&mut self, acked_blocks: u64) {
+        self.entry.acked_blocks = acked_blocks;
+        self.record();
+    }
+
+    fn record(&self) {
+        // Until the first block is acknowledged the client may still wait for
+        // the option acknowledgment, the transfer can't be resumed.
+        if self.entry.acked_blocks == 0 {
+            return
+        }
+        let mut entries = self.registry.entries.borrow_mut();
+        match entries.get_mut(&self.id) {
+            Some(entry) => entry.acked_blocks = self.entry.acked_blocks,
+            None => { entries.insert(self.id, self.entry.clone()); }
+        }
+        self.registry.changed.set(true);
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if self.registry.entries.borrow_mut().remove(&self.id).is_some() {
+            self.registry.changed.set(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use packet::Mode;
+
+    use super::{Entry, Registry, load};
+
+    #[test]
+    fn transfers_are_saved_and_loaded() {
+        let path = env::temp_dir().join(format!("tftp-snapshot-test-{}", process::id()));
+        let registry = Rc::new(Registry::new(path.clone()));
+        let entry = Entry {
+            client: "10.0.0.7:2044".parse().unwrap(),
+            local: "10.0.0.1:50112".parse().unwrap(),
+            filename: "boot images/kernel 1%\n".to_owned(),
+            mode: Mode::Octet,
+            block_size: 1428,
+            acked_blocks: 0,
+        };
+        let mut tracked = Registry::track(&registry, entry.clone());
+        let finished = Registry::track(&registry, entry.clone());
+        tracked.update(312);
+        registry.save().unwrap();
+        drop(finished);
+
+        let loaded = load(&path, Duration::from_secs(60)).unwrap();
+        assert_eq!(vec![Entry { acked_blocks: 312, ..entry }], loaded);
+
+        drop(tracked);
+        registry.save().unwrap();
+        assert!(load(&path, Duration::from_secs(60)).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
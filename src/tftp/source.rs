@@ -0,0 +1,299 @@
+//! Data sources of the sending side of a transfer.
+//!
+//! `WriteTransfer` reads each block at its offset in the data, so a block can
+//! be read again when the window is rewound or the transfer restarts with a
+//! smaller block size. Files, slices and cursors support reading at any
+//! offset. Other readers are wrapped in a `ReadSource` which keeps the blocks
+//! of the last window and fails when an earlier one is needed again.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+use config::WindowSize;
+
+/// Data sent by the sending side of a transfer.
+pub trait BlockSource {
+    /// Reads the data starting at `offset` into `buf`.
+    ///
+    /// `buf` is filled unless the data ends before, a shorter read is the end
+    /// of the data.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Returns the size of the data in bytes, if it is known up front.
+    fn size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the number of blocks read again from a cache of the source
+    /// instead of the data, 0 for sources reading any offset.
+    fn cache_hits(&self) -> u64 {
+        0
+    }
+}
+
+impl<'a, S: BlockSource + ?Sized> BlockSource for &'a mut S {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_at(offset, buf)
+    }
+
+    fn size(&self) -> Option<u64> {
+        (**self).size()
+    }
+
+    fn cache_hits(&self) -> u64 {
+        (**self).cache_hits()
+    }
+}
+
+impl<'a> BlockSource for &'a [u8] {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let start = cmp::min(offset, self.len() as u64) as usize;
+        let len = cmp::min(buf.len(), self.len() - start);
+        buf[..len].copy_from_slice(&self[start..start + len]);
+        Ok(len)
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.len() as u64)
+    }
+}
+
+/// The whole buffer of the cursor is sent, regardless of its position.
+impl<T: AsRef<[u8]>> BlockSource for Cursor<T> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.get_ref().as_ref().read_at(offset, buf)
+    }
+
+    fn size(&self) -> Option<u64> {
+        Some(self.get_ref().as_ref().len() as u64)
+    }
+}
+
+impl BlockSource for File {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.seek(SeekFrom::Start(offset)));
+        read_full(self, buf)
+    }
+
+    fn size(&self) -> Option<u64> {
+        self.metadata().ok().map(|metadata| metadata.len())
+    }
+}
+
+/// Reads into `buf` until it is full or the reader ends.
+fn read_full<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+/// Source reading a sequential reader, e.g. a pipe or a reader of a handler.
+///
+/// The blocks of the last window are kept, they are read again from the
+/// cache when the window is rewound or a block is retransmitted. Reading
+/// before them fails. Data before the requested offset is skipped, which
+/// positions the reader of a resumed transfer. Transfers count the blocks
+/// read again in `ProtocolStats::cache_hits`.
+///
+/// A read that would block returns the `WouldBlock` error and keeps the data
+/// read so far, the same block should be read again once the reader is ready.
+/// This makes the source usable with non-blocking readers.
+#[derive(Debug)]
+pub struct ReadSource<R> {
+    reader: R,
+    /// Offset of the next byte of the reader.
+    position: u64,
+    /// Bytes read last, they end at `position`.
+    cache: Vec<u8>,
+    /// Number of blocks kept in the cache.
+    window_size: usize,
+    /// Reader returned the end of the data.
+    end: bool,
+    cache_hits: u64,
+}
+
+impl<R: Read> ReadSource<R> {
+    /// Creates a source reading `reader` from its current position, keeping
+    /// only the last block.
+    pub fn new(reader: R) -> ReadSource<R> {
+        ReadSource::with_window_size(reader, WindowSize::default())
+    }
+
+    /// Creates a source keeping the blocks of the last window, for a transfer
+    /// using the negotiated `window_size`.
+    pub fn with_window_size(reader: R, window_size: WindowSize) -> ReadSource<R> {
+        ReadSource {
+            reader: reader,
+            position: 0,
+            cache: Vec::new(),
+            window_size: window_size.get() as usize,
+            end: false,
+            cache_hits: 0,
+        }
+    }
+
+    /// Returns the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads from the reader into the cache until it holds `len` bytes or the reader ends.
+    fn fill(&mut self, len: usize) -> io::Result<()> {
+        while self.cache.len() < len && !self.end {
+            let filled = self.cache.len();
+            self.cache.resize(len, 0);
+            match self.reader.read(&mut self.cache[filled..]) {
+                Ok(0) => {
+                    self.cache.truncate(filled);
+                    self.end = true;
+                }
+                Ok(n) => {
+                    self.cache.truncate(filled + n);
+                    self.position += n as u64;
+                }
+                Err(e) => {
+                    self.cache.truncate(filled);
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e)
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R> ReadSource<R> {
+    /// Returns the number of blocks read again from the cache instead of the reader.
+    pub fn cache_hits(&self) -> u64 {
+        self.cache_hits
+    }
+}
+
+impl<R: Read> BlockSource for ReadSource<R> {
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset < self.position - self.cache.len() as u64 {
+            return Err(io::Error::new(io::ErrorKind::Other, "reader can't be rewound to an earlier block"))
+        }
+        while self.position < offset {
+            let len = cmp::min(offset - self.position, buf.len() as u64) as usize;
+            self.cache.clear();
+            try!(self.fill(len));
+            if self.end && self.position < offset {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "data ends before the requested block"))
+            }
+        }
+        if offset < self.position && (self.end || self.position - offset >= buf.len() as u64) {
+            self.cache_hits += 1;
+        }
+        let skipped = (offset - (self.position - self.cache.len() as u64)) as usize;
+        try!(self.fill(skipped + buf.len()));
+        let len = cmp::min(buf.len(), self.cache.len() - skipped);
+        buf[..len].copy_from_slice(&self.cache[skipped..skipped + len]);
+        // Blocks before the window ending with this one aren't needed anymore.
+        let kept = (self.window_size - 1) * buf.len();
+        self.cache.drain(..skipped.saturating_sub(kept));
+        Ok(len)
+    }
+
+    fn cache_hits(&self) -> u64 {
+        self.cache_hits
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Read};
+
+    use config::WindowSize;
+    use packet::AckPacket;
+    use transfer::{AckReceived, WriteTransfer};
+    use super::{BlockSource, ReadSource};
+
+    /// Reader returning at most 3 bytes per read and blocking every other read.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        block: bool,
+    }
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.block = !self.block;
+            if self.block {
+                return Err(io::ErrorKind::WouldBlock.into())
+            }
+            let len = buf.len().min(3).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn read_until_ready<S: BlockSource>(source: &mut S, offset: u64, buf: &mut [u8]) -> usize {
+        loop {
+            match source.read_at(offset, buf) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result.unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn random_access_sources_read_at_any_offset() {
+        let data = b"0123456789";
+        let mut buf = [0; 4];
+        let mut cursor = Cursor::new(&data[..]);
+        assert_eq!(4, cursor.read_at(4, &mut buf).unwrap());
+        assert_eq!(b"4567", &buf);
+        assert_eq!(2, cursor.read_at(8, &mut buf).unwrap());
+        assert_eq!(b"89", &buf[..2]);
+        assert_eq!(0, (&data[..]).read_at(12, &mut buf).unwrap());
+        assert_eq!(Some(10), cursor.size());
+    }
+
+    #[test]
+    fn read_source_repeats_last_block_and_skips_forward() {
+        let data = b"abcdefghijklmnopqrstuvwxyz";
+        let mut source = ReadSource::new(Trickle { data: data, block: false });
+        let mut buf = [0; 8];
+        assert_eq!(8, read_until_ready(&mut source, 0, &mut buf));
+        assert_eq!(b"abcdefgh", &buf);
+        // Retransmission of the last block.
+        assert_eq!(8, read_until_ready(&mut source, 0, &mut buf));
+        assert_eq!(b"abcdefgh", &buf);
+        // Resumed transfer skipping a block.
+        assert_eq!(8, read_until_ready(&mut source, 16, &mut buf));
+        assert_eq!(b"qrstuvwx", &buf);
+        assert_eq!(2, read_until_ready(&mut source, 24, &mut buf));
+        assert_eq!(b"yz", &buf[..2]);
+        assert!(source.read_at(8, &mut buf).is_err());
+        assert_eq!(None, source.size());
+        assert_eq!(1, source.cache_hits());
+    }
+
+    #[test]
+    fn read_source_keeps_last_window_for_rewinds() {
+        let data = b"aaaabbbbccccddddee";
+        let mut source = ReadSource::with_window_size(&data[..], WindowSize::new(3).unwrap());
+        let mut transfer = WriteTransfer::new(4);
+        transfer.set_window_size(WindowSize::new(3).unwrap(), false);
+        while transfer.can_send() {
+            transfer.next_block_from(&mut source).unwrap();
+        }
+        assert_eq!(AckReceived::Rewind(4), transfer.receive_ack(&AckPacket::new(1)));
+        assert_eq!(b"bbbb", transfer.next_block_from(&mut source).unwrap().data());
+        assert_eq!(b"cccc", transfer.next_block_from(&mut source).unwrap().data());
+        assert_eq!(b"dddd", transfer.next_block_from(&mut source).unwrap().data());
+        assert_eq!(2, source.cache_hits());
+        // Block 1 left the window.
+        assert!(source.read_at(0, &mut [0; 4]).is_err());
+    }
+}
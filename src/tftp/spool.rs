@@ -0,0 +1,289 @@
+//! Spool between a read transfer and a slow writer.
+//!
+//! The client acknowledges a block once the writer took it. A writer that
+//! stalls, e.g. on a slow disk or a full pipe, stops the acknowledgments and
+//! the server times the transfer out. With a `Spool` configured the transfer
+//! runs on another thread and writes the blocks into a bounded buffer, in
+//! memory or in a temporary file, while the thread calling the client feeds
+//! the writer from the buffer. The transfer keeps acknowledging as long as
+//! the buffer has room, a transfer getting further ahead of the writer than
+//! the spool holds fails with `Error::SpoolOverflow`.
+//!
+//! The progress of an interrupted transfer counts the bytes spooled. Spooled
+//! data is still written out after the transfer failed, unless the writer
+//! failed itself.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::panic;
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use client::{Error, Result};
+
+/// Bytes handed to the writer at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Numbers the temporary files of the spools of this process.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Storage of the spooled data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpoolStorage {
+    /// Data is kept in memory.
+    Memory,
+
+    /// Data is kept in a temporary file in `std::env::temp_dir`, removed once
+    /// the transfer is written out.
+    TempFile,
+}
+
+/// Bounded buffer between read transfers and their writers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Spool {
+    storage: SpoolStorage,
+    max_bytes: u64,
+}
+
+impl Spool {
+    /// Creates a spool keeping up to `max_bytes` bytes in memory.
+    pub fn memory(max_bytes: u64) -> Spool {
+        Spool {
+            storage: SpoolStorage::Memory,
+            max_bytes: max_bytes,
+        }
+    }
+
+    /// Creates a spool keeping up to `max_bytes` bytes in a temporary file.
+    pub fn temp_file(max_bytes: u64) -> Spool {
+        Spool {
+            storage: SpoolStorage::TempFile,
+            max_bytes: max_bytes,
+        }
+    }
+
+    /// Returns the storage of the spooled data.
+    pub fn storage(&self) -> SpoolStorage {
+        self.storage
+    }
+
+    /// Returns the most bytes the spool holds.
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+}
+
+/// Error of a transfer that wrote more than the spool holds, carried by the
+/// `io::Error` the spool returns to the transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Overflow(pub u64);
+
+impl Overflow {
+    /// Returns the limit of the spool if `err` is an overflow.
+    pub fn from_io_error(err: &io::Error) -> Option<u64> {
+        err.get_ref().and_then(|inner| inner.downcast_ref::<Overflow>()).map(|overflow| overflow.0)
+    }
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spool of {} bytes is full", self.0)
+    }
+}
+
+impl error::Error for Overflow {
+    fn description(&self) -> &str {
+        "spool is full"
+    }
+}
+
+/// Temporary file holding the spooled data between `read` and `written`.
+struct TempFile {
+    file: File,
+    path: PathBuf,
+    read: u64,
+    written: u64,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+enum Buffer {
+    Memory(VecDeque<u8>),
+    File(TempFile),
+}
+
+impl Buffer {
+    fn new(storage: SpoolStorage) -> io::Result<Buffer> {
+        match storage {
+            SpoolStorage::Memory => Ok(Buffer::Memory(VecDeque::new())),
+            SpoolStorage::TempFile => {
+                let path = env::temp_dir().join(format!("tftp-spool-{}-{}", process::id(),
+                                                        TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+                let file = try!(OpenOptions::new().read(true).write(true).create_new(true).open(&path));
+                Ok(Buffer::File(TempFile { file: file, path: path, read: 0, written: 0 }))
+            }
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        match *self {
+            Buffer::Memory(ref mut queue) => queue.extend(data),
+            Buffer::File(ref mut temp) => {
+                try!(temp.file.seek(SeekFrom::Start(temp.written)));
+                try!(temp.file.write_all(data));
+                temp.written += data.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves up to `buf.len()` spooled bytes into `buf`.
+    fn take(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Buffer::Memory(ref mut queue) => queue.read(buf),
+            Buffer::File(ref mut temp) => {
+                let len = cmp::min(temp.written - temp.read, buf.len() as u64) as usize;
+                try!(temp.file.seek(SeekFrom::Start(temp.read)));
+                try!(temp.file.read_exact(&mut buf[..len]));
+                temp.read += len as u64;
+                // The file starts over once it's written out.
+                if temp.read == temp.written {
+                    try!(temp.file.set_len(0));
+                    temp.read = 0;
+                    temp.written = 0;
+                }
+                Ok(len)
+            }
+        }
+    }
+}
+
+struct State {
+    buffer: Buffer,
+    /// Bytes spooled and not taken by the writer yet.
+    pending: u64,
+    /// The transfer ended, nothing more is spooled.
+    closed: bool,
+    /// Error of the writer, returned to the transfer by its next write.
+    failed: Option<(io::ErrorKind, String)>,
+}
+
+/// Buffer shared by the transfer and the writer.
+struct Shared {
+    state: Mutex<State>,
+    spooled: Condvar,
+    max_bytes: u64,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.spooled.notify_one();
+    }
+
+    /// Writes the spooled data out until the transfer ended and all of it
+    /// is written, counting the written bytes in `drained`.
+    fn drain(&self, writer: &mut io::Write, drained: &mut u64) -> io::Result<()> {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        loop {
+            let len = {
+                let mut state = self.lock();
+                while state.pending == 0 && !state.closed {
+                    state = self.spooled.wait(state).unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+                if state.pending == 0 {
+                    break
+                }
+                let len = try!(state.buffer.take(&mut chunk));
+                state.pending -= len as u64;
+                len
+            };
+            try!(writer.write_all(&chunk[..len]));
+            *drained += len as u64;
+        }
+        writer.flush()
+    }
+
+    fn fail(&self, err: &io::Error) {
+        self.lock().failed = Some((err.kind(), err.to_string()));
+    }
+}
+
+/// Writer of the transfer, spooling the data.
+struct Input<'a>(&'a Shared);
+
+impl<'a> Write for Input<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.lock();
+        if let Some((kind, ref message)) = state.failed {
+            return Err(io::Error::new(kind, message.clone()))
+        }
+        if state.pending + buf.len() as u64 > self.0.max_bytes {
+            return Err(io::Error::new(io::ErrorKind::Other, Overflow(self.0.max_bytes)))
+        }
+        try!(state.buffer.push(buf));
+        state.pending += buf.len() as u64;
+        self.0.spooled.notify_one();
+        Ok(buf.len())
+    }
+
+    /// The writer is flushed once the spool is written out.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `transfer` on another thread writing into `spool`, while `writer`
+/// is fed from the spool on this one.
+///
+/// A failure of the writer is returned as `Error::Write` with the bytes
+/// written before, whatever error it caused the transfer.
+pub(crate) fn run<T, F>(spool: &Spool, writer: &mut io::Write, transfer: F) -> Result<T>
+    where F: FnOnce(&mut io::Write) -> Result<T> + Send,
+          T: Send,
+{
+    let shared = Shared {
+        state: Mutex::new(State {
+            buffer: try!(Buffer::new(spool.storage)),
+            pending: 0,
+            closed: false,
+            failed: None,
+        }),
+        spooled: Condvar::new(),
+        max_bytes: spool.max_bytes,
+    };
+    thread::scope(|scope| {
+        let running = scope.spawn(|| {
+            let result = transfer(&mut Input(&shared));
+            shared.close();
+            result
+        });
+        let mut written = 0;
+        let drained = shared.drain(writer, &mut written);
+        if let Err(ref err) = drained {
+            // The transfer fails with the error once it spools again.
+            shared.fail(err);
+        }
+        let result = running.join().unwrap_or_else(|panicked| panic::resume_unwind(panicked));
+        match drained {
+            Ok(()) => result,
+            Err(err) => Err(Error::Write(err, written)),
+        }
+    })
+}
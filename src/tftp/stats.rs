@@ -0,0 +1,508 @@
+//! Counters of transfers for operators.
+//!
+//! Socket counters describe the datagrams a transfer exchanged, protocol
+//! counters what the transfer did with them. Many retransmissions with few
+//! received datagrams point at a lossy network, many discarded or unexpected
+//! packets at a misbehaving peer.
+//!
+//! The client also records the time the server took to respond to each
+//! packet in a `ResponseTimes` histogram. Response times close to the network
+//! round trip with many retransmissions point at the network, long response
+//! times without them at a slow server.
+//!
+//! `ConformanceStats` record the `Conformance` transfers ran with and the
+//! deviations from the RFCs they tolerated or refused, so the counters of a
+//! strict lab and a lenient deployment can be told apart.
+//!
+//! The server records the high-water marks of the arenas of its transfers in
+//! `ArenaStats`, the memory transfers take with the block sizes clients use.
+//!
+//! The client and the server add the counters of every transfer to a `Stats`
+//! collector once the transfer ended, successfully or not. Clones of a
+//! collector share the counters, so one collector can be handed to several
+//! clients and read from another thread while transfers run.
+
+use std::cmp;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use config::Conformance;
+
+/// Counters of the datagrams sent and received by transfer sockets.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct SocketStats {
+    /// Datagrams handed to the socket.
+    pub datagrams_sent: u64,
+
+    /// Datagrams received, including the discarded ones.
+    pub datagrams_received: u64,
+
+    /// Sends that failed, sends that would block are not counted.
+    pub send_errors: u64,
+
+    /// Datagrams discarded because they came from a host or port that is not
+    /// the peer of the transfer.
+    pub wrong_tid_discarded: u64,
+
+    /// Unknown transfer ID errors answering such datagrams that were not sent
+    /// because their host exceeded the `RateLimit`.
+    pub wrong_tid_suppressed: u64,
+}
+
+impl SocketStats {
+    fn add(&mut self, other: &SocketStats) {
+        self.datagrams_sent += other.datagrams_sent;
+        self.datagrams_received += other.datagrams_received;
+        self.send_errors += other.send_errors;
+        self.wrong_tid_discarded += other.wrong_tid_discarded;
+        self.wrong_tid_suppressed += other.wrong_tid_suppressed;
+    }
+}
+
+/// Counters of protocol events of transfers.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct ProtocolStats {
+    /// Packets sent again after the retransmission timeout expired.
+    pub retransmissions: u64,
+
+    /// Data blocks received again and acknowledgments of blocks that were
+    /// already acknowledged.
+    pub duplicates: u64,
+
+    /// Packets from the peer that could not be decoded or must not be sent in
+    /// the state of the transfer.
+    pub unexpected_packets: u64,
+
+    /// Blocks sent again to keep a client waiting while the server's handler
+    /// was slow to produce the next block.
+    pub keepalives: u64,
+
+    /// Requests sent again without options after the server rejected the
+    /// options with an option negotiation error, counted by the client only.
+    pub option_fallbacks: u64,
+
+    /// Uploads restarted with a smaller block size after the blocks of the
+    /// negotiated size timed out repeatedly, counted by the client only.
+    pub block_size_fallbacks: u64,
+
+    /// Data blocks sent again from the blocks of the last window a sender
+    /// keeps of a sequential reader, instead of reading them again, see
+    /// `source::ReadSource`.
+    pub cache_hits: u64,
+}
+
+impl ProtocolStats {
+    fn add(&mut self, other: &ProtocolStats) {
+        self.retransmissions += other.retransmissions;
+        self.duplicates += other.duplicates;
+        self.unexpected_packets += other.unexpected_packets;
+        self.keepalives += other.keepalives;
+        self.option_fallbacks += other.option_fallbacks;
+        self.block_size_fallbacks += other.block_size_fallbacks;
+        self.cache_hits += other.cache_hits;
+    }
+}
+
+/// Counters of the conformance of transfers.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct ConformanceStats {
+    /// Transfers that ran with strict conformance.
+    pub strict_transfers: u64,
+
+    /// Transfers that ran with lenient conformance.
+    pub lenient_transfers: u64,
+
+    /// Deviations of peers lenient transfers tolerated.
+    pub tolerated: u64,
+
+    /// Deviations of peers strict transfers refused.
+    pub refused: u64,
+}
+
+impl ConformanceStats {
+    fn add(&mut self, other: &ConformanceStats) {
+        self.strict_transfers += other.strict_transfers;
+        self.lenient_transfers += other.lenient_transfers;
+        self.tolerated += other.tolerated;
+        self.refused += other.refused;
+    }
+}
+
+/// High-water marks of the arenas of server transfers.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct ArenaStats {
+    /// Transfers that allocated their buffers from an arena.
+    pub transfers: u64,
+
+    /// Highest high-water mark of an arena in bytes.
+    pub max_high_water: u64,
+
+    /// Sum of the high-water marks in bytes.
+    pub total_high_water: u64,
+}
+
+impl ArenaStats {
+    /// Returns the mean high-water mark of an arena in bytes.
+    pub fn mean_high_water(&self) -> Option<u64> {
+        if self.transfers == 0 { None } else { Some(self.total_high_water / self.transfers) }
+    }
+
+    fn add(&mut self, other: &ArenaStats) {
+        self.transfers += other.transfers;
+        self.max_high_water = cmp::max(self.max_high_water, other.max_high_water);
+        self.total_high_water += other.total_high_water;
+    }
+}
+
+/// Number of buckets of `ResponseTimes`.
+const RESPONSE_TIME_BUCKETS: usize = 32;
+
+/// Histogram of the times the peer took to respond to packets.
+///
+/// A response time is measured from sending a packet to receiving the packet
+/// answering it, e.g. from an acknowledgment to the next data block of a
+/// download. Packets that were retransmitted are not measured, the response
+/// can't be matched to one of the copies. Buckets are a power of two
+/// microseconds wide, so percentiles are estimates within a factor of two.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct ResponseTimes {
+    count: u64,
+    /// Sum of the response times in microseconds.
+    total: u64,
+    min: u64,
+    max: u64,
+    /// Bucket `i` counts the times below `2^(i + 1)` microseconds, the last
+    /// one all longer times.
+    buckets: [u64; RESPONSE_TIME_BUCKETS],
+}
+
+impl ResponseTimes {
+    /// Returns the number of responses measured.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the shortest response time.
+    pub fn min(&self) -> Option<Duration> {
+        self.time(self.min)
+    }
+
+    /// Returns the mean response time.
+    pub fn mean(&self) -> Option<Duration> {
+        self.time(self.total / cmp::max(self.count, 1))
+    }
+
+    /// Returns the longest response time.
+    pub fn max(&self) -> Option<Duration> {
+        self.time(self.max)
+    }
+
+    /// Returns an estimate of the response time `fraction` of the responses
+    /// were faster than, e.g. `0.95` for the 95th percentile.
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        let rank = cmp::max((fraction * self.count as f64).ceil() as u64, 1);
+        let mut counted = 0;
+        for (i, &count) in self.buckets.iter().enumerate() {
+            counted += count;
+            if counted >= rank {
+                return self.time(cmp::max(cmp::min(bucket_limit(i), self.max), self.min))
+            }
+        }
+        None
+    }
+
+    /// Returns the estimated 95th percentile of the response times.
+    pub fn p95(&self) -> Option<Duration> {
+        self.percentile(0.95)
+    }
+
+    /// Returns the upper limit and the count of the non-empty buckets, e.g.
+    /// to export the histogram to a monitoring system.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        self.buckets.iter().enumerate().filter(|&(_, &count)| count > 0)
+            .map(|(i, &count)| (Duration::from_micros(bucket_limit(i)), count))
+            .collect()
+    }
+
+    fn record(&mut self, time: Duration) {
+        let micros = time.as_secs().saturating_mul(1_000_000).saturating_add(time.subsec_micros() as u64);
+        self.min = if self.count == 0 { micros } else { cmp::min(self.min, micros) };
+        self.max = cmp::max(self.max, micros);
+        self.count += 1;
+        self.total = self.total.saturating_add(micros);
+        let bucket = (64 - (micros | 1).leading_zeros() as usize) - 1;
+        self.buckets[cmp::min(bucket, RESPONSE_TIME_BUCKETS - 1)] += 1;
+    }
+
+    fn add(&mut self, other: &ResponseTimes) {
+        if other.count == 0 {
+            return
+        }
+        self.min = if self.count == 0 { other.min } else { cmp::min(self.min, other.min) };
+        self.max = cmp::max(self.max, other.max);
+        self.count += other.count;
+        self.total = self.total.saturating_add(other.total);
+        for (bucket, other) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += *other;
+        }
+    }
+
+    fn time(&self, micros: u64) -> Option<Duration> {
+        if self.count == 0 { None } else { Some(Duration::from_micros(micros)) }
+    }
+}
+
+/// Returns the upper limit of bucket `i` in microseconds.
+fn bucket_limit(i: usize) -> u64 {
+    if i == RESPONSE_TIME_BUCKETS - 1 { u64::max_value() } else { 1 << (i + 1) }
+}
+
+/// Socket and protocol counters of one or more transfers.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct TransferStats {
+    /// Counters of the transfer sockets.
+    pub socket: SocketStats,
+
+    /// Counters of protocol events.
+    pub protocol: ProtocolStats,
+
+    /// Times the server took to respond, measured by the client only.
+    pub response_times: ResponseTimes,
+
+    /// Conformance of the transfers.
+    pub conformance: ConformanceStats,
+
+    /// Arenas of the transfers, recorded by the server only.
+    pub arena: ArenaStats,
+}
+
+impl TransferStats {
+    /// Adds the counters of `other` to these.
+    pub fn add(&mut self, other: &TransferStats) {
+        self.socket.add(&other.socket);
+        self.protocol.add(&other.protocol);
+        self.response_times.add(&other.response_times);
+        self.conformance.add(&other.conformance);
+        self.arena.add(&other.arena);
+    }
+}
+
+impl fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "sent {}, received {}, send errors {}, wrong tid {}, retransmissions {}, duplicates {}, \
+                        unexpected {}, keepalives {}, option fallbacks {}",
+                    self.socket.datagrams_sent, self.socket.datagrams_received, self.socket.send_errors,
+                    self.socket.wrong_tid_discarded, self.protocol.retransmissions, self.protocol.duplicates,
+                    self.protocol.unexpected_packets, self.protocol.keepalives, self.protocol.option_fallbacks));
+        if self.socket.wrong_tid_suppressed > 0 {
+            try!(write!(f, ", wrong tid errors suppressed {}", self.socket.wrong_tid_suppressed));
+        }
+        if self.protocol.block_size_fallbacks > 0 {
+            try!(write!(f, ", block size fallbacks {}", self.protocol.block_size_fallbacks));
+        }
+        if self.protocol.cache_hits > 0 {
+            try!(write!(f, ", cache hits {}", self.protocol.cache_hits));
+        }
+        let conformance = &self.conformance;
+        if conformance.strict_transfers > 0 || conformance.lenient_transfers > 0 {
+            try!(write!(f, ", strict {}, lenient {}, tolerated {}, refused {}", conformance.strict_transfers,
+                        conformance.lenient_transfers, conformance.tolerated, conformance.refused));
+        }
+        if let Some(mean) = self.arena.mean_high_water() {
+            try!(write!(f, ", arena high water max {} bytes, mean {} bytes", self.arena.max_high_water, mean));
+        }
+        let times = &self.response_times;
+        if let (Some(min), Some(mean), Some(p95), Some(max)) = (times.min(), times.mean(), times.p95(), times.max()) {
+            try!(write!(f, ", response time min {:?}, mean {:?}, p95 {:?}, max {:?}", min, mean, p95, max));
+        }
+        Ok(())
+    }
+}
+
+/// Collector of the counters of finished transfers, clones share the counters.
+#[derive(Debug, Default, Clone)]
+pub struct Stats(Arc<Mutex<TransferStats>>);
+
+impl Stats {
+    /// Creates a collector with all counters at zero.
+    pub fn new() -> Stats {
+        Stats::default()
+    }
+
+    /// Returns the counters collected so far.
+    pub fn get(&self) -> TransferStats {
+        *self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Adds the counters of a transfer.
+    pub fn add(&self, stats: &TransferStats) {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).add(stats);
+    }
+}
+
+/// Counts the events of a single transfer, the counters are added to the
+/// collector when the recorder is dropped.
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    stats: TransferStats,
+    collector: Stats,
+}
+
+impl Recorder {
+    pub(crate) fn new(collector: Stats) -> Recorder {
+        Recorder {
+            stats: TransferStats::default(),
+            collector: collector,
+        }
+    }
+
+    /// Counts the result of sending a datagram.
+    pub(crate) fn sent<T>(&mut self, result: &io::Result<T>) {
+        match *result {
+            Ok(_) => self.stats.socket.datagrams_sent += 1,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => self.stats.socket.send_errors += 1,
+        }
+    }
+
+    /// Counts the datagrams of a send of several segments.
+    pub(crate) fn sent_segments(&mut self, result: &io::Result<usize>) {
+        match *result {
+            Ok(n) => self.stats.socket.datagrams_sent += n as u64,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(_) => self.stats.socket.send_errors += 1,
+        }
+    }
+
+    pub(crate) fn received(&mut self) {
+        self.stats.socket.datagrams_received += 1;
+    }
+
+    pub(crate) fn wrong_tid(&mut self) {
+        self.stats.socket.wrong_tid_discarded += 1;
+    }
+
+    pub(crate) fn wrong_tid_suppressed(&mut self) {
+        self.stats.socket.wrong_tid_suppressed += 1;
+    }
+
+    pub(crate) fn retransmission(&mut self) {
+        self.stats.protocol.retransmissions += 1;
+    }
+
+    pub(crate) fn duplicate(&mut self) {
+        self.stats.protocol.duplicates += 1;
+    }
+
+    pub(crate) fn unexpected(&mut self) {
+        self.stats.protocol.unexpected_packets += 1;
+    }
+
+    pub(crate) fn keepalive(&mut self) {
+        self.stats.protocol.keepalives += 1;
+    }
+
+    pub(crate) fn option_fallback(&mut self) {
+        self.stats.protocol.option_fallbacks += 1;
+    }
+
+    pub(crate) fn block_size_fallback(&mut self) {
+        self.stats.protocol.block_size_fallbacks += 1;
+    }
+
+    /// Records the blocks the sender of the transfer read again from its cache.
+    pub(crate) fn cache_hits(&mut self, hits: u64) {
+        self.stats.protocol.cache_hits = hits;
+    }
+
+    pub(crate) fn response_time(&mut self, time: Duration) {
+        self.stats.response_times.record(time);
+    }
+
+    /// Records the conformance the transfer runs with.
+    pub(crate) fn conformance(&mut self, conformance: Conformance) {
+        let strict = conformance.is_strict() as u64;
+        self.stats.conformance.strict_transfers = strict;
+        self.stats.conformance.lenient_transfers = 1 - strict;
+    }
+
+    /// Records the high-water mark of the arena of the transfer.
+    pub(crate) fn arena(&mut self, high_water: usize) {
+        self.stats.arena = ArenaStats {
+            transfers: 1,
+            max_high_water: high_water as u64,
+            total_high_water: high_water as u64,
+        };
+    }
+
+    /// Counts a deviation of the peer, tolerated or refused by `conformance`.
+    pub(crate) fn deviation(&mut self, conformance: Conformance) {
+        match conformance {
+            Conformance::Strict => self.stats.conformance.refused += 1,
+            Conformance::Lenient => self.stats.conformance.tolerated += 1,
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.collector.add(&self.stats);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::Duration;
+
+    use super::{Stats, Recorder};
+
+    #[test]
+    fn counters_are_collected_when_transfer_ends() {
+        let stats = Stats::new();
+        {
+            let mut recorder = Recorder::new(stats.clone());
+            recorder.sent(&Ok(4));
+            recorder.sent::<usize>(&Err(io::ErrorKind::WouldBlock.into()));
+            recorder.sent::<usize>(&Err(io::ErrorKind::ConnectionRefused.into()));
+            recorder.received();
+            recorder.wrong_tid();
+            recorder.retransmission();
+            recorder.cache_hits(2);
+            assert_eq!(0, stats.get().socket.datagrams_sent);
+        }
+        let collected = stats.get();
+        assert_eq!(1, collected.socket.datagrams_sent);
+        assert_eq!(1, collected.socket.send_errors);
+        assert_eq!(1, collected.socket.datagrams_received);
+        assert_eq!(1, collected.socket.wrong_tid_discarded);
+        assert_eq!(1, collected.protocol.retransmissions);
+        assert_eq!(0, collected.protocol.duplicates);
+        assert_eq!(2, collected.protocol.cache_hits);
+        assert!(collected.to_string().ends_with(", cache hits 2"), "{}", collected);
+    }
+
+    #[test]
+    fn response_times_are_summarized() {
+        let stats = Stats::new();
+        {
+            let mut recorder = Recorder::new(stats.clone());
+            for millis in 1..101 {
+                recorder.response_time(Duration::from_millis(millis));
+            }
+        }
+        Recorder::new(stats.clone()).response_time(Duration::from_millis(500));
+        let times = stats.get().response_times;
+        assert_eq!(101, times.count());
+        assert_eq!(Some(Duration::from_millis(1)), times.min());
+        assert_eq!(Some(Duration::from_micros(54950)), times.mean());
+        assert_eq!(Some(Duration::from_millis(500)), times.max());
+        // The 96th time is in the bucket below 2^17 microseconds.
+        assert_eq!(Some(Duration::from_micros(1 << 17)), times.p95());
+        assert_eq!(Some(Duration::from_micros(1024)), times.percentile(0.0));
+        assert_eq!(Some(Duration::from_millis(500)), times.percentile(1.0));
+        assert_eq!(101, times.buckets().iter().map(|&(_, count)| count).sum::<u64>());
+    }
+}
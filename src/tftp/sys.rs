@@ -0,0 +1,137 @@
+//! Platform specific helpers shared by the socket fast paths and the file
+//! system handler.
+
+extern crate libc;
+
+use std::ffi::CString;
+#[cfg(feature = "tokio-server")]
+use std::fs::File;
+use std::io;
+use std::mem;
+#[cfg(feature = "tokio-server")]
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+#[cfg(feature = "tokio-server")]
+use std::os::unix::io::FromRawFd;
+#[cfg(feature = "tokio-server")]
+use std::path::{Component, Path};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr};
+
+/// Converts a socket address filled in by the kernel into `SocketAddr`.
+pub fn to_socket_addr(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port),
+                                                addr.sin6_flowinfo, addr.sin6_scope_id)))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unsupported address family")),
+    }
+}
+
+/// Converts `SocketAddr` into a socket address passed to the kernel and its length.
+pub fn from_socket_addr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match *addr {
+        SocketAddr::V4(ref addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(ref addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+/// Restricts a socket to the network interface `device` (`SO_BINDTODEVICE`).
+pub fn bind_to_device<S: AsRawFd>(socket: &S, device: &str) -> io::Result<()> {
+    let name = try!(CString::new(device)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid interface name")));
+    let result = unsafe {
+        libc::setsockopt(socket.as_raw_fd(), libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+                         name.as_ptr() as *const libc::c_void, name.as_bytes_with_nul().len() as libc::socklen_t)
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Dissolves the association of a connected datagram socket (`connect` with
+/// `AF_UNSPEC`), it receives datagrams from all peers again.
+#[cfg(feature = "mio-client")]
+pub fn disconnect<S: AsRawFd>(socket: &S) -> io::Result<()> {
+    let mut addr: libc::sockaddr = unsafe { mem::zeroed() };
+    addr.sa_family = libc::AF_UNSPEC as libc::sa_family_t;
+    let result = unsafe {
+        libc::connect(socket.as_raw_fd(), &addr, mem::size_of::<libc::sockaddr>() as libc::socklen_t)
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Opens the file at `relative` inside the directory `root` for reading
+/// without following symbolic links.
+///
+/// Every component is opened relative to the directory opened before it
+/// (`openat` with `O_NOFOLLOW`), so a link swapped into the path fails the
+/// open with `PermissionDenied` instead of leading elsewhere.
+#[cfg(feature = "tokio-server")]
+pub fn open_beneath(root: &Path, relative: &Path) -> io::Result<File> {
+    let mut opened = try!(File::open(root));
+    let components: Vec<_> = relative.components().filter(|component| *component != Component::CurDir).collect();
+    for (i, component) in components.iter().enumerate() {
+        let name = match *component {
+            Component::Normal(name) => name,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "path leaves the directory")),
+        };
+        let name = try!(CString::new(name.as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid file name")));
+        let mut flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        if i + 1 < components.len() {
+            flags |= libc::O_DIRECTORY;
+        }
+        let fd = unsafe { libc::openat(opened.as_raw_fd(), name.as_ptr(), flags) };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            // Links fail with ELOOP, links in place of directories with ENOTDIR.
+            let link = match e.raw_os_error() {
+                Some(libc::ELOOP) => true,
+                Some(libc::ENOTDIR) => {
+                    let mut stat: libc::stat = unsafe { mem::zeroed() };
+                    let result = unsafe {
+                        libc::fstatat(opened.as_raw_fd(), name.as_ptr(), &mut stat, libc::AT_SYMLINK_NOFOLLOW)
+                    };
+                    result == 0 && stat.st_mode & libc::S_IFMT == libc::S_IFLNK
+                }
+                _ => false,
+            };
+            if link {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "symbolic links are not allowed"))
+            }
+            return Err(e)
+        }
+        opened = unsafe { File::from_raw_fd(fd) };
+    }
+    Ok(opened)
+}
@@ -0,0 +1,1102 @@
+//! Transfer state machines shared by the client and the server.
+//!
+//! The state machines don't perform any network I/O, they only consume decoded
+//! packets and tell the caller what should be sent to the remote side next.
+//! `ReadTransfer` is the receiving side of a transfer (client reading a file,
+//! server accepting a written file) and `WriteTransfer` is the sending side.
+
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read};
+use std::time::Duration;
+
+use config::{BlockSize, Retries, WindowSize};
+use packet::{AckPacket, DataPacketOctet, OptionAckPacket, TransferOptions, BLKSIZE_OPTION, TIMEOUT_OPTION,
+             UTIMEOUT_OPTION, WINDOWSIZE_OPTION};
+use source::BlockSource;
+
+/// Data block size defined in RFC 1350.
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Shortest timeout negotiated with the `utimeout` option.
+pub const MIN_NEGOTIATED_TIMEOUT: Duration = Duration::from_millis(10);
+
+/// Longest timeout negotiated, the limit of the `timeout` option (RFC 2349).
+pub const MAX_NEGOTIATED_TIMEOUT: Duration = Duration::from_secs(255);
+
+/// Result of handling a data packet by `ReadTransfer`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum DataReceived {
+    /// Packet contains the next expected block. The payload should be written
+    /// out and the returned acknowledgment sent.
+    Accepted(AckPacket),
+
+    /// Packet repeats the last accepted block because the sender missed its
+    /// acknowledgment. The returned acknowledgment should be sent again, the
+    /// payload was already written out and must not be written again.
+    Duplicate(AckPacket),
+
+    /// Packet has an unexpected block number and must be ignored.
+    Ignored,
+}
+
+/// Result of handling an acknowledgment by `WriteTransfer`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum AckReceived {
+    /// Current block was acknowledged, the next block should be sent.
+    Next,
+
+    /// Last block was acknowledged and the transfer is complete.
+    Done,
+
+    /// Acknowledgment is not for a block in flight and must be ignored.
+    Ignored,
+
+    /// Only a part of the window was acknowledged. The data source must be
+    /// positioned at the given byte offset before sending continues.
+    Rewind(u64),
+}
+
+/// Action required after the retransmission timeout expired.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Timeout {
+    /// Current block should be sent again.
+    Retransmit,
+
+    /// Window should be sent again, starting at the first unacknowledged block.
+    /// The data source must be positioned at the given byte offset.
+    Rewind(u64),
+
+    /// Transfer should be restarted using a smaller block size.
+    Fallback(usize),
+
+    /// Retries are exhausted and the transfer failed.
+    Failed,
+}
+
+/// Policy for restarting a transfer with a smaller block size.
+///
+/// Large negotiated blocks are fragmented into multiple IP packets and losing any
+/// fragment loses the whole block. When a block times out `timeouts` times in a
+/// row the sender restarts the negotiation using `block_size` instead of failing.
+///
+/// Only a client can restart an upload with a new request, see
+/// `ClientBuilder::block_size_fallback`. A server sends the block size the
+/// client acknowledged and front-ends that can't read their data again from
+/// its start don't set a fallback.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct BlockSizeFallback {
+    /// Number of consecutive timeouts that trigger the fallback.
+    pub timeouts: Retries,
+
+    /// Block size to fall back to.
+    pub block_size: usize,
+}
+
+impl BlockSizeFallback {
+    /// Creates a fallback policy to `block_size` after `timeouts` consecutive timeouts.
+    pub fn new(timeouts: Retries, block_size: usize) -> BlockSizeFallback {
+        BlockSizeFallback {
+            timeouts: timeouts,
+            block_size: block_size,
+        }
+    }
+}
+
+/// Parameters a transfer runs with after option negotiation.
+///
+/// These are the values in effect, not the requested ones: options the remote
+/// side didn't acknowledge keep their defaults.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct TransferParams {
+    /// Size of a data block in bytes.
+    pub block_size: usize,
+
+    /// Time to wait for a response before the last packet is sent again.
+    pub timeout: Duration,
+
+    /// Number of blocks sent before waiting for an acknowledgment.
+    pub window_size: WindowSize,
+
+    /// Size of the file, if announced with the transfer size option (RFC 2349).
+    pub transfer_size: Option<u64>,
+}
+
+impl TransferParams {
+    /// Creates parameters of a transfer using `block_size` and `timeout`, sending
+    /// one block at a time and without a known size.
+    pub fn new(block_size: usize, timeout: Duration) -> TransferParams {
+        TransferParams {
+            block_size: block_size,
+            timeout: timeout,
+            window_size: WindowSize::default(),
+            transfer_size: None,
+        }
+    }
+}
+
+impl fmt::Display for TransferParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "block size {}, timeout {:?}, window size {}", self.block_size, self.timeout,
+                    self.window_size.get()));
+        if let Some(size) = self.transfer_size {
+            try!(write!(f, ", transfer size {}", size));
+        }
+        Ok(())
+    }
+}
+
+/// Receiving side of a transfer.
+#[derive(Debug)]
+pub struct ReadTransfer {
+    block_size: usize,
+    block_id: u16,
+    accepted: bool,
+    done: bool,
+    retries: Retries,
+    timeouts: u32,
+    accept_block_zero: bool,
+    block_offset: u16,
+}
+
+impl ReadTransfer {
+    /// Creates a transfer expecting data blocks of `block_size` bytes.
+    pub fn new(block_size: usize) -> ReadTransfer {
+        ReadTransfer {
+            block_size: block_size,
+            block_id: 1,
+            accepted: false,
+            done: false,
+            retries: Retries::default(),
+            timeouts: 0,
+            accept_block_zero: false,
+            block_offset: 0,
+        }
+    }
+
+    /// Accepts a first data block numbered 0 instead of 1, as sent by some
+    /// broken embedded servers. The numbers of all following blocks are then
+    /// shifted by one, acknowledgments keep the numbering of the sender.
+    ///
+    /// By default block 0 is ignored as RFC 1350 requires.
+    pub fn set_accept_block_zero(&mut self, accept: bool) {
+        self.accept_block_zero = accept;
+    }
+
+    /// Sets the number of retransmissions of an acknowledgment before the transfer fails.
+    pub fn set_retries(&mut self, retries: Retries) {
+        self.retries = retries;
+    }
+
+    /// Returns the size of data blocks.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the block number of the next expected data packet.
+    pub fn expected_block_id(&self) -> u16 {
+        self.block_id
+    }
+
+    /// Handles a received data packet.
+    ///
+    /// A block shorter than the block size is the last one and completes the transfer.
+    /// The last accepted block is reported as a duplicate, also after the
+    /// transfer completed, as the sender keeps sending it until the final
+    /// acknowledgment arrives.
+    pub fn receive_data(&mut self, packet: &DataPacketOctet) -> DataReceived {
+        if self.accept_block_zero && !self.accepted && packet.block_id() == 0 {
+            self.block_offset = 1;
+        }
+        let block_id = packet.block_id().wrapping_add(self.block_offset);
+        if self.accepted && block_id == self.block_id.wrapping_sub(1) {
+            return DataReceived::Duplicate(AckPacket::new(packet.block_id()))
+        }
+        if self.done || block_id != self.block_id {
+            return DataReceived::Ignored
+        }
+        if packet.data().len() < self.block_size {
+            self.done = true;
+        }
+        self.accepted = true;
+        self.block_id = self.block_id.wrapping_add(1);
+        self.timeouts = 0;
+        DataReceived::Accepted(AckPacket::new(packet.block_id()))
+    }
+
+    /// Handles an expired retransmission timeout.
+    ///
+    /// On `Timeout::Retransmit` the last sent packet should be sent again. A
+    /// receiver doesn't rewind or fall back, the only other result is
+    /// `Timeout::Failed`.
+    pub fn timeout(&mut self) -> Timeout {
+        self.timeouts += 1;
+        if self.timeouts > self.retries.get() {
+            Timeout::Failed
+        } else {
+            Timeout::Retransmit
+        }
+    }
+
+    /// Returns `true` when the last block was received.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+/// Acknowledgments of a receiver of windows (RFC 7440).
+///
+/// The receiver acknowledges the last block of every window and the last block
+/// of the transfer. A block that doesn't follow the last accepted one is
+/// answered once with the acknowledgment of that block, so the sender goes on
+/// after it without waiting for its timeout. With a window size of one every
+/// block is acknowledged.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct AckWindow {
+    size: u16,
+    unacknowledged: u16,
+    gap: bool,
+}
+
+impl AckWindow {
+    /// Creates the acknowledgments of windows of `window_size` blocks.
+    pub fn new(window_size: WindowSize) -> AckWindow {
+        AckWindow {
+            size: window_size.get(),
+            unacknowledged: 0,
+            gap: false,
+        }
+    }
+
+    /// Records a block `ReadTransfer` accepted, returns `true` if it's acknowledged.
+    pub fn accepted(&mut self, last: bool) -> bool {
+        self.unacknowledged += 1;
+        self.gap = false;
+        last || self.unacknowledged >= self.size
+    }
+
+    /// Records a block `ReadTransfer` ignored, returns `true` if the last
+    /// accepted block is acknowledged again.
+    pub fn skipped(&mut self) -> bool {
+        if self.size == 1 || self.gap {
+            return false
+        }
+        self.gap = true;
+        true
+    }
+
+    /// Records that an acknowledgment was sent, the next window of the sender
+    /// starts after it.
+    pub fn acknowledged(&mut self) {
+        self.unacknowledged = 0;
+    }
+}
+
+/// Additive-increase/multiplicative-decrease controller of the pace of windows.
+///
+/// Receivers (RFC 7440) acknowledge the last block of a full window, so the
+/// sender always sends the negotiated window. The controller's window of
+/// blocks per round trip is halved on every loss and grows by one block for
+/// every window acknowledged without loss, up to the negotiated window size.
+/// Below it the sender pauses between windows, see `pause`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct CongestionWindow {
+    max: u16,
+    current: u16,
+}
+
+impl CongestionWindow {
+    /// Creates a controller for the negotiated window size `max`, starting at the full window.
+    pub fn new(max: u16) -> CongestionWindow {
+        CongestionWindow {
+            max: max,
+            current: max,
+        }
+    }
+
+    /// Returns the number of blocks sent per round trip.
+    pub fn window(&self) -> u16 {
+        self.current
+    }
+
+    /// Returns the time to wait before the next full window is sent, after
+    /// the last one was acknowledged `round_trip` after it was sent.
+    ///
+    /// The windows then take as long as `window` blocks per round trip.
+    pub fn pause(&self, round_trip: Duration) -> Duration {
+        round_trip * (self.max - self.current) as u32 / self.current as u32
+    }
+
+    /// Grows the window after a window was acknowledged without loss.
+    pub fn on_ack(&mut self) {
+        if self.current < self.max {
+            self.current += 1;
+        }
+    }
+
+    /// Shrinks the window after a block was lost.
+    pub fn on_loss(&mut self) {
+        self.current = if self.current > 1 { self.current / 2 } else { 1 };
+    }
+}
+
+/// Sending side of a transfer.
+///
+/// The transfer starts waiting for the acknowledgment of block 0, as sent by the
+/// server in response to a write request. Senders responding to a read request
+/// call `next_block` right away.
+///
+/// With a window size larger than one (RFC 7440) blocks are read and sent for as
+/// long as `can_send` returns `true`. When the receiver acknowledges only a part
+/// of the window or the window times out, the caller is asked to rewind the data
+/// source and continue sending from the first unacknowledged block.
+/// `next_block_from` reads blocks of a `BlockSource` at their offsets, the
+/// source then doesn't have to be rewound.
+#[derive(Debug)]
+pub struct WriteTransfer {
+    block_size: usize,
+    block_id: u16,
+    acked: u16,
+    acked_blocks: u64,
+    buffer: Vec<u8>,
+    len: usize,
+    last: bool,
+    done: bool,
+    retries: Retries,
+    timeouts: u32,
+    fallback: Option<BlockSizeFallback>,
+    window_size: u16,
+    congestion: Option<CongestionWindow>,
+}
+
+impl WriteTransfer {
+    /// Creates a transfer sending data blocks of `block_size` bytes.
+    pub fn new(block_size: usize) -> WriteTransfer {
+        WriteTransfer {
+            block_size: block_size,
+            block_id: 0,
+            acked: 0,
+            acked_blocks: 0,
+            buffer: vec![0; block_size],
+            len: 0,
+            last: false,
+            done: false,
+            retries: Retries::default(),
+            timeouts: 0,
+            fallback: None,
+            window_size: 1,
+            congestion: None,
+        }
+    }
+
+    /// Sets the number of retransmissions of a block before the transfer fails.
+    pub fn set_retries(&mut self, retries: Retries) {
+        self.retries = retries;
+    }
+
+    /// Sets the policy for falling back to a smaller block size on repeated timeouts.
+    pub fn set_block_size_fallback(&mut self, fallback: Option<BlockSizeFallback>) {
+        self.fallback = fallback;
+    }
+
+    /// Sets the number of blocks sent before waiting for an acknowledgment.
+    ///
+    /// When `congestion_control` is enabled the pace of the windows is
+    /// adjusted by `CongestionWindow`, see `pause`.
+    pub fn set_window_size(&mut self, window_size: WindowSize, congestion_control: bool) {
+        self.window_size = window_size.get();
+        self.congestion = if congestion_control {
+            Some(CongestionWindow::new(self.window_size))
+        } else {
+            None
+        };
+    }
+
+    /// Returns the size of data blocks.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the number of blocks that can be in flight.
+    pub fn window(&self) -> u16 {
+        self.window_size
+    }
+
+    /// Returns the time to wait before the next window is sent, after the
+    /// last acknowledgment arrived `round_trip` after the last block was sent.
+    ///
+    /// Without congestion control, or while no block was lost, it's zero.
+    pub fn pause(&self, round_trip: Duration) -> Duration {
+        self.congestion.map_or(Duration::from_secs(0), |congestion| congestion.pause(round_trip))
+    }
+
+    /// Returns `true` if the next block can be sent before waiting for an acknowledgment.
+    pub fn can_send(&self) -> bool {
+        !self.done && !self.last && self.in_flight() < self.window()
+    }
+
+    fn in_flight(&self) -> u16 {
+        self.block_id.wrapping_sub(self.acked)
+    }
+
+    /// Reads the next block from `reader` and returns a data packet that should be sent.
+    ///
+    /// The block is kept until the next one is read so it can be retransmitted.
+    pub fn next_block<R: Read>(&mut self, reader: &mut R) -> io::Result<DataPacketOctet> {
+        let mut len = 0;
+        while len < self.block_size {
+            match reader.read(&mut self.buffer[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.len = len;
+        self.last = len < self.block_size;
+        self.block_id = self.block_id.wrapping_add(1);
+        Ok(self.current_block())
+    }
+
+    /// Reads the next block from `source` and returns a data packet that should be sent.
+    ///
+    /// The block is read at its offset in the data, the source doesn't have
+    /// to be positioned after a rewind, a restart or when resuming. The
+    /// transfer is unchanged when the read fails.
+    pub fn next_block_from<S: BlockSource + ?Sized>(&mut self, source: &mut S) -> io::Result<DataPacketOctet> {
+        let offset = (self.acked_blocks + self.in_flight() as u64) * self.block_size as u64;
+        let len = try!(source.read_at(offset, &mut self.buffer));
+        self.len = len;
+        self.last = len < self.block_size;
+        self.block_id = self.block_id.wrapping_add(1);
+        Ok(self.current_block())
+    }
+
+    /// Returns a data packet for the last read block.
+    pub fn current_block(&self) -> DataPacketOctet {
+        DataPacketOctet::from_slice(self.block_id, &self.buffer[..self.len])
+    }
+
+    /// Handles a received acknowledgment.
+    pub fn receive_ack(&mut self, packet: &AckPacket) -> AckReceived {
+        if self.block_id == 0 && packet.block_id() == 0 && !self.done {
+            // Response to a write request.
+            return AckReceived::Next
+        }
+        let in_flight = self.in_flight();
+        let advance = packet.block_id().wrapping_sub(self.acked);
+        if self.done || advance == 0 || advance > in_flight {
+            return AckReceived::Ignored
+        }
+        self.acked = packet.block_id();
+        self.acked_blocks += advance as u64;
+        self.timeouts = 0;
+        if advance < in_flight {
+            // Receiver lost a block in the middle of the window.
+            if let Some(ref mut congestion) = self.congestion {
+                congestion.on_loss();
+            }
+            return self.rewind()
+        }
+        if let Some(ref mut congestion) = self.congestion {
+            congestion.on_ack();
+        }
+        if self.last {
+            self.done = true;
+            AckReceived::Done
+        } else {
+            AckReceived::Next
+        }
+    }
+
+    /// Handles an expired retransmission timeout.
+    pub fn timeout(&mut self) -> Timeout {
+        self.timeouts += 1;
+        match self.fallback {
+            Some(fallback) if self.timeouts >= fallback.timeouts.get() && fallback.block_size < self.block_size => {
+                return Timeout::Fallback(fallback.block_size)
+            }
+            _ => {}
+        }
+        if self.timeouts > self.retries.get() {
+            return Timeout::Failed
+        }
+        if let Some(ref mut congestion) = self.congestion {
+            congestion.on_loss();
+        }
+        if self.in_flight() <= 1 {
+            Timeout::Retransmit
+        } else {
+            match self.rewind() {
+                AckReceived::Rewind(offset) => Timeout::Rewind(offset),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    fn rewind(&mut self) -> AckReceived {
+        self.block_id = self.acked;
+        self.len = 0;
+        self.last = false;
+        AckReceived::Rewind(self.acked_blocks * self.block_size as u64)
+    }
+
+    /// Restarts the transfer from the beginning using a new block size.
+    ///
+    /// The data source must be rewound by the caller before the first block is read.
+    pub fn restart(&mut self, block_size: usize) {
+        self.block_size = block_size;
+        self.buffer.resize(block_size, 0);
+        self.block_id = 0;
+        self.acked = 0;
+        self.acked_blocks = 0;
+        self.len = 0;
+        self.last = false;
+        self.done = false;
+        self.timeouts = 0;
+    }
+
+    /// Continues a transfer of which `acked_blocks` blocks were acknowledged
+    /// before, e.g. by a previous server process.
+    ///
+    /// The data source must be positioned after these blocks by the caller,
+    /// the next block read is sent as block `acked_blocks + 1`.
+    pub fn resume(&mut self, acked_blocks: u64) {
+        self.block_id = acked_blocks as u16;
+        self.acked = self.block_id;
+        self.acked_blocks = acked_blocks;
+        self.len = 0;
+        self.last = false;
+        self.done = false;
+        self.timeouts = 0;
+    }
+
+    /// Returns the number of blocks the receiver acknowledged.
+    pub fn acked_blocks(&self) -> u64 {
+        self.acked_blocks
+    }
+
+    /// Returns `true` when the last block was acknowledged.
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+}
+
+/// Returns the options a client requests for a transfer using `block_size`.
+///
+/// The default block size is not negotiated so servers without option support
+/// are not bothered with options.
+pub fn request_options(block_size: usize) -> TransferOptions<'static> {
+    let mut options = TransferOptions::new();
+    if block_size != DEFAULT_BLOCK_SIZE {
+        options.insert(BLKSIZE_OPTION, block_size.to_string());
+    }
+    options
+}
+
+/// Adds the option asking the server to use `timeout` as its retransmission
+/// interval to `options`.
+///
+/// Whole seconds are requested with the `timeout` option (RFC 2349), other
+/// timeouts with the `utimeout` option in microseconds, which servers without
+/// support for it ignore. The timeout is clamped to the negotiable range first,
+/// `MIN_NEGOTIATED_TIMEOUT` to `MAX_NEGOTIATED_TIMEOUT`.
+pub fn request_timeout(options: &mut TransferOptions, timeout: Duration) {
+    let (name, value) = timeout_option(timeout);
+    options.insert(name, value);
+}
+
+fn timeout_option(timeout: Duration) -> (&'static str, String) {
+    let timeout = cmp::max(MIN_NEGOTIATED_TIMEOUT, cmp::min(timeout, MAX_NEGOTIATED_TIMEOUT));
+    if timeout.subsec_nanos() == 0 {
+        (TIMEOUT_OPTION, timeout.as_secs().to_string())
+    } else {
+        (UTIMEOUT_OPTION, (timeout.as_secs() * 1_000_000 + u64::from(timeout.subsec_micros())).to_string())
+    }
+}
+
+/// Returns the timeout requested by a client with the `utimeout` or the
+/// `timeout` option and the name of the option that is acknowledged.
+///
+/// A valid `utimeout` takes precedence. Values outside of the negotiable range
+/// are ignored, the option must be acknowledged with the requested value.
+pub fn requested_timeout(options: &TransferOptions) -> Option<(&'static str, Duration)> {
+    let valid = |timeout: Duration| timeout >= MIN_NEGOTIATED_TIMEOUT && timeout <= MAX_NEGOTIATED_TIMEOUT;
+    let utimeout = options.get(UTIMEOUT_OPTION)
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_micros)
+        .filter(|&timeout| valid(timeout));
+    let timeout = options.get(TIMEOUT_OPTION)
+        .and_then(|value| value.parse().ok())
+        .map(Duration::from_secs)
+        .filter(|&timeout| valid(timeout));
+    match (utimeout, timeout) {
+        (Some(utimeout), _) => Some((UTIMEOUT_OPTION, utimeout)),
+        (None, Some(timeout)) => Some((TIMEOUT_OPTION, timeout)),
+        (None, None) => None,
+    }
+}
+
+/// Returns the block size acknowledged by the server in response to `request_options(requested)`.
+///
+/// The server may only acknowledge the requested options and lower the requested block size.
+pub fn negotiated_block_size(requested: usize, oack: &OptionAckPacket) -> Result<usize, &'static str> {
+    negotiated_options(requested, None, &TransferOptions::new(), oack)
+}
+
+/// Returns the block size acknowledged by the server in response to
+/// `request_options(requested)` with `request_timeout(timeout)` and the
+/// nonstandard `extensions` added.
+///
+/// The server may only acknowledge the requested options, lower the requested
+/// block size and must acknowledge the timeout unchanged. Values of extensions
+/// are up to the caller.
+pub fn negotiated_options(requested: usize, timeout: Option<Duration>, extensions: &TransferOptions,
+                          oack: &OptionAckPacket) -> Result<usize, &'static str> {
+    let timeout = timeout.map(timeout_option);
+    let requested_option = |name: &str| {
+        name.eq_ignore_ascii_case(BLKSIZE_OPTION) || extensions.get(name).is_some() ||
+            timeout.as_ref().map_or(false, |&(timeout_name, _)| name.eq_ignore_ascii_case(timeout_name))
+    };
+    if oack.options().iter().any(|&(ref name, _)| !requested_option(name)) {
+        return Err("server acknowledged an option that was not requested")
+    }
+    if let Some((name, value)) = timeout {
+        match oack.options().get(name) {
+            Some(acknowledged) if acknowledged.parse::<u64>().ok() != value.parse().ok() => {
+                return Err("server acknowledged a different timeout")
+            }
+            _ => {}
+        }
+    }
+    match oack.options().get(BLKSIZE_OPTION) {
+        None => Ok(DEFAULT_BLOCK_SIZE),
+        Some(value) => match value.parse::<usize>() {
+            Ok(size) if size <= requested && BlockSize::new(size).is_ok() => Ok(size),
+            _ => Err("server acknowledged an invalid block size"),
+        },
+    }
+}
+
+/// Adds the option asking the receiver to acknowledge every `window_size`
+/// blocks (RFC 7440) to `options`.
+///
+/// The default of one block is not requested.
+pub fn request_window_size(options: &mut TransferOptions, window_size: WindowSize) {
+    if window_size != WindowSize::default() {
+        options.insert(WINDOWSIZE_OPTION, window_size.get().to_string());
+    }
+}
+
+/// Returns the window size requested by a client, lowered to `max`.
+///
+/// Invalid values are ignored, the option is not acknowledged then.
+pub fn requested_window_size(options: &TransferOptions, max: WindowSize) -> Option<WindowSize> {
+    options.get(WINDOWSIZE_OPTION)
+        .and_then(|value| value.parse().ok())
+        .and_then(|size| WindowSize::new(size).ok())
+        .map(|size| cmp::min(size, max))
+}
+
+/// Returns the window size acknowledged by the server in response to
+/// `request_window_size(requested)`.
+///
+/// The server may only lower the requested window size, without the option
+/// every block is acknowledged.
+pub fn negotiated_window_size(requested: WindowSize, oack: &OptionAckPacket) -> Result<WindowSize, &'static str> {
+    match oack.options().get(WINDOWSIZE_OPTION) {
+        None => Ok(WindowSize::default()),
+        Some(value) => match value.parse().ok().and_then(|size| WindowSize::new(size).ok()) {
+            Some(size) if size <= requested => Ok(size),
+            _ => Err("server acknowledged an invalid window size"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use config::{Retries, WindowSize};
+    use packet::{AckPacket, DataPacketOctet, OptionAckPacket, TransferOptions};
+
+    use super::{ReadTransfer, WriteTransfer, DataReceived, AckReceived, Timeout, BlockSizeFallback, AckWindow,
+                CongestionWindow, negotiated_block_size, negotiated_options, negotiated_window_size, request_timeout,
+                requested_timeout, request_window_size, requested_window_size};
+
+    #[test]
+    fn read_transfer_accepts_blocks_in_order() {
+        let mut transfer = ReadTransfer::new(4);
+        let first = DataPacketOctet::from_slice(1, b"abcd");
+        assert_eq!(DataReceived::Accepted(AckPacket::new(1)), transfer.receive_data(&first));
+        assert_eq!(2, transfer.expected_block_id());
+        assert!(!transfer.is_done());
+    }
+
+    #[test]
+    fn read_transfer_ignores_unexpected_block() {
+        let mut transfer = ReadTransfer::new(4);
+        let packet = DataPacketOctet::from_slice(2, b"abcd");
+        assert_eq!(DataReceived::Ignored, transfer.receive_data(&packet));
+        assert_eq!(1, transfer.expected_block_id());
+    }
+
+    #[test]
+    fn read_transfer_reports_duplicate_block() {
+        let mut transfer = ReadTransfer::new(4);
+        let first = DataPacketOctet::from_slice(1, b"abcd");
+        let last = DataPacketOctet::from_slice(2, b"ab");
+        transfer.receive_data(&first);
+        assert_eq!(DataReceived::Duplicate(AckPacket::new(1)), transfer.receive_data(&first));
+        assert_eq!(2, transfer.expected_block_id());
+        transfer.receive_data(&last);
+        assert_eq!(DataReceived::Duplicate(AckPacket::new(2)), transfer.receive_data(&last));
+        assert_eq!(DataReceived::Ignored, transfer.receive_data(&first));
+        assert!(transfer.is_done());
+    }
+
+    #[test]
+    fn read_transfer_is_done_after_short_block() {
+        let mut transfer = ReadTransfer::new(4);
+        transfer.receive_data(&DataPacketOctet::from_slice(1, b"abcd"));
+        transfer.receive_data(&DataPacketOctet::from_slice(2, b"ab"));
+        assert!(transfer.is_done());
+    }
+
+    #[test]
+    fn read_transfer_accepts_block_zero_when_enabled() {
+        let mut strict = ReadTransfer::new(4);
+        assert_eq!(DataReceived::Ignored, strict.receive_data(&DataPacketOctet::from_slice(0, b"abcd")));
+
+        let mut transfer = ReadTransfer::new(4);
+        transfer.set_accept_block_zero(true);
+        let first = DataPacketOctet::from_slice(0, b"abcd");
+        assert_eq!(DataReceived::Accepted(AckPacket::new(0)), transfer.receive_data(&first));
+        assert_eq!(DataReceived::Duplicate(AckPacket::new(0)), transfer.receive_data(&first));
+        let last = DataPacketOctet::from_slice(1, b"ef");
+        assert_eq!(DataReceived::Accepted(AckPacket::new(1)), transfer.receive_data(&last));
+        assert!(transfer.is_done());
+    }
+
+    #[test]
+    fn read_transfer_is_done_after_empty_block() {
+        let mut transfer = ReadTransfer::new(4);
+        let empty = DataPacketOctet::from_slice(1, b"");
+        assert_eq!(DataReceived::Accepted(AckPacket::new(1)), transfer.receive_data(&empty));
+        assert!(transfer.is_done());
+
+        let mut transfer = ReadTransfer::new(4);
+        transfer.receive_data(&DataPacketOctet::from_slice(1, b"abcd"));
+        assert!(!transfer.is_done());
+        let empty = DataPacketOctet::from_slice(2, b"");
+        assert_eq!(DataReceived::Accepted(AckPacket::new(2)), transfer.receive_data(&empty));
+        assert!(transfer.is_done());
+    }
+
+    #[test]
+    fn read_transfer_fails_after_retries_are_exhausted() {
+        let mut transfer = ReadTransfer::new(4);
+        transfer.set_retries(Retries::new(1).unwrap());
+        assert_eq!(Timeout::Retransmit, transfer.timeout());
+        transfer.receive_data(&DataPacketOctet::from_slice(1, b"abcd"));
+        assert_eq!(Timeout::Retransmit, transfer.timeout());
+        assert_eq!(Timeout::Failed, transfer.timeout());
+    }
+
+    #[test]
+    fn write_transfer_sends_blocks_until_short_block_is_acked() {
+        let mut data = Cursor::new(b"abcdef".to_vec());
+        let mut transfer = WriteTransfer::new(4);
+        assert_eq!(AckReceived::Next, transfer.receive_ack(&AckPacket::new(0)));
+
+        assert_eq!(b"abcd", transfer.next_block(&mut data).unwrap().data());
+        assert_eq!(AckReceived::Ignored, transfer.receive_ack(&AckPacket::new(0)));
+        assert_eq!(AckReceived::Next, transfer.receive_ack(&AckPacket::new(1)));
+
+        let packet = transfer.next_block(&mut data).unwrap();
+        assert_eq!(2, packet.block_id());
+        assert_eq!(b"ef", packet.data());
+        assert_eq!(AckReceived::Done, transfer.receive_ack(&AckPacket::new(2)));
+        assert!(transfer.is_done());
+    }
+
+    #[test]
+    fn write_transfer_sends_empty_file_as_empty_block() {
+        let mut transfer = WriteTransfer::new(4);
+        let packet = transfer.next_block(&mut Cursor::new(Vec::new())).unwrap();
+        assert_eq!(1, packet.block_id());
+        assert!(packet.data().is_empty());
+        assert!(!transfer.can_send());
+        assert_eq!(AckReceived::Done, transfer.receive_ack(&AckPacket::new(1)));
+    }
+
+    #[test]
+    fn write_transfer_ends_block_multiple_with_empty_block() {
+        let mut data = Cursor::new(b"abcd".to_vec());
+        let mut transfer = WriteTransfer::new(4);
+        assert_eq!(b"abcd", transfer.next_block(&mut data).unwrap().data());
+        assert_eq!(AckReceived::Next, transfer.receive_ack(&AckPacket::new(1)));
+        let packet = transfer.next_block(&mut data).unwrap();
+        assert_eq!(2, packet.block_id());
+        assert!(packet.data().is_empty());
+        assert_eq!(AckReceived::Done, transfer.receive_ack(&AckPacket::new(2)));
+    }
+
+    #[test]
+    fn write_transfer_retransmits_current_block() {
+        let mut data = Cursor::new(b"abc".to_vec());
+        let mut transfer = WriteTransfer::new(4);
+        transfer.next_block(&mut data).unwrap();
+        let packet = transfer.current_block();
+        assert_eq!(1, packet.block_id());
+        assert_eq!(b"abc", packet.data());
+    }
+
+    #[test]
+    fn write_transfer_fails_after_retries_are_exhausted() {
+        let mut transfer = WriteTransfer::new(4);
+        transfer.set_retries(Retries::new(2).unwrap());
+        transfer.next_block(&mut Cursor::new(b"abcd".to_vec())).unwrap();
+        assert_eq!(Timeout::Retransmit, transfer.timeout());
+        assert_eq!(Timeout::Retransmit, transfer.timeout());
+        assert_eq!(Timeout::Failed, transfer.timeout());
+    }
+
+    #[test]
+    fn write_transfer_acknowledgment_resets_timeouts() {
+        let mut data = Cursor::new(b"abcdefgh".to_vec());
+        let mut transfer = WriteTransfer::new(4);
+        transfer.set_retries(Retries::new(1).unwrap());
+        transfer.next_block(&mut data).unwrap();
+        assert_eq!(Timeout::Retransmit, transfer.timeout());
+        transfer.receive_ack(&AckPacket::new(1));
+        transfer.next_block(&mut data).unwrap();
+        assert_eq!(Timeout::Retransmit, transfer.timeout());
+    }
+
+    #[test]
+    fn write_transfer_falls_back_to_smaller_block_size() {
+        let mut data = Cursor::new(vec![1; 16]);
+        let mut transfer = WriteTransfer::new(8);
+        transfer.set_block_size_fallback(Some(BlockSizeFallback::new(Retries::new(2).unwrap(), 4)));
+        transfer.next_block(&mut data).unwrap();
+        assert_eq!(Timeout::Retransmit, transfer.timeout());
+        assert_eq!(Timeout::Fallback(4), transfer.timeout());
+
+        transfer.restart(4);
+        data.set_position(0);
+        let packet = transfer.next_block(&mut data).unwrap();
+        assert_eq!(1, packet.block_id());
+        assert_eq!(4, packet.data().len());
+        assert_eq!(Timeout::Retransmit, transfer.timeout());
+    }
+
+    #[test]
+    fn write_transfer_resumes_after_acknowledged_blocks() {
+        let mut data = Cursor::new(b"abcdefgh".to_vec());
+        let mut transfer = WriteTransfer::new(4);
+        transfer.resume(1);
+        data.set_position(4);
+        let packet = transfer.next_block(&mut data).unwrap();
+        assert_eq!(2, packet.block_id());
+        assert_eq!(b"efgh", packet.data());
+        assert_eq!(AckReceived::Ignored, transfer.receive_ack(&AckPacket::new(1)));
+        assert_eq!(AckReceived::Next, transfer.receive_ack(&AckPacket::new(2)));
+        assert_eq!(2, transfer.acked_blocks());
+    }
+
+    #[test]
+    fn write_transfer_sends_whole_window() {
+        let mut data = Cursor::new(vec![1; 10]);
+        let mut transfer = WriteTransfer::new(4);
+        transfer.set_window_size(WindowSize::new(3).unwrap(), false);
+        let mut sent = Vec::new();
+        while transfer.can_send() {
+            sent.push(transfer.next_block(&mut data).unwrap().block_id());
+        }
+        assert_eq!(vec![1, 2, 3], sent);
+        assert_eq!(AckReceived::Done, transfer.receive_ack(&AckPacket::new(3)));
+    }
+
+    #[test]
+    fn write_transfer_rewinds_on_partial_window_ack() {
+        let mut data = Cursor::new(vec![1; 16]);
+        let mut transfer = WriteTransfer::new(4);
+        transfer.set_window_size(WindowSize::new(3).unwrap(), false);
+        while transfer.can_send() {
+            transfer.next_block(&mut data).unwrap();
+        }
+        assert_eq!(AckReceived::Rewind(4), transfer.receive_ack(&AckPacket::new(1)));
+        data.set_position(4);
+        assert_eq!(2, transfer.next_block(&mut data).unwrap().block_id());
+    }
+
+    #[test]
+    fn block_source_is_read_at_block_offsets() {
+        let data = b"aaaabbbbccccdd";
+        let mut transfer = WriteTransfer::new(4);
+        transfer.set_window_size(WindowSize::new(3).unwrap(), false);
+        while transfer.can_send() {
+            transfer.next_block_from(&mut &data[..]).unwrap();
+        }
+        assert_eq!(AckReceived::Rewind(4), transfer.receive_ack(&AckPacket::new(1)));
+        let packet = transfer.next_block_from(&mut &data[..]).unwrap();
+        assert_eq!((2, &b"bbbb"[..]), (packet.block_id(), packet.data()));
+        transfer.restart(8);
+        assert_eq!(b"aaaabbbb", transfer.next_block_from(&mut &data[..]).unwrap().data());
+        transfer.resume(1);
+        let packet = transfer.next_block_from(&mut &data[..]).unwrap();
+        assert_eq!((2, &b"ccccdd"[..]), (packet.block_id(), packet.data()));
+    }
+
+    #[test]
+    fn write_transfer_rewinds_window_on_timeout() {
+        let mut data = Cursor::new(vec![1; 16]);
+        let mut transfer = WriteTransfer::new(4);
+        transfer.set_window_size(WindowSize::new(4).unwrap(), true);
+        while transfer.can_send() {
+            transfer.next_block(&mut data).unwrap();
+        }
+        assert_eq!(Timeout::Rewind(0), transfer.timeout());
+        // The full window is sent again, after a pause.
+        assert_eq!(4, transfer.window());
+        assert_eq!(Duration::from_millis(10), transfer.pause(Duration::from_millis(10)));
+        data.set_position(0);
+        assert_eq!(1, transfer.next_block(&mut data).unwrap().block_id());
+    }
+
+    #[test]
+    fn congestion_window_is_adjusted() {
+        let mut window = CongestionWindow::new(8);
+        window.on_loss();
+        window.on_loss();
+        assert_eq!(2, window.window());
+        assert_eq!(Duration::from_millis(30), window.pause(Duration::from_millis(10)));
+        window.on_ack();
+        assert_eq!(3, window.window());
+        for _ in 0..10 {
+            window.on_ack();
+        }
+        assert_eq!(8, window.window());
+        assert_eq!(Duration::from_secs(0), window.pause(Duration::from_millis(10)));
+        for _ in 0..10 {
+            window.on_loss();
+        }
+        assert_eq!(1, window.window());
+    }
+
+    #[test]
+    fn windows_are_acknowledged_at_their_end() {
+        let mut transfer = ReadTransfer::new(4);
+        let mut window = AckWindow::new(WindowSize::new(2).unwrap());
+        let mut acknowledged = Vec::new();
+        for &block_id in &[1, 2, 4, 5, 3, 4, 5] {
+            let data = if block_id == 5 { &b"a"[..] } else { &b"abcd"[..] };
+            let ack = match transfer.receive_data(&DataPacketOctet::from_slice(block_id, data)) {
+                DataReceived::Accepted(ack) => if window.accepted(transfer.is_done()) { Some(ack) } else { None },
+                DataReceived::Ignored => if window.skipped() { Some(AckPacket::new(2)) } else { None },
+                other => panic!("unexpected result: {:?}", other),
+            };
+            if let Some(ack) = ack {
+                acknowledged.push(ack.block_id());
+                window.acknowledged();
+            }
+        }
+        // Block 3 was lost, the gap is reported once.
+        assert_eq!(vec![2, 2, 4, 5], acknowledged);
+        assert!(!AckWindow::new(WindowSize::default()).skipped());
+    }
+
+    fn oack(name: &'static str, value: &'static str) -> OptionAckPacket<'static> {
+        let mut options = TransferOptions::new();
+        options.insert(name, value);
+        OptionAckPacket::new(options)
+    }
+
+    #[test]
+    fn server_can_lower_block_size() {
+        assert_eq!(Ok(256), negotiated_block_size(1024, &oack("BLKSIZE", "256")));
+        assert_eq!(Ok(512), negotiated_block_size(1024, &OptionAckPacket::new(TransferOptions::new())));
+    }
+
+    #[test]
+    fn larger_block_size_is_rejected() {
+        assert!(negotiated_block_size(1024, &oack("blksize", "1428")).is_err());
+        assert!(negotiated_block_size(1024, &oack("blksize", "4")).is_err());
+    }
+
+    #[test]
+    fn unrequested_option_is_rejected() {
+        assert!(negotiated_block_size(1024, &oack("tsize", "0")).is_err());
+        assert!(negotiated_block_size(1024, &oack("timeout", "1")).is_err());
+    }
+
+    #[test]
+    fn sub_second_timeouts_are_requested_in_microseconds() {
+        let mut options = TransferOptions::new();
+        request_timeout(&mut options, Duration::from_millis(250));
+        assert_eq!(Some("250000"), options.get("utimeout"));
+        assert_eq!(Some(("utimeout", Duration::from_millis(250))), requested_timeout(&options));
+
+        let mut options = TransferOptions::new();
+        request_timeout(&mut options, Duration::from_secs(3));
+        assert_eq!(Some("3"), options.get("timeout"));
+        assert_eq!(Some(("timeout", Duration::from_secs(3))), requested_timeout(&options));
+
+        let mut options = TransferOptions::new();
+        request_timeout(&mut options, Duration::from_millis(1));
+        assert_eq!(Some("10000"), options.get("utimeout"));
+        request_timeout(&mut options, Duration::from_secs(1000));
+        assert_eq!(Some("255"), options.get("timeout"));
+    }
+
+    #[test]
+    fn invalid_timeouts_are_ignored() {
+        let mut options = TransferOptions::new();
+        options.insert("utimeout", "5");
+        options.insert("timeout", "2");
+        assert_eq!(Some(("timeout", Duration::from_secs(2))), requested_timeout(&options));
+        options.insert("timeout", "256");
+        assert_eq!(None, requested_timeout(&options));
+    }
+
+    #[test]
+    fn timeout_must_be_acknowledged_unchanged() {
+        let timeout = Some(Duration::from_millis(250));
+        let none = TransferOptions::new();
+        assert_eq!(Ok(512), negotiated_options(1024, timeout, &none, &oack("utimeout", "250000")));
+        assert_eq!(Ok(512), negotiated_options(1024, timeout, &none, &OptionAckPacket::new(TransferOptions::new())));
+        assert!(negotiated_options(1024, timeout, &none, &oack("utimeout", "500000")).is_err());
+        assert!(negotiated_options(1024, timeout, &none, &oack("timeout", "1")).is_err());
+    }
+
+    #[test]
+    fn requested_extensions_may_be_acknowledged() {
+        let mut extensions = TransferOptions::new();
+        extensions.insert("x-vendor", "1");
+        assert_eq!(Ok(512), negotiated_options(1024, None, &extensions, &oack("X-VENDOR", "2")));
+        assert!(negotiated_options(1024, None, &extensions, &oack("x-other", "1")).is_err());
+    }
+
+    #[test]
+    fn window_size_is_negotiated() {
+        let mut options = TransferOptions::new();
+        request_window_size(&mut options, WindowSize::default());
+        assert!(options.is_empty());
+        request_window_size(&mut options, WindowSize::new(8).unwrap());
+        assert_eq!(Some("8"), options.get("windowsize"));
+
+        let max = WindowSize::new(4).unwrap();
+        assert_eq!(Some(max), requested_window_size(&options, max));
+        assert_eq!(None, requested_window_size(&TransferOptions::new(), max));
+        assert_eq!(None, requested_window_size(&oack("windowsize", "0").options(), max));
+
+        let requested = WindowSize::new(8).unwrap();
+        assert_eq!(Ok(max), negotiated_window_size(requested, &oack("WINDOWSIZE", "4")));
+        assert_eq!(Ok(WindowSize::default()),
+                   negotiated_window_size(requested, &OptionAckPacket::new(TransferOptions::new())));
+        assert!(negotiated_window_size(requested, &oack("windowsize", "16")).is_err());
+        assert!(negotiated_window_size(requested, &oack("windowsize", "0")).is_err());
+    }
+}
@@ -0,0 +1,567 @@
+//! Datagram transports the protocol runs over.
+//!
+//! The blocking client and the server exchange packets through a `Transport`.
+//! UDP sockets of the standard library, mio (`UdpTransport`) and tokio-core are
+//! transports, other implementations can tunnel the protocol through something
+//! else, like the DTLS transport of the `experimental-dtls` feature.
+//!
+//! `send_packet` and `recv_packet` exchange single packets over any transport,
+//! building blocks for flows the client doesn't cover, like probing the options
+//! a server supports without completing a transfer.
+//!
+//! On Unix, datagram sockets in the `unix` domain are transports too. Their
+//! peers are addressed by path, so client and server can talk without any IP
+//! networking, e.g. in tests or sandboxes.
+//!
+//! A `Tap` observes the raw datagrams of a transport wrapped in `Tapped`, e.g.
+//! for wire-level diagnostics. The client and the server tap their UDP
+//! sockets when configured with one.
+//!
+//! Transports silently truncate datagrams longer than the receive buffer. The
+//! client and the server receive into buffers of `receive_buffer` with a spare
+//! byte, so an oversized datagram is detected with `is_truncated` instead of
+//! being taken for a short last block.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{self, IpAddr, SocketAddr};
+use std::sync::Arc;
+#[cfg(unix)]
+use std::os::unix::net as unix_net;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use packet::{AnyPacket, DataPacketOctet, DecodePacket, EncodePacket};
+use replay::Direction;
+
+/// Non-blocking datagram socket.
+///
+/// Operations that can't complete right away fail with `WouldBlock`.
+pub trait Transport {
+    /// Address of a peer.
+    type Addr: Clone + PartialEq + fmt::Debug + 'static;
+
+    /// Sends a datagram to `addr`.
+    fn send_to(&mut self, buf: &[u8], addr: &Self::Addr) -> io::Result<usize>;
+
+    /// Receives a datagram, returns its length and the address of the sender.
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, Self::Addr)>;
+
+    /// Restricts the transport to the peer at `addr`.
+    ///
+    /// Afterwards datagrams are only sent to `addr`. Transports that can drop
+    /// datagrams from other peers do so, by default nothing changes and the
+    /// caller filters received datagrams.
+    fn connect(&mut self, addr: &Self::Addr) -> io::Result<()> {
+        let _ = addr;
+        Ok(())
+    }
+
+    /// Lifts the restriction of `connect`, datagrams of all peers are
+    /// received again.
+    ///
+    /// A client restarting a transfer needs it to accept the new transfer
+    /// identifier of the server.
+    fn disconnect(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns `true` if `a` and `b` are addresses of the same host.
+    ///
+    /// Clients check the first reply comes from the server the request was
+    /// sent to. By default all addresses are considered the same host.
+    fn same_host(a: &Self::Addr, b: &Self::Addr) -> bool where Self: Sized {
+        let _ = (a, b);
+        true
+    }
+
+    /// Returns the IP address of the host at `addr`.
+    ///
+    /// The errors sent to hosts that aren't peers of a transfer are rate
+    /// limited per IP address. By default addresses have none and the errors
+    /// sent to them are not limited.
+    fn host_ip(&self, addr: &Self::Addr) -> Option<IpAddr> {
+        let _ = addr;
+        None
+    }
+
+    /// Sends a data packet, `buffer` is used to encode it.
+    ///
+    /// Transports that can send the payload without copying it into an encoded
+    /// packet override this.
+    fn send_data(&mut self, packet: &DataPacketOctet, addr: &Self::Addr, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        send_packet(self, packet, addr, buffer)
+    }
+
+    /// Sends each of `segments` as a datagram to `addr`, returns the number
+    /// of datagrams sent.
+    ///
+    /// All segments but the last have the same length, like the data packets
+    /// of a window. Transports that can pass them to the kernel at once
+    /// override this, by default they are sent one at a time until one would
+    /// block.
+    fn send_segments(&mut self, segments: &[&[u8]], addr: &Self::Addr) -> io::Result<usize> {
+        send_each(self, segments, addr)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    type Addr = T::Addr;
+
+    fn send_to(&mut self, buf: &[u8], addr: &T::Addr) -> io::Result<usize> {
+        (**self).send_to(buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, T::Addr)> {
+        (**self).recv_from(buf)
+    }
+
+    fn connect(&mut self, addr: &T::Addr) -> io::Result<()> {
+        (**self).connect(addr)
+    }
+
+    fn disconnect(&mut self) -> io::Result<()> {
+        (**self).disconnect()
+    }
+
+    fn host_ip(&self, addr: &T::Addr) -> Option<IpAddr> {
+        (**self).host_ip(addr)
+    }
+
+    fn send_data(&mut self, packet: &DataPacketOctet, addr: &T::Addr, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        (**self).send_data(packet, addr, buffer)
+    }
+
+    fn send_segments(&mut self, segments: &[&[u8]], addr: &T::Addr) -> io::Result<usize> {
+        (**self).send_segments(segments, addr)
+    }
+}
+
+/// Observer of the raw datagrams of a transport.
+///
+/// The callback gets the direction of each datagram, the address of the peer
+/// and the datagram as it was sent or received, including invalid datagrams
+/// and datagrams from unknown peers. Datagrams that failed to send are not
+/// passed. The callback runs on the thread doing the transfer and should
+/// return quickly.
+pub struct Tap<A = SocketAddr>(Arc<Fn(Direction, &A, &[u8]) + Send + Sync>);
+
+impl<A> Tap<A> {
+    /// Creates a tap calling `callback` for every datagram.
+    pub fn new<F>(callback: F) -> Tap<A>
+        where F: Fn(Direction, &A, &[u8]) + Send + Sync + 'static,
+    {
+        Tap(Arc::new(callback))
+    }
+
+    pub(crate) fn observe(&self, direction: Direction, addr: &A, datagram: &[u8]) {
+        (self.0)(direction, addr, datagram)
+    }
+}
+
+impl<A> Clone for Tap<A> {
+    fn clone(&self) -> Tap<A> {
+        Tap(self.0.clone())
+    }
+}
+
+impl<A> fmt::Debug for Tap<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Tap")
+    }
+}
+
+/// Transport passing the datagrams it sends and receives to a `Tap`.
+///
+/// Data packets are encoded into a buffer to pass them to the tap, the
+/// payloads are not sent from the caller's buffer directly.
+#[derive(Debug)]
+pub struct Tapped<T: Transport> {
+    inner: T,
+    tap: Option<Tap<T::Addr>>,
+}
+
+impl<T: Transport> Tapped<T> {
+    /// Wraps `inner`, passing its datagrams to `tap`.
+    pub fn new(inner: T, tap: Tap<T::Addr>) -> Tapped<T> {
+        Tapped::optional(inner, Some(tap))
+    }
+
+    /// Wraps `inner`, passing its datagrams to `tap` if there is one.
+    pub(crate) fn optional(inner: T, tap: Option<Tap<T::Addr>>) -> Tapped<T> {
+        Tapped {
+            inner: inner,
+            tap: tap,
+        }
+    }
+
+    /// Returns the wrapped transport.
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the wrapped transport.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for Tapped<T> {
+    type Addr = T::Addr;
+
+    fn send_to(&mut self, buf: &[u8], addr: &T::Addr) -> io::Result<usize> {
+        let n = try!(self.inner.send_to(buf, addr));
+        if let Some(ref tap) = self.tap {
+            tap.observe(Direction::Sent, addr, &buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, T::Addr)> {
+        let (n, addr) = try!(self.inner.recv_from(buf));
+        if let Some(ref tap) = self.tap {
+            tap.observe(Direction::Received, &addr, &buf[..n]);
+        }
+        Ok((n, addr))
+    }
+
+    fn connect(&mut self, addr: &T::Addr) -> io::Result<()> {
+        self.inner.connect(addr)
+    }
+
+    fn disconnect(&mut self) -> io::Result<()> {
+        self.inner.disconnect()
+    }
+
+    fn same_host(a: &T::Addr, b: &T::Addr) -> bool {
+        T::same_host(a, b)
+    }
+
+    fn host_ip(&self, addr: &T::Addr) -> Option<IpAddr> {
+        self.inner.host_ip(addr)
+    }
+
+    fn send_data(&mut self, packet: &DataPacketOctet, addr: &T::Addr, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        if self.tap.is_some() {
+            send_packet(self, packet, addr, buffer)
+        } else {
+            self.inner.send_data(packet, addr, buffer)
+        }
+    }
+
+    fn send_segments(&mut self, segments: &[&[u8]], addr: &T::Addr) -> io::Result<usize> {
+        let n = try!(self.inner.send_segments(segments, addr));
+        if let Some(ref tap) = self.tap {
+            for segment in &segments[..n] {
+                tap.observe(Direction::Sent, addr, segment);
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Sends `segments` to `addr` one at a time, stops at the first that would
+/// block after others were sent.
+fn send_each<T: Transport + ?Sized>(transport: &mut T, segments: &[&[u8]], addr: &T::Addr) -> io::Result<usize> {
+    let mut sent = 0;
+    for segment in segments {
+        match transport.send_to(segment, addr) {
+            Ok(_) => sent += 1,
+            Err(ref e) if sent > 0 && e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(sent)
+}
+
+/// Encodes a packet into `buffer` and sends it to `addr`.
+pub fn send_packet<T, P>(transport: &mut T, packet: &P, addr: &T::Addr, buffer: &mut Vec<u8>) -> io::Result<usize>
+    where T: Transport + ?Sized,
+          P: EncodePacket,
+{
+    let encoded = packet.encode_using(mem::replace(buffer, Vec::new()));
+    let result = transport.send_to(encoded.packet_buf(), addr);
+    *buffer = encoded.into_buffer();
+    result
+}
+
+/// Receives a datagram into `buf` and decodes it, returns the packet and the
+/// address of the sender.
+///
+/// Datagrams that are not a valid packet are returned as `None`, custom flows
+/// usually drop them like the client and the server do.
+pub fn recv_packet<'a, T>(transport: &mut T, buf: &'a mut [u8]) -> io::Result<(Option<AnyPacket<'a>>, T::Addr)>
+    where T: Transport + ?Sized,
+{
+    let (n, addr) = try!(transport.recv_from(buf));
+    Ok((AnyPacket::decode(&buf[..n]), addr))
+}
+
+/// Returns a buffer receiving datagrams of up to `max_len` bytes.
+///
+/// The buffer has a spare byte, a datagram filling it is longer than
+/// `max_len` and may have been truncated.
+pub fn receive_buffer(max_len: usize) -> Vec<u8> {
+    vec![0; max_len + 1]
+}
+
+/// Returns `true` if a datagram of `len` bytes received into `buf`, a buffer
+/// of `receive_buffer`, is longer than the buffer was created for.
+pub fn is_truncated(len: usize, buf: &[u8]) -> bool {
+    len >= buf.len()
+}
+
+/// Error of a datagram longer than the transfer allows, e.g. a data packet
+/// with more than the negotiated block size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketTooLarge {
+    /// Maximum length of the datagram in bytes.
+    pub max_len: usize,
+}
+
+impl fmt::Display for PacketTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "packet is larger than {} bytes", self.max_len)
+    }
+}
+
+impl error::Error for PacketTooLarge {
+    fn description(&self) -> &str {
+        "packet too large"
+    }
+}
+
+impl From<PacketTooLarge> for io::Error {
+    fn from(err: PacketTooLarge) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// The socket has to be in non-blocking mode.
+impl Transport for net::UdpSocket {
+    type Addr = SocketAddr;
+
+    fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        net::UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        net::UdpSocket::recv_from(self, buf)
+    }
+
+    fn same_host(a: &SocketAddr, b: &SocketAddr) -> bool {
+        a.ip() == b.ip()
+    }
+
+    fn host_ip(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        Some(addr.ip())
+    }
+}
+
+/// The socket has to be in non-blocking mode. Peers that didn't bind their
+/// socket to a path have an empty address and can't be answered.
+#[cfg(unix)]
+impl Transport for unix_net::UnixDatagram {
+    type Addr = PathBuf;
+
+    fn send_to(&mut self, buf: &[u8], addr: &PathBuf) -> io::Result<usize> {
+        unix_net::UnixDatagram::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, PathBuf)> {
+        let (n, addr) = try!(unix_net::UnixDatagram::recv_from(self, buf));
+        Ok((n, unix_path(&addr)))
+    }
+}
+
+/// Returns the path of a Unix socket address, empty if the socket is unnamed.
+#[cfg(unix)]
+pub(crate) fn unix_path(addr: &unix_net::SocketAddr) -> PathBuf {
+    addr.as_pathname().map(|path| path.to_path_buf()).unwrap_or_default()
+}
+
+#[cfg(feature = "tokio-server")]
+impl Transport for ::tokio_core::net::UdpSocket {
+    type Addr = SocketAddr;
+
+    fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+        ::tokio_core::net::UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        ::tokio_core::net::UdpSocket::recv_from(self, buf)
+    }
+
+    fn same_host(a: &SocketAddr, b: &SocketAddr) -> bool {
+        a.ip() == b.ip()
+    }
+
+    fn host_ip(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        Some(addr.ip())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_data(&mut self, packet: &DataPacketOctet, addr: &SocketAddr, buffer: &mut Vec<u8>) -> io::Result<usize> {
+        if let ::futures::Async::NotReady = self.poll_write() {
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+        match ::vectored::send_data_to(self, packet, addr) {
+            // Regular send clears the write readiness so the task is notified when
+            // the socket becomes writable again.
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => send_packet(self, packet, addr, buffer),
+            result => result,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn send_segments(&mut self, segments: &[&[u8]], addr: &SocketAddr) -> io::Result<usize> {
+        if let ::futures::Async::NotReady = self.poll_write() {
+            return Err(io::ErrorKind::WouldBlock.into())
+        }
+        match ::batch::send_segments_over(self, segments, *addr) {
+            // Like in `send_data`, a regular send clears the write readiness.
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                ::tokio_core::net::UdpSocket::send_to(self, segments[0], addr).map(|_| 1)
+            }
+            result => result,
+        }
+    }
+}
+
+#[cfg(feature = "mio-client")]
+pub use self::mio_udp::UdpTransport;
+
+#[cfg(feature = "mio-client")]
+mod mio_udp {
+    use std::io;
+    use std::net::{IpAddr, SocketAddr};
+    #[cfg(unix)]
+    use std::path::PathBuf;
+
+    use mio::event::Source;
+    use mio::net::UdpSocket;
+    use mio::{Interest, Registry, Token};
+
+    use super::{Tapped, Transport};
+
+    /// UDP transport of the blocking client.
+    ///
+    /// Connecting locks the socket to the transfer identifier of the server,
+    /// the kernel then drops datagrams from other sources.
+    #[derive(Debug)]
+    pub struct UdpTransport {
+        socket: UdpSocket,
+        connected: bool,
+    }
+
+    impl UdpTransport {
+        /// Creates a transport bound to `addr`.
+        pub fn bind(addr: SocketAddr) -> io::Result<UdpTransport> {
+            Ok(UdpTransport {
+                socket: try!(UdpSocket::bind(addr)),
+                connected: false,
+            })
+        }
+
+        /// Restricts the transport to the network interface `device`, e.g.
+        /// `eth1`, datagrams are sent and received through it only.
+        #[cfg(target_os = "linux")]
+        pub fn bind_to_device(&self, device: &str) -> io::Result<()> {
+            ::sys::bind_to_device(&self.socket, device)
+        }
+    }
+
+    impl Transport for UdpTransport {
+        type Addr = SocketAddr;
+
+        fn send_to(&mut self, buf: &[u8], addr: &SocketAddr) -> io::Result<usize> {
+            if self.connected {
+                self.socket.send(buf)
+            } else {
+                self.socket.send_to(buf, *addr)
+            }
+        }
+
+        fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+            self.socket.recv_from(buf)
+        }
+
+        fn connect(&mut self, addr: &SocketAddr) -> io::Result<()> {
+            try!(self.socket.connect(*addr));
+            self.connected = true;
+            Ok(())
+        }
+
+        #[cfg(target_os = "linux")]
+        fn disconnect(&mut self) -> io::Result<()> {
+            if self.connected {
+                try!(::sys::disconnect(&self.socket));
+                self.connected = false;
+            }
+            Ok(())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        fn disconnect(&mut self) -> io::Result<()> {
+            if self.connected {
+                return Err(io::Error::new(io::ErrorKind::Other, "connected sockets can't be disconnected"))
+            }
+            Ok(())
+        }
+
+        fn same_host(a: &SocketAddr, b: &SocketAddr) -> bool {
+            a.ip() == b.ip()
+        }
+
+        fn host_ip(&self, addr: &SocketAddr) -> Option<IpAddr> {
+            Some(addr.ip())
+        }
+    }
+
+    /// Unix datagram transport of the blocking client.
+    ///
+    /// The socket has to be bound to a path for the server to answer.
+    #[cfg(unix)]
+    impl Transport for ::mio::net::UnixDatagram {
+        type Addr = PathBuf;
+
+        fn send_to(&mut self, buf: &[u8], addr: &PathBuf) -> io::Result<usize> {
+            ::mio::net::UnixDatagram::send_to(self, buf, addr)
+        }
+
+        fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, PathBuf)> {
+            let (n, addr) = try!(::mio::net::UnixDatagram::recv_from(self, buf));
+            Ok((n, addr.as_pathname().map(|path| path.to_path_buf()).unwrap_or_default()))
+        }
+    }
+
+    impl<T: Transport + Source> Source for Tapped<T> {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.inner.register(registry, token, interests)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.inner.reregister(registry, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            self.inner.deregister(registry)
+        }
+    }
+
+    impl Source for UdpTransport {
+        fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.socket.register(registry, token, interests)
+        }
+
+        fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> io::Result<()> {
+            self.socket.reregister(registry, token, interests)
+        }
+
+        fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+            self.socket.deregister(registry)
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Vectored sending of data packets.
+//!
+//! Packet header and payload are passed to `sendmsg` as separate buffers, so file
+//! data flows from the transfer's read buffer to the socket without being copied
+//! into an encoded packet first.
+
+extern crate libc;
+
+use std::io;
+use std::mem;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+use packet::DataPacketOctet;
+use sys::from_socket_addr;
+
+/// Sends a data packet to `addr` without copying the payload.
+///
+/// Returns the number of bytes sent, including the packet header.
+pub fn send_data_to<S: AsRawFd>(socket: &S, packet: &DataPacketOctet, addr: &SocketAddr) -> io::Result<usize> {
+    let header = packet.header();
+    let data = packet.data();
+    let (mut storage, storage_len) = from_socket_addr(addr);
+    let mut iovecs = [
+        libc::iovec {
+            iov_base: header.as_ptr() as *mut libc::c_void,
+            iov_len: header.len(),
+        },
+        libc::iovec {
+            iov_base: data.as_ptr() as *mut libc::c_void,
+            iov_len: data.len(),
+        },
+    ];
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut storage as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = storage_len;
+    msg.msg_iov = iovecs.as_mut_ptr();
+    msg.msg_iovlen = iovecs.len() as _;
+
+    let sent = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) };
+    if sent < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(sent as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+
+    use packet::{DataPacketOctet, EncodePacket};
+
+    use super::send_data_to;
+
+    #[test]
+    fn data_packet_is_sent_vectored() {
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let packet = DataPacketOctet::from_slice(258, b"payload");
+
+        let sent = send_data_to(&sender, &packet, &receiver.local_addr().unwrap()).unwrap();
+        assert_eq!(11, sent);
+
+        let mut buf = [0; 16];
+        let (n, _) = receiver.recv_from(&mut buf).unwrap();
+        assert_eq!(packet.encode().packet_buf(), &buf[..n]);
+    }
+}
@@ -0,0 +1,379 @@
+//! Timer wheel for the retransmission timers of many concurrent transfers.
+//!
+//! Every transfer resets its retransmission timer whenever it sends a packet,
+//! during a boot storm thousands of times per second. With a `TimerWheel` the
+//! timers of all transfers of a reactor thread share a single reactor timeout
+//! instead: a hashed wheel of slots one `tick` wide keeps their deadlines,
+//! resetting a timer only changes its deadline and once per tick the wheel
+//! wakes up the transfers whose deadline passed. Timers fire up to a tick late
+//! and the wheel sleeps while no timer is pending.
+//!
+//! `TimerStats` collects how late the timers fired, their drift, to tune the
+//! tick. `ServerBuilder::timer_wheel` runs the timers of a server's transfers
+//! on a wheel.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// Number of slots of a wheel, deadlines further out wrap around it.
+const SLOTS: u64 = 512;
+
+/// Summary of the drift of fired timers.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct Drift {
+    /// Timers that fired.
+    pub fired: u64,
+    /// Sum of the drift in microseconds.
+    total: u64,
+    max: u64,
+}
+
+impl Drift {
+    /// Returns the mean time timers fired after their deadline.
+    pub fn mean(&self) -> Option<Duration> {
+        if self.fired == 0 {
+            return None
+        }
+        Some(Duration::from_micros(self.total / self.fired))
+    }
+
+    /// Returns the longest time a timer fired after its deadline.
+    pub fn max(&self) -> Option<Duration> {
+        if self.fired == 0 {
+            return None
+        }
+        Some(Duration::from_micros(self.max))
+    }
+}
+
+/// Collector of the drift of timers, clones share the summary.
+#[derive(Debug, Default, Clone)]
+pub struct TimerStats(Arc<Mutex<Drift>>);
+
+impl TimerStats {
+    /// Creates a collector of no fired timers.
+    pub fn new() -> TimerStats {
+        TimerStats::default()
+    }
+
+    /// Returns the drift of the timers fired so far.
+    pub fn get(&self) -> Drift {
+        *self.0.lock().unwrap()
+    }
+
+    fn record(&self, drift: Duration) {
+        let micros = drift.as_secs() * 1_000_000 + drift.subsec_micros() as u64;
+        let mut summary = self.0.lock().unwrap();
+        summary.fired += 1;
+        summary.total += micros;
+        summary.max = cmp::max(summary.max, micros);
+    }
+}
+
+/// Configuration of the timer wheels of a server, clones share the stats.
+///
+/// Each reactor thread has one wheel per tick, created by the first timer.
+#[derive(Debug, Clone)]
+pub struct TimerWheel {
+    tick: Duration,
+    stats: TimerStats,
+}
+
+impl TimerWheel {
+    /// Creates wheels with slots `tick` wide, e.g. 10 milliseconds.
+    pub fn new(tick: Duration) -> TimerWheel {
+        TimerWheel {
+            tick: cmp::max(tick, Duration::from_millis(1)),
+            stats: TimerStats::new(),
+        }
+    }
+
+    /// Collects the drift of the timers in `stats`, e.g. to read it from
+    /// another thread.
+    pub fn stats(mut self, stats: TimerStats) -> TimerWheel {
+        self.stats = stats;
+        self
+    }
+
+    /// Returns the collector of the drift of the timers.
+    pub fn timer_stats(&self) -> &TimerStats {
+        &self.stats
+    }
+
+    /// Returns a timer firing at `deadline` on the wheel of the reactor
+    /// thread of `handle`.
+    pub fn timer(&self, deadline: Instant, handle: &Handle) -> Timer {
+        thread_local!(static WHEELS: RefCell<HashMap<Duration, Rc<RefCell<Wheel>>>> = RefCell::new(HashMap::new()));
+        let tick = self.tick;
+        let wheel = WHEELS.with(|wheels| {
+            wheels.borrow_mut().entry(tick).or_insert_with(|| Rc::new(RefCell::new(Wheel::new(tick)))).clone()
+        });
+        let id = wheel.borrow_mut().next_id();
+        Timer {
+            wheel: wheel,
+            id: id,
+            deadline: deadline,
+            fired: false,
+            stats: self.stats.clone(),
+            handle: handle.clone(),
+        }
+    }
+}
+
+/// Timer on a wheel, a future resolving once its deadline passed.
+///
+/// Like a reactor `Timeout` it stays resolved until it's reset.
+pub struct Timer {
+    wheel: Rc<RefCell<Wheel>>,
+    id: u64,
+    deadline: Instant,
+    /// The drift of the current deadline was recorded.
+    fired: bool,
+    stats: TimerStats,
+    handle: Handle,
+}
+
+impl Timer {
+    /// Sets the deadline of the timer to `deadline`.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.deadline = deadline;
+        self.fired = false;
+    }
+
+    /// Returns the deadline of the timer.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+}
+
+impl Future for Timer {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            if !self.fired {
+                self.fired = true;
+                self.wheel.borrow_mut().timers.remove(&self.id);
+                self.stats.record(now - self.deadline);
+            }
+            return Ok(Async::Ready(()))
+        }
+        try!(Wheel::insert(&self.wheel, self.id, self.deadline, task::current(), &self.handle, now));
+        Ok(Async::NotReady)
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.wheel.borrow_mut().timers.remove(&self.id);
+    }
+}
+
+/// Hashed timer wheel of a reactor thread.
+struct Wheel {
+    tick: Duration,
+    /// Start of the first tick.
+    origin: Instant,
+    /// Ticks whose timers were woken up.
+    processed: u64,
+    /// Identifiers of the timers and the tick of their deadline, in the slot
+    /// of the tick. Entries of timers registered again since are left behind
+    /// and skipped.
+    slots: Vec<Vec<(u64, u64)>>,
+    /// Tick of the deadline and task of the registered timers.
+    timers: HashMap<u64, (u64, Task)>,
+    next_id: u64,
+    /// A driver is spawned on the reactor.
+    driving: bool,
+    /// Task of the driver while no timer is registered.
+    idle: Option<Task>,
+}
+
+impl Wheel {
+    fn new(tick: Duration) -> Wheel {
+        Wheel {
+            tick: tick,
+            origin: Instant::now(),
+            processed: 0,
+            slots: (0..SLOTS).map(|_| Vec::new()).collect(),
+            timers: HashMap::new(),
+            next_id: 0,
+            driving: false,
+            idle: None,
+        }
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Returns the number of whole ticks between the origin and `at`.
+    fn ticks_until(&self, at: Instant) -> u64 {
+        (nanos(at.duration_since(self.origin)) / nanos(self.tick)) as u64
+    }
+
+    /// Returns the time the tick `tick` ends.
+    ///
+    /// Computed in nanoseconds, the tick count of a long running wheel
+    /// doesn't fit the `u32` a `Duration` can be multiplied by.
+    fn end_of(&self, tick: u64) -> Instant {
+        let end = nanos(self.tick) * (tick as u128 + 1);
+        self.origin + Duration::new((end / 1_000_000_000) as u64, (end % 1_000_000_000) as u32)
+    }
+
+    /// Registers the timer `id` to wake up `task` once `deadline` passed,
+    /// spawning the driver of the wheel on `handle` if none is running.
+    fn insert(wheel: &Rc<RefCell<Wheel>>, id: u64, deadline: Instant, task: Task, handle: &Handle,
+              now: Instant) -> io::Result<()> {
+        let mut this = wheel.borrow_mut();
+        if this.timers.is_empty() {
+            // Nothing to wake up in the ticks that passed while idle.
+            this.processed = cmp::max(this.processed, this.ticks_until(now));
+        }
+        // Woken up once the tick of the deadline ended.
+        let tick = cmp::max(this.ticks_until(deadline), this.processed + 1);
+        let moved = this.timers.get(&id).map_or(true, |&(registered, _)| registered != tick);
+        if moved {
+            this.slots[(tick % SLOTS) as usize].push((id, tick));
+        }
+        this.timers.insert(id, (tick, task));
+        if let Some(idle) = this.idle.take() {
+            idle.notify();
+        }
+        if !this.driving {
+            let first = this.end_of(this.processed);
+            let driver = Driver {
+                wheel: wheel.clone(),
+                timeout: try!(Timeout::new_at(first, handle)),
+            };
+            this.driving = true;
+            handle.spawn(driver.map_err(|e| error!("Timer wheel failed: {}", e)));
+        }
+        Ok(())
+    }
+
+    /// Wakes up the timers of the ticks that ended by `now`.
+    fn advance(&mut self, now: Instant) {
+        let current = self.ticks_until(now);
+        while self.processed < current {
+            self.processed += 1;
+            let processed = self.processed;
+            let slot = mem::replace(&mut self.slots[(processed % SLOTS) as usize], Vec::new());
+            let mut kept = Vec::new();
+            for (id, tick) in slot {
+                let registered = self.timers.get(&id).map(|&(registered, _)| registered);
+                if registered != Some(tick) {
+                    continue
+                }
+                if tick > processed {
+                    kept.push((id, tick));
+                } else if let Some((_, task)) = self.timers.remove(&id) {
+                    task.notify();
+                }
+            }
+            self.slots[(processed % SLOTS) as usize] = kept;
+        }
+    }
+}
+
+fn nanos(duration: Duration) -> u128 {
+    duration.as_secs() as u128 * 1_000_000_000 + duration.subsec_nanos() as u128
+}
+
+/// Task of a wheel on the reactor, wakes up the timers once per tick.
+struct Driver {
+    wheel: Rc<RefCell<Wheel>>,
+    timeout: Timeout,
+}
+
+impl Future for Driver {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        loop {
+            let mut wheel = self.wheel.borrow_mut();
+            if wheel.timers.is_empty() {
+                wheel.idle = Some(task::current());
+                return Ok(Async::NotReady)
+            }
+            if try!(self.timeout.poll()).is_not_ready() {
+                return Ok(Async::NotReady)
+            }
+            wheel.advance(Instant::now());
+            let next = wheel.end_of(wheel.processed);
+            self.timeout.reset(next);
+        }
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        let mut wheel = self.wheel.borrow_mut();
+        wheel.driving = false;
+        wheel.idle = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use futures::Future;
+    use futures::future;
+    use tokio_core::reactor::Core;
+
+    use super::{TimerWheel, Wheel};
+
+    #[test]
+    fn timers_fire_after_their_deadline() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let wheel = TimerWheel::new(Duration::from_millis(5));
+        let start = Instant::now();
+        let timers: Vec<_> = (0..200).map(|i| {
+            let deadline = start + Duration::from_millis(10 + i % 50);
+            wheel.timer(deadline, &handle).map(move |_| (deadline, Instant::now()))
+        }).collect();
+        // A reset moves the deadline later.
+        let mut reset = wheel.timer(start + Duration::from_millis(10), &handle);
+        reset.reset(start + Duration::from_millis(80));
+        let (fired, reset_fired) = core.run(future::join_all(timers).join(reset.map(|_| Instant::now()))).unwrap();
+        for (deadline, at) in fired {
+            assert!(at >= deadline);
+        }
+        assert!(reset_fired >= start + Duration::from_millis(80));
+
+        let drift = wheel.timer_stats().get();
+        assert_eq!(201, drift.fired);
+        assert!(drift.mean().unwrap() <= drift.max().unwrap());
+        // The wheel sleeps once no timer is pending and wakes up for new ones.
+        let later = wheel.timer(Instant::now() + Duration::from_millis(20), &handle);
+        core.run(later).unwrap();
+        assert_eq!(202, wheel.timer_stats().get().fired);
+    }
+
+    #[test]
+    fn end_of_tick_is_exact_after_years() {
+        let wheel = Wheel::new(Duration::from_millis(1));
+        // About 139 years of 1 millisecond ticks, past the range of a u32.
+        let tick = 1u64 << 42;
+        let end = wheel.end_of(tick);
+        assert_eq!(Duration::from_millis(tick + 1), end.duration_since(wheel.origin));
+        assert_eq!(tick + 1, wheel.ticks_until(end));
+        assert_eq!(tick, wheel.ticks_until(end - Duration::from_nanos(1)));
+    }
+}
@@ -0,0 +1,148 @@
+//! Allocations of the transfer state machines and the server data path.
+//!
+//! The counting allocator replaces the allocator of the whole test binary, so
+//! these tests have a binary of their own.
+
+extern crate tftp;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use tftp::packet::{AckPacket, DataPacketOctet, DecodePacket, EncodePacket};
+use tftp::transfer::{AckReceived, WriteTransfer};
+
+/// Allocator counting allocations made by threads which called `count_allocations`.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+thread_local!(static COUNTED: Cell<bool> = Cell::new(false));
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTED.try_with(|counted| counted.get()).unwrap_or(false) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Counts the allocations of the current thread from now on.
+fn count_allocations() {
+    COUNTED.with(|counted| counted.set(true));
+}
+
+fn allocations() -> usize {
+    ALLOCATIONS.load(Ordering::SeqCst)
+}
+
+#[test]
+fn write_transfer_steady_state_does_not_allocate() {
+    let mut data = Cursor::new(vec![1; 4 * 100]);
+    let mut transfer = WriteTransfer::new(4);
+    let mut send_buffer = vec![0; 8];
+    let mut ack_buffer = vec![0; 8];
+    transfer.next_block(&mut data).unwrap();
+
+    count_allocations();
+    let before = allocations();
+    loop {
+        let encoded = transfer.current_block().encode_using(send_buffer);
+        let block_id = encoded.decode::<DataPacketOctet>().unwrap().block_id();
+        send_buffer = encoded.get_buffer();
+
+        let encoded = AckPacket::new(block_id).encode_using(ack_buffer);
+        let ack = AckPacket::decode(encoded.packet_buf()).unwrap();
+        ack_buffer = encoded.get_buffer();
+        match transfer.receive_ack(&ack) {
+            AckReceived::Next => { transfer.next_block(&mut data).unwrap(); }
+            AckReceived::Done => break,
+            other => panic!("unexpected ack result: {:?}", other),
+        }
+    }
+    assert_eq!(0, allocations() - before);
+}
+
+/// Downloads `file` from the server at `addr` with a client of plain UDP
+/// sockets, acknowledging every block.
+#[cfg(feature = "tokio-server")]
+fn download(addr: std::net::SocketAddr, file: &str) -> usize {
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    use tftp::packet::{Mode, RequestPacket};
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    client.send_to(RequestPacket::read_request(file, Mode::Octet).encode().packet_buf(), addr).unwrap();
+    let mut buf = vec![0; 1024];
+    let mut blocks = 0;
+    loop {
+        let (n, transfer) = client.recv_from(&mut buf).unwrap();
+        let data = DataPacketOctet::decode(&buf[..n]).unwrap();
+        client.send_to(AckPacket::new(data.block_id()).encode().packet_buf(), transfer).unwrap();
+        blocks += 1;
+        if data.data().len() < 512 {
+            return blocks
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "tokio-server")]
+fn server_data_path_steady_state_does_not_allocate() {
+    use std::env;
+    use std::fs;
+    use std::net::UdpSocket;
+    use std::process;
+    use std::thread;
+    use std::time::Duration;
+
+    use tftp::server::ServerBuilder;
+    use tftp::stats::Stats;
+
+    let dir = env::temp_dir().join(format!("tftp-allocations-{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // Names of the same length, the paths of both files allocate alike.
+    fs::write(dir.join("small"), vec![1; 512 * 10]).unwrap();
+    fs::write(dir.join("large"), vec![1; 512 * 210]).unwrap();
+    let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let (root, stats) = (dir.clone(), Stats::new());
+    let collector = stats.clone();
+    thread::spawn(move || {
+        count_allocations();
+        ServerBuilder::new(addr).root(root).stats(collector).build().unwrap().run().unwrap()
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Allocations of the server between the request and the end of a transfer.
+    let transfer_allocations = |file: &str, transfers: u64| {
+        let before = allocations();
+        let blocks = download(addr, file);
+        for _ in 0..100 {
+            if stats.get().arena.transfers == transfers {
+                break
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        (blocks, allocations() - before)
+    };
+    // The first transfer also allocates what the server keeps for later ones.
+    transfer_allocations("small", 1);
+    let (small_blocks, small) = transfer_allocations("small", 2);
+    let (large_blocks, large) = transfer_allocations("large", 3);
+    assert_eq!((11, 211), (small_blocks, large_blocks));
+    // Setting up a transfer allocates, the 200 additional blocks don't.
+    assert_eq!(small, large);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,222 @@
+//! Interoperability of the client with reference server implementations.
+//!
+//! Loopback tests only show that the client and the server of this crate
+//! agree with each other. These tests run the client against the servers
+//! installed on the host, tftpd-hpa (`in.tftpd`) and dnsmasq, each serving a
+//! temporary directory, and print a summary of the cases per server.
+//!
+//! They are opt-in, without `TFTP_INTEROP=1` they pass without doing anything:
+//!
+//! ```text
+//! TFTP_INTEROP=1 cargo test --test interop -- --nocapture
+//! ```
+//!
+//! Servers that aren't installed are skipped, `TFTP_INTEROP_TFTPD_HPA` and
+//! `TFTP_INTEROP_DNSMASQ` name the binaries if they aren't found in `PATH` or
+//! `/usr/sbin`. dnsmasq always listens on port 69 and tftpd-hpa changes to
+//! the user in `USER` (`nobody` if unset), both usually need to run as root.
+
+extern crate tftp;
+
+use std::env;
+use std::fs;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tftp::packet::{DecodePacket, OptionAckPacket, TIMEOUT_OPTION};
+use tftp::prelude::{BlockSize, ClientBuilder, Mode};
+use tftp::replay::Direction;
+use tftp::transport::Tap;
+
+/// Time a server gets to answer its first request.
+const STARTUP: Duration = Duration::from_secs(5);
+
+/// Reference server, killed when dropped.
+struct Reference {
+    name: &'static str,
+    addr: SocketAddr,
+    child: Child,
+}
+
+impl Drop for Reference {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Returns the path of the binary named by `var` or found as `name`.
+fn binary(var: &str, name: &str) -> Option<PathBuf> {
+    if let Some(path) = env::var_os(var) {
+        return Some(PathBuf::from(path))
+    }
+    let path = env::var_os("PATH").unwrap_or_default();
+    env::split_paths(&path).chain(vec![PathBuf::from("/usr/sbin"), PathBuf::from("/sbin")])
+        .map(|dir| dir.join(name))
+        .find(|candidate| candidate.is_file())
+}
+
+fn free_port() -> SocketAddr {
+    UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+}
+
+fn tftpd_hpa(root: &Path) -> Option<Command> {
+    let binary = match binary("TFTP_INTEROP_TFTPD_HPA", "in.tftpd") {
+        Some(binary) => binary,
+        None => return None,
+    };
+    let mut command = Command::new(binary);
+    command.arg("--foreground").arg("--secure")
+        .arg("--user").arg(env::var("USER").unwrap_or_else(|_| "nobody".to_owned()))
+        .arg(root);
+    Some(command)
+}
+
+fn dnsmasq(root: &Path) -> Option<Command> {
+    let binary = match binary("TFTP_INTEROP_DNSMASQ", "dnsmasq") {
+        Some(binary) => binary,
+        None => return None,
+    };
+    let mut command = Command::new(binary);
+    // No DNS, no configuration file and no pid file, only TFTP.
+    command.arg("--keep-in-foreground").arg("--conf-file=/dev/null").arg("--port=0").arg("--pid-file=")
+        .arg("--listen-address=127.0.0.1").arg("--bind-interfaces")
+        .arg("--enable-tftp").arg(format!("--tftp-root={}", root.display()));
+    Some(command)
+}
+
+/// Starts the server and waits until it answers, `None` if it isn't installed.
+fn start(name: &'static str, command: Option<Command>, addr: SocketAddr, probe: &Path)
+         -> Result<Option<Reference>, String> {
+    let mut command = match command {
+        Some(command) => command,
+        None => return Ok(None),
+    };
+    if name == "tftpd-hpa" {
+        command.arg("--address").arg(addr.to_string());
+    }
+    let child = try!(command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn()
+        .map_err(|e| format!("can't start: {}", e)));
+    let mut server = Reference { name: name, addr: addr, child: child };
+    let client = ClientBuilder::new(addr).timeout(Duration::from_millis(200)).build().unwrap();
+    let started = Instant::now();
+    loop {
+        if client.get(probe, Mode::Octet, &mut Vec::new()).is_ok() {
+            return Ok(Some(server))
+        }
+        if let Ok(Some(status)) = server.child.try_wait() {
+            return Err(format!("exited with {}", status))
+        }
+        if started.elapsed() > STARTUP {
+            return Err("doesn't answer".to_owned())
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Runs the cases against `server`, returns the failed ones.
+fn run_cases(server: &Reference, root: &Path) -> Vec<String> {
+    let binary: Vec<u8> = (0..5000u32).map(|i| (i * 7 + i / 251) as u8).collect();
+    let aligned: Vec<u8> = (0..2048u32).map(|i| i as u8).collect();
+    let text = "first line\nsecond line\n\nlast line\n".repeat(50);
+    fs::write(root.join("binary.bin"), &binary).unwrap();
+    fs::write(root.join("aligned.bin"), &aligned).unwrap();
+    fs::write(root.join("text.txt"), text.as_bytes()).unwrap();
+
+    let plain = ClientBuilder::new(server.addr).build().unwrap();
+    let large_blocks = ClientBuilder::new(server.addr).block_size(BlockSize::new(1428).unwrap()).build().unwrap();
+    // The client keeps its own timeout either way, the acknowledgment is
+    // taken from the datagrams.
+    let acknowledged = Arc::new(Mutex::new(None));
+    let tap_acknowledged = acknowledged.clone();
+    let tap = Tap::new(move |direction, _, datagram: &[u8]| {
+        if let (Direction::Received, Some(oack)) = (direction, OptionAckPacket::decode(datagram)) {
+            *tap_acknowledged.lock().unwrap() = oack.options().get(TIMEOUT_OPTION).map(str::to_owned);
+        }
+    });
+    let timeout = ClientBuilder::new(server.addr).timeout(Duration::from_secs(2)).negotiate_timeout(true).tap(tap)
+        .build().unwrap();
+
+    let mut failures = Vec::new();
+    let mut case = |name: &str, result: Result<String, String>| {
+        match result {
+            Ok(detail) => println!("  {:<10} {:<32} ok {}", server.name, name, detail),
+            Err(detail) => {
+                println!("  {:<10} {:<32} FAILED {}", server.name, name, detail);
+                failures.push(format!("{}: {}: {}", server.name, name, detail));
+            }
+        }
+    };
+    let get = |client: &tftp::client::Client, file: &str, mode: Mode, expected: &[u8]| {
+        let mut received = Vec::new();
+        let params = try!(client.get(Path::new(file), mode, &mut received).map_err(|e| e.to_string()));
+        if received != expected {
+            return Err(format!("received {} bytes instead of {}", received.len(), expected.len()))
+        }
+        Ok(params)
+    };
+
+    case("octet", get(&plain, "binary.bin", Mode::Octet, &binary).map(|_| String::new()));
+    case("octet, multiple of block size", get(&plain, "aligned.bin", Mode::Octet, &aligned).map(|_| String::new()));
+    // The client writes the data as it's sent, with CR LF line ends.
+    let netascii = text.replace("\n", "\r\n");
+    case("netascii", get(&plain, "text.txt", Mode::NetAscii, netascii.as_bytes()).map(|_| String::new()));
+    case("blksize 1428", get(&large_blocks, "binary.bin", Mode::Octet, &binary).and_then(|params| {
+        match params.block_size {
+            1428 => Ok(String::new()),
+            size => Err(format!("negotiated block size {}", size)),
+        }
+    }));
+    case("timeout 2", get(&timeout, "binary.bin", Mode::Octet, &binary).and_then(|_| {
+        match acknowledged.lock().unwrap().take() {
+            Some(ref value) if value == "2" => Ok(String::new()),
+            Some(value) => Err(format!("acknowledged timeout {}", value)),
+            None => Err("timeout not acknowledged".to_owned()),
+        }
+    }));
+    case("missing file", match plain.get(Path::new("missing"), Mode::Octet, &mut Vec::new()) {
+        Err(tftp::client::Error::Server(error)) => Ok(format!("({})", error)),
+        Err(e) => Err(format!("unexpected error: {}", e)),
+        Ok(_) => Err("a missing file was read".to_owned()),
+    });
+    failures
+}
+
+#[test]
+fn client_works_with_reference_servers() {
+    if env::var_os("TFTP_INTEROP").is_none() {
+        println!("Set TFTP_INTEROP=1 to run the client against reference servers");
+        return
+    }
+    let root = env::temp_dir().join(format!("tftp-interop-{}", process::id()));
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("probe"), b"probe").unwrap();
+
+    let servers: Vec<(&'static str, Option<Command>, SocketAddr)> = vec![
+        ("tftpd-hpa", tftpd_hpa(&root), free_port()),
+        ("dnsmasq", dnsmasq(&root), "127.0.0.1:69".parse().unwrap()),
+    ];
+    let mut failures = Vec::new();
+    let mut tested = 0;
+    for (name, command, addr) in servers {
+        println!("{}:", name);
+        match start(name, command, addr, Path::new("probe")) {
+            Ok(Some(server)) => {
+                tested += 1;
+                failures.extend(run_cases(&server, &root));
+            }
+            Ok(None) => println!("  not installed, skipped"),
+            Err(e) => {
+                println!("  {}", e);
+                failures.push(format!("{}: {}", name, e));
+            }
+        }
+    }
+    fs::remove_dir_all(&root).unwrap();
+    println!("{} servers tested, {} failures", tested, failures.len());
+    assert!(failures.is_empty(), "{:#?}", failures);
+}